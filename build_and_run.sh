@@ -22,7 +22,7 @@ DB_FILE=$ROOT_DIR/data/db.db
 # Firecracker executable
 FIRECRACKER_EXECUTABLE=$ROOT_DIR/data/firecracker
 
-rustup override set nightly
+rustup override set stable
 rustup update
 
 # Clean Previous Data
@@ -57,7 +57,7 @@ cargo build --release > /dev/null
 
 # Run the project
 echo "Running project..."
-sudo -E NANOS_KERNEL=$NANOS_KERNEL FIRECRACKER_EXECUTABLE=$FIRECRACKER_EXECUTABLE DATABASE_URL=sqlite://$DB_FILE RUST_LOG=WARN  ./target/release/ohsw --cidr $CIDR --broker-address $BROKER_ADDRESS --broker-port $BROKER_PORT --bridge-name $BRIDGE_INTERFACE 
+sudo -E NANOS_KERNEL=$NANOS_KERNEL FIRECRACKER_EXECUTABLE=$FIRECRACKER_EXECUTABLE DATABASE_URL=sqlite://$DB_FILE RUST_LOG=WARN  ./target/release/ohsw --cidr $CIDR --broker-address $BROKER_ADDRESS --broker-port $BROKER_PORT --bridge-name $BRIDGE_INTERFACE --no-sticky-offload
 
 # Clean Tap
 sudo ip link | awk -F: '/fc-/{print $2}' | xargs -I{} sudo ip link del {}
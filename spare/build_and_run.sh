@@ -38,7 +38,7 @@ touch db.db
 
 # Run the project
 echo "Running project..."
-sudo -E NANOS_KERNEL=$NANOS_KERNEL FIRECRACKER_EXECUTABLE=$FIRECRACKER_EXECUTABLE DATABASE_URL=$DATABASE_URL RUST_LOG=WARN  .target/release/ohsw --cidr $CIDR --broker-address $BROKER_ADDRESS --broker-port $BROKER_PORT --bridge-name $BRIDGE_INTERFACE 
+sudo -E NANOS_KERNEL=$NANOS_KERNEL FIRECRACKER_EXECUTABLE=$FIRECRACKER_EXECUTABLE DATABASE_URL=$DATABASE_URL RUST_LOG=WARN  .target/release/ohsw --cidr $CIDR --broker-address $BROKER_ADDRESS --broker-port $BROKER_PORT --bridge-name $BRIDGE_INTERFACE --no-sticky-offload
 
 # Clean Tap
 sudo ip link | awk -F: '/fc-/{print $2}' | xargs -I{} sudo ip link del {}
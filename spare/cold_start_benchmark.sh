@@ -1,22 +1,19 @@
 #!/bin/sh
 
-# Script to run the cold start benchmark
+# Script to run the cold start benchmark on a running node
+# The node must be started with --admin-token
 # Root directory
 ROOT_DIR=$(dirname $(dirname $(realpath $0)))
-# Cargo path
-CARGO_PATH=$(dirname $(which cargo))
-# Nanos kernel
-NANOS_KERNEL=$ROOT_DIR/data/kernel.img
-# Database URL
-DATABASE_URL=$ROOT_DIR/data/db.db
-# Firecracker executable
-FIRECRACKER_EXECUTABLE=$ROOT_DIR/data/firecracker
 # Function image
 SPARE_FUNCTION=$ROOT_DIR/data/nanosvm
-# Bridge network interface
-BRIDGE_INTERFACE=br0
+# Address of the node
+NODE_ADDRESS=127.0.0.1:8085
+# Token of the administrative endpoints of the node
+ADMIN_TOKEN=${ADMIN_TOKEN:?ADMIN_TOKEN must be set}
+# Number of instances booted (at most 50)
+ITERATIONS=50
 
-SPARE_FUNCTION=$SPARE_FUNCTION FIRECRACKER_EXECUTABLE=$FIRECRACKER_EXECUTABLE NANOS_KERNEL=$NANOS_KERNEL BRIDGE_INTERFACE=$BRIDGE_INTERFACE sudo -E  $CARGO_PATH/cargo test --package ohsw --release --lib -- endpoints::test::benchmark --exact --show-output
-
-# Clean Tap
-sudo ip link | awk -F: '/fc-/{print $2}' | xargs -I{} sudo ip link del {}
\ No newline at end of file
+curl -sf -X POST "http://$NODE_ADDRESS/calibrate" \
+    -H "Authorization: Bearer $ADMIN_TOKEN" \
+    -H "Content-Type: application/json" \
+    -d "{\"iterations\": $ITERATIONS, \"image\": \"$SPARE_FUNCTION\"}"
@@ -5,7 +5,7 @@ use std::{
 
 use crate::{
     builder::{Builder, BuilderError},
-    executor::{Executor, FirecrackerExecutor},
+    executor::{Executor, FirecrackerExecutor, JailerExecutor},
 };
 
 use super::assert_not_none;
@@ -129,6 +129,92 @@ impl Builder<Executor> for FirecrackerExecutorBuilder {
     }
 }
 
+#[derive(Debug)]
+pub struct JailerExecutorBuilder {
+    chroot_base: Option<String>,
+    jailer_binary: Option<PathBuf>,
+    exec_binary: Option<PathBuf>,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    parent_cgroup: Option<String>,
+    cgroups: Vec<String>,
+}
+
+impl JailerExecutorBuilder {
+    pub fn new() -> JailerExecutorBuilder {
+        JailerExecutorBuilder {
+            chroot_base: None,
+            jailer_binary: None,
+            exec_binary: None,
+            uid: None,
+            gid: None,
+            parent_cgroup: None,
+            cgroups: Vec::new(),
+        }
+    }
+
+    /// Base folder of the chroots, the jailer defaults to "/srv/jailer"
+    pub fn with_chroot_base(mut self, chroot_base: String) -> JailerExecutorBuilder {
+        self.chroot_base = Some(chroot_base);
+        self
+    }
+
+    pub fn with_jailer_binary(mut self, jailer_binary: PathBuf) -> JailerExecutorBuilder {
+        self.jailer_binary = Some(jailer_binary);
+        self
+    }
+
+    pub fn with_exec_binary(mut self, exec_binary: PathBuf) -> JailerExecutorBuilder {
+        self.exec_binary = Some(exec_binary);
+        self
+    }
+
+    /// User and group the firecracker process runs as
+    pub fn with_user(mut self, uid: u32, gid: u32) -> JailerExecutorBuilder {
+        self.uid = Some(uid);
+        self.gid = Some(gid);
+        self
+    }
+
+    pub fn with_parent_cgroup(mut self, parent_cgroup: String) -> JailerExecutorBuilder {
+        self.parent_cgroup = Some(parent_cgroup);
+        self
+    }
+
+    /// Add a cgroup value applied to the machine, e.g. `memory.max=536870912`
+    pub fn with_cgroup(mut self, cgroup: String) -> JailerExecutorBuilder {
+        self.cgroups.push(cgroup);
+        self
+    }
+}
+
+impl Default for JailerExecutorBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Builder<Executor> for JailerExecutorBuilder {
+    fn try_build(self) -> Result<Executor, BuilderError> {
+        assert_not_none(stringify!(self.jailer_binary), &self.jailer_binary)?;
+        assert_not_none(stringify!(self.exec_binary), &self.exec_binary)?;
+        assert_not_none(stringify!(self.uid), &self.uid)?;
+        assert_not_none(stringify!(self.gid), &self.gid)?;
+        let executor = JailerExecutor {
+            chroot_base: self
+                .chroot_base
+                .unwrap_or_else(|| "/srv/jailer".to_string()),
+            jailer_binary: self.jailer_binary.unwrap(),
+            exec_binary: self.exec_binary.unwrap(),
+            uid: self.uid.unwrap(),
+            gid: self.gid.unwrap(),
+            parent_cgroup: self.parent_cgroup,
+            cgroups: self.cgroups,
+        };
+        Ok(Executor::new_with_jailer(executor))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use serial_test::serial;
@@ -168,6 +254,33 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_jailer_executor_builder() {
+        use super::JailerExecutorBuilder;
+        use crate::builder::Builder;
+        use std::path::PathBuf;
+
+        let executor = JailerExecutorBuilder::new()
+            .with_jailer_binary(PathBuf::from("/usr/bin/jailer"))
+            .with_exec_binary(PathBuf::from("/usr/bin/firecracker"))
+            .with_user(10001, 10001)
+            .try_build()
+            .unwrap()
+            .with_id("vm1".to_string());
+        assert!(executor.is_jailed());
+        assert_eq!(
+            executor.chroot(),
+            PathBuf::from("/srv/jailer/firecracker/vm1/root")
+        );
+
+        // The user is required
+        let result = JailerExecutorBuilder::new()
+            .with_jailer_binary(PathBuf::from("/usr/bin/jailer"))
+            .with_exec_binary(PathBuf::from("/usr/bin/firecracker"))
+            .try_build();
+        assert!(result.is_err());
+    }
+
     #[test]
     #[serial]
     fn test_can_determine_binary_location_from_env() {
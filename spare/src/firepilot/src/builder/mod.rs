@@ -36,7 +36,7 @@
 //!     .try_build()
 //!     .unwrap();
 //! // Configure the executor that will be used to start the microVM
-//! // only firecracker is available, but you could add a jailer executor
+//! // firecracker is run directly, use JailerExecutorBuilder to run it through the jailer
 //! let executor = FirecrackerExecutorBuilder::new()
 //!     .with_chroot("./examples/executor/".to_string())
 //!     .with_exec_binary(PathBuf::from("/usr/bin/firecracker"))
@@ -51,7 +51,9 @@
 //! ```
 use crate::executor::Executor;
 
-use firepilot_models::models::{BootSource, Drive, Logger, MachineConfiguration, NetworkInterface};
+use firepilot_models::models::{
+    BootSource, Drive, Logger, MachineConfiguration, MmdsConfig, NetworkInterface,
+};
 
 pub mod drive;
 pub mod executor;
@@ -113,6 +115,12 @@ pub struct Configuration {
     pub interfaces: Vec<NetworkInterface>,
     pub logger: Option<Logger>,
     pub machine: Option<MachineConfiguration>,
+    pub mmds: Option<MmdsConfig>,
+    pub mmds_data: Option<serde_json::Value>,
+    /// Copy the drives into the workspace of the machine before booting (default)
+    pub copy_drives: bool,
+    /// Write the metrics of the machine in the workspace
+    pub metrics: bool,
 
     pub vm_id: String,
 }
@@ -126,6 +134,10 @@ impl Configuration {
             interfaces: Vec::new(),
             logger: None,
             machine: None,
+            mmds: None,
+            mmds_data: None,
+            copy_drives: true,
+            metrics: false,
             vm_id,
         }
     }
@@ -160,6 +172,26 @@ impl Configuration {
         self.machine = Some(machine);
         self
     }
+
+    /// Use the drives from their location on the host instead of copying them
+    /// into the workspace of the machine
+    pub fn with_drives_in_place(mut self) -> Configuration {
+        self.copy_drives = false;
+        self
+    }
+
+    /// Make the microVM write its metrics in the workspace, see [crate::machine::Machine::get_metrics_path]
+    pub fn with_metrics(mut self) -> Configuration {
+        self.metrics = true;
+        self
+    }
+
+    /// Enable MMDS on the microVM and fill its data store with `data`
+    pub fn with_mmds(mut self, mmds: MmdsConfig, data: serde_json::Value) -> Configuration {
+        self.mmds = Some(mmds);
+        self.mmds_data = Some(data);
+        self
+    }
 }
 
 #[cfg(test)]
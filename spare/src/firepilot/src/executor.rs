@@ -15,9 +15,12 @@
 //!
 //! You can either run firecracker directly with the binary by using
 //! [FirecrackerExecutor] or you could decide to be safer and run with a
-//! JailerExecutor. Be aware that the JailerExecutor is not yet implemented, but
-//! we welcome contributions.
-use std::{path::PathBuf, process::Stdio};
+//! [JailerExecutor], which runs each microVM in its own chroot, with a
+//! dedicated uid/gid and cgroup.
+use std::{
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+};
 
 use tokio::process::{Child, Command};
 
@@ -28,7 +31,7 @@ use tracing::{debug, error, info, instrument, trace};
 use crate::machine::FirepilotError;
 use firepilot_models::models::vm::Vm;
 use firepilot_models::models::{
-    BootSource, Drive, Logger, MachineConfiguration, NetworkInterface, Vsock,
+    BootSource, Drive, Logger, MachineConfiguration, Metrics, MmdsConfig, NetworkInterface, Vsock,
 };
 
 /// Interface to determine how to execute commands on the socket and where to do it
@@ -39,6 +42,33 @@ pub trait Execute {
     ///
     /// It is only used to spawn the executor process, not to send commands to it
     fn spawn_binary_child(&self, args: &Vec<String>) -> Result<Child, ExecuteError>;
+    /// Directory holding everything related to the machine `id`, removed on cleanup
+    fn workspace(&self, id: &str) -> PathBuf {
+        self.chroot().join(id)
+    }
+    /// Directory where the files of the machine `id` must be placed to be
+    /// visible to the binary
+    fn root(&self, id: &str) -> PathBuf {
+        self.workspace(id)
+    }
+    /// Translate a path on the host into the path seen by the binary
+    fn resolve_path(&self, _id: &str, path: &Path) -> PathBuf {
+        path.to_path_buf()
+    }
+    /// Arguments given to the binary to run the machine `id`, `args` are the
+    /// arguments meant for firecracker
+    fn binary_args(&self, _id: &str, args: &[String]) -> Vec<String> {
+        args.to_vec()
+    }
+    /// Give the binary access to a file placed in the root of the machine
+    fn grant_access(&self, _path: &Path) -> Result<(), ExecuteError> {
+        Ok(())
+    }
+    /// Remove everything related to the machine `id`
+    fn cleanup(&self, id: &str) -> Result<(), ExecuteError> {
+        std::fs::remove_dir_all(self.workspace(id))
+            .map_err(|e| ExecuteError::WorkspaceDeletion(e.to_string()))
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -81,6 +111,7 @@ impl From<ExecuteError> for FirepilotError {
 pub enum Action {
     InstanceStart,
     SendCtrlAltDel,
+    FlushMetrics,
 }
 
 /// Contains an instance of the microVM, this low-level implementation hold the
@@ -94,6 +125,8 @@ pub struct Executor {
     /// everywhere. We could have been using an enum, but due to the small
     /// number of implementation we judged it was not worth it.
     firecracker: Option<FirecrackerExecutor>,
+    /// Optional jailer executor, takes precedence over `firecracker`
+    jailer: Option<JailerExecutor>,
     /// Holds the process of the executor when it is running
     socket_process: Option<Child>,
     /// A RPC client to talk to the socket
@@ -109,6 +142,7 @@ impl Executor {
     pub fn new() -> Executor {
         Executor {
             firecracker: None,
+            jailer: None,
             socket_process: None,
             id: "default".to_string(),
             client: Client::unix(),
@@ -118,6 +152,17 @@ impl Executor {
     pub fn new_with_firecracker(firecracker: FirecrackerExecutor) -> Executor {
         Executor {
             firecracker: Some(firecracker),
+            jailer: None,
+            socket_process: None,
+            id: "default".to_string(),
+            client: Client::unix(),
+        }
+    }
+    /// Create a new Executor running firecracker through the jailer binary
+    pub fn new_with_jailer(jailer: JailerExecutor) -> Executor {
+        Executor {
+            firecracker: None,
+            jailer: Some(jailer),
             socket_process: None,
             id: "default".to_string(),
             client: Client::unix(),
@@ -136,12 +181,29 @@ impl Executor {
 
     /// Return the configured executor, or panic if none is configured
     fn executor(&self) -> &dyn Execute {
-        match &self.firecracker {
-            Some(firecracker) => return firecracker,
-            None => panic!("No executor found"),
+        match (&self.jailer, &self.firecracker) {
+            (Some(jailer), _) => jailer,
+            (None, Some(firecracker)) => firecracker,
+            (None, None) => panic!("No executor found"),
         }
     }
 
+    /// PID of the executor process, if it is running
+    pub fn pid(&self) -> Option<u32> {
+        self.socket_process.as_ref().and_then(|child| child.id())
+    }
+
+    /// Wait for the executor process to exit, returning how it exited.
+    /// None if the process was never spawned or cannot be waited for.
+    pub async fn wait_exit(&mut self) -> Option<ExitStatus> {
+        self.socket_process.as_mut()?.wait().await.ok()
+    }
+
+    /// Tells whether the mVM is confined in a chroot by the jailer
+    pub fn is_jailed(&self) -> bool {
+        self.jailer.is_some()
+    }
+
     #[instrument(skip(self), fields(id = %self.id))]
     fn wait_healthy(&self) -> Result<(), ExecuteError> {
         debug!("Waiting for socket to be healthy");
@@ -229,7 +291,28 @@ impl Executor {
 
     /// Full path to the chroot of the machine which contains the socket, drives, kernel, etc...
     pub fn chroot(&self) -> PathBuf {
-        self.executor().chroot().join(&self.id)
+        self.executor().root(&self.id)
+    }
+
+    /// Translate a path of the chroot into the path seen by the microVM process
+    pub fn resolve_path(&self, path: &Path) -> String {
+        self.executor()
+            .resolve_path(&self.id, path)
+            .into_os_string()
+            .into_string()
+            .unwrap()
+    }
+
+    /// Give the microVM process access to a file of the chroot
+    pub fn grant_access(&self, path: &Path) -> Result<(), ExecuteError> {
+        self.executor().grant_access(path)
+    }
+
+    /// Remove the workspace of the machine
+    #[instrument(skip(self), fields(id = %self.id))]
+    pub fn remove_workspace(&self) -> Result<(), ExecuteError> {
+        debug!("Removing workspace at {}", self.chroot().display());
+        self.executor().cleanup(&self.id)
     }
 
     /// Tries to spawn the executor process, the workspace for the machine should
@@ -240,10 +323,11 @@ impl Executor {
         let executor = self.executor();
         let sock = self.chroot().join("firecracker.socket");
 
-        let child = executor.spawn_binary_child(&vec![
-            "--api-sock".to_string(),
-            sock.into_os_string().into_string().unwrap(),
-        ])?;
+        let args = executor.binary_args(
+            &self.id,
+            &["--api-sock".to_string(), self.resolve_path(&sock)],
+        );
+        let child = executor.spawn_binary_child(&args)?;
         self.wait_healthy()?;
         self.socket_process = Some(child);
         debug!("Socket is now running");
@@ -261,10 +345,16 @@ impl Executor {
                 "Socket hasn't been spawned, you must spawn it before destroying it".to_string(),
             )
         })?;
-        socket
-            .kill()
-            .await
+        // A process that already exited (e.g. it crashed) has nothing left to kill
+        let exited = socket
+            .try_wait()
             .map_err(|e| ExecuteError::Socket(e.to_string()))?;
+        if exited.is_none() {
+            socket
+                .kill()
+                .await
+                .map_err(|e| ExecuteError::Socket(e.to_string()))?;
+        }
         std::fs::remove_file(sock_path).map_err(|e| ExecuteError::Socket(e.to_string()))?;
         debug!("Socket is now destroyed and the socket file doesn't exist anymore");
         self.socket_process = None;
@@ -342,6 +432,8 @@ impl Executor {
         debug!("Creating workspace at {}", self.chroot().display());
         std::fs::create_dir_all(self.chroot())
             .map_err(|e| ExecuteError::WorkspaceCreation(e.to_string()))?;
+        self.grant_access(&self.chroot())
+            .map_err(|e| ExecuteError::WorkspaceCreation(e.to_string()))?;
         Ok(())
     }
 
@@ -357,6 +449,18 @@ impl Executor {
         Ok(())
     }
 
+    /// Apply the Metrics configuration to the VM
+    #[instrument(skip_all, fields(id = %self.id))]
+    pub async fn configure_metrics(&self, metrics: Metrics) -> Result<(), ExecuteError> {
+        debug!("Configure metrics");
+        trace!("Metrics: {:#?}", metrics);
+        let json = serde_json::to_string(&metrics).map_err(ExecuteError::Serialize)?;
+
+        let url: hyper::Uri = Uri::new(self.chroot().join("firecracker.socket"), "/metrics").into();
+        self.send_request(url, Method::PUT, json).await?;
+        Ok(())
+    }
+
     /// Configure VSOCK
     #[instrument(skip_all, fields(id = %self.id))]
     pub async fn configure_vsock(&self, vsock: Vsock) -> Result<(), ExecuteError> {
@@ -368,6 +472,32 @@ impl Executor {
         self.send_request(url, Method::PUT, json).await?;
         Ok(())
     }
+
+    /// Configure MMDS, the network interfaces listed in the configuration
+    /// must already be configured on the VM
+    #[instrument(skip_all, fields(id = %self.id))]
+    pub async fn configure_mmds(&self, mmds: MmdsConfig) -> Result<(), ExecuteError> {
+        debug!("Configure mmds");
+        trace!("Mmds: {:#?}", mmds);
+        let json = serde_json::to_string(&mmds).map_err(ExecuteError::Serialize)?;
+
+        let url: hyper::Uri =
+            Uri::new(self.chroot().join("firecracker.socket"), "/mmds/config").into();
+        self.send_request(url, Method::PUT, json).await?;
+        Ok(())
+    }
+
+    /// Replace the content of the MMDS data store
+    #[instrument(skip_all, fields(id = %self.id))]
+    pub async fn put_mmds(&self, data: serde_json::Value) -> Result<(), ExecuteError> {
+        debug!("Put mmds data");
+        trace!("Mmds data: {:#?}", data);
+        let json = serde_json::to_string(&data).map_err(ExecuteError::Serialize)?;
+
+        let url: hyper::Uri = Uri::new(self.chroot().join("firecracker.socket"), "/mmds").into();
+        self.send_request(url, Method::PUT, json).await?;
+        Ok(())
+    }
 }
 
 /// Implementation of Executor for Firecracker, it will spawn the microVM using
@@ -399,6 +529,126 @@ impl Execute for FirecrackerExecutor {
     }
 }
 
+/// Implementation of Executor for the jailer, it will spawn the microVM using
+/// firecracker inside a chroot, with a dedicated uid/gid and cgroup.
+///
+/// The jailer lays out the workspace of each machine as
+/// `<chroot_base>/<exec_binary file name>/<id>/root`, which becomes `/` for
+/// the firecracker process.
+#[derive(Debug, Clone)]
+pub struct JailerExecutor {
+    /// Base folder of the chroots
+    pub chroot_base: String,
+    /// Path to the jailer binary
+    pub jailer_binary: PathBuf,
+    /// Path to the firecracker binary, copied in the chroot by the jailer
+    pub exec_binary: PathBuf,
+    /// User the firecracker process runs as
+    pub uid: u32,
+    /// Group the firecracker process runs as
+    pub gid: u32,
+    /// Cgroup under which the cgroup of the machine is created
+    pub parent_cgroup: Option<String>,
+    /// Cgroup values applied to the machine, e.g. `cpu.max=50000 100000`
+    pub cgroups: Vec<String>,
+}
+
+impl JailerExecutor {
+    /// Name of the firecracker binary, used by the jailer in the chroot layout
+    fn exec_name(&self) -> String {
+        self.exec_binary
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "firecracker".to_string())
+    }
+}
+
+impl Execute for JailerExecutor {
+    fn chroot(&self) -> PathBuf {
+        PathBuf::from(&self.chroot_base).join(self.exec_name())
+    }
+
+    fn root(&self, id: &str) -> PathBuf {
+        self.workspace(id).join("root")
+    }
+
+    fn resolve_path(&self, id: &str, path: &Path) -> PathBuf {
+        match path.strip_prefix(self.root(id)) {
+            Ok(relative) => Path::new("/").join(relative),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+
+    fn binary_args(&self, id: &str, args: &[String]) -> Vec<String> {
+        let mut jailer_args = vec![
+            "--id".to_string(),
+            id.to_string(),
+            "--exec-file".to_string(),
+            self.exec_binary.to_string_lossy().to_string(),
+            "--uid".to_string(),
+            self.uid.to_string(),
+            "--gid".to_string(),
+            self.gid.to_string(),
+            "--chroot-base-dir".to_string(),
+            self.chroot_base.clone(),
+            "--cgroup-version".to_string(),
+            "2".to_string(),
+        ];
+        if let Some(parent_cgroup) = &self.parent_cgroup {
+            jailer_args.push("--parent-cgroup".to_string());
+            jailer_args.push(parent_cgroup.clone());
+        }
+        for cgroup in &self.cgroups {
+            jailer_args.push("--cgroup".to_string());
+            jailer_args.push(cgroup.clone());
+        }
+        jailer_args.push("--".to_string());
+        jailer_args.extend_from_slice(args);
+        jailer_args
+    }
+
+    fn spawn_binary_child(&self, args: &Vec<String>) -> Result<Child, ExecuteError> {
+        let command = Command::new(&self.jailer_binary)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| ExecuteError::CommandExecution(e.to_string()))?;
+        Ok(command)
+    }
+
+    fn grant_access(&self, path: &Path) -> Result<(), ExecuteError> {
+        let status = std::process::Command::new("chown")
+            .arg(format!("{}:{}", self.uid, self.gid))
+            .arg(path)
+            .status()
+            .map_err(|e| ExecuteError::CommandExecution(e.to_string()))?;
+        match status.success() {
+            true => Ok(()),
+            false => Err(ExecuteError::CommandExecution(format!(
+                "Failed to change the owner of {}: {}",
+                path.display(),
+                status
+            ))),
+        }
+    }
+
+    fn cleanup(&self, id: &str) -> Result<(), ExecuteError> {
+        std::fs::remove_dir_all(self.workspace(id))
+            .map_err(|e| ExecuteError::WorkspaceDeletion(e.to_string()))?;
+        // The jailer creates a cgroup per machine, it can be removed once the process is gone
+        let cgroup = Path::new("/sys/fs/cgroup")
+            .join(self.parent_cgroup.as_deref().unwrap_or(&self.exec_name()))
+            .join(id);
+        if cgroup.exists() {
+            std::fs::remove_dir(&cgroup)
+                .map_err(|e| ExecuteError::WorkspaceDeletion(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -440,10 +690,83 @@ mod tests {
     fn test_no_executor_fails() {
         let machine = Executor {
             firecracker: None,
+            jailer: None,
             socket_process: None,
             id: "default".to_string(),
             client: Client::unix(),
         };
         machine.create_workspace().unwrap();
     }
+
+    fn jailer() -> JailerExecutor {
+        JailerExecutor {
+            chroot_base: "/srv/jailer".to_string(),
+            jailer_binary: PathBuf::from("/usr/bin/jailer"),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+            uid: 10001,
+            gid: 10001,
+            parent_cgroup: Some("spare".to_string()),
+            cgroups: vec!["cpu.max=50000 100000".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_jailer_paths() {
+        let machine = Executor::new_with_jailer(jailer()).with_id("vm1".to_string());
+        assert!(machine.is_jailed());
+        assert_eq!(
+            machine.chroot(),
+            PathBuf::from("/srv/jailer/firecracker/vm1/root")
+        );
+        assert_eq!(
+            machine.resolve_path(&machine.chroot().join("firecracker.socket")),
+            "/firecracker.socket"
+        );
+        // Paths outside of the chroot are left untouched
+        assert_eq!(machine.resolve_path(Path::new("/tmp/x")), "/tmp/x");
+
+        let machine = Executor::new_with_firecracker(FirecrackerExecutor {
+            chroot: "/tmp".to_string(),
+            exec_binary: PathBuf::from("/usr/bin/firecracker"),
+        })
+        .with_id("vm1".to_string());
+        assert!(!machine.is_jailed());
+        assert_eq!(machine.chroot(), PathBuf::from("/tmp/vm1"));
+        assert_eq!(
+            machine.resolve_path(&machine.chroot().join("firecracker.socket")),
+            "/tmp/vm1/firecracker.socket"
+        );
+    }
+
+    #[test]
+    fn test_jailer_args() {
+        let args = jailer().binary_args(
+            "vm1",
+            &["--api-sock".to_string(), "/firecracker.socket".to_string()],
+        );
+        assert_eq!(
+            args,
+            vec![
+                "--id",
+                "vm1",
+                "--exec-file",
+                "/usr/bin/firecracker",
+                "--uid",
+                "10001",
+                "--gid",
+                "10001",
+                "--chroot-base-dir",
+                "/srv/jailer",
+                "--cgroup-version",
+                "2",
+                "--parent-cgroup",
+                "spare",
+                "--cgroup",
+                "cpu.max=50000 100000",
+                "--",
+                "--api-sock",
+                "/firecracker.socket",
+            ]
+        );
+    }
 }
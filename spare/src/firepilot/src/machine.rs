@@ -27,11 +27,11 @@
 //! ```
 
 use std::{
-    fs::{copy, File},
-    path::{self, Path},
+    fs::{copy, hard_link, File},
+    path::{Path, PathBuf},
+    process::ExitStatus,
 };
 
-use tokio::fs;
 use tracing::{debug, info, instrument};
 
 use crate::{
@@ -42,7 +42,7 @@ use crate::{
 use firepilot_models::models::{
     logger::Level,
     vm::{State, Vm},
-    Logger, Vsock,
+    Logger, Metrics, Vsock,
 };
 
 #[derive(Debug)]
@@ -66,6 +66,7 @@ impl std::fmt::Display for FirepilotError {
         }
     }
 }
+impl std::error::Error for FirepilotError {}
 
 /// An instance of microVM which can be created and deployed easily
 #[derive(Debug)]
@@ -98,12 +99,25 @@ impl Machine {
         Ok(())
     }
 
+    /// Link `from` to `to`, falling back to a copy when they are not on the same filesystem
+    fn link<P, Q>(from: P, to: Q) -> Result<(), FirepilotError>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        match hard_link(&from, &to) {
+            Ok(_) => Ok(()),
+            Err(_) => Machine::copy(from, to),
+        }
+    }
+
     /// Setup an initial workspace to be working and to have the microVM
     /// starting as expected, it is going through a few steps. The workspace is
     /// configured when you are creating the executor object.
     ///
     /// 1. Setup the machine workspace from the executor
-    /// 2. Copy drives into the machine workspace (rootfs included)
+    /// 2. Copy drives into the machine workspace (rootfs included), jailed machines
+    ///    link the drives used in place as they cannot reach files outside of the chroot
     /// 3. Copy the kernel in the system workspace
     /// 4. Spawn the socket process
     /// 5. Configure the socket with given informations from the configuration
@@ -119,18 +133,25 @@ impl Machine {
         // Step 1. Setup the machine workspace from the executor
         self.executor.create_workspace()?;
 
-        // Step 3. Copy drives into the machine workspace
+        // Step 3. Copy drives into the machine workspace, unless they are used in place
         let machine = config.machine.unwrap();
-        let kernel = config.kernel.unwrap();
-        for drive in config.storage.iter_mut() {
-            let new_drive_path = self.executor.chroot().join(&drive.drive_id);
-            info!("Copy drive {} in the workspace", drive.drive_id);
-            debug!(
-                "Drive from {:?} to {:?}",
-                drive.path_on_host, new_drive_path
-            );
-            Machine::copy(&drive.path_on_host.as_ref().unwrap(), &new_drive_path)?;
-            drive.path_on_host = Some(new_drive_path.into_os_string().into_string().unwrap());
+        let mut kernel = config.kernel.unwrap();
+        if config.copy_drives || self.executor.is_jailed() {
+            for drive in config.storage.iter_mut() {
+                let new_drive_path = self.executor.chroot().join(&drive.drive_id);
+                info!("Copy drive {} in the workspace", drive.drive_id);
+                debug!(
+                    "Drive from {:?} to {:?}",
+                    drive.path_on_host, new_drive_path
+                );
+                if config.copy_drives {
+                    Machine::copy(drive.path_on_host.as_ref().unwrap(), &new_drive_path)?;
+                } else {
+                    Machine::link(drive.path_on_host.as_ref().unwrap(), &new_drive_path)?;
+                }
+                self.executor.grant_access(&new_drive_path)?;
+                drive.path_on_host = Some(self.executor.resolve_path(&new_drive_path));
+            }
         }
 
         // Step 4. Copy the kernel in the system workspace
@@ -140,18 +161,24 @@ impl Machine {
             "Kernel from {:?} to {:?}",
             kernel.kernel_image_path, kernel_path
         );
-        Machine::copy(kernel.kernel_image_path.clone(), kernel_path)?;
+        Machine::copy(kernel.kernel_image_path.clone(), &kernel_path)?;
+        self.executor.grant_access(&kernel_path)?;
+        kernel.kernel_image_path = self.executor.resolve_path(&kernel_path);
 
         if let Some(initrd) = kernel.initrd_path.clone() {
-            Machine::copy(initrd, self.executor.chroot().join("initrd"))?;
+            let initrd_path = self.executor.chroot().join("initrd");
+            Machine::copy(initrd, &initrd_path)?;
+            self.executor.grant_access(&initrd_path)?;
+            kernel.initrd_path = Some(self.executor.resolve_path(&initrd_path));
         }
 
         // TODO: Make possible to configure Logger
         let logger_path = self.executor.chroot().join("firecracker.log");
         let _logger_file = File::create(&logger_path).unwrap();
+        self.executor.grant_access(&logger_path)?;
         let logger = Logger {
             level: Some(Level::Info),
-            log_path: Some(logger_path.into_os_string().into_string().unwrap()),
+            log_path: Some(self.executor.resolve_path(&logger_path)),
             show_level: Some(true),
             show_log_origin: Some(true),
             module: None,
@@ -165,19 +192,30 @@ impl Machine {
         self.executor.configure_drives(config.storage).await?;
         self.executor.configure_boot_source(kernel).await?;
         self.executor.configure_network(config.interfaces).await?;
+        if let Some(mmds) = config.mmds {
+            self.executor.configure_mmds(mmds).await?;
+        }
+        if let Some(mmds_data) = config.mmds_data {
+            self.executor.put_mmds(mmds_data).await?;
+        }
         self.executor.configure_machine(machine).await?;
         self.executor.configure_logger(logger).await?;
+        if config.metrics {
+            let metrics_path = self.get_metrics_path();
+            File::create(&metrics_path).map_err(|e| {
+                FirepilotError::Setup(format!("Failed to create {:?}: {}", metrics_path, e))
+            })?;
+            self.executor.grant_access(&metrics_path)?;
+            let metrics = Metrics::new(self.executor.resolve_path(&metrics_path));
+            self.executor.configure_metrics(metrics).await?;
+        }
 
         // Configure VSOCK
         let vsock = Vsock {
             guest_cid: 3,
             uds_path: self
                 .executor
-                .chroot()
-                .join("vsock.sock")
-                .into_os_string()
-                .into_string()
-                .unwrap(),
+                .resolve_path(&self.executor.chroot().join("vsock.sock")),
             vsock_id: None,
         };
         self.executor.configure_vsock(vsock).await?;
@@ -187,9 +225,7 @@ impl Machine {
     /// Shutdown abruptly the socket process, if the VM was running it will stop it
     pub async fn kill(&mut self) -> Result<(), FirepilotError> {
         self.executor.destroy_socket().await?;
-        fs::remove_dir_all(path::Path::new(&self.executor.chroot()))
-            .await
-            .unwrap();
+        self.executor.remove_workspace()?;
         Ok(())
     }
 
@@ -205,6 +241,13 @@ impl Machine {
         Ok(())
     }
 
+    /// Ask a running VM to write its metrics, firecracker otherwise
+    /// writes them every 60 seconds
+    pub async fn flush_metrics(&self) -> Result<(), FirepilotError> {
+        self.executor.send_action(Action::FlushMetrics).await?;
+        Ok(())
+    }
+
     /// Pause a running VM
     pub async fn pause(&self) -> Result<(), FirepilotError> {
         self.executor.set_vm_state(Vm::new(State::Paused)).await?;
@@ -222,7 +265,24 @@ impl Machine {
         self.executor.is_running()
     }
 
-    /// Return vsock path
+    /// Return the path of the file the metrics are written to, as seen from the host.
+    /// Each flush appends a JSON object on its own line.
+    pub fn get_metrics_path(&self) -> PathBuf {
+        self.executor.chroot().join("metrics.json")
+    }
+
+    /// Wait for the firecracker process to exit, returning how it exited.
+    /// None if the process was never spawned.
+    pub async fn wait_exit(&mut self) -> Option<ExitStatus> {
+        self.executor.wait_exit().await
+    }
+
+    /// PID of the firecracker process, if it is running
+    pub fn pid(&self) -> Option<u32> {
+        self.executor.pid()
+    }
+
+    /// Return vsock path, as seen from the host
     pub fn get_vsock_path(&self) -> String {
         self.executor
             .chroot()
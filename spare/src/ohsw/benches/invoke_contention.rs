@@ -0,0 +1,108 @@
+//! Contention of the node under concurrent requests.
+//! Sends batches of concurrent requests to /invoke of a node running its instances in the
+//! mock execution environment, and takes and gives back the cpus of the orchestrator from
+//! many threads at once, as the workers of the server do on every request.
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use std::{sync::Arc, thread, time::Instant};
+
+use actix_web::{http::StatusCode, rt::System};
+use common::{request, TestNode, POSITION};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::future::join_all;
+use ohsw::{
+    execution_environment::mock::GuestBehavior,
+    orchestrator::{
+        global::{identity::Node, NeighborNodeStrategy},
+        Orchestrator,
+    },
+};
+
+/// Requests in flight at once
+const CONCURRENCY: [usize; 3] = [1, 16, 64];
+
+/// Threads taking the cpus of the orchestrator at once
+const THREADS: [usize; 3] = [1, 4, 16];
+
+/// Cpus taken and given back by each thread
+const ROUNDS: usize = 1000;
+
+/// An orchestrator admitting every request of the largest batch
+fn orchestrator() -> Orchestrator {
+    let cpus = num_cpus::get() as f64;
+    Orchestrator::with_strategy(
+        vec![],
+        Node::new("10.0.0.0:8085".to_string(), POSITION),
+        NeighborNodeStrategy::GeoDistance,
+    )
+    .with_cpu_overcommit(CONCURRENCY[CONCURRENCY.len() - 1] as f64 / cpus)
+}
+
+fn bench_concurrent_invokes(c: &mut Criterion) {
+    let system = System::new();
+    let node = system.block_on(async {
+        let mut node = TestNode::new(GuestBehavior::Echo, vec![]).await;
+        node.state.context.orchestrator = Arc::new(orchestrator());
+        node
+    });
+    // The memory of the host must not limit the batch
+    let mut invoke = request("hello", false);
+    invoke.memory = 1;
+
+    let mut group = c.benchmark_group("concurrent_invokes");
+    group.sample_size(10);
+    for concurrency in CONCURRENCY {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(concurrency),
+            &concurrency,
+            |b, &concurrency| {
+                b.iter_custom(|iters| {
+                    system.block_on(async {
+                        let start = Instant::now();
+                        for _ in 0..iters {
+                            let responses =
+                                join_all((0..concurrency).map(|_| node.invoke(&invoke))).await;
+                            assert!(responses
+                                .iter()
+                                .all(|(status, _)| *status == StatusCode::OK));
+                        }
+                        start.elapsed()
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_resources(c: &mut Criterion) {
+    let orchestrator = orchestrator();
+    let mut group = c.benchmark_group("acquire_release_1000");
+    for threads in THREADS {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter(|| {
+                    thread::scope(|scope| {
+                        for _ in 0..threads {
+                            scope.spawn(|| {
+                                for _ in 0..ROUNDS {
+                                    orchestrator
+                                        .check_and_acquire_resources(1, 0, false)
+                                        .unwrap();
+                                    orchestrator.release_resources(1, 0).unwrap();
+                                }
+                            });
+                        }
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_concurrent_invokes, bench_resources);
+criterion_main!(benches);
@@ -0,0 +1,59 @@
+//! Sorting of the neighbor nodes by distance.
+//! Compares a comparator that evaluates the haversine distance at every comparison
+//! with `sort_by_distance`, which computes it once per node.
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use ohsw::orchestrator::global::{
+    geo_distance::GeoDistance, NeighborNodeList, NeighborNodeStrategy, NeighborNodeType,
+};
+
+const NODES: usize = 1000;
+
+fn current() -> GeoDistance {
+    GeoDistance::new((45.4685, 9.1824), "current".to_string())
+}
+
+/// Nodes spread over a grid around the current node, inserted in a scrambled order
+fn node_list() -> NeighborNodeList {
+    let mut list = NeighborNodeList::new(NeighborNodeStrategy::GeoDistance);
+    for i in 0..NODES {
+        let j = (i * 7919) % NODES;
+        let position = (40.0 + (j % 40) as f64 * 0.25, 5.0 + (j / 40) as f64 * 0.25);
+        list.add_node(format!("10.0.{}.{}:8085", j / 256, j % 256), position);
+    }
+    list
+}
+
+/// The comparator used before the distances were cached
+fn sort_in_comparator(list: &mut NeighborNodeList, current: &mut GeoDistance) {
+    list.nodes.sort_by(|a, b| {
+        let distance = |node: &NeighborNodeType, current: &mut GeoDistance| match node {
+            NeighborNodeType::Distance(node) => node.distance(current),
+            NeighborNodeType::Latency(node) => node.distance(current),
+        };
+        let distance_a = distance(a, current);
+        let distance_b = distance(b, current);
+        distance_a.partial_cmp(&distance_b).unwrap()
+    });
+}
+
+fn bench_sort_by_distance(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sort_by_distance_1000");
+    group.bench_function("distance_in_comparator", |b| {
+        b.iter_batched(
+            node_list,
+            |mut list| sort_in_comparator(black_box(&mut list), &mut current()),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("cached_distance", |b| {
+        b.iter_batched(
+            node_list,
+            |mut list| black_box(&mut list).sort_by_distance(&mut current()),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_sort_by_distance);
+criterion_main!(benches);
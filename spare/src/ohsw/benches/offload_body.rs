@@ -0,0 +1,75 @@
+//! Body of an offloaded request.
+//! Compares encoding the request again, with the hops in the body, with forwarding the
+//! body as it was received, and prints the bytes each of them allocates.
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use actix_web::web::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ohsw::api::invoke::InvokeFunction;
+
+/// Size of the payload of the request
+const PAYLOAD: usize = 1024 * 1024;
+
+/// Allocator counting the bytes allocated
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// The body of a request as received on /invoke
+fn body() -> Bytes {
+    serde_json::to_vec(&serde_json::json!({
+        "function": "test",
+        "image": "image",
+        "vcpus": 1,
+        "memory": 128,
+        "payload": "x".repeat(PAYLOAD),
+        "emergency": false,
+        "hops": 1,
+    }))
+    .unwrap()
+    .into()
+}
+
+/// Bytes allocated by a run of `f`
+fn allocated<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    black_box(f());
+    ALLOCATED.load(Ordering::Relaxed) - before
+}
+
+fn bench_offload_body(c: &mut Criterion) {
+    let body = body();
+    let data: InvokeFunction = serde_json::from_slice(&body).unwrap();
+    println!(
+        "Allocated per offload: re-encoded {} bytes, raw {} bytes",
+        allocated(|| data.forwarded_body()),
+        allocated(|| body.clone())
+    );
+
+    let mut group = c.benchmark_group("offload_body_1MiB");
+    group.bench_function("re_encoded", |b| {
+        b.iter(|| black_box(&data).forwarded_body())
+    });
+    group.bench_function("raw", |b| b.iter(|| black_box(&body).clone()));
+    group.finish();
+}
+
+criterion_group!(benches, bench_offload_body);
+criterion_main!(benches);
@@ -0,0 +1,34 @@
+//! Nearest neighbor lookup over a large set of nodes.
+//! Compares the linear scan with the spatial index behind `NeighborNodeList::nearest_k`.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ohsw::orchestrator::global::{NeighborNode, NeighborNodeList, NeighborNodeStrategy};
+
+const NODES: usize = 1000;
+const K: usize = 8;
+
+/// Nodes spread over a grid, inserted in a scrambled order
+fn node_list(threshold: usize) -> NeighborNodeList {
+    let mut list =
+        NeighborNodeList::new(NeighborNodeStrategy::GeoDistance).with_index_threshold(threshold);
+    for i in 0..NODES {
+        let j = (i * 7919) % NODES;
+        let position = (40.0 + (j % 40) as f64 * 0.25, 5.0 + (j / 40) as f64 * 0.25);
+        list.add_node(format!("10.0.{}.{}:8085", j / 256, j % 256), position);
+    }
+    list
+}
+
+fn bench_nearest_k(c: &mut Criterion) {
+    let position = (45.4685, 9.1824);
+    let mut group = c.benchmark_group("nearest_8_of_1000");
+    for (name, threshold) in [("linear", usize::MAX), ("indexed", 0)] {
+        let list = node_list(threshold);
+        group.bench_function(name, |b| {
+            b.iter(|| black_box(list.nearest_k(black_box(position), K, |node| !node.emergency())))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_nearest_k);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+//! Hand-off of a payload to the vsock of an instance.
+//! Compares copying the length prefix and the payload into a single buffer before writing
+//! it with writing both in vectored writes, for a 10 MB payload. Both go through
+//! `write_all_vectored`, only the copy differs. The guest is a thread reading the other end
+//! of the socket.
+use std::{io::IoSlice, io::Read, thread};
+
+use actix_web::rt::{net::UnixStream, System};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ohsw::utils::socket::write_all_vectored;
+
+/// Size of the payload
+const PAYLOAD: usize = 10 * 1024 * 1024;
+
+/// A stream of the node, with the other end read by a thread until it is closed
+fn guest() -> (UnixStream, thread::JoinHandle<usize>) {
+    let (node, mut guest) = std::os::unix::net::UnixStream::pair().unwrap();
+    node.set_nonblocking(true).unwrap();
+    let reader = thread::spawn(move || {
+        let mut buf = vec![0; 64 * 1024];
+        let mut received = 0;
+        loop {
+            match guest.read(&mut buf).unwrap() {
+                0 => return received,
+                n => received += n,
+            }
+        }
+    });
+    (UnixStream::from_std(node).unwrap(), reader)
+}
+
+fn bench_vsock_payload(c: &mut Criterion) {
+    let system = System::new();
+    let payload = vec![0x5a; PAYLOAD];
+    let len = (PAYLOAD as u64).to_be_bytes();
+
+    let mut group = c.benchmark_group("vsock_payload_10MB");
+    group.sample_size(20);
+    group.bench_function("concatenated", |b| {
+        b.iter(|| {
+            let (mut node, reader) = system.block_on(async { guest() });
+            system.block_on(async {
+                let mut buf = Vec::with_capacity(8 + PAYLOAD);
+                buf.extend_from_slice(&len);
+                buf.extend_from_slice(black_box(&payload));
+                write_all_vectored(&mut node, &[IoSlice::new(&buf)], 10_000)
+                    .await
+                    .unwrap();
+            });
+            drop(node);
+            assert_eq!(reader.join().unwrap(), 8 + PAYLOAD);
+        })
+    });
+    group.bench_function("vectored", |b| {
+        b.iter(|| {
+            let (mut node, reader) = system.block_on(async { guest() });
+            system.block_on(async {
+                let bufs = [IoSlice::new(&len), IoSlice::new(black_box(&payload))];
+                write_all_vectored(&mut node, &bufs, 10_000).await.unwrap();
+            });
+            drop(node);
+            assert_eq!(reader.join().unwrap(), 8 + PAYLOAD);
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_vsock_payload);
+criterion_main!(benches);
@@ -0,0 +1,26 @@
+// Record the commit the node is built from, stored with each run of the node.
+// A build outside of a checkout can pass it in SPARE_GIT_HASH instead.
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SPARE_GIT_HASH");
+    if std::env::var_os("SPARE_GIT_HASH").is_some() {
+        return;
+    }
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    // Build again when the checked out commit changes
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo:rerun-if-changed={git_dir}/HEAD");
+        println!("cargo:rerun-if-changed={git_dir}/refs");
+    }
+    if let Some(hash) = git(&["rev-parse", "--short=12", "HEAD"]) {
+        println!("cargo:rustc-env=SPARE_GIT_HASH={hash}");
+    }
+}
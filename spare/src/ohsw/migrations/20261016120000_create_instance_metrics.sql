@@ -0,0 +1,11 @@
+-- Summary of the Firecracker metrics of each instance, collected on teardown
+CREATE TABLE IF NOT EXISTS instance_metrics (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    instance_id INTEGER NOT NULL REFERENCES instances(id) ON DELETE CASCADE,
+    net_rx_bytes INTEGER NOT NULL,
+    net_tx_bytes INTEGER NOT NULL,
+    block_read_ops INTEGER NOT NULL,
+    block_write_ops INTEGER NOT NULL,
+    vcpu_exits INTEGER NOT NULL,
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
@@ -0,0 +1,11 @@
+-- Emergencies seen by the node, to know when it entered and exited emergency mode
+CREATE TABLE IF NOT EXISTS emergency_events (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    event TEXT NOT NULL, -- start, stop or expired
+    emergency_id TEXT,
+    position_x REAL NOT NULL,
+    position_y REAL NOT NULL,
+    radius REAL NOT NULL,
+    inside BOOLEAN NOT NULL, -- Whether the node was in the emergency zone
+    created_at DATETIME DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
@@ -0,0 +1,11 @@
+-- Every request received on /invoke, with its outcome, including the offloaded ones
+CREATE TABLE IF NOT EXISTS requests (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    function TEXT NOT NULL,
+    outcome TEXT NOT NULL, -- served_locally, offloaded, rejected or failed
+    offloaded_to TEXT,
+    hops INTEGER NOT NULL,
+    received_at DATETIME NOT NULL,
+    completed_at DATETIME NOT NULL
+);
+CREATE INDEX IF NOT EXISTS requests_received_at ON requests (received_at);
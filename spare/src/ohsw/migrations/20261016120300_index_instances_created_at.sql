@@ -0,0 +1,2 @@
+-- The exports and the stats filter the instances by creation time
+CREATE INDEX IF NOT EXISTS instances_created_at ON instances (created_at);
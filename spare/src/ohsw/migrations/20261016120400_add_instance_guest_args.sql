@@ -0,0 +1,3 @@
+-- Environment variables and arguments the instance was booted with, as JSON
+ALTER TABLE instances ADD COLUMN env TEXT;
+ALTER TABLE instances ADD COLUMN args TEXT;
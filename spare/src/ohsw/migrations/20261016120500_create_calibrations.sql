@@ -0,0 +1,14 @@
+-- Timings of the instances booted by /calibrate, one row per iteration of a run
+CREATE TABLE IF NOT EXISTS calibrations (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    run_id TEXT NOT NULL,
+    image TEXT NOT NULL,
+    iteration INTEGER NOT NULL,
+    create_us INTEGER, -- NULL if the iteration failed
+    boot_us INTEGER,
+    handshake_us INTEGER,
+    execute_us INTEGER,
+    error TEXT,
+    created_at DATETIME NOT NULL
+);
+CREATE INDEX IF NOT EXISTS calibrations_run_id ON calibrations (run_id);
@@ -0,0 +1,10 @@
+-- Limits on the resources used at the same time by the requests of an API key
+CREATE TABLE IF NOT EXISTS quotas (
+    api_key TEXT NOT NULL PRIMARY KEY,
+    max_vcpus INTEGER NOT NULL,
+    max_memory INTEGER NOT NULL, -- MiB
+    max_instances INTEGER NOT NULL,
+    updated_at DATETIME NOT NULL
+);
+-- API key of the request the instance was started for
+ALTER TABLE instances ADD COLUMN api_key TEXT;
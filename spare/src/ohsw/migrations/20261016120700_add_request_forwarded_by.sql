@@ -0,0 +1,2 @@
+-- Verified identity of the node that forwarded the request, from its client certificate
+ALTER TABLE requests ADD COLUMN forwarded_by TEXT;
@@ -0,0 +1,13 @@
+-- Invocations run by the node on a schedule
+CREATE TABLE IF NOT EXISTS schedules (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    function TEXT NOT NULL,
+    request TEXT NOT NULL, -- The invocation, as sent to /invoke
+    cron_expr TEXT, -- NULL if the schedule runs at an interval
+    interval INTEGER, -- Seconds, NULL if the schedule follows a cron expression
+    paused BOOLEAN NOT NULL DEFAULT 0,
+    next_run DATETIME NOT NULL,
+    last_run DATETIME,
+    last_status TEXT,
+    created_at DATETIME NOT NULL
+);
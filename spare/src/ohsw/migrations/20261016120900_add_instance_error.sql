@@ -0,0 +1,2 @@
+-- Why an instance failed, shown in the diagnostics bundle
+ALTER TABLE instances ADD COLUMN error TEXT;
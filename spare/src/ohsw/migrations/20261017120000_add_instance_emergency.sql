@@ -0,0 +1,2 @@
+-- Whether the instance was started for an emergency request, to split the stats by it
+ALTER TABLE instances ADD COLUMN emergency BOOLEAN NOT NULL DEFAULT FALSE;
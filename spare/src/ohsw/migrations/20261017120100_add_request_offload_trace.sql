@@ -0,0 +1,2 @@
+-- Identifier of the trace of the offload decisions of the request, when they are recorded
+ALTER TABLE requests ADD COLUMN offload_trace_id INTEGER;
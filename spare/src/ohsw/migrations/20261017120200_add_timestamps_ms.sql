@@ -0,0 +1,19 @@
+-- The timestamps as unix milliseconds in UTC, compared with the windows of the stats.
+-- Computed from the stored text, so the existing rows have them too; an offset written
+-- with a timestamp is applied, a timestamp without one is UTC as the node writes them.
+-- The digits past the millisecond are cut before parsing, as SQLite would round them:
+-- a row is in the same millisecond as by chrono, so within the window ending at it.
+ALTER TABLE instances ADD COLUMN created_at_ms INTEGER
+    GENERATED ALWAYS AS (CAST(ROUND(unixepoch(
+        CASE WHEN substr(created_at, 20, 5) GLOB '.[0-9][0-9][0-9][0-9]'
+            THEN substr(created_at, 1, 23) || ltrim(substr(created_at, 24), '0123456789')
+            ELSE created_at END,
+        'subsec') * 1000) AS INTEGER)) VIRTUAL;
+CREATE INDEX IF NOT EXISTS instances_created_at_ms ON instances (created_at_ms);
+ALTER TABLE requests ADD COLUMN received_at_ms INTEGER
+    GENERATED ALWAYS AS (CAST(ROUND(unixepoch(
+        CASE WHEN substr(received_at, 20, 5) GLOB '.[0-9][0-9][0-9][0-9]'
+            THEN substr(received_at, 1, 23) || ltrim(substr(received_at, 24), '0123456789')
+            ELSE received_at END,
+        'subsec') * 1000) AS INTEGER)) VIRTUAL;
+CREATE INDEX IF NOT EXISTS requests_received_at_ms ON requests (received_at_ms);
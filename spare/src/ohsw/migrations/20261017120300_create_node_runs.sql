@@ -0,0 +1,18 @@
+-- Each start of the node, with the configuration it resolved, for auditing the results.
+-- The instances and the requests are stamped with the run that recorded them.
+CREATE TABLE IF NOT EXISTS node_runs (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    started_at DATETIME NOT NULL,
+    address TEXT NOT NULL,
+    strategy TEXT NOT NULL,
+    runtime TEXT NOT NULL,
+    cidr TEXT NOT NULL,
+    limits TEXT NOT NULL, -- JSON object
+    firecracker TEXT, -- NULL when the instances are simulated
+    kernel_sha256 TEXT,
+    version TEXT NOT NULL,
+    git_hash TEXT,
+    config TEXT NOT NULL -- the arguments, redacted as in the diagnostics bundle
+);
+ALTER TABLE instances ADD COLUMN run_id INTEGER REFERENCES node_runs (id);
+ALTER TABLE requests ADD COLUMN run_id INTEGER REFERENCES node_runs (id);
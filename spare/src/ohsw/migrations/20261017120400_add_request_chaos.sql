@@ -0,0 +1,2 @@
+-- Whether a chaos injection touched the request, so that the analysis can leave it out
+ALTER TABLE requests ADD COLUMN chaos BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,2 @@
+-- Which attempt at starting an instance for its request it was, to tell the retries apart
+ALTER TABLE instances ADD COLUMN attempt INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,2 @@
+-- Vertices of the emergency zone as a JSON array, NULL for a circle
+ALTER TABLE emergency_events ADD COLUMN polygon TEXT;
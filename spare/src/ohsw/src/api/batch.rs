@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of functions in a batch
+pub const MAX_BATCH_SIZE: usize = 64;
+
+/// Query of the invocation of a batch
+#[derive(Deserialize, Default)]
+pub struct BatchQuery {
+    // Stream the results as ndjson, as soon as each function completes
+    #[serde(default)]
+    pub stream: bool,
+}
+
+/// Result of a function of a batch
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BatchItemResult {
+    // The position of the function in the batch
+    pub index: usize,
+    // The HTTP status the function would have been answered with by /invoke
+    pub status: u16,
+    // How the function was handled: served_locally, offloaded, rejected, failed,
+    // or replayed for a request already run with the same idempotency key
+    pub outcome: String,
+    // The node the function was offloaded to
+    pub offloaded_to: Option<String>,
+    // The local instance that ran the function
+    pub instance_id: Option<i64>,
+    // The output of the function, if it succeeded
+    pub output: Option<String>,
+    // The error, if it failed
+    pub error: Option<String>,
+}
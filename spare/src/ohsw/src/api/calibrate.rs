@@ -0,0 +1,198 @@
+//! Calibration of the cold start of the node.
+//! A calibration run boots a few instances of an image back-to-back and measures how
+//! long each phase of their cold start takes, so the cost of serving a request locally
+//! can be compared with the cost of offloading it.
+use serde::{Deserialize, Serialize};
+
+use crate::db::models::Calibration;
+
+/// Number of instances booted when the request does not say
+pub const DEFAULT_CALIBRATION_ITERATIONS: usize = 5;
+/// Largest number of instances a run can boot
+pub const MAX_CALIBRATION_ITERATIONS: usize = 50;
+
+fn default_iterations() -> usize {
+    DEFAULT_CALIBRATION_ITERATIONS
+}
+
+fn default_vcpus() -> i32 {
+    1
+}
+
+fn default_memory() -> i32 {
+    128
+}
+
+/// Request of a calibration run
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Calibrate {
+    // The image booted at every iteration
+    pub image: String,
+    // The number of instances to boot, one after the other
+    #[serde(default = "default_iterations")]
+    pub iterations: usize,
+    // The resources of each instance
+    #[serde(default = "default_vcpus")]
+    pub vcpus: i32,
+    #[serde(default = "default_memory")]
+    pub memory: i32,
+}
+
+impl Calibrate {
+    /// Check that the run is bounded and its instances can be booted
+    pub fn validate(&self) -> Result<(), String> {
+        if self.iterations == 0 || self.iterations > MAX_CALIBRATION_ITERATIONS {
+            return Err(format!(
+                "Iterations must be between 1 and {}",
+                MAX_CALIBRATION_ITERATIONS
+            ));
+        }
+        if self.vcpus <= 0 || self.memory <= 0 {
+            return Err("vcpus and memory must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Statistics of a phase over the successful iterations of a run (in microseconds)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PhaseStats {
+    pub min: i64,
+    pub mean: f64,
+    pub max: i64,
+}
+
+impl PhaseStats {
+    /// Compute the statistics of the given samples, None if there are none
+    fn of(samples: impl Iterator<Item = i64>) -> Option<Self> {
+        let samples: Vec<i64> = samples.collect();
+        Some(PhaseStats {
+            min: *samples.iter().min()?,
+            mean: samples.iter().sum::<i64>() as f64 / samples.len() as f64,
+            max: *samples.iter().max()?,
+        })
+    }
+}
+
+/// Summary of a calibration run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CalibrationSummary {
+    pub run_id: String,
+    pub image: String,
+    // The number of instances booted
+    pub iterations: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    // The statistics of each phase, None if no iteration succeeded
+    pub create: Option<PhaseStats>,
+    pub boot: Option<PhaseStats>,
+    pub handshake: Option<PhaseStats>,
+    pub execute: Option<PhaseStats>,
+}
+
+impl CalibrationSummary {
+    /// Summarize the iterations of a run
+    pub fn new(run_id: String, image: String, iterations: &[Calibration]) -> Self {
+        let succeeded: Vec<&Calibration> =
+            iterations.iter().filter(|i| i.error.is_none()).collect();
+        let phase = |f: fn(&Calibration) -> Option<i64>| {
+            PhaseStats::of(succeeded.iter().filter_map(|i| f(i)))
+        };
+        CalibrationSummary {
+            run_id,
+            image,
+            iterations: iterations.len(),
+            succeeded: succeeded.len(),
+            failed: iterations.len() - succeeded.len(),
+            create: phase(|i| i.create_us),
+            boot: phase(|i| i.boot_us),
+            handshake: phase(|i| i.handshake_us),
+            execute: phase(|i| i.execute_us),
+        }
+    }
+
+    /// Mean time to serve a request on a new instance (in ms), None if no iteration succeeded
+    pub fn service_time(&self) -> Option<f64> {
+        let phases = [self.create, self.boot, self.handshake, self.execute];
+        let total: f64 = phases
+            .iter()
+            .map(|phase| phase.map(|phase| phase.mean))
+            .sum::<Option<f64>>()?;
+        Some(total / 1000.0)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::execution_environment::metrics::ColdStartTimings;
+
+    fn iteration(iteration: i64, create_ms: u64) -> Calibration {
+        let timings = ColdStartTimings {
+            create: Duration::from_millis(create_ms),
+            boot: Duration::from_millis(2),
+            handshake: Duration::from_millis(1),
+            execute: Duration::from_millis(1),
+        };
+        Calibration::new(
+            "run".to_string(),
+            "image".to_string(),
+            iteration,
+            Ok(timings),
+        )
+    }
+
+    #[test]
+    fn test_validate() {
+        let calibrate: Calibrate = serde_json::from_str(r#"{"image":"fn.img"}"#).unwrap();
+        assert_eq!(calibrate.iterations, DEFAULT_CALIBRATION_ITERATIONS);
+        assert!(calibrate.validate().is_ok());
+
+        for iterations in [0, MAX_CALIBRATION_ITERATIONS + 1] {
+            let calibrate = Calibrate {
+                iterations,
+                ..calibrate.clone()
+            };
+            assert!(calibrate.validate().is_err());
+        }
+        let calibrate = Calibrate {
+            vcpus: 0,
+            ..calibrate
+        };
+        assert!(calibrate.validate().is_err());
+    }
+
+    #[test]
+    fn test_summary() {
+        let failed = Calibration::new(
+            "run".to_string(),
+            "image".to_string(),
+            2,
+            Err("Timed out".to_string()),
+        );
+        let iterations = vec![iteration(0, 10), iteration(1, 20), failed.clone()];
+        let summary = CalibrationSummary::new("run".to_string(), "image".to_string(), &iterations);
+        assert_eq!(summary.iterations, 3);
+        assert_eq!(summary.succeeded, 2);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(
+            summary.create,
+            Some(PhaseStats {
+                min: 10_000,
+                mean: 15_000.0,
+                max: 20_000,
+            })
+        );
+        assert_eq!(summary.boot.unwrap().mean, 2_000.0);
+        assert_eq!(summary.service_time(), Some(19.0));
+
+        // Nothing to summarize when every iteration failed
+        let summary = CalibrationSummary::new("run".to_string(), "image".to_string(), &[failed]);
+        assert_eq!(summary.failed, 1);
+        assert_eq!(summary.create, None);
+        assert_eq!(summary.service_time(), None);
+    }
+}
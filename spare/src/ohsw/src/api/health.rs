@@ -0,0 +1,34 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    orchestrator::{
+        drain::DrainState,
+        readiness::{PhaseChange, ReadinessPhase},
+    },
+    utils::supervisor::TaskHealth,
+};
+
+/// Health of the node
+#[derive(Serialize, Deserialize)]
+pub struct Health {
+    // Whether the node takes new requests or is being drained
+    pub state: DrainState,
+    // The number of local instances running
+    pub running_instances: usize,
+    // Whether the node is in the emergency area
+    pub emergency: bool,
+    // Whether a task of the node failed and waits to be started again, e.g. the emergency
+    // controller: the node still serves requests, but may miss the emergencies meanwhile
+    #[serde(default)]
+    pub degraded: bool,
+    // The supervised tasks of the node
+    #[serde(default)]
+    pub tasks: Vec<TaskHealth>,
+    // The phase of the node: it takes requests once ready, ready for the nodes that do
+    // not tell
+    #[serde(default)]
+    pub phase: ReadinessPhase,
+    // The phases the node entered since it started, and when
+    #[serde(default)]
+    pub phase_changes: Vec<PhaseChange>,
+}
@@ -1,13 +1,78 @@
+use std::{collections::HashMap, time::Instant};
+
+use actix_web::{
+    http::header::{HeaderMap, HeaderValue},
+    web::Bytes,
+    HttpResponse,
+};
+
+use super::rate_limits::RateLimits;
+use crate::execution_environment::boot_args::GuestArgs;
+
+/// Header set by a node forwarding a request to another node, with its address
+pub const FORWARDED_BY_HEADER: &str = "X-Spare-Forwarded-By";
+
+/// Header set by a node forwarding a request to another node, with the hops of the
+/// request, so its body can be forwarded as it was received. It wins over the body.
+/// Set on the answer too, with the hops the request took to the node that ran it.
+pub const HOPS_HEADER: &str = "X-Spare-Hops";
+
+/// Header set by a node forwarding a request to another node, with the time left before
+/// the deadline of the request (in ms). It wins over the timeout in the body.
+pub const BUDGET_HEADER: &str = "X-Spare-Budget";
+
+/// Header set on the answer by the node that ran the function, with its id, or its
+/// address when it has none
+pub const SERVED_BY_HEADER: &str = "X-Spare-Served-By";
+
+/// Header set on the answer by the node that ran the function, with the id of the instance
+pub const INSTANCE_ID_HEADER: &str = "X-Spare-Instance-Id";
+
+/// Headers of the answer set by the node that ran the function, passed through unchanged
+/// by the nodes forwarding it back
+pub const SERVED_HEADERS: [&str; 3] = [SERVED_BY_HEADER, HOPS_HEADER, INSTANCE_ID_HEADER];
+
+/// Tell the client where its request ran, on the answer of the node that ran it
+pub fn set_served(response: &mut HttpResponse, by: &str, hops: i32, instance_id: i64) {
+    let headers = response.headers_mut();
+    if let Ok(by) = HeaderValue::from_str(by) {
+        headers.insert(SERVED_BY_HEADER.parse().unwrap(), by);
+    }
+    headers.insert(HOPS_HEADER.parse().unwrap(), HeaderValue::from(hops));
+    headers.insert(
+        INSTANCE_ID_HEADER.parse().unwrap(),
+        HeaderValue::from(instance_id),
+    );
+}
+
+/// Copy where a request ran from the answer of the node it was forwarded to
+pub fn copy_served(from: &HeaderMap, response: &mut HttpResponse) {
+    for name in SERVED_HEADERS {
+        if let Some(value) = from.get(name) {
+            response
+                .headers_mut()
+                .insert(name.parse().unwrap(), value.clone());
+        }
+    }
+}
+
 /// Define a struct to represent the invocation of a function
 #[derive(serde::Deserialize, serde::Serialize, Clone)]
 pub struct InvokeFunction {
     // The name of the function to be invoked
     pub function: String,
-    // The image associated with the function
+    // The image associated with the function: a local path, a file:// or an http(s):// URL
     pub image: String,
-    // The number of virtual CPUs allocated for the function
+    // The expected SHA-256 digest of the image, verified when the image is fetched
+    #[serde(default)]
+    pub image_digest: Option<String>,
+    // The number of virtual CPUs allocated for the function, 0 or left out for the
+    // default of the function
+    #[serde(default)]
     pub vcpus: i32,
-    // The amount of memory allocated for the function
+    // The amount of memory allocated for the function, 0 or left out for the default
+    // of the function
+    #[serde(default)]
     pub memory: i32,
     // The payload to be passed to the function
     pub payload: Option<String>,
@@ -15,4 +80,114 @@ pub struct InvokeFunction {
     pub emergency: bool,
     // The number of hops the invocation has taken
     pub hops: i32,
+    // The channel used to deliver the payload to the guest
+    #[serde(default)]
+    pub payload_via: PayloadVia,
+    // The I/O rate limits of the instance, unset limits fall back to the node defaults
+    #[serde(default)]
+    pub rate_limits: Option<RateLimits>,
+    // Key identifying the request across retries, forwarded when the request is offloaded
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    // The environment variables of the function, passed on the kernel command line
+    #[serde(default)]
+    pub env: Option<HashMap<String, String>>,
+    // The arguments of the function, passed on the kernel command line
+    #[serde(default)]
+    pub args: Option<Vec<String>>,
+    // The API key the instance is accounted to, forwarded when the request is offloaded
+    #[serde(default)]
+    pub api_key: Option<String>,
+    // Whether the output of the function compresses well, false for e.g. images or videos
+    #[serde(default)]
+    pub compressible: Option<bool>,
+    // The time the client waits for the answer (in ms), the nodes shed the request when
+    // they cannot answer it in time
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+    // When the answer is due, by the clock of this node: set on arrival from the timeout
+    // or `BUDGET_HEADER`, never sent
+    #[serde(skip)]
+    pub deadline: Option<Instant>,
+}
+
+impl InvokeFunction {
+    /// Get the environment variables and the arguments to boot the instance with
+    pub fn guest_args(&self) -> GuestArgs {
+        GuestArgs::new(self.env.as_ref(), self.args.as_deref())
+    }
+
+    /// Get the request to send to the next node when offloading this one
+    pub fn forwarded(&self) -> Self {
+        Self {
+            hops: self.hops + 1,
+            ..self.clone()
+        }
+    }
+
+    /// Encode the request to send to the next node, with the hops counted in the body
+    /// for the nodes that do not read `HOPS_HEADER`
+    pub fn forwarded_body(&self) -> Bytes {
+        serde_json::to_vec(&self.forwarded()).unwrap().into()
+    }
+}
+
+/// Invocation of a function with a binary payload: the parameters are given in the query
+/// string of `/invoke/{function}`, and the payload is the body of the request
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug)]
+pub struct InvokeBinary {
+    // The image associated with the function
+    pub image: String,
+    // The expected SHA-256 digest of the image, verified when the image is fetched
+    #[serde(default)]
+    pub image_digest: Option<String>,
+    // The number of virtual CPUs allocated for the function, left out for the default
+    #[serde(default)]
+    pub vcpus: i32,
+    // The amount of memory allocated for the function, left out for the default
+    #[serde(default)]
+    pub memory: i32,
+    // A flag indicating if the invocation is an emergency
+    #[serde(default)]
+    pub emergency: bool,
+    // Whether the output of the function compresses well
+    #[serde(default)]
+    pub compressible: Option<bool>,
+}
+
+impl InvokeBinary {
+    /// Get the invocation of `function`, without the payload, which is sent apart
+    pub fn invocation(&self, function: String) -> InvokeFunction {
+        InvokeFunction {
+            function,
+            image: self.image.clone(),
+            image_digest: self.image_digest.clone(),
+            vcpus: self.vcpus,
+            memory: self.memory,
+            payload: None,
+            emergency: self.emergency,
+            hops: 0,
+            payload_via: PayloadVia::Vsock,
+            rate_limits: None,
+            idempotency_key: None,
+            env: None,
+            args: None,
+            api_key: None,
+            compressible: self.compressible,
+            timeout_ms: None,
+            deadline: None,
+        }
+    }
+}
+
+/// Channel used to deliver the payload to the guest
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadVia {
+    /// The payload is written on the vsock after the guest is ready
+    #[default]
+    Vsock,
+    /// The payload is exposed through the Firecracker metadata service (MMDS).
+    /// Suited for small, configuration-style payloads.
+    Mmds,
 }
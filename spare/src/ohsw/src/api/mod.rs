@@ -1,4 +1,11 @@
 //! API module for SPARE project.
+pub mod batch;
+pub mod calibrate;
+pub mod health;
 pub mod invoke;
 pub mod payload;
+pub mod pipeline;
+pub mod quota;
+pub mod rate_limits;
 pub mod resources;
+pub mod schedule;
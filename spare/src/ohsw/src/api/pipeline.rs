@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    invoke::{InvokeFunction, PayloadVia},
+    rate_limits::RateLimits,
+};
+
+/// Maximum number of steps in a pipeline
+pub const MAX_PIPELINE_STEPS: usize = 16;
+
+/// Invocation of a pipeline: the output of each step is the payload of the next one
+#[derive(Deserialize, Serialize, Clone)]
+pub struct InvokePipeline {
+    // The steps, run one after the other
+    pub pipeline: Vec<InvokeStep>,
+    // The payload of the first step
+    #[serde(default)]
+    pub payload: Option<String>,
+    // A flag indicating if the invocation is an emergency, for every step
+    #[serde(default)]
+    pub emergency: bool,
+    // The API key the instances of the steps are accounted to
+    #[serde(default)]
+    pub api_key: Option<String>,
+}
+
+/// A function of a pipeline
+#[derive(Deserialize, Serialize, Clone)]
+pub struct InvokeStep {
+    // The name of the function to be invoked
+    pub function: String,
+    // The image associated with the function
+    pub image: String,
+    // The expected SHA-256 digest of the image, verified when the image is fetched
+    #[serde(default)]
+    pub image_digest: Option<String>,
+    // The number of virtual CPUs allocated for the function, left out for the default
+    #[serde(default)]
+    pub vcpus: i32,
+    // The amount of memory allocated for the function, left out for the default
+    #[serde(default)]
+    pub memory: i32,
+    // The I/O rate limits of the instance, unset limits fall back to the node defaults
+    #[serde(default)]
+    pub rate_limits: Option<RateLimits>,
+    // The environment variables of the function
+    #[serde(default)]
+    pub env: Option<HashMap<String, String>>,
+    // The arguments of the function
+    #[serde(default)]
+    pub args: Option<Vec<String>>,
+    // The time the step must answer in, from its start (in milliseconds)
+    #[serde(default)]
+    pub timeout_ms: Option<u64>,
+}
+
+impl InvokePipeline {
+    /// Get the invocation of the step `index`, with the payload it is given
+    pub fn invocation(&self, index: usize, payload: Option<String>) -> InvokeFunction {
+        let step = &self.pipeline[index];
+        InvokeFunction {
+            function: step.function.clone(),
+            image: step.image.clone(),
+            image_digest: step.image_digest.clone(),
+            vcpus: step.vcpus,
+            memory: step.memory,
+            payload,
+            emergency: self.emergency,
+            hops: 0,
+            payload_via: PayloadVia::Vsock,
+            rate_limits: step.rate_limits,
+            idempotency_key: None,
+            env: step.env.clone(),
+            args: step.args.clone(),
+            api_key: self.api_key.clone(),
+            compressible: None,
+            timeout_ms: step.timeout_ms,
+            deadline: None,
+        }
+    }
+}
+
+/// How a step of a pipeline ran
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StepResult {
+    // The position of the step in the pipeline
+    pub index: usize,
+    pub function: String,
+    // The HTTP status the step would have been answered with by /invoke
+    pub status: u16,
+    // How the step was handled: served_locally, offloaded, rejected or failed
+    pub outcome: String,
+    // The node the step was offloaded to
+    pub offloaded_to: Option<String>,
+    // Time spent on the step, from its admission to its output (in milliseconds)
+    pub duration_ms: u64,
+}
+
+/// Result of a pipeline
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PipelineResult {
+    // The steps that ran, the last one being the one that failed if any
+    pub steps: Vec<StepResult>,
+    // The output of the last step, if every step succeeded
+    pub output: Option<String>,
+    // The step that failed, aborting the pipeline
+    pub failed_step: Option<usize>,
+    // The error of the step that failed
+    pub error: Option<String>,
+}
@@ -0,0 +1,53 @@
+//! Quotas of the API keys.
+//! A quota limits the resources used at the same time by the instances started for the
+//! requests of an API key, so a single tenant cannot take a whole node.
+use serde::{Deserialize, Serialize};
+
+/// Header carrying the API key of a request
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Limits of a quota
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaLimits {
+    // The vcpus of the running instances
+    pub max_vcpus: i64,
+    // The memory of the running instances (in MiB)
+    pub max_memory: i64,
+    // The number of running instances
+    pub max_instances: i64,
+}
+
+impl QuotaLimits {
+    /// Check that the limits make sense
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_vcpus < 0 || self.max_memory < 0 || self.max_instances < 0 {
+            return Err("Quota limits cannot be negative".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Resources used by the running instances of an API key
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub vcpus: i64,
+    // In MiB
+    pub memory: i64,
+    pub instances: i64,
+}
+
+impl QuotaUsage {
+    /// Check whether the usage stays within the limits
+    pub fn within(&self, limits: &QuotaLimits) -> bool {
+        self.vcpus <= limits.max_vcpus
+            && self.memory <= limits.max_memory
+            && self.instances <= limits.max_instances
+    }
+}
+
+/// Usage of an API key, with its limits if it has a quota
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaStatus {
+    pub usage: QuotaUsage,
+    pub limits: Option<QuotaLimits>,
+}
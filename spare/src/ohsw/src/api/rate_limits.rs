@@ -0,0 +1,197 @@
+use firepilot_models::models::{RateLimiter, TokenBucket};
+use serde::{Deserialize, Serialize};
+
+/// Token bucket as exposed through the API.
+/// The refill rate is `size` tokens every `refill_time` milliseconds.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bucket {
+    // The total number of tokens the bucket can hold (bytes or operations)
+    pub size: i64,
+    // The amount of milliseconds it takes for the bucket to refill
+    pub refill_time: i64,
+    // The initial burst allowed before the refill kicks in
+    #[serde(default)]
+    pub one_time_burst: Option<i64>,
+}
+
+impl Bucket {
+    /// Create a bucket that allows `rate` tokens per second
+    pub fn per_second(rate: i64) -> Self {
+        Self {
+            size: rate,
+            refill_time: 1000,
+            one_time_burst: None,
+        }
+    }
+
+    fn validate(&self, field: &'static str) -> Result<(), RateLimitError> {
+        if self.size <= 0 {
+            return Err(RateLimitError::InvalidSize(field));
+        }
+        if self.refill_time <= 0 {
+            return Err(RateLimitError::InvalidRefillTime(field));
+        }
+        if self.one_time_burst.is_some_and(|burst| burst < 0) {
+            return Err(RateLimitError::InvalidBurst(field));
+        }
+        Ok(())
+    }
+
+    fn token_bucket(&self) -> Box<TokenBucket> {
+        Box::new(TokenBucket {
+            one_time_burst: self.one_time_burst,
+            refill_time: self.refill_time,
+            size: self.size,
+        })
+    }
+}
+
+/// Error returned when the rate limits do not make sense
+#[derive(Debug, PartialEq, Eq)]
+pub enum RateLimitError {
+    InvalidSize(&'static str),
+    InvalidRefillTime(&'static str),
+    InvalidBurst(&'static str),
+}
+impl std::fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RateLimitError::InvalidSize(field) => {
+                write!(f, "Invalid {}: size must be greater than zero", field)
+            }
+            RateLimitError::InvalidRefillTime(field) => {
+                write!(f, "Invalid {}: refill time must be greater than zero", field)
+            }
+            RateLimitError::InvalidBurst(field) => {
+                write!(f, "Invalid {}: one time burst must not be negative", field)
+            }
+        }
+    }
+}
+
+/// I/O rate limits applied to a microVM.
+/// Limits that are not set are not enforced.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    // Bandwidth (bytes) received by the guest network interface
+    #[serde(default)]
+    pub rx_bandwidth: Option<Bucket>,
+    // Bandwidth (bytes) sent by the guest network interface
+    #[serde(default)]
+    pub tx_bandwidth: Option<Bucket>,
+    // Bandwidth (bytes) of the root drive
+    #[serde(default)]
+    pub disk_bandwidth: Option<Bucket>,
+    // Operations of the root drive
+    #[serde(default)]
+    pub disk_ops: Option<Bucket>,
+}
+
+impl RateLimits {
+    /// Check that every limit is meaningful
+    pub fn validate(&self) -> Result<(), RateLimitError> {
+        let fields = [
+            ("rx_bandwidth", &self.rx_bandwidth),
+            ("tx_bandwidth", &self.tx_bandwidth),
+            ("disk_bandwidth", &self.disk_bandwidth),
+            ("disk_ops", &self.disk_ops),
+        ];
+        for (field, bucket) in fields {
+            if let Some(bucket) = bucket {
+                bucket.validate(field)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill the limits that are not set with the ones in `defaults`
+    pub fn or(&self, defaults: &RateLimits) -> RateLimits {
+        RateLimits {
+            rx_bandwidth: self.rx_bandwidth.or(defaults.rx_bandwidth),
+            tx_bandwidth: self.tx_bandwidth.or(defaults.tx_bandwidth),
+            disk_bandwidth: self.disk_bandwidth.or(defaults.disk_bandwidth),
+            disk_ops: self.disk_ops.or(defaults.disk_ops),
+        }
+    }
+
+    /// Rate limiter for the traffic received by the guest
+    pub fn rx_rate_limiter(&self) -> Option<Box<RateLimiter>> {
+        Self::rate_limiter(self.rx_bandwidth, None)
+    }
+
+    /// Rate limiter for the traffic sent by the guest
+    pub fn tx_rate_limiter(&self) -> Option<Box<RateLimiter>> {
+        Self::rate_limiter(self.tx_bandwidth, None)
+    }
+
+    /// Rate limiter for the root drive
+    pub fn disk_rate_limiter(&self) -> Option<Box<RateLimiter>> {
+        Self::rate_limiter(self.disk_bandwidth, self.disk_ops)
+    }
+
+    fn rate_limiter(bandwidth: Option<Bucket>, ops: Option<Bucket>) -> Option<Box<RateLimiter>> {
+        if bandwidth.is_none() && ops.is_none() {
+            return None;
+        }
+        Some(Box::new(RateLimiter {
+            bandwidth: bandwidth.map(|b| b.token_bucket()),
+            ops: ops.map(|b| b.token_bucket()),
+        }))
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert!(RateLimits::default().validate().is_ok());
+
+        let mut limits = RateLimits {
+            rx_bandwidth: Some(Bucket::per_second(1024)),
+            ..Default::default()
+        };
+        assert!(limits.validate().is_ok());
+
+        limits.disk_ops = Some(Bucket {
+            size: 100,
+            refill_time: 0,
+            one_time_burst: None,
+        });
+        assert_eq!(
+            limits.validate(),
+            Err(RateLimitError::InvalidRefillTime("disk_ops"))
+        );
+
+        limits.disk_ops = Some(Bucket::per_second(0));
+        assert_eq!(limits.validate(), Err(RateLimitError::InvalidSize("disk_ops")));
+
+        limits.disk_ops = Some(Bucket {
+            size: 100,
+            refill_time: 1000,
+            one_time_burst: Some(-1),
+        });
+        assert_eq!(limits.validate(), Err(RateLimitError::InvalidBurst("disk_ops")));
+    }
+
+    #[test]
+    fn test_defaults() {
+        let defaults = RateLimits {
+            rx_bandwidth: Some(Bucket::per_second(1)),
+            tx_bandwidth: Some(Bucket::per_second(2)),
+            ..Default::default()
+        };
+        let limits = RateLimits {
+            rx_bandwidth: Some(Bucket::per_second(10)),
+            disk_ops: Some(Bucket::per_second(20)),
+            ..Default::default()
+        }
+        .or(&defaults);
+        assert_eq!(limits.rx_bandwidth, Some(Bucket::per_second(10)));
+        assert_eq!(limits.tx_bandwidth, Some(Bucket::per_second(2)));
+        assert_eq!(limits.disk_bandwidth, None);
+        assert_eq!(limits.disk_ops, Some(Bucket::per_second(20)));
+    }
+}
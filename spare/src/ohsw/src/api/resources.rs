@@ -7,4 +7,44 @@ pub struct Resources {
     pub cpus: usize,
     // The amount of memory available on the node
     pub memory: usize,
+    // The space allocated on disk by the overlays of the running instances (in bytes)
+    #[serde(default)]
+    pub overlay_disk_usage: u64,
+    // The calibrated service time of a request on the node (in ms), None if not calibrated
+    #[serde(default)]
+    pub service_time: Option<f64>,
+    // The number of CPUs of the node
+    #[serde(default)]
+    pub total_cpus: usize,
+    // The memory of the node
+    #[serde(default)]
+    pub total_memory: usize,
+    // The number of instances running on the node
+    #[serde(default)]
+    pub running_instances: usize,
+    // The number of requests waiting for resources on the node
+    #[serde(default)]
+    pub queued_requests: usize,
+    // Whether the node has the image asked for in the query, None if none was asked for
+    // or the node does not tell
+    #[serde(default)]
+    pub has_image: Option<bool>,
+    // The number of addresses left for the instances of the function asked for in the
+    // query, in the default network if none was asked for; None if the node does not tell
+    #[serde(default)]
+    pub free_addresses: Option<usize>,
+}
+
+/// Query of the resources of a node
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ResourcesQuery {
+    // Include the reserve kept for the emergency requests
+    #[serde(default)]
+    pub emergency: bool,
+    // Tell whether the node has this image
+    pub image: Option<String>,
+    // The expected SHA-256 digest of the image
+    pub image_digest: Option<String>,
+    // Count the addresses left in the network of this function
+    pub function: Option<String>,
 }
@@ -0,0 +1,28 @@
+//! Scheduled invocations.
+//! A schedule runs a function at the times of a cron expression, or every given number
+//! of seconds, as if the invocation was received on /invoke.
+use serde::{Deserialize, Serialize};
+
+use super::invoke::InvokeFunction;
+use crate::schedules::Timing;
+
+/// Schedule to create
+#[derive(Serialize, Deserialize, Clone)]
+pub struct NewSchedule {
+    // The invocation to run, as sent to /invoke
+    #[serde(flatten)]
+    pub invoke: InvokeFunction,
+    // The times of the runs, e.g. "*/5 * * * *", with an optional leading seconds field
+    #[serde(default)]
+    pub cron_expr: Option<String>,
+    // The seconds between two runs, instead of a cron expression
+    #[serde(default)]
+    pub interval: Option<i64>,
+}
+
+impl NewSchedule {
+    /// Get the times of the runs, checking that exactly one of them is given
+    pub fn timing(&self) -> Result<Timing, String> {
+        Timing::new(self.cron_expr.as_deref(), self.interval)
+    }
+}
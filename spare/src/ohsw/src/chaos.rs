@@ -0,0 +1,399 @@
+//! Failure injection, to exercise the emergency and offload logic without unplugging the
+//! nodes. Only a node started with `--chaos` takes injections, from `POST /chaos` or from
+//! the control plane, and lists and clears them on `GET` and `DELETE /chaos`.
+//! A response touched by an injection is marked, and the mark is recorded with the request
+//! (`requests.chaos`) so that the analysis can leave it out. The injections log under the
+//! `ohsw::chaos` target.
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use actix_web::HttpResponse;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::orchestrator::global::identity::Node;
+
+/// Error returned when an injection makes no sense
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum ChaosError {
+    #[error("The percentage must be in [0, 100], got {0}")]
+    Percent(f64),
+    #[error("The probability must be in [0, 1], got {0}")]
+    Probability(f64),
+    #[error("The peer to blackhole is missing")]
+    Peer,
+}
+
+/// A fault to inject
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Fault {
+    /// Drop this percentage of the incoming invokes, answered with 503
+    DropInvokes { percent: f64 },
+    /// Delay every forward of an offloaded request by this many ms
+    OffloadLatency { ms: u64 },
+    /// Fail each start of an instance with this probability
+    FailInstances { probability: f64 },
+    /// Send nothing to this neighbor, by address, as if it were unreachable
+    Blackhole { peer: String },
+}
+
+impl Fault {
+    /// Check that the fault can be injected
+    pub fn validate(&self) -> Result<(), ChaosError> {
+        match self {
+            Fault::DropInvokes { percent } if !(0.0..=100.0).contains(percent) => {
+                Err(ChaosError::Percent(*percent))
+            }
+            Fault::FailInstances { probability } if !(0.0..=1.0).contains(probability) => {
+                Err(ChaosError::Probability(*probability))
+            }
+            Fault::Blackhole { peer } if peer.trim().is_empty() => Err(ChaosError::Peer),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Where an injection came from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// The admin endpoint
+    Admin,
+    /// A command of the control plane, e.g. sent by the benchmark
+    ControlPlane,
+}
+
+/// A fault injected in the node
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Injection {
+    pub id: u64,
+    #[serde(flatten)]
+    pub fault: Fault,
+    pub source: Source,
+    pub created_at: chrono::NaiveDateTime,
+    /// Times the fault was injected so far
+    pub hits: u64,
+}
+
+/// What a chaos command of the control plane does
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChaosAction {
+    Inject(Fault),
+    /// Remove every injection
+    Clear,
+}
+
+/// A chaos command sent on the control plane
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChaosCommand {
+    /// Ids or addresses of the nodes it applies to, every node if empty
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    pub action: ChaosAction,
+}
+
+impl ChaosCommand {
+    /// Check if the command applies to the given node
+    pub fn applies_to(&self, node: &Node) -> bool {
+        self.nodes.is_empty()
+            || self
+                .nodes
+                .iter()
+                .any(|target| target == node.key() || *target == node.address)
+    }
+}
+
+/// Mark of a response touched by an injection
+#[derive(Debug, Clone, Copy)]
+pub struct Injected;
+
+/// Mark a response as touched by an injection
+pub fn mark(response: &mut HttpResponse) {
+    response.extensions_mut().insert(Injected);
+}
+
+/// Check if a response was touched by an injection
+pub fn marked(response: &HttpResponse) -> bool {
+    response.extensions().get::<Injected>().is_some()
+}
+
+/// Draw true with the given probability
+fn roll(probability: f64) -> bool {
+    rand::random::<f64>() < probability
+}
+
+/// The faults injected in the node
+#[derive(Debug)]
+pub struct Chaos {
+    injections: Mutex<Vec<Injection>>,
+    next_id: AtomicU64,
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chaos {
+    /// Create a new node without injections
+    pub fn new() -> Self {
+        Chaos {
+            injections: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Inject a fault, until it is removed
+    /// # Errors
+    /// * The fault makes no sense, e.g. a percentage above 100
+    pub fn inject(&self, fault: Fault, source: Source) -> Result<Injection, ChaosError> {
+        fault.validate()?;
+        let injection = Injection {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            fault,
+            source,
+            created_at: chrono::Utc::now().naive_utc(),
+            hits: 0,
+        };
+        warn!("Injecting {:?} ({:?})", injection.fault, source);
+        self.injections.lock().unwrap().push(injection.clone());
+        Ok(injection)
+    }
+
+    /// The injections, oldest first
+    pub fn list(&self) -> Vec<Injection> {
+        self.injections.lock().unwrap().clone()
+    }
+
+    /// Remove an injection
+    /// # Returns
+    /// * Whether it existed
+    pub fn remove(&self, id: u64) -> bool {
+        let mut injections = self.injections.lock().unwrap();
+        let before = injections.len();
+        injections.retain(|injection| injection.id != id);
+        let removed = injections.len() < before;
+        if removed {
+            info!("Removed injection {}", id);
+        }
+        removed
+    }
+
+    /// Remove every injection
+    /// # Returns
+    /// * How many were removed
+    pub fn clear(&self) -> usize {
+        let cleared = std::mem::take(&mut *self.injections.lock().unwrap()).len();
+        info!("Cleared {} injections", cleared);
+        cleared
+    }
+
+    /// Run a command of the control plane
+    pub fn apply(&self, command: ChaosCommand) -> Result<(), ChaosError> {
+        match command.action {
+            ChaosAction::Inject(fault) => self.inject(fault, Source::ControlPlane).map(|_| ()),
+            ChaosAction::Clear => {
+                self.clear();
+                Ok(())
+            }
+        }
+    }
+
+    /// Count a hit of every injection `hits` selects
+    /// # Returns
+    /// * The faults that hit
+    fn hit(&self, hits: impl Fn(&Fault) -> bool) -> Vec<Fault> {
+        let mut injections = self.injections.lock().unwrap();
+        injections
+            .iter_mut()
+            .filter(|injection| hits(&injection.fault))
+            .map(|injection| {
+                injection.hits += 1;
+                injection.fault.clone()
+            })
+            .collect()
+    }
+
+    /// Whether an incoming invoke of `function` is dropped
+    pub fn drop_invoke(&self, function: &str) -> bool {
+        let hits = self.hit(|fault| match fault {
+            Fault::DropInvokes { percent } => roll(percent / 100.0),
+            _ => false,
+        });
+        if !hits.is_empty() {
+            warn!("Dropped an invoke of {}", function);
+        }
+        !hits.is_empty()
+    }
+
+    /// Delay added to the forward of an offloaded request to `peer`
+    pub fn offload_latency(&self, peer: &str) -> Duration {
+        let delay: Duration = self
+            .hit(|fault| matches!(fault, Fault::OffloadLatency { .. }))
+            .iter()
+            .map(|fault| match fault {
+                Fault::OffloadLatency { ms } => Duration::from_millis(*ms),
+                _ => Duration::ZERO,
+            })
+            .sum();
+        if !delay.is_zero() {
+            warn!(
+                "Delaying the forward to {} by {} ms",
+                peer,
+                delay.as_millis()
+            );
+        }
+        delay
+    }
+
+    /// Whether a start of an instance of `image` fails
+    pub fn fail_instance(&self, image: &str) -> bool {
+        let hits = self.hit(|fault| match fault {
+            Fault::FailInstances { probability } => roll(*probability),
+            _ => false,
+        });
+        if !hits.is_empty() {
+            warn!("Failed a start of an instance of {}", image);
+        }
+        !hits.is_empty()
+    }
+
+    /// Whether nothing is sent to `peer`
+    pub fn blackholed(&self, peer: &str) -> bool {
+        let hits = self.hit(|fault| matches!(fault, Fault::Blackhole { peer: p } if p == peer));
+        if !hits.is_empty() {
+            warn!("Blackholed a request to {}", peer);
+        }
+        !hits.is_empty()
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let chaos = Chaos::new();
+        for (fault, error) in [
+            (
+                Fault::DropInvokes { percent: 120.0 },
+                ChaosError::Percent(120.0),
+            ),
+            (
+                Fault::FailInstances { probability: -0.5 },
+                ChaosError::Probability(-0.5),
+            ),
+            (
+                Fault::Blackhole {
+                    peer: " ".to_string(),
+                },
+                ChaosError::Peer,
+            ),
+        ] {
+            assert_eq!(chaos.inject(fault, Source::Admin), Err(error));
+        }
+        assert!(chaos
+            .inject(Fault::DropInvokes { percent: f64::NAN }, Source::Admin)
+            .is_err());
+        assert!(chaos.list().is_empty());
+    }
+
+    #[test]
+    fn test_injections() {
+        let chaos = Chaos::new();
+        let drop = chaos
+            .inject(Fault::DropInvokes { percent: 100.0 }, Source::Admin)
+            .unwrap();
+        let never = chaos
+            .inject(Fault::FailInstances { probability: 0.0 }, Source::Admin)
+            .unwrap();
+        assert_eq!((drop.id, never.id), (1, 2));
+
+        // Every invoke is dropped, no instance fails
+        for _ in 0..10 {
+            assert!(chaos.drop_invoke("f"));
+            assert!(!chaos.fail_instance("f"));
+        }
+        let hits: Vec<_> = chaos.list().iter().map(|i| (i.id, i.hits)).collect();
+        assert_eq!(hits, vec![(1, 10), (2, 0)]);
+
+        assert!(chaos.remove(1));
+        assert!(!chaos.remove(1));
+        assert!(!chaos.drop_invoke("f"));
+        chaos
+            .inject(Fault::FailInstances { probability: 1.0 }, Source::Admin)
+            .unwrap();
+        assert!(chaos.fail_instance("f"));
+        assert_eq!(chaos.clear(), 2);
+        assert!(chaos.list().is_empty());
+    }
+
+    #[test]
+    fn test_offload_faults() {
+        let chaos = Chaos::new();
+        assert_eq!(chaos.offload_latency("10.0.0.1:8085"), Duration::ZERO);
+        assert!(!chaos.blackholed("10.0.0.1:8085"));
+
+        for ms in [30, 20] {
+            chaos
+                .inject(Fault::OffloadLatency { ms }, Source::Admin)
+                .unwrap();
+        }
+        let peer = Fault::Blackhole {
+            peer: "10.0.0.1:8085".to_string(),
+        };
+        chaos.inject(peer, Source::Admin).unwrap();
+        // The delays add up, only the named peer is blackholed
+        assert_eq!(
+            chaos.offload_latency("10.0.0.1:8085"),
+            Duration::from_millis(50)
+        );
+        assert!(chaos.blackholed("10.0.0.1:8085"));
+        assert!(!chaos.blackholed("10.0.0.2:8085"));
+        let hits: Vec<_> = chaos.list().iter().map(|i| i.hits).collect();
+        assert_eq!(hits, vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn test_command() {
+        let raw =
+            r#"{"nodes": ["a"], "action": {"inject": {"kind": "drop_invokes", "percent": 20}}}"#;
+        let command: ChaosCommand = serde_json::from_str(raw).unwrap();
+        assert_eq!(
+            command.action,
+            ChaosAction::Inject(Fault::DropInvokes { percent: 20.0 })
+        );
+        let a = Node::new("10.0.0.1:8085".to_string(), (0.0, 0.0)).with_id("a".to_string());
+        let b = Node::new("10.0.0.2:8085".to_string(), (0.0, 0.0));
+        assert!(command.applies_to(&a));
+        assert!(!command.applies_to(&b));
+
+        let chaos = Chaos::new();
+        chaos.apply(command).unwrap();
+        assert_eq!(chaos.list()[0].source, Source::ControlPlane);
+
+        // Without nodes it applies to every node
+        let clear: ChaosCommand = serde_json::from_str(r#"{"action": "clear"}"#).unwrap();
+        assert!(clear.applies_to(&b));
+        chaos.apply(clear).unwrap();
+        assert!(chaos.list().is_empty());
+    }
+
+    #[test]
+    fn test_mark() {
+        let mut response = HttpResponse::Ok().finish();
+        assert!(!marked(&response));
+        mark(&mut response);
+        assert!(marked(&response));
+    }
+}
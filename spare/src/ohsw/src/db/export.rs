@@ -0,0 +1,412 @@
+//! Export of the database, so the results of an experiment can be collected
+//! from the nodes without copying the sqlite file around.
+//! The instances are streamed from the database in chunks, so the size of the
+//! table does not matter, and the stats are aggregated by sqlite.
+use std::{fmt::Write, io};
+
+use actix_web::web::Bytes;
+use chrono::NaiveDateTime;
+use futures::{channel::mpsc, SinkExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use sqlx::{Pool, Sqlite};
+
+use super::{models::Instance, window::Window};
+
+/// Size from which a chunk of the export is sent to the client
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of chunks buffered before the database reads wait for the client
+const CHUNKS_IN_FLIGHT: usize = 4;
+
+/// Maximum number of buckets of a stats export
+pub const MAX_BUCKETS: i64 = 100_000;
+
+/// Header of the csv export of the instances
+const INSTANCES_HEADER: &str =
+    "id,functions,kernel,image,vcpus,memory,ip,port,hops,status,created_at,env,args\n";
+
+/// Format of an export
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// Comma-separated values, with a header
+    #[default]
+    Csv,
+    /// One JSON object per line
+    Ndjson,
+}
+
+impl ExportFormat {
+    /// Content type of the format
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// Extension of the files in the format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+        }
+    }
+}
+
+/// Quote a csv field if needed
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Append an instance to the export
+fn encode_instance(out: &mut String, instance: &Instance, format: ExportFormat) {
+    match format {
+        ExportFormat::Csv => {
+            let _ = writeln!(
+                out,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                instance.id,
+                csv_field(&instance.functions),
+                csv_field(&instance.kernel),
+                csv_field(&instance.image),
+                instance.vcpus,
+                instance.memory,
+                csv_field(&instance.ip),
+                instance.port,
+                instance.hops,
+                csv_field(&instance.status),
+                instance.created_at,
+                csv_field(instance.env.as_deref().unwrap_or_default()),
+                csv_field(instance.args.as_deref().unwrap_or_default())
+            );
+        }
+        ExportFormat::Ndjson => {
+            // Serializing a struct of plain fields cannot fail
+            let _ = writeln!(out, "{}", serde_json::to_string(instance).unwrap());
+        }
+    }
+}
+
+/// Stream the instances created from `since` (all of them if None), oldest first.
+/// The rows are read by a background task, which waits when the client is slow
+/// and stops when it goes away. Must be called from within an actix runtime.
+pub fn export_instances(
+    pool: Pool<Sqlite>,
+    since: Option<NaiveDateTime>,
+    format: ExportFormat,
+) -> mpsc::Receiver<Result<Bytes, io::Error>> {
+    let (mut sender, receiver) = mpsc::channel(CHUNKS_IN_FLIGHT);
+    actix_web::rt::spawn(async move {
+        let mut chunk = String::new();
+        if format == ExportFormat::Csv {
+            chunk.push_str(INSTANCES_HEADER);
+        }
+
+        let since = since.unwrap_or_default();
+        let mut rows = sqlx::query_as::<_, Instance>(
+            "SELECT * FROM instances WHERE created_at >= $1 ORDER BY created_at, id",
+        )
+        .bind(since)
+        .fetch(&pool);
+        loop {
+            match rows.try_next().await {
+                Ok(Some(instance)) => {
+                    encode_instance(&mut chunk, &instance, format);
+                    if chunk.len() < CHUNK_SIZE {
+                        continue;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    // The client sees a truncated body
+                    let _ = sender.send(Err(io::Error::other(e))).await;
+                    return;
+                }
+            }
+            if sender
+                .send(Ok(Bytes::from(std::mem::take(&mut chunk))))
+                .await
+                .is_err()
+            {
+                // The client went away
+                return;
+            }
+        }
+        if !chunk.is_empty() {
+            let _ = sender.send(Ok(Bytes::from(chunk))).await;
+        }
+    });
+    receiver
+}
+
+/// Parse the length of a bucket, in seconds: a number followed by s, m or h
+pub fn parse_bucket(bucket: &str) -> Result<i64, String> {
+    let (value, unit) = match bucket.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => bucket.split_at(index),
+        None => (bucket, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => return Err(format!("Unknown bucket unit: {}", unit)),
+    };
+    match value.parse::<i64>() {
+        Ok(value) if value > 0 => Ok(value * multiplier),
+        _ => Err(format!("Invalid bucket: {}", bucket)),
+    }
+}
+
+/// Statistics of the node over a bucket of time, as in `Stats`
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatsBucket {
+    /// Start of the bucket (unix timestamp, in seconds)
+    pub start: i64,
+    pub hops_avg: f64,
+    pub vcpus_sum: i64,
+    pub memory_sum: i64,
+    pub requests: i64,
+    pub received: i64,
+    pub offloaded: i64,
+}
+
+/// Aggregates of the terminated instances of a bucket
+#[derive(sqlx::FromRow)]
+struct InstanceBucket {
+    start: i64,
+    hops_avg: f64,
+    vcpus_sum: i64,
+    memory_sum: i64,
+    requests: i64,
+}
+
+/// Aggregates of the received requests of a bucket
+#[derive(sqlx::FromRow)]
+struct RequestBucket {
+    start: i64,
+    received: i64,
+    offloaded: i64,
+}
+
+/// Get the statistics in the window, in buckets of `bucket` seconds.
+/// Buckets are aligned to multiples of their length, the ones without any
+/// instance or request are omitted.
+pub async fn stats_buckets(
+    pool: &Pool<Sqlite>,
+    window: &Window,
+    bucket: i64,
+) -> Result<Vec<StatsBucket>, sqlx::Error> {
+    let instances = sqlx::query_as::<_, InstanceBucket>(
+        r#"
+        SELECT
+            created_at_ms / 1000 / $3 * $3 AS start,
+            AVG(hops) AS hops_avg,
+            SUM(vcpus) AS vcpus_sum,
+            SUM(memory) AS memory_sum,
+            COUNT(id) AS requests
+        FROM
+            instances
+        WHERE
+            created_at_ms BETWEEN $1 AND $2
+            AND status = 'terminated'
+        GROUP BY 1
+        ORDER BY 1
+        "#,
+    )
+    .bind(window.start_ms())
+    .bind(window.end_ms())
+    .bind(bucket)
+    .fetch_all(pool)
+    .await?;
+
+    let requests = sqlx::query_as::<_, RequestBucket>(
+        r#"
+        SELECT
+            received_at_ms / 1000 / $3 * $3 AS start,
+            COUNT(id) AS received,
+            SUM(outcome = 'offloaded') AS offloaded
+        FROM
+            requests
+        WHERE
+            received_at_ms BETWEEN $1 AND $2
+        GROUP BY 1
+        ORDER BY 1
+        "#,
+    )
+    .bind(window.start_ms())
+    .bind(window.end_ms())
+    .bind(bucket)
+    .fetch_all(pool)
+    .await?;
+
+    // Merge the two sorted lists of buckets
+    let mut buckets = Vec::with_capacity(instances.len().max(requests.len()));
+    let mut instances = instances.into_iter().peekable();
+    let mut requests = requests.into_iter().peekable();
+    loop {
+        let start = match (instances.peek(), requests.peek()) {
+            (Some(i), Some(r)) => i.start.min(r.start),
+            (Some(i), None) => i.start,
+            (None, Some(r)) => r.start,
+            (None, None) => break,
+        };
+        let mut bucket = StatsBucket {
+            start,
+            ..Default::default()
+        };
+        if let Some(i) = instances.next_if(|i| i.start == start) {
+            bucket.hops_avg = i.hops_avg;
+            bucket.vcpus_sum = i.vcpus_sum;
+            bucket.memory_sum = i.memory_sum;
+            bucket.requests = i.requests;
+        }
+        if let Some(r) = requests.next_if(|r| r.start == start) {
+            bucket.received = r.received;
+            bucket.offloaded = r.offloaded;
+        }
+        buckets.push(bucket);
+    }
+    Ok(buckets)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        self,
+        models::{Request, RequestOutcome},
+    };
+    use futures::StreamExt;
+
+    /// Insert `count` terminated instances, one per second from 2026-01-01 00:00:00
+    async fn insert_instances(pool: &Pool<Sqlite>, count: i64) {
+        sqlx::query(
+            r#"
+            WITH RECURSIVE seq(n) AS (SELECT 0 UNION ALL SELECT n + 1 FROM seq WHERE n + 1 < $1)
+            INSERT INTO instances (functions, kernel, image, vcpus, memory, ip, port, hops, status, created_at)
+            SELECT 'f' || n, 'kernel', 'image', 1 + n % 2, 128, '192.168.0.2', 8084, n % 3, 'terminated',
+                datetime('2026-01-01 00:00:00', '+' || n || ' seconds')
+            FROM seq
+            "#,
+        )
+        .bind(count)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    async fn collect(receiver: mpsc::Receiver<Result<Bytes, io::Error>>) -> String {
+        let chunks: Vec<_> = receiver.map(|chunk| chunk.unwrap()).collect().await;
+        chunks
+            .iter()
+            .map(|chunk| std::str::from_utf8(chunk).unwrap())
+            .collect()
+    }
+
+    fn timestamp(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn test_parse_bucket() {
+        assert_eq!(parse_bucket("60s"), Ok(60));
+        assert_eq!(parse_bucket("60"), Ok(60));
+        assert_eq!(parse_bucket("5m"), Ok(300));
+        assert_eq!(parse_bucket("1h"), Ok(3600));
+        assert!(parse_bucket("0s").is_err());
+        assert!(parse_bucket("10d").is_err());
+        assert!(parse_bucket("s").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_export_instances() {
+        let pool = db::establish_connection().await.unwrap();
+        insert_instances(&pool, 100_000).await;
+
+        let csv = collect(export_instances(pool.clone(), None, ExportFormat::Csv)).await;
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 100_001);
+        assert_eq!(lines[0], INSTANCES_HEADER.trim_end());
+        assert_eq!(
+            lines[1],
+            "1,f0,kernel,image,1,128,192.168.0.2,8084,0,terminated,2026-01-01 00:00:00,,"
+        );
+
+        // The last 10 instances
+        let since = timestamp("2026-01-02 03:46:30");
+        let ndjson = collect(export_instances(pool, Some(since), ExportFormat::Ndjson)).await;
+        let instances: Vec<Instance> = ndjson
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(instances.len(), 10);
+        assert_eq!(instances[0].functions, "f99990");
+        assert_eq!(instances[9].id, 100_000);
+    }
+
+    #[actix_web::test]
+    async fn test_export_stops_when_client_leaves() {
+        let pool = db::establish_connection().await.unwrap();
+        insert_instances(&pool, 100_000).await;
+
+        let mut receiver = export_instances(pool, None, ExportFormat::Csv);
+        let first = receiver.next().await.unwrap().unwrap();
+        assert!(first.len() >= CHUNK_SIZE);
+        // Dropping the receiver stops the task instead of reading the whole table
+        drop(receiver);
+    }
+
+    #[actix_web::test]
+    async fn test_stats_buckets() {
+        let pool = db::establish_connection().await.unwrap();
+        insert_instances(&pool, 100_000).await;
+        let received_at = timestamp("2026-01-01 00:00:30");
+        let requests = [
+            Request::new(
+                "f".to_string(),
+                0,
+                RequestOutcome::ServedLocally,
+                received_at,
+            ),
+            Request::new(
+                "f".to_string(),
+                0,
+                RequestOutcome::OffloadedTo("10.0.0.2:8085".to_string()),
+                received_at,
+            ),
+        ];
+        Request::insert_batch(&requests, &pool).await.unwrap();
+
+        let window = Window::parse("2026-01-01 00:00:00", "2026-01-01 00:09:59").unwrap();
+        let buckets = stats_buckets(&pool, &window, 60).await.unwrap();
+        assert_eq!(buckets.len(), 10);
+        assert_eq!(buckets[0].start, 1_767_225_600);
+        assert_eq!(buckets[1].start - buckets[0].start, 60);
+        assert_eq!(buckets[0].requests, 60);
+        assert_eq!(buckets[0].vcpus_sum, 90);
+        assert_eq!(buckets[0].memory_sum, 60 * 128);
+        assert_eq!(buckets[0].hops_avg, 1.0);
+        assert_eq!(buckets[0].received, 2);
+        assert_eq!(buckets[0].offloaded, 1);
+        assert_eq!(buckets[1].received, 0);
+
+        // The whole table in a few buckets
+        let window = Window::parse("2026-01-01T00:00:00Z", "2026-01-03T00:00:00Z").unwrap();
+        let buckets = stats_buckets(&pool, &window, 86400).await.unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].requests + buckets[1].requests, 100_000);
+    }
+}
@@ -1,74 +1,127 @@
 //! Database module for SPARE project.
+use chrono::{DateTime, Utc};
 use models::Instance;
 use serde::{Deserialize, Serialize};
 use sqlx::{
-    sqlite::{self, SqlitePoolOptions},
+    sqlite::{self, SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous},
     Pool, Sqlite,
 };
-use std::io;
+use std::{io, str::FromStr, time::Duration};
+use window::Window;
 
+pub mod export;
 pub mod models;
+pub mod request_log;
+pub mod status_writer;
+pub mod window;
+
+/// Time a connection waits for a lock held by another connection before failing
+pub const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
 
 // Establish a connection to the database
 // If is a test, use an in-memory database. Otherwise, use the DATABASE_URL environment variable.
 pub async fn establish_connection() -> Result<Pool<sqlite::Sqlite>, sqlx::Error> {
-    if cfg!(test) {
-        let pool = SqlitePoolOptions::new()
-            .max_connections(10)
-            .connect(":memory:")
-            .await?;
-        sqlx::migrate!().run(&pool).await?;
+    establish_connection_with(false).await
+}
 
-        Ok(pool)
+// Same as `establish_connection`, `legacy` keeps the default sqlite settings
+pub async fn establish_connection_with(legacy: bool) -> Result<Pool<sqlite::Sqlite>, sqlx::Error> {
+    if cfg!(test) {
+        connect(":memory:", legacy).await
     } else {
         let env = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-        let pool = SqlitePoolOptions::new()
-            .max_connections(10)
-            .connect(&env)
-            .await?;
-        sqlx::migrate!().run(&pool).await?;
-        Ok(pool)
+        connect(&env, legacy).await
     }
 }
 
+/// Open a pool on the database at `url` and run the migrations.
+/// Unless `legacy` is set, the database is switched to WAL mode, so readers do not
+/// block the writer, and a connection waits up to `BUSY_TIMEOUT` for a lock
+/// instead of failing with `database is locked`.
+pub async fn connect(url: &str, legacy: bool) -> Result<Pool<sqlite::Sqlite>, sqlx::Error> {
+    let mut options = SqliteConnectOptions::from_str(url)?;
+    if !legacy {
+        options = options
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(BUSY_TIMEOUT)
+            // In WAL mode NORMAL is still safe against corruption, it only
+            // flushes less often
+            .synchronous(SqliteSynchronous::Normal);
+    }
+    let pool = SqlitePoolOptions::new()
+        .max_connections(10)
+        .connect_with(options)
+        .await?;
+    sqlx::migrate!().run(&pool).await?;
+    Ok(pool)
+}
+
 // Return a list of all instances in the database
 pub async fn get_list(pool: &Pool<sqlite::Sqlite>) -> Result<Vec<models::Instance>, sqlx::Error> {
     Instance::list(pool).await
 }
 
+/// Aggregates of the instances of an epoch started for one kind of request
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct InstanceStats {
+    pub hops_avg: f64,
+    pub vcpus: i64,
+    pub memory: i64,
+    pub requests: i64,
+}
+
 // Used in SPARE paper. Struct that represents the statistics of an epoch.
-#[derive(Deserialize, Serialize)]
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
 pub struct Stats {
     pub hops_avg: f64,
     pub vcpus: i64,
     pub memory: i64,
     pub requests: i64,
+    /// Requests received on /invoke, whatever their outcome
+    pub received: i64,
+    /// Requests forwarded to another node
+    pub offloaded: i64,
+    /// Requests shed for being answered after their deadline, missing in the reports of
+    /// the older nodes
+    #[serde(default)]
+    pub shed: i64,
+    /// Instances started for the emergency requests, missing in the reports of the
+    /// older nodes
+    #[serde(default)]
+    pub emergency: InstanceStats,
+    /// Instances started for the other requests
+    #[serde(default)]
+    pub normal: InstanceStats,
 }
 
-// Get statistics from the database from start to end timestamps.
-pub async fn stats(
-    pool: &Pool<Sqlite>,
-    start_timestamp: &str,
-    end_timestamp: &str,
-) -> Result<Stats, io::Error> {
-    // SQL query to aggregate statistics from start to end timestamps
+// Get statistics from the database in the window, both bounds included.
+// The rows are compared as unix milliseconds in UTC, whatever the format of the bounds.
+pub async fn stats(pool: &Pool<Sqlite>, window: &Window) -> Result<Stats, io::Error> {
+    let (start_ms, end_ms) = (window.start_ms(), window.end_ms());
+    // SQL query to aggregate statistics in the window
     let result = sqlx::query!(
         r#"
         SELECT
-            strftime('%s', ?) * 1000 AS timestamp_ms,  -- Convert end timestamp to milliseconds
             COALESCE(AVG(hops), 0.0) AS hops_avg,
             COALESCE(SUM(vcpus), 0) AS vcpus_sum,
             COALESCE(SUM(memory), 0) AS memory_sum,
-            COALESCE(COUNT(id), 0) AS requests
+            COALESCE(COUNT(id), 0) AS requests,
+            COALESCE(AVG(CASE WHEN emergency THEN hops END), 0.0) AS "emergency_hops_avg!: f64",
+            COALESCE(SUM(CASE WHEN emergency THEN vcpus END), 0) AS "emergency_vcpus!: i64",
+            COALESCE(SUM(CASE WHEN emergency THEN memory END), 0) AS "emergency_memory!: i64",
+            COALESCE(SUM(emergency), 0) AS "emergency_requests!: i64",
+            COALESCE(AVG(CASE WHEN NOT emergency THEN hops END), 0.0) AS "normal_hops_avg!: f64",
+            COALESCE(SUM(CASE WHEN NOT emergency THEN vcpus END), 0) AS "normal_vcpus!: i64",
+            COALESCE(SUM(CASE WHEN NOT emergency THEN memory END), 0) AS "normal_memory!: i64",
+            COALESCE(SUM(NOT emergency), 0) AS "normal_requests!: i64"
         FROM
             instances
         WHERE
-            created_at BETWEEN ? AND ?
+            created_at_ms BETWEEN ? AND ?
             AND status = 'terminated'
         "#,
-        end_timestamp,
-        start_timestamp,
-        end_timestamp
+        start_ms,
+        end_ms
     )
     .fetch_one(pool)
     .await
@@ -80,10 +133,351 @@ pub async fn stats(
     let memory = result.memory_sum;
     let requests = result.requests;
 
+    // Requests handled by the node, including the ones that did not create an instance
+    let handled = sqlx::query!(
+        r#"
+        SELECT
+            COUNT(id) AS received,
+            COALESCE(SUM(outcome = 'offloaded'), 0) AS "offloaded!: i64",
+            COALESCE(SUM(outcome = 'shed'), 0) AS "shed!: i64"
+        FROM
+            requests
+        WHERE
+            received_at_ms BETWEEN ? AND ?
+        "#,
+        start_ms,
+        end_ms
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(io::Error::other)?;
+
     Ok(Stats {
         hops_avg,
         vcpus,
         memory,
         requests,
+        received: handled.received,
+        offloaded: handled.offloaded,
+        shed: handled.shed,
+        emergency: InstanceStats {
+            hops_avg: result.emergency_hops_avg,
+            vcpus: result.emergency_vcpus,
+            memory: result.emergency_memory,
+            requests: result.emergency_requests,
+        },
+        normal: InstanceStats {
+            hops_avg: result.normal_hops_avg,
+            vcpus: result.normal_vcpus,
+            memory: result.normal_memory,
+            requests: result.normal_requests,
+        },
     })
 }
+
+/// Statistics of a bucket of a window, as in an epoch
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct BucketStats {
+    pub start: DateTime<Utc>,
+    /// End of the bucket, excluded but for the last bucket of the window
+    pub end: DateTime<Utc>,
+    #[serde(flatten)]
+    pub stats: Stats,
+    /// Average time from the reception of a request to its answer (in ms)
+    pub duration_avg: f64,
+}
+
+/// Aggregates of the terminated instances of a bucket
+#[derive(sqlx::FromRow)]
+struct InstanceRow {
+    bucket: i64,
+    hops_avg: f64,
+    vcpus: i64,
+    memory: i64,
+    requests: i64,
+    emergency_hops_avg: f64,
+    emergency_vcpus: i64,
+    emergency_memory: i64,
+    emergency_requests: i64,
+    normal_hops_avg: f64,
+    normal_vcpus: i64,
+    normal_memory: i64,
+    normal_requests: i64,
+}
+
+/// Aggregates of the received requests of a bucket
+#[derive(sqlx::FromRow)]
+struct RequestRow {
+    bucket: i64,
+    received: i64,
+    offloaded: i64,
+    shed: i64,
+    duration_avg: f64,
+}
+
+/// Get the statistics of the window in buckets of `bucket` seconds, from its start.
+/// Every bucket is returned, the empty ones too; the last one is cut at the end of the
+/// window and includes it.
+pub async fn stats_by_bucket(
+    pool: &Pool<Sqlite>,
+    window: &Window,
+    bucket: i64,
+) -> Result<Vec<BucketStats>, sqlx::Error> {
+    let bucket_ms = bucket * 1000;
+    let length = window.end_ms() - window.start_ms();
+    let count = (length / bucket_ms + (length % bucket_ms > 0) as i64).max(1);
+
+    let instances = sqlx::query_as::<_, InstanceRow>(
+        r#"
+        SELECT
+            MIN((created_at_ms - $1) / $3, $4) AS bucket,
+            AVG(hops) AS hops_avg,
+            SUM(vcpus) AS vcpus,
+            SUM(memory) AS memory,
+            COUNT(id) AS requests,
+            COALESCE(AVG(CASE WHEN emergency THEN hops END), 0.0) AS emergency_hops_avg,
+            COALESCE(SUM(CASE WHEN emergency THEN vcpus END), 0) AS emergency_vcpus,
+            COALESCE(SUM(CASE WHEN emergency THEN memory END), 0) AS emergency_memory,
+            COALESCE(SUM(emergency), 0) AS emergency_requests,
+            COALESCE(AVG(CASE WHEN NOT emergency THEN hops END), 0.0) AS normal_hops_avg,
+            COALESCE(SUM(CASE WHEN NOT emergency THEN vcpus END), 0) AS normal_vcpus,
+            COALESCE(SUM(CASE WHEN NOT emergency THEN memory END), 0) AS normal_memory,
+            COALESCE(SUM(NOT emergency), 0) AS normal_requests
+        FROM
+            instances
+        WHERE
+            created_at_ms BETWEEN $1 AND $2
+            AND status = 'terminated'
+        GROUP BY 1
+        "#,
+    )
+    .bind(window.start_ms())
+    .bind(window.end_ms())
+    .bind(bucket_ms)
+    .bind(count - 1)
+    .fetch_all(pool)
+    .await?;
+
+    let requests = sqlx::query_as::<_, RequestRow>(
+        r#"
+        SELECT
+            MIN((received_at_ms - $1) / $3, $4) AS bucket,
+            COUNT(id) AS received,
+            SUM(outcome = 'offloaded') AS offloaded,
+            SUM(outcome = 'shed') AS shed,
+            COALESCE(AVG(unixepoch(completed_at, 'subsec') * 1000 - received_at_ms), 0.0)
+                AS duration_avg
+        FROM
+            requests
+        WHERE
+            received_at_ms BETWEEN $1 AND $2
+        GROUP BY 1
+        "#,
+    )
+    .bind(window.start_ms())
+    .bind(window.end_ms())
+    .bind(bucket_ms)
+    .bind(count - 1)
+    .fetch_all(pool)
+    .await?;
+
+    let mut buckets: Vec<BucketStats> = (0..count)
+        .map(|index| {
+            let start = window.start + chrono::Duration::milliseconds(index * bucket_ms);
+            BucketStats {
+                start,
+                end: (start + chrono::Duration::milliseconds(bucket_ms)).min(window.end),
+                ..Default::default()
+            }
+        })
+        .collect();
+    for row in instances {
+        buckets[row.bucket as usize].stats = Stats {
+            hops_avg: row.hops_avg,
+            vcpus: row.vcpus,
+            memory: row.memory,
+            requests: row.requests,
+            emergency: InstanceStats {
+                hops_avg: row.emergency_hops_avg,
+                vcpus: row.emergency_vcpus,
+                memory: row.emergency_memory,
+                requests: row.emergency_requests,
+            },
+            normal: InstanceStats {
+                hops_avg: row.normal_hops_avg,
+                vcpus: row.normal_vcpus,
+                memory: row.normal_memory,
+                requests: row.normal_requests,
+            },
+            ..Default::default()
+        };
+    }
+    for row in requests {
+        let bucket = &mut buckets[row.bucket as usize];
+        bucket.stats.received = row.received;
+        bucket.stats.offloaded = row.offloaded;
+        bucket.stats.shed = row.shed;
+        bucket.duration_avg = row.duration_avg;
+    }
+    Ok(buckets)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::{Request, RequestOutcome};
+
+    /// A window holding every row of the tests
+    fn always() -> Window {
+        Window::parse("2000-01-01T00:00:00Z", "2100-01-01T00:00:00Z").unwrap()
+    }
+
+    async fn terminated(pool: &Pool<Sqlite>, vcpus: i32, memory: i32, hops: i32, emergency: bool) {
+        let mut instance = Instance::new(
+            "test".to_string(),
+            "kernel".to_string(),
+            "image".to_string(),
+            vcpus,
+            memory,
+            hops,
+            "192.168.30.2".to_string(),
+            8084,
+        )
+        .with_emergency(emergency);
+        instance.set_status("terminated".to_string());
+        instance.insert(pool).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_stats_by_emergency() {
+        let pool = establish_connection().await.unwrap();
+        terminated(&pool, 1, 128, 0, false).await;
+        terminated(&pool, 2, 256, 2, false).await;
+        terminated(&pool, 4, 512, 1, true).await;
+
+        let epoch = stats(&pool, &always()).await.unwrap();
+        assert_eq!(epoch.requests, 3);
+        assert_eq!(epoch.vcpus, 7);
+        assert_eq!(
+            epoch.emergency,
+            InstanceStats {
+                hops_avg: 1.0,
+                vcpus: 4,
+                memory: 512,
+                requests: 1,
+            }
+        );
+        assert_eq!(
+            epoch.normal,
+            InstanceStats {
+                hops_avg: 1.0,
+                vcpus: 3,
+                memory: 384,
+                requests: 2,
+            }
+        );
+
+        // An epoch without emergencies has nothing on that side
+        let pool = establish_connection().await.unwrap();
+        terminated(&pool, 1, 128, 0, false).await;
+        let epoch = stats(&pool, &always()).await.unwrap();
+        assert_eq!(epoch.emergency, InstanceStats::default());
+        assert_eq!(epoch.normal.requests, 1);
+    }
+
+    #[actix_web::test]
+    async fn test_stats_window() {
+        let pool = establish_connection().await.unwrap();
+        let at = |timestamp: &str| window::parse_utc(timestamp).unwrap().naive_utc();
+        // Stamped by the node in UTC, one on a whole second and one just after
+        for created_at in [
+            "2026-01-01 00:00:00",
+            "2026-01-01 00:00:00.500",
+            "2026-01-01 00:00:02",
+        ] {
+            let mut instance = Instance::new(
+                "test".to_string(),
+                "kernel".to_string(),
+                "image".to_string(),
+                1,
+                128,
+                0,
+                "192.168.30.2".to_string(),
+                8084,
+            );
+            instance.created_at = at(created_at);
+            instance.set_status("terminated".to_string());
+            instance.insert(&pool).await.unwrap();
+        }
+        let request = Request::new(
+            "test".to_string(),
+            0,
+            RequestOutcome::ServedLocally,
+            at("2026-01-01 00:00:00.500"),
+        );
+        Request::insert_batch(&[request], &pool).await.unwrap();
+
+        // The same second, written by a benchmark in UTC, in its local time, and as the
+        // older benchmarks did, with more digits than the rows: compared as text the
+        // bounds with an offset or a fraction missed the first rows
+        for (start, end) in [
+            ("2026-01-01T00:00:00Z", "2026-01-01T00:00:01Z"),
+            ("2026-01-01T02:00:00+02:00", "2026-01-01T02:00:01+02:00"),
+            ("2025-12-31T19:00:00-05:00", "2025-12-31T19:00:01-05:00"),
+            (
+                "2026-01-01 00:00:00.000000000",
+                "2026-01-01 00:00:01.000000000",
+            ),
+        ] {
+            let epoch = stats(&pool, &Window::parse(start, end).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(epoch.requests, 2, "{start} - {end}");
+            assert_eq!(epoch.received, 1, "{start} - {end}");
+        }
+
+        // The bounds are included, to the millisecond
+        let epoch = stats(
+            &pool,
+            &Window::parse("2026-01-01T00:00:00.500Z", "2026-01-01T00:00:02Z").unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!((epoch.requests, epoch.received), (2, 1));
+        let epoch = stats(
+            &pool,
+            &Window::parse("2026-01-01T00:00:00.501Z", "2026-01-01T00:00:01.999Z").unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!((epoch.requests, epoch.received), (0, 0));
+
+        // Stamped to the microsecond as the node does, in the last half millisecond of a
+        // window ending when the stats are asked: the row is within it, as by chrono
+        let late = window::parse_utc("2026-01-01 00:00:02.999700").unwrap();
+        let mut instance = Instance::new(
+            "test".to_string(),
+            "kernel".to_string(),
+            "image".to_string(),
+            1,
+            128,
+            0,
+            "192.168.30.2".to_string(),
+            8084,
+        );
+        instance.created_at = late.naive_utc();
+        instance.set_status("terminated".to_string());
+        instance.insert(&pool).await.unwrap();
+        let request = Request::new(
+            "test".to_string(),
+            0,
+            RequestOutcome::ServedLocally,
+            late.naive_utc(),
+        );
+        Request::insert_batch(&[request], &pool).await.unwrap();
+        let window = Window::new(at("2026-01-01 00:00:02.001").and_utc(), late).unwrap();
+        let epoch = stats(&pool, &window).await.unwrap();
+        assert_eq!((epoch.requests, epoch.received), (1, 1));
+    }
+}
@@ -1,6 +1,18 @@
 use serde::{Deserialize, Serialize};
 use sqlx::Pool;
 
+use crate::{
+    api::{
+        invoke::InvokeFunction,
+        quota::{QuotaLimits, QuotaUsage},
+    },
+    execution_environment::{
+        boot_args::GuestArgs,
+        metrics::{ColdStartTimings, MetricsSummary},
+    },
+    orchestrator::global::emergency::Emergency,
+};
+
 /// Struct that represents a function instance in the database
 #[derive(Debug, sqlx::FromRow, Serialize, Deserialize)]
 pub struct Instance {
@@ -14,7 +26,31 @@ pub struct Instance {
     pub port: i32,
     pub hops: i32,
     pub status: String,
+    /// In UTC, by the clock of the node, see `db::window` for the stats
     pub created_at: chrono::NaiveDateTime,
+    /// Environment variables the instance was booted with, as a JSON object
+    pub env: Option<String>,
+    /// Arguments the instance was booted with, as a JSON array
+    pub args: Option<String>,
+    /// API key of the request the instance was started for, never sent to the clients
+    #[serde(skip_serializing, default)]
+    pub api_key: Option<String>,
+    /// Why the instance failed, when its status is `failed`
+    #[serde(default)]
+    pub error: Option<String>,
+    /// Whether the instance was started for an emergency request
+    #[serde(default)]
+    pub emergency: bool,
+    /// Run of the node that started the instance, see `NodeRun`
+    #[serde(default)]
+    pub run_id: Option<i64>,
+    /// Which attempt at starting an instance for its request this one was, from 1
+    #[serde(default = "first_attempt")]
+    pub attempt: i64,
+}
+
+fn first_attempt() -> i64 {
+    1
 }
 
 impl Instance {
@@ -41,18 +77,52 @@ impl Instance {
             hops,
             status: "started".to_string(),
             created_at: chrono::Utc::now().naive_utc(),
+            env: None,
+            args: None,
+            api_key: None,
+            error: None,
+            emergency: false,
+            run_id: None,
+            attempt: 1,
         }
     }
 
+    /// Set whether the instance is started for an emergency request
+    pub fn with_emergency(mut self, emergency: bool) -> Self {
+        self.emergency = emergency;
+        self
+    }
+
+    /// Set which attempt at starting an instance for its request this one is, from 1
+    pub fn with_attempt(mut self, attempt: u32) -> Self {
+        self.attempt = attempt.into();
+        self
+    }
+
+    /// Set the API key of the request the instance is started for
+    pub fn with_api_key(mut self, api_key: Option<String>) -> Self {
+        self.api_key = api_key;
+        self
+    }
+
+    /// Set the environment variables and the arguments the instance is booted with
+    pub fn with_guest_args(mut self, guest_args: &GuestArgs) -> Self {
+        self.env = (!guest_args.env.is_empty())
+            .then(|| serde_json::to_string(&guest_args.env).unwrap_or_default());
+        self.args = (!guest_args.args.is_empty())
+            .then(|| serde_json::to_string(&guest_args.args).unwrap_or_default());
+        self
+    }
+
     /// Set the status of the instance
     pub fn set_status(&mut self, status: String) {
         self.status = status;
     }
 
-    /// Insert the instance into the database
+    /// Insert the instance into the database, stamped with the current run of the node
     pub async fn insert(&mut self, pool: &Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
-        self.id = sqlx::query(
-            "INSERT INTO instances (functions, kernel, image, vcpus, memory, ip, port, hops, status, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)",
+        (self.id, self.run_id) = sqlx::query_as(
+            "INSERT INTO instances (functions, kernel, image, vcpus, memory, ip, port, hops, status, created_at, env, args, api_key, emergency, attempt, run_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, (SELECT MAX(id) FROM node_runs)) RETURNING id, run_id",
         )
         .bind(&self.functions)
         .bind(&self.kernel)
@@ -64,9 +134,13 @@ impl Instance {
         .bind(&self.hops)
         .bind(&self.status)
         .bind(&self.created_at)
-        .execute(pool)
-        .await?
-        .last_insert_rowid();
+        .bind(&self.env)
+        .bind(&self.args)
+        .bind(&self.api_key)
+        .bind(self.emergency)
+        .bind(self.attempt)
+        .fetch_one(pool)
+        .await?;
 
         Ok(())
     }
@@ -74,7 +148,7 @@ impl Instance {
     /// Update the instance in the database
     pub async fn update(&self, pool: &Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
         sqlx::query(
-            "UPDATE instances SET functions = $1, kernel = $2, image = $3, vcpus = $4, memory = $5, ip = $6, port = $7, hops = $8, status = $9, created_at = $10 WHERE id = $11",
+            "UPDATE instances SET functions = $1, kernel = $2, image = $3, vcpus = $4, memory = $5, ip = $6, port = $7, hops = $8, status = $9, created_at = $10, env = $11, args = $12, api_key = $13, emergency = $14 WHERE id = $15",
         )
         .bind(&self.functions)
         .bind(&self.kernel)
@@ -86,12 +160,72 @@ impl Instance {
         .bind(&self.hops)
         .bind(&self.status)
         .bind(&self.created_at)
+        .bind(&self.env)
+        .bind(&self.args)
+        .bind(&self.api_key)
+        .bind(self.emergency)
         .bind(&self.id)
         .execute(pool)
         .await?;
         Ok(())
     }
 
+    /// Update the status of several instances, given as (id, status), in a single transaction
+    pub async fn update_status_batch(
+        updates: &[(i64, String)],
+        pool: &Pool<sqlx::Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        for (id, status) in updates {
+            sqlx::query("UPDATE instances SET status = $1 WHERE id = $2")
+                .bind(status)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Record why an instance failed
+    pub async fn set_error(
+        pool: &Pool<sqlx::Sqlite>,
+        id: i64,
+        error: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE instances SET error = $1 WHERE id = $2")
+            .bind(error)
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(())
+    }
+
+    /// The last failed instances, the most recent first
+    pub async fn last_failed(
+        pool: &Pool<sqlx::Sqlite>,
+        limit: i64,
+    ) -> Result<Vec<Instance>, sqlx::Error> {
+        sqlx::query_as::<_, Instance>(
+            "SELECT * FROM instances WHERE status = 'failed' ORDER BY id DESC LIMIT $1",
+        )
+        .bind(limit)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Fail the instances the previous runs of the node left started: they died with their
+    /// run, and would hold the quota of their key for good
+    /// # Returns
+    /// * The number of instances failed
+    pub async fn fail_stale(pool: &Pool<sqlx::Sqlite>) -> Result<u64, sqlx::Error> {
+        let failed = sqlx::query(
+            "UPDATE instances SET status = 'failed', error = 'The node stopped while the instance was running' WHERE status = 'started' AND run_id IS NOT (SELECT MAX(id) FROM node_runs)",
+        )
+        .execute(pool)
+        .await?;
+        Ok(failed.rows_affected())
+    }
+
     /// Delete the instance from the database
     pub async fn delete(&self, pool: &Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
         sqlx::query("DELETE FROM instances WHERE id = $1")
@@ -122,6 +256,705 @@ impl Instance {
     }
 }
 
+/// Struct that represents the summary of the metrics of a function instance in the database
+#[derive(Debug, sqlx::FromRow, Serialize, Deserialize)]
+pub struct InstanceMetrics {
+    #[serde(skip)]
+    pub id: i64,
+    #[serde(skip)]
+    pub instance_id: i64,
+    pub net_rx_bytes: i64,
+    pub net_tx_bytes: i64,
+    pub block_read_ops: i64,
+    pub block_write_ops: i64,
+    pub vcpu_exits: i64,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl InstanceMetrics {
+    /// Create the metrics of an instance from their summary
+    pub fn new(instance_id: i64, summary: MetricsSummary) -> Self {
+        InstanceMetrics {
+            id: 0,
+            instance_id,
+            net_rx_bytes: summary.net_rx_bytes,
+            net_tx_bytes: summary.net_tx_bytes,
+            block_read_ops: summary.block_read_ops,
+            block_write_ops: summary.block_write_ops,
+            vcpu_exits: summary.vcpu_exits,
+            created_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    /// Insert the metrics into the database
+    pub async fn insert(&mut self, pool: &Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+        self.id = sqlx::query(
+            "INSERT INTO instance_metrics (instance_id, net_rx_bytes, net_tx_bytes, block_read_ops, block_write_ops, vcpu_exits, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(self.instance_id)
+        .bind(self.net_rx_bytes)
+        .bind(self.net_tx_bytes)
+        .bind(self.block_read_ops)
+        .bind(self.block_write_ops)
+        .bind(self.vcpu_exits)
+        .bind(self.created_at)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(())
+    }
+
+    /// Get the latest metrics of an instance
+    pub async fn get_by_instance(
+        instance_id: i64,
+        pool: &Pool<sqlx::Sqlite>,
+    ) -> Result<Option<InstanceMetrics>, sqlx::Error> {
+        let metrics = sqlx::query_as::<_, InstanceMetrics>(
+            "SELECT * FROM instance_metrics WHERE instance_id = $1 ORDER BY id DESC LIMIT 1",
+        )
+        .bind(instance_id)
+        .fetch_optional(pool)
+        .await?;
+        Ok(metrics)
+    }
+}
+
+/// Type of an emergency event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmergencyEventType {
+    /// The node received an emergency
+    Start,
+    /// The emergency was stopped
+    Stop,
+    /// The emergency ended without being stopped
+    Expired,
+}
+
+impl EmergencyEventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmergencyEventType::Start => "start",
+            EmergencyEventType::Stop => "stop",
+            EmergencyEventType::Expired => "expired",
+        }
+    }
+}
+
+/// Struct that represents an emergency event seen by the node in the database
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct EmergencyEvent {
+    pub id: i64,
+    pub event: String,
+    pub emergency_id: Option<String>,
+    pub position_x: f64,
+    pub position_y: f64,
+    pub radius: f64,
+    /// Vertices of the zone as a JSON array, None for a circle
+    pub polygon: Option<String>,
+    pub inside: bool,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl EmergencyEvent {
+    /// Create a new emergency event
+    /// # Arguments
+    /// * `event` - Type of the event
+    /// * `emergency_id` - Identifier of the emergency, if known
+    /// * `emergency` - Zone of the emergency
+    /// * `inside` - Whether the node was in the emergency zone
+    pub fn new(
+        event: EmergencyEventType,
+        emergency_id: Option<String>,
+        emergency: &Emergency,
+        inside: bool,
+    ) -> Self {
+        EmergencyEvent {
+            id: 0,
+            event: event.as_str().to_string(),
+            emergency_id,
+            position_x: emergency.position.0,
+            position_y: emergency.position.1,
+            radius: emergency.radius,
+            polygon: emergency
+                .polygon
+                .as_ref()
+                .map(|vertices| serde_json::to_string(vertices).unwrap()),
+            inside,
+            created_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    /// Insert the event into the database
+    pub async fn insert(&mut self, pool: &Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+        self.id = sqlx::query(
+            "INSERT INTO emergency_events (event, emergency_id, position_x, position_y, radius, polygon, inside, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+        )
+        .bind(&self.event)
+        .bind(&self.emergency_id)
+        .bind(self.position_x)
+        .bind(self.position_y)
+        .bind(self.radius)
+        .bind(&self.polygon)
+        .bind(self.inside)
+        .bind(self.created_at)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(())
+    }
+
+    /// List all the emergency events, oldest first
+    pub async fn list(pool: &Pool<sqlx::Sqlite>) -> Result<Vec<EmergencyEvent>, sqlx::Error> {
+        sqlx::query_as::<_, EmergencyEvent>("SELECT * FROM emergency_events ORDER BY id")
+            .fetch_all(pool)
+            .await
+    }
+}
+
+/// Outcome of a request received by the node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestOutcome {
+    /// The function was executed on this node
+    ServedLocally,
+    /// The request was forwarded to the node with the given address
+    OffloadedTo(String),
+    /// The request was refused, or no node could take it
+    Rejected,
+    /// The function could not be executed
+    Failed,
+    /// The request could not be answered before its deadline, nothing was run
+    Shed,
+}
+
+impl RequestOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestOutcome::ServedLocally => "served_locally",
+            RequestOutcome::OffloadedTo(_) => "offloaded",
+            RequestOutcome::Rejected => "rejected",
+            RequestOutcome::Failed => "failed",
+            RequestOutcome::Shed => "shed",
+        }
+    }
+}
+
+/// Struct that represents a request received on /invoke in the database
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct Request {
+    pub id: i64,
+    pub function: String,
+    pub outcome: String,
+    pub offloaded_to: Option<String>,
+    pub hops: i32,
+    /// In UTC, by the clock of the node, see `db::window` for the stats
+    pub received_at: chrono::NaiveDateTime,
+    pub completed_at: chrono::NaiveDateTime,
+    /// Verified identity of the node that forwarded the request, if any
+    pub forwarded_by: Option<String>,
+    /// Identifier of the trace of the offload decisions, served on /debug/offloads
+    pub offload_trace_id: Option<i64>,
+    /// Run of the node that received the request, see `NodeRun`
+    #[serde(default)]
+    pub run_id: Option<i64>,
+    /// Whether a chaos injection touched the request
+    #[serde(default)]
+    pub chaos: bool,
+}
+
+impl Request {
+    /// Create a new request, completed now
+    pub fn new(
+        function: String,
+        hops: i32,
+        outcome: RequestOutcome,
+        received_at: chrono::NaiveDateTime,
+    ) -> Self {
+        Request {
+            id: 0,
+            function,
+            outcome: outcome.as_str().to_string(),
+            offloaded_to: match outcome {
+                RequestOutcome::OffloadedTo(address) => Some(address),
+                _ => None,
+            },
+            hops,
+            received_at,
+            completed_at: chrono::Utc::now().naive_utc(),
+            forwarded_by: None,
+            offload_trace_id: None,
+            run_id: None,
+            chaos: false,
+        }
+    }
+
+    /// Set the verified identity of the node that forwarded the request
+    pub fn with_forwarded_by(self, forwarded_by: Option<String>) -> Self {
+        Self {
+            forwarded_by,
+            ..self
+        }
+    }
+
+    /// Set the identifier of the trace of the offload decisions of the request
+    pub fn with_offload_trace(self, offload_trace_id: Option<u64>) -> Self {
+        Self {
+            offload_trace_id: offload_trace_id.map(|id| id as i64),
+            ..self
+        }
+    }
+
+    /// Set whether a chaos injection touched the request
+    pub fn with_chaos(self, chaos: bool) -> Self {
+        Self { chaos, ..self }
+    }
+
+    /// Insert a batch of requests into the database, in a single transaction.
+    /// They are stamped with the current run of the node.
+    pub async fn insert_batch(
+        requests: &[Request],
+        pool: &Pool<sqlx::Sqlite>,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = pool.begin().await?;
+        for request in requests {
+            sqlx::query(
+                "INSERT INTO requests (function, outcome, offloaded_to, hops, received_at, completed_at, forwarded_by, offload_trace_id, chaos, run_id) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, (SELECT MAX(id) FROM node_runs))",
+            )
+            .bind(&request.function)
+            .bind(&request.outcome)
+            .bind(&request.offloaded_to)
+            .bind(request.hops)
+            .bind(request.received_at)
+            .bind(request.completed_at)
+            .bind(&request.forwarded_by)
+            .bind(request.offload_trace_id)
+            .bind(request.chaos)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /// List all the requests, oldest first
+    pub async fn list(pool: &Pool<sqlx::Sqlite>) -> Result<Vec<Request>, sqlx::Error> {
+        sqlx::query_as::<_, Request>("SELECT * FROM requests ORDER BY id")
+            .fetch_all(pool)
+            .await
+    }
+}
+
+/// Struct that represents a start of the node in the database, with the configuration it
+/// resolved. The instances and the requests recorded afterwards belong to the last run.
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct NodeRun {
+    pub id: i64,
+    pub started_at: chrono::NaiveDateTime,
+    pub address: String,
+    /// Strategy ranking the neighbors
+    pub strategy: String,
+    /// Runtime of the instances, firecracker or simulate
+    pub runtime: String,
+    /// Network of the instances
+    pub cidr: String,
+    /// Limits of the resources of the instances, as a JSON object
+    pub limits: String,
+    /// Version reported by firecracker, None when the instances are simulated
+    pub firecracker: Option<String>,
+    /// Digest of the kernel of the instances, None when the instances are simulated
+    pub kernel_sha256: Option<String>,
+    /// Version of the node
+    pub version: String,
+    /// Commit the node was built from, if known
+    pub git_hash: Option<String>,
+    /// Arguments of the node, redacted as in the diagnostics bundle
+    pub config: String,
+}
+
+impl NodeRun {
+    /// Create a new run, started now by this build of the node
+    pub fn new(address: String, strategy: String, runtime: String, cidr: String) -> Self {
+        NodeRun {
+            id: 0,
+            started_at: chrono::Utc::now().naive_utc(),
+            address,
+            strategy,
+            runtime,
+            cidr,
+            limits: "{}".to_string(),
+            firecracker: None,
+            kernel_sha256: None,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: option_env!("SPARE_GIT_HASH").map(str::to_string),
+            config: String::new(),
+        }
+    }
+
+    /// Set the limits of the resources of the instances
+    pub fn with_limits(mut self, limits: serde_json::Value) -> Self {
+        self.limits = limits.to_string();
+        self
+    }
+
+    /// Set the version of firecracker and the digest of the kernel
+    pub fn with_machines(
+        mut self,
+        firecracker: Option<String>,
+        kernel_sha256: Option<String>,
+    ) -> Self {
+        self.firecracker = firecracker;
+        self.kernel_sha256 = kernel_sha256;
+        self
+    }
+
+    /// Set the arguments of the node, already redacted
+    pub fn with_config(mut self, config: String) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Insert the run into the database, the rows recorded from now on belong to it
+    pub async fn insert(&mut self, pool: &Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+        self.id = sqlx::query(
+            "INSERT INTO node_runs (started_at, address, strategy, runtime, cidr, limits, firecracker, kernel_sha256, version, git_hash, config) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)",
+        )
+        .bind(self.started_at)
+        .bind(&self.address)
+        .bind(&self.strategy)
+        .bind(&self.runtime)
+        .bind(&self.cidr)
+        .bind(&self.limits)
+        .bind(&self.firecracker)
+        .bind(&self.kernel_sha256)
+        .bind(&self.version)
+        .bind(&self.git_hash)
+        .bind(&self.config)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+        Ok(())
+    }
+
+    /// List the last runs, the most recent first
+    pub async fn list(pool: &Pool<sqlx::Sqlite>, limit: i64) -> Result<Vec<NodeRun>, sqlx::Error> {
+        sqlx::query_as::<_, NodeRun>("SELECT * FROM node_runs ORDER BY id DESC LIMIT $1")
+            .bind(limit)
+            .fetch_all(pool)
+            .await
+    }
+}
+
+/// Struct that represents an iteration of a calibration run in the database
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct Calibration {
+    pub id: i64,
+    pub run_id: String,
+    pub image: String,
+    pub iteration: i64,
+    /// Time to create the machine (in microseconds), None if the iteration failed
+    pub create_us: Option<i64>,
+    pub boot_us: Option<i64>,
+    pub handshake_us: Option<i64>,
+    pub execute_us: Option<i64>,
+    /// Why the iteration failed
+    pub error: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl Calibration {
+    /// Create a new iteration of the run `run_id`, from its timings or its error
+    pub fn new(
+        run_id: String,
+        image: String,
+        iteration: i64,
+        result: Result<ColdStartTimings, String>,
+    ) -> Self {
+        let (timings, error) = match result {
+            Ok(timings) => (Some(timings), None),
+            Err(e) => (None, Some(e)),
+        };
+        Calibration {
+            id: 0,
+            run_id,
+            image,
+            iteration,
+            create_us: timings.map(|t| t.create.as_micros() as i64),
+            boot_us: timings.map(|t| t.boot.as_micros() as i64),
+            handshake_us: timings.map(|t| t.handshake.as_micros() as i64),
+            execute_us: timings.map(|t| t.execute.as_micros() as i64),
+            error,
+            created_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    /// Insert the iteration into the database
+    pub async fn insert(&mut self, pool: &Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+        self.id = sqlx::query(
+            "INSERT INTO calibrations (run_id, image, iteration, create_us, boot_us, handshake_us, execute_us, error, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)",
+        )
+        .bind(&self.run_id)
+        .bind(&self.image)
+        .bind(self.iteration)
+        .bind(self.create_us)
+        .bind(self.boot_us)
+        .bind(self.handshake_us)
+        .bind(self.execute_us)
+        .bind(&self.error)
+        .bind(self.created_at)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(())
+    }
+
+    /// List the iterations of a run, in order
+    pub async fn list_run(
+        run_id: &str,
+        pool: &Pool<sqlx::Sqlite>,
+    ) -> Result<Vec<Calibration>, sqlx::Error> {
+        sqlx::query_as::<_, Calibration>(
+            "SELECT * FROM calibrations WHERE run_id = $1 ORDER BY iteration",
+        )
+        .bind(run_id)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// List the iterations of the most recent run, empty if the node was never calibrated
+    pub async fn last_run(pool: &Pool<sqlx::Sqlite>) -> Result<Vec<Calibration>, sqlx::Error> {
+        sqlx::query_as::<_, Calibration>(
+            "SELECT * FROM calibrations WHERE run_id = (SELECT run_id FROM calibrations ORDER BY id DESC LIMIT 1) ORDER BY iteration",
+        )
+        .fetch_all(pool)
+        .await
+    }
+}
+
+/// Struct that represents the quota of an API key in the database
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct Quota {
+    pub api_key: String,
+    pub max_vcpus: i64,
+    /// In MiB
+    pub max_memory: i64,
+    pub max_instances: i64,
+    pub updated_at: chrono::NaiveDateTime,
+}
+
+impl Quota {
+    /// Create a new quota for an API key
+    pub fn new(api_key: String, limits: QuotaLimits) -> Self {
+        Quota {
+            api_key,
+            max_vcpus: limits.max_vcpus,
+            max_memory: limits.max_memory,
+            max_instances: limits.max_instances,
+            updated_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    /// Get the limits of the quota
+    pub fn limits(&self) -> QuotaLimits {
+        QuotaLimits {
+            max_vcpus: self.max_vcpus,
+            max_memory: self.max_memory,
+            max_instances: self.max_instances,
+        }
+    }
+
+    /// Insert the quota into the database, replacing the previous one of the key
+    pub async fn upsert(&self, pool: &Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO quotas (api_key, max_vcpus, max_memory, max_instances, updated_at) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (api_key) DO UPDATE SET max_vcpus = excluded.max_vcpus, max_memory = excluded.max_memory, max_instances = excluded.max_instances, updated_at = excluded.updated_at",
+        )
+        .bind(&self.api_key)
+        .bind(self.max_vcpus)
+        .bind(self.max_memory)
+        .bind(self.max_instances)
+        .bind(self.updated_at)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// List the quotas of all the keys
+    pub async fn list(pool: &Pool<sqlx::Sqlite>) -> Result<Vec<Quota>, sqlx::Error> {
+        sqlx::query_as::<_, Quota>("SELECT * FROM quotas ORDER BY api_key")
+            .fetch_all(pool)
+            .await
+    }
+
+    /// Get the resources used by the instances still running for each API key
+    pub async fn usage(
+        pool: &Pool<sqlx::Sqlite>,
+    ) -> Result<Vec<(String, QuotaUsage)>, sqlx::Error> {
+        let rows: Vec<(String, i64, i64, i64)> = sqlx::query_as(
+            "SELECT api_key, SUM(vcpus), SUM(memory), COUNT(id) FROM instances WHERE api_key IS NOT NULL AND status = 'started' GROUP BY api_key",
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(api_key, vcpus, memory, instances)| {
+                (
+                    api_key,
+                    QuotaUsage {
+                        vcpus,
+                        memory,
+                        instances,
+                    },
+                )
+            })
+            .collect())
+    }
+}
+
+/// Struct that represents a scheduled invocation in the database
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: i64,
+    pub function: String,
+    /// The invocation, as sent to /invoke
+    pub request: String,
+    pub cron_expr: Option<String>,
+    /// Seconds between two runs
+    pub interval: Option<i64>,
+    pub paused: bool,
+    pub next_run: chrono::NaiveDateTime,
+    pub last_run: Option<chrono::NaiveDateTime>,
+    /// Outcome of the last run, or why it was skipped
+    pub last_status: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl Schedule {
+    /// Create a new schedule of an invocation, first run at `next_run`
+    pub fn new(
+        invoke: &InvokeFunction,
+        cron_expr: Option<String>,
+        interval: Option<i64>,
+        next_run: chrono::NaiveDateTime,
+    ) -> Self {
+        Schedule {
+            id: 0,
+            function: invoke.function.clone(),
+            request: serde_json::to_string(invoke).unwrap(),
+            cron_expr,
+            interval,
+            paused: false,
+            next_run,
+            last_run: None,
+            last_status: None,
+            created_at: chrono::Utc::now().naive_utc(),
+        }
+    }
+
+    /// Get the invocation of the schedule
+    pub fn invoke(&self) -> Result<InvokeFunction, serde_json::Error> {
+        serde_json::from_str(&self.request)
+    }
+
+    /// Insert the schedule into the database
+    pub async fn insert(&mut self, pool: &Pool<sqlx::Sqlite>) -> Result<(), sqlx::Error> {
+        self.id = sqlx::query(
+            "INSERT INTO schedules (function, request, cron_expr, interval, paused, next_run, created_at) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(&self.function)
+        .bind(&self.request)
+        .bind(&self.cron_expr)
+        .bind(self.interval)
+        .bind(self.paused)
+        .bind(self.next_run)
+        .bind(self.created_at)
+        .execute(pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(())
+    }
+
+    /// Get a schedule by id
+    pub async fn get(pool: &Pool<sqlx::Sqlite>, id: i64) -> Result<Option<Schedule>, sqlx::Error> {
+        sqlx::query_as::<_, Schedule>("SELECT * FROM schedules WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await
+    }
+
+    /// List all the schedules, oldest first
+    pub async fn list(pool: &Pool<sqlx::Sqlite>) -> Result<Vec<Schedule>, sqlx::Error> {
+        sqlx::query_as::<_, Schedule>("SELECT * FROM schedules ORDER BY id")
+            .fetch_all(pool)
+            .await
+    }
+
+    /// List the schedules not paused with a run due at `now`
+    pub async fn due(
+        pool: &Pool<sqlx::Sqlite>,
+        now: chrono::NaiveDateTime,
+    ) -> Result<Vec<Schedule>, sqlx::Error> {
+        sqlx::query_as::<_, Schedule>(
+            "SELECT * FROM schedules WHERE paused = 0 AND next_run <= $1 ORDER BY next_run",
+        )
+        .bind(now)
+        .fetch_all(pool)
+        .await
+    }
+
+    /// Pause or resume a schedule, a resumed schedule next runs at `next_run`
+    /// # Returns
+    /// * Whether the schedule exists
+    pub async fn set_paused(
+        pool: &Pool<sqlx::Sqlite>,
+        id: i64,
+        paused: bool,
+        next_run: Option<chrono::NaiveDateTime>,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE schedules SET paused = $1, next_run = COALESCE($2, next_run) WHERE id = $3",
+        )
+        .bind(paused)
+        .bind(next_run)
+        .bind(id)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Record a run of the schedule, or the runs skipped, and when it runs next.
+    /// The time of the last run is kept if `last_run` is None.
+    pub async fn record_run(
+        pool: &Pool<sqlx::Sqlite>,
+        id: i64,
+        last_run: Option<chrono::NaiveDateTime>,
+        last_status: &str,
+        next_run: chrono::NaiveDateTime,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE schedules SET last_run = COALESCE($1, last_run), last_status = $2, next_run = $3 WHERE id = $4",
+        )
+        .bind(last_run)
+        .bind(last_status)
+        .bind(next_run)
+        .bind(id)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Delete a schedule
+    /// # Returns
+    /// * Whether the schedule existed
+    pub async fn delete(pool: &Pool<sqlx::Sqlite>, id: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM schedules WHERE id = $1")
+            .bind(id)
+            .execute(pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
 // Unit tests
 #[cfg(test)]
 mod tests {
@@ -148,6 +981,52 @@ mod tests {
         assert_eq!(instance.id, 1);
     }
 
+    #[actix_web::test]
+    async fn test_insert_guest_args() {
+        let pool = db::establish_connection().await.unwrap();
+        let env = std::collections::HashMap::from([("MODE".to_string(), "fast".to_string())]);
+        let args = vec!["--threads=4".to_string()];
+        let mut instance = Instance::new(
+            "test".to_string(),
+            "test".to_string(),
+            "test".to_string(),
+            1,
+            1,
+            1,
+            "test".to_string(),
+            1,
+        )
+        .with_guest_args(&GuestArgs::new(Some(&env), Some(&args)));
+        instance.insert(&pool).await.unwrap();
+
+        let instance = Instance::get_by_id(instance.id, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(instance.env.as_deref(), Some(r#"{"MODE":"fast"}"#));
+        assert_eq!(instance.args.as_deref(), Some(r#"["--threads=4"]"#));
+
+        // Nothing is stored for an instance booted without them
+        let mut instance = Instance::new(
+            "test".to_string(),
+            "test".to_string(),
+            "test".to_string(),
+            1,
+            1,
+            1,
+            "test".to_string(),
+            1,
+        )
+        .with_guest_args(&GuestArgs::default());
+        instance.insert(&pool).await.unwrap();
+        let instance = Instance::get_by_id(instance.id, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(instance.env, None);
+        assert_eq!(instance.args, None);
+    }
+
     #[actix_web::test]
     async fn test_delete() {
         let pool = db::establish_connection().await.unwrap();
@@ -188,4 +1067,366 @@ mod tests {
         let instances = Instance::list(&pool).await.unwrap();
         assert_eq!(instances.len(), 1);
     }
+
+    #[actix_web::test]
+    async fn test_metrics() {
+        let pool = db::establish_connection().await.unwrap();
+        let mut instance = Instance::new(
+            "test".to_string(),
+            "test".to_string(),
+            "test".to_string(),
+            1,
+            1,
+            1,
+            "test".to_string(),
+            1,
+        );
+        instance.insert(&pool).await.unwrap();
+        assert!(InstanceMetrics::get_by_instance(instance.id, &pool)
+            .await
+            .unwrap()
+            .is_none());
+
+        let summary = MetricsSummary {
+            net_rx_bytes: 1,
+            net_tx_bytes: 2,
+            block_read_ops: 3,
+            block_write_ops: 4,
+            vcpu_exits: 5,
+        };
+        InstanceMetrics::new(instance.id, summary)
+            .insert(&pool)
+            .await
+            .unwrap();
+        let metrics = InstanceMetrics::get_by_instance(instance.id, &pool)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(metrics.instance_id, instance.id);
+        assert_eq!(metrics.net_tx_bytes, 2);
+        assert_eq!(metrics.vcpu_exits, 5);
+    }
+
+    #[actix_web::test]
+    async fn test_emergency_events() {
+        use crate::orchestrator::{global::identity::Node, Orchestrator};
+
+        let pool = db::establish_connection().await.unwrap();
+        assert!(EmergencyEvent::list(&pool).await.unwrap().is_empty());
+
+        let orchestrator = Orchestrator::new(vec![], Node::new("node".to_string(), (45.0, 9.0)));
+        let emergency = Emergency {
+            position: (45.0, 9.001),
+            radius: 1000.0,
+            polygon: None,
+        };
+        orchestrator
+            .set_emergency(true, emergency.clone())
+            .insert(&pool)
+            .await
+            .unwrap();
+        // The controller stops the emergencies with a placeholder position
+        orchestrator
+            .set_emergency(
+                false,
+                Emergency {
+                    position: (0.0, 0.0),
+                    radius: 0.0,
+                    polygon: None,
+                },
+            )
+            .insert(&pool)
+            .await
+            .unwrap();
+
+        let events = EmergencyEvent::list(&pool).await.unwrap();
+        assert_eq!(events.len(), 2);
+        let (start, stop) = (&events[0], &events[1]);
+        assert_eq!(start.event, "start");
+        assert_eq!(stop.event, "stop");
+        assert!(start.inside && stop.inside);
+        assert!(start.emergency_id.is_some());
+        assert_eq!(start.emergency_id, stop.emergency_id);
+        assert_eq!((stop.position_x, stop.position_y), emergency.position);
+        assert_eq!(stop.radius, 1000.0);
+        assert_eq!(stop.polygon, None);
+        assert!(start.created_at <= stop.created_at);
+
+        // The vertices of a polygon are recorded with the circle around them
+        let zone = Emergency::polygon(vec![(44.99, 8.99), (44.99, 9.01), (45.01, 9.0)]).unwrap();
+        let event = orchestrator.set_emergency(true, zone.clone());
+        assert!(event.inside);
+        assert_eq!(event.radius, zone.radius);
+        assert_eq!(
+            event.polygon.as_deref(),
+            Some("[[44.99,8.99],[44.99,9.01],[45.01,9.0]]")
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_calibrations() {
+        use std::time::Duration;
+
+        let pool = db::establish_connection().await.unwrap();
+        let timings = ColdStartTimings {
+            create: Duration::from_millis(12),
+            boot: Duration::from_millis(3),
+            handshake: Duration::from_micros(1500),
+            execute: Duration::from_micros(250),
+        };
+        for (iteration, result) in [Ok(timings), Err("Timed out".to_string())]
+            .into_iter()
+            .enumerate()
+        {
+            Calibration::new(
+                "run".to_string(),
+                "image".to_string(),
+                iteration as i64,
+                result,
+            )
+            .insert(&pool)
+            .await
+            .unwrap();
+        }
+        Calibration::new("other".to_string(), "image".to_string(), 0, Ok(timings))
+            .insert(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(Calibration::last_run(&pool).await.unwrap().len(), 1);
+        let run = Calibration::list_run("run", &pool).await.unwrap();
+        assert_eq!(run.len(), 2);
+        assert_eq!(run[0].create_us, Some(12000));
+        assert_eq!(run[0].handshake_us, Some(1500));
+        assert_eq!(run[0].execute_us, Some(250));
+        assert_eq!(run[0].error, None);
+        assert_eq!(run[1].boot_us, None);
+        assert_eq!(run[1].error.as_deref(), Some("Timed out"));
+    }
+
+    #[actix_web::test]
+    async fn test_quotas() {
+        let pool = db::establish_connection().await.unwrap();
+        let limits = QuotaLimits {
+            max_vcpus: 4,
+            max_memory: 1024,
+            max_instances: 2,
+        };
+        Quota::new("tenant".to_string(), limits)
+            .upsert(&pool)
+            .await
+            .unwrap();
+        let limits = QuotaLimits {
+            max_instances: 3,
+            ..limits
+        };
+        Quota::new("tenant".to_string(), limits)
+            .upsert(&pool)
+            .await
+            .unwrap();
+        let quotas = Quota::list(&pool).await.unwrap();
+        assert_eq!(quotas.len(), 1);
+        assert_eq!(quotas[0].limits(), limits);
+
+        // Only the running instances of a key use its quota
+        for (status, api_key) in [
+            ("started", Some("tenant")),
+            ("started", Some("tenant")),
+            ("terminated", Some("tenant")),
+            ("started", None),
+        ] {
+            let mut instance = Instance::new(
+                "test".to_string(),
+                "test".to_string(),
+                "test".to_string(),
+                2,
+                128,
+                0,
+                "test".to_string(),
+                1,
+            )
+            .with_api_key(api_key.map(str::to_string));
+            instance.set_status(status.to_string());
+            instance.insert(&pool).await.unwrap();
+        }
+        let usage = Quota::usage(&pool).await.unwrap();
+        assert_eq!(
+            usage,
+            vec![(
+                "tenant".to_string(),
+                QuotaUsage {
+                    vcpus: 4,
+                    memory: 256,
+                    instances: 2
+                }
+            )]
+        );
+        // The key is not sent to the clients
+        let instance = Instance::list(&pool).await.unwrap().remove(0);
+        assert_eq!(instance.api_key.as_deref(), Some("tenant"));
+        assert!(!serde_json::to_string(&instance).unwrap().contains("tenant"));
+    }
+
+    #[actix_web::test]
+    async fn test_restart_quotas() {
+        use crate::utils::quota::QuotaTracker;
+        use std::sync::Arc;
+
+        let dir = std::env::temp_dir().join(format!("spare-restart-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let url = format!("sqlite://{}?mode=rwc", dir.join("spare.db").display());
+        let started = || {
+            Instance::new(
+                "test".to_string(),
+                "test".to_string(),
+                "test".to_string(),
+                2,
+                128,
+                0,
+                "test".to_string(),
+                1,
+            )
+            .with_api_key(Some("tenant".to_string()))
+        };
+        let run = || {
+            NodeRun::new(
+                "10.0.0.1:8085".to_string(),
+                "GeoDistance".to_string(),
+                "simulate".to_string(),
+                "172.16.0.0/24".to_string(),
+            )
+        };
+
+        // The node crashes with two instances of the key running, one started before the
+        // runs were recorded
+        let pool = db::connect(&url, false).await.unwrap();
+        started().insert(&pool).await.unwrap();
+        run().insert(&pool).await.unwrap();
+        started().insert(&pool).await.unwrap();
+        pool.close().await;
+
+        // Started again, the node starts an instance before the quotas are loaded
+        let pool = db::connect(&url, false).await.unwrap();
+        run().insert(&pool).await.unwrap();
+        let mut running = started();
+        running.insert(&pool).await.unwrap();
+        assert_eq!(Instance::fail_stale(&pool).await.unwrap(), 2);
+        assert_eq!(Instance::fail_stale(&pool).await.unwrap(), 0);
+
+        // Only the instance of this run uses the quota of the key
+        let quotas = Arc::new(QuotaTracker::new());
+        quotas.reconcile(Quota::usage(&pool).await.unwrap());
+        assert_eq!(
+            quotas.status("tenant").usage,
+            QuotaUsage {
+                vcpus: 2,
+                memory: 128,
+                instances: 1
+            }
+        );
+        let statuses: Vec<(Option<i64>, String)> = Instance::list(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|instance| (instance.run_id, instance.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                (None, "failed".to_string()),
+                (Some(1), "failed".to_string()),
+                (Some(2), "started".to_string()),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_node_runs() {
+        let dir = std::env::temp_dir().join(format!("spare-runs-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let url = format!("sqlite://{}?mode=rwc", dir.join("spare.db").display());
+        let instance = || {
+            Instance::new(
+                "test".to_string(),
+                "test".to_string(),
+                "test".to_string(),
+                1,
+                128,
+                0,
+                "test".to_string(),
+                1,
+            )
+        };
+        let request = || {
+            Request::new(
+                "test".to_string(),
+                0,
+                RequestOutcome::ServedLocally,
+                chrono::Utc::now().naive_utc(),
+            )
+        };
+        let start = |strategy: &str| {
+            NodeRun::new(
+                "10.0.0.1:8085".to_string(),
+                strategy.to_string(),
+                "simulate".to_string(),
+                "172.16.0.0/24".to_string(),
+            )
+            .with_limits(serde_json::json!({"max_instance_vcpus": 2}))
+            .with_config("Args { port: 8085 }".to_string())
+        };
+
+        // Recorded before the node ever started a run
+        let pool = db::connect(&url, false).await.unwrap();
+        let mut before = instance();
+        before.insert(&pool).await.unwrap();
+        assert_eq!(before.run_id, None);
+
+        let mut first = start("GeoDistance");
+        first.insert(&pool).await.unwrap();
+        let mut old = instance();
+        old.insert(&pool).await.unwrap();
+        assert_eq!(old.run_id, Some(first.id));
+        Request::insert_batch(&[request(), request()], &pool)
+            .await
+            .unwrap();
+        pool.close().await;
+
+        // Restart the node on the same database
+        let pool = db::connect(&url, false).await.unwrap();
+        let mut second = start("Probed");
+        second.insert(&pool).await.unwrap();
+        assert!(second.id > first.id);
+        let mut new = instance();
+        new.insert(&pool).await.unwrap();
+        Request::insert_batch(&[request()], &pool).await.unwrap();
+        // Updating an instance of the previous run keeps its run
+        old.set_status("terminated".to_string());
+        old.update(&pool).await.unwrap();
+
+        let runs: Vec<_> = Instance::list(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|i| i.run_id)
+            .collect();
+        assert_eq!(runs, vec![None, Some(first.id), Some(second.id)]);
+        let runs: Vec<_> = Request::list(&pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.run_id)
+            .collect();
+        assert_eq!(runs, vec![Some(first.id), Some(first.id), Some(second.id)]);
+
+        let listed = NodeRun::list(&pool, 10).await.unwrap();
+        let strategies: Vec<_> = listed.iter().map(|r| r.strategy.as_str()).collect();
+        assert_eq!(strategies, vec!["Probed", "GeoDistance"]);
+        assert_eq!(listed[0].limits, r#"{"max_instance_vcpus":2}"#);
+        assert_eq!(listed[0].version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(NodeRun::list(&pool, 1).await.unwrap().len(), 1);
+        pool.close().await;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
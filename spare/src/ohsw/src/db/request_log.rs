@@ -0,0 +1,108 @@
+//! Accounting of the requests received by the node.
+//! Requests are recorded from the hot path of /invoke, so they are only queued there
+//! and a background task writes them to the database in batches.
+use futures::{channel::mpsc, StreamExt};
+use log::error;
+use sqlx::{Pool, Sqlite};
+
+use super::models::Request;
+
+/// Maximum number of requests written in a single transaction
+pub const BATCH_SIZE: usize = 128;
+
+/// Queue of the requests to record
+#[derive(Clone)]
+pub struct RequestLog {
+    sender: mpsc::UnboundedSender<Request>,
+}
+
+impl RequestLog {
+    /// Create a new log and spawn the task that writes it to the database.
+    /// Must be called from within an actix runtime.
+    pub fn spawn(pool: Pool<Sqlite>) -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+        actix_web::rt::spawn(write_requests(pool, receiver));
+        Self { sender }
+    }
+
+    /// Queue a request, it is written to the database later
+    pub fn record(&self, request: Request) {
+        if self.sender.unbounded_send(request).is_err() {
+            error!("The request log is closed, request not recorded");
+        }
+    }
+}
+
+/// Write the queued requests until all the senders are dropped
+async fn write_requests(pool: Pool<Sqlite>, mut receiver: mpsc::UnboundedReceiver<Request>) {
+    while let Some(request) = receiver.next().await {
+        // Take whatever else is already queued, up to a batch
+        let mut batch = vec![request];
+        while batch.len() < BATCH_SIZE {
+            match receiver.try_next() {
+                Ok(Some(request)) => batch.push(request),
+                _ => break,
+            }
+        }
+        if let Err(e) = Request::insert_batch(&batch, &pool).await {
+            error!("Failed to record {} requests: {}", batch.len(), e);
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, models::RequestOutcome, window::Window};
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_request_log() {
+        let pool = db::establish_connection().await.unwrap();
+        let log = RequestLog::spawn(pool.clone());
+        let now = chrono::Utc::now().naive_utc();
+        let outcomes = [
+            RequestOutcome::ServedLocally,
+            RequestOutcome::OffloadedTo("10.0.0.2:8085".to_string()),
+            RequestOutcome::Rejected,
+            RequestOutcome::Failed,
+            RequestOutcome::Shed,
+        ];
+        for i in 0..200 {
+            let outcome = outcomes[i % outcomes.len()].clone();
+            let forwarded_by = (i % 2 == 1).then(|| "node-b".to_string());
+            log.record(
+                Request::new("test".to_string(), 1, outcome, now).with_forwarded_by(forwarded_by),
+            );
+        }
+
+        // Wait for the writer to catch up
+        let mut requests = Vec::new();
+        for _ in 0..100 {
+            requests = Request::list(&pool).await.unwrap();
+            if requests.len() == 200 {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(requests.len(), 200);
+        assert_eq!(requests[0].outcome, "served_locally");
+        assert_eq!(requests[1].outcome, "offloaded");
+        assert_eq!(requests[1].offloaded_to.as_deref(), Some("10.0.0.2:8085"));
+        assert_eq!(requests[2].outcome, "rejected");
+        assert_eq!(requests[3].offloaded_to, None);
+        assert_eq!(requests[4].outcome, "shed");
+        // The node that forwarded a request is kept for the audits
+        assert_eq!(requests[0].forwarded_by, None);
+        assert_eq!(requests[1].forwarded_by.as_deref(), Some("node-b"));
+
+        // Offloaded requests show up in the stats, even without a local instance
+        let window = Window::parse("2000-01-01T00:00:00Z", "2100-01-01T00:00:00Z").unwrap();
+        let stats = db::stats(&pool, &window).await.unwrap();
+        assert_eq!(stats.requests, 0);
+        assert_eq!(stats.received, 200);
+        assert_eq!(stats.offloaded, 40);
+        assert_eq!(stats.shed, 40);
+    }
+}
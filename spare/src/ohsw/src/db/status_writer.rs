@@ -0,0 +1,165 @@
+//! Write-behind of the status of the instances.
+//! Every terminated or failed instance updates its row, and under load the pool
+//! connections end up fighting for the database lock. The updates are queued
+//! instead, and a single task applies them in batches.
+use futures::{channel::mpsc, StreamExt};
+use log::error;
+use sqlx::{Pool, Sqlite};
+
+use super::models::Instance;
+
+/// Maximum number of updates applied in a single transaction
+pub const BATCH_SIZE: usize = 128;
+
+/// Writer of the status of the instances
+#[derive(Clone)]
+pub enum StatusWriter {
+    /// Update the row right away, from the caller
+    Direct(Pool<Sqlite>),
+    /// Queue the update, it is applied by the writer task
+    Queued(mpsc::UnboundedSender<(i64, String)>),
+}
+
+impl StatusWriter {
+    /// Create a new writer and spawn the task that applies the updates.
+    /// Must be called from within an actix runtime.
+    pub fn spawn(pool: Pool<Sqlite>) -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+        actix_web::rt::spawn(write_statuses(pool, receiver));
+        StatusWriter::Queued(sender)
+    }
+
+    /// Set the status of an instance and write it to the database
+    pub async fn set_status(&self, instance: &mut Instance, status: &str) {
+        instance.set_status(status.to_string());
+        match self {
+            StatusWriter::Direct(pool) => {
+                if let Err(e) = instance.update(pool).await {
+                    error!("Failed to update instance {}: {}", instance.id, e);
+                }
+            }
+            StatusWriter::Queued(sender) => {
+                if sender
+                    .unbounded_send((instance.id, status.to_string()))
+                    .is_err()
+                {
+                    error!(
+                        "The status writer is closed, status of instance {} not updated",
+                        instance.id
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Apply the queued updates until all the senders are dropped
+async fn write_statuses(pool: Pool<Sqlite>, mut receiver: mpsc::UnboundedReceiver<(i64, String)>) {
+    while let Some(update) = receiver.next().await {
+        // Take whatever else is already queued, up to a batch
+        let mut batch = vec![update];
+        while batch.len() < BATCH_SIZE {
+            match receiver.try_next() {
+                Ok(Some(update)) => batch.push(update),
+                _ => break,
+            }
+        }
+        if let Err(e) = Instance::update_status_batch(&batch, &pool).await {
+            error!(
+                "Failed to update the status of {} instances: {}",
+                batch.len(),
+                e
+            );
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+    use std::time::Duration;
+
+    fn instance(i: usize) -> Instance {
+        Instance::new(
+            format!("function-{}", i),
+            "kernel".to_string(),
+            "image".to_string(),
+            1,
+            128,
+            0,
+            format!("192.168.0.{}", i % 250 + 2),
+            8084,
+        )
+    }
+
+    /// Insert instances and update their status from many tasks at once
+    async fn stress(pool: Pool<Sqlite>, writer: StatusWriter) {
+        let tasks: Vec<_> = (0..50)
+            .map(|task| {
+                let pool = pool.clone();
+                let writer = writer.clone();
+                actix_web::rt::spawn(async move {
+                    for i in 0..20 {
+                        let mut instance = instance(task * 20 + i);
+                        instance.insert(&pool).await.unwrap();
+                        writer.set_status(&mut instance, "terminated").await;
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // Wait for the writer to catch up
+        let mut terminated = 0;
+        for _ in 0..200 {
+            let instances = Instance::list(&pool).await.unwrap();
+            assert_eq!(instances.len(), 1000);
+            terminated = instances
+                .iter()
+                .filter(|instance| instance.status == "terminated")
+                .count();
+            if terminated == 1000 {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(terminated, 1000);
+    }
+
+    fn database_url() -> (std::path::PathBuf, String) {
+        let path = std::env::temp_dir().join(format!("spare-db-{}.sqlite", uuid::Uuid::new_v4()));
+        let url = format!("sqlite://{}?mode=rwc", path.display());
+        (path, url)
+    }
+
+    fn remove_database(path: &std::path::Path) {
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_concurrent_writes() {
+        let (path, url) = database_url();
+        let pool = db::connect(&url, false).await.unwrap();
+        let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(journal_mode, "wal");
+
+        stress(pool.clone(), StatusWriter::spawn(pool.clone())).await;
+        pool.close().await;
+        remove_database(&path);
+    }
+
+    #[actix_web::test]
+    async fn test_direct_writes() {
+        let pool = db::establish_connection().await.unwrap();
+        stress(pool.clone(), StatusWriter::Direct(pool.clone())).await;
+    }
+}
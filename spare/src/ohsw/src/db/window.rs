@@ -0,0 +1,134 @@
+//! Time windows of the stats, as sent by the benchmark or asked on /export/stats.
+//! The bounds are parsed into instants in UTC and compared, as unix milliseconds, with the
+//! `created_at_ms` and `received_at_ms` columns: comparing the text of the timestamps
+//! only works when both sides are written the same way, in the same timezone.
+//!
+//! The bounds come from the clock of the benchmark, the rows are stamped with the clock of
+//! the node, so the two must be kept in sync with NTP: a node whose clock is off by some
+//! milliseconds moves that many milliseconds of requests into the next or the previous
+//! epoch. Well within an epoch with chrony or systemd-timesyncd on every machine.
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+/// Formats of the timestamps without a timezone, taken as UTC. The older benchmarks and
+/// the database write them this way.
+const NAIVE_FORMATS: [&str; 2] = ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"];
+
+/// Error returned when a window cannot be parsed
+#[derive(Debug, thiserror::Error, PartialEq)]
+pub enum WindowError {
+    #[error("Invalid timestamp {0:?}, expected RFC 3339, e.g. 2026-01-01T00:00:00.000Z")]
+    Timestamp(String),
+    #[error("The window ends at {end} before it starts at {start}")]
+    Reversed {
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    },
+}
+
+/// Parse a timestamp in RFC 3339, converted to UTC from its offset. A timestamp without
+/// an offset is taken as UTC.
+pub fn parse_utc(value: &str) -> Result<DateTime<Utc>, WindowError> {
+    let value = value.trim();
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(value) {
+        return Ok(timestamp.with_timezone(&Utc));
+    }
+    NAIVE_FORMATS
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .map(|timestamp| timestamp.and_utc())
+        .ok_or_else(|| WindowError::Timestamp(value.to_string()))
+}
+
+/// A window of time, both bounds included
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl Window {
+    /// Create a window, refusing one that ends before it starts
+    pub fn new(start: DateTime<Utc>, end: DateTime<Utc>) -> Result<Self, WindowError> {
+        if end < start {
+            return Err(WindowError::Reversed { start, end });
+        }
+        Ok(Window { start, end })
+    }
+
+    /// Parse the bounds of a window, as `parse_utc` does
+    pub fn parse(start: &str, end: &str) -> Result<Self, WindowError> {
+        Self::new(parse_utc(start)?, parse_utc(end)?)
+    }
+
+    /// Start of the window, in unix milliseconds
+    pub fn start_ms(&self) -> i64 {
+        self.start.timestamp_millis()
+    }
+
+    /// End of the window, in unix milliseconds
+    pub fn end_ms(&self) -> i64 {
+        self.end.timestamp_millis()
+    }
+
+    /// Length of the window, in seconds
+    pub fn seconds(&self) -> i64 {
+        (self.end - self.start).num_seconds()
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_utc() {
+        let expected = DateTime::parse_from_rfc3339("2026-01-01T00:00:00.250Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        for value in [
+            "2026-01-01T00:00:00.250Z",
+            "2026-01-01T00:00:00.250+00:00",
+            // Written by a benchmark in its local time
+            "2026-01-01T02:00:00.250+02:00",
+            "2025-12-31T19:00:00.250-05:00",
+            // Written by the older benchmarks, and by the database
+            "2026-01-01 00:00:00.250",
+            "2026-01-01 00:00:00.250000000",
+            "2026-01-01T00:00:00.25",
+        ] {
+            assert_eq!(parse_utc(value), Ok(expected), "{value}");
+        }
+        assert_eq!(
+            parse_utc("2026-01-01 00:00:00").unwrap().timestamp_millis(),
+            1767225600000
+        );
+
+        for value in [
+            "",
+            "yesterday",
+            "2026-01-01",
+            "2026-13-01 00:00:00",
+            "1767225600",
+        ] {
+            assert_eq!(
+                parse_utc(value),
+                Err(WindowError::Timestamp(value.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_window() {
+        let window = Window::parse("2026-01-01T00:00:00Z", "2026-01-01T01:00:00+01:00").unwrap();
+        assert_eq!(window.start, window.end);
+        assert_eq!(window.seconds(), 0);
+
+        let window = Window::parse("2026-01-01 00:00:00", "2026-01-01T00:01:00.5Z").unwrap();
+        assert_eq!(window.end_ms() - window.start_ms(), 60_500);
+        assert_eq!(window.seconds(), 60);
+
+        let reversed = Window::parse("2026-01-01T00:01:00Z", "2026-01-01T00:00:00Z");
+        assert!(matches!(reversed, Err(WindowError::Reversed { .. })));
+    }
+}
@@ -1,4 +1,6 @@
 use std::{
+    io,
+    net::{IpAddr, SocketAddr},
     os::fd::AsRawFd,
     sync::Arc,
     time::{Duration, Instant},
@@ -6,694 +8,5737 @@ use std::{
 };
 
 use actix_web::{
-    get, post,
-    rt::{net::UnixListener, time::timeout},
+    body::to_bytes,
+    delete, dev, error, get,
+    http::{header, StatusCode},
+    post, put,
+    rt::{net::UnixStream, time::timeout},
     web::{self, Bytes},
-    HttpRequest, HttpResponse, Responder,
+    FromRequest, HttpRequest, HttpResponse, Responder,
+};
+use firepilot::machine::FirepilotError;
+use futures::{
+    future::{join_all, ready, Ready},
+    stream::FuturesUnordered,
+    StreamExt,
 };
 use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
 use sqlx::{sqlite, Pool};
 
 use crate::{
-    api::invoke::InvokeFunction,
-    db::{self, models::Instance},
-    execution_environment::firecracker::{FirecrackerBuilder, FirecrackerInstance},
-    orchestrator::{self},
-    utils::socket::{read_exact, write_all},
+    api::{
+        batch::{BatchItemResult, BatchQuery, MAX_BATCH_SIZE},
+        calibrate::{Calibrate, CalibrationSummary},
+        health::Health,
+        invoke::{
+            set_served, InvokeBinary, InvokeFunction, PayloadVia, BUDGET_HEADER, HOPS_HEADER,
+        },
+        pipeline::{InvokePipeline, PipelineResult, StepResult, MAX_PIPELINE_STEPS},
+        quota::{QuotaLimits, QuotaStatus, API_KEY_HEADER},
+        resources::ResourcesQuery,
+        schedule::NewSchedule,
+    },
+    chaos::{self, Fault, Source},
+    db::{
+        self,
+        export::{self as db_export, ExportFormat, MAX_BUCKETS},
+        models::{
+            Calibration, EmergencyEvent, Instance, InstanceMetrics, NodeRun, Quota, Request,
+            RequestOutcome, Schedule,
+        },
+        request_log::RequestLog,
+        status_writer::StatusWriter,
+        window::Window,
+    },
+    execution_environment::{
+        firecracker::{
+            FirecrackerBuilder, FirecrackerBuilderError, FirecrackerInstance, InstanceSpec,
+        },
+        guest::{forward_http, GuestMode, GuestRequest, HttpGuestError, Retry},
+        image_cache::ImageCacheError,
+        lifecycle::{InstanceState, LifecycleError, VmmExit},
+        metrics::ColdStartTimings,
+        vsock::{VsockError, VsockPorts},
+    },
+    net::{
+        control_plane::Announcer,
+        iggy::{Message, Operation, Payload},
+    },
+    orchestrator::{
+        self,
+        drain::DrainState,
+        readiness::{Readiness, ReadinessPhase, RETRY_AFTER},
+        resource_spec::{ResolveError, Resolved, ResourceSpec},
+        scheduler::Decision,
+        trace,
+    },
+    schedules::Timing,
+    utils::{
+        auth::{AdminToken, ClusterAuth},
+        compression::Compression,
+        diagnostics::{self, Diagnostics, Section, MAX_SECTION_SIZE},
+        idempotency::{Begin, IdempotencyCache, IDEMPOTENCY_KEY_HEADER},
+        protocol::{
+            read_frame, Frame, FrameType, GuestProtocol, ProtocolError, LEGACY_READY, MAX_FRAME_LEN,
+        },
+        quota::{QuotaReservation, QuotaTracker},
+        socket::{read_exact, write_all},
+        spill::{Body, SpillConfig, SpillError},
+        supervisor::Supervisor,
+    },
 };
 
 /// Error types for the instance
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum InstanceError {
+    #[error("Application not initialized")]
     ApplicationNotInitialized,
-    InstanceCreation,
-    InstanceStart,
+    #[error("Failed to create the instance: {0}")]
+    InstanceCreation(#[from] FirepilotError),
+    /// The network of the function has no address left
+    #[error("No address available in network {0}")]
+    AddressesExhausted(String),
+    #[error("Failed to start the instance: {0}")]
+    InstanceStart(#[from] LifecycleError),
+    #[error("Failed to communicate with the guest through the vsock")]
     VSock,
+    #[error("Timed out waiting for the guest to connect")]
     VSockTimeout,
-    VSockCreation,
-    Database,
+    /// The firecracker process exited while the instance was running
+    #[error("The firecracker process crashed: {0}")]
+    VmmCrashed(VmmExit),
+    #[error("Failed to create the vsock socket: {0}")]
+    VSockCreation(#[from] VsockError),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Timed out waiting for the function")]
     Timeout,
+    #[error("Host unreachable")]
     HostUnreachable,
+    /// The image of the function cannot be fetched
+    #[error("Image unavailable: {0}")]
+    ImageUnavailable(#[from] ImageCacheError),
+    /// The function running in the guest reported an error
+    #[error("Function error: {0}")]
+    GuestError(String),
+    /// The payload of the request cannot be read back
+    #[error("Cannot read the payload: {0}")]
+    Payload(#[source] std::io::Error),
+    #[error("Unknown error")]
     Unknown,
 }
 
+impl InstanceError {
+    /// Whether starting another instance may succeed, e.g. the guest did not come up in time.
+    /// A failure of the node, of the image or of the function happens again on every attempt.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            InstanceError::InstanceCreation(_)
+            | InstanceError::InstanceStart(_)
+            | InstanceError::VSock
+            | InstanceError::VSockTimeout
+            | InstanceError::VmmCrashed(_)
+            | InstanceError::HostUnreachable
+            | InstanceError::Unknown => true,
+            InstanceError::VSockCreation(e) => e.is_retryable(),
+            InstanceError::ImageUnavailable(e) => matches!(e, ImageCacheError::Download(_)),
+            InstanceError::ApplicationNotInitialized
+            | InstanceError::AddressesExhausted(_)
+            | InstanceError::Database(_)
+            | InstanceError::Timeout
+            | InstanceError::GuestError(_)
+            | InstanceError::Payload(_) => false,
+        }
+    }
+}
+
+impl From<HttpGuestError> for InstanceError {
+    fn from(e: HttpGuestError) -> Self {
+        match e {
+            // The function itself failed
+            HttpGuestError::Status(_, message) => InstanceError::GuestError(message),
+            HttpGuestError::Timeout => InstanceError::Timeout,
+            _ => InstanceError::HostUnreachable,
+        }
+    }
+}
+
+impl From<FirecrackerBuilderError> for InstanceError {
+    fn from(e: FirecrackerBuilderError) -> Self {
+        match e {
+            FirecrackerBuilderError::AddressesExhausted(network) => {
+                InstanceError::AddressesExhausted(network)
+            }
+            FirecrackerBuilderError::Machine(e) => InstanceError::InstanceCreation(e),
+        }
+    }
+}
+
 /// Index endpoint
 #[get("/")]
 async fn index() -> impl Responder {
     HttpResponse::Ok().body("Server is up and running!\n")
 }
 
-/// List all instances in the database
-#[get("/list")]
-async fn list(db_pool: web::Data<Pool<sqlite::Sqlite>>) -> impl Responder {
-    HttpResponse::Ok().json(db::get_list(&db_pool).await.unwrap())
-}
+/// List all instances in the database
+#[get("/list")]
+async fn list(db_pool: web::Data<Pool<sqlite::Sqlite>>) -> impl Responder {
+    HttpResponse::Ok().json(db::get_list(&db_pool).await.unwrap())
+}
+
+/// Details of an instance, with the summary of its metrics once it is terminated
+#[derive(Serialize)]
+struct InstanceDetails {
+    #[serde(flatten)]
+    instance: Instance,
+    state: InstanceState,
+    metrics: Option<InstanceMetrics>,
+}
+
+/// State of an instance, from its status in the database
+fn instance_state(status: &str) -> InstanceState {
+    match status {
+        "started" => InstanceState::Running,
+        "terminated" => InstanceState::Stopped,
+        _ => InstanceState::Failed,
+    }
+}
+
+/// Get an instance and its metrics
+#[get("/instances/{id}")]
+async fn get_instance(
+    id: web::Path<i64>,
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+) -> impl Responder {
+    let id = id.into_inner();
+    let instance = match Instance::get_by_id(id, &db_pool).await {
+        Ok(Some(instance)) => instance,
+        Ok(None) => return HttpResponse::NotFound().body("Instance not found"),
+        Err(e) => {
+            error!("Failed to get instance {}: {:?}", id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let metrics = match InstanceMetrics::get_by_instance(id, &db_pool).await {
+        Ok(metrics) => metrics,
+        Err(e) => {
+            error!("Failed to get metrics of instance {}: {:?}", id, e);
+            None
+        }
+    };
+    HttpResponse::Ok().json(InstanceDetails {
+        state: instance_state(&instance.status),
+        instance,
+        metrics,
+    })
+}
+
+/// Query of the export of the instances
+#[derive(Deserialize)]
+struct ExportInstancesQuery {
+    #[serde(default)]
+    format: ExportFormat,
+    /// Only the instances created from this time
+    since: Option<chrono::NaiveDateTime>,
+}
+
+/// Export the instances, oldest first, as a file
+#[get("/export/instances")]
+async fn export_instances(
+    query: web::Query<ExportInstancesQuery>,
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+) -> impl Responder {
+    let format = query.format;
+    HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"instances.{}\"", format.extension()),
+        ))
+        .streaming(db_export::export_instances(
+            db_pool.get_ref().clone(),
+            query.since,
+            format,
+        ))
+}
+
+/// Query of the export of the stats
+#[derive(Deserialize)]
+struct ExportStatsQuery {
+    /// In RFC 3339, UTC without an offset
+    start: String,
+    end: String,
+    /// Length of a bucket, e.g. 60s, 5m or 1h
+    #[serde(default = "default_bucket")]
+    bucket: String,
+}
+
+fn default_bucket() -> String {
+    "60s".to_string()
+}
+
+/// Export the stats of the node from start to end, aggregated in buckets
+#[get("/export/stats")]
+async fn export_stats(
+    query: web::Query<ExportStatsQuery>,
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+) -> impl Responder {
+    let bucket = match db_export::parse_bucket(&query.bucket) {
+        Ok(bucket) => bucket,
+        Err(e) => return HttpResponse::BadRequest().body(format!("{}\n", e)),
+    };
+    let window = match Window::parse(&query.start, &query.end) {
+        Ok(window) => window,
+        Err(e) => return HttpResponse::BadRequest().body(format!("{}\n", e)),
+    };
+    if window.seconds() / bucket >= MAX_BUCKETS {
+        return HttpResponse::BadRequest().body(format!(
+            "Too many buckets, at most {} are allowed\n",
+            MAX_BUCKETS
+        ));
+    }
+    match db_export::stats_buckets(&db_pool, &window, bucket).await {
+        Ok(buckets) => HttpResponse::Ok().json(buckets),
+        Err(e) => {
+            error!("Cannot export the stats: {}", e);
+            HttpResponse::InternalServerError().body("Cannot export the stats\n")
+        }
+    }
+}
+
+/// Largest number of buckets of /stats
+const MAX_STATS_BUCKETS: i64 = 1000;
+
+/// Query of the stats endpoint
+#[derive(Deserialize)]
+struct StatsQuery {
+    /// In RFC 3339, UTC without an offset
+    start: Option<String>,
+    end: Option<String>,
+    /// Length of the window ending now (in seconds), instead of start and end
+    last: Option<i64>,
+    /// Length of a bucket, e.g. 60, 60s, 5m or 1h; the whole window by default
+    bucket: Option<String>,
+}
+
+impl StatsQuery {
+    /// Get the window of the query and the length of its buckets (in seconds)
+    fn resolve(&self, now: chrono::DateTime<chrono::Utc>) -> Result<(Window, i64), String> {
+        let window = match (&self.start, &self.end, self.last) {
+            (None, None, Some(last)) if last > 0 => {
+                Window::new(now - chrono::Duration::seconds(last), now)
+            }
+            (None, None, Some(last)) => return Err(format!("Invalid last: {}", last)),
+            (Some(start), Some(end), None) => Window::parse(start, end),
+            _ => return Err("Give either start and end, or last".to_string()),
+        }
+        .map_err(|e| e.to_string())?;
+        let bucket = match &self.bucket {
+            Some(bucket) => db_export::parse_bucket(bucket)?,
+            None => window.seconds().max(1),
+        };
+        if window.seconds() / bucket >= MAX_STATS_BUCKETS {
+            return Err(format!(
+                "Too many buckets, at most {} are allowed",
+                MAX_STATS_BUCKETS
+            ));
+        }
+        Ok((window, bucket))
+    }
+}
+
+/// Get the stats of the node over a window, as reported for an epoch, in buckets from the
+/// start of the window: `?start=...&end=...` or `?last=600` for the last 10 minutes
+#[get("/stats")]
+async fn stats(
+    query: web::Query<StatsQuery>,
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+) -> impl Responder {
+    let (window, bucket) = match query.resolve(chrono::Utc::now()) {
+        Ok(resolved) => resolved,
+        Err(e) => return HttpResponse::BadRequest().body(format!("{}\n", e)),
+    };
+    match db::stats_by_bucket(&db_pool, &window, bucket).await {
+        Ok(buckets) => HttpResponse::Ok().json(buckets),
+        Err(e) => {
+            error!("Cannot compute the stats: {}", e);
+            HttpResponse::InternalServerError().body("Cannot compute the stats\n")
+        }
+    }
+}
+
+/// Query of the runs endpoint
+#[derive(Deserialize)]
+struct RunsQuery {
+    /// Largest number of runs returned, the most recent ones
+    #[serde(default = "default_runs_limit")]
+    limit: i64,
+}
+
+fn default_runs_limit() -> i64 {
+    100
+}
+
+/// List the past starts of the node, the most recent first, with the configuration each
+/// one resolved. The instances and the requests carry the `run_id` of the run that
+/// recorded them.
+#[get("/runs")]
+async fn runs(
+    query: web::Query<RunsQuery>,
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    match NodeRun::list(&db_pool, query.limit).await {
+        Ok(runs) => HttpResponse::Ok().json(runs),
+        Err(e) => {
+            error!("Cannot list the runs: {}", e);
+            HttpResponse::InternalServerError().body("Cannot list the runs\n")
+        }
+    }
+}
+
+/// Get resources available in the system.
+/// The reserve of the node is only advertised to the emergency requests.
+/// With `?image=...`, also tell whether the node has the image.
+#[get("/resources")]
+async fn resources(
+    query: web::Query<ResourcesQuery>,
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    firecracker_builder: web::Data<Arc<FirecrackerBuilder>>,
+) -> impl Responder {
+    let mut resources = orchestrator.usable_resources(query.emergency);
+    resources.overlay_disk_usage = firecracker_builder.overlay_disk_usage();
+    resources.free_addresses =
+        Some(firecracker_builder.free_addresses(query.function.as_deref().unwrap_or_default()));
+    resources.has_image = query.image.as_deref().map(|image| {
+        firecracker_builder.images.contains(
+            image,
+            query.image_digest.as_deref(),
+            &firecracker_builder.image_cache,
+        )
+    });
+    HttpResponse::Ok().json(resources)
+}
+
+/// List the images present on the node: the files of its image directories, as last
+/// scanned, and the downloads of its image cache
+#[get("/images")]
+async fn images(firecracker_builder: web::Data<Arc<FirecrackerBuilder>>) -> impl Responder {
+    HttpResponse::Ok().json(
+        firecracker_builder
+            .images
+            .list(&firecracker_builder.image_cache),
+    )
+}
+
+/// Get the totals and the available resources of the node, with the number of releases
+/// of cpus that were never acquired, which point at an accounting bug
+#[get("/debug/resources")]
+async fn debug_resources(
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    HttpResponse::Ok().json(orchestrator.resources_snapshot())
+}
+
+/// State of the orchestrator, as served on /debug/orchestrator and in the diagnostics bundle
+fn orchestrator_state(orchestrator: &orchestrator::Orchestrator) -> serde_json::Value {
+    let topology = orchestrator.topology_source();
+    serde_json::json!({
+        "identity": orchestrator.get_identity(),
+        "in_emergency_area": orchestrator.in_emergency_area(),
+        "neighbors": orchestrator.number_of_nodes(),
+        "sort_policy": orchestrator.sort_policy().to_string(),
+        "neighbor_sorts": orchestrator.neighbor_sorts(),
+        "topology": topology,
+        "stale": topology.is_stale(),
+        "advertised": orchestrator.get_resources(),
+        "accounting": orchestrator.resources_snapshot(),
+        "memory_pressure": orchestrator.memory_pressure().status(),
+    })
+}
+
+/// Get the state of the orchestrator: the identity of the node, its neighbors and whether
+/// their list is the last known one, stale while the control plane does not answer
+#[get("/debug/orchestrator")]
+async fn debug_orchestrator(
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    HttpResponse::Ok().json(orchestrator_state(&orchestrator))
+}
+
+/// Number of failed instances in the diagnostics bundle
+const BUNDLE_FAILED_INSTANCES: i64 = 50;
+
+/// Download a tar.gz bundle of diagnostics to attach to a bug report: recent logs, state of
+/// the orchestrator, last failed instances, configuration and preflight checks
+#[get("/debug/bundle")]
+async fn debug_bundle(
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    diagnostics: web::Data<Diagnostics>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+
+    let failed = match Instance::last_failed(&db_pool, BUNDLE_FAILED_INSTANCES).await {
+        Ok(mut instances) => {
+            // The environment of a function may hold its secrets
+            for instance in instances.iter_mut().filter(|i| i.env.is_some()) {
+                instance.env = Some("[redacted]".to_string());
+            }
+            serde_json::to_vec_pretty(&instances).unwrap_or_default()
+        }
+        Err(e) => {
+            error!("Cannot list the failed instances: {}", e);
+            format!("Cannot list the failed instances: {e}\n").into_bytes()
+        }
+    };
+    let state = orchestrator_state(&orchestrator);
+    let sections = vec![
+        Section::new("logs.txt", diagnostics.logs.tail(MAX_SECTION_SIZE)),
+        Section::new(
+            "orchestrator.json",
+            serde_json::to_vec_pretty(&state).unwrap_or_default(),
+        ),
+        Section::new("failed_instances.json", failed),
+        Section::new("config.txt", diagnostics.config.clone()),
+        Section::new("preflight.txt", diagnostics.preflight.clone()),
+    ];
+
+    HttpResponse::Ok()
+        .content_type("application/gzip")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"spare-bundle-{}.tar.gz\"",
+                chrono::Utc::now().format("%Y%m%dT%H%M%S")
+            ),
+        ))
+        .streaming(diagnostics::bundle(sections))
+}
+
+/// Get the number of crashes of the firecracker process since the node started, by image
+#[get("/debug/crashes")]
+async fn debug_crashes(
+    builder: web::Data<Arc<FirecrackerBuilder>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    HttpResponse::Ok().json(builder.crashes())
+}
+
+/// Get the sweeps of the workspaces left behind by the instances since the node started:
+/// how many were removed, the space reclaimed and the last sweep
+#[get("/debug/workspaces")]
+async fn debug_workspaces(
+    builder: web::Data<Arc<FirecrackerBuilder>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    HttpResponse::Ok().json(builder.workspaces.totals())
+}
+
+/// Query of the offload traces endpoint
+#[derive(Deserialize)]
+struct OffloadsQuery {
+    /// Largest number of traces returned, the newest ones
+    #[serde(default = "default_offloads_limit")]
+    limit: usize,
+}
+
+fn default_offloads_limit() -> usize {
+    trace::DEFAULT_LIMIT
+}
+
+/// Get the traces of the last offloads, newest first: the neighbors considered for each
+/// request in the order they were ranked, what their probes answered and where the
+/// request went. Only recorded when the node runs with `--trace-offloads`.
+#[get("/debug/offloads")]
+async fn debug_offloads(
+    query: web::Query<OffloadsQuery>,
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    match orchestrator.offload_traces() {
+        Some(traces) => HttpResponse::Ok().json(traces.recent(query.limit)),
+        None => HttpResponse::NotFound().body("The offload traces are not recorded\n"),
+    }
+}
+
+/// Get the ranking of the neighbors: the ones the offloads try, in order, with the
+/// distance or the latency each one is ranked by and the age of the cached latency,
+/// then the ones skipped because in the emergency area or draining.
+#[get("/nodes/ranking")]
+async fn nodes_ranking(
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    HttpResponse::Ok().json(orchestrator.ranking())
+}
+
+/// Get the packing of the requests of mixed sizes: the cpus held by each size class, the
+/// requests waiting, the free cpus too few for the largest of them and the requests held
+/// back for it. Only when the node runs with `--packing-hold`.
+#[get("/debug/packing")]
+async fn debug_packing(
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    match orchestrator.packing_stats() {
+        Some(packing) => HttpResponse::Ok().json(packing),
+        None => HttpResponse::NotFound().body("The packing is disabled\n"),
+    }
+}
+
+/// List the faults injected in the node, oldest first, with how many times each was
+/// injected. Only when the node runs with `--chaos`.
+#[get("/chaos")]
+async fn list_chaos(
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    match orchestrator.chaos() {
+        Some(chaos) => HttpResponse::Ok().json(chaos.list()),
+        None => HttpResponse::NotFound().body("The node takes no chaos injection\n"),
+    }
+}
+
+/// Inject a fault in the node, until it is removed
+#[post("/chaos")]
+async fn inject_chaos(
+    fault: web::Json<Fault>,
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    let Some(chaos) = orchestrator.chaos() else {
+        return HttpResponse::NotFound().body("The node takes no chaos injection\n");
+    };
+    match chaos.inject(fault.into_inner(), Source::Admin) {
+        Ok(injection) => HttpResponse::Created().json(injection),
+        Err(e) => HttpResponse::BadRequest().body(format!("{}\n", e)),
+    }
+}
+
+/// Remove every fault injected in the node
+#[delete("/chaos")]
+async fn clear_chaos(
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    match orchestrator.chaos() {
+        Some(chaos) => HttpResponse::Ok().json(serde_json::json!({ "cleared": chaos.clear() })),
+        None => HttpResponse::NotFound().body("The node takes no chaos injection\n"),
+    }
+}
+
+/// Remove a fault injected in the node
+#[delete("/chaos/{id}")]
+async fn remove_chaos(
+    path: web::Path<u64>,
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    match orchestrator.chaos() {
+        Some(chaos) if chaos.remove(path.into_inner()) => HttpResponse::NoContent().finish(),
+        Some(_) => HttpResponse::NotFound().body("Injection not found\n"),
+        None => HttpResponse::NotFound().body("The node takes no chaos injection\n"),
+    }
+}
+
+/// Get if the node is in emergency mode
+#[get("/emergency")]
+async fn emergency(orchestrator: web::Data<Arc<orchestrator::Orchestrator>>) -> impl Responder {
+    let in_emergency = orchestrator.in_emergency_area();
+    HttpResponse::Ok().json(in_emergency)
+}
+
+/// List the emergencies seen by the node, oldest first
+#[get("/emergency/history")]
+async fn emergency_history(db_pool: web::Data<Pool<sqlite::Sqlite>>) -> impl Responder {
+    match EmergencyEvent::list(&db_pool).await {
+        Ok(events) => HttpResponse::Ok().json(events),
+        Err(e) => {
+            error!("Cannot list the emergency events: {}", e);
+            HttpResponse::InternalServerError().body("Cannot list the emergency events\n")
+        }
+    }
+}
+
+/// Get the health of the node, whether it is draining, the instances still running and
+/// whether a supervised task failed
+#[get("/healthz")]
+async fn healthz(
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    supervisor: Option<web::Data<Arc<Supervisor>>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(health(&orchestrator, supervisor.as_ref()))
+}
+
+fn health(
+    orchestrator: &orchestrator::Orchestrator,
+    supervisor: Option<&web::Data<Arc<Supervisor>>>,
+) -> Health {
+    Health {
+        state: orchestrator.drain().state(),
+        running_instances: orchestrator.drain().running(),
+        emergency: orchestrator.in_emergency_area(),
+        degraded: supervisor.is_some_and(|supervisor| supervisor.degraded()),
+        tasks: supervisor
+            .map(|supervisor| supervisor.health())
+            .unwrap_or_default(),
+        phase: orchestrator.readiness().phase(),
+        phase_changes: orchestrator.readiness().changes(),
+    }
+}
+
+/// Query of the drain endpoint
+#[derive(Deserialize)]
+struct DrainQuery {
+    /// Answer once the last local instance terminated
+    #[serde(default)]
+    wait: bool,
+}
+
+/// Drain the node before a maintenance: it advertises no resources and offloads the new
+/// requests, but for the emergency ones, and the other nodes are told to leave it alone.
+/// With `?wait=true` the answer comes once the node is drained, or undrained meanwhile.
+#[post("/drain")]
+async fn drain(
+    query: web::Query<DrainQuery>,
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    announcer: web::Data<Announcer>,
+    admin_token: web::Data<AdminToken>,
+    supervisor: Option<web::Data<Arc<Supervisor>>>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    if orchestrator.drain().start() {
+        info!(
+            "Draining the node, {} instances running",
+            orchestrator.drain().running()
+        );
+        orchestrator.readiness().advance(ReadinessPhase::Draining);
+        announcer.announce(Message::new(
+            Operation::DRAINING,
+            Some(Payload::Nodes(vec![orchestrator.get_identity().clone()])),
+        ));
+    }
+    if query.wait && orchestrator.drain().drained().await {
+        info!("The node is drained");
+    }
+    HttpResponse::Ok().json(health(&orchestrator, supervisor.as_ref()))
+}
+
+/// Take new requests again after a drain
+#[post("/undrain")]
+async fn undrain(
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    announcer: web::Data<Announcer>,
+    admin_token: web::Data<AdminToken>,
+    supervisor: Option<web::Data<Arc<Supervisor>>>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    if orchestrator.drain().stop() {
+        info!("The node takes requests again");
+        orchestrator.readiness().advance(ReadinessPhase::Ready);
+        announcer.announce(Message::new(
+            Operation::RESUMED,
+            Some(Payload::Nodes(vec![orchestrator.get_identity().clone()])),
+        ));
+    }
+    HttpResponse::Ok().json(health(&orchestrator, supervisor.as_ref()))
+}
+
+/// Guard of the endpoints running functions, taken before their body is read.
+/// Until the node knows its neighbors and its runtime, a request could be neither
+/// offloaded nor served: it is refused, and the client told when to retry it.
+struct AcceptingRequests;
+
+impl FromRequest for AcceptingRequests {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        let Some(orchestrator) = req.app_data::<web::Data<Arc<orchestrator::Orchestrator>>>()
+        else {
+            return ready(Err(error::ErrorInternalServerError(
+                "The node is not configured",
+            )));
+        };
+        let phase = orchestrator.readiness().phase();
+        if phase.accepts_requests() {
+            return ready(Ok(Self));
+        }
+        let refused = error::InternalError::from_response(phase, not_ready(phase));
+        ready(Err(refused.into()))
+    }
+}
+
+/// Answer of a node that does not take requests yet
+fn not_ready(phase: ReadinessPhase) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, RETRY_AFTER.as_secs().to_string()))
+        .body(format!("The node is {phase}, retry later\n"))
+}
+
+/*
+Example API: curl --header "Content-Type: application/json" \
+     --request POST \
+     --data '{"function":"mandelbrot","image":"/home/ubuntu/.ops/images/nanosvm","vcpus":8,"memory":256, "payload": "test"}' \
+     http://localhost:8085/invoke
+
+*/
+/// Invoke function endpoint
+/// This endpoint is used to invoke a registered function in the system
+#[post("/invoke")]
+async fn invoke(
+    _: AcceptingRequests,
+    body: web::Bytes,
+    context: InvokeContext,
+    idempotency: web::Data<Arc<IdempotencyCache>>,
+    cluster_auth: web::Data<ClusterAuth>,
+    compression: web::Data<Compression>,
+    req: HttpRequest,
+) -> impl Responder {
+    // Read the body as it is, so that an offloaded request is forwarded without
+    // being encoded again
+    let mut data = match serde_json::from_slice::<InvokeFunction>(&body) {
+        Ok(data) => web::Json(data),
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid request: {}\n", e)),
+    };
+    // A forwarding node counts the hops in a header when it does not touch the body
+    if let Some(hops) = req
+        .headers()
+        .get(HOPS_HEADER)
+        .and_then(|hops| hops.to_str().ok())
+        .and_then(|hops| hops.parse().ok())
+    {
+        data.hops = hops;
+    }
+    // The deadline runs from the arrival, with the budget left by the forwarding node
+    data.deadline = req
+        .headers()
+        .get(BUDGET_HEADER)
+        .and_then(|budget| budget.to_str().ok())
+        .and_then(|budget| budget.parse().ok())
+        .or(data.timeout_ms)
+        .map(|budget| Instant::now() + Duration::from_millis(budget));
+
+    // Only the members of the cluster can forward requests
+    let forwarded_by = match cluster_auth.check(&req, data.hops) {
+        Ok(peer) => peer.map(|peer| peer.0),
+        Err(refused) => {
+            warn!(
+                "Refused a forwarded request from {:?} without a node certificate",
+                req.peer_addr()
+            );
+            return refused;
+        }
+    };
+    if let Some(peer) = &forwarded_by {
+        info!("Request for {} forwarded by {}", data.function, peer);
+    }
+
+    // The keys in the body win over the headers, and are the ones forwarded on offload
+    let (key_in_body, api_key_in_body) = (data.idempotency_key.clone(), data.api_key.clone());
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .and_then(|key| key.to_str().ok())
+            .map(str::to_string)
+    };
+    if data.idempotency_key.is_none() {
+        data.idempotency_key = header(IDEMPOTENCY_KEY_HEADER);
+    }
+    if data.api_key.is_none() {
+        data.api_key = header(API_KEY_HEADER);
+    }
+    // The keys taken from the headers must be written in the forwarded body
+    let raw =
+        (data.idempotency_key == key_in_body && data.api_key == api_key_in_body).then_some(body);
+    // Leave the output as it is if the function says it does not compress well
+    let compressible = data.compressible;
+    let output = |mut response: HttpResponse| {
+        compression.apply(compressible, &mut response);
+        response
+    };
+    let key = data.idempotency_key.clone();
+    let in_flight = match key.as_deref().map(|key| idempotency.begin(key)) {
+        None => None,
+        Some(Begin::New(in_flight)) => Some(in_flight),
+        Some(Begin::Running) => {
+            return HttpResponse::Conflict()
+                .body("A request with the same idempotency key is still running\n")
+        }
+        // Replays are not recorded, the request was already accounted
+        Some(Begin::Done(response)) => return output(response.to_response()),
+    };
+
+    let received_at = chrono::Utc::now().naive_utc();
+    let (function, hops) = (data.function.clone(), data.hops);
+    let (response, outcome) = serve(
+        &context,
+        data,
+        raw,
+        req.peer_addr().map(|addr| addr.ip()),
+        None,
+    )
+    .await;
+    let failed = matches!(
+        outcome,
+        RequestOutcome::Rejected | RequestOutcome::Failed | RequestOutcome::Shed
+    );
+    context.request_log.record(
+        Request::new(function, hops, outcome, received_at)
+            .with_forwarded_by(forwarded_by)
+            .with_offload_trace(trace::trace_id(&response))
+            .with_chaos(chaos::marked(&response)),
+    );
+
+    match in_flight {
+        // A failed request did not run, dropping the key lets the client retry it
+        Some(in_flight) if !failed => {
+            let (response, body) = response.into_parts();
+            let body = match to_bytes(body).await {
+                Ok(body) => body,
+                Err(e) => {
+                    error!("Cannot read the response to store it: {}", e);
+                    return HttpResponse::InternalServerError().finish();
+                }
+            };
+            in_flight.finish(response.status(), &body);
+            output(response.set_body(body).map_into_boxed_body())
+        }
+        _ => output(response),
+    }
+}
+
+/// Shared state needed to run a function with a binary payload
+struct BinaryContext {
+    context: InvokeContext,
+    spill: web::Data<SpillConfig>,
+    compression: web::Data<Compression>,
+}
+
+impl FromRequest for BinaryContext {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut dev::Payload) -> Self::Future {
+        let mut context = || {
+            Ok(Self {
+                context: InvokeContext::from_request(req, payload).into_inner()?,
+                spill: web::Data::extract(req).into_inner()?,
+                compression: web::Data::extract(req).into_inner()?,
+            })
+        };
+        ready(context())
+    }
+}
+
+/// Invoke a function with a binary payload, the body of the request.
+/// A payload over the spill threshold is written to disk while it is received, and sent
+/// to the instance from there. These requests always run on this node.
+#[post("/invoke/{function}")]
+async fn invoke_binary(
+    _: AcceptingRequests,
+    function: web::Path<String>,
+    query: web::Query<InvokeBinary>,
+    payload: web::Payload,
+    binary: BinaryContext,
+    req: HttpRequest,
+) -> impl Responder {
+    let BinaryContext {
+        context,
+        spill,
+        compression,
+    } = binary;
+    let mut data = query.invocation(function.into_inner());
+    data.api_key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok())
+        .map(str::to_string);
+    // Dropping the payload removes its file, when the request leaves this function or
+    // when the client goes away and the request is dropped with it
+    let body = match spill.receive(payload).await {
+        Ok(body) => body,
+        Err(e @ SpillError::TooLarge(_)) => {
+            return HttpResponse::PayloadTooLarge().body(format!("{}\n", e))
+        }
+        Err(e @ SpillError::Payload(_)) => {
+            return HttpResponse::BadRequest().body(format!("{}\n", e))
+        }
+        Err(e) => {
+            error!("{}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let received_at = chrono::Utc::now().naive_utc();
+    let (function, compressible) = (data.function.clone(), data.compressible);
+    let (mut response, outcome) = serve(
+        &context,
+        web::Json(data),
+        None,
+        req.peer_addr().map(|addr| addr.ip()),
+        Some(&body),
+    )
+    .await;
+    context.request_log.record(
+        Request::new(function, 0, outcome, received_at)
+            .with_offload_trace(trace::trace_id(&response))
+            .with_chaos(chaos::marked(&response)),
+    );
+    compression.apply(compressible, &mut response);
+    response
+}
+
+/// Shared state needed to run the functions of a batch
+#[derive(Clone)]
+struct BatchContext {
+    context: InvokeContext,
+    idempotency: web::Data<Arc<IdempotencyCache>>,
+    // The node that forwarded the batch, if it proved its identity
+    forwarded_by: Option<String>,
+}
+
+/// A function of a batch, once it went through `triage`
+struct BatchItem {
+    position: usize,
+    data: InvokeFunction,
+    triage: Triage,
+    // The resources of the function, reserved against the quota of its key
+    reservation: Option<QuotaReservation>,
+    // Whether the resources of the function were acquired with the batch
+    admitted: bool,
+}
+
+/// Invoke a batch of functions.
+/// The functions that fit are admitted locally as a group, the others are offloaded,
+/// and the results are returned in the order of the batch, or streamed as they
+/// complete with `?stream=true`.
+#[post("/invoke_batch")]
+async fn invoke_batch(
+    _: AcceptingRequests,
+    batch: web::Json<Vec<InvokeFunction>>,
+    query: web::Query<BatchQuery>,
+    context: InvokeContext,
+    idempotency: web::Data<Arc<IdempotencyCache>>,
+    cluster_auth: web::Data<ClusterAuth>,
+    req: HttpRequest,
+) -> HttpResponse {
+    let mut batch = batch.into_inner();
+    if batch.is_empty() || batch.len() > MAX_BATCH_SIZE {
+        return HttpResponse::BadRequest().body(format!(
+            "A batch must have between 1 and {} functions\n",
+            MAX_BATCH_SIZE
+        ));
+    }
+
+    // Check the functions as /invoke would, the ones refused are answered when they run.
+    // The ones that may run here reserve their resources against the quota of their key
+    // before taking them, the key of the batch being the one of the functions without
+    let api_key = req
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok());
+    let mut reservations = Vec::with_capacity(batch.len());
+    let mut forwarded_by = None;
+    let triaged: Vec<Triage> = batch
+        .iter_mut()
+        .map(|data| {
+            // Only the members of the cluster can forward requests
+            match cluster_auth.check(&req, data.hops) {
+                Ok(peer) => forwarded_by = peer.map(|peer| peer.0),
+                Err(refused) => {
+                    warn!(
+                        "Refused a forwarded function from {:?} without a node certificate",
+                        req.peer_addr()
+                    );
+                    reservations.push(None);
+                    return Triage::Refused(refused, RequestOutcome::Rejected);
+                }
+            }
+            if data.api_key.is_none() {
+                data.api_key = api_key.map(str::to_string);
+            }
+            let (triage, reservation) = match triage(&context, data) {
+                Triage::Local => match reserve_quota(&context.quotas, data) {
+                    Ok(reservation) => (Triage::Local, reservation),
+                    Err(status) => {
+                        let (response, outcome) = over_quota(status);
+                        (Triage::Refused(response, outcome), None)
+                    }
+                },
+                triage => (triage, None),
+            };
+            reservations.push(reservation);
+            triage
+        })
+        .collect();
+
+    // Admit together the functions that can run on this node
+    let local: Vec<usize> = (0..batch.len())
+        .filter(|&i| matches!(triaged[i], Triage::Local))
+        .collect();
+    let requests: Vec<(usize, usize, bool)> = local
+        .iter()
+        .map(|&i| {
+            (
+                batch[i].vcpus.try_into().unwrap(),
+                (batch[i].memory * 1024).try_into().unwrap(),
+                batch[i].emergency,
+            )
+        })
+        .collect();
+    let mut admitted = vec![false; batch.len()];
+    let acquired = context.orchestrator.acquire_batch(&requests);
+    for (i, admit) in local.into_iter().zip(acquired) {
+        admitted[i] = admit;
+    }
+
+    let origin = req.peer_addr().map(|addr| addr.ip());
+    if let Some(peer) = &forwarded_by {
+        info!("Batch of {} functions forwarded by {}", batch.len(), peer);
+    }
+    let context = BatchContext {
+        context,
+        idempotency,
+        forwarded_by,
+    };
+    let items = batch
+        .into_iter()
+        .zip(triaged)
+        .zip(reservations)
+        .zip(admitted)
+        .enumerate()
+        .map(|(position, (((data, triage), reservation), admitted))| {
+            let item = BatchItem {
+                position,
+                data,
+                triage,
+                reservation,
+                admitted,
+            };
+            run_batch_item(context.clone(), item, origin)
+        });
+
+    if query.stream {
+        let results = items.collect::<FuturesUnordered<_>>().map(|result| {
+            let mut line = serde_json::to_vec(&result).map_err(io::Error::other)?;
+            line.push(b'\n');
+            Ok::<_, io::Error>(Bytes::from(line))
+        });
+        HttpResponse::Ok()
+            .content_type("application/x-ndjson")
+            .streaming(results)
+    } else {
+        HttpResponse::Ok().json(join_all(items).await)
+    }
+}
+
+/// Run a function of a batch, as /invoke would.
+/// If admitted, its resources were acquired with the batch and it runs locally.
+async fn run_batch_item(
+    batch: BatchContext,
+    item: BatchItem,
+    origin: Option<IpAddr>,
+) -> BatchItemResult {
+    let BatchItem {
+        position,
+        data,
+        triage,
+        reservation,
+        admitted,
+    } = item;
+    let context = &batch.context;
+    let release = || {
+        if admitted {
+            let _ = context.orchestrator.release_resources(
+                data.vcpus.try_into().unwrap(),
+                (data.memory * 1024).try_into().unwrap(),
+            );
+        }
+    };
+    let in_flight = match data
+        .idempotency_key
+        .as_deref()
+        .map(|key| batch.idempotency.begin(key))
+    {
+        None => None,
+        Some(Begin::New(in_flight)) => Some(in_flight),
+        Some(Begin::Running) => {
+            release();
+            return BatchItemResult {
+                index: position,
+                status: StatusCode::CONFLICT.as_u16(),
+                outcome: RequestOutcome::Rejected.as_str().to_string(),
+                offloaded_to: None,
+                instance_id: None,
+                output: None,
+                error: Some("A request with the same idempotency key is still running".to_string()),
+            };
+        }
+        Some(Begin::Done(response)) => {
+            release();
+            let body = response
+                .body
+                .map(|body| String::from_utf8_lossy(&body).into_owned());
+            return BatchItemResult {
+                index: position,
+                status: response.status.as_u16(),
+                outcome: "replayed".to_string(),
+                offloaded_to: None,
+                instance_id: None,
+                output: body,
+                error: None,
+            };
+        }
+    };
+
+    let received_at = chrono::Utc::now().naive_utc();
+    let (function, hops) = (data.function.clone(), data.hops);
+    let (response, outcome, instance_id) = match triage {
+        Triage::Refused(response, outcome) => (response, outcome, None),
+        Triage::Local if admitted => {
+            let run = run_locally(
+                &data,
+                None,
+                &context.db_pool,
+                &context.status_writer,
+                &context.firecracker_builder,
+                &context.orchestrator,
+            )
+            .await;
+            drop(reservation);
+            run
+        }
+        // Sent away by the checks, or not admitted with the batch
+        Triage::Local | Triage::Offload => {
+            drop(reservation);
+            let (response, outcome) = context
+                .orchestrator
+                .offload(web::Json(data), None, origin)
+                .await;
+            (response, outcome, None)
+        }
+    };
+
+    let status = response.status();
+    let trace_id = trace::trace_id(&response);
+    let injected = chaos::marked(&response);
+    let body = to_bytes(response.into_body()).await.unwrap_or_default();
+    let failed = matches!(
+        outcome,
+        RequestOutcome::Rejected | RequestOutcome::Failed | RequestOutcome::Shed
+    );
+    if let Some(in_flight) = in_flight {
+        // A failed request did not run, dropping the key lets the client retry it
+        if !failed {
+            in_flight.finish(status, &body);
+        }
+    }
+
+    let body = String::from_utf8_lossy(&body).into_owned();
+    let result = BatchItemResult {
+        index: position,
+        status: status.as_u16(),
+        outcome: outcome.as_str().to_string(),
+        offloaded_to: match &outcome {
+            RequestOutcome::OffloadedTo(address) => Some(address.clone()),
+            _ => None,
+        },
+        instance_id,
+        output: status.is_success().then(|| body.clone()),
+        error: (!status.is_success()).then_some(body),
+    };
+    context.request_log.record(
+        Request::new(function, hops, outcome, received_at)
+            .with_forwarded_by(batch.forwarded_by.clone())
+            .with_offload_trace(trace_id)
+            .with_chaos(injected),
+    );
+    result
+}
+
+/// Invoke a pipeline of functions.
+/// The steps run one after the other, each admitted on its own as /invoke would: on this
+/// node if it fits, offloaded otherwise. The output of a step is the payload of the next
+/// one, never going back to the client, and the first step that fails aborts the pipeline.
+#[post("/invoke_pipeline")]
+async fn invoke_pipeline(
+    _: AcceptingRequests,
+    data: web::Json<InvokePipeline>,
+    context: InvokeContext,
+    req: HttpRequest,
+) -> HttpResponse {
+    let mut data = data.into_inner();
+    if data.pipeline.is_empty() || data.pipeline.len() > MAX_PIPELINE_STEPS {
+        return HttpResponse::BadRequest().body(format!(
+            "A pipeline must have between 1 and {} steps\n",
+            MAX_PIPELINE_STEPS
+        ));
+    }
+
+    // The steps are accounted to the key of the pipeline, sent in the body or a header
+    if data.api_key.is_none() {
+        data.api_key = req
+            .headers()
+            .get(API_KEY_HEADER)
+            .and_then(|key| key.to_str().ok())
+            .map(str::to_string);
+    }
+    let origin = req.peer_addr().map(|addr| addr.ip());
+    let mut result = PipelineResult {
+        steps: Vec::with_capacity(data.pipeline.len()),
+        output: None,
+        failed_step: None,
+        error: None,
+    };
+    // The output of the previous step is passed as text when it is, and as a binary
+    // payload otherwise, which keeps the step on this node
+    let mut payload = data.payload.clone();
+    let mut binary: Option<Body> = None;
+    let mut output = Bytes::new();
+    for position in 0..data.pipeline.len() {
+        let step = data.invocation(position, payload.take());
+        let function = step.function.clone();
+        let received_at = chrono::Utc::now().naive_utc();
+        let start = Instant::now();
+        let (response, outcome) = serve(
+            &context,
+            web::Json(step),
+            None,
+            origin,
+            binary.take().as_ref(),
+        )
+        .await;
+        let status = response.status();
+        context.request_log.record(
+            Request::new(function.clone(), 0, outcome.clone(), received_at)
+                .with_offload_trace(trace::trace_id(&response))
+                .with_chaos(chaos::marked(&response)),
+        );
+        output = to_bytes(response.into_body()).await.unwrap_or_default();
+        result.steps.push(StepResult {
+            index: position,
+            function: function.clone(),
+            status: status.as_u16(),
+            outcome: outcome.as_str().to_string(),
+            offloaded_to: match &outcome {
+                RequestOutcome::OffloadedTo(address) => Some(address.clone()),
+                _ => None,
+            },
+            duration_ms: start.elapsed().as_millis() as u64,
+        });
+
+        if !status.is_success() {
+            warn!(
+                "Pipeline aborted at step {} ({}): {}",
+                position, function, status
+            );
+            result.failed_step = Some(position);
+            result.error = Some(format!(
+                "Step {} ({}) failed: {}",
+                position,
+                function,
+                String::from_utf8_lossy(&output).trim_end()
+            ));
+            return HttpResponse::build(status).json(result);
+        }
+        match std::str::from_utf8(&output) {
+            Ok(text) => payload = Some(text.to_string()),
+            Err(_) => binary = Some(Body::Memory(output.clone())),
+        }
+    }
+
+    result.output = Some(String::from_utf8_lossy(&output).into_owned());
+    HttpResponse::Ok().json(result)
+}
+
+/// Calibrate the cold start of the node.
+/// Boots the requested number of instances of an image one after the other, records
+/// the time spent in each phase of their cold start and returns the summary of the run.
+/// The resources of one instance are held for the whole run.
+#[post("/calibrate")]
+#[allow(clippy::too_many_arguments)]
+async fn calibrate(
+    data: web::Json<Calibrate>,
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+    firecracker_builder: web::Data<Arc<FirecrackerBuilder>>,
+    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
+    status_writer: web::Data<StatusWriter>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    let data = data.into_inner();
+    if let Err(e) = data.validate() {
+        return HttpResponse::BadRequest().body(format!("{}\n", e));
+    }
+
+    let cpus: usize = data.vcpus.try_into().unwrap();
+    if orchestrator
+        .check_and_acquire_resources(cpus, (data.memory * 1024).try_into().unwrap(), false)
+        .is_err()
+    {
+        return HttpResponse::ServiceUnavailable().body("Insufficient resources\n");
+    }
+
+    let run_id = uuid::Uuid::new_v4().to_string();
+    let function = InvokeFunction {
+        function: format!("calibration-{}", run_id),
+        image: data.image.clone(),
+        image_digest: None,
+        vcpus: data.vcpus,
+        memory: data.memory,
+        payload: None,
+        emergency: false,
+        hops: 0,
+        payload_via: PayloadVia::Vsock,
+        rate_limits: None,
+        idempotency_key: None,
+        env: None,
+        args: None,
+        api_key: None,
+        compressible: None,
+        timeout_ms: None,
+        deadline: None,
+    };
+    info!(
+        "Calibration {}: booting {} instances of {}",
+        run_id, data.iterations, data.image
+    );
+
+    let mut iterations = Vec::with_capacity(data.iterations);
+    for iteration in 0..data.iterations {
+        let mut timings = ColdStartTimings::default();
+        let result = start_instance(
+            &firecracker_builder,
+            &db_pool,
+            &status_writer,
+            &function,
+            None,
+            1,
+            &mut timings,
+        )
+        .await
+        .map(|_| timings)
+        .map_err(|e| e.to_string());
+        if let Err(e) = &result {
+            warn!(
+                "Calibration {}: iteration {} failed: {}",
+                run_id, iteration, e
+            );
+        }
+        let mut calibration =
+            Calibration::new(run_id.clone(), data.image.clone(), iteration as i64, result);
+        if let Err(e) = calibration.insert(&db_pool).await {
+            error!("Failed to insert calibration {}: {:?}", run_id, e);
+        }
+        iterations.push(calibration);
+    }
+    let _ = orchestrator.release_resources(cpus, (data.memory * 1024).try_into().unwrap());
+
+    let summary = CalibrationSummary::new(run_id, data.image, &iterations);
+    if let Some(service_time) = summary.service_time() {
+        info!("Calibrated service time: {:.3} ms", service_time);
+        orchestrator.scheduler().calibrate(service_time);
+    }
+    HttpResponse::Ok().json(summary)
+}
+
+/// Set the quota of an API key
+#[put("/quotas/{key}")]
+async fn set_quota(
+    path: web::Path<String>,
+    data: web::Json<QuotaLimits>,
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+    quotas: web::Data<Arc<QuotaTracker>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    let (api_key, limits) = (path.into_inner(), data.into_inner());
+    if let Err(e) = limits.validate() {
+        return HttpResponse::BadRequest().body(format!("{}\n", e));
+    }
+    if let Err(e) = Quota::new(api_key.clone(), limits).upsert(&db_pool).await {
+        error!("Failed to store the quota of {}: {:?}", api_key, e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    quotas.set_limits(&api_key, limits);
+    HttpResponse::Ok().json(quotas.status(&api_key))
+}
+
+/// Get the usage and the quota of an API key
+#[get("/quotas/{key}/usage")]
+async fn quota_usage(
+    path: web::Path<String>,
+    quotas: web::Data<Arc<QuotaTracker>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    HttpResponse::Ok().json(quotas.status(&path.into_inner()))
+}
+
+/// Schedule an invocation
+#[post("/schedules")]
+async fn create_schedule(
+    data: web::Json<NewSchedule>,
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    let data = data.into_inner();
+    let timing = match data.timing() {
+        Ok(timing) => timing,
+        Err(e) => return HttpResponse::BadRequest().body(format!("{}\n", e)),
+    };
+    if let Some((response, _)) = check_request(&data.invoke) {
+        return response;
+    }
+    let Some(next_run) = timing.next_after(chrono::Utc::now()) else {
+        return HttpResponse::BadRequest().body("The schedule never runs\n");
+    };
+    let mut schedule = Schedule::new(
+        &data.invoke,
+        data.cron_expr,
+        data.interval,
+        next_run.naive_utc(),
+    );
+    if let Err(e) = schedule.insert(&db_pool).await {
+        error!(
+            "Failed to store the schedule of {}: {:?}",
+            schedule.function, e
+        );
+        return HttpResponse::InternalServerError().finish();
+    }
+    info!(
+        "Scheduled {} as {}, first run at {}",
+        schedule.function, schedule.id, schedule.next_run
+    );
+    HttpResponse::Created().json(schedule)
+}
+
+/// List the scheduled invocations, with their last run
+#[get("/schedules")]
+async fn list_schedules(
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    match Schedule::list(&db_pool).await {
+        Ok(schedules) => HttpResponse::Ok().json(schedules),
+        Err(e) => {
+            error!("Cannot list the schedules: {}", e);
+            HttpResponse::InternalServerError().body("Cannot list the schedules\n")
+        }
+    }
+}
+
+/// Pause or resume a schedule. A resumed schedule does not run the runs it missed
+/// while paused, it runs next at its first time after now.
+#[post("/schedules/{id}/{action}")]
+async fn pause_schedule(
+    path: web::Path<(i64, String)>,
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    let (id, action) = path.into_inner();
+    let paused = match action.as_str() {
+        "pause" => true,
+        "resume" => false,
+        _ => return HttpResponse::NotFound().finish(),
+    };
+    let schedule = match Schedule::get(&db_pool, id).await {
+        Ok(Some(schedule)) => schedule,
+        Ok(None) => return HttpResponse::NotFound().body("Schedule not found\n"),
+        Err(e) => {
+            error!("Cannot read schedule {}: {}", id, e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+    let next_run = match paused {
+        true => None,
+        false => Timing::new(schedule.cron_expr.as_deref(), schedule.interval)
+            .ok()
+            .and_then(|timing| timing.next_after(chrono::Utc::now()))
+            .map(|next_run| next_run.naive_utc()),
+    };
+    if let Err(e) = Schedule::set_paused(&db_pool, id, paused, next_run).await {
+        error!("Cannot {} schedule {}: {}", action, id, e);
+        return HttpResponse::InternalServerError().finish();
+    }
+    match Schedule::get(&db_pool, id).await {
+        Ok(Some(schedule)) => HttpResponse::Ok().json(schedule),
+        _ => HttpResponse::NotFound().body("Schedule not found\n"),
+    }
+}
+
+/// Delete a schedule
+#[delete("/schedules/{id}")]
+async fn delete_schedule(
+    path: web::Path<i64>,
+    db_pool: web::Data<Pool<sqlite::Sqlite>>,
+    admin_token: web::Data<AdminToken>,
+    req: HttpRequest,
+) -> HttpResponse {
+    if let Some(refused) = admin_token.check(&req) {
+        return refused;
+    }
+    match Schedule::delete(&db_pool, path.into_inner()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => HttpResponse::NotFound().body("Schedule not found\n"),
+        Err(e) => {
+            error!("Cannot delete the schedule: {}", e);
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+/// Shared state needed to run a function outside of a request, e.g. for a schedule
+#[derive(Clone)]
+pub struct InvokeContext {
+    pub db_pool: Pool<sqlite::Sqlite>,
+    pub firecracker_builder: web::Data<Arc<FirecrackerBuilder>>,
+    pub orchestrator: Arc<orchestrator::Orchestrator>,
+    pub request_log: RequestLog,
+    pub status_writer: StatusWriter,
+    pub quotas: Arc<QuotaTracker>,
+}
+
+/// The endpoints get the context from the state of the app, registered by
+/// `NodeState::configure`
+impl FromRequest for InvokeContext {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut dev::Payload) -> Self::Future {
+        fn data<T: 'static>(req: &HttpRequest) -> Result<&web::Data<T>, actix_web::Error> {
+            req.app_data::<web::Data<T>>().ok_or_else(|| {
+                error!("{} is missing from the app", std::any::type_name::<T>());
+                error::ErrorInternalServerError("The node is not configured")
+            })
+        }
+        let context = || {
+            Ok(Self {
+                db_pool: data::<Pool<sqlite::Sqlite>>(req)?.get_ref().clone(),
+                firecracker_builder: data::<Arc<FirecrackerBuilder>>(req)?.clone(),
+                orchestrator: data::<Arc<orchestrator::Orchestrator>>(req)?
+                    .get_ref()
+                    .clone(),
+                request_log: data::<RequestLog>(req)?.get_ref().clone(),
+                status_writer: data::<StatusWriter>(req)?.get_ref().clone(),
+                quotas: data::<Arc<QuotaTracker>>(req)?.get_ref().clone(),
+            })
+        };
+        ready(context())
+    }
+}
+
+/// Run a function as /invoke would, with nobody waiting for its output
+/// # Returns
+/// * The outcome of the request, also recorded with the other requests
+pub async fn invoke_unattended(context: &InvokeContext, data: InvokeFunction) -> RequestOutcome {
+    let received_at = chrono::Utc::now().naive_utc();
+    let (function, hops) = (data.function.clone(), data.hops);
+    let (response, outcome) = serve(context, web::Json(data), None, None, None).await;
+    context.request_log.record(
+        Request::new(function, hops, outcome.clone(), received_at)
+            .with_offload_trace(trace::trace_id(&response))
+            .with_chaos(chaos::marked(&response)),
+    );
+    outcome
+}
+
+/// Everything the endpoints of a node depend on, given to the app of every worker.
+/// The node builds it from its command line, the tests from in-process fakes.
+#[derive(Clone)]
+pub struct NodeState {
+    pub context: InvokeContext,
+    pub idempotency: Arc<IdempotencyCache>,
+    pub admin_token: AdminToken,
+    pub cluster_auth: ClusterAuth,
+    pub compression: Compression,
+    pub spill: SpillConfig,
+    pub announcer: Announcer,
+    pub diagnostics: Diagnostics,
+    pub supervisor: Arc<Supervisor>,
+}
+
+/// Register the endpoints answering while the node starts and registers, before its state
+/// is built: the health tells the phase, and every other request is refused until the
+/// node is ready
+pub fn configure_startup(cfg: &mut web::ServiceConfig, readiness: Arc<Readiness>) {
+    cfg.app_data(web::Data::new(readiness))
+        .service(startup_healthz)
+        .default_service(web::to(refuse_starting));
+}
+
+/// Get the health of a node that starts
+#[get("/healthz")]
+async fn startup_healthz(readiness: web::Data<Arc<Readiness>>) -> impl Responder {
+    HttpResponse::Ok().json(Health {
+        state: DrainState::Serving,
+        running_instances: 0,
+        emergency: false,
+        degraded: false,
+        tasks: vec![],
+        phase: readiness.phase(),
+        phase_changes: readiness.changes(),
+    })
+}
+
+async fn refuse_starting(readiness: web::Data<Arc<Readiness>>) -> HttpResponse {
+    not_ready(readiness.phase())
+}
+
+impl NodeState {
+    /// Register the state and the endpoints of the node in an app
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        let context = &self.context;
+        cfg.app_data(web::JsonConfig::default().limit(self.spill.max_size))
+            // The requests to /invoke are read as raw bytes
+            .app_data(web::PayloadConfig::new(self.spill.max_size))
+            .app_data(web::Data::new(self.spill.clone()))
+            .app_data(web::Data::new(context.db_pool.clone()))
+            .app_data(context.firecracker_builder.clone())
+            .app_data(web::Data::new(context.orchestrator.clone()))
+            .app_data(web::Data::new(context.request_log.clone()))
+            .app_data(web::Data::new(context.status_writer.clone()))
+            .app_data(web::Data::new(self.idempotency.clone()))
+            .app_data(web::Data::new(self.admin_token.clone()))
+            .app_data(web::Data::new(context.quotas.clone()))
+            .app_data(web::Data::new(self.cluster_auth))
+            .app_data(web::Data::new(self.compression))
+            .app_data(web::Data::new(self.announcer.clone()))
+            .app_data(web::Data::new(self.diagnostics.clone()))
+            .app_data(web::Data::new(self.supervisor.clone()))
+            .service(index)
+            .service(list)
+            .service(invoke)
+            .service(invoke_binary)
+            .service(invoke_batch)
+            .service(invoke_pipeline)
+            .service(resources)
+            .service(images)
+            .service(debug_resources)
+            .service(debug_bundle)
+            .service(debug_crashes)
+            .service(debug_workspaces)
+            .service(debug_offloads)
+            .service(debug_packing)
+            .service(nodes_ranking)
+            .service(debug_orchestrator)
+            .service(list_chaos)
+            .service(inject_chaos)
+            .service(clear_chaos)
+            .service(remove_chaos)
+            .service(emergency)
+            .service(emergency_history)
+            .service(healthz)
+            .service(drain)
+            .service(undrain)
+            .service(get_instance)
+            .service(export_instances)
+            .service(export_stats)
+            .service(stats)
+            .service(runs)
+            .service(calibrate)
+            .service(set_quota)
+            .service(quota_usage)
+            .service(create_schedule)
+            .service(list_schedules)
+            .service(pause_schedule)
+            .service(delete_schedule);
+    }
+}
+
+/// Resolve the resources of a request against the defaults of its function and the
+/// limits of the node
+fn resolve_request(
+    orchestrator: &orchestrator::Orchestrator,
+    data: &InvokeFunction,
+) -> Result<Resolved, ResolveError> {
+    let request = ResourceSpec::requested(data.vcpus, data.memory);
+    orchestrator
+        .resolve_resources(&data.function, &request)
+        .inspect_err(|e| warn!("Refused a request for {}: {}", data.function, e))
+}
+
+/// Answer to a request whose resources cannot be resolved
+fn unresolved(e: ResolveError) -> (HttpResponse, RequestOutcome) {
+    (
+        HttpResponse::BadRequest().body(format!("{}\n", e)),
+        RequestOutcome::Rejected,
+    )
+}
+
+/// Offload a request, unless its payload was received apart from it: such a payload
+/// may be on disk, and is not forwarded to the other nodes
+async fn offload(
+    orchestrator: &orchestrator::Orchestrator,
+    data: web::Json<InvokeFunction>,
+    raw: Option<web::Bytes>,
+    origin: Option<IpAddr>,
+    body: Option<&Body>,
+) -> (HttpResponse, RequestOutcome) {
+    if body.is_some() {
+        warn!(
+            "Cannot offload {}, its payload was received apart",
+            data.function
+        );
+        return (
+            HttpResponse::ServiceUnavailable()
+                .body("Insufficient resources, binary payloads are not offloaded\n"),
+            RequestOutcome::Rejected,
+        );
+    }
+    orchestrator.offload(data, raw, origin).await
+}
+
+/// Check a request, returning the answer if it must be refused
+fn check_request(data: &InvokeFunction) -> Option<(HttpResponse, RequestOutcome)> {
+    // Only for debug
+    if data.hops > 0 {
+        warn!("Request with number of hops: {:?}", data.hops);
+    }
+    if data.hops > 10 {
+        // TODO: Find a better way
+        return Some((
+            HttpResponse::InternalServerError().body("Too many hops\n"),
+            RequestOutcome::Rejected,
+        ));
+    }
+
+    // Reject rate limits that do not make sense
+    if let Some(rate_limits) = &data.rate_limits {
+        if let Err(e) = rate_limits.validate() {
+            return Some((
+                HttpResponse::BadRequest().body(format!("{}\n", e)),
+                RequestOutcome::Rejected,
+            ));
+        }
+    }
+
+    // Reject environment variables and arguments that cannot be put on the command line
+    if let Err(e) = data.guest_args().validate() {
+        return Some((
+            HttpResponse::BadRequest().body(format!("{}\n", e)),
+            RequestOutcome::Rejected,
+        ));
+    }
+    None
+}
+
+/// Where a request goes, once checked
+enum Triage {
+    /// The request is refused, with its answer
+    Refused(HttpResponse, RequestOutcome),
+    /// The request is served by another node
+    Offload,
+    /// The request is served by this node, if it has the resources
+    Local,
+}
+
+/// Check a request before the resources of its instance are acquired, the same way for
+/// every endpoint. The resources of the request are resolved against the defaults of
+/// its function.
+fn triage(context: &InvokeContext, data: &mut InvokeFunction) -> Triage {
+    let orchestrator = &context.orchestrator;
+    if orchestrator
+        .chaos()
+        .is_some_and(|chaos| chaos.drop_invoke(&data.function))
+    {
+        let mut response =
+            HttpResponse::ServiceUnavailable().body("Dropped by a chaos injection\n");
+        chaos::mark(&mut response);
+        return Triage::Refused(response, RequestOutcome::Rejected);
+    }
+    if let Some((response, outcome)) = check_request(data) {
+        return Triage::Refused(response, outcome);
+    }
+    match resolve_request(orchestrator, data) {
+        Ok(resolved) => {
+            data.vcpus = resolved.vcpus;
+            data.memory = resolved.memory;
+        }
+        Err(e) => {
+            let (response, outcome) = unresolved(e);
+            return Triage::Refused(response, outcome);
+        }
+    }
+
+    // Without a deadline set on arrival, e.g. for a function of a batch or a step of a
+    // pipeline, the timeout runs from now
+    if data.deadline.is_none() {
+        data.deadline = data
+            .timeout_ms
+            .map(|timeout| Instant::now() + Duration::from_millis(timeout));
+    }
+
+    // Booting an instance is pointless when the client gives up before it answers
+    if let Some(Err(late)) = data
+        .deadline
+        .map(|deadline| orchestrator.scheduler().local_deadline(deadline))
+    {
+        warn!(
+            "Shedding {}, it would answer too late: {}",
+            data.function, late
+        );
+        return Triage::Refused(
+            HttpResponse::GatewayTimeout().body(format!("Deadline exceeded, {}\n", late)),
+            RequestOutcome::Shed,
+        );
+    }
+
+    // Emergency Management
+    // If in emergency mode, but the request is not in emergency, offload the request
+    if orchestrator.in_emergency_area() && !data.emergency {
+        return Triage::Offload;
+    }
+
+    // A draining node only keeps the emergency requests
+    if orchestrator.drain().is_draining() && !data.emergency {
+        return Triage::Offload;
+    }
+
+    // So does a node short of memory, rather than risking the OOM killer
+    if orchestrator.memory_pressure().is_under_pressure() && !data.emergency {
+        return Triage::Offload;
+    }
+
+    // An instance cannot start without an address, unless it may wait for one
+    let firecracker_builder = &context.firecracker_builder;
+    if firecracker_builder.address_wait.is_zero()
+        && firecracker_builder.free_addresses(&data.function) == 0
+    {
+        warn!("No address left for {}, offloading it", data.function);
+        return Triage::Offload;
+    }
+    Triage::Local
+}
+
+/// Reserve the resources of a request against the quota of its key, if it has one
+/// # Errors
+/// * The usage and limits of the key, if the instance would exceed its quota
+fn reserve_quota(
+    quotas: &Arc<QuotaTracker>,
+    data: &InvokeFunction,
+) -> Result<Option<QuotaReservation>, QuotaStatus> {
+    let Some(api_key) = &data.api_key else {
+        return Ok(None);
+    };
+    quotas
+        .reserve(api_key, data.vcpus.into(), data.memory.into())
+        .map(Some)
+        .inspect_err(|status| warn!("Quota of {} exceeded: {:?}", api_key, status.usage))
+}
+
+/// Answer to a request whose instance would exceed the quota of its key
+fn over_quota(status: QuotaStatus) -> (HttpResponse, RequestOutcome) {
+    (
+        HttpResponse::TooManyRequests().json(status),
+        RequestOutcome::Rejected,
+    )
+}
+
+/// Serve a request, locally or by offloading it
+async fn serve(
+    context: &InvokeContext,
+    mut data: web::Json<InvokeFunction>,
+    mut raw: Option<web::Bytes>,
+    origin: Option<IpAddr>,
+    body: Option<&Body>,
+) -> (HttpResponse, RequestOutcome) {
+    let orchestrator = &context.orchestrator;
+    let requested = (data.vcpus, data.memory);
+    let triage = triage(context, &mut data);
+    if (data.vcpus, data.memory) != requested {
+        // The resolved resources must be written in the forwarded body
+        raw = None;
+    }
+    match triage {
+        Triage::Refused(response, outcome) => return (response, outcome),
+        Triage::Offload => return offload(orchestrator, data, raw, origin, body).await,
+        Triage::Local => {}
+    }
+
+    // Reserve the resources of the instance against the quota of the key,
+    // they are given back when the request leaves this function
+    let reservation = match reserve_quota(&context.quotas, &data) {
+        Ok(reservation) => reservation,
+        Err(status) => return over_quota(status),
+    };
+
+    // Otherwise, handle the request
+    // Leave the cpus freeing to a larger waiting request for a while, with packing
+    orchestrator
+        .hold_for_larger(data.vcpus.try_into().unwrap(), data.emergency)
+        .await;
+
+    // Check and acquire resources
+    let _resources = orchestrator.check_and_acquire_resources(
+        data.vcpus.try_into().unwrap(),
+        (data.memory * 1024).try_into().unwrap(),
+        data.emergency,
+    );
+
+    // If no resources are available, wait for them or offload the request,
+    // whichever is expected to complete first. Nothing was acquired, nothing to release.
+    if _resources.is_err() {
+        match orchestrator.schedule(&data, origin) {
+            Decision::Offload => {
+                drop(reservation);
+                return offload(orchestrator, data, raw, origin, body).await;
+            }
+            Decision::Wait(timeout) => {
+                let acquired = orchestrator
+                    .wait_for_resources(
+                        data.vcpus.try_into().unwrap(),
+                        (data.memory * 1024).try_into().unwrap(),
+                        data.emergency,
+                        timeout,
+                    )
+                    .await;
+                if acquired.is_err() {
+                    drop(reservation);
+                    return offload(orchestrator, data, raw, origin, body).await;
+                }
+            }
+        }
+    }
+
+    let (response, outcome, _) = run_locally(
+        &data,
+        body,
+        &context.db_pool,
+        &context.status_writer,
+        &context.firecracker_builder,
+        orchestrator,
+    )
+    .await;
+    drop(reservation);
+    (response, outcome)
+}
+
+/// Number of instances started for a request before giving up, the first one included
+const MAX_START_ATTEMPTS: u32 = 4;
+
+/// Wait before the second start of an instance for a request, see `start_backoff`
+const START_BACKOFF: Duration = Duration::from_millis(20);
+
+/// Run a request on a new local instance, retrying if the instance fails for a reason that
+/// may not happen again, see `InstanceError::is_retryable`.
+/// The resources of the request must be acquired, they are released here.
+/// # Returns
+/// * The answer, the outcome and the id of the instance that ran the function
+async fn run_locally(
+    data: &InvokeFunction,
+    body: Option<&Body>,
+    db_pool: &Pool<sqlite::Sqlite>,
+    status_writer: &StatusWriter,
+    firecracker_builder: &web::Data<Arc<FirecrackerBuilder>>,
+    orchestrator: &orchestrator::Orchestrator,
+) -> (HttpResponse, RequestOutcome, Option<i64>) {
+    // The node is not drained until the request leaves this function
+    let _running = orchestrator.drain().instance();
+    // Start instance
+    let mut attempt = 0;
+    // Whether a start failed for a chaos injection
+    let mut injected = false;
+    let mark = |mut response: HttpResponse, injected: bool| {
+        if injected {
+            chaos::mark(&mut response);
+        }
+        response
+    };
+    let failed = |attempts: u32, injected: bool| {
+        // If an error occurs, release resources and return error
+        let _ = orchestrator.release_resources(
+            data.vcpus.try_into().unwrap(),
+            (data.memory * 1024).try_into().unwrap(),
+        );
+        (
+            mark(
+                HttpResponse::InternalServerError().body(format!(
+                    "Failed to start instance, attempt {} of {}\n",
+                    attempts, MAX_START_ATTEMPTS
+                )),
+                injected,
+            ),
+            RequestOutcome::Failed,
+            None,
+        )
+    };
+    loop {
+        if attempt == MAX_START_ATTEMPTS {
+            return failed(attempt, injected);
+        }
+        attempt += 1;
+        if orchestrator
+            .chaos()
+            .is_some_and(|chaos| chaos.fail_instance(&data.image))
+        {
+            injected = true;
+            continue;
+        }
+        let mut timings = ColdStartTimings::default();
+        match start_instance(
+            firecracker_builder,
+            db_pool,
+            status_writer,
+            data,
+            body,
+            attempt,
+            &mut timings,
+        )
+        .await
+        {
+            Ok((id, body)) => {
+                // Release resources
+                let _ = orchestrator.release_resources(
+                    data.vcpus.try_into().unwrap(),
+                    (data.memory * 1024).try_into().unwrap(),
+                );
+                let mut response = HttpResponse::Ok().body(body);
+                set_served(
+                    &mut response,
+                    orchestrator.get_identity().key(),
+                    data.hops,
+                    id,
+                );
+                return (
+                    mark(response, injected),
+                    RequestOutcome::ServedLocally,
+                    Some(id),
+                );
+            }
+            Err(InstanceError::AddressesExhausted(network)) => {
+                // Every attempt would fail the same until an instance is deleted
+                warn!(
+                    "No address left in network {} for {}",
+                    network, data.function
+                );
+                let _ = orchestrator.release_resources(
+                    data.vcpus.try_into().unwrap(),
+                    (data.memory * 1024).try_into().unwrap(),
+                );
+                return (
+                    mark(
+                        HttpResponse::ServiceUnavailable().body(format!(
+                            "Insufficient resources, no address available in network {}\n",
+                            network
+                        )),
+                        injected,
+                    ),
+                    RequestOutcome::Rejected,
+                    None,
+                );
+            }
+            Err(InstanceError::GuestError(message)) => {
+                // The function itself failed, retrying would not help
+                let _ = orchestrator.release_resources(
+                    data.vcpus.try_into().unwrap(),
+                    (data.memory * 1024).try_into().unwrap(),
+                );
+                return (
+                    mark(HttpResponse::InternalServerError().body(message), injected),
+                    RequestOutcome::Failed,
+                    None,
+                );
+            }
+            Err(e) if !e.is_retryable() => {
+                // Retrying would fail the same, only later
+                error!(
+                    "Error in starting execution environment, not retried: {}",
+                    e
+                );
+                return failed(attempt, injected);
+            }
+            Err(e) => {
+                error!(
+                    "Error in starting execution environment (attempt {} of {}): {}",
+                    attempt, MAX_START_ATTEMPTS, e
+                );
+                if attempt < MAX_START_ATTEMPTS {
+                    actix_web::rt::time::sleep(start_backoff(attempt)).await;
+                }
+            }
+        };
+    }
+}
+
+/// Time to wait before the next start of an instance, after `attempt` failed.
+/// It doubles at each attempt, with a random part so that the requests failing together
+/// do not start their instances together again.
+fn start_backoff(attempt: u32) -> Duration {
+    let base = START_BACKOFF * 2u32.pow(attempt.saturating_sub(1).min(8));
+    base / 2 + base.mul_f64(rand::random::<f64>() / 2.0)
+}
+
+/// Store the summary of the metrics of an instance, before it is deleted
+async fn record_metrics(
+    db_pool: &Pool<sqlite::Sqlite>,
+    instance: &Instance,
+    fc_instance: &FirecrackerInstance,
+) {
+    match fc_instance.metrics().await {
+        Ok(summary) => {
+            let mut metrics = InstanceMetrics::new(instance.id, summary);
+            if let Err(e) = metrics.insert(db_pool).await {
+                error!(
+                    "Failed to insert metrics of instance {}: {:?}",
+                    instance.id, e
+                );
+            }
+        }
+        Err(e) => error!("Failed to read metrics of instance {}: {}", instance.id, e),
+    }
+}
+
+async fn emergency_cleanup(
+    db_pool: &Pool<sqlite::Sqlite>,
+    status_writer: &StatusWriter,
+    instance: &mut Instance,
+    fc_instance: &mut FirecrackerInstance,
+    builder: &web::Data<Arc<FirecrackerBuilder>>,
+    reason: &InstanceError,
+) {
+    status_writer.set_status(instance, "failed").await;
+    // Failures are rare, the reason is written right away for the diagnostics bundle
+    let reason = reason.to_string();
+    if let Err(e) = Instance::set_error(db_pool, instance.id, &reason).await {
+        error!(
+            "Failed to record the error of instance {}: {}",
+            instance.id, e
+        );
+    }
+    instance.error = Some(reason);
+    record_metrics(db_pool, instance, fc_instance).await;
+    let _ = fc_instance.delete().await;
+    builder.release_address(fc_instance);
+}
+
+/// The firecracker process of an instance exited on its own: count the crash against the
+/// image, so a broken image stands out.
+fn vmm_crashed(
+    builder: &FirecrackerBuilder,
+    image: &str,
+    instance_id: i64,
+    exit: VmmExit,
+) -> InstanceError {
+    error!(
+        "The firecracker process of instance {} ({}) crashed: {}",
+        instance_id, image, exit
+    );
+    builder.record_crash(image);
+    InstanceError::VmmCrashed(exit)
+}
+
+/// Map an I/O error on the vsock to an instance error.
+/// A timeout means the function is slow, anything else means the guest went away.
+fn vsock_error(e: &std::io::Error) -> InstanceError {
+    match e.kind() {
+        std::io::ErrorKind::TimedOut => InstanceError::Timeout,
+        _ => InstanceError::VSock,
+    }
+}
+
+/// Exchange payload and response with a guest speaking the legacy (v1) protocol:
+/// a bare "ready" string followed by raw length-prefixed payloads.
+async fn exchange_v1(
+    stream: &mut UnixStream,
+    payload: Option<&Body>,
+    instance_id: i64,
+) -> Result<Vec<u8>, InstanceError> {
+    let start = Instant::now();
+    let mut buf = [0; 5];
+    // Read from the vsock socket
+    match read_exact(stream, &mut buf, 500).await {
+        // 500ms Timeout for machine to be ready
+        Ok(_) => {}
+        Err(e) => {
+            error!("Error reading from vsocket: {}", e);
+            return Err(InstanceError::VSock);
+        }
+    }
+
+    let duration = start.elapsed();
+    error!("Time to read from vsock: {} ms", duration.as_millis());
+
+    // Escaped when logged, nothing is allocated to check the message
+    info!(
+        "Received message: {}, for instance {}",
+        buf.escape_ascii(),
+        instance_id
+    );
+
+    // Check if the instance is ready through the vsock socket
+    if &buf != LEGACY_READY {
+        error!("Message not ready: {}", buf.escape_ascii());
+        error!("Instance {} failed to start", instance_id);
+        return Err(InstanceError::VSock);
+    }
+
+    let start = Instant::now();
+    // Write payload in the vsock socket
+    if let Some(payload) = payload {
+        info!("Sending payload to instance: {}", instance_id);
+        // Write the length of the payload, then the payload
+        let len = payload.len();
+        // TODO: Specify the timeout
+        if let Err(e) = payload.write_to(stream, &len.to_be_bytes(), 1000).await {
+            error!("Error writing to vsocket: {}", e);
+            return Err(InstanceError::VSock);
+        }
+    }
+
+    let duration = start.elapsed();
+    error!(
+        "Time to write payload to vsock: {} ms",
+        duration.as_millis()
+    );
+
+    let start = Instant::now();
+    // Read the length of the response
+    info!("Reading length of response from instance: {}", instance_id);
+    let mut len = [0; 8];
+    // TODO: Specify the timeout
+    if let Err(e) = read_exact(stream, &mut len, 10000).await {
+        error!("Error reading from vsocket: {}", e);
+        return Err(vsock_error(&e));
+    }
+
+    let len = u64::from_be_bytes(len);
+    info!("Length of response: {}, for instance {}", len, instance_id);
+    // The length comes from the guest, a frame of the v2 protocol has the same limit
+    if len > MAX_FRAME_LEN as u64 {
+        error!(
+            "Response of {} bytes from instance {}, too large",
+            len, instance_id
+        );
+        return Err(InstanceError::VSock);
+    }
+    let mut buf = vec![0; len as usize];
+    // Read the response
+    // TODO: Specify the timeout
+    if let Err(e) = read_exact(stream, &mut buf, 10000).await {
+        error!("Error reading from vsocket: {}", e);
+        return Err(vsock_error(&e));
+    }
+
+    let duration = start.elapsed();
+    error!(
+        "Time to read response from vsock: {} ms",
+        duration.as_millis()
+    );
+
+    Ok(buf)
+}
+
+/// Exchange payload and response with a guest speaking the framed (v2) protocol.
+/// See [`crate::utils::protocol`] for the description of the frames.
+async fn exchange_v2(
+    stream: &mut UnixStream,
+    payload: Option<&Body>,
+    instance_id: i64,
+) -> Result<Vec<u8>, InstanceError> {
+    let start = Instant::now();
+    // 500ms Timeout for machine to be ready
+    let ready = match read_frame(stream, 500).await {
+        Ok(frame) if frame.frame_type == FrameType::Ready => frame,
+        Ok(frame) => {
+            error!(
+                "Instance {} failed to start: {}",
+                instance_id,
+                ProtocolError::UnexpectedFrame(frame.frame_type)
+            );
+            return Err(InstanceError::VSock);
+        }
+        Err(e) => {
+            error!("Error reading ready frame from vsocket: {}", e);
+            return Err(InstanceError::VSock);
+        }
+    };
+
+    let duration = start.elapsed();
+    error!("Time to read from vsock: {} ms", duration.as_millis());
+
+    let metadata = ready.metadata();
+    info!(
+        "Guest agent {} ready (function: {:?}), for instance {}",
+        metadata.agent, metadata.function, instance_id
+    );
+
+    let start = Instant::now();
+    // Write payload in the vsock socket, an empty payload is still sent
+    // so the guest does not have to guess whether there is one
+    info!("Sending payload to instance: {}", instance_id);
+    let len = payload.map_or(0, Body::len);
+    // TODO: Specify the timeout
+    let header = Frame::header(FrameType::Payload, len);
+    let written = match payload {
+        Some(payload) => payload.write_to(stream, &header, 1000).await,
+        None => write_all(stream, &header, 1000).await,
+    };
+    if let Err(e) = written {
+        error!("Error writing to vsocket: {}", e);
+        return Err(InstanceError::VSock);
+    }
+
+    let duration = start.elapsed();
+    error!(
+        "Time to write payload to vsock: {} ms",
+        duration.as_millis()
+    );
+
+    let start = Instant::now();
+    loop {
+        // TODO: Specify the timeout
+        let frame = match read_frame(stream, 10000).await {
+            Ok(frame) => frame,
+            Err(ProtocolError::Io(e)) => {
+                error!("Error reading from vsocket: {}", e);
+                return Err(vsock_error(&e));
+            }
+            Err(e) => {
+                error!("Invalid frame from instance {}: {}", instance_id, e);
+                return Err(InstanceError::VSock);
+            }
+        };
+        match frame.frame_type {
+            FrameType::Log => {
+                info!("[instance {}] {}", instance_id, frame.body_str());
+            }
+            FrameType::Result => {
+                let duration = start.elapsed();
+                error!(
+                    "Time to read response from vsock: {} ms",
+                    duration.as_millis()
+                );
+                return Ok(frame.body);
+            }
+            FrameType::Error => {
+                let message = frame.body_str();
+                error!("Instance {} reported an error: {}", instance_id, message);
+                return Err(InstanceError::GuestError(message));
+            }
+            frame_type => {
+                error!(
+                    "Invalid frame from instance {}: {}",
+                    instance_id,
+                    ProtocolError::UnexpectedFrame(frame_type)
+                );
+                return Err(InstanceError::VSock);
+            }
+        }
+    }
+}
+
+/// Time a guest serving HTTP has to answer, once it is reachable
+const HTTP_GUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum size of a payload delivered through MMDS.
+/// Firecracker limits the whole data store to 51200 bytes by default.
+const MMDS_MAX_PAYLOAD: usize = 48 * 1024;
+
+/// Decide how the payload is delivered to the guest.
+/// Returns the data to be exposed through MMDS and the payload to be written on the vsock.
+fn route_payload(data: &InvokeFunction) -> (Option<serde_json::Value>, Option<String>) {
+    match (data.payload_via, &data.payload) {
+        (PayloadVia::Mmds, Some(payload)) if payload.len() <= MMDS_MAX_PAYLOAD => (
+            Some(serde_json::json!({
+                "spare": {
+                    "function": data.function,
+                    "payload": payload,
+                }
+            })),
+            None,
+        ),
+        (PayloadVia::Mmds, Some(payload)) => {
+            warn!(
+                "Payload of {} bytes is too large for MMDS, falling back to vsock",
+                payload.len()
+            );
+            (None, Some(payload.clone()))
+        }
+        _ => (None, data.payload.clone()),
+    }
+}
+
+/// Method to start a new instance on the node
+async fn start_instance(
+    firecracker_builder: &web::Data<Arc<FirecrackerBuilder>>,
+    db_pool: &Pool<sqlite::Sqlite>,
+    status_writer: &StatusWriter,
+    data: &InvokeFunction,
+    body: Option<&Body>,
+    attempt: u32,
+    timings: &mut ColdStartTimings,
+) -> Result<(i64, Bytes), InstanceError> {
+    /*
+    TODO: START INSTANCE
+        1) Create new vm instance (todo: check if it already exists and mantain warm pool)
+        2) Start instance
+        3) Update instance status
+        4) Forward request to instance
+        5) Wait for response
+        6) Return response
+        7) Delete instance
+    */
+    let builder = firecracker_builder;
+
+    let guest = builder.guest(&data.function);
+    let (mmds, payload) = route_payload(data);
+    // A guest serving HTTP gets the whole payload at once
+    let request = match guest.mode {
+        GuestMode::Vsock => None,
+        GuestMode::Http => {
+            let body = body
+                .map(Body::to_bytes)
+                .transpose()
+                .map_err(InstanceError::Payload)?;
+            Some(GuestRequest::new(payload.clone(), body))
+        }
+    };
+    // A payload received apart from the request wins over the one in it
+    let payload = payload.map(|payload| Body::Memory(payload.into()));
+    let payload = body.or(payload.as_ref());
+    let guest_args = data.guest_args();
+
+    // Fetch the image, if it is not available locally
+    let image = builder
+        .image_cache
+        .resolve(&data.image, data.image_digest.as_deref())
+        .await?;
+
+    let start = Instant::now();
+    // Create new instance
+    let fc_instance = builder
+        .new_instance(
+            &data.function,
+            InstanceSpec::new(image, data.vcpus, data.memory)
+                .with_mmds(mmds)
+                .with_rate_limits(data.rate_limits.unwrap_or_default())
+                .with_guest_args(guest_args.clone()),
+        )
+        .await;
+
+    let duration = start.elapsed();
+    error!("Time to create instance: {} ms", duration.as_millis());
+    timings.create = duration;
+
+    let mut fc_instance = fc_instance?;
+    info!("Created new instance: {}", fc_instance.get_address());
+    // Insert instance in the database
+    let mut instance = Instance::new(
+        data.function.clone(),
+        builder.kernel.clone(),
+        data.image.clone(),
+        data.vcpus,
+        data.memory,
+        data.hops,
+        fc_instance.get_address().to_string(),
+        guest.guest_port as i32,
+    )
+    .with_guest_args(&guest_args)
+    .with_api_key(data.api_key.clone())
+    .with_emergency(data.emergency)
+    .with_attempt(attempt);
+    match instance.insert(db_pool).await {
+        Ok(_) => {}
+        Err(e) => {
+            error!("Failed to insert instance in the database: {}", e);
+            let err = InstanceError::Database(e);
+            emergency_cleanup(
+                db_pool,
+                status_writer,
+                &mut instance,
+                &mut fc_instance,
+                builder,
+                &err,
+            )
+            .await;
+            return Err(err);
+        }
+    }
+
+    info!("Created new function instance: {}", instance.id);
+
+    // Make sure the vsock socket is ready, for the guests connecting to it. The socket
+    // files are removed when the ports are dropped, with the instance.
+    let ports = match guest.mode {
+        GuestMode::Http => None,
+        GuestMode::Vsock => {
+            match VsockPorts::reserve(&fc_instance.get_vsock_path(), &[guest.vsock_port]) {
+                Ok(ports) => {
+                    info!(
+                        "Socket created: {}, for instance {}",
+                        ports.control().path().display(),
+                        instance.id
+                    );
+                    Some(ports)
+                }
+                Err(e) => {
+                    error!("Error binding vsock socket: {}", e);
+                    let err = InstanceError::VSockCreation(e);
+                    emergency_cleanup(
+                        db_pool,
+                        status_writer,
+                        &mut instance,
+                        &mut fc_instance,
+                        builder,
+                        &err,
+                    )
+                    .await;
+                    return Err(err);
+                }
+            }
+        }
+    };
+
+    let start = Instant::now();
+    // Start instance
+    match fc_instance.start().await {
+        Ok(_) => {}
+        Err(e) => {
+            error!("Error in starting the instance: {}", e);
+            let err = InstanceError::InstanceStart(e);
+            emergency_cleanup(
+                db_pool,
+                status_writer,
+                &mut instance,
+                &mut fc_instance,
+                builder,
+                &err,
+            )
+            .await;
+            return Err(err);
+        }
+    }
+
+    let duration = start.elapsed();
+    error!("Time to start instance: {} ms", duration.as_millis());
+    timings.boot = duration;
+
+    info!("Starting instance: {} ip: {}", instance.id, instance.ip);
+
+    let buf = match &ports {
+        // The guest serves HTTP, the request is forwarded once its network is up
+        None => {
+            let start = Instant::now();
+            let request = request.unwrap_or(GuestRequest::Get);
+            let address = SocketAddr::new(IpAddr::V4(fc_instance.get_address()), guest.guest_port);
+            let forwarded = fc_instance
+                .watch(forward_http(
+                    address,
+                    &request,
+                    Retry::default(),
+                    HTTP_GUEST_TIMEOUT,
+                ))
+                .await;
+            let buf = match forwarded {
+                Ok(Ok(buf)) => buf.to_vec(),
+                Ok(Err(e)) => {
+                    error!(
+                        "Error forwarding the request to instance {}: {}",
+                        instance.id, e
+                    );
+                    let err = InstanceError::from(e);
+                    emergency_cleanup(
+                        db_pool,
+                        status_writer,
+                        &mut instance,
+                        &mut fc_instance,
+                        builder,
+                        &err,
+                    )
+                    .await;
+                    return Err(err);
+                }
+                Err(exit) => {
+                    let err = vmm_crashed(builder, &data.image, instance.id, exit);
+                    emergency_cleanup(
+                        db_pool,
+                        status_writer,
+                        &mut instance,
+                        &mut fc_instance,
+                        builder,
+                        &err,
+                    )
+                    .await;
+                    return Err(err);
+                }
+            };
+            timings.execute = start.elapsed();
+            buf
+        }
+        Some(ports) => {
+            let start = Instant::now();
+            let accepted = fc_instance
+                .watch(timeout(
+                    Duration::from_millis(500),
+                    ports.control().listener().accept(),
+                ))
+                .await;
+            let mut stream = match accepted {
+                Ok(Ok(res)) => match res {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!("Error accepting vsocket (stream): {:?}", e);
+                        let err = InstanceError::VSock;
+                        emergency_cleanup(
+                            db_pool,
+                            status_writer,
+                            &mut instance,
+                            &mut fc_instance,
+                            builder,
+                            &err,
+                        )
+                        .await;
+                        return Err(err);
+                    }
+                },
+                Ok(Err(e)) => {
+                    // If an error occurs, delete the instance and set 'failed' status
+                    error!("Error accepting vsocket (timeout): {:?}", e);
+                    let err = InstanceError::VSockTimeout;
+                    emergency_cleanup(
+                        db_pool,
+                        status_writer,
+                        &mut instance,
+                        &mut fc_instance,
+                        builder,
+                        &err,
+                    )
+                    .await;
+                    return Err(err);
+                }
+                Err(exit) => {
+                    let err = vmm_crashed(builder, &data.image, instance.id, exit);
+                    emergency_cleanup(
+                        db_pool,
+                        status_writer,
+                        &mut instance,
+                        &mut fc_instance,
+                        builder,
+                        &err,
+                    )
+                    .await;
+                    return Err(err);
+                }
+            };
+
+            let duration = start.elapsed();
+            error!("Time to accept vsock: {} ms", duration.as_millis());
+            timings.handshake = duration;
+
+            info!(
+                "Socket accepted: {}, for instance {}",
+                stream.as_raw_fd(),
+                instance.id
+            );
+
+            // Exchange payload and response with the guest
+            let start = Instant::now();
+            let exchange = async {
+                match builder.guest_protocol {
+                    GuestProtocol::V1 => exchange_v1(&mut stream, payload, instance.id).await,
+                    GuestProtocol::V2 => exchange_v2(&mut stream, payload, instance.id).await,
+                }
+            };
+            let buf = match fc_instance.watch(exchange).await {
+                Ok(buf) => buf,
+                Err(exit) => Err(vmm_crashed(builder, &data.image, instance.id, exit)),
+            };
+            let buf = match buf {
+                Ok(buf) => buf,
+                Err(e) => {
+                    emergency_cleanup(
+                        db_pool,
+                        status_writer,
+                        &mut instance,
+                        &mut fc_instance,
+                        builder,
+                        &e,
+                    )
+                    .await;
+                    return Err(e);
+                }
+            };
+            timings.execute = start.elapsed();
+            info!("Successfully read response from instance: {}", instance.id);
+
+            match stream.into_std() {
+                Ok(std_stream) => match std_stream.shutdown(std::net::Shutdown::Both) {
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("Error shutting down vsocket: {}", e);
+                    }
+                },
+                Err(e) => {
+                    error!("Error in obtaining std stream: {}", e);
+                }
+            }
+            buf
+        }
+    };
+
+    record_metrics(db_pool, &instance, &fc_instance).await;
+    let _ = fc_instance.stop().await;
+    let _ = fc_instance.delete().await;
+    status_writer.set_status(&mut instance, "terminated").await;
+
+    // Cleanup instance
+    builder.release_address(&fc_instance);
+
+    info!("Instance {} terminated", instance.id);
+
+    Ok((instance.id, Bytes::from(buf)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::net::{addresses::Addresses, networks::Lease};
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    use super::*;
+
+    fn invoke_function(payload: Option<String>, payload_via: PayloadVia) -> InvokeFunction {
+        InvokeFunction {
+            function: "test".to_string(),
+            image: "test".to_string(),
+            image_digest: None,
+            vcpus: 1,
+            memory: 128,
+            payload,
+            emergency: false,
+            hops: 0,
+            payload_via,
+            rate_limits: None,
+            idempotency_key: None,
+            env: None,
+            args: None,
+            api_key: None,
+            compressible: None,
+            timeout_ms: None,
+            deadline: None,
+        }
+    }
+
+    #[test]
+    fn test_route_payload() {
+        // Vsock is the default
+        let data: InvokeFunction = serde_json::from_str(
+            r#"{"function":"test","image":"test","vcpus":1,"memory":128,"payload":"x","emergency":false,"hops":0}"#,
+        )
+        .unwrap();
+        assert_eq!(data.payload_via, PayloadVia::Vsock);
+        assert_eq!(route_payload(&data), (None, Some("x".to_string())));
+
+        // Small payloads go through MMDS
+        let data = invoke_function(Some("x".to_string()), PayloadVia::Mmds);
+        let (mmds, payload) = route_payload(&data);
+        assert_eq!(payload, None);
+        assert_eq!(mmds.unwrap()["spare"]["payload"], "x");
+
+        // Large payloads fall back to vsock
+        let large = "x".repeat(MMDS_MAX_PAYLOAD + 1);
+        let data = invoke_function(Some(large.clone()), PayloadVia::Mmds);
+        assert_eq!(route_payload(&data), (None, Some(large)));
+
+        // No payload, no MMDS
+        let data = invoke_function(None, PayloadVia::Mmds);
+        assert_eq!(route_payload(&data), (None, None));
+    }
+
+    #[test]
+    fn test_retryable() {
+        use std::path::PathBuf;
+
+        let cases = [
+            (InstanceError::VSockTimeout, true),
+            (InstanceError::VSock, true),
+            (InstanceError::HostUnreachable, true),
+            (
+                InstanceError::VSockCreation(VsockError::AddressInUse(PathBuf::from("v.sock"))),
+                true,
+            ),
+            (
+                InstanceError::ImageUnavailable(ImageCacheError::Download("reset".to_string())),
+                true,
+            ),
+            (
+                InstanceError::VSockCreation(VsockError::PermissionDenied(PathBuf::from("v.sock"))),
+                false,
+            ),
+            (
+                InstanceError::ImageUnavailable(ImageCacheError::DigestMismatch {
+                    expected: "a".to_string(),
+                    actual: "b".to_string(),
+                }),
+                false,
+            ),
+            (InstanceError::Database(sqlx::Error::RowNotFound), false),
+            (InstanceError::Timeout, false),
+            (InstanceError::GuestError("boom".to_string()), false),
+        ];
+        for (error, retryable) in cases {
+            assert_eq!(error.is_retryable(), retryable, "{}", error);
+        }
+
+        // The backoff doubles, give or take its random half
+        for attempt in 1..=3 {
+            let base = START_BACKOFF * 2u32.pow(attempt - 1);
+            let backoff = start_backoff(attempt);
+            assert!(backoff >= base / 2 && backoff <= base, "{:?}", backoff);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_calibrate() {
+        use crate::orchestrator::{global::identity::Node, Orchestrator};
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        // Nothing can be booted, every iteration fails while creating the machine
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        );
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let cpus = orchestrator.get_resources().cpus;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(builder)))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(StatusWriter::Direct(pool.clone())))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .service(calibrate),
+        )
+        .await;
+        let post = |token: &str, body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/calibrate")
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .set_json(body)
+                .to_request()
+        };
+
+        let request = post("other", serde_json::json!({"image": "missing.img"}));
+        assert_eq!(test::call_service(&app, request).await.status(), 401);
+        let request = post(
+            "secret",
+            serde_json::json!({"image": "missing.img", "iterations": 1000}),
+        );
+        assert_eq!(test::call_service(&app, request).await.status(), 400);
+
+        let request = post(
+            "secret",
+            serde_json::json!({"image": "missing.img", "iterations": 3}),
+        );
+        let summary: CalibrationSummary = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(summary.iterations, 3);
+        assert_eq!(summary.failed, 3);
+        assert_eq!(summary.create, None);
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+
+        // Every iteration is recorded, with its error
+        let run = Calibration::list_run(&summary.run_id, &pool).await.unwrap();
+        let iterations: Vec<i64> = run.iter().map(|i| i.iteration).collect();
+        assert_eq!(iterations, vec![0, 1, 2]);
+        assert!(run
+            .iter()
+            .all(|i| i.error.is_some() && i.create_us.is_none()));
+    }
+
+    #[actix_web::test]
+    async fn test_quotas() {
+        use crate::{
+            api::pipeline::InvokeStep,
+            orchestrator::{global::identity::Node, Orchestrator},
+            utils::idempotency::IdempotencyConfig,
+        };
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        );
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let cpus = orchestrator.get_resources().cpus;
+        let quotas = Arc::new(QuotaTracker::new());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(builder)))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool.clone())))
+                .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                    IdempotencyConfig::default(),
+                ))))
+                .app_data(web::Data::new(quotas.clone()))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .app_data(web::Data::new(ClusterAuth::default()))
+                .app_data(web::Data::new(Compression::default()))
+                .service(invoke)
+                .service(invoke_batch)
+                .service(invoke_pipeline)
+                .service(set_quota)
+                .service(quota_usage),
+        )
+        .await;
+        let limits = QuotaLimits {
+            max_vcpus: 1,
+            max_memory: 1024,
+            max_instances: 1,
+        };
+
+        let request = test::TestRequest::put()
+            .uri("/quotas/tenant")
+            .set_json(limits)
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 401);
+        let request = test::TestRequest::put()
+            .uri("/quotas/tenant")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .set_json(limits)
+            .to_request();
+        let status: QuotaStatus = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(status.limits, Some(limits));
+        assert_eq!(Quota::list(&pool).await.unwrap().len(), 1);
+
+        // The instance would exceed the quota, the request is refused before
+        // taking any resource
+        let _running = quotas.reserve("tenant", 1, 128).unwrap();
+        let request = test::TestRequest::post()
+            .uri("/invoke")
+            .insert_header((API_KEY_HEADER, "tenant"))
+            .set_json(invoke_function(None, PayloadVia::Vsock))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), 429);
+        let status: QuotaStatus = test::read_body_json(response).await;
+        assert_eq!(status.usage.instances, 1);
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+
+        let request = test::TestRequest::get()
+            .uri("/quotas/tenant/usage")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let status: QuotaStatus = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(status.usage.vcpus, 1);
+        assert_eq!(status.usage.memory, 128);
+
+        // Nor a step of a pipeline, the key of the pipeline sent in a header as well
+        let pipeline = InvokePipeline {
+            pipeline: vec![InvokeStep {
+                function: "test".to_string(),
+                image: "test".to_string(),
+                image_digest: None,
+                vcpus: 1,
+                memory: 128,
+                rate_limits: None,
+                env: None,
+                args: None,
+                timeout_ms: None,
+            }],
+            payload: None,
+            emergency: false,
+            api_key: None,
+        };
+        let request = test::TestRequest::post()
+            .uri("/invoke_pipeline")
+            .insert_header((API_KEY_HEADER, "tenant"))
+            .set_json(&pipeline)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), 429);
+        let result: PipelineResult = test::read_body_json(response).await;
+        assert_eq!(result.failed_step, Some(0));
+        assert_eq!(result.steps[0].outcome, "rejected");
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+
+        // Neither can a batch, the key of the batch applying to the functions without one
+        drop(_running);
+        let mut keyed = invoke_function(None, PayloadVia::Vsock);
+        keyed.api_key = Some("tenant".to_string());
+        let request = test::TestRequest::post()
+            .uri("/invoke_batch")
+            .insert_header((API_KEY_HEADER, "tenant"))
+            .set_json(vec![keyed, invoke_function(None, PayloadVia::Vsock)])
+            .to_request();
+        let results: Vec<BatchItemResult> = test::call_and_read_body_json(&app, request).await;
+        assert_ne!(results[0].status, 429);
+        assert_eq!(results[1].status, 429);
+        assert_eq!(results[1].outcome, "rejected");
+        let status: QuotaStatus =
+            serde_json::from_str(results[1].error.as_deref().unwrap()).unwrap();
+        assert_eq!(status.usage.instances, 1);
+        assert_eq!(quotas.status("tenant").usage, Default::default());
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+    }
+
+    #[actix_web::test]
+    async fn test_compression() {
+        use crate::{
+            orchestrator::{global::identity::Node, Orchestrator},
+            utils::idempotency::IdempotencyConfig,
+        };
+        use actix_web::{middleware, test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        );
+        let builder = Arc::new(builder);
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        // The output of a function that already ran, replayed without booting anything
+        let idempotency = Arc::new(IdempotencyCache::new(IdempotencyConfig::default()));
+        let output = Bytes::from("output ".repeat(1000));
+        if let Begin::New(in_flight) = idempotency.begin("done") {
+            in_flight.finish(StatusCode::OK, &output);
+        }
+        let app = |compression: Compression| {
+            test::init_service(
+                App::new()
+                    .wrap(middleware::Condition::new(
+                        compression.enabled(),
+                        middleware::Compress::default(),
+                    ))
+                    .app_data(web::Data::new(pool.clone()))
+                    .app_data(web::Data::new(builder.clone()))
+                    .app_data(web::Data::new(orchestrator.clone()))
+                    .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                    .app_data(web::Data::new(StatusWriter::Direct(pool.clone())))
+                    .app_data(web::Data::new(idempotency.clone()))
+                    .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                    .app_data(web::Data::new(ClusterAuth::default()))
+                    .app_data(web::Data::new(compression))
+                    .service(invoke),
+            )
+        };
+        let request = |compressible| {
+            let mut data = invoke_function(None, PayloadVia::Vsock);
+            data.idempotency_key = Some("done".to_string());
+            data.compressible = compressible;
+            test::TestRequest::post()
+                .uri("/invoke")
+                .insert_header((header::ACCEPT_ENCODING, "gzip"))
+                .set_json(data)
+                .to_request()
+        };
+        let encoding = |headers: &header::HeaderMap| {
+            headers
+                .get(header::CONTENT_ENCODING)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
+
+        let auto = app(Compression::Auto).await;
+        let response = test::call_service(&auto, request(None)).await;
+        assert_eq!(encoding(response.headers()).as_deref(), Some("gzip"));
+        assert!(test::read_body(response).await.len() < output.len());
+        // The function says its output does not compress well
+        let response = test::call_service(&auto, request(Some(false))).await;
+        assert_eq!(encoding(response.headers()).as_deref(), Some("identity"));
+        assert_eq!(test::read_body(response).await, output);
+
+        let forced = app(Compression::Forced).await;
+        let response = test::call_service(&forced, request(Some(false))).await;
+        assert_eq!(encoding(response.headers()).as_deref(), Some("gzip"));
+
+        let off = app(Compression::Off).await;
+        let response = test::call_service(&off, request(None)).await;
+        assert_eq!(encoding(response.headers()), None);
+        assert_eq!(test::read_body(response).await, output);
+    }
+
+    #[actix_web::test]
+    async fn test_export() {
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let mut instance = Instance::new(
+            "test".to_string(),
+            "kernel".to_string(),
+            "a,b".to_string(),
+            1,
+            128,
+            0,
+            "192.168.0.2".to_string(),
+            8084,
+        );
+        instance.insert(&pool).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .service(export_instances)
+                .service(export_stats),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/export/instances?since=2000-01-01T00:00:00")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert!(response.status().is_success());
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/csv"
+        );
+        assert_eq!(
+            response.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+            "attachment; filename=\"instances.csv\""
+        );
+        let body = test::read_body(response).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert_eq!(body.lines().count(), 2);
+        assert!(body.contains(",\"a,b\","));
+
+        let request = test::TestRequest::get()
+            .uri("/export/instances?format=ndjson")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-ndjson"
+        );
+
+        let request = test::TestRequest::get()
+            .uri("/export/stats?start=2000-01-01T00:00:00&end=2100-01-01T00:00:00&bucket=1h")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        // Too many buckets
+        assert_eq!(response.status(), 400);
+
+        let request = test::TestRequest::get()
+            .uri("/export/stats?start=yesterday&end=2100-01-01T00:00:00")
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 400);
+
+        let request = test::TestRequest::get()
+            .uri("/export/stats?start=2000-01-01T00:00:00&end=2001-01-01T00:00:00&bucket=1h")
+            .to_request();
+        let buckets: Vec<db_export::StatsBucket> =
+            test::call_and_read_body_json(&app, request).await;
+        assert!(buckets.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_runs() {
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        for strategy in ["GeoDistance", "Probed", "SmartLatency"] {
+            NodeRun::new(
+                "10.0.0.1:8085".to_string(),
+                strategy.to_string(),
+                "firecracker".to_string(),
+                "172.16.0.0/24".to_string(),
+            )
+            .with_machines(
+                Some("Firecracker v1.10.1".to_string()),
+                Some("ab".repeat(32)),
+            )
+            .insert(&pool)
+            .await
+            .unwrap();
+        }
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .service(runs),
+        )
+        .await;
+        let get = |uri: &str, token: &str| {
+            test::TestRequest::get()
+                .uri(uri)
+                .insert_header((header::AUTHORIZATION, format!("Bearer {}", token)))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, get("/runs", "other")).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let listed: Vec<NodeRun> =
+            test::call_and_read_body_json(&app, get("/runs", "secret")).await;
+        let listed: Vec<_> = listed.iter().map(|r| (r.id, r.strategy.as_str())).collect();
+        assert_eq!(
+            listed,
+            vec![(3, "SmartLatency"), (2, "Probed"), (1, "GeoDistance")]
+        );
+        let listed: Vec<NodeRun> =
+            test::call_and_read_body_json(&app, get("/runs?limit=1", "secret")).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(
+            listed[0].firecracker.as_deref(),
+            Some("Firecracker v1.10.1")
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_stats() {
+        use crate::db::{window::parse_utc, BucketStats};
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let at = |timestamp: &str| parse_utc(timestamp).unwrap().naive_utc();
+        for (created_at, vcpus, hops, urgent, status) in [
+            ("2026-01-01T00:00:10Z", 1, 0, false, "terminated"),
+            ("2026-01-01T00:00:50Z", 2, 2, true, "terminated"),
+            ("2026-01-01T00:00:55Z", 8, 0, false, "started"),
+            ("2026-01-01T00:01:30Z", 1, 1, false, "terminated"),
+            // On the end of the window
+            ("2026-01-01T00:03:00Z", 4, 0, false, "terminated"),
+        ] {
+            let mut instance = Instance::new(
+                "test".to_string(),
+                "kernel".to_string(),
+                "image".to_string(),
+                vcpus,
+                128,
+                hops,
+                "192.168.0.2".to_string(),
+                8084,
+            )
+            .with_emergency(urgent);
+            instance.created_at = at(created_at);
+            instance.set_status(status.to_string());
+            instance.insert(&pool).await.unwrap();
+        }
+        let requests = [
+            ("2026-01-01T00:00:10Z", "2026-01-01T00:00:10.250Z", None),
+            (
+                "2026-01-01T00:01:30Z",
+                "2026-01-01T00:01:30.750Z",
+                Some("10.0.0.2:8085"),
+            ),
+        ]
+        .map(|(received_at, completed_at, offloaded_to)| {
+            let outcome = match offloaded_to {
+                Some(address) => RequestOutcome::OffloadedTo(address.to_string()),
+                None => RequestOutcome::ServedLocally,
+            };
+            let mut request = Request::new("test".to_string(), 0, outcome, at(received_at));
+            request.completed_at = at(completed_at);
+            request
+        });
+        Request::insert_batch(&requests, &pool).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .service(stats),
+        )
+        .await;
+        let get = |uri: &str| test::TestRequest::get().uri(uri).to_request();
+
+        let buckets: Vec<BucketStats> = test::call_and_read_body_json(
+            &app,
+            get("/stats?start=2026-01-01T00:00:00Z&end=2026-01-01T00:03:00Z&bucket=60"),
+        )
+        .await;
+        assert_eq!(buckets.len(), 3);
+        assert_eq!(buckets[0].start, parse_utc("2026-01-01T00:00:00Z").unwrap());
+        assert_eq!(buckets[0].end, buckets[1].start);
+        assert_eq!(buckets[2].end, parse_utc("2026-01-01T00:03:00Z").unwrap());
+        let first = &buckets[0].stats;
+        assert_eq!((first.requests, first.vcpus, first.hops_avg), (2, 3, 1.0));
+        assert_eq!((first.emergency.requests, first.normal.requests), (1, 1));
+        assert_eq!((first.received, first.offloaded), (1, 0));
+        assert_eq!(buckets[0].duration_avg, 250.0);
+        let second = &buckets[1].stats;
+        assert_eq!(
+            (second.requests, second.received, second.offloaded),
+            (1, 1, 1)
+        );
+        assert_eq!(buckets[1].duration_avg, 750.0);
+        // The end of the window is in the last bucket
+        assert_eq!(buckets[2].stats.requests, 1);
+        assert_eq!(buckets[2].stats.vcpus, 4);
+
+        // The whole window in one bucket, the same as an epoch
+        let buckets: Vec<BucketStats> = test::call_and_read_body_json(
+            &app,
+            get("/stats?start=2026-01-01%2002:00:00%2B02:00&end=2026-01-01T00:03:00Z"),
+        )
+        .await;
+        assert_eq!(buckets.len(), 1);
+        let window = Window::parse("2026-01-01T00:00:00Z", "2026-01-01T00:03:00Z").unwrap();
+        assert_eq!(buckets[0].stats, db::stats(&pool, &window).await.unwrap());
+
+        // The last minutes, empty buckets included. The instance is well within the last
+        // one, not on the end of the window
+        let mut instance = Instance::new(
+            "test".to_string(),
+            "kernel".to_string(),
+            "image".to_string(),
+            1,
+            128,
+            0,
+            "192.168.0.2".to_string(),
+            8084,
+        );
+        instance.created_at = (chrono::Utc::now() - chrono::Duration::seconds(30)).naive_utc();
+        instance.set_status("terminated".to_string());
+        instance.insert(&pool).await.unwrap();
+        let buckets: Vec<BucketStats> =
+            test::call_and_read_body_json(&app, get("/stats?last=600&bucket=1m")).await;
+        assert_eq!(buckets.len(), 10);
+        assert_eq!(buckets.iter().map(|b| b.stats.requests).sum::<i64>(), 1);
+        assert_eq!(buckets[9].stats.requests, 1);
+
+        for uri in [
+            "/stats",
+            "/stats?start=2026-01-01T00:00:00Z",
+            "/stats?last=0",
+            "/stats?last=600&start=2026-01-01T00:00:00Z",
+            "/stats?start=2026-01-01T00:03:00Z&end=2026-01-01T00:00:00Z",
+            "/stats?start=yesterday&end=2026-01-01T00:00:00Z",
+            "/stats?last=600&bucket=10d",
+            // Too many buckets
+            "/stats?last=86400&bucket=1",
+        ] {
+            let response = test::call_service(&app, get(uri)).await;
+            assert_eq!(response.status(), 400, "{uri}");
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_invoke_batch() {
+        use crate::{
+            orchestrator::{global::identity::Node, Orchestrator},
+            utils::idempotency::IdempotencyConfig,
+        };
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        );
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let cpus = orchestrator.get_resources().cpus;
+        let idempotency = Arc::new(IdempotencyCache::new(IdempotencyConfig::default()));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(builder)))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool)))
+                .app_data(web::Data::new(idempotency.clone()))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .app_data(web::Data::new(ClusterAuth::default()))
+                .service(invoke_batch),
+        )
+        .await;
+
+        let request = test::TestRequest::post()
+            .uri("/invoke_batch")
+            .set_json(Vec::<InvokeFunction>::new())
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 400);
+
+        // Functions refused by the node never take its resources
+        let mut refused = invoke_function(None, PayloadVia::Vsock);
+        refused.hops = 11;
+        let mut running = refused.clone();
+        running.idempotency_key = Some("running".to_string());
+        let mut done = refused.clone();
+        done.idempotency_key = Some("done".to_string());
+        let _running = idempotency.begin("running");
+        if let Begin::New(in_flight) = idempotency.begin("done") {
+            in_flight.finish(StatusCode::OK, &Bytes::from("result"));
+        }
+        let batch = vec![refused, running, done];
+
+        let request = test::TestRequest::post()
+            .uri("/invoke_batch")
+            .set_json(&batch)
+            .to_request();
+        let results: Vec<BatchItemResult> = test::call_and_read_body_json(&app, request).await;
+        let statuses: Vec<u16> = results.iter().map(|result| result.status).collect();
+        assert_eq!(statuses, vec![500, 409, 200]);
+        assert_eq!(results[0].outcome, "rejected");
+        assert_eq!(results[0].error.as_deref(), Some("Too many hops\n"));
+        assert_eq!(results[2].outcome, "replayed");
+        assert_eq!(results[2].output.as_deref(), Some("result"));
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+
+        // Streamed, the results come in completion order, with their position
+        let request = test::TestRequest::post()
+            .uri("/invoke_batch?stream=true")
+            .set_json(&batch)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/x-ndjson"
+        );
+        let body = test::read_body(response).await;
+        let mut indexes: Vec<usize> = std::str::from_utf8(&body)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<BatchItemResult>(line).unwrap().index)
+            .collect();
+        indexes.sort();
+        assert_eq!(indexes, vec![0, 1, 2]);
+    }
+
+    #[actix_web::test]
+    async fn test_invoke_batch_forwarded() {
+        use crate::{
+            api::invoke::FORWARDED_BY_HEADER,
+            orchestrator::{global::identity::Node, Orchestrator},
+        };
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let cpus = orchestrator.get_resources().cpus;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(FirecrackerBuilder::new(
+                    "firecracker".to_string(),
+                    "kernel".to_string(),
+                    "br0".to_string(),
+                    Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+                ))))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool.clone())))
+                .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                    Default::default(),
+                ))))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .app_data(web::Data::new(ClusterAuth { required: true }))
+                .service(invoke_batch),
+        )
+        .await;
+
+        // The functions that took some hops need the certificate of a node, the
+        // others of the batch still run
+        let mut forwarded = invoke_function(None, PayloadVia::Vsock);
+        forwarded.hops = 1;
+        let batch = vec![forwarded, invoke_function(None, PayloadVia::Vsock)];
+        let request = test::TestRequest::post()
+            .uri("/invoke_batch")
+            .set_json(&batch)
+            .to_request();
+        let results: Vec<BatchItemResult> = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(results[0].status, 403);
+        assert_eq!(results[0].outcome, "rejected");
+        assert_ne!(results[1].status, 403);
+
+        // So does a whole batch forwarded by a node
+        let request = test::TestRequest::post()
+            .uri("/invoke_batch")
+            .insert_header((FORWARDED_BY_HEADER, "10.0.0.1:8085"))
+            .set_json(&batch)
+            .to_request();
+        let results: Vec<BatchItemResult> = test::call_and_read_body_json(&app, request).await;
+        assert!(results.iter().all(|result| result.status == 403));
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+    }
+
+    #[actix_web::test]
+    async fn test_invoke_pipeline() {
+        use crate::{
+            api::pipeline::InvokeStep,
+            execution_environment::{mock::MockTaps, simulate::SimulatedExecutionEnvironment},
+            orchestrator::{global::identity::Node, Orchestrator},
+        };
+        use actix_web::{test, App};
+        use std::os::unix::fs::PermissionsExt;
+
+        let pool = db::establish_connection().await.unwrap();
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        )
+        .with_tap_factory(Arc::new(MockTaps))
+        .with_execution_environment(Arc::new(SimulatedExecutionEnvironment));
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let cpus = orchestrator.get_resources().cpus;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(builder)))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool)))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .service(invoke_pipeline),
+        )
+        .await;
+
+        // The functions run on the host, as executables reading the payload on stdin
+        let dir = std::env::temp_dir().join(format!("spare-pipeline-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let step = |function: &str, script: &str| {
+            let image = dir.join(function);
+            std::fs::write(&image, format!("#!/bin/sh\n{script}\n")).unwrap();
+            std::fs::set_permissions(&image, std::fs::Permissions::from_mode(0o755)).unwrap();
+            InvokeStep {
+                function: function.to_string(),
+                image: image.display().to_string(),
+                image_digest: None,
+                vcpus: 1,
+                memory: 128,
+                rate_limits: None,
+                env: None,
+                args: None,
+                timeout_ms: None,
+            }
+        };
+        let upper = step("upper", "tr a-z A-Z");
+        let shout = step("shout", "sed 's/$/!/'");
+        let broken = step("broken", "echo broken >&2; exit 3");
+        let pipeline = |steps: Vec<InvokeStep>| {
+            let pipeline = InvokePipeline {
+                pipeline: steps,
+                payload: Some("hello".to_string()),
+                emergency: false,
+                api_key: None,
+            };
+            test::TestRequest::post()
+                .uri("/invoke_pipeline")
+                .set_json(pipeline)
+                .to_request()
+        };
+
+        let response = test::call_service(&app, pipeline(vec![])).await;
+        assert_eq!(response.status(), 400);
+
+        // The output of each step is the payload of the next one
+        let response = test::call_service(&app, pipeline(vec![upper.clone(), shout])).await;
+        assert_eq!(response.status(), 200);
+        let result: PipelineResult = test::read_body_json(response).await;
+        assert_eq!(result.output.as_deref(), Some("HELLO!"));
+        assert_eq!(result.failed_step, None);
+        let steps: Vec<(usize, &str, &str)> = result
+            .steps
+            .iter()
+            .map(|step| (step.index, step.function.as_str(), step.outcome.as_str()))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (0, "upper", "served_locally"),
+                (1, "shout", "served_locally")
+            ]
+        );
+        // Each step gave its resources back
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+
+        // A step that fails aborts the pipeline
+        let response = test::call_service(&app, pipeline(vec![upper.clone(), broken, upper])).await;
+        assert!(!response.status().is_success());
+        let result: PipelineResult = test::read_body_json(response).await;
+        assert_eq!(result.failed_step, Some(1));
+        assert_eq!(result.steps.len(), 2);
+        assert_eq!(result.steps[0].status, 200);
+        assert!(result.error.unwrap().starts_with("Step 1 (broken) failed"));
+        assert_eq!(result.output, None);
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A builder whose only network has two addresses, both taken: the first one is returned
+    fn exhausted_builder(address_wait: Duration) -> (Arc<FirecrackerBuilder>, Lease) {
+        use crate::execution_environment::{
+            mock::MockTaps, simulate::SimulatedExecutionEnvironment,
+        };
+
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::new(10, 0, 0, 0), 30).unwrap(),
+        )
+        .with_tap_factory(Arc::new(MockTaps))
+        .with_execution_environment(Arc::new(SimulatedExecutionEnvironment))
+        .with_address_wait(address_wait);
+        let taken = builder.networks.acquire("echo").unwrap();
+        builder.networks.acquire("echo").unwrap();
+        (Arc::new(builder), taken)
+    }
+
+    /// A request of a function echoing its payload, run on the host from `dir`
+    fn echo_request(dir: &std::path::Path) -> actix_web::test::TestRequest {
+        use std::os::unix::fs::PermissionsExt;
+
+        let image = dir.join("echo");
+        std::fs::write(&image, "#!/bin/sh\ncat\n").unwrap();
+        std::fs::set_permissions(&image, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut data = invoke_function(Some("hello".to_string()), PayloadVia::Vsock);
+        data.function = "echo".to_string();
+        data.image = image.display().to_string();
+        actix_web::test::TestRequest::post()
+            .uri("/invoke")
+            .set_json(data)
+    }
+
+    #[actix_web::test]
+    async fn test_address_exhaustion() {
+        use crate::{
+            api::resources::Resources,
+            orchestrator::{global::identity::Node, Orchestrator},
+            utils::idempotency::IdempotencyConfig,
+        };
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let (builder, _) = exhausted_builder(Duration::ZERO);
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let cpus = orchestrator.get_resources().cpus;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(builder))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool)))
+                .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                    IdempotencyConfig::default(),
+                ))))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .app_data(web::Data::new(ClusterAuth::default()))
+                .app_data(web::Data::new(Compression::default()))
+                .service(invoke)
+                .service(resources),
+        )
+        .await;
+        let dir = std::env::temp_dir().join(format!("spare-addresses-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Without waiting for an address, the request is offloaded right away, to nobody here
+        let response = test::call_service(&app, echo_request(&dir).to_request()).await;
+        assert_eq!(response.status(), 500);
+        assert_eq!(
+            test::read_body(response).await,
+            Bytes::from_static(b"Insufficient resources\n")
+        );
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+
+        // The neighbors are told there is no address left for the function
+        let request = test::TestRequest::get()
+            .uri("/resources?function=echo")
+            .to_request();
+        let advertised: Resources = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(advertised.free_addresses, Some(0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_address_wait() {
+        use crate::{
+            orchestrator::{global::identity::Node, Orchestrator},
+            utils::idempotency::IdempotencyConfig,
+        };
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let (builder, taken) = exhausted_builder(Duration::from_millis(200));
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let cpus = orchestrator.get_resources().cpus;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(builder.clone()))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool)))
+                .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                    IdempotencyConfig::default(),
+                ))))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .app_data(web::Data::new(ClusterAuth::default()))
+                .app_data(web::Data::new(Compression::default()))
+                .service(invoke),
+        )
+        .await;
+        let dir = std::env::temp_dir().join(format!("spare-addresses-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // The request fails once the wait is over, without retrying
+        let start = Instant::now();
+        let response = test::call_service(&app, echo_request(&dir).to_request()).await;
+        let elapsed = start.elapsed();
+        assert_eq!(response.status(), 503);
+        assert_eq!(
+            test::read_body(response).await,
+            Bytes::from_static(
+                b"Insufficient resources, no address available in network default\n"
+            )
+        );
+        assert!(elapsed >= Duration::from_millis(200) && elapsed < Duration::from_millis(800));
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+
+        // An address given back while waiting is taken
+        let (response, _) = futures::join!(
+            test::call_service(&app, echo_request(&dir).to_request()),
+            async {
+                actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+                builder.networks.release(&taken.network, taken.address);
+            }
+        );
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            test::read_body(response).await,
+            Bytes::from_static(b"hello")
+        );
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+        // Its instance gave it back
+        assert_eq!(builder.free_addresses("echo"), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Records the announcements of the node
+    #[derive(Clone, Default)]
+    struct FakeControlPlane(Arc<std::sync::Mutex<Vec<Operation>>>);
+
+    impl crate::net::control_plane::ControlPlane for FakeControlPlane {
+        async fn register_node(
+            &self,
+            _node: orchestrator::global::identity::Node,
+        ) -> Result<(), crate::net::iggy::MessageError> {
+            Ok(())
+        }
+
+        async fn receive_message(&self) -> Result<Option<Message>, crate::net::iggy::MessageError> {
+            Ok(None)
+        }
+
+        async fn announce(&self, message: Message) -> Result<(), crate::net::iggy::MessageError> {
+            self.0.lock().unwrap().push(message.op);
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_drain() {
+        use crate::{
+            api::resources::Resources,
+            orchestrator::{drain::DrainState, global::identity::Node, Orchestrator},
+            utils::idempotency::IdempotencyConfig,
+        };
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        );
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let cpus = orchestrator.get_resources().cpus;
+        let control_plane = FakeControlPlane::default();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(builder)))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool.clone())))
+                .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                    IdempotencyConfig::default(),
+                ))))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .app_data(web::Data::new(ClusterAuth::default()))
+                .app_data(web::Data::new(Compression::default()))
+                .app_data(web::Data::new(Announcer::spawn(control_plane.clone())))
+                .service(invoke)
+                .service(resources)
+                .service(healthz)
+                .service(drain)
+                .service(undrain),
+        )
+        .await;
+        let post = |uri: &str| {
+            test::TestRequest::post()
+                .uri(uri)
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .to_request()
+        };
+        let announced = || async {
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+            control_plane
+                .0
+                .lock()
+                .unwrap()
+                .drain(..)
+                .collect::<Vec<_>>()
+        };
+
+        // An instance still running
+        let instance = orchestrator.drain().instance();
+        let request = test::TestRequest::post().uri("/drain").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 401);
+        let health: Health = test::call_and_read_body_json(&app, post("/drain")).await;
+        assert_eq!(health.state, DrainState::Draining);
+        assert_eq!(health.running_instances, 1);
+        // Draining twice is announced once
+        test::call_service(&app, post("/drain")).await;
+        assert_eq!(announced().await, vec![Operation::DRAINING]);
+
+        // Nothing is advertised, and the new requests are offloaded, to nobody here
+        let request = test::TestRequest::get().uri("/resources").to_request();
+        let advertised: Resources = test::call_and_read_body_json(&app, request).await;
+        assert_eq!((advertised.cpus, advertised.memory), (0, 0));
+        let request = test::TestRequest::post()
+            .uri("/invoke")
+            .set_json(invoke_function(None, PayloadVia::Vsock))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), 500);
+        assert_eq!(
+            test::read_body(response).await,
+            Bytes::from_static(b"Insufficient resources\n")
+        );
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+        assert_eq!(orchestrator.drain().running(), 1);
+
+        // Waiting answers once the last instance terminates
+        let (health, _) = futures::join!(
+            test::call_and_read_body_json::<_, _, Health>(&app, post("/drain?wait=true")),
+            async {
+                actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+                drop(instance);
+            }
+        );
+        assert_eq!(health.state, DrainState::Drained);
+        assert_eq!(health.running_instances, 0);
+        let request = test::TestRequest::get().uri("/healthz").to_request();
+        let health: Health = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(health.state, DrainState::Drained);
+
+        let health: Health = test::call_and_read_body_json(&app, post("/undrain")).await;
+        assert_eq!(health.state, DrainState::Serving);
+        assert_eq!(announced().await, vec![Operation::RESUMED]);
+        assert_eq!(orchestrator.usable_resources(false).cpus, cpus);
+    }
+
+    #[actix_web::test]
+    async fn test_readiness() {
+        use crate::{
+            api::pipeline::InvokeStep,
+            orchestrator::{global::identity::Node, readiness::Readiness, Orchestrator},
+            utils::idempotency::IdempotencyConfig,
+        };
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        );
+        let orchestrator = Arc::new(
+            Orchestrator::new(
+                vec![],
+                Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+            )
+            .with_readiness(Arc::new(Readiness::new())),
+        );
+        // Every cpu is taken, the admitted requests fail without starting an instance
+        let cpus = orchestrator.get_resources().cpus;
+        orchestrator
+            .check_and_acquire_resources(cpus, 0, false)
+            .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(builder)))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool.clone())))
+                .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                    IdempotencyConfig::default(),
+                ))))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .app_data(web::Data::new(ClusterAuth::default()))
+                .app_data(web::Data::new(Compression::default()))
+                .app_data(web::Data::new(
+                    Announcer::spawn(FakeControlPlane::default()),
+                ))
+                .app_data(web::Data::new(SpillConfig::new(
+                    std::env::temp_dir(),
+                    1024,
+                    1024,
+                )))
+                .service(invoke)
+                .service(invoke_binary)
+                .service(invoke_batch)
+                .service(invoke_pipeline)
+                .service(healthz)
+                .service(drain)
+                .service(undrain),
+        )
+        .await;
+        // Every endpoint running functions, with a request each
+        let pipeline = InvokePipeline {
+            pipeline: vec![InvokeStep {
+                function: "test".to_string(),
+                image: "test".to_string(),
+                image_digest: None,
+                vcpus: 1,
+                memory: 128,
+                rate_limits: None,
+                env: None,
+                args: None,
+                timeout_ms: None,
+            }],
+            payload: None,
+            emergency: false,
+            api_key: None,
+        };
+        let requests = || {
+            [
+                test::TestRequest::post()
+                    .uri("/invoke")
+                    .set_json(invoke_function(None, PayloadVia::Vsock)),
+                test::TestRequest::post()
+                    .uri("/invoke/test?image=test&vcpus=1&memory=128")
+                    .set_payload("payload"),
+                test::TestRequest::post()
+                    .uri("/invoke_batch")
+                    .set_json(vec![invoke_function(None, PayloadVia::Vsock)]),
+                test::TestRequest::post()
+                    .uri("/invoke_pipeline")
+                    .set_json(&pipeline),
+            ]
+        };
+        let call = |request: test::TestRequest| async {
+            let response = test::call_service(&app, request.to_request()).await;
+            let retry_after = response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .map(|retry_after| retry_after.to_str().unwrap().to_string());
+            (
+                response.status(),
+                retry_after,
+                test::read_body(response).await,
+            )
+        };
+        let health = || async {
+            let request = test::TestRequest::get().uri("/healthz").to_request();
+            test::call_and_read_body_json::<_, _, Health>(&app, request).await
+        };
+
+        let call_invoke = || call(requests().into_iter().next().unwrap());
+
+        // Refused until the node is ready, by every endpoint
+        for phase in [ReadinessPhase::Starting, ReadinessPhase::Registering] {
+            orchestrator.readiness().advance(phase);
+            assert_eq!(health().await.phase, phase);
+            for request in requests() {
+                let (status, retry_after, body) = call(request).await;
+                assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(retry_after.as_deref(), Some("1"));
+                assert_eq!(body, format!("The node is {phase}, retry later\n"));
+            }
+        }
+        assert_eq!(orchestrator.drain().running(), 0);
+
+        orchestrator.readiness().advance(ReadinessPhase::Ready);
+        let (status, retry_after, body) = call_invoke().await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(retry_after, None);
+        assert_eq!(body, Bytes::from_static(b"Insufficient resources\n"));
+        // Admitted, though the node has no cpu left to run them
+        for request in requests() {
+            assert_eq!(call(request).await.1, None);
+        }
+
+        // A draining node still takes the requests, to serve the emergency ones
+        let request = test::TestRequest::post()
+            .uri("/drain")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let drained: Health = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(drained.phase, ReadinessPhase::Draining);
+        assert_eq!(call_invoke().await.0, StatusCode::INTERNAL_SERVER_ERROR);
+        let request = test::TestRequest::post()
+            .uri("/undrain")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        test::call_service(&app, request).await;
+
+        let health = health().await;
+        assert_eq!(health.phase, ReadinessPhase::Ready);
+        let phases: Vec<ReadinessPhase> = health
+            .phase_changes
+            .iter()
+            .map(|change| change.phase)
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                ReadinessPhase::Starting,
+                ReadinessPhase::Registering,
+                ReadinessPhase::Ready,
+                ReadinessPhase::Draining,
+                ReadinessPhase::Ready,
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_startup() {
+        use crate::orchestrator::readiness::Readiness;
+        use actix_web::{test, App};
+
+        let readiness = Arc::new(Readiness::new());
+        let app = test::init_service(
+            App::new().configure(|cfg| configure_startup(cfg, readiness.clone())),
+        )
+        .await;
+        let health = || async {
+            let request = test::TestRequest::get().uri("/healthz").to_request();
+            test::call_and_read_body_json::<_, _, Health>(&app, request).await
+        };
+
+        for phase in [ReadinessPhase::Starting, ReadinessPhase::Registering] {
+            readiness.advance(phase);
+            let health = health().await;
+            assert_eq!(health.phase, phase);
+            assert_eq!(health.state, DrainState::Serving);
+            assert_eq!(health.phase_changes.last().unwrap().phase, phase);
+            // Any other endpoint is refused, it is served once the node is ready
+            for request in [
+                test::TestRequest::post()
+                    .uri("/invoke")
+                    .set_json(invoke_function(None, PayloadVia::Vsock)),
+                test::TestRequest::get().uri("/resources"),
+            ] {
+                let response = test::call_service(&app, request.to_request()).await;
+                assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+                assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "1");
+                assert_eq!(
+                    test::read_body(response).await,
+                    format!("The node is {phase}, retry later\n")
+                );
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_failed_admissions() {
+        use crate::orchestrator::{global::identity::Node, Orchestrator, ResourcesSnapshot};
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        );
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(builder)))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool.clone())))
+                .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                    Default::default(),
+                ))))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .app_data(web::Data::new(ClusterAuth::default()))
+                .app_data(web::Data::new(Compression::default()))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .service(invoke)
+                .service(debug_resources),
+        )
+        .await;
+
+        // Every cpu is taken, the requests are not admitted and go nowhere
+        let cpus = orchestrator.get_resources().cpus;
+        orchestrator
+            .check_and_acquire_resources(cpus, 0, false)
+            .unwrap();
+        for _ in 0..5 {
+            let request = test::TestRequest::post()
+                .uri("/invoke")
+                .set_json(invoke_function(None, PayloadVia::Vsock))
+                .to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), 500);
+            // Nothing was acquired, so nothing was given back
+            assert_eq!(orchestrator.get_resources().cpus, 0);
+        }
+        orchestrator.release_resources(cpus, 0).unwrap();
+        assert_eq!(orchestrator.get_resources().cpus, num_cpus::get());
+
+        // Requests that cannot be resolved are refused before taking anything
+        let mut data = invoke_function(None, PayloadVia::Vsock);
+        data.vcpus = 0;
+        let request = test::TestRequest::post()
+            .uri("/invoke")
+            .set_json(&data)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), 400);
+        assert_eq!(
+            test::read_body(response).await,
+            Bytes::from_static(
+                b"Missing resources, given neither by the request nor by the function: vcpus\n"
+            )
+        );
+        data.vcpus = cpus as i32 + 1;
+        let request = test::TestRequest::post()
+            .uri("/invoke")
+            .set_json(&data)
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 400);
+        assert_eq!(orchestrator.get_resources().cpus, num_cpus::get());
+
+        let request = test::TestRequest::get()
+            .uri("/debug/resources")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let snapshot: ResourcesSnapshot = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(snapshot.available_cpus, snapshot.total_cpus);
+        assert_eq!(snapshot.acquired_memory, 0);
+        assert_eq!(snapshot.drift, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_body_limits() {
+        use crate::orchestrator::{global::identity::Node, Orchestrator};
+        use actix_web::{test, web::PayloadConfig, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        );
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let dir = std::env::temp_dir().join(format!("spare-spill-{}", uuid::Uuid::new_v4()));
+        let spill = SpillConfig::new(dir.clone(), 16, 1024);
+        spill.prepare().unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(PayloadConfig::new(1024))
+                .app_data(web::Data::new(spill))
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(builder)))
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool.clone())))
+                .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                    Default::default(),
+                ))))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .app_data(web::Data::new(ClusterAuth::default()))
+                .app_data(web::Data::new(Compression::default()))
+                .service(invoke)
+                .service(invoke_binary),
+        )
+        .await;
+        let binary = |body: Vec<u8>| {
+            test::TestRequest::post()
+                .uri("/invoke/test?image=test&vcpus=1&memory=128")
+                .set_payload(body)
+                .to_request()
+        };
+
+        // A request over the limit is refused before it is parsed
+        let request = test::TestRequest::post()
+            .uri("/invoke")
+            .set_json(invoke_function(Some("x".repeat(2048)), PayloadVia::Vsock))
+            .to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 413);
+        let response = test::call_service(&app, binary(vec![b'x'; 2048])).await;
+        assert_eq!(response.status(), 413);
+        assert_eq!(
+            test::read_body(response).await,
+            Bytes::from_static(b"The payload is larger than 1024 bytes\n")
+        );
+
+        // Every cpu is taken and the payload cannot be offloaded, the spilled payload is
+        // removed once the request is refused
+        let cpus = orchestrator.get_resources().cpus;
+        orchestrator
+            .check_and_acquire_resources(cpus, 0, false)
+            .unwrap();
+        let response = test::call_service(&app, binary(vec![b'x'; 512])).await;
+        assert_eq!(response.status(), 503);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+        orchestrator.release_resources(cpus, 0).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_debug_bundle() {
+        use crate::{
+            orchestrator::{global::identity::Node, Orchestrator},
+            utils::{diagnostics::tests::unpack, log_ring::LogRing},
+        };
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let mut failed = Instance::new(
+            "test".to_string(),
+            "kernel".to_string(),
+            "image".to_string(),
+            1,
+            128,
+            0,
+            "192.168.30.2".to_string(),
+            8084,
+        );
+        failed.env = Some(r#"{"PASSWORD":"hunter2"}"#.to_string());
+        failed.insert(&pool).await.unwrap();
+        failed.set_status("failed".to_string());
+        failed.update(&pool).await.unwrap();
+        Instance::set_error(
+            &pool,
+            failed.id,
+            "Timed out waiting for the guest to connect",
+        )
+        .await
+        .unwrap();
+
+        let logs = LogRing::new(10);
+        logs.push("[ERROR ohsw] something went wrong".to_string());
+        let diagnostics = Diagnostics {
+            logs,
+            config: diagnostics::redact(
+                "Args {\n    port: 8084,\n    admin_token: Some(\n        \"secret\",\n    ),\n}\n",
+                &diagnostics::SECRETS,
+            ),
+            preflight: "ok: kvm\n".to_string(),
+        };
+        let orchestrator = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(orchestrator))
+                .app_data(web::Data::new(diagnostics))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .service(debug_bundle),
+        )
+        .await;
+
+        // The bundle needs the token
+        let request = test::TestRequest::get().uri("/debug/bundle").to_request();
+        assert_eq!(test::call_service(&app, request).await.status(), 401);
+
+        let request = test::TestRequest::get()
+            .uri("/debug/bundle")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/gzip"
+        );
+        let entries = unpack(&test::read_body(response).await);
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "logs.txt",
+                "orchestrator.json",
+                "failed_instances.json",
+                "config.txt",
+                "preflight.txt"
+            ]
+        );
+        let entry = |name: &str| {
+            let (_, data) = entries.iter().find(|(n, _)| n == name).unwrap();
+            String::from_utf8(data.clone()).unwrap()
+        };
+
+        assert_eq!(entry("logs.txt"), "[ERROR ohsw] something went wrong\n");
+        let state: serde_json::Value = serde_json::from_str(&entry("orchestrator.json")).unwrap();
+        assert_eq!(state["identity"]["address"], "10.0.0.0:8085");
+        assert_eq!(state["accounting"]["drift"], 0);
+        let instances: Vec<Instance> =
+            serde_json::from_str(&entry("failed_instances.json")).unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(
+            instances[0].error.as_deref(),
+            Some("Timed out waiting for the guest to connect")
+        );
+        // Neither the secrets of the node nor those of the functions leave it
+        assert_eq!(instances[0].env.as_deref(), Some("[redacted]"));
+        assert_eq!(
+            entry("config.txt"),
+            "Args {\n    port: 8084,\n    admin_token: \"[redacted]\",\n}\n"
+        );
+        assert_eq!(entry("preflight.txt"), "ok: kvm\n");
+    }
+
+    #[actix_web::test]
+    async fn test_debug_crashes() {
+        use actix_web::{test, App};
+
+        let builder = Arc::new(FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        ));
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(builder.clone()))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .service(debug_crashes),
+        )
+        .await;
+
+        let err = vmm_crashed(&builder, "broken", 1, VmmExit::Signal(11));
+        assert_eq!(
+            err.to_string(),
+            "The firecracker process crashed: killed by signal 11"
+        );
+        vmm_crashed(&builder, "broken", 2, VmmExit::Code(1));
+
+        let request = test::TestRequest::get()
+            .uri("/debug/crashes")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let crashes: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(crashes, serde_json::json!({"broken": 2}));
+    }
+
+    #[actix_web::test]
+    async fn test_debug_workspaces() {
+        use crate::execution_environment::{firecracker::Jailer, workspaces::SweepConfig};
+        use actix_web::{test, App};
+
+        let base = std::env::temp_dir().join(format!("spare-jailer-{}", uuid::Uuid::new_v4()));
+        let builder = Arc::new(
+            FirecrackerBuilder::new(
+                "/usr/bin/firecracker".to_string(),
+                "kernel".to_string(),
+                "br0".to_string(),
+                Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+            )
+            .with_jailer(Jailer {
+                binary: std::path::PathBuf::from("/usr/bin/jailer"),
+                chroot_base: base.clone(),
+                uid_base: 10000,
+            }),
+        );
+        // Left behind by a crash, in the chroot of the jailer
+        let stale = base
+            .join("firecracker")
+            .join(format!("firecracker-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(stale.join("root")).unwrap();
+        std::fs::write(stale.join("root").join("kernel.img"), vec![0; 4096]).unwrap();
+        let report = builder.workspaces.sweep(&SweepConfig {
+            min_age: Duration::ZERO,
+            interval: Duration::ZERO,
+            dry_run: false,
+        });
+        assert_eq!(report.stale, vec![stale.clone()]);
+        assert!(!stale.exists());
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(builder.clone()))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .service(debug_workspaces),
+        )
+        .await;
+        let request = test::TestRequest::get()
+            .uri("/debug/workspaces")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let totals: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(totals["sweeps"], 1);
+        assert_eq!(totals["removed"], 1);
+        assert_eq!(totals["reclaimed_bytes"], report.reclaimed_bytes);
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_debug_packing() {
+        use crate::orchestrator::{global::identity::Node, Orchestrator};
+        use actix_web::{test, App};
+
+        for (hold, status) in [(0, StatusCode::NOT_FOUND), (20, StatusCode::OK)] {
+            let orchestrator = Orchestrator::new(
+                vec![],
+                Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+            )
+            .with_packing_hold(Duration::from_millis(hold));
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(Arc::new(orchestrator)))
+                    .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                    .service(debug_packing),
+            )
+            .await;
+            let request = test::TestRequest::get()
+                .uri("/debug/packing")
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), status);
+            if status == StatusCode::OK {
+                let packing: serde_json::Value = test::read_body_json(response).await;
+                assert_eq!(packing["hold_ms"], 20);
+                assert_eq!(packing["deferred"], 0);
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_nodes_ranking() {
+        use crate::orchestrator::{global::identity::Node, Orchestrator};
+        use actix_web::{test, App};
+
+        let orchestrator = Orchestrator::new(
+            vec![
+                Node::new("10.0.0.1:8085".to_string(), (48.8575, 2.3514)),
+                Node::new("10.0.0.2:8085".to_string(), (45.4642, 9.1900)),
+                Node::new("10.0.0.3:8085".to_string(), (45.0703, 7.6869)),
+            ],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        );
+        orchestrator.set_neighbor_draining("10.0.0.3:8085", true);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(Arc::new(orchestrator)))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .service(nodes_ranking),
+        )
+        .await;
+        let request = test::TestRequest::get().uri("/nodes/ranking").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = test::TestRequest::get()
+            .uri("/nodes/ranking")
+            .insert_header((header::AUTHORIZATION, "Bearer secret"))
+            .to_request();
+        let ranking: serde_json::Value =
+            test::read_body_json(test::call_service(&app, request).await).await;
+        assert_eq!(ranking["strategy"], "GeoDistance");
+        let nodes: Vec<_> = ranking["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| (node["address"].as_str().unwrap(), node["excluded"].clone()))
+            .collect();
+        assert_eq!(
+            nodes,
+            vec![
+                ("10.0.0.2:8085", serde_json::Value::Null),
+                ("10.0.0.1:8085", serde_json::Value::Null),
+                ("10.0.0.3:8085", serde_json::json!("draining")),
+            ]
+        );
+        assert_eq!(ranking["nodes"][0]["metric"], "distance");
+        assert!(ranking["nodes"][0]["value"].as_f64().unwrap() < 1000.0);
+    }
+
+    #[actix_web::test]
+    async fn test_debug_orchestrator() {
+        use crate::orchestrator::{global::identity::Node, Orchestrator};
+        use actix_web::{test, App};
+
+        let saved_at = chrono::Utc::now();
+        let orchestrator = Arc::new(
+            Orchestrator::new(
+                vec![Node::new("10.0.0.1:8085".to_string(), (48.8575, 2.3514))],
+                Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+            )
+            .with_cached_topology(Some(saved_at)),
+        );
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(orchestrator.clone()))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .service(debug_orchestrator),
+        )
+        .await;
+        let request = || {
+            test::TestRequest::get()
+                .uri("/debug/orchestrator")
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .to_request()
+        };
+        // Started from the last known list of nodes
+        let cached: serde_json::Value =
+            test::read_body_json(test::call_service(&app, request()).await).await;
+        assert_eq!(cached["neighbors"], 1);
+        assert_eq!(cached["stale"], true);
+        assert_eq!(
+            cached["topology"],
+            serde_json::json!({"source": "cache", "saved_at": saved_at})
+        );
+
+        // Until the control plane answers
+        orchestrator.reconcile(vec![Node::new(
+            "10.0.0.2:8085".to_string(),
+            (45.4642, 9.1900),
+        )]);
+        let joined: serde_json::Value =
+            test::read_body_json(test::call_service(&app, request()).await).await;
+        assert_eq!(joined["stale"], false);
+        assert_eq!(joined["topology"]["source"], "control_plane");
+        assert_eq!(joined["identity"]["address"], "10.0.0.0:8085");
+    }
+
+    #[actix_web::test]
+    async fn test_chaos() {
+        use crate::{
+            chaos::Injection,
+            orchestrator::{global::identity::Node, Orchestrator},
+        };
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let app = |chaos| {
+            let orchestrator = Orchestrator::new(
+                vec![],
+                Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+            )
+            .with_chaos(chaos);
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(pool.clone()))
+                    .app_data(web::Data::new(Arc::new(FirecrackerBuilder::new(
+                        "firecracker".to_string(),
+                        "kernel".to_string(),
+                        "br0".to_string(),
+                        Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+                    ))))
+                    .app_data(web::Data::new(Arc::new(orchestrator)))
+                    .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                    .app_data(web::Data::new(StatusWriter::Direct(pool.clone())))
+                    .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                        Default::default(),
+                    ))))
+                    .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                    .app_data(web::Data::new(ClusterAuth::default()))
+                    .app_data(web::Data::new(Compression::default()))
+                    .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                    .service(invoke)
+                    .service(invoke_batch)
+                    .service(list_chaos)
+                    .service(inject_chaos)
+                    .service(clear_chaos)
+                    .service(remove_chaos),
+            )
+        };
+        let admin = |request: test::TestRequest| {
+            request
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .to_request()
+        };
+        let inject = |fault: serde_json::Value| {
+            admin(test::TestRequest::post().uri("/chaos").set_json(fault))
+        };
+        let request = || {
+            test::TestRequest::post()
+                .uri("/invoke")
+                .set_json(invoke_function(None, PayloadVia::Vsock))
+                .to_request()
+        };
+
+        // Nothing is injected unless enabled
+        let disabled = app(false).await;
+        let response = test::call_service(
+            &disabled,
+            inject(serde_json::json!({
+                "kind": "drop_invokes",
+                "percent": 100.0
+            })),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response =
+            test::call_service(&disabled, admin(test::TestRequest::get().uri("/chaos"))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let app = app(true).await;
+        let response = test::call_service(
+            &app,
+            inject(serde_json::json!({
+                "kind": "drop_invokes",
+                "percent": 150.0
+            })),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response =
+            test::call_service(&app, test::TestRequest::get().uri("/chaos").to_request()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        // Every invoke is dropped
+        let response = test::call_service(
+            &app,
+            inject(serde_json::json!({
+                "kind": "drop_invokes",
+                "percent": 100.0
+            })),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let dropped: Injection = test::read_body_json(response).await;
+        let response = test::call_service(&app, request()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            test::read_body(response).await,
+            "Dropped by a chaos injection\n"
+        );
+        let injections: Vec<Injection> =
+            test::call_and_read_body_json(&app, admin(test::TestRequest::get().uri("/chaos")))
+                .await;
+        assert_eq!(injections.len(), 1);
+        assert_eq!((injections[0].id, injections[0].hits), (dropped.id, 1));
+        assert_eq!(injections[0].source, Source::Admin);
+        // So is every function of a batch, before it takes resources
+        let batch = test::TestRequest::post()
+            .uri("/invoke_batch")
+            .set_json(vec![invoke_function(None, PayloadVia::Vsock)])
+            .to_request();
+        let results: Vec<BatchItemResult> = test::call_and_read_body_json(&app, batch).await;
+        assert_eq!(results[0].status, 503);
+        assert_eq!(
+            results[0].error.as_deref(),
+            Some("Dropped by a chaos injection\n")
+        );
 
-/// Get resources available in the system
-#[get("/resources")]
-async fn resources(orchestrator: web::Data<Arc<orchestrator::Orchestrator>>) -> impl Responder {
-    let resources = orchestrator.get_resources();
-    HttpResponse::Ok().json(resources)
-}
+        let uri = format!("/chaos/{}", dropped.id);
+        let response = test::call_service(&app, admin(test::TestRequest::delete().uri(&uri))).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test::call_service(&app, admin(test::TestRequest::delete().uri(&uri))).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
 
-/// Get if the node is in emergency mode
-#[get("/emergency")]
-async fn emergency(orchestrator: web::Data<Arc<orchestrator::Orchestrator>>) -> impl Responder {
-    let in_emergency = orchestrator.in_emergency_area();
-    HttpResponse::Ok().json(in_emergency)
-}
+        // Every start of an instance fails, each retry included
+        let response = test::call_service(
+            &app,
+            inject(serde_json::json!({
+                "kind": "fail_instances",
+                "probability": 1.0
+            })),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let response = test::call_service(&app, request()).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            test::read_body(response).await,
+            "Failed to start instance, attempt 4 of 4\n"
+        );
+        let injections: Vec<Injection> =
+            test::call_and_read_body_json(&app, admin(test::TestRequest::get().uri("/chaos")))
+                .await;
+        assert_eq!(injections[0].hits, 4);
 
-/*
-Example API: curl --header "Content-Type: application/json" \
-     --request POST \
-     --data '{"function":"mandelbrot","image":"/home/ubuntu/.ops/images/nanosvm","vcpus":8,"memory":256, "payload": "test"}' \
-     http://localhost:8085/invoke
+        let cleared: serde_json::Value =
+            test::call_and_read_body_json(&app, admin(test::TestRequest::delete().uri("/chaos")))
+                .await;
+        assert_eq!(cleared["cleared"], 1);
 
-*/
-/// Invoke function endpoint
-/// This endpoint is used to invoke a registered function in the system
-#[post("/invoke")]
-async fn invoke(
-    data: web::Json<InvokeFunction>,
-    db_pool: web::Data<Pool<sqlite::Sqlite>>,
-    firecracker_builder: web::Data<Arc<FirecrackerBuilder>>,
-    orchestrator: web::Data<Arc<orchestrator::Orchestrator>>,
-    req: HttpRequest,
-) -> impl Responder {
-    // Only for debug
-    if data.hops > 0 {
-        warn!("Request with number of hops: {:?}", data.hops);
+        // The requests are recorded as touched by an injection, the batch included
+        let mut requests = Vec::new();
+        for _ in 0..100 {
+            requests = Request::list(&pool).await.unwrap();
+            if requests.len() == 3 {
+                break;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        let recorded: Vec<_> = requests
+            .iter()
+            .map(|request| (request.outcome.as_str(), request.chaos))
+            .collect();
+        assert_eq!(
+            recorded,
+            vec![("rejected", true), ("rejected", true), ("failed", true)]
+        );
     }
-    if data.hops > 10 {
-        // TODO: Find a better way
-        return HttpResponse::InternalServerError().body("Too many hops\n");
+
+    #[actix_web::test]
+    async fn test_offload_chaos() {
+        use crate::orchestrator::{global::identity::Node, Orchestrator};
+
+        let (close, far) = (echo_neighbor(0), echo_neighbor(0));
+        let orchestrator = Orchestrator::new(
+            vec![
+                Node::new(close.clone(), (45.4642, 9.1900)),
+                Node::new(far.clone(), (48.8575, 2.3514)),
+            ],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        )
+        .with_sticky_offload(false)
+        .with_chaos(true);
+        let chaos = orchestrator.chaos().unwrap();
+        let data = || web::Json(invoke_function(None, PayloadVia::Vsock));
+
+        let (response, outcome) = orchestrator.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(close.clone()));
+        assert!(!chaos::marked(&response));
+
+        // The closest neighbor looks unreachable, the request goes to the other one
+        let blackhole = |peer: &String| Fault::Blackhole { peer: peer.clone() };
+        chaos.inject(blackhole(&close), Source::Admin).unwrap();
+        let (response, outcome) = orchestrator.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(far.clone()));
+        assert!(chaos::marked(&response));
+
+        // The forward is delayed
+        chaos
+            .inject(Fault::OffloadLatency { ms: 100 }, Source::Admin)
+            .unwrap();
+        let started = Instant::now();
+        let (response, outcome) = orchestrator.offload(data(), None, None).await;
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(far.clone()));
+        assert!(chaos::marked(&response));
+
+        // Nobody is left to take the request
+        chaos.inject(blackhole(&far), Source::Admin).unwrap();
+        let (response, outcome) = orchestrator.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::Rejected);
+        assert!(chaos::marked(&response));
     }
 
-    // Emergency Management
-    // If in emergency mode, but the request is not in emergency, offload the request
-    if orchestrator.in_emergency_area() && !data.emergency {
-        let body = orchestrator.offload(data, req).await;
-        return body;
+    /// A neighbor with free resources and the given requests waiting for them, answering
+    /// with the hops header and the body of the requests it receives
+    fn echo_neighbor(queued_requests: usize) -> String {
+        neighbor(64, queued_requests)
     }
 
-    // Otherwise, handle the request
-    // Check and acquire resources
-    let _resources = orchestrator.check_and_acquire_resources(
-        data.vcpus.try_into().unwrap(),
-        (data.memory * 1024).try_into().unwrap(),
-    );
+    /// A neighbor as `echo_neighbor`, offering the given cpus
+    fn neighbor(cpus: usize, queued_requests: usize) -> String {
+        use actix_web::{App, HttpServer};
 
-    // If no resources are available, offload the request
-    if _resources.is_err() {
-        let _ = orchestrator.release_resources(data.vcpus.try_into().unwrap());
-        let body = orchestrator.offload(data, req).await;
-        return body;
+        let server = HttpServer::new(move || {
+            App::new()
+                .route(
+                    "/resources",
+                    web::get().to(move || async move {
+                        HttpResponse::Ok().json(serde_json::json!({
+                            "cpus": cpus,
+                            "memory": 1 << 30,
+                            "queued_requests": queued_requests,
+                        }))
+                    }),
+                )
+                .route(
+                    "/invoke",
+                    web::post().to(|req: HttpRequest, body: web::Bytes| async move {
+                        let hops = req.headers().get(HOPS_HEADER).unwrap().to_str().unwrap();
+                        HttpResponse::Ok().body(format!(
+                            "{}\n{}",
+                            hops,
+                            String::from_utf8_lossy(&body)
+                        ))
+                    }),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0].to_string();
+        actix_web::rt::spawn(server.run());
+        address
     }
 
-    // If resources are available, start the instance
-    // Start instance
-    let max_retries = 3;
-    let mut retries = 0;
-    loop {
-        if retries > max_retries {
-            // If an error occurs, release resources and return error
-            let _ = orchestrator.release_resources(data.vcpus.try_into().unwrap());
-            return HttpResponse::InternalServerError().body("Failed to start instance\n");
-        }
-        match start_instance(&firecracker_builder, &db_pool, &data).await {
-            Ok(body) => {
-                // Release resources
-                let _ = orchestrator.release_resources(data.vcpus.try_into().unwrap());
-                return HttpResponse::Ok().body(body);
+    #[actix_web::test]
+    async fn test_offload_body() {
+        use crate::orchestrator::{global::identity::Node, Orchestrator};
+        use actix_web::{test, App};
+
+        let pool = db::establish_connection().await.unwrap();
+        let neighbor = echo_neighbor(0);
+        let app = |raw_offload| {
+            let orchestrator = Orchestrator::new(
+                vec![Node::new(neighbor.clone(), (45.4685, 9.1824))],
+                Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+            )
+            .with_raw_offload(raw_offload);
+            // A draining node offloads every request
+            orchestrator.drain().start();
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(pool.clone()))
+                    .app_data(web::Data::new(Arc::new(FirecrackerBuilder::new(
+                        "firecracker".to_string(),
+                        "kernel".to_string(),
+                        "br0".to_string(),
+                        Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+                    ))))
+                    .app_data(web::Data::new(Arc::new(orchestrator)))
+                    .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                    .app_data(web::Data::new(StatusWriter::Direct(pool.clone())))
+                    .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                        Default::default(),
+                    ))))
+                    .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                    .app_data(web::Data::new(ClusterAuth::default()))
+                    .app_data(web::Data::new(Compression::default()))
+                    .service(invoke),
+            )
+        };
+        let body = r#"{ "function": "test", "image": "test", "vcpus": 1, "memory": 128,
+            "payload": "x", "emergency": false, "hops": 0 }"#;
+        let request = |hops: Option<&str>, api_key: Option<&str>| {
+            let mut request = test::TestRequest::post()
+                .uri("/invoke")
+                .insert_header((header::CONTENT_TYPE, "application/json"))
+                .set_payload(body);
+            if let Some(hops) = hops {
+                request = request.insert_header((HOPS_HEADER, hops));
             }
-            Err(e) => {
-                error!("Error in starting execution environment: {:?}", e);
+            if let Some(api_key) = api_key {
+                request = request.insert_header((API_KEY_HEADER, api_key));
             }
+            request.to_request()
         };
-        retries += 1;
-    }
-}
-
-async fn emergency_cleanup(
-    db_pool: &Pool<sqlite::Sqlite>,
-    instance: &mut Instance,
-    fc_instance: &mut FirecrackerInstance,
-    builder: &web::Data<Arc<FirecrackerBuilder>>,
-) {
-    instance.set_status("failed".to_string());
-    let _ = instance.update(&db_pool).await;
-    let _ = fc_instance.delete().await;
-    builder
-        .network
-        .lock()
-        .unwrap()
-        .release(fc_instance.get_address());
-}
 
-/// Method to start a new instance on the node
-async fn start_instance(
-    firecracker_builder: &web::Data<Arc<FirecrackerBuilder>>,
-    db_pool: &Pool<sqlite::Sqlite>,
-    data: &web::Json<InvokeFunction>,
-) -> Result<Bytes, InstanceError> {
-    /*
-    TODO: START INSTANCE
-        1) Create new vm instance (todo: check if it already exists and mantain warm pool)
-        2) Start instance
-        3) Update instance status
-        4) Forward request to instance
-        5) Wait for response
-        6) Return response
-        7) Delete instance
-    */
-    let builder = firecracker_builder;
+        // The body is forwarded as it was received, the hops header counts the hop
+        let raw = app(true).await;
+        let output = test::call_and_read_body(&raw, request(None, None)).await;
+        assert_eq!(output, format!("1\n{}", body));
+        // The hops header wins over the body
+        let output = test::call_and_read_body(&raw, request(Some("3"), None)).await;
+        assert_eq!(output, format!("4\n{}", body));
+        let response = test::call_service(&raw, request(Some("11"), None)).await;
+        assert_eq!(response.status(), 500);
+        assert_eq!(test::read_body(response).await, "Too many hops\n");
 
-    let start = Instant::now();
-    // Create new instance
-    let fc_instance = builder
-        .new_instance(data.image.clone(), data.vcpus, data.memory)
-        .await;
+        // A key read from a header must be written in the body
+        let output = test::call_and_read_body(&raw, request(None, Some("tenant"))).await;
+        let (hops, forwarded) = std::str::from_utf8(&output)
+            .unwrap()
+            .split_once('\n')
+            .unwrap();
+        let forwarded: InvokeFunction = serde_json::from_str(forwarded).unwrap();
+        assert_eq!((hops, forwarded.hops), ("1", 1));
+        assert_eq!(forwarded.api_key.as_deref(), Some("tenant"));
 
-    let duration = start.elapsed();
-    error!("Time to create instance: {} ms", duration.as_millis());
+        // The older nodes read the hops in the body
+        let legacy = app(false).await;
+        let output = test::call_and_read_body(&legacy, request(Some("3"), None)).await;
+        let (hops, forwarded) = std::str::from_utf8(&output)
+            .unwrap()
+            .split_once('\n')
+            .unwrap();
+        let forwarded: InvokeFunction = serde_json::from_str(forwarded).unwrap();
+        assert_eq!((hops, forwarded.hops), ("4", 4));
 
-    match fc_instance {
-        Ok(mut fc_instance) => {
-            info!("Created new instance: {}", fc_instance.get_address());
-            // Insert instance in the database
-            let mut instance = Instance::new(
-                data.function.clone(),
-                builder.kernel.clone(),
-                data.image.clone(),
-                data.vcpus,
-                data.memory,
-                data.hops,
-                fc_instance.get_address().to_string(),
-                8084,
-            );
-            match instance.insert(&db_pool).await {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Failed to insert instance in the database: {:?}", e);
-                    emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder).await;
-                    return Err(InstanceError::Database);
-                }
-            }
+        // A body that is not a request is refused
+        let request = test::TestRequest::post()
+            .uri("/invoke")
+            .set_payload("{")
+            .to_request();
+        assert_eq!(test::call_service(&raw, request).await.status(), 400);
+    }
 
-            info!("Created new function instance: {}", instance.id);
+    #[actix_web::test]
+    async fn test_offload_busy() {
+        use crate::orchestrator::{global::identity::Node, Orchestrator};
 
-            // Make sure the vsock socket is ready
-            let mut path = fc_instance.get_vsock_path();
+        let (busy, idle) = (echo_neighbor(1), echo_neighbor(0));
+        let orchestrator = |neighbors: Vec<(&String, (f64, f64))>| {
+            let neighbors = neighbors
+                .into_iter()
+                .map(|(address, position)| Node::new(address.clone(), position))
+                .collect();
+            Orchestrator::new(
+                neighbors,
+                Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+            )
+            .with_sticky_offload(false)
+        };
+        let data = || web::Json(invoke_function(None, PayloadVia::Vsock));
 
-            path.push_str("_1234");
-            let socket = UnixListener::bind(path);
+        // The busy neighbor is the closest, the idle one is tried first
+        let orchestrator_busy_first =
+            orchestrator(vec![(&busy, (45.4642, 9.1900)), (&idle, (48.8575, 2.3514))]);
+        let (_, outcome) = orchestrator_busy_first.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(idle.clone()));
 
-            if socket.is_err() {
-                error!("Error binding vsock socket: {}", socket.err().unwrap());
-                emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder).await;
-                return Err(InstanceError::VSockCreation);
-            }
-            let socket = socket.unwrap();
-            info!(
-                "Socket created: {}, for instance {}",
-                socket.as_raw_fd(),
-                instance.id
-            );
+        // A busy neighbor still takes the request when nobody else can
+        let only_busy = orchestrator(vec![(&busy, (45.4642, 9.1900))]);
+        let (_, outcome) = only_busy.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(busy));
+    }
 
-            let start = Instant::now();
-            // Start instance
-            match fc_instance.start().await {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Error in starting the instance: {}", e);
-                    emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder).await;
-                    return Err(InstanceError::InstanceStart);
-                }
-            }
+    /// A neighbor taking `delay` to answer the requests
+    fn slow_neighbor(delay: Duration) -> String {
+        use actix_web::{App, HttpServer};
 
-            let duration = start.elapsed();
-            error!("Time to start instance: {} ms", duration.as_millis());
+        let server = HttpServer::new(move || {
+            App::new()
+                .route(
+                    "/resources",
+                    web::get().to(|| async {
+                        HttpResponse::Ok().json(serde_json::json!({
+                            "cpus": 64,
+                            "memory": 1 << 30,
+                        }))
+                    }),
+                )
+                .route(
+                    "/invoke",
+                    web::post().to(move || async move {
+                        actix_web::rt::time::sleep(delay).await;
+                        HttpResponse::Ok().body("done")
+                    }),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0].to_string();
+        actix_web::rt::spawn(server.run());
+        address
+    }
 
-            info!("Starting instance: {} ip: {}", instance.id, instance.ip);
+    #[actix_web::test]
+    async fn test_offload_neighbor_left() {
+        use crate::orchestrator::{
+            global::{identity::Node, NeighborNodeStrategy},
+            Orchestrator,
+        };
 
-            let start = Instant::now();
-            let mut stream = match timeout(Duration::from_millis(500), socket.accept()).await {
-                Ok(res) => match res {
-                    Ok((stream, _)) => stream,
-                    Err(e) => {
-                        error!("Error accepting vsocket (stream): {:?}", e);
-                        emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder).await;
-                        return Err(InstanceError::VSock);
-                    }
-                },
-                Err(e) => {
-                    // If an error occurs, delete the instance and set 'failed' status
-                    error!("Error accepting vsocket (timeout): {:?}", e);
-                    emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder).await;
-                    return Err(InstanceError::VSockTimeout);
-                }
-            };
+        let neighbor = slow_neighbor(Duration::from_millis(200));
+        let orchestrator = Arc::new(Orchestrator::with_strategy(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+            NeighborNodeStrategy::SmartLatency(4),
+        ));
+        assert!(orchestrator.add_node(Node::new(neighbor.clone(), (45.4642, 9.1900))));
+        let forward = actix_web::rt::spawn({
+            let orchestrator = orchestrator.clone();
+            async move {
+                let data = web::Json(invoke_function(None, PayloadVia::Vsock));
+                orchestrator.offload(data, None, None).await
+            }
+        });
 
-            let duration = start.elapsed();
-            error!("Time to accept vsock: {} ms", duration.as_millis());
+        // The neighbor leaves the list of the control plane while it runs the request:
+        // the answer is still sent back, without the latency of a node no longer known
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(orchestrator.reconcile(vec![]), (0, 1));
+        let (response, outcome) = forward.await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(neighbor));
+    }
 
-            info!(
-                "Socket accepted: {}, for instance {}",
-                stream.as_raw_fd(),
-                instance.id
-            );
+    #[actix_web::test]
+    async fn test_offload_trace() {
+        use crate::orchestrator::{
+            global::identity::Node,
+            trace::{trace_id, AttemptOutcome, Probe},
+            Orchestrator,
+        };
 
-            let start = Instant::now();
-            let mut buf = [0; 5];
-            // Read from the vsock socket
-            match read_exact(&mut stream, &mut buf, 500).await {
-                // 500ms Timeout for machine to be ready
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Error reading from vsocket: {}", e);
-                    emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder).await;
-                    return Err(InstanceError::VSock);
-                }
-            }
+        // Nobody listens on the address of the closest neighbor anymore
+        let unreachable = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let (full, busy) = (neighbor(0, 0), echo_neighbor(2));
+        let neighbors = vec![
+            Node::new(unreachable.clone(), (45.4642, 9.1900)),
+            Node::new(full.clone(), (45.0703, 7.6869)),
+            Node::new(busy.clone(), (48.8575, 2.3514)),
+        ];
+        let identity = Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824));
+        let data = || web::Json(invoke_function(None, PayloadVia::Vsock));
 
-            let duration = start.elapsed();
-            error!("Time to read from vsock: {} ms", duration.as_millis());
+        // Nothing is recorded unless enabled
+        let untraced =
+            Orchestrator::new(neighbors.clone(), identity.clone()).with_sticky_offload(false);
+        let (response, _) = untraced.offload(data(), None, None).await;
+        assert!(untraced.offload_traces().is_none());
+        assert_eq!(trace_id(&response), None);
 
-            let message: std::borrow::Cow<'_, str> = String::from_utf8_lossy(&buf);
+        let orchestrator = Orchestrator::new(neighbors, identity)
+            .with_sticky_offload(false)
+            .with_offload_traces(4);
+        let (response, outcome) = orchestrator.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(busy.clone()));
+        let traces = orchestrator.offload_traces().unwrap().recent(usize::MAX);
+        assert_eq!(traces.len(), 1);
+        let trace = &traces[0];
+        assert_eq!(trace_id(&response), Some(trace.id));
+        assert_eq!((trace.function.as_str(), trace.hops), ("test", 0));
+        assert_eq!(trace.strategy, "GeoDistance");
+        assert!(!trace.sticky);
 
-            info!(
-                "Received message: {}, for instance {}",
-                message, instance.id
-            );
+        // The candidates in the order they were ranked, closest first
+        let candidates: Vec<_> = trace.candidates.iter().map(|c| &c.address).collect();
+        assert_eq!(candidates, vec![&unreachable, &full, &busy]);
+        assert!(trace.candidates[0].distance < 1000.0);
+        assert!(trace.candidates[0].distance < trace.candidates[1].distance);
+        assert!(trace.candidates.iter().all(|c| c.latency.is_none()));
 
-            // Check if the instance is ready through the vsock socket
-            match message.contains("ready") {
-                true => {}
-                false => {
-                    error!("Message not ready: {}", message);
-                    error!("Instance {} failed to start", instance.id);
-                    emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder).await;
-                    return Err(InstanceError::VSock);
-                }
+        // The busy neighbor was put back once, then took the request
+        let attempts: Vec<_> = trace
+            .attempts
+            .iter()
+            .map(|a| (a.address.as_str(), &a.outcome))
+            .collect();
+        assert_eq!(attempts.len(), 4);
+        assert_eq!(
+            attempts[0],
+            (unreachable.as_str(), &AttemptOutcome::Unreachable)
+        );
+        assert_eq!(attempts[1], (full.as_str(), &AttemptOutcome::Insufficient));
+        assert_eq!(attempts[2], (busy.as_str(), &AttemptOutcome::Deferred));
+        assert_eq!(attempts[3].0, busy.as_str());
+        assert!(matches!(attempts[3].1, AttemptOutcome::Forwarded { .. }));
+        assert!(matches!(trace.attempts[0].probe, Probe::Error(_)));
+        assert_eq!(
+            trace.attempts[1].probe,
+            Probe::Resources {
+                cpus: 0,
+                memory: 1 << 30,
+                queued_requests: 0
             }
+        );
+        assert_eq!(trace.chosen.as_deref(), Some(busy.as_str()));
+    }
 
-            let start = Instant::now();
-            // Write payload in the vsock socket
-            match &data.payload {
-                Some(payload) => {
-                    info!("Sending payload to instance: {}", instance.id);
-                    // Write length of payload
-                    let len = payload.len();
-                    // Concatenate the length of the payload and the payload
-                    let mut buf = vec![0; 8 + len];
-                    buf[0..8].copy_from_slice(&len.to_be_bytes());
-                    buf[8..].copy_from_slice(payload.as_bytes());
-                    // TODO: Specify the timeout
-                    match write_all(&mut stream, &buf, 1000).await {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("Error writing to vsocket: {}", e);
-                            emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder)
-                                .await;
-                            return Err(InstanceError::VSock);
-                        }
-                    }
-                }
-                None => {}
-            }
+    #[actix_web::test]
+    async fn test_offload_capabilities() {
+        use crate::orchestrator::{
+            capabilities::FunctionRequires,
+            global::identity::Node,
+            trace::{AttemptOutcome, Probe},
+            Orchestrator,
+        };
 
-            let duration = start.elapsed();
-            error!(
-                "Time to write payload to vsock: {} ms",
-                duration.as_millis()
-            );
+        let (plain, gpu) = (echo_neighbor(0), echo_neighbor(0));
+        let identity = Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824));
+        let requires = || {
+            vec![FunctionRequires {
+                function: "test".to_string(),
+                requires: vec!["gpu".to_string(), "mem:4096".to_string()],
+            }]
+        };
+        let data = || web::Json(invoke_function(None, PayloadVia::Vsock));
 
-            let start = Instant::now();
-            // Read the length of the response
-            info!("Reading length of response from instance: {}", instance.id);
-            let mut len = [0; 8];
-            // TODO: Specify the timeout
-            match read_exact(&mut stream, &mut len, 10000).await {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Error reading from vsocket: {}", e);
-                    emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder).await;
-                    return Err(InstanceError::VSock);
-                }
+        // The closest neighbor has no gpu, it is skipped without being probed
+        let orchestrator = Orchestrator::new(
+            vec![
+                Node::new(plain.clone(), (45.4642, 9.1900))
+                    .with_capabilities(vec!["mem:8192".to_string()]),
+                Node::new(gpu.clone(), (48.8575, 2.3514))
+                    .with_capabilities(vec!["gpu".to_string(), "mem:8192".to_string()]),
+            ],
+            identity.clone(),
+        )
+        .with_sticky_offload(false)
+        .with_offload_traces(4)
+        .with_function_requires(requires());
+        let (_, outcome) = orchestrator.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(gpu.clone()));
+        let trace = &orchestrator.offload_traces().unwrap().recent(1)[0];
+        assert_eq!(trace.attempts.len(), 2);
+        assert_eq!(trace.attempts[0].address, plain);
+        assert_eq!(trace.attempts[0].probe, Probe::Skipped);
+        assert_eq!(
+            trace.attempts[0].outcome,
+            AttemptOutcome::MissingCapabilities {
+                missing: vec!["gpu".to_string()]
             }
+        );
+        let json = serde_json::to_value(&trace.attempts[0]).unwrap();
+        assert_eq!(json["outcome"]["missing_capabilities"]["missing"][0], "gpu");
 
-            let len = u64::from_be_bytes(len.as_slice().try_into().unwrap()) as usize;
-            info!("Length of response: {}, for instance {}", len, instance.id);
-            let mut buf = vec![0; len];
-            // Read the response
-            // TODO: Specify the timeout
-            match read_exact(&mut stream, &mut buf, 10000).await {
-                Ok(_) => {}
-                Err(e) => {
-                    error!("Error reading from vsocket: {}", e);
-                    emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder).await;
-                    return Err(InstanceError::VSock);
-                }
-            }
+        // Without a capable neighbor the request is refused, saying why
+        let orchestrator =
+            Orchestrator::new(vec![Node::new(plain.clone(), (45.4642, 9.1900))], identity)
+                .with_function_requires(requires());
+        let (response, outcome) = orchestrator.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::Rejected);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(
+            body,
+            "Insufficient resources, 1 neighbors lack the capabilities required by test\n"
+        );
+    }
 
-            let duration = start.elapsed();
-            error!(
-                "Time to read response from vsock: {} ms",
-                duration.as_millis()
-            );
+    /// A neighbor as `echo_neighbor`, telling it has `image` only
+    fn image_neighbor(image: &'static str) -> String {
+        use actix_web::{App, HttpServer};
 
-            info!("Successfully read response from instance: {}", instance.id);
+        let server = HttpServer::new(move || {
+            App::new()
+                .route(
+                    "/resources",
+                    web::get().to(move |query: web::Query<ResourcesQuery>| async move {
+                        HttpResponse::Ok().json(serde_json::json!({
+                            "cpus": 64,
+                            "memory": 1 << 30,
+                            "has_image": query.image.as_ref().map(|asked| asked == image),
+                        }))
+                    }),
+                )
+                .route(
+                    "/invoke",
+                    web::post().to(|| async { HttpResponse::Ok().body("done") }),
+                )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0].to_string();
+        actix_web::rt::spawn(server.run());
+        address
+    }
 
-            match stream.into_std() {
-                Ok(std_stream) => match std_stream.shutdown(std::net::Shutdown::Both) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        error!("Error shutting down vsocket: {}", e);
-                    }
-                },
-                Err(e) => {
-                    error!("Error in obtaining std stream: {}", e);
-                }
-            }
+    #[actix_web::test]
+    async fn test_offload_image() {
+        use crate::orchestrator::{
+            global::identity::Node, trace::AttemptOutcome, ImageAffinity, Orchestrator,
+        };
 
-            /*
-               The problem here: The instance at this point is ready, but in some
-               rare cases, firecracker has not initialized the network yet, so
-               request to the instance may go in timeout.
-            */
-
-            /*
-            info!("Instance is ready: {}", instance.id);
-            // Forward request to instance
-            let client = Client::default();
-            let max_retries = 3;
-            let mut retries = 0;
-            let mut res;
-            loop {
-                info!("Instance: {}, num of retries: {}", instance.id, retries);
-                if retries > max_retries {
-                    emergency_cleanup(db_pool, &mut instance, &mut fc_instance, builder).await;
-                    return Err(InstanceError::Timeout);
-                }
-                // TODO: Here we should put a timeout
-                if data.payload.is_none() {
-                    match client
-                        .get(format!("http://{}:{}", instance.ip, instance.port))
-                        .send()
-                        .await
-                    {
-                        Ok(result) => {
-                            res = result;
-                            break;
-                        }
-                        Err(e) => match e {
-                            awc::error::SendRequestError::Send(e) => {
-                                error!("Error in sending the request: {:?}", e);
-                                retries += 1;
-                                sleep(Duration::from_millis(10)).await;
-                                continue;
-                            },
-                            awc::error::SendRequestError::Connect(e) => {
-                                error!("Error in connecting to the instance: {:?}", e);
-                                retries += 1;
-                                sleep(Duration::from_millis(50)).await;
-                                continue;
-                            },
-                            awc::error::SendRequestError::Timeout => {
-                                error!("Error in connecting to the instance due timeout!");
-                                retries += 1;
-                                sleep(Duration::from_millis(10)).await;
-                                continue;
-                            }
-                            _ => {
-                                error!("Send error: {:?}", e);
-                                emergency_cleanup(
-                                    db_pool,
-                                    &mut instance,
-                                    &mut fc_instance,
-                                    builder,
-                                )
-                                .await;
-                                return Err(InstanceError::HostUnreachable);
-                            }
-                        },
-                    };
-                } else {
-                    let payload = Payload {
-                        payload: data.payload.clone().unwrap(),
-                    };
-                    match client
-                        .post(format!("http://{}:{}", instance.ip, instance.port))
-                        .send_json(&payload)
-                        .await
-                    {
-                        Ok(result) => {
-                            res = result;
-                            break;
-                        }
-                        Err(e) => {
-                            match e {
-                                awc::error::SendRequestError::Send(e) => {
-                                    error!("Error sending the request: {:?}", e);
-                                    retries += 1;
-                                    sleep(Duration::from_millis(10)).await;
-                                    continue;
-                                }
-                                _ => {
-                                    error!("Send error: {:?}", e);
-                                    emergency_cleanup(
-                                        db_pool,
-                                        &mut instance,
-                                        &mut fc_instance,
-                                        builder,
-                                    )
-                                    .await;
-                                    return Err(InstanceError::HostUnreachable);
-                                }
-                            };
-                        }
-                    };
-                }
-            }
-            */
+        let (without, with, older) = (
+            image_neighbor("other.img"),
+            image_neighbor("test"),
+            echo_neighbor(0),
+        );
+        let identity = Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824));
+        let data = || web::Json(invoke_function(None, PayloadVia::Vsock));
 
-            let _ = fc_instance.stop().await;
-            let _ = fc_instance.delete().await;
-            let _ = instance.set_status("terminated".to_string());
-            let _ = instance.update(&db_pool).await;
+        // The closest neighbor does not have the image, it is tried after the other one
+        let orchestrator = Orchestrator::new(
+            vec![
+                Node::new(without.clone(), (45.4642, 9.1900)),
+                Node::new(with.clone(), (48.8575, 2.3514)),
+            ],
+            identity.clone(),
+        )
+        .with_sticky_offload(false)
+        .with_offload_traces(4);
+        let (_, outcome) = orchestrator.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(with.clone()));
+        let trace = &orchestrator.offload_traces().unwrap().recent(1)[0];
+        assert_eq!(trace.attempts[0].address, without);
+        assert_eq!(trace.attempts[0].outcome, AttemptOutcome::Deferred);
 
-            // Cleanup instance
-            builder
-                .network
-                .lock()
-                .unwrap()
-                .release(fc_instance.get_address());
+        // Preferred only, it still takes the request when nobody else can
+        let orchestrator = Orchestrator::new(
+            vec![Node::new(without.clone(), (45.4642, 9.1900))],
+            identity.clone(),
+        );
+        let (_, outcome) = orchestrator.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(without.clone()));
 
-            info!("Instance {} terminated", instance.id);
+        // Required, the request is refused, saying why
+        let orchestrator = Orchestrator::new(
+            vec![Node::new(without.clone(), (45.4642, 9.1900))],
+            identity.clone(),
+        )
+        .with_image_affinity(ImageAffinity::Require)
+        .with_offload_traces(4);
+        let (response, outcome) = orchestrator.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::Rejected);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(
+            body,
+            "Insufficient resources, 1 neighbors do not have the image of test\n"
+        );
+        let trace = &orchestrator.offload_traces().unwrap().recent(1)[0];
+        assert_eq!(trace.attempts[0].outcome, AttemptOutcome::MissingImage);
 
-            Ok(Bytes::from(buf))
-        }
-        Err(e) => {
-            error!("Failed to create instance: {:?}", e);
-            return Err(InstanceError::InstanceCreation);
-        }
+        // A neighbor that does not tell is not held to it
+        let orchestrator =
+            Orchestrator::new(vec![Node::new(older.clone(), (45.4642, 9.1900))], identity)
+                .with_image_affinity(ImageAffinity::Require);
+        let (_, outcome) = orchestrator.offload(data(), None, None).await;
+        assert_eq!(outcome, RequestOutcome::OffloadedTo(older));
+        assert_eq!("require".parse(), Ok(ImageAffinity::Require));
+        assert!("always".parse::<ImageAffinity>().is_err());
     }
-}
 
-#[cfg(test)]
-mod test {
-    use awc::Client;
+    #[actix_web::test]
+    async fn test_served_by() {
+        use crate::{
+            api::invoke::{INSTANCE_ID_HEADER, SERVED_BY_HEADER},
+            execution_environment::{mock::MockTaps, simulate::SimulatedExecutionEnvironment},
+            orchestrator::{global::identity::Node, Orchestrator},
+        };
+        use actix_web::{test, App, HttpServer};
 
-    use crate::net::addresses::Addresses;
-    use std::fs::{self, OpenOptions};
-    use std::io::{Read, Write};
-    use std::path::Path;
-    use std::{net::Ipv4Addr, str::FromStr, time::Instant};
+        let pool = db::establish_connection().await.unwrap();
+        // A node running the instances on the host
+        let node = |pool: Pool<sqlite::Sqlite>, orchestrator: Arc<Orchestrator>| {
+            let builder = FirecrackerBuilder::new(
+                "firecracker".to_string(),
+                "kernel".to_string(),
+                "br0".to_string(),
+                Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+            )
+            .with_tap_factory(Arc::new(MockTaps))
+            .with_execution_environment(Arc::new(SimulatedExecutionEnvironment));
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(builder)))
+                .app_data(web::Data::new(orchestrator))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool)))
+                .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                    Default::default(),
+                ))))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .app_data(web::Data::new(ClusterAuth::default()))
+                .app_data(web::Data::new(Compression::default()))
+                .service(resources)
+                .service(invoke)
+        };
 
-    use super::*;
-    /*
-       Small benchmark to measure the cold start time of a firecracker instance and execution time of a demo function.
-       The test will create 1000 instances and measure the time it takes to start each instance and the time it takes to execute the function.
-       The results are saved in two csv files: cold_start.csv and execution.csv
-    */
-    #[actix_web::test]
-    async fn benchmark() {
-        let addresses = Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap();
+        // The node the requests are forwarded to, which runs them
+        let far = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.2:8085".to_string(), (48.8575, 2.3514)).with_id("far".to_string()),
+        ));
+        let far_pool = pool.clone();
+        let server = HttpServer::new(move || node(far_pool.clone(), far.clone()))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let far_address = server.addrs()[0].to_string();
+        actix_web::rt::spawn(server.run());
+
+        // The node the client calls, which forwards every request while draining
+        let near = Arc::new(Orchestrator::new(
+            vec![Node::new(far_address, (48.8575, 2.3514))],
+            Node::new("10.0.0.1:8085".to_string(), (45.4685, 9.1824)).with_id("near".to_string()),
+        ));
+        let app = test::init_service(node(pool.clone(), near.clone())).await;
+        // An image that is not executable answers with the payload
+        let image = std::env::temp_dir().join(format!("spare-served-{}.img", uuid::Uuid::new_v4()));
+        std::fs::write(&image, "image").unwrap();
+        let request = || {
+            let mut data = invoke_function(Some("x".to_string()), PayloadVia::Vsock);
+            data.image = image.display().to_string();
+            test::TestRequest::post()
+                .uri("/invoke")
+                .set_json(data)
+                .to_request()
+        };
+        let header = |response: &actix_web::dev::ServiceResponse, name| {
+            response
+                .headers()
+                .get(name)
+                .map(|value| value.to_str().unwrap().to_string())
+        };
 
-        let mut cold_start_times = Vec::new();
-        let mut execution_times = Vec::new();
+        // Run on the node called
+        let response = test::call_service(&app, request()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(header(&response, SERVED_BY_HEADER).as_deref(), Some("near"));
+        assert_eq!(header(&response, HOPS_HEADER).as_deref(), Some("0"));
+        let local = header(&response, INSTANCE_ID_HEADER).unwrap();
+
+        // Run on the neighbor, its headers are passed through unchanged
+        near.drain().start();
+        let response = test::call_service(&app, request()).await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(header(&response, SERVED_BY_HEADER).as_deref(), Some("far"));
+        assert_eq!(header(&response, HOPS_HEADER).as_deref(), Some("1"));
+        let remote = header(&response, INSTANCE_ID_HEADER).unwrap();
+        assert!(remote.parse::<i64>().is_ok());
+        assert_ne!(remote, local);
+        assert_eq!(test::read_body(response).await, "x");
+        std::fs::remove_file(&image).unwrap();
+    }
 
-        // Fetch configuration from environment variables
-        // Fetch function image path from environment variable
-        let function_image_path = if let Ok(val) = std::env::var("SPARE_FUNCTION") {
-            val
-        } else {
-            panic!("SPARE_FUNCTION environment variable not set");
+    #[actix_web::test]
+    async fn test_deadline() {
+        use crate::{
+            api::{invoke::BUDGET_HEADER, pipeline::InvokeStep},
+            execution_environment::{mock::MockTaps, simulate::SimulatedExecutionEnvironment},
+            orchestrator::{global::identity::Node, trace::AttemptOutcome, Orchestrator},
         };
-        // Check if the image exists
-        if !Path::new(&function_image_path).exists() {
-            panic!("Function image not found");
-        }
+        use actix_web::{test, App, HttpServer};
 
-        // Fetch firecracker executable path from environment variable
-        let firecracker_executable = if let Ok(val) = std::env::var("FIRECRACKER_EXECUTABLE") {
-            val
-        } else {
-            panic!("FIRECRACKER_EXECUTABLE environment variable not set");
+        // Offloaded, a request must reach a neighbor that answers in time: one never
+        // measured is assumed 50 ms away and as fast as this node, 100 ms
+        let neighbor = echo_neighbor(0);
+        let identity = Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824));
+        let orchestrator = Orchestrator::new(
+            vec![Node::new(neighbor.clone(), (45.4642, 9.1900))],
+            identity,
+        )
+        .with_offload_traces(4);
+        let data = |budget: Option<u64>| {
+            let mut data = invoke_function(None, PayloadVia::Vsock);
+            data.deadline = budget.map(|ms| Instant::now() + Duration::from_millis(ms));
+            web::Json(data)
         };
-        // Check if the executable exists
-        if !Path::new(&firecracker_executable).exists() {
-            panic!("Firecracker executable not found");
+        let (response, outcome) = orchestrator.offload(data(Some(100)), None, None).await;
+        assert_eq!(outcome, RequestOutcome::Shed);
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let trace = &orchestrator.offload_traces().unwrap().recent(1)[0];
+        assert!(matches!(
+            trace.attempts[0].outcome,
+            AttemptOutcome::DeadlineExceeded {
+                expected_ms: 150,
+                ..
+            }
+        ));
+        for budget in [None, Some(10_000)] {
+            let (_, outcome) = orchestrator.offload(data(budget), None, None).await;
+            assert_eq!(outcome, RequestOutcome::OffloadedTo(neighbor.clone()));
         }
 
-        // Fetch kernel image path from environment variable
-        let kernel_image_path = if let Ok(val) = std::env::var("NANOS_KERNEL") {
-            val
-        } else {
-            panic!("NANOS_KERNEL environment variable not set");
+        let pool = db::establish_connection().await.unwrap();
+        let node = |pool: Pool<sqlite::Sqlite>, orchestrator: Arc<Orchestrator>| {
+            let builder = FirecrackerBuilder::new(
+                "firecracker".to_string(),
+                "kernel".to_string(),
+                "br0".to_string(),
+                Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+            )
+            .with_tap_factory(Arc::new(MockTaps))
+            .with_execution_environment(Arc::new(SimulatedExecutionEnvironment));
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(Arc::new(builder)))
+                .app_data(web::Data::new(orchestrator))
+                .app_data(web::Data::new(RequestLog::spawn(pool.clone())))
+                .app_data(web::Data::new(StatusWriter::Direct(pool)))
+                .app_data(web::Data::new(Arc::new(IdempotencyCache::new(
+                    Default::default(),
+                ))))
+                .app_data(web::Data::new(Arc::new(QuotaTracker::new())))
+                .app_data(web::Data::new(ClusterAuth::default()))
+                .app_data(web::Data::new(Compression::default()))
+                .service(resources)
+                .service(invoke)
+                .service(invoke_batch)
+                .service(invoke_pipeline)
         };
-        // Check if the kernel image exists
-        if !Path::new(&kernel_image_path).exists() {
-            panic!("Kernel image not found");
-        }
 
-        // Fetch bridge name from environment variable
-        let bridge_name = if let Ok(val) = std::env::var("BRIDGE_INTERFACE") {
-            val
-        } else {
-            panic!("BRIDGE_INTERFACE environment variable not set");
+        // Calibrated at 2 s, the far node cannot boot for a request with 1 s left
+        let far = Arc::new(Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.2:8085".to_string(), (48.8575, 2.3514)),
+        ));
+        far.scheduler().calibrate(2000.0);
+        let far_pool = pool.clone();
+        let server = HttpServer::new(move || node(far_pool.clone(), far.clone()))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let far_address = server.addrs()[0].to_string();
+        actix_web::rt::spawn(server.run());
+        let near = Arc::new(Orchestrator::new(
+            vec![Node::new(far_address.clone(), (48.8575, 2.3514))],
+            Node::new("10.0.0.1:8085".to_string(), (45.4685, 9.1824)),
+        ));
+        let app = test::init_service(node(pool.clone(), near.clone())).await;
+        let request = |timeout_ms: Option<u64>| {
+            let mut data = invoke_function(Some("x".to_string()), PayloadVia::Vsock);
+            data.timeout_ms = timeout_ms;
+            test::TestRequest::post().uri("/invoke").set_json(data)
         };
 
-        // Obviously this test will fail if the paths are not correct, so change them accordingly
-        let firecracker_builder = FirecrackerBuilder::new(
-            firecracker_executable,        // Firecracker executable
-            kernel_image_path.to_string(), // Kernel image
-            bridge_name,                   // Bridge name
-            addresses,
+        // Shed on arrival, nothing is booted
+        near.scheduler().calibrate(2000.0);
+        let response = test::call_service(&app, request(Some(1000)).to_request()).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let body = test::read_body(response).await;
+        assert!(body.starts_with(b"Deadline exceeded, "), "{:?}", body);
+        // The budget left by a forwarding node wins over the timeout
+        let response = test::call_service(
+            &app,
+            request(Some(60_000))
+                .insert_header((HOPS_HEADER, "1"))
+                .insert_header((BUDGET_HEADER, "1000"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        // So are the functions of a batch and the steps of a pipeline
+        let mut data = invoke_function(Some("x".to_string()), PayloadVia::Vsock);
+        data.timeout_ms = Some(1000);
+        let batch = test::TestRequest::post()
+            .uri("/invoke_batch")
+            .set_json(vec![data])
+            .to_request();
+        let results: Vec<BatchItemResult> = test::call_and_read_body_json(&app, batch).await;
+        assert_eq!(
+            (results[0].status, results[0].outcome.as_str()),
+            (504, "shed")
         );
-        let builder = firecracker_builder;
-        let mut i = 0;
+        let pipeline = InvokePipeline {
+            pipeline: vec![InvokeStep {
+                function: "test".to_string(),
+                image: "test".to_string(),
+                image_digest: None,
+                vcpus: 1,
+                memory: 128,
+                rate_limits: None,
+                env: None,
+                args: None,
+                timeout_ms: Some(1000),
+            }],
+            payload: Some("x".to_string()),
+            emergency: false,
+            api_key: None,
+        };
+        let pipeline = test::TestRequest::post()
+            .uri("/invoke_pipeline")
+            .set_json(pipeline)
+            .to_request();
+        let response = test::call_service(&app, pipeline).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let result: PipelineResult = test::read_body_json(response).await;
+        assert_eq!(result.failed_step, Some(0));
+        assert_eq!(result.steps[0].outcome, "shed");
 
-        while i < 1000 {
-            let fc_instance = builder
-                .new_instance(function_image_path.clone(), 2, 256) // Image, vcpus, memory
-                .await;
+        // Forwarded with the budget left, the neighbor sheds it and so does this node
+        near.scheduler().calibrate(10.0);
+        near.drain().start();
+        let response = test::call_service(&app, request(Some(1000)).to_request()).await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            test::read_body(response).await,
+            "Deadline exceeded, 1 neighbors cannot answer test in time\n"
+        );
 
-            match fc_instance {
-                Ok(mut fc_instance) => {
-                    // VSOCK
-                    let mut path = fc_instance.get_vsock_path();
-                    path.push_str("_1234");
-                    let socket = std::os::unix::net::UnixListener::bind(path).unwrap();
-
-                    let start = Instant::now();
-                    fc_instance.start().await.unwrap();
-                    let (mut stream, _) = socket.accept().unwrap();
-
-                    let mut buf = [0; 5];
-                    stream.read(&mut buf).unwrap();
-                    let message = String::from_utf8_lossy(&buf);
-
-                    match message.contains("ready") {
-                        true => {
-                            // Update cold start time
-                            cold_start_times.push(start.elapsed().as_nanos());
-
-                            // Forward request to instance
-                            let client = Client::default();
-
-                            let res;
-
-                            // Invoke the function
-                            res = client
-                                .get(format!("http://{}:{}", fc_instance.get_address(), 8084))
-                                .send()
-                                .await;
-
-                            if res.is_ok() {
-                                // Update execution time
-                                execution_times.push(
-                                    start.elapsed().as_nanos() - cold_start_times.last().unwrap(),
-                                );
-                                i += 1;
-                            } else {
-                                // Remove last cold start time and retry
-                                let _ = cold_start_times.pop();
-                            }
-                        }
-                        false => {}
-                    };
-
-                    // Delete instance
-                    let _ = fc_instance.stop().await;
-                    builder
-                        .network
-                        .lock()
-                        .unwrap()
-                        .release(fc_instance.get_address());
-                    let _ = fc_instance.delete().await;
-                }
-                Err(e) => {
-                    error!("Failed to create instance: {:?}", e);
-                    i -= 1;
-                    continue;
-                }
+        // The shed requests are recorded and counted
+        let mut shed = 0;
+        for _ in 0..100 {
+            let requests = Request::list(&pool).await.unwrap();
+            shed = requests.iter().filter(|r| r.outcome == "shed").count();
+            if shed == 6 {
+                break;
             }
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
         }
+        assert_eq!(shed, 6);
+        let window = Window::parse("2000-01-01T00:00:00Z", "2100-01-01T00:00:00Z").unwrap();
+        assert_eq!(db::stats(&pool, &window).await.unwrap().shed, 6);
+    }
 
-        // Save times in csv
-        let cold_start_path = "cold_start.csv";
-        // If file already exists, clear it
-        if Path::new(cold_start_path).exists() {
-            fs::remove_file(cold_start_path).unwrap();
-        }
-        let mut cold_start = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(false)
-            .open(cold_start_path)
-            .unwrap();
+    #[actix_web::test]
+    async fn test_schedules() {
+        use actix_web::{test, App};
 
-        // Write header
-        writeln!(cold_start, "Elapsed time").unwrap();
-        // Write Data
-        for time in &cold_start_times {
-            writeln!(cold_start, "{}", *time as f64 / 1_000_000.00).unwrap();
-        }
-        // Flush data into the file
-        cold_start.flush().unwrap();
-
-        // compute average times
-        let avg = cold_start_times.iter().sum::<u128>() / cold_start_times.len() as u128;
-        // nanos to ms f64
-        let avg = avg as f64 / 1_000_000.00;
-        println!("Average cold start time: {} ms", avg);
-
-        let execution_path = "execution.csv";
-        // If file already exists, clear it
-        if Path::new(execution_path).exists() {
-            fs::remove_file(execution_path).unwrap();
-        }
-        let mut execution = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .append(false)
-            .open(execution_path)
-            .unwrap();
+        let pool = db::establish_connection().await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(AdminToken(Some("secret".to_string()))))
+                .service(create_schedule)
+                .service(list_schedules)
+                .service(pause_schedule)
+                .service(delete_schedule),
+        )
+        .await;
+        let request = |request: test::TestRequest| {
+            request
+                .insert_header((header::AUTHORIZATION, "Bearer secret"))
+                .to_request()
+        };
+        let schedule = |timing: serde_json::Value| {
+            let mut body = serde_json::to_value(invoke_function(None, PayloadVia::Vsock)).unwrap();
+            body.as_object_mut()
+                .unwrap()
+                .extend(timing.as_object().unwrap().clone());
+            test::TestRequest::post().uri("/schedules").set_json(body)
+        };
 
-        // Write header
-        writeln!(execution, "Elapsed time").unwrap();
-        // Write Data
-        for time in &execution_times {
-            writeln!(execution, "{}", *time as f64 / 1_000_000.00).unwrap();
+        let create = schedule(serde_json::json!({"interval": 300})).to_request();
+        assert_eq!(test::call_service(&app, create).await.status(), 401);
+        for timing in [
+            serde_json::json!({}),
+            serde_json::json!({"interval": -1}),
+            serde_json::json!({"cron_expr": "sometimes"}),
+            serde_json::json!({"cron_expr": "*/5 * * * *", "interval": 300}),
+        ] {
+            let response = test::call_service(&app, request(schedule(timing))).await;
+            assert_eq!(response.status(), 400);
         }
-        // Flush data into the file
-        execution.flush().unwrap();
 
-        // compute average times
-        let avg = execution_times.iter().sum::<u128>() / execution_times.len() as u128;
-        // nanos to ms f64
-        let avg = avg as f64 / 1_000_000.00;
-        println!("Average execution time: {} ms", avg);
+        let response = test::call_service(
+            &app,
+            request(schedule(serde_json::json!({"cron_expr": "*/5 * * * *"}))),
+        )
+        .await;
+        assert_eq!(response.status(), 201);
+        let created: Schedule = test::read_body_json(response).await;
+        assert_eq!(created.function, "test");
+        assert!(created.next_run > chrono::Utc::now().naive_utc());
+        assert_eq!(created.invoke().unwrap().memory, 128);
+
+        let pause = test::TestRequest::post().uri(&format!("/schedules/{}/pause", created.id));
+        let paused: Schedule = test::call_and_read_body_json(&app, request(pause)).await;
+        assert!(paused.paused);
+        let resume = test::TestRequest::post().uri(&format!("/schedules/{}/resume", created.id));
+        let resumed: Schedule = test::call_and_read_body_json(&app, request(resume)).await;
+        assert!(!resumed.paused);
+        let unknown = test::TestRequest::post().uri("/schedules/42/pause");
+        assert_eq!(
+            test::call_service(&app, request(unknown)).await.status(),
+            404
+        );
+
+        let listed = test::TestRequest::get().uri("/schedules");
+        let schedules: Vec<Schedule> = test::call_and_read_body_json(&app, request(listed)).await;
+        assert_eq!(schedules.len(), 1);
+
+        let delete = || test::TestRequest::delete().uri(&format!("/schedules/{}", created.id));
+        assert_eq!(
+            test::call_service(&app, request(delete())).await.status(),
+            204
+        );
+        assert_eq!(
+            test::call_service(&app, request(delete())).await.status(),
+            404
+        );
+        assert!(Schedule::list(&pool).await.unwrap().is_empty());
     }
 }
@@ -0,0 +1,246 @@
+//! Environment variables and arguments passed to the guest on the kernel command line.
+//! The environment is rendered as `env.KEY=value` parameters and the arguments follow
+//! a `--`, as the kernel hands them to the init process.
+use std::collections::{BTreeMap, HashMap};
+
+/// Maximum length of the kernel command line accepted by Firecracker
+pub const MAX_BOOT_ARGS_LEN: usize = 2048;
+
+/// Maximum length of the environment and arguments, the rest is left to the node
+pub const MAX_GUEST_ARGS_LEN: usize = 1024;
+
+/// Error returned when the environment or the arguments cannot be passed to the guest
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum BootArgsError {
+    /// Keys must be made of letters, digits and underscores, and not start with a digit
+    #[error("Invalid environment variable name: {0:?}")]
+    InvalidKey(String),
+    /// Values must be printable ASCII characters, without double quotes
+    #[error("Invalid character in {0:?}")]
+    InvalidValue(String),
+    /// The rendered command line is too long
+    #[error("The command line is too long: {0} characters, at most {1} are allowed")]
+    TooLong(usize, usize),
+}
+
+/// Environment variables and arguments of the guest
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GuestArgs {
+    /// Sorted, so the same invocation always boots with the same command line
+    pub env: BTreeMap<String, String>,
+    pub args: Vec<String>,
+}
+
+impl GuestArgs {
+    pub fn new(env: Option<&HashMap<String, String>>, args: Option<&[String]>) -> Self {
+        Self {
+            env: env
+                .map(|env| env.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+                .unwrap_or_default(),
+            args: args.map(|args| args.to_vec()).unwrap_or_default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.env.is_empty() && self.args.is_empty()
+    }
+
+    /// Check that the environment and the arguments can be passed to the guest
+    pub fn validate(&self) -> Result<(), BootArgsError> {
+        let rendered = self.render_guest()?;
+        if rendered.len() > MAX_GUEST_ARGS_LEN {
+            return Err(BootArgsError::TooLong(rendered.len(), MAX_GUEST_ARGS_LEN));
+        }
+        Ok(())
+    }
+
+    /// Append the environment and the arguments to the `base` command line
+    pub fn render(&self, base: &str) -> Result<String, BootArgsError> {
+        let guest = self.render_guest()?;
+        let boot_args = if guest.is_empty() {
+            base.to_string()
+        } else {
+            format!("{} {}", base, guest)
+        };
+        if boot_args.len() > MAX_BOOT_ARGS_LEN {
+            return Err(BootArgsError::TooLong(boot_args.len(), MAX_BOOT_ARGS_LEN));
+        }
+        Ok(boot_args)
+    }
+
+    /// Read the environment and the arguments back from a kernel command line, as the
+    /// init process of the guest does: the other parameters are left out
+    pub fn parse(cmdline: &str) -> Self {
+        let mut guest = Self::default();
+        let mut params = split(cmdline).into_iter();
+        for param in params.by_ref() {
+            if param == "--" {
+                break;
+            }
+            let env = param.strip_prefix("env.").and_then(|p| p.split_once('='));
+            if let Some((key, value)) = env {
+                guest.env.insert(key.to_string(), value.to_string());
+            }
+        }
+        guest.args = params.collect();
+        guest
+    }
+
+    fn render_guest(&self) -> Result<String, BootArgsError> {
+        let mut params = Vec::with_capacity(self.env.len() + self.args.len() + 1);
+        for (key, value) in &self.env {
+            if !is_valid_key(key) {
+                return Err(BootArgsError::InvalidKey(key.clone()));
+            }
+            params.push(format!("env.{}={}", key, quote(value)?));
+        }
+        if !self.args.is_empty() {
+            params.push("--".to_string());
+            for arg in &self.args {
+                params.push(quote(arg)?);
+            }
+        }
+        Ok(params.join(" "))
+    }
+}
+
+fn is_valid_key(key: &str) -> bool {
+    let mut chars = key.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Split a command line on the spaces out of double quotes, dropping the quotes
+fn split(cmdline: &str) -> Vec<String> {
+    let mut params = vec![];
+    let (mut param, mut quoted, mut started) = (String::new(), false, false);
+    for c in cmdline.chars() {
+        match c {
+            '"' => (quoted, started) = (!quoted, true),
+            ' ' if !quoted => {
+                if started {
+                    params.push(std::mem::take(&mut param));
+                }
+                started = false;
+            }
+            c => {
+                param.push(c);
+                started = true;
+            }
+        }
+    }
+    if started {
+        params.push(param);
+    }
+    params
+}
+
+/// Quote a value if it contains spaces or is empty.
+/// The kernel has no escape sequences, so double quotes and control characters
+/// cannot be passed at all.
+fn quote(value: &str) -> Result<String, BootArgsError> {
+    if value.chars().any(|c| !(' '..='~').contains(&c) || c == '"') {
+        return Err(BootArgsError::InvalidValue(value.to_string()));
+    }
+    if value.is_empty() || value.contains(' ') {
+        Ok(format!("\"{}\"", value))
+    } else {
+        Ok(value.to_string())
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "console=ttyS0 reboot=k panic=1 pci=off";
+
+    fn guest(env: &[(&str, &str)], args: &[&str]) -> GuestArgs {
+        GuestArgs {
+            env: env
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_render_nothing() {
+        assert_eq!(GuestArgs::default().render(BASE).unwrap(), BASE);
+        let empty = GuestArgs::new(Some(&HashMap::new()), Some(&[]));
+        assert!(empty.is_empty());
+        assert_eq!(empty.render(BASE).unwrap(), BASE);
+    }
+
+    #[test]
+    fn test_render() {
+        let guest = guest(
+            &[("MODE", "fast"), ("GREETING", "hello world"), ("EMPTY", "")],
+            &["--threads=4", "two words", ""],
+        );
+        assert_eq!(
+            guest.render(BASE).unwrap(),
+            format!(
+                "{} env.EMPTY=\"\" env.GREETING=\"hello world\" env.MODE=fast -- --threads=4 \"two words\" \"\"",
+                BASE
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let expected = guest(
+            &[("MODE", "fast"), ("GREETING", "hello world"), ("EMPTY", "")],
+            &["--threads=4", "two words", "", "--"],
+        );
+        assert_eq!(GuestArgs::parse(&expected.render(BASE).unwrap()), expected);
+        assert_eq!(GuestArgs::parse(BASE), GuestArgs::default());
+        // Only the environment is read before the arguments
+        let parsed = GuestArgs::parse("env.URL=http://a.b/?c=d en1.ipaddr=10.0.0.2 env.X -- a");
+        assert_eq!(parsed, guest(&[("URL", "http://a.b/?c=d")], &["a"]));
+    }
+
+    #[test]
+    fn test_tricky_values() {
+        // Equal signs, dots and other punctuation are kept as they are
+        let tricky = guest(&[("URL", "http://a.b/c?d=e&f=g"), ("_X1", "a=b=c")], &[]);
+        assert_eq!(
+            tricky.render("").unwrap(),
+            " env.URL=http://a.b/c?d=e&f=g env._X1=a=b=c"
+        );
+
+        for value in ["say \"hi\"", "line\nbreak", "tab\there", "caffè", "\0"] {
+            assert_eq!(
+                guest(&[("KEY", value)], &[]).validate(),
+                Err(BootArgsError::InvalidValue(value.to_string()))
+            );
+            assert!(guest(&[], &[value]).validate().is_err());
+        }
+        for key in ["", "1KEY", "MY-KEY", "KEY=1", "K Y", "env.KEY"] {
+            assert_eq!(
+                guest(&[(key, "value")], &[]).validate(),
+                Err(BootArgsError::InvalidKey(key.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_length_cap() {
+        let long = "x".repeat(MAX_GUEST_ARGS_LEN);
+        assert!(matches!(
+            guest(&[("KEY", &long)], &[]).validate(),
+            Err(BootArgsError::TooLong(_, MAX_GUEST_ARGS_LEN))
+        ));
+        let fits = guest(&[], &[&"x".repeat(MAX_GUEST_ARGS_LEN - 3)]);
+        assert!(fits.validate().is_ok());
+
+        // The whole command line is capped too
+        let base = "x".repeat(MAX_BOOT_ARGS_LEN - MAX_GUEST_ARGS_LEN);
+        assert!(matches!(
+            fits.render(&base),
+            Err(BootArgsError::TooLong(_, MAX_BOOT_ARGS_LEN))
+        ));
+    }
+}
@@ -0,0 +1,170 @@
+//! cgroup-v2 enforcement of the resources requested by the instances.
+//! The orchestrator only does the bookkeeping of vcpus and memory, so without this
+//! the Firecracker processes compete freely for the host resources. Each instance gets
+//! its own cgroup under a common parent, limited according to its request.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::error;
+
+/// Period used for `cpu.max` (in microseconds)
+pub const CPU_PERIOD: u64 = 100_000;
+/// Memory allowed to the VMM on top of the guest memory (in MiB)
+pub const MEMORY_OVERHEAD: u64 = 32;
+
+/// Limits applied to the cgroup of an instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CgroupLimits {
+    /// CPU time allowed in each `CPU_PERIOD` (in microseconds)
+    pub cpu_quota: u64,
+    /// Relative share of CPU when the host is contended, in [1, 10000]
+    pub cpu_weight: u64,
+    /// Maximum memory (in bytes)
+    pub memory_max: u64,
+}
+
+impl CgroupLimits {
+    /// Compute the limits of an instance with the given vcpus and memory (in MiB).
+    /// Each vcpu is worth a full host CPU and the default weight (100).
+    pub fn new(vcpus: u64, memory: u64) -> Self {
+        Self {
+            cpu_quota: vcpus * CPU_PERIOD,
+            cpu_weight: (vcpus * 100).clamp(1, 10_000),
+            memory_max: (memory + MEMORY_OVERHEAD) << 20,
+        }
+    }
+}
+
+/// Parent cgroup of the instances
+#[derive(Debug, Clone)]
+pub struct Cgroups {
+    /// Mount point of the cgroup-v2 hierarchy
+    root: PathBuf,
+    /// Path of the parent cgroup, relative to `root`
+    parent: PathBuf,
+}
+
+impl Cgroups {
+    /// Create the parent cgroup `parent` in the hierarchy mounted at `root`
+    /// (usually /sys/fs/cgroup) and enable the cpu and memory controllers for its children.
+    pub fn new(root: PathBuf, parent: PathBuf) -> Result<Self, io::Error> {
+        let cgroups = Self { root, parent };
+        fs::create_dir_all(cgroups.path())?;
+        fs::write(
+            cgroups.path().join("cgroup.subtree_control"),
+            "+cpu +memory",
+        )?;
+        Ok(cgroups)
+    }
+
+    /// Get the path of the parent cgroup
+    pub fn path(&self) -> PathBuf {
+        self.root.join(&self.parent)
+    }
+
+    /// Create the cgroup `name` with the given limits and move the process `pid` into it.
+    pub fn create(&self, name: &str, limits: CgroupLimits, pid: u32) -> Result<Cgroup, io::Error> {
+        let cgroup = Cgroup {
+            path: self.path().join(name),
+        };
+        fs::create_dir(&cgroup.path)?;
+        let setup = cgroup
+            .write("cpu.max", &format!("{} {}", limits.cpu_quota, CPU_PERIOD))
+            .and_then(|_| cgroup.write("cpu.weight", &limits.cpu_weight.to_string()))
+            .and_then(|_| cgroup.write("memory.max", &limits.memory_max.to_string()))
+            .and_then(|_| cgroup.write("cgroup.procs", &pid.to_string()));
+        match setup {
+            Ok(_) => Ok(cgroup),
+            Err(e) => {
+                cgroup.remove();
+                Err(e)
+            }
+        }
+    }
+}
+
+/// The cgroup of an instance
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+impl Cgroup {
+    /// Get the path of the cgroup
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn write(&self, file: &str, value: &str) -> Result<(), io::Error> {
+        fs::write(self.path.join(file), value)
+    }
+
+    /// Delete the cgroup, the processes in it must have exited
+    pub fn remove(&self) {
+        if let Err(e) = fs::remove_dir(&self.path) {
+            error!("Failed to remove cgroup {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        assert_eq!(
+            CgroupLimits::new(1, 128),
+            CgroupLimits {
+                cpu_quota: 100_000,
+                cpu_weight: 100,
+                memory_max: 160 << 20,
+            }
+        );
+        let limits = CgroupLimits::new(8, 1024);
+        assert_eq!(limits.cpu_quota, 800_000);
+        assert_eq!(limits.cpu_weight, 800);
+        assert_eq!(limits.memory_max, 1056 << 20);
+        // The weight is capped by the kernel
+        assert_eq!(CgroupLimits::new(256, 128).cpu_weight, 10_000);
+    }
+
+    #[test]
+    fn test_cgroup_files() {
+        // A plain directory stands in for the cgroupfs: the kernel would create
+        // the interface files, here they are just written
+        let root = std::env::temp_dir().join(format!("spare-cgroup-{}", uuid::Uuid::new_v4()));
+        let cgroups = Cgroups::new(root.clone(), PathBuf::from("spare")).unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("spare/cgroup.subtree_control")).unwrap(),
+            "+cpu +memory"
+        );
+
+        let cgroup = cgroups
+            .create("instance", CgroupLimits::new(2, 256), 4242)
+            .unwrap();
+        assert_eq!(cgroup.path(), root.join("spare/instance"));
+        let read = |file: &str| fs::read_to_string(cgroup.path().join(file)).unwrap();
+        assert_eq!(read("cpu.max"), "200000 100000");
+        assert_eq!(read("cpu.weight"), "200");
+        assert_eq!(read("memory.max"), (288u64 << 20).to_string());
+        assert_eq!(read("cgroup.procs"), "4242");
+
+        // The same instance cannot be created twice
+        assert!(cgroups
+            .create("instance", CgroupLimits::new(2, 256), 4242)
+            .is_err());
+
+        // On a real cgroupfs the interface files vanish with the directory
+        for file in ["cpu.max", "cpu.weight", "memory.max", "cgroup.procs"] {
+            fs::remove_file(cgroup.path().join(file)).unwrap();
+        }
+        cgroup.remove();
+        assert!(!cgroup.path().exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+//! Environments the machines of the instances run in.
+//! The node runs every machine with firecracker, unless it simulates them. The environment
+//! is a seam for the rest of the node: the mock one boots its machines instantly and
+//! answers through the guest protocol in process, so the whole path of a request runs
+//! without firecracker or root. The simulated one does the same with the function of the
+//! image, run on the host, for the nodes without KVM (`--runtime simulate`).
+use std::{path::PathBuf, process::ExitStatus, str::FromStr};
+
+use firepilot::{
+    builder::Configuration,
+    machine::{FirepilotError, Machine},
+};
+use futures::future::LocalBoxFuture;
+
+use super::{lifecycle::Vmm, mock::MockVmm, simulate::SimulatedVmm};
+
+/// Runtime of the instances of the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runtime {
+    /// Machines run with firecracker
+    Firecracker,
+    /// Functions run on the host, see [`super::simulate`]
+    Simulate,
+}
+
+impl Runtime {
+    /// Name of the runtime, as given to --runtime
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Runtime::Firecracker => "firecracker",
+            Runtime::Simulate => "simulate",
+        }
+    }
+}
+
+impl FromStr for Runtime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "firecracker" => Ok(Runtime::Firecracker),
+            "simulate" => Ok(Runtime::Simulate),
+            _ => Err(format!("Unknown runtime: {}", s)),
+        }
+    }
+}
+
+/// Environment creating the machines of the instances
+pub trait ExecutionEnvironment: Send + Sync {
+    /// Create the machine described by `configuration`, ready to be started
+    fn create(
+        &self,
+        configuration: Configuration,
+    ) -> LocalBoxFuture<'_, Result<AnyVmm, FirepilotError>>;
+}
+
+/// Environment running the machines with firecracker
+pub struct FirecrackerEnvironment;
+
+impl ExecutionEnvironment for FirecrackerEnvironment {
+    fn create(
+        &self,
+        configuration: Configuration,
+    ) -> LocalBoxFuture<'_, Result<AnyVmm, FirepilotError>> {
+        Box::pin(async move {
+            let mut machine = Machine::new();
+            machine.create(configuration).await?;
+            Ok(AnyVmm::Firecracker(Box::new(machine)))
+        })
+    }
+}
+
+/// Machine created by an execution environment
+#[derive(Debug)]
+pub enum AnyVmm {
+    Firecracker(Box<Machine>),
+    Mock(MockVmm),
+    Simulated(SimulatedVmm),
+}
+
+impl AnyVmm {
+    /// PID of the process of the machine, if it is running
+    pub fn pid(&self) -> Option<u32> {
+        match self {
+            AnyVmm::Firecracker(machine) => machine.pid(),
+            AnyVmm::Mock(_) => None,
+            AnyVmm::Simulated(_) => None,
+        }
+    }
+
+    /// Path of the vsock, as seen from the host
+    pub fn get_vsock_path(&self) -> String {
+        match self {
+            AnyVmm::Firecracker(machine) => machine.get_vsock_path(),
+            AnyVmm::Mock(machine) => machine.get_vsock_path(),
+            AnyVmm::Simulated(machine) => machine.get_vsock_path(),
+        }
+    }
+
+    /// Path of the metrics of the machine, as seen from the host
+    pub fn get_metrics_path(&self) -> PathBuf {
+        match self {
+            AnyVmm::Firecracker(machine) => machine.get_metrics_path(),
+            AnyVmm::Mock(machine) => machine.get_metrics_path(),
+            AnyVmm::Simulated(machine) => machine.get_metrics_path(),
+        }
+    }
+
+    /// Ask the machine to write its metrics
+    pub async fn flush_metrics(&self) -> Result<(), FirepilotError> {
+        match self {
+            AnyVmm::Firecracker(machine) => machine.flush_metrics().await,
+            AnyVmm::Mock(_) => Ok(()),
+            AnyVmm::Simulated(_) => Ok(()),
+        }
+    }
+}
+
+impl Vmm for AnyVmm {
+    async fn start(&self) -> Result<(), FirepilotError> {
+        match self {
+            AnyVmm::Firecracker(machine) => Vmm::start(machine.as_ref()).await,
+            AnyVmm::Mock(machine) => machine.start().await,
+            AnyVmm::Simulated(machine) => machine.start().await,
+        }
+    }
+    async fn stop(&self) -> Result<(), FirepilotError> {
+        match self {
+            AnyVmm::Firecracker(machine) => Vmm::stop(machine.as_ref()).await,
+            AnyVmm::Mock(machine) => machine.stop().await,
+            AnyVmm::Simulated(machine) => machine.stop().await,
+        }
+    }
+    async fn pause(&self) -> Result<(), FirepilotError> {
+        match self {
+            AnyVmm::Firecracker(machine) => Vmm::pause(machine.as_ref()).await,
+            AnyVmm::Mock(machine) => machine.pause().await,
+            AnyVmm::Simulated(machine) => machine.pause().await,
+        }
+    }
+    async fn resume(&self) -> Result<(), FirepilotError> {
+        match self {
+            AnyVmm::Firecracker(machine) => Vmm::resume(machine.as_ref()).await,
+            AnyVmm::Mock(machine) => machine.resume().await,
+            AnyVmm::Simulated(machine) => machine.resume().await,
+        }
+    }
+    async fn kill(&mut self) -> Result<(), FirepilotError> {
+        match self {
+            AnyVmm::Firecracker(machine) => Vmm::kill(machine.as_mut()).await,
+            AnyVmm::Mock(machine) => machine.kill().await,
+            AnyVmm::Simulated(machine) => machine.kill().await,
+        }
+    }
+    async fn is_running(&self) -> bool {
+        match self {
+            AnyVmm::Firecracker(machine) => Vmm::is_running(machine.as_ref()).await,
+            AnyVmm::Mock(machine) => machine.is_running().await,
+            AnyVmm::Simulated(machine) => machine.is_running().await,
+        }
+    }
+    async fn wait_exit(&mut self) -> Option<ExitStatus> {
+        match self {
+            AnyVmm::Firecracker(machine) => Vmm::wait_exit(machine.as_mut()).await,
+            AnyVmm::Mock(machine) => machine.wait_exit().await,
+            AnyVmm::Simulated(machine) => machine.wait_exit().await,
+        }
+    }
+}
@@ -1,118 +1,558 @@
-use std::{net::Ipv4Addr, sync::Mutex};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    net::Ipv4Addr,
+    path::PathBuf,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use crate::net::{
-    addresses::Addresses,
-    linux::{
-        bridge::{self, interface_id},
-        tap::Tap,
+use super::{
+    boot_args::GuestArgs,
+    cgroup::{Cgroup, CgroupLimits, Cgroups},
+    environment::{AnyVmm, ExecutionEnvironment, FirecrackerEnvironment},
+    guest::{FunctionGuest, GuestConfig},
+    image_cache::ImageCache,
+    inventory::ImageInventory,
+    lifecycle::{InstanceState, Lifecycle, LifecycleError, VmmExit},
+    metrics::MetricsSummary,
+    overlay::{self, ImageMode, Overlay},
+    registry::{Control, InstanceRegistry, Registration},
+    workspaces::{WorkspaceLease, Workspaces},
+};
+use crate::{
+    api::rate_limits::RateLimits,
+    net::{
+        addresses::Addresses,
+        linux::tap::{is_retryable, LinuxTaps, Tap, TapFactory},
+        networks::{NetworkError, Networks, DEFAULT_NETWORK},
     },
+    utils::{blocking::BlockingPool, protocol::GuestProtocol},
 };
-use builder::{executor::FirecrackerExecutorBuilder, Builder, Configuration};
+use builder::{
+    executor::{FirecrackerExecutorBuilder, JailerExecutorBuilder},
+    Builder, BuilderError, Configuration,
+};
+use executor::Executor;
 use firepilot::{machine::FirepilotError, *};
-use firepilot_models::models::{BootSource, Drive, MachineConfiguration, NetworkInterface};
-use log::info;
-use machine::Machine;
+use firepilot_models::models::{
+    mmds_config::Version, BootSource, Drive, MachineConfiguration, MmdsConfig, NetworkInterface,
+};
+use futures::future::{select, Either};
+use log::{info, warn};
+
+/// Number of tap interfaces tried for an instance, on transient failures
+pub const TAP_ATTEMPTS: u32 = 3;
+
+/// Time waited before trying another tap interface, multiplied by the attempts so far
+const TAP_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+/// Folder of the workspaces of the instances, when they do not run through the jailer
+const CHROOT: &str = "/tmp";
+
+/// Configuration of the jailer used to confine the instances.
+#[derive(Debug, Clone)]
+pub struct Jailer {
+    /// Path to the jailer binary
+    pub binary: PathBuf,
+    /// Base folder of the chroots of the instances
+    pub chroot_base: PathBuf,
+    /// Each instance runs with uid and gid `uid_base + <host part of its address>`,
+    /// which is unique among the running instances
+    pub uid_base: u32,
+}
+
+impl Jailer {
+    /// Get the folder of the workspaces of the instances, as laid out by the jailer.
+    pub fn workspaces_dir(&self, executable: &str) -> PathBuf {
+        let name = PathBuf::from(executable)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| "firecracker".to_string());
+        self.chroot_base.join(name)
+    }
+
+    /// Get the uid (and gid) of the instance with the given address.
+    pub fn uid(&self, address: Ipv4Addr, netmask: Ipv4Addr) -> u32 {
+        self.uid_base + (u32::from(address) & !u32::from(netmask))
+    }
+}
+
+/// What an instance boots: its image and machine, and what its guest is given.
+#[derive(Debug, Clone, Default)]
+pub struct InstanceSpec {
+    /// Path to the function image
+    pub image: String,
+    pub vcpus: i32,
+    /// In MiB
+    pub memory: i32,
+    /// Data exposed to the guest through MMDS, if any
+    pub mmds: Option<serde_json::Value>,
+    /// Limits that are not set fall back to the defaults of the builder
+    pub rate_limits: RateLimits,
+    pub guest_args: GuestArgs,
+}
+
+impl InstanceSpec {
+    /// Create a new InstanceSpec, without MMDS, rate limits or guest arguments
+    pub fn new(image: String, vcpus: i32, memory: i32) -> Self {
+        Self {
+            image,
+            vcpus,
+            memory,
+            ..Default::default()
+        }
+    }
+
+    /// Enable the metadata service, filled with `mmds` before boot
+    pub fn with_mmds(self, mmds: Option<serde_json::Value>) -> Self {
+        Self { mmds, ..self }
+    }
+
+    /// Set the I/O rate limits of the network interface and the root drive
+    pub fn with_rate_limits(self, rate_limits: RateLimits) -> Self {
+        Self {
+            rate_limits,
+            ..self
+        }
+    }
+
+    /// Set the environment variables and the arguments of the guest
+    pub fn with_guest_args(self, guest_args: GuestArgs) -> Self {
+        Self { guest_args, ..self }
+    }
+}
+
+/// Where an instance is attached to the network of its function.
+pub struct InstanceNetwork {
+    /// The tap interface of the instance, attached to its bridge
+    pub tap: Tap,
+    pub address: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
 
 /// Struct that acts as a builder for Firecracker instances.
 pub struct FirecrackerBuilder {
     pub executable: String,
     pub kernel: String, // TODO: Remove kernel from here! It should be coupled with the function image
-    pub bridge: String,
-    pub network: Mutex<Addresses>,
+    pub networks: Networks,
+    pub guest_protocol: GuestProtocol,
+    /// How the node talks to the guests of the functions, the others use the defaults
+    guests: HashMap<String, GuestConfig>,
+    pub rate_limits: RateLimits,
+    pub image_mode: ImageMode,
+    pub workdir: PathBuf,
+    pub image_cache: ImageCache,
+    pub jailer: Option<Jailer>,
+    pub cgroups: Option<Cgroups>,
+    pub blocking: BlockingPool,
+    /// Number of crashes of the firecracker process, by image
+    crashes: Mutex<BTreeMap<String, u64>>,
+    pub taps: Arc<dyn TapFactory>,
+    pub environment: Arc<dyn ExecutionEnvironment>,
+    /// Workspaces of the instances, with the ones in use
+    pub workspaces: Arc<Workspaces>,
+    /// The live instances, reachable from the other tasks
+    pub instances: Arc<InstanceRegistry>,
+    /// The images of the image directories of the node
+    pub images: Arc<ImageInventory>,
+    /// Time an instance waits for an address once its network has none left
+    pub address_wait: Duration,
 }
 
 impl FirecrackerBuilder {
-    /// Create a new FirecrackerBuilder.
+    /// Create a new FirecrackerBuilder, attaching the instances to `bridge` by default.
     pub fn new(executable: String, kernel: String, bridge: String, network: Addresses) -> Self {
         Self {
             executable,
             kernel,
-            bridge,
-            network: Mutex::new(network),
+            networks: Networks::new(bridge, network),
+            guest_protocol: GuestProtocol::V2,
+            guests: HashMap::new(),
+            rate_limits: RateLimits::default(),
+            image_mode: ImageMode::Overlay,
+            workdir: PathBuf::from("/tmp/spare/overlays"),
+            image_cache: ImageCache::new(PathBuf::from("/tmp/spare/images"), 10 << 30),
+            jailer: None,
+            cgroups: None,
+            blocking: BlockingPool::default(),
+            crashes: Mutex::new(BTreeMap::new()),
+            taps: Arc::new(LinuxTaps),
+            environment: Arc::new(FirecrackerEnvironment),
+            workspaces: Arc::new(Workspaces::new(PathBuf::from(CHROOT))),
+            instances: Arc::new(InstanceRegistry::default()),
+            images: Arc::new(ImageInventory::default()),
+            address_wait: Duration::ZERO,
+        }
+    }
+
+    /// Set how the tap interfaces of the instances are created (defaults to the host ones).
+    pub fn with_tap_factory(mut self, taps: Arc<dyn TapFactory>) -> Self {
+        self.taps = taps;
+        self
+    }
+
+    /// Set the environment the machines of the instances run in (defaults to firecracker).
+    pub fn with_execution_environment(
+        mut self,
+        environment: Arc<dyn ExecutionEnvironment>,
+    ) -> Self {
+        self.environment = environment;
+        self
+    }
+
+    /// Create a tap interface attached to `bridge`. A transient failure is retried with
+    /// another interface, up to `TAP_ATTEMPTS` times.
+    async fn create_tap(&self, bridge: &str) -> Result<Tap, nix::Error> {
+        let mut attempt = 1;
+        loop {
+            let name = format!("fc-{}-tap", &uuid::Uuid::new_v4().to_string()[..8]);
+            match self.taps.create(&name, bridge) {
+                Ok(tap) => return Ok(tap),
+                Err(e) if is_retryable(e) && attempt < TAP_ATTEMPTS => {
+                    warn!(
+                        "Failed to set up {} on {} (attempt {}): {}, trying another one",
+                        name, bridge, attempt, e
+                    );
+                    actix_web::rt::time::sleep(TAP_RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
         }
     }
 
-    /// Create a new FirecrackerInstance from this builder.
+    /// Count a crash of the firecracker process of an instance of `image`.
+    pub fn record_crash(&self, image: &str) {
+        *self
+            .crashes
+            .lock()
+            .unwrap()
+            .entry(image.to_string())
+            .or_default() += 1;
+    }
+
+    /// Get the number of crashes of the firecracker process, by image.
+    pub fn crashes(&self) -> BTreeMap<String, u64> {
+        self.crashes.lock().unwrap().clone()
+    }
+
+    /// Set the networks of the instances, with the functions pinned to them.
+    pub fn with_networks(mut self, networks: Networks) -> Self {
+        self.networks = networks;
+        self
+    }
+
+    /// Set how long an instance waits for an address once its network has none left
+    /// (defaults to not waiting).
+    pub fn with_address_wait(mut self, address_wait: Duration) -> Self {
+        self.address_wait = address_wait;
+        self
+    }
+
+    /// Get the number of addresses left for the instances of `function`.
+    pub fn free_addresses(&self, function: &str) -> usize {
+        self.networks.free(function)
+    }
+
+    /// Give back the address of an instance to its network.
+    pub fn release_address(&self, instance: &FirecrackerInstance) {
+        self.networks.release(&instance.network, instance.address);
+    }
+
+    /// Set where the overlays of the images are copied (defaults to the blocking pool).
+    pub fn with_blocking_pool(mut self, blocking: BlockingPool) -> Self {
+        self.blocking = blocking;
+        self
+    }
+
+    /// Enforce the vcpus and memory of the instances through a cgroup each, created under `cgroups`.
+    pub fn with_cgroups(mut self, cgroups: Cgroups) -> Self {
+        self.cgroups = Some(cgroups);
+        self
+    }
+
+    /// Run the instances through the jailer, each one with its own chroot, uid/gid and cgroup.
+    pub fn with_jailer(mut self, jailer: Jailer) -> Self {
+        self.workspaces = Arc::new(Workspaces::new(jailer.workspaces_dir(&self.executable)));
+        self.jailer = Some(jailer);
+        self
+    }
+
+    /// Get the executor of the instance with the given address.
+    pub fn executor(&self, address: Ipv4Addr, netmask: Ipv4Addr) -> Result<Executor, BuilderError> {
+        match &self.jailer {
+            Some(jailer) => {
+                let uid = jailer.uid(address, netmask);
+                JailerExecutorBuilder::new()
+                    .with_chroot_base(jailer.chroot_base.to_string_lossy().to_string())
+                    .with_jailer_binary(jailer.binary.clone())
+                    .with_exec_binary(PathBuf::from(&self.executable))
+                    .with_user(uid, uid)
+                    .with_parent_cgroup("spare".to_owned())
+                    .try_build()
+            }
+            None => FirecrackerExecutorBuilder::new()
+                .with_chroot(CHROOT.to_owned())
+                .with_exec_binary(PathBuf::from(&self.executable))
+                .try_build(),
+        }
+    }
+
+    /// Set the cache used to store the images fetched from remote locations.
+    pub fn with_image_cache(mut self, image_cache: ImageCache) -> Self {
+        self.image_cache = image_cache;
+        self
+    }
+
+    /// Set the directories the images of the node are listed from
+    pub fn with_image_dirs(mut self, dirs: Vec<PathBuf>) -> Self {
+        self.images = Arc::new(ImageInventory::new(dirs));
+        self
+    }
+
+    /// Set how the function images are mounted in the instances (defaults to overlays).
+    /// Overlays are stored in `workdir`.
+    pub fn with_image_mode(mut self, image_mode: ImageMode, workdir: PathBuf) -> Self {
+        self.image_mode = image_mode;
+        self.workdir = workdir;
+        self
+    }
+
+    /// Get the space allocated on disk by the overlays of the running instances (in bytes).
+    pub fn overlay_disk_usage(&self) -> u64 {
+        overlay::disk_usage(&self.workdir)
+    }
+
+    /// Set the node-level default rate limits, applied when an invocation does not set them.
+    pub fn with_rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    /// Set the protocol spoken with the guest agent (defaults to v2).
+    pub fn with_guest_protocol(mut self, guest_protocol: GuestProtocol) -> Self {
+        self.guest_protocol = guest_protocol;
+        self
+    }
+
+    /// Set how the node talks to the guests of some functions.
+    pub fn with_function_guests(mut self, guests: Vec<FunctionGuest>) -> Self {
+        self.guests.extend(
+            guests
+                .into_iter()
+                .map(|guest| (guest.function, guest.config)),
+        );
+        self
+    }
+
+    /// Get how the node talks to the guest of `function`.
+    pub fn guest(&self, function: &str) -> GuestConfig {
+        self.guests.get(function).copied().unwrap_or_default()
+    }
+
+    /// Create a new FirecrackerInstance of `function` from this builder, in the network
+    /// the function is pinned to.
+    /// The rate limits of the `spec` that are not set fall back to the defaults of the builder.
+    /// The guest arguments are appended to the kernel command line, with the ports of the guest.
+    /// If the network has no address left, it waits up to `address_wait` for one.
     pub async fn new_instance(
         &self,
-        image: String,
-        vcpus: i32,
-        memory: i32,
-    ) -> Result<FirecrackerInstance, FirepilotError> {
-        // Scope to release the lock immediately after getting IP and network info
-        let (ip, gateway, netmask) = {
-            let mut network = self
-                .network
-                .lock()
-                .map_err(|e| FirepilotError::Unknown(format!("Failed to lock network: {}", e)))?;
-
-            match network.get() {
-                Some(ip) => {
-                    let gateway = network.get_gateway();
-                    let netmask = network.get_netmask();
-                    info!("Assigned IP address: {}", ip);
-                    (ip, gateway, netmask)
+        function: &str,
+        mut spec: InstanceSpec,
+    ) -> Result<FirecrackerInstance, FirecrackerBuilderError> {
+        spec.rate_limits = spec.rate_limits.or(&self.rate_limits);
+        self.guest(function).apply(&mut spec.guest_args);
+        let (image, vcpus, memory) = (spec.image.clone(), spec.vcpus, spec.memory);
+        let requested_image = image.clone();
+
+        let lease = self
+            .networks
+            .acquire_within(function, self.address_wait)
+            .await
+            .map_err(|e| match e {
+                NetworkError::Exhausted(network) => {
+                    FirecrackerBuilderError::AddressesExhausted(network)
                 }
-                None => {
-                    return Err(FirepilotError::Unknown(
-                        "No more addresses available".to_string(),
-                    ))
+                e => FirepilotError::Unknown(e.to_string()).into(),
+            })?;
+        let ip = lease.address;
+        info!("Assigned IP address: {} in network {}", ip, lease.network);
+
+        // Create the per-instance copy of the image, if needed
+        let overlay = match self.image_mode {
+            ImageMode::SharedRo => None,
+            ImageMode::Overlay => {
+                let name = uuid::Uuid::new_v4().to_string();
+                let (source, workdir) = (PathBuf::from(&image), self.workdir.clone());
+                let overlay = self
+                    .blocking
+                    .run(move || Overlay::create(&source, &workdir, &name))
+                    .await;
+                match overlay {
+                    Ok(overlay) => Some(overlay),
+                    Err(e) => {
+                        self.networks.release(&lease.network, ip);
+                        return Err(FirepilotError::Setup(format!(
+                            "Failed to create overlay of {}: {}",
+                            image, e
+                        ))
+                        .into());
+                    }
                 }
             }
         };
+        let image_path = match &overlay {
+            Some(overlay) => overlay.path().to_string_lossy().to_string(),
+            None => image,
+        };
 
-        let create_instance = FirecrackerInstance::new(
-            self.executable.clone(),
-            self.kernel.clone(),
-            image,
-            vcpus,
-            memory,
-            self.bridge.clone(),
-            ip,
-            gateway,
-            netmask,
-        )
-        .await;
+        // Attach the instance to the bridge of its network
+        let tap = match self.create_tap(&lease.bridge).await {
+            Ok(tap) => tap,
+            Err(e) => {
+                if let Some(overlay) = overlay {
+                    overlay.remove();
+                }
+                self.networks.release(&lease.network, ip);
+                return Err(FirepilotError::Setup(format!(
+                    "Failed to set up the tap of the instance on {}: {}",
+                    lease.bridge, e
+                ))
+                .into());
+            }
+        };
+        let tap_name = tap.name().to_owned();
+
+        let create_instance = match self.executor(ip, lease.netmask) {
+            Ok(executor) => FirecrackerInstance::new(
+                self.environment.as_ref(),
+                executor,
+                self.workspaces.lease(),
+                self.kernel.clone(),
+                self.image_mode,
+                InstanceSpec {
+                    image: image_path,
+                    ..spec
+                },
+                InstanceNetwork {
+                    tap,
+                    address: ip,
+                    gateway: lease.gateway,
+                    netmask: lease.netmask,
+                },
+            )
+            .await
+            .map(|mut instance| {
+                instance.taps = self.taps.clone();
+                instance
+            }),
+            Err(e) => Err(FirecrackerInstanceCreationError::CreationError(format!(
+                "Failed to create executor: {}",
+                e
+            ))),
+        };
+        // Once created, the instance removes its tap when deleted
+        if create_instance.is_err() {
+            if let Err(e) = self.taps.remove(&Tap::from_name(&tap_name)) {
+                info!("Failed to remove {}: {}", tap_name, e);
+            }
+        }
+
+        // Confine the instance in its own cgroup, if enforcement is enabled
+        let create_instance = match (create_instance, &self.cgroups) {
+            (Ok(mut instance), Some(cgroups)) => {
+                let name = uuid::Uuid::new_v4().to_string();
+                let limits = CgroupLimits::new(vcpus as u64, memory as u64);
+                let cgroup = match instance.lifecycle.machine.pid() {
+                    Some(pid) => cgroups.create(&name, limits, pid),
+                    None => Err(std::io::Error::other("Firecracker is not running")),
+                };
+                match cgroup {
+                    Ok(cgroup) => {
+                        instance.cgroup = Some(cgroup);
+                        Ok(instance)
+                    }
+                    Err(e) => {
+                        if let Err(e) = instance.delete().await {
+                            info!("Failed to delete instance: {}", e);
+                        }
+                        Err(FirecrackerInstanceCreationError::CreationError(format!(
+                            "Failed to create cgroup: {}",
+                            e
+                        )))
+                    }
+                }
+            }
+            (create_instance, _) => create_instance,
+        };
 
         match create_instance {
-            Ok(instance) => {
+            Ok(mut instance) => {
                 info!("Created instance with IP address: {}", ip);
+                instance.overlay = overlay;
+                instance.network = lease.network;
+                instance.registration = Some(self.instances.register(
+                    instance.id(),
+                    function,
+                    &requested_image,
+                ));
                 Ok(instance)
             }
             Err(e) => {
                 info!("Failed to create instance: {}", e);
+                if let Some(overlay) = overlay {
+                    overlay.remove();
+                }
                 // Release IP address
-                self.network
-                    .lock()
-                    .map_err(|e| FirepilotError::Unknown(format!("Failed to lock network: {}", e)))?
-                    .release(ip);
-                Err(FirepilotError::Unknown(format!(
-                    "Failed to create instance: {}",
-                    e
-                )))
+                self.networks.release(&lease.network, ip);
+                Err(FirepilotError::Unknown(format!("Failed to create instance: {}", e)).into())
             }
         }
     }
 }
 
+/// Error of the builder, creating a new instance
+#[derive(Debug, thiserror::Error)]
+pub enum FirecrackerBuilderError {
+    /// Every address of the network of the function is taken, retrying is useless until
+    /// an instance is deleted
+    #[error("No address available in network {0}")]
+    AddressesExhausted(String),
+    /// Error setting up or starting the machine of the instance
+    #[error(transparent)]
+    Machine(#[from] FirepilotError),
+}
+
+/// Error types for the creation of an instance
+#[derive(Debug, thiserror::Error)]
 pub enum FirecrackerInstanceCreationError {
     /// Error creating the instance.
+    #[error("Creation error: {0}")]
     CreationError(String),
-}
-impl std::fmt::Display for FirecrackerInstanceCreationError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            FirecrackerInstanceCreationError::CreationError(e) => {
-                write!(f, "Creation error: {}", e)
-            }
-        }
-    }
+    /// Error setting up the network of the instance.
+    #[error("Network error: {0}")]
+    Network(#[from] nix::Error),
+    /// Error configuring the machine.
+    #[error("Machine error: {0}")]
+    Machine(#[from] FirepilotError),
 }
 /// Struct that represents a Firecracker instance.
 pub struct FirecrackerInstance {
-    machine: Machine,
+    lifecycle: Lifecycle<AnyVmm>,
     address: Ipv4Addr,
+    network: String,
     tap: Tap,
+    /// Factory the tap was created with, which removes it
+    taps: Arc<dyn TapFactory>,
+    overlay: Option<Overlay>,
+    cgroup: Option<Cgroup>,
+    /// Keeps the workspace of the instance from being swept
+    workspace: WorkspaceLease,
+    /// Keeps the instance in the registry of the builder, as long as it lives
+    registration: Option<Registration>,
 }
 
 impl Drop for FirecrackerInstance {
@@ -124,97 +564,53 @@ impl Drop for FirecrackerInstance {
 impl FirecrackerInstance {
     /// Create a new FirecrackerInstance.
     /// # Arguments
-    /// * `executable_path` - The path to the Firecracker executable.
+    /// * `environment` - The environment the machine runs in.
+    /// * `executor` - The executor running Firecracker, either directly or through the jailer.
+    /// * `workspace` - The name of the workspace of the machine, held as long as the instance.
     /// * `kernel_path` - The path to the kernel image.
-    /// * `image_path` - The path to the function image.
-    /// * `vcpu` - The number of virtual CPUs.
-    /// * `memory` - The amount of memory in MiB.
-    /// * `bridge` - The name of the bridge to attach the instance to.
-    /// * `address` - The IP address to assign to the instance.
-    /// * `gateway` - The IP address of the gateway.
-    /// * `netmask` - The netmask to use.
+    /// * `image_mode` - How the function image is mounted.
+    /// * `spec` - The image, the machine and the guest of the instance.
+    /// * `network` - The tap interface and the addresses of the instance.
     /// # Returns
     /// A FirecrackerInstance.
-    /// # Panics
-    /// If the instance cannot be created.
+    /// # Errors
+    /// If the network or the machine of the instance cannot be set up.
     pub async fn new(
-        executable_path: String,
+        environment: &dyn ExecutionEnvironment,
+        executor: Executor,
+        workspace: WorkspaceLease,
         kernel_path: String,
-        image_path: String,
-        vcpu: i32,
-        memory: i32,
-        bridge: String,
-        address: Ipv4Addr,
-        gateway: Ipv4Addr,
-        netmask: Ipv4Addr,
+        image_mode: ImageMode,
+        spec: InstanceSpec,
+        network: InstanceNetwork,
     ) -> Result<Self, FirecrackerInstanceCreationError> {
-        let uuid = uuid::Uuid::new_v4();
-        let name = format!("firecracker-{}", uuid);
+        let name = workspace.name().to_string();
+        let InstanceSpec {
+            image: image_path,
+            vcpus: vcpu,
+            memory,
+            mmds,
+            rate_limits,
+            guest_args,
+        } = spec;
+        let InstanceNetwork {
+            tap,
+            address,
+            gateway,
+            netmask,
+        } = network;
 
         let boot_source = BootSource {
-            boot_args: Some(format!("console=ttyS0 reboot=k panic=1 pci=off en1.ipaddr={} en1.netmask={} en1.gateway={}", address, netmask, gateway)),
+            boot_args: Some(guest_args.render(&format!("console=ttyS0 reboot=k panic=1 pci=off en1.ipaddr={} en1.netmask={} en1.gateway={}", address, netmask, gateway)).map_err(|e| FirecrackerInstanceCreationError::CreationError(e.to_string()))?),
             initrd_path: None,
             kernel_image_path: kernel_path
         };
 
-        let disk = Drive {
-            drive_id: "rootfs".to_owned(),
-            partuuid: None,
-            is_root_device: true,
-            cache_type: None,
-            is_read_only: Some(false),
-            path_on_host: Some(image_path),
-            rate_limiter: None,
-            io_engine: None,
-            socket: None, //VHOST
-        };
-
-        let tap_name = format!("fc-{}-tap", uuid.to_string()[..8].to_owned()); // fetch name
-        let tmp = Tap::create(&tap_name);
-        match tmp {
-            Ok(_) => log::info!("Created {}", tap_name),
-            Err(e) => {
-                return Err(FirecrackerInstanceCreationError::CreationError(format!(
-                    "Failed to create {}: {}",
-                    tap_name, e
-                )))
-            }
-        }
-        let tap = tmp.unwrap();
-
-        let attach_tap = bridge::add_interface_to_bridge(interface_id(&tap_name).unwrap(), &bridge);
-        match attach_tap {
-            Ok(_) => log::info!("Added {} to {}", tap_name, bridge),
-            Err(e) => {
-                return Err(FirecrackerInstanceCreationError::CreationError(format!(
-                    "Failed to add {} to {}: {}",
-                    tap_name, bridge, e
-                )))
-            }
-        }
-
-        let net = NetworkInterface {
-            guest_mac: Some("AA:FC:00:00:00:00".to_owned()),
-            host_dev_name: tap_name,
-            iface_id: "eth0".to_owned(),
-            rx_rate_limiter: None,
-            tx_rate_limiter: None,
-        };
-
-        let executor = FirecrackerExecutorBuilder::new()
-            .with_chroot("/tmp".to_owned())
-            .with_exec_binary(executable_path.into())
-            .try_build();
+        // The image is used in place: it is either shared read-only or a private overlay
+        let read_only = image_mode == ImageMode::SharedRo;
+        let disk = root_drive(image_path, read_only, &rate_limits);
 
-        let executor = match executor {
-            Ok(executor) => executor,
-            Err(e) => {
-                return Err(FirecrackerInstanceCreationError::CreationError(format!(
-                    "Failed to create executor: {}",
-                    e
-                )))
-            }
-        };
+        let net = network_interface(tap.name().to_owned(), &rate_limits);
 
         let machine_configuration = MachineConfiguration {
             cpu_template: None,
@@ -225,78 +621,184 @@ impl FirecrackerInstance {
             huge_pages: None,
         };
 
-        let conf = Configuration::new(name.clone())
+        let mut conf = Configuration::new(name.clone())
             .with_kernel(boot_source)
             .with_drive(disk)
             .with_interface(net)
             .with_executor(executor)
-            .with_machine_config(machine_configuration);
+            .with_machine_config(machine_configuration)
+            .with_drives_in_place()
+            .with_metrics();
 
-        let mut machine = Machine::new();
-        match machine.create(conf).await {
-            Ok(_) => log::info!("Created {}", name),
-            Err(e) => {
-                return Err(FirecrackerInstanceCreationError::CreationError(format!(
-                    "Failed to create {}: {}",
-                    name, e
-                )))
-            }
+        if let Some(data) = mmds {
+            conf = conf.with_mmds(mmds_config(), data);
         }
 
+        let machine = environment.create(conf).await?;
+        log::info!("Created {}", name);
+
         Ok(Self {
-            machine,
+            lifecycle: Lifecycle::new(machine),
             address,
+            network: DEFAULT_NETWORK.to_string(),
             tap,
+            taps: Arc::new(LinuxTaps),
+            overlay: None,
+            cgroup: None,
+            workspace,
+            registration: None,
         })
     }
 
+    /// Get the id of the instance, the name of its workspace.
+    pub fn id(&self) -> &str {
+        self.workspace.name()
+    }
+
     /// Get the IP address of the instance.
     pub fn get_address(&self) -> Ipv4Addr {
         self.address
     }
 
-    /// Get the name of the instance.
-    pub async fn get_status(&self) -> String {
-        self.machine.is_running().await.to_string()
+    /// Get the name of the network of the instance.
+    pub fn network(&self) -> &str {
+        &self.network
+    }
+
+    /// Get the PID of the firecracker process of the instance, if it is running.
+    pub fn pid(&self) -> Option<u32> {
+        self.lifecycle.machine.pid()
+    }
+
+    /// Run `operation`, unless the firecracker process of the instance exits first.
+    /// Once the process is gone, the instance has failed and can only be deleted.
+    /// Meanwhile, the operations sent to the instance through the registry are performed.
+    pub async fn watch<T>(&mut self, operation: impl Future<Output = T>) -> Result<T, VmmExit> {
+        let mut operation = pin!(operation);
+        loop {
+            let Some(registration) = self.registration.as_mut() else {
+                return self.lifecycle.watch(operation).await;
+            };
+            let request = {
+                let watched = pin!(self.lifecycle.watch(operation.as_mut()));
+                let request = pin!(registration.next_request());
+                match select(watched, request).await {
+                    Either::Left((result, _)) => return result,
+                    Either::Right((request, _)) => request,
+                }
+            };
+            // No more operations can be sent once the registry forgot the instance
+            let Some((control, answer)) = request else {
+                return self.lifecycle.watch(operation).await;
+            };
+            info!("Instance {} asked to {}", self.id(), control);
+            let result = match control {
+                Control::Pause => self.pause().await,
+                Control::Resume => self.resume().await,
+                Control::Stop => self.stop().await,
+                Control::Delete => self.delete().await,
+            };
+            // The sender may have given up waiting
+            let _ = answer.send(result);
+        }
+    }
+
+    /// Get the state of the instance.
+    pub async fn get_status(&self) -> InstanceState {
+        self.lifecycle.state().await
     }
 
     /// Get the path to the vsock socket.
     pub fn get_vsock_path(&self) -> String {
-        self.machine.get_vsock_path()
+        self.lifecycle.machine.get_vsock_path()
+    }
+
+    /// Get the summary of the metrics of the instance.
+    /// A running instance is asked to flush its metrics first.
+    pub async fn metrics(&self) -> Result<MetricsSummary, std::io::Error> {
+        if let Err(e) = self.lifecycle.machine.flush_metrics().await {
+            log::warn!("Failed to flush metrics: {}", e);
+        }
+        MetricsSummary::read(&self.lifecycle.machine.get_metrics_path())
     }
 
-    /// Start the instance.
-    pub async fn start(&self) -> Result<(), FirepilotError> {
-        self.machine.start().await
+    /// Start the instance, it must not have been started before.
+    pub async fn start(&mut self) -> Result<(), LifecycleError> {
+        self.lifecycle.start().await
     }
 
-    /// Stop the instance.
-    pub async fn stop(&self) -> Result<(), FirepilotError> {
-        self.machine.stop().await
+    /// Stop the instance, it must be running.
+    pub async fn stop(&mut self) -> Result<(), LifecycleError> {
+        self.lifecycle.stop().await
     }
 
-    /// Pause the instance.
-    pub async fn pause(&self) -> Result<(), FirepilotError> {
-        self.machine.pause().await
+    /// Pause the instance, it must be running.
+    pub async fn pause(&mut self) -> Result<(), LifecycleError> {
+        self.lifecycle.pause().await
     }
 
-    /// Resume the instance.
-    pub async fn resume(&self) -> Result<(), FirepilotError> {
-        self.machine.resume().await
+    /// Resume the instance, it must be paused.
+    pub async fn resume(&mut self) -> Result<(), LifecycleError> {
+        self.lifecycle.resume().await
     }
 
-    /// Delete the instance.
-    pub async fn delete(&mut self) -> Result<(), FirepilotError> {
-        self.machine.kill().await?;
-        self.tap.remove().unwrap();
+    /// Delete the instance, whatever its state.
+    pub async fn delete(&mut self) -> Result<(), LifecycleError> {
+        self.lifecycle.delete().await?;
+        self.taps.remove(&self.tap).unwrap();
+        if let Some(overlay) = self.overlay.take() {
+            overlay.remove();
+        }
+        if let Some(cgroup) = self.cgroup.take() {
+            cgroup.remove();
+        }
         Ok(())
     }
 }
 
+/// Root drive of an instance, backed by the function image.
+fn root_drive(image_path: String, read_only: bool, rate_limits: &RateLimits) -> Drive {
+    Drive {
+        drive_id: "rootfs".to_owned(),
+        partuuid: None,
+        is_root_device: true,
+        cache_type: None,
+        is_read_only: Some(read_only),
+        path_on_host: Some(image_path),
+        rate_limiter: rate_limits.disk_rate_limiter(),
+        io_engine: None,
+        socket: None, //VHOST
+    }
+}
+
+/// Network interface of an instance, attached to the tap device `tap_name`.
+fn network_interface(tap_name: String, rate_limits: &RateLimits) -> NetworkInterface {
+    NetworkInterface {
+        guest_mac: Some("AA:FC:00:00:00:00".to_owned()),
+        host_dev_name: tap_name,
+        iface_id: "eth0".to_owned(),
+        rx_rate_limiter: rate_limits.rx_rate_limiter(),
+        tx_rate_limiter: rate_limits.tx_rate_limiter(),
+    }
+}
+
+/// Address of the metadata service as seen by the guest
+pub const MMDS_ADDRESS: &str = "169.254.169.254";
+
+/// MMDS configuration used by the instances: the service is reachable
+/// from the only network interface of the guest.
+pub fn mmds_config() -> MmdsConfig {
+    MmdsConfig {
+        version: Some(Version::V2),
+        network_interfaces: vec!["eth0".to_owned()],
+        ipv4_address: Some(MMDS_ADDRESS.to_owned()),
+    }
+}
+
 // Unit tests
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use std::{path::Path, time::Duration};
 
     use actix_web::rt::time::sleep;
 
@@ -310,37 +812,329 @@ mod tests {
         let image_path = "/home/ubuntu/.ops/images/nanosvm".to_owned();
         let vcpu = 8;
         let memory = 512;
-        let bridge = "br0".to_owned();
+        let tap = LinuxTaps.create("fc-test-tap", "br0").unwrap();
         let address = Ipv4Addr::new(192, 168, 30, 2);
         let gateway = Ipv4Addr::new(192, 168, 30, 1);
         let netmask = Ipv4Addr::new(255, 255, 255, 0);
+        let executor = FirecrackerExecutorBuilder::new()
+            .with_chroot("/tmp".to_owned())
+            .with_exec_binary(executable_path.into())
+            .try_build()
+            .unwrap();
         let instance = FirecrackerInstance::new(
-            executable_path,
+            &FirecrackerEnvironment,
+            executor,
+            Workspaces::new(PathBuf::from("/tmp")).lease(),
             kernel_path,
-            image_path,
-            vcpu,
-            memory,
-            bridge,
-            address,
-            gateway,
-            netmask,
+            ImageMode::Overlay,
+            InstanceSpec::new(image_path, vcpu, memory),
+            InstanceNetwork {
+                tap,
+                address,
+                gateway,
+                netmask,
+            },
         )
         .await;
 
         assert!(instance.is_ok());
         match instance {
-            Ok(instance) => {
+            Ok(mut instance) => {
                 assert_eq!(instance.get_address(), address);
                 assert_eq!(instance.get_vsock_path(), "/tmp/vsock.sock");
-                assert_eq!(instance.get_status().await, "false");
+                assert_eq!(instance.get_status().await, InstanceState::NotStarted);
                 instance.start().await.unwrap();
                 sleep(Duration::from_secs(5)).await;
-                assert_eq!(instance.get_status().await, "true");
+                assert_eq!(instance.get_status().await, InstanceState::Running);
                 instance.stop().await.unwrap();
                 sleep(Duration::from_secs(5)).await;
-                assert_eq!(instance.get_status().await, "false");
+                assert_eq!(instance.get_status().await, InstanceState::Stopped);
             }
             Err(e) => panic!("Failed to create instance: {}", e),
         }
     }
+
+    // Boots an instance through the jailer, it only runs when SPARE_TEST_JAILER is set.
+    // Use SPARE_TEST_FIRECRACKER, SPARE_TEST_KERNEL and SPARE_TEST_IMAGE to locate the
+    // firecracker executable, the kernel and the function image.
+    #[actix_web::test]
+    async fn test_jailed_firecracker() {
+        let jailer = match std::env::var("SPARE_TEST_JAILER") {
+            Ok(jailer) => jailer,
+            Err(_) => return,
+        };
+        let env = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{name} not set"));
+        let builder = FirecrackerBuilder::new(
+            env("SPARE_TEST_FIRECRACKER"),
+            env("SPARE_TEST_KERNEL"),
+            "br0".to_owned(),
+            Addresses::new(Ipv4Addr::new(192, 168, 30, 0), 24).unwrap(),
+        )
+        .with_jailer(Jailer {
+            binary: PathBuf::from(jailer),
+            chroot_base: PathBuf::from("/srv/jailer"),
+            uid_base: 10000,
+        });
+        let mut instance = builder
+            .new_instance("test", InstanceSpec::new(env("SPARE_TEST_IMAGE"), 1, 128))
+            .await
+            .unwrap();
+        let vsock = PathBuf::from(instance.get_vsock_path());
+        assert!(vsock.starts_with("/srv/jailer/firecracker"));
+        let workspace = vsock.parent().unwrap().parent().unwrap().to_path_buf();
+        instance.start().await.unwrap();
+        sleep(Duration::from_secs(2)).await;
+        assert_eq!(instance.get_status().await, InstanceState::Running);
+        instance.delete().await.unwrap();
+        assert_eq!(instance.get_status().await, InstanceState::Stopped);
+        assert!(!workspace.exists());
+    }
+
+    // Kills the firecracker process of a running instance, it only runs when
+    // SPARE_TEST_CRASH is set. Uses the same variables as test_jailed_firecracker.
+    #[actix_web::test]
+    async fn test_firecracker_crash() {
+        if std::env::var("SPARE_TEST_CRASH").is_err() {
+            return;
+        }
+        let env = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{name} not set"));
+        let builder = FirecrackerBuilder::new(
+            env("SPARE_TEST_FIRECRACKER"),
+            env("SPARE_TEST_KERNEL"),
+            "br0".to_owned(),
+            Addresses::new(Ipv4Addr::new(192, 168, 30, 0), 24).unwrap(),
+        );
+        let mut instance = builder
+            .new_instance("test", InstanceSpec::new(env("SPARE_TEST_IMAGE"), 1, 128))
+            .await
+            .unwrap();
+        instance.start().await.unwrap();
+        let pid = instance.pid().unwrap();
+        // As the OOM killer would
+        assert_eq!(unsafe { libc::kill(pid as i32, libc::SIGKILL) }, 0);
+
+        let guest = sleep(Duration::from_secs(10));
+        assert_eq!(instance.watch(guest).await, Err(VmmExit::Signal(9)));
+        assert_eq!(instance.get_status().await, InstanceState::Failed);
+        // The tap and the workspace of the dead process are still removed
+        instance.delete().await.unwrap();
+        builder.release_address(&instance);
+    }
+
+    #[test]
+    fn test_jailer_executor() {
+        let jailer = Jailer {
+            binary: PathBuf::from("/usr/bin/jailer"),
+            chroot_base: PathBuf::from("/srv/jailer"),
+            uid_base: 10000,
+        };
+        let netmask = Ipv4Addr::new(255, 255, 0, 0);
+        assert_eq!(jailer.uid(Ipv4Addr::new(10, 0, 1, 2), netmask), 10258);
+
+        let builder = FirecrackerBuilder::new(
+            "/usr/bin/firecracker".to_owned(),
+            "kernel".to_owned(),
+            "br0".to_owned(),
+            Addresses::new(Ipv4Addr::new(10, 0, 0, 0), 16).unwrap(),
+        );
+        let executor = builder
+            .executor(Ipv4Addr::new(10, 0, 1, 2), netmask)
+            .unwrap()
+            .with_id("vm".to_owned());
+        assert!(!executor.is_jailed());
+        assert_eq!(executor.chroot(), PathBuf::from("/tmp/vm"));
+        assert_eq!(builder.workspaces.dir(), Path::new("/tmp"));
+
+        let builder = builder.with_jailer(jailer);
+        assert_eq!(
+            builder.workspaces.dir(),
+            Path::new("/srv/jailer/firecracker")
+        );
+        let executor = builder
+            .executor(Ipv4Addr::new(10, 0, 1, 2), netmask)
+            .unwrap()
+            .with_id("vm".to_owned());
+        assert!(executor.is_jailed());
+        assert_eq!(
+            executor.chroot(),
+            PathBuf::from("/srv/jailer/firecracker/vm/root")
+        );
+        assert_eq!(
+            executor.resolve_path(&executor.chroot().join("vsock.sock")),
+            "/vsock.sock"
+        );
+    }
+
+    /// Tap factory failing with the given errors before succeeding
+    #[derive(Default)]
+    struct FlakyTaps {
+        failures: Mutex<std::collections::VecDeque<nix::Error>>,
+        created: Mutex<Vec<String>>,
+        removed: Mutex<Vec<String>>,
+    }
+
+    impl FlakyTaps {
+        fn new(failures: &[nix::Error]) -> Arc<Self> {
+            Arc::new(FlakyTaps {
+                failures: Mutex::new(failures.iter().copied().collect()),
+                ..Default::default()
+            })
+        }
+    }
+
+    impl TapFactory for FlakyTaps {
+        fn create(&self, name: &str, _bridge: &str) -> Result<Tap, nix::Error> {
+            self.created.lock().unwrap().push(name.to_owned());
+            match self.failures.lock().unwrap().pop_front() {
+                Some(e) => Err(e),
+                None => Ok(Tap::from_name(name)),
+            }
+        }
+
+        fn remove(&self, tap: &Tap) -> Result<(), nix::Error> {
+            self.removed.lock().unwrap().push(tap.name().to_owned());
+            Ok(())
+        }
+    }
+
+    /// A builder whose only network has two addresses
+    fn flaky_builder(taps: Arc<FlakyTaps>) -> FirecrackerBuilder {
+        FirecrackerBuilder::new(
+            "/nonexistent/firecracker".to_owned(),
+            "/nonexistent/kernel".to_owned(),
+            "br0".to_owned(),
+            Addresses::new(Ipv4Addr::new(10, 0, 0, 0), 30).unwrap(),
+        )
+        .with_image_mode(ImageMode::SharedRo, PathBuf::from("/nonexistent"))
+        .with_tap_factory(taps)
+    }
+
+    /// Check that both addresses of the builder are available
+    fn assert_no_leak(builder: &FirecrackerBuilder) {
+        let first = builder.networks.acquire("test").unwrap();
+        let second = builder.networks.acquire("test").unwrap();
+        assert!(builder.networks.acquire("test").is_err());
+        builder.networks.release(&first.network, first.address);
+        builder.networks.release(&second.network, second.address);
+    }
+
+    async fn new_instance(
+        builder: &FirecrackerBuilder,
+    ) -> Result<FirecrackerInstance, FirecrackerBuilderError> {
+        builder
+            .new_instance("test", InstanceSpec::new("image".to_owned(), 1, 128))
+            .await
+    }
+
+    #[actix_web::test]
+    async fn test_tap_retries() {
+        use nix::errno::Errno;
+
+        // Transient failures are retried, each time with another tap
+        let taps = FlakyTaps::new(&[Errno::EBUSY, Errno::EBUSY]);
+        let builder = flaky_builder(taps.clone());
+        let tap = builder.create_tap("br0").await.unwrap();
+        let created = taps.created.lock().unwrap().clone();
+        assert_eq!(created.len(), 3);
+        assert_eq!(tap.name(), created[2]);
+        assert!(created[0] != created[1] && created[1] != created[2]);
+
+        // Up to TAP_ATTEMPTS times
+        let taps = FlakyTaps::new(&[]);
+        let builder = flaky_builder(taps.clone());
+        for _ in 0..3 {
+            taps.failures
+                .lock()
+                .unwrap()
+                .extend([Errno::EBUSY; TAP_ATTEMPTS as usize]);
+            let e = new_instance(&builder).await.err().unwrap();
+            assert!(e.to_string().contains("Failed to set up the tap"));
+        }
+        assert_eq!(
+            taps.created.lock().unwrap().len(),
+            3 * TAP_ATTEMPTS as usize
+        );
+        // No address was leaked across the attempts
+        assert_no_leak(&builder);
+
+        // A fatal failure is not retried
+        let taps = FlakyTaps::new(&[Errno::ENODEV]);
+        let builder = flaky_builder(taps.clone());
+        assert!(new_instance(&builder).await.is_err());
+        assert_eq!(taps.created.lock().unwrap().len(), 1);
+        assert_no_leak(&builder);
+    }
+
+    #[test]
+    fn test_crash_counter() {
+        let builder = FirecrackerBuilder::new(
+            "/usr/bin/firecracker".to_owned(),
+            "kernel".to_owned(),
+            "br0".to_owned(),
+            Addresses::new(Ipv4Addr::new(10, 0, 0, 0), 16).unwrap(),
+        );
+        assert!(builder.crashes().is_empty());
+        builder.record_crash("broken");
+        builder.record_crash("broken");
+        builder.record_crash("flaky");
+        assert_eq!(
+            builder.crashes(),
+            BTreeMap::from([("broken".to_string(), 2), ("flaky".to_string(), 1)])
+        );
+    }
+
+    #[test]
+    fn test_mmds_config() {
+        let config = serde_json::to_value(mmds_config()).unwrap();
+        assert_eq!(
+            config,
+            serde_json::json!({
+                "version": "V2",
+                "network_interfaces": ["eth0"],
+                "ipv4_address": "169.254.169.254",
+            })
+        );
+    }
+
+    #[test]
+    fn test_rate_limits_configuration() {
+        use crate::api::rate_limits::Bucket;
+
+        // No limits, no rate limiters
+        let net =
+            serde_json::to_value(network_interface("tap0".to_owned(), &RateLimits::default()))
+                .unwrap();
+        assert!(net.get("rx_rate_limiter").is_none());
+        assert!(net.get("tx_rate_limiter").is_none());
+
+        let rate_limits = RateLimits {
+            rx_bandwidth: Some(Bucket::per_second(1_000_000)),
+            tx_bandwidth: Some(Bucket {
+                size: 500_000,
+                refill_time: 100,
+                one_time_burst: Some(1_000_000),
+            }),
+            disk_bandwidth: None,
+            disk_ops: Some(Bucket::per_second(100)),
+        };
+
+        let net = serde_json::to_value(network_interface("tap0".to_owned(), &rate_limits)).unwrap();
+        assert_eq!(
+            net["rx_rate_limiter"],
+            serde_json::json!({"bandwidth": {"refill_time": 1000, "size": 1_000_000}})
+        );
+        assert_eq!(
+            net["tx_rate_limiter"],
+            serde_json::json!({
+                "bandwidth": {"one_time_burst": 1_000_000, "refill_time": 100, "size": 500_000}
+            })
+        );
+
+        let disk =
+            serde_json::to_value(root_drive("rootfs".to_owned(), false, &rate_limits)).unwrap();
+        assert_eq!(
+            disk["rate_limiter"],
+            serde_json::json!({"ops": {"refill_time": 1000, "size": 100}})
+        );
+    }
 }
@@ -0,0 +1,459 @@
+//! How the node talks to the guest of a function.
+//! By default the guest agent connects to the host through the vsock and speaks the guest
+//! protocol. Images that serve HTTP instead are reached on their address: the request is
+//! forwarded once the network of the instance is up. The ports of both are set per
+//! function and passed to the guest in its environment, as `SPARE_VSOCK_PORT` and
+//! `SPARE_GUEST_PORT`.
+use std::{fmt::Display, future::Future, net::SocketAddr, str::FromStr, time::Duration};
+
+use actix_web::{
+    http::StatusCode,
+    rt::time::sleep,
+    web::{Bytes, BytesMut},
+};
+use awc::{error::SendRequestError, Client};
+use futures::StreamExt;
+use log::warn;
+
+use super::boot_args::GuestArgs;
+use crate::api::payload::Payload;
+
+/// Vsock port the guest agent connects to, unless set for the function
+pub const DEFAULT_VSOCK_PORT: u32 = 1234;
+
+/// Port a guest serving HTTP listens on, unless set for the function
+pub const DEFAULT_GUEST_PORT: u16 = 8084;
+
+/// Variable of the environment of the guest holding its vsock port
+pub const VSOCK_PORT_ENV: &str = "SPARE_VSOCK_PORT";
+
+/// Variable of the environment of the guest holding its HTTP port
+pub const GUEST_PORT_ENV: &str = "SPARE_GUEST_PORT";
+
+/// Maximum size of the answer of a guest serving HTTP
+const MAX_RESPONSE_SIZE: usize = 50 << 20;
+
+/// How the node talks to the guest
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum GuestMode {
+    /// The guest agent connects to the vsock and speaks the guest protocol
+    #[default]
+    Vsock,
+    /// The guest serves HTTP on its address, the request is forwarded to it
+    Http,
+}
+
+impl FromStr for GuestMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "vsock" => Ok(GuestMode::Vsock),
+            "http" => Ok(GuestMode::Http),
+            _ => Err(format!("Invalid guest mode: {s}, expected vsock or http")),
+        }
+    }
+}
+
+/// How the node talks to the guest of a function
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestConfig {
+    pub mode: GuestMode,
+    /// Port the guest serves HTTP on
+    pub guest_port: u16,
+    /// Port the guest agent connects to on the vsock
+    pub vsock_port: u32,
+}
+
+impl Default for GuestConfig {
+    fn default() -> Self {
+        GuestConfig {
+            mode: GuestMode::Vsock,
+            guest_port: DEFAULT_GUEST_PORT,
+            vsock_port: DEFAULT_VSOCK_PORT,
+        }
+    }
+}
+
+impl GuestConfig {
+    /// Pass the ports to the guest, in its environment
+    pub fn apply(&self, guest_args: &mut GuestArgs) {
+        guest_args
+            .env
+            .insert(VSOCK_PORT_ENV.to_string(), self.vsock_port.to_string());
+        guest_args
+            .env
+            .insert(GUEST_PORT_ENV.to_string(), self.guest_port.to_string());
+    }
+
+    /// Path of the socket the host listens on for the connections of the guest to its
+    /// vsock port, as firecracker names it
+    pub fn vsock_listener(&self, vsock_path: &str) -> String {
+        super::vsock::listener_path(vsock_path, self.vsock_port)
+            .display()
+            .to_string()
+    }
+}
+
+/// How the node talks to the guest of a function, given on the command line as
+/// `function=mode,guest_port,vsock_port`; each may be left empty for its default
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionGuest {
+    pub function: String,
+    pub config: GuestConfig,
+}
+
+impl FromStr for FunctionGuest {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("Invalid function guest: {s}, expected function=mode,guest_port,vsock_port");
+        let (function, rest) = s.split_once('=').ok_or_else(invalid)?;
+        let [mode, guest_port, vsock_port] = rest
+            .split(',')
+            .collect::<Vec<_>>()
+            .try_into()
+            .map_err(|_| invalid())?;
+        if function.is_empty() {
+            return Err(invalid());
+        }
+        let defaults = GuestConfig::default();
+        Ok(FunctionGuest {
+            function: function.to_string(),
+            config: GuestConfig {
+                mode: match mode {
+                    "" => defaults.mode,
+                    mode => mode.parse()?,
+                },
+                guest_port: match guest_port {
+                    "" => defaults.guest_port,
+                    port => port.parse().map_err(|_| invalid())?,
+                },
+                vsock_port: match vsock_port {
+                    "" => defaults.vsock_port,
+                    port => port.parse().map_err(|_| invalid())?,
+                },
+            },
+        })
+    }
+}
+
+/// How many times, and how often, an operation is tried while the guest comes up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retry {
+    pub attempts: u32,
+    pub delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: 20,
+            delay: Duration::from_millis(50),
+        }
+    }
+}
+
+impl Retry {
+    /// Run `operation` until it succeeds, it fails with an error that is not `retryable`,
+    /// or the attempts run out. The operation is given the number of the attempt.
+    pub async fn run<T, E, F, Fut>(
+        &self,
+        mut operation: F,
+        retryable: impl Fn(&E) -> bool,
+    ) -> Result<T, E>
+    where
+        E: Display,
+        F: FnMut(u32) -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation(attempt).await {
+                Err(e) if attempt < self.attempts && retryable(&e) => {
+                    warn!("Attempt {} of {} failed: {}", attempt, self.attempts, e);
+                    sleep(self.delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Request forwarded to a guest serving HTTP
+#[derive(Clone)]
+pub enum GuestRequest {
+    /// No payload, the guest is only called
+    Get,
+    /// The payload of the request, as `{"payload": ...}`
+    Json(Payload),
+    /// A binary payload, as it was received
+    Raw(Bytes),
+}
+
+impl GuestRequest {
+    /// Request carrying the binary `body` of the invocation, or else its `payload`
+    pub fn new(payload: Option<String>, body: Option<Bytes>) -> Self {
+        match (payload, body) {
+            (_, Some(body)) => GuestRequest::Raw(body),
+            (Some(payload), None) => GuestRequest::Json(Payload { payload }),
+            (None, None) => GuestRequest::Get,
+        }
+    }
+}
+
+/// Error while forwarding a request to a guest serving HTTP
+#[derive(Debug, thiserror::Error)]
+pub enum HttpGuestError {
+    /// The network of the guest is not up, or nothing listens on its port
+    #[error("The guest is unreachable: {0}")]
+    Unreachable(String),
+    #[error("Timed out waiting for the guest")]
+    Timeout,
+    #[error("Cannot send the request to the guest: {0}")]
+    Request(String),
+    /// The function answered with an error
+    #[error("The guest answered with {0}: {1}")]
+    Status(StatusCode, String),
+    #[error("Cannot read the answer of the guest: {0}")]
+    Body(String),
+}
+
+/// Forward a request to the guest serving HTTP on `address`, returning its answer.
+/// The network of an instance may come up after its guest is started, so the request is
+/// tried again while the guest is unreachable.
+pub async fn forward_http(
+    address: SocketAddr,
+    request: &GuestRequest,
+    retry: Retry,
+    timeout: Duration,
+) -> Result<Bytes, HttpGuestError> {
+    let client = Client::builder().timeout(timeout).finish();
+    let (client, url) = (&client, &format!("http://{}/", address));
+    let send = move |_| async move {
+        let sent = match request {
+            GuestRequest::Get => client.get(url).send().await,
+            GuestRequest::Json(payload) => client.post(url).send_json(payload).await,
+            GuestRequest::Raw(body) => {
+                client
+                    .post(url)
+                    .content_type("application/octet-stream")
+                    .send_body(body.clone())
+                    .await
+            }
+        };
+        sent.map_err(|e| match e {
+            SendRequestError::Connect(e) => HttpGuestError::Unreachable(e.to_string()),
+            SendRequestError::Send(e) => HttpGuestError::Unreachable(e.to_string()),
+            SendRequestError::Timeout => HttpGuestError::Timeout,
+            e => HttpGuestError::Request(e.to_string()),
+        })
+    };
+    let mut response = retry
+        .run(send, |e| matches!(e, HttpGuestError::Unreachable(_)))
+        .await?;
+
+    let mut body = BytesMut::new();
+    while let Some(chunk) = response.next().await {
+        let chunk = chunk.map_err(|e| HttpGuestError::Body(e.to_string()))?;
+        if body.len() + chunk.len() > MAX_RESPONSE_SIZE {
+            return Err(HttpGuestError::Body(format!(
+                "The answer is larger than {} bytes",
+                MAX_RESPONSE_SIZE
+            )));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let body = body.freeze();
+    if !response.status().is_success() {
+        return Err(HttpGuestError::Status(
+            response.status(),
+            String::from_utf8_lossy(&body).to_string(),
+        ));
+    }
+    Ok(body)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpServer};
+    use std::{cell::Cell, net::TcpListener};
+
+    fn parse(s: &str) -> Result<GuestConfig, String> {
+        s.parse::<FunctionGuest>().map(|guest| guest.config)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "legacy=http,8080,".parse::<FunctionGuest>().unwrap(),
+            FunctionGuest {
+                function: "legacy".to_string(),
+                config: GuestConfig {
+                    mode: GuestMode::Http,
+                    guest_port: 8080,
+                    vsock_port: DEFAULT_VSOCK_PORT,
+                },
+            }
+        );
+        assert_eq!(
+            parse("resize=,,4321"),
+            Ok(GuestConfig {
+                vsock_port: 4321,
+                ..GuestConfig::default()
+            })
+        );
+        assert_eq!(parse("resize=,,"), Ok(GuestConfig::default()));
+        assert!(parse("resize=http,8080").is_err());
+        assert!(parse("resize=tcp,,").is_err());
+        assert!(parse("resize=,70000,").is_err());
+        assert!(parse("=http,,").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let config = GuestConfig {
+            mode: GuestMode::Vsock,
+            guest_port: 8080,
+            vsock_port: 4321,
+        };
+        let mut guest_args = GuestArgs::default();
+        config.apply(&mut guest_args);
+        assert_eq!(
+            guest_args.render("").unwrap(),
+            " env.SPARE_GUEST_PORT=8080 env.SPARE_VSOCK_PORT=4321"
+        );
+        assert_eq!(config.vsock_listener("/run/v.sock"), "/run/v.sock_4321");
+    }
+
+    #[actix_web::test]
+    async fn test_retry() {
+        let retry = Retry {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+        };
+        let calls = Cell::new(0);
+        let flaky = |attempt: u32| {
+            calls.set(calls.get() + 1);
+            async move {
+                match attempt {
+                    3 => Ok(attempt),
+                    _ => Err("not ready"),
+                }
+            }
+        };
+        // Retried until it succeeds
+        assert_eq!(retry.run(flaky, |_| true).await, Ok(3));
+        assert_eq!(calls.get(), 3);
+        // Not when the error is not retryable
+        calls.set(0);
+        assert_eq!(retry.run(flaky, |_| false).await, Err("not ready"));
+        assert_eq!(calls.get(), 1);
+        // Up to the attempts
+        calls.set(0);
+        let retry = Retry {
+            attempts: 2,
+            ..retry
+        };
+        assert_eq!(retry.run(flaky, |_| true).await, Err("not ready"));
+        assert_eq!(calls.get(), 2);
+    }
+
+    /// Address of the loopback nothing listens on yet
+    fn free_address() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    /// Serve on `address` a guest answering with what it receives, after `delay`
+    fn serve_echo(address: SocketAddr, delay: Duration) {
+        actix_web::rt::spawn(async move {
+            sleep(delay).await;
+            let server = HttpServer::new(|| {
+                App::new()
+                    .route("/", web::get().to(|| async { "ready" }))
+                    .route("/", web::post().to(|body: Bytes| async move { body }))
+            })
+            .workers(1)
+            .bind(address)
+            .unwrap()
+            .run();
+            server.await.unwrap();
+        });
+    }
+
+    #[actix_web::test]
+    async fn test_forward_http() {
+        let address = free_address();
+        // The network of the guest comes up after the first attempts
+        serve_echo(address, Duration::from_millis(100));
+        let (retry, timeout) = (Retry::default(), Duration::from_secs(5));
+
+        let body = forward_http(address, &GuestRequest::Get, retry, timeout)
+            .await
+            .unwrap();
+        assert_eq!(body, "ready");
+        let payload = GuestRequest::new(Some("aGVsbG8=".to_string()), None);
+        let body = forward_http(address, &payload, retry, timeout)
+            .await
+            .unwrap();
+        assert_eq!(body, r#"{"payload":"aGVsbG8="}"#);
+        // A binary body wins over the payload
+        let raw = GuestRequest::new(
+            Some("aGVsbG8=".to_string()),
+            Some(Bytes::from_static(b"\x00\x01")),
+        );
+        let body = forward_http(address, &raw, retry, timeout).await.unwrap();
+        assert_eq!(body, b"\x00\x01"[..]);
+    }
+
+    #[actix_web::test]
+    async fn test_unreachable() {
+        let retry = Retry {
+            attempts: 3,
+            delay: Duration::from_millis(1),
+        };
+        let result = forward_http(
+            free_address(),
+            &GuestRequest::Get,
+            retry,
+            Duration::from_secs(1),
+        )
+        .await;
+        assert!(matches!(result, Err(HttpGuestError::Unreachable(_))));
+    }
+
+    #[actix_web::test]
+    async fn test_status() {
+        let address = free_address();
+        actix_web::rt::spawn(
+            HttpServer::new(|| {
+                App::new().route(
+                    "/",
+                    web::get().to(|| async { actix_web::HttpResponse::BadRequest().body("no") }),
+                )
+            })
+            .workers(1)
+            .bind(address)
+            .unwrap()
+            .run(),
+        );
+        let result = forward_http(
+            address,
+            &GuestRequest::Get,
+            Retry::default(),
+            Duration::from_secs(5),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(HttpGuestError::Status(StatusCode::BAD_REQUEST, body)) if body == "no"
+        ));
+    }
+}
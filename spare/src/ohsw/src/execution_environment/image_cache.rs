@@ -0,0 +1,476 @@
+//! Local cache of the function images.
+//! Images can be referenced by a local path, a `file://` URL or an `http(s)://` URL.
+//! Remote images are downloaded once into the cache directory, verified against their
+//! SHA-256 digest and stored by digest, so that the same content served from different
+//! URLs is stored only once. When the cache grows beyond its size cap, the least recently
+//! used images are evicted.
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use futures::{lock::Mutex as AsyncMutex, StreamExt};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::{net::tls::NodeClient, utils::blocking::BlockingPool};
+
+/// Error types for the image cache
+#[derive(Debug)]
+pub enum ImageCacheError {
+    /// The image cannot be downloaded
+    Download(String),
+    /// The digest of the image does not match the expected one
+    DigestMismatch { expected: String, actual: String },
+    /// Error reading or writing the cache directory
+    Io(io::Error),
+}
+impl std::fmt::Display for ImageCacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageCacheError::Download(e) => write!(f, "Download error: {}", e),
+            ImageCacheError::DigestMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "Digest mismatch: expected {}, found {}",
+                    expected, actual
+                )
+            }
+            ImageCacheError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+impl std::error::Error for ImageCacheError {}
+impl From<io::Error> for ImageCacheError {
+    fn from(e: io::Error) -> Self {
+        ImageCacheError::Io(e)
+    }
+}
+
+/// An image stored in the cache
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    last_used: Instant,
+}
+
+/// Cache of the function images fetched from remote locations.
+pub struct ImageCache {
+    dir: PathBuf,
+    max_size: u64,
+    // Digest of the content served by each URL fetched so far
+    urls: Mutex<HashMap<String, String>>,
+    // Images in the cache, indexed by digest
+    entries: Mutex<HashMap<String, CacheEntry>>,
+    // Per-URL locks, used to coalesce concurrent downloads of the same image
+    downloads: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+    // Where the digests of the local images are computed
+    blocking: BlockingPool,
+    // Factory of the clients downloading the images
+    client: NodeClient,
+}
+
+impl ImageCache {
+    /// Create a new cache storing at most `max_size` bytes of images in `dir`.
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self {
+            dir,
+            max_size,
+            urls: Mutex::new(HashMap::new()),
+            entries: Mutex::new(HashMap::new()),
+            downloads: Mutex::new(HashMap::new()),
+            blocking: BlockingPool::default(),
+            client: NodeClient::plain(),
+        }
+    }
+
+    /// Set where the digests of the local images are computed
+    pub fn with_blocking_pool(mut self, blocking: BlockingPool) -> Self {
+        self.blocking = blocking;
+        self
+    }
+
+    /// Set the factory of the clients downloading the images, to check the certificates
+    /// of the servers against the CA bundle of the node
+    pub fn with_client(mut self, client: NodeClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Resolve an image reference to a local path, downloading it if needed.
+    /// # Arguments
+    /// * `image` - A local path, a `file://` URL or an `http(s)://` URL.
+    /// * `digest` - The expected SHA-256 digest (hex encoded) of the image, if known.
+    /// # Returns
+    /// The path of the image on the local filesystem.
+    pub async fn resolve(
+        &self,
+        image: &str,
+        digest: Option<&str>,
+    ) -> Result<String, ImageCacheError> {
+        let digest = digest.map(normalize_digest);
+
+        if !image.starts_with("http://") && !image.starts_with("https://") {
+            let path = image.strip_prefix("file://").unwrap_or(image);
+            if let Some(expected) = digest {
+                let file = PathBuf::from(path);
+                let actual = self.blocking.run(move || file_digest(&file)).await?;
+                verify(&expected, &actual)?;
+            }
+            return Ok(path.to_string());
+        }
+
+        // Only one download per URL at a time: the other requests wait and then hit the cache
+        let lock = self
+            .downloads
+            .lock()
+            .unwrap()
+            .entry(image.to_string())
+            .or_default()
+            .clone();
+        let _guard = lock.lock().await;
+
+        if let Some(path) = self.lookup(image, digest.as_deref()) {
+            info!("Image cache hit: {}", image);
+            return Ok(path.to_string_lossy().to_string());
+        }
+
+        info!("Image cache miss, downloading: {}", image);
+        let path = self.download(image, digest.as_deref()).await?;
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    /// Get the number of bytes used by the images in the cache
+    pub fn size(&self) -> u64 {
+        self.entries.lock().unwrap().values().map(|e| e.size).sum()
+    }
+
+    /// Get the images in the cache, by the URLs they were fetched from, with their digest
+    /// and size
+    pub fn images(&self) -> Vec<(String, String, u64)> {
+        let urls = self.urls.lock().unwrap().clone();
+        let entries = self.entries.lock().unwrap();
+        urls.into_iter()
+            .filter_map(|(url, digest)| {
+                let size = entries.get(&digest)?.size;
+                Some((url, digest, size))
+            })
+            .collect()
+    }
+
+    /// Check whether the image served by `url`, or with the content of `digest`, is in
+    /// the cache, without fetching it
+    pub fn contains(&self, url: &str, digest: Option<&str>) -> bool {
+        let entries = self.entries.lock().unwrap();
+        match digest.map(normalize_digest) {
+            Some(digest) => entries.contains_key(&digest),
+            None => self
+                .urls
+                .lock()
+                .unwrap()
+                .get(url)
+                .is_some_and(|digest| entries.contains_key(digest)),
+        }
+    }
+
+    /// Look for a cached copy of the image served by `url`
+    fn lookup(&self, url: &str, digest: Option<&str>) -> Option<PathBuf> {
+        let cached = self.urls.lock().unwrap().get(url).cloned()?;
+        if digest.is_some_and(|digest| digest != cached) {
+            // The expected content changed, fetch it again
+            return None;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(&cached)?;
+        if !entry.path.exists() {
+            entries.remove(&cached);
+            return None;
+        }
+        entry.last_used = Instant::now();
+        Some(entry.path.clone())
+    }
+
+    /// Download the image served by `url` in the cache
+    async fn download(&self, url: &str, digest: Option<&str>) -> Result<PathBuf, ImageCacheError> {
+        fs::create_dir_all(&self.dir)?;
+        let part = self.dir.join(format!(".{}.part", uuid::Uuid::new_v4()));
+
+        let result = self.fetch(url, &part).await;
+        let (actual, size) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                let _ = fs::remove_file(&part);
+                return Err(e);
+            }
+        };
+        if let Some(expected) = digest {
+            if let Err(e) = verify(expected, &actual) {
+                let _ = fs::remove_file(&part);
+                return Err(e);
+            }
+        }
+
+        let path = self.dir.join(format!("{}.img", actual));
+        {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.contains_key(&actual) && path.exists() {
+                // Same content already cached from another URL
+                let _ = fs::remove_file(&part);
+            } else {
+                fs::rename(&part, &path)?;
+            }
+            entries.insert(
+                actual.clone(),
+                CacheEntry {
+                    path: path.clone(),
+                    size,
+                    last_used: Instant::now(),
+                },
+            );
+        }
+        self.urls
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), actual.clone());
+
+        self.evict(&actual);
+        Ok(path)
+    }
+
+    /// Stream the content of `url` into `path`, returning its digest and size
+    async fn fetch(&self, url: &str, path: &Path) -> Result<(String, u64), ImageCacheError> {
+        let mut response = self
+            .client
+            .client()
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| ImageCacheError::Download(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(ImageCacheError::Download(format!(
+                "{} returned {}",
+                url,
+                response.status()
+            )));
+        }
+
+        let mut file = fs::File::create(path)?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        while let Some(chunk) = response.next().await {
+            let chunk = chunk.map_err(|e| ImageCacheError::Download(e.to_string()))?;
+            hasher.update(&chunk);
+            file.write_all(&chunk)?;
+            size += chunk.len() as u64;
+        }
+        file.flush()?;
+        Ok((hex::encode(hasher.finalize()), size))
+    }
+
+    /// Evict the least recently used images until the cache fits its size cap.
+    /// The image identified by `keep` is never evicted.
+    fn evict(&self, keep: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        let mut total: u64 = entries.values().map(|e| e.size).sum();
+        while total > self.max_size {
+            let lru = entries
+                .iter()
+                .filter(|(digest, _)| digest.as_str() != keep)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(digest, _)| digest.clone());
+            let Some(lru) = lru else {
+                warn!("Image cache is over its size cap, but nothing can be evicted");
+                break;
+            };
+            let entry = entries.remove(&lru).unwrap();
+            info!("Evicting image {} from the cache", entry.path.display());
+            if let Err(e) = fs::remove_file(&entry.path) {
+                warn!("Failed to remove {}: {}", entry.path.display(), e);
+            }
+            total -= entry.size;
+            self.urls.lock().unwrap().retain(|_, digest| *digest != lru);
+        }
+    }
+}
+
+/// Normalize a digest, accepting an optional `sha256:` prefix
+pub(crate) fn normalize_digest(digest: &str) -> String {
+    digest
+        .strip_prefix("sha256:")
+        .unwrap_or(digest)
+        .to_lowercase()
+}
+
+/// Check that the actual digest matches the expected one
+fn verify(expected: &str, actual: &str) -> Result<(), ImageCacheError> {
+    if expected != actual {
+        return Err(ImageCacheError::DigestMismatch {
+            expected: expected.to_string(),
+            actual: actual.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Compute the SHA-256 digest of a local file
+pub(crate) fn file_digest(path: &Path) -> Result<String, io::Error> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use actix_web::{get, web, App, HttpResponse, HttpServer, Responder};
+
+    use super::*;
+
+    /// Requests served by the fixture, per image
+    struct Hits(Mutex<HashMap<String, usize>>);
+
+    #[get("/{name}")]
+    async fn serve(name: web::Path<String>, hits: web::Data<Hits>) -> impl Responder {
+        *hits.0.lock().unwrap().entry(name.clone()).or_default() += 1;
+        match name.as_str() {
+            "missing" => HttpResponse::NotFound().finish(),
+            // Same content as "a", served from another URL
+            "alias" => HttpResponse::Ok().body(vec![b'a'; 1024]),
+            name => HttpResponse::Ok().body(name.repeat(1024)),
+        }
+    }
+
+    /// Start a local HTTP server serving images, returning its address
+    fn fixture(hits: web::Data<Hits>) -> String {
+        let server = HttpServer::new(move || App::new().app_data(hits.clone()).service(serve))
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        format!("http://{}", address)
+    }
+
+    fn cache(max_size: u64) -> (ImageCache, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("spare-images-{}", uuid::Uuid::new_v4()));
+        (ImageCache::new(dir.clone(), max_size), dir)
+    }
+
+    fn sha256(data: &[u8]) -> String {
+        hex::encode(Sha256::digest(data))
+    }
+
+    #[actix_web::test]
+    async fn test_hit_and_miss() {
+        let hits = web::Data::new(Hits(Mutex::new(HashMap::new())));
+        let base = fixture(hits.clone());
+        let (cache, dir) = cache(1024 * 1024);
+
+        let url = format!("{}/a", base);
+        let path = cache.resolve(&url, None).await.unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![b'a'; 1024]);
+        let again = cache
+            .resolve(&url, Some(&sha256(&[b'a'; 1024])))
+            .await
+            .unwrap();
+        assert_eq!(path, again);
+        assert_eq!(hits.0.lock().unwrap()["a"], 1);
+
+        // Same content from another URL is stored once
+        let alias = cache
+            .resolve(&format!("{}/alias", base), None)
+            .await
+            .unwrap();
+        assert_eq!(path, alias);
+        assert_eq!(cache.size(), 1024);
+
+        assert!(matches!(
+            cache.resolve(&format!("{}/missing", base), None).await,
+            Err(ImageCacheError::Download(_))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_digest_mismatch() {
+        let hits = web::Data::new(Hits(Mutex::new(HashMap::new())));
+        let base = fixture(hits.clone());
+        let (cache, dir) = cache(1024 * 1024);
+
+        let url = format!("{}/b", base);
+        assert!(matches!(
+            cache.resolve(&url, Some(&sha256(b"other"))).await,
+            Err(ImageCacheError::DigestMismatch { .. })
+        ));
+        assert_eq!(cache.size(), 0);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_coalescing() {
+        let hits = web::Data::new(Hits(Mutex::new(HashMap::new())));
+        let base = fixture(hits.clone());
+        let (cache, dir) = cache(1024 * 1024);
+
+        let url = format!("{}/c", base);
+        let (first, second) = futures::join!(cache.resolve(&url, None), cache.resolve(&url, None));
+        assert_eq!(first.unwrap(), second.unwrap());
+        assert_eq!(hits.0.lock().unwrap()["c"], 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_eviction() {
+        let hits = web::Data::new(Hits(Mutex::new(HashMap::new())));
+        let base = fixture(hits.clone());
+        // Room for two images only
+        let (cache, dir) = cache(2 * 1024);
+
+        let a = cache.resolve(&format!("{}/a", base), None).await.unwrap();
+        let b = cache.resolve(&format!("{}/b", base), None).await.unwrap();
+        // Use "a" again, so "b" becomes the least recently used
+        cache.resolve(&format!("{}/a", base), None).await.unwrap();
+        let c = cache.resolve(&format!("{}/c", base), None).await.unwrap();
+
+        assert!(Path::new(&a).exists());
+        assert!(!Path::new(&b).exists());
+        assert!(Path::new(&c).exists());
+        assert_eq!(cache.size(), 2 * 1024);
+
+        // "b" is downloaded again
+        cache.resolve(&format!("{}/b", base), None).await.unwrap();
+        assert_eq!(hits.0.lock().unwrap()["b"], 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_local_paths() {
+        let (cache, dir) = cache(1024);
+        fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("local");
+        fs::write(&image, b"local").unwrap();
+        let path = image.to_string_lossy().to_string();
+
+        assert_eq!(cache.resolve(&path, None).await.unwrap(), path);
+        assert_eq!(
+            cache
+                .resolve(&format!("file://{}", path), Some(&sha256(b"local")))
+                .await
+                .unwrap(),
+            path
+        );
+        assert!(cache.resolve(&path, Some(&sha256(b"other"))).await.is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
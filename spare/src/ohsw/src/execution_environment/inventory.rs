@@ -0,0 +1,247 @@
+//! Inventory of the images present on the node.
+//! The offloads probe the cpus and the memory of the neighbors, not whether they have the
+//! image of the request, so a request could be forwarded to a node that then fails it.
+//! The node lists the images of its image directories, with their digests, and the images
+//! it downloaded in its cache, and tells on `/resources?image=...` whether it has the
+//! image of a request. The directories are scanned again periodically, hashing only the
+//! files that changed; the cache is looked up as it is, so a download counts right away.
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use super::image_cache::{file_digest, normalize_digest, ImageCache};
+use crate::utils::blocking::BlockingPool;
+
+/// Where an image of the node comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageSource {
+    /// A file of an image directory
+    Directory,
+    /// A download of the image cache
+    Cache,
+}
+
+/// An image present on the node
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageEntry {
+    /// Path of the image, or the URL it was downloaded from
+    pub image: String,
+    /// SHA-256 digest of the image (hex encoded)
+    pub digest: String,
+    /// Size of the image (in bytes)
+    pub size: u64,
+    pub source: ImageSource,
+}
+
+/// The images of the node, as seen by the operator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Images {
+    /// When the image directories were last scanned
+    pub scanned_at: Option<DateTime<Utc>>,
+    pub images: Vec<ImageEntry>,
+}
+
+/// A file of an image directory, as last scanned
+#[derive(Debug, Clone)]
+struct Scanned {
+    digest: String,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Default)]
+struct Scan {
+    files: BTreeMap<PathBuf, Scanned>,
+    at: Option<DateTime<Utc>>,
+}
+
+/// Images of the image directories of the node
+#[derive(Debug, Default)]
+pub struct ImageInventory {
+    dirs: Vec<PathBuf>,
+    scan: RwLock<Scan>,
+}
+
+impl ImageInventory {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        Self {
+            dirs,
+            scan: RwLock::default(),
+        }
+    }
+
+    /// Scan the image directories again, hashing the new and modified files.
+    /// Blocks while hashing, the node runs it on the blocking pool.
+    /// # Returns
+    /// * The number of images found
+    pub fn refresh(&self) -> usize {
+        let previous = self.scan.read().unwrap().files.clone();
+        let mut files = BTreeMap::new();
+        for dir in &self.dirs {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("Cannot scan the image directory {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                // Partial downloads and other hidden files are not images
+                if entry.file_name().to_string_lossy().starts_with('.') {
+                    continue;
+                }
+                match scan_file(&path, previous.get(&path)) {
+                    Ok(Some(scanned)) => {
+                        files.insert(path, scanned);
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("Cannot read the image {}: {}", path.display(), e),
+                }
+            }
+        }
+        let found = files.len();
+        *self.scan.write().unwrap() = Scan {
+            files,
+            at: Some(Utc::now()),
+        };
+        found
+    }
+
+    /// Check whether the node has an image, without fetching it: an image with the content
+    /// of `digest` when given, otherwise the file at the path of `image` or the download
+    /// of its URL
+    pub fn contains(&self, image: &str, digest: Option<&str>, cache: &ImageCache) -> bool {
+        if let Some(digest) = digest.map(normalize_digest) {
+            let scan = self.scan.read().unwrap();
+            return scan.files.values().any(|file| file.digest == digest)
+                || cache.contains(image, Some(&digest));
+        }
+        if image.starts_with("http://") || image.starts_with("https://") {
+            return cache.contains(image, None);
+        }
+        let path = image.strip_prefix("file://").unwrap_or(image);
+        Path::new(path).is_file()
+    }
+
+    /// List the images of the node, those of the directories as last scanned
+    pub fn list(&self, cache: &ImageCache) -> Images {
+        let scan = self.scan.read().unwrap();
+        let mut images: Vec<ImageEntry> = scan
+            .files
+            .iter()
+            .map(|(path, file)| ImageEntry {
+                image: path.display().to_string(),
+                digest: file.digest.clone(),
+                size: file.size,
+                source: ImageSource::Directory,
+            })
+            .collect();
+        let mut cached: Vec<ImageEntry> = cache
+            .images()
+            .into_iter()
+            .map(|(url, digest, size)| ImageEntry {
+                image: url,
+                digest,
+                size,
+                source: ImageSource::Cache,
+            })
+            .collect();
+        cached.sort_by(|a, b| a.image.cmp(&b.image));
+        images.append(&mut cached);
+        Images {
+            scanned_at: scan.at,
+            images,
+        }
+    }
+}
+
+/// Scan a file of an image directory, reusing its digest if it did not change
+fn scan_file(path: &Path, previous: Option<&Scanned>) -> io::Result<Option<Scanned>> {
+    let metadata = fs::metadata(path)?;
+    if !metadata.is_file() {
+        return Ok(None);
+    }
+    let modified = metadata.modified().ok();
+    if let Some(previous) = previous.filter(|previous| {
+        previous.size == metadata.len()
+            && previous.modified.is_some()
+            && previous.modified == modified
+    }) {
+        return Ok(Some(previous.clone()));
+    }
+    Ok(Some(Scanned {
+        digest: file_digest(path)?,
+        size: metadata.len(),
+        modified,
+    }))
+}
+
+/// Scan the image directories every `interval`
+pub async fn run(inventory: Arc<ImageInventory>, blocking: BlockingPool, interval: Duration) {
+    loop {
+        let scanned = inventory.clone();
+        let found = blocking.run(move || scanned.refresh()).await;
+        info!("Found {} images in the image directories", found);
+        actix_web::rt::time::sleep(interval).await;
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_inventory() {
+        let dir = std::env::temp_dir().join(format!("spare-inventory-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("resize.img"), "resize").unwrap();
+        fs::write(dir.join(".download.part"), "partial").unwrap();
+        let cache = ImageCache::new(dir.join("cache"), 1 << 20);
+        let inventory = ImageInventory::new(vec![dir.clone(), dir.join("missing")]);
+        assert_eq!(inventory.refresh(), 1);
+
+        let resize = dir.join("resize.img").display().to_string();
+        let listed = inventory.list(&cache);
+        assert!(listed.scanned_at.is_some());
+        assert_eq!(
+            listed.images,
+            vec![ImageEntry {
+                image: resize.clone(),
+                digest: file_digest(&dir.join("resize.img")).unwrap(),
+                size: 6,
+                source: ImageSource::Directory,
+            }]
+        );
+
+        // By path, by file URL, or by content wherever it is
+        assert!(inventory.contains(&resize, None, &cache));
+        assert!(inventory.contains(&format!("file://{resize}"), None, &cache));
+        let digest = format!("sha256:{}", listed.images[0].digest.to_uppercase());
+        assert!(inventory.contains("http://registry/resize.img", Some(&digest), &cache));
+        assert!(!inventory.contains(&resize, Some(&"0".repeat(64)), &cache));
+        assert!(!inventory.contains("/missing/render.img", None, &cache));
+        // A remote image is present once downloaded only
+        assert!(!inventory.contains("http://registry/resize.img", None, &cache));
+
+        // A modified image is hashed again, a removed one leaves the list
+        fs::write(dir.join("resize.img"), "resized").unwrap();
+        fs::write(dir.join("render.img"), "render").unwrap();
+        assert_eq!(inventory.refresh(), 2);
+        assert!(!inventory.contains(&resize, Some(&digest), &cache));
+        fs::remove_file(dir.join("render.img")).unwrap();
+        assert_eq!(inventory.refresh(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,418 @@
+//! Lifecycle of the instances.
+//! Firecracker accepts actions in any order and fails in obscure ways when they make no
+//! sense (e.g. resuming a machine that was never started), so the state of each instance
+//! is tracked here and illegal transitions are rejected before reaching the machine.
+use std::{
+    future::{pending, Future},
+    os::unix::process::ExitStatusExt,
+    pin::pin,
+    process::ExitStatus,
+};
+
+use firepilot::machine::{FirepilotError, Machine};
+use futures::future::{select, Either};
+use serde::{Deserialize, Serialize};
+
+/// State of an instance
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceState {
+    /// The machine is configured, but it was never started
+    NotStarted,
+    /// The machine is running
+    Running,
+    /// The machine is paused, it can be resumed
+    Paused,
+    /// The machine was stopped or deleted
+    Stopped,
+    /// The machine failed to perform an operation, it can only be deleted
+    Failed,
+}
+
+impl std::fmt::Display for InstanceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InstanceState::NotStarted => write!(f, "not started"),
+            InstanceState::Running => write!(f, "running"),
+            InstanceState::Paused => write!(f, "paused"),
+            InstanceState::Stopped => write!(f, "stopped"),
+            InstanceState::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// How the process of a machine exited on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmmExit {
+    /// It exited with a code
+    Code(i32),
+    /// It was killed by a signal, e.g. 9 when OOM-killed or 11 on a segfault
+    Signal(i32),
+    /// It cannot be waited for
+    Unknown,
+}
+
+impl From<Option<ExitStatus>> for VmmExit {
+    fn from(status: Option<ExitStatus>) -> Self {
+        match status {
+            Some(status) => match (status.code(), status.signal()) {
+                (Some(code), _) => VmmExit::Code(code),
+                (None, Some(signal)) => VmmExit::Signal(signal),
+                (None, None) => VmmExit::Unknown,
+            },
+            None => VmmExit::Unknown,
+        }
+    }
+}
+
+impl std::fmt::Display for VmmExit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmmExit::Code(code) => write!(f, "exit code {}", code),
+            VmmExit::Signal(signal) => write!(f, "killed by signal {}", signal),
+            VmmExit::Unknown => write!(f, "unknown exit status"),
+        }
+    }
+}
+
+/// Error types for the lifecycle of an instance
+#[derive(Debug, thiserror::Error)]
+pub enum LifecycleError {
+    /// The operation is not allowed in the current state of the instance
+    #[error("Cannot {operation} an instance that is {state}")]
+    IllegalTransition {
+        state: InstanceState,
+        operation: &'static str,
+    },
+    /// The machine failed to perform the operation
+    #[error("Machine error: {0}")]
+    Machine(#[from] FirepilotError),
+}
+
+/// Operations of the machine behind an instance
+pub(crate) trait Vmm {
+    async fn start(&self) -> Result<(), FirepilotError>;
+    async fn stop(&self) -> Result<(), FirepilotError>;
+    async fn pause(&self) -> Result<(), FirepilotError>;
+    async fn resume(&self) -> Result<(), FirepilotError>;
+    async fn kill(&mut self) -> Result<(), FirepilotError>;
+    async fn is_running(&self) -> bool;
+    async fn wait_exit(&mut self) -> Option<ExitStatus>;
+}
+
+impl Vmm for Machine {
+    async fn start(&self) -> Result<(), FirepilotError> {
+        Machine::start(self).await
+    }
+    async fn stop(&self) -> Result<(), FirepilotError> {
+        Machine::stop(self).await
+    }
+    async fn pause(&self) -> Result<(), FirepilotError> {
+        Machine::pause(self).await
+    }
+    async fn resume(&self) -> Result<(), FirepilotError> {
+        Machine::resume(self).await
+    }
+    async fn kill(&mut self) -> Result<(), FirepilotError> {
+        Machine::kill(self).await
+    }
+    async fn is_running(&self) -> bool {
+        Machine::is_running(self).await
+    }
+    async fn wait_exit(&mut self) -> Option<ExitStatus> {
+        Machine::wait_exit(self).await
+    }
+}
+
+/// A machine together with the bookkeeping of its state
+#[derive(Debug)]
+pub(crate) struct Lifecycle<M> {
+    pub(crate) machine: M,
+    state: InstanceState,
+}
+
+impl<M: Vmm> Lifecycle<M> {
+    /// Track a machine that was just created
+    pub(crate) fn new(machine: M) -> Self {
+        Self {
+            machine,
+            state: InstanceState::NotStarted,
+        }
+    }
+
+    /// Get the state of the instance.
+    /// A machine whose process is gone while it should be alive has failed.
+    pub(crate) async fn state(&self) -> InstanceState {
+        match self.state {
+            InstanceState::NotStarted | InstanceState::Running | InstanceState::Paused
+                if !self.machine.is_running().await =>
+            {
+                InstanceState::Failed
+            }
+            state => state,
+        }
+    }
+
+    /// Check that `operation` is allowed in the current state
+    fn check(
+        &self,
+        operation: &'static str,
+        allowed: &[InstanceState],
+    ) -> Result<(), LifecycleError> {
+        if allowed.contains(&self.state) {
+            Ok(())
+        } else {
+            Err(LifecycleError::IllegalTransition {
+                state: self.state,
+                operation,
+            })
+        }
+    }
+
+    /// Move to `next` if the operation succeeded, to `Failed` otherwise
+    fn complete(
+        &mut self,
+        result: Result<(), FirepilotError>,
+        next: InstanceState,
+    ) -> Result<(), LifecycleError> {
+        match result {
+            Ok(_) => {
+                self.state = next;
+                Ok(())
+            }
+            Err(e) => {
+                self.state = InstanceState::Failed;
+                Err(LifecycleError::Machine(e))
+            }
+        }
+    }
+
+    pub(crate) async fn start(&mut self) -> Result<(), LifecycleError> {
+        self.check("start", &[InstanceState::NotStarted])?;
+        let result = self.machine.start().await;
+        self.complete(result, InstanceState::Running)
+    }
+
+    pub(crate) async fn stop(&mut self) -> Result<(), LifecycleError> {
+        self.check("stop", &[InstanceState::Running])?;
+        let result = self.machine.stop().await;
+        self.complete(result, InstanceState::Stopped)
+    }
+
+    pub(crate) async fn pause(&mut self) -> Result<(), LifecycleError> {
+        self.check("pause", &[InstanceState::Running])?;
+        let result = self.machine.pause().await;
+        self.complete(result, InstanceState::Paused)
+    }
+
+    pub(crate) async fn resume(&mut self) -> Result<(), LifecycleError> {
+        self.check("resume", &[InstanceState::Paused])?;
+        let result = self.machine.resume().await;
+        self.complete(result, InstanceState::Running)
+    }
+
+    /// Wait for the process of a machine that should be alive to exit, which is a crash:
+    /// the instance is failed afterwards. Never returns for a stopped or failed instance.
+    pub(crate) async fn crashed(&mut self) -> VmmExit {
+        if matches!(self.state, InstanceState::Stopped | InstanceState::Failed) {
+            return pending().await;
+        }
+        let exit = VmmExit::from(self.machine.wait_exit().await);
+        self.state = InstanceState::Failed;
+        exit
+    }
+
+    /// Run `operation`, unless the process of the machine crashes first
+    pub(crate) async fn watch<T>(
+        &mut self,
+        operation: impl Future<Output = T>,
+    ) -> Result<T, VmmExit> {
+        match select(pin!(operation), pin!(self.crashed())).await {
+            Either::Left((result, _)) => Ok(result),
+            Either::Right((exit, _)) => Err(exit),
+        }
+    }
+
+    /// Kill the machine, allowed in any state as long as the machine still exists
+    pub(crate) async fn delete(&mut self) -> Result<(), LifecycleError> {
+        if !self.machine.is_running().await {
+            return Err(LifecycleError::IllegalTransition {
+                state: self.state().await,
+                operation: "delete",
+            });
+        }
+        let result = self.machine.kill().await;
+        self.complete(result, InstanceState::Stopped)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Machine that records the operations it receives
+    #[derive(Default)]
+    struct MockMachine {
+        alive: bool,
+        fail: bool,
+        /// Raw wait status of the process once it exits, it runs forever if not set
+        exit: Option<i32>,
+        operations: std::sync::Mutex<Vec<&'static str>>,
+    }
+
+    impl MockMachine {
+        fn run(&self, operation: &'static str) -> Result<(), FirepilotError> {
+            self.operations.lock().unwrap().push(operation);
+            match self.fail {
+                true => Err(FirepilotError::Execute(operation.to_string())),
+                false => Ok(()),
+            }
+        }
+    }
+
+    impl Vmm for MockMachine {
+        async fn start(&self) -> Result<(), FirepilotError> {
+            self.run("start")
+        }
+        async fn stop(&self) -> Result<(), FirepilotError> {
+            self.run("stop")
+        }
+        async fn pause(&self) -> Result<(), FirepilotError> {
+            self.run("pause")
+        }
+        async fn resume(&self) -> Result<(), FirepilotError> {
+            self.run("resume")
+        }
+        async fn kill(&mut self) -> Result<(), FirepilotError> {
+            self.run("kill")?;
+            self.alive = false;
+            Ok(())
+        }
+        async fn is_running(&self) -> bool {
+            self.alive
+        }
+        async fn wait_exit(&mut self) -> Option<ExitStatus> {
+            match self.exit {
+                Some(status) => Some(ExitStatus::from_raw(status)),
+                None => pending().await,
+            }
+        }
+    }
+
+    fn lifecycle(fail: bool) -> Lifecycle<MockMachine> {
+        Lifecycle::new(MockMachine {
+            alive: true,
+            fail,
+            ..Default::default()
+        })
+    }
+
+    fn is_illegal(result: Result<(), LifecycleError>, expected: InstanceState) -> bool {
+        matches!(result, Err(LifecycleError::IllegalTransition { state, .. }) if state == expected)
+    }
+
+    #[actix_web::test]
+    async fn test_transitions() {
+        let mut instance = lifecycle(false);
+        assert_eq!(instance.state().await, InstanceState::NotStarted);
+        instance.start().await.unwrap();
+        assert_eq!(instance.state().await, InstanceState::Running);
+        instance.pause().await.unwrap();
+        assert_eq!(instance.state().await, InstanceState::Paused);
+        instance.resume().await.unwrap();
+        instance.stop().await.unwrap();
+        assert_eq!(instance.state().await, InstanceState::Stopped);
+        instance.delete().await.unwrap();
+        assert_eq!(instance.state().await, InstanceState::Stopped);
+        assert_eq!(
+            *instance.machine.operations.lock().unwrap(),
+            vec!["start", "pause", "resume", "stop", "kill"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_illegal_transitions() {
+        let mut instance = lifecycle(false);
+        assert!(is_illegal(
+            instance.resume().await,
+            InstanceState::NotStarted
+        ));
+        assert!(is_illegal(
+            instance.pause().await,
+            InstanceState::NotStarted
+        ));
+        assert!(is_illegal(instance.stop().await, InstanceState::NotStarted));
+
+        instance.start().await.unwrap();
+        assert!(is_illegal(instance.start().await, InstanceState::Running));
+        assert!(is_illegal(instance.resume().await, InstanceState::Running));
+
+        instance.pause().await.unwrap();
+        assert!(is_illegal(instance.pause().await, InstanceState::Paused));
+        assert!(is_illegal(instance.stop().await, InstanceState::Paused));
+
+        instance.delete().await.unwrap();
+        assert!(is_illegal(instance.delete().await, InstanceState::Stopped));
+        assert!(is_illegal(instance.start().await, InstanceState::Stopped));
+
+        // Rejected operations never reach the machine
+        assert_eq!(
+            *instance.machine.operations.lock().unwrap(),
+            vec!["start", "pause", "kill"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_failures() {
+        let mut instance = lifecycle(true);
+        assert!(matches!(
+            instance.start().await,
+            Err(LifecycleError::Machine(_))
+        ));
+        assert_eq!(instance.state().await, InstanceState::Failed);
+        assert!(is_illegal(instance.resume().await, InstanceState::Failed));
+
+        // The process went away while the instance was supposed to be running
+        let mut instance = lifecycle(false);
+        instance.start().await.unwrap();
+        instance.machine.alive = false;
+        assert_eq!(instance.state().await, InstanceState::Failed);
+    }
+
+    #[actix_web::test]
+    async fn test_crash() {
+        use std::time::Duration;
+
+        // The process is fine, the operation completes
+        let mut instance = lifecycle(false);
+        instance.start().await.unwrap();
+        assert_eq!(instance.watch(async { 42 }).await, Ok(42));
+        assert_eq!(instance.state().await, InstanceState::Running);
+
+        // The process is killed (OOM) while the guest is awaited
+        instance.machine.exit = Some(9);
+        let guest = actix_web::rt::time::sleep(Duration::from_secs(60));
+        assert_eq!(instance.watch(guest).await, Err(VmmExit::Signal(9)));
+        assert_eq!(instance.state().await, InstanceState::Failed);
+        // It can still be deleted, to free its resources
+        instance.delete().await.unwrap();
+        assert_eq!(instance.state().await, InstanceState::Stopped);
+
+        // A process exiting with a code
+        let mut instance = lifecycle(false);
+        instance.start().await.unwrap();
+        instance.machine.exit = Some(1 << 8);
+        assert_eq!(instance.crashed().await, VmmExit::Code(1));
+        // A stopped instance never crashes
+        let mut instance = lifecycle(false);
+        instance.start().await.unwrap();
+        instance.stop().await.unwrap();
+        instance.machine.exit = Some(0);
+        assert_eq!(instance.watch(async { 42 }).await, Ok(42));
+
+        assert_eq!(VmmExit::Signal(11).to_string(), "killed by signal 11");
+        assert_eq!(VmmExit::Code(1).to_string(), "exit code 1");
+        assert_eq!(VmmExit::from(None), VmmExit::Unknown);
+    }
+}
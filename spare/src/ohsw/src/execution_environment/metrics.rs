@@ -0,0 +1,103 @@
+//! Summary of the metrics written by Firecracker.
+//! Firecracker appends a JSON object to the metrics file at every flush. Counters
+//! report the increment since the previous flush, so the summary is their sum.
+//! The phases of the cold start of an instance are timed by the node itself.
+use std::{fs, io, path::Path, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Summary of the metrics of an instance
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MetricsSummary {
+    /// Bytes received by the network interfaces
+    pub net_rx_bytes: i64,
+    /// Bytes sent by the network interfaces
+    pub net_tx_bytes: i64,
+    /// Read operations on the block devices
+    pub block_read_ops: i64,
+    /// Write operations on the block devices
+    pub block_write_ops: i64,
+    /// Exits of the vcpus caused by I/O and MMIO accesses
+    pub vcpu_exits: i64,
+}
+
+impl MetricsSummary {
+    /// Parse the content of a metrics file, lines that are not valid JSON are skipped.
+    pub fn parse(content: &str) -> Self {
+        content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+            .fold(Self::default(), |summary, metrics| {
+                let counter =
+                    |group: &str, name: &str| metrics[group][name].as_i64().unwrap_or_default();
+                Self {
+                    net_rx_bytes: summary.net_rx_bytes + counter("net", "rx_bytes_count"),
+                    net_tx_bytes: summary.net_tx_bytes + counter("net", "tx_bytes_count"),
+                    block_read_ops: summary.block_read_ops + counter("block", "read_count"),
+                    block_write_ops: summary.block_write_ops + counter("block", "write_count"),
+                    vcpu_exits: summary.vcpu_exits
+                        + counter("vcpu", "exit_io_in")
+                        + counter("vcpu", "exit_io_out")
+                        + counter("vcpu", "exit_mmio_read")
+                        + counter("vcpu", "exit_mmio_write"),
+                }
+            })
+    }
+
+    /// Read and parse a metrics file
+    pub fn read(path: &Path) -> Result<Self, io::Error> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+}
+
+/// Time spent in each phase of the cold start of an instance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ColdStartTimings {
+    /// Creation of the machine, from the image to a configured VM
+    pub create: Duration,
+    /// Start of the machine
+    pub boot: Duration,
+    /// Wait for the guest to connect to the vsock
+    pub handshake: Duration,
+    /// Exchange of the payload and of the response with the guest
+    pub execute: Duration,
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let content = r#"{"utc_timestamp_ms":1,"net":{"rx_bytes_count":100,"tx_bytes_count":20},"block":{"read_count":3,"write_count":1},"vcpu":{"exit_io_in":5,"exit_io_out":6,"exit_mmio_read":7,"exit_mmio_write":8,"failures":0}}
+{"utc_timestamp_ms":2,"net":{"rx_bytes_count":50,"tx_bytes_count":0},"block":{"read_count":0,"write_count":4},"vcpu":{"exit_io_in":1,"exit_io_out":0,"exit_mmio_read":0,"exit_mmio_write":0,"failures":0}}
+"#;
+        assert_eq!(
+            MetricsSummary::parse(content),
+            MetricsSummary {
+                net_rx_bytes: 150,
+                net_tx_bytes: 20,
+                block_read_ops: 3,
+                block_write_ops: 5,
+                vcpu_exits: 27,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        // Missing groups, truncated lines and empty files do not break the summary
+        let content = r#"{"net":{"rx_bytes_count":10}}
+{"block":{"write_c"#;
+        assert_eq!(
+            MetricsSummary::parse(content),
+            MetricsSummary {
+                net_rx_bytes: 10,
+                ..Default::default()
+            }
+        );
+        assert_eq!(MetricsSummary::parse(""), MetricsSummary::default());
+    }
+}
@@ -0,0 +1,195 @@
+//! Mock execution environment, for the tests of the node.
+//! Its machines boot instantly: once started, a task of the runtime plays the guest agent,
+//! connecting to the vsock listener of the instance and speaking the guest protocol (v2)
+//! as the real agent would, on the vsock port given in its environment. Nothing is run and
+//! no privilege is needed.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use actix_web::rt::net::UnixStream;
+use firepilot::{builder::Configuration, machine::FirepilotError};
+use futures::future::{pending, LocalBoxFuture};
+use log::warn;
+
+use super::{
+    environment::{AnyVmm, ExecutionEnvironment},
+    guest::{DEFAULT_VSOCK_PORT, VSOCK_PORT_ENV},
+    lifecycle::Vmm,
+    vsock::listener_path,
+};
+use crate::{
+    net::linux::tap::{Tap, TapFactory},
+    utils::protocol::{read_frame, write_frame, Frame, FrameType, GuestMetadata},
+};
+
+/// Name the mock guest agent gives in its `Ready` frame
+pub const MOCK_AGENT: &str = "spare-mock";
+
+/// How the guest of a mock machine behaves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestBehavior {
+    /// Answer with the payload it receives
+    Echo,
+    /// Never connect to the vsock, as a guest that does not come up
+    Silent,
+}
+
+/// Environment of mock machines, all with the same guest
+#[derive(Debug, Clone)]
+pub struct MockExecutionEnvironment {
+    guest: GuestBehavior,
+    boots: Arc<AtomicUsize>,
+}
+
+impl MockExecutionEnvironment {
+    pub fn new(guest: GuestBehavior) -> Self {
+        MockExecutionEnvironment {
+            guest,
+            boots: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Number of machines started so far
+    pub fn boots(&self) -> usize {
+        self.boots.load(Ordering::SeqCst)
+    }
+}
+
+impl ExecutionEnvironment for MockExecutionEnvironment {
+    fn create(
+        &self,
+        configuration: Configuration,
+    ) -> LocalBoxFuture<'_, Result<AnyVmm, FirepilotError>> {
+        Box::pin(async move {
+            let executor = configuration.executor.ok_or_else(|| {
+                FirepilotError::Setup("No executor was provided in the configuration".to_string())
+            })?;
+            // The vsock of the instance is bound in the workspace, as with firecracker
+            let workspace = executor.chroot();
+            fs::create_dir_all(&workspace)
+                .map_err(|e| FirepilotError::Setup(format!("Cannot create workspace: {e}")))?;
+            let boot_args = configuration.kernel.and_then(|kernel| kernel.boot_args);
+            Ok(AnyVmm::Mock(MockVmm {
+                workspace,
+                vsock_port: vsock_port(boot_args.as_deref().unwrap_or_default()),
+                guest: self.guest,
+                boots: self.boots.clone(),
+                alive: true,
+            }))
+        })
+    }
+}
+
+/// Tap interfaces of the mock machines, only named: nothing is created on the host
+#[derive(Debug, Default)]
+pub struct MockTaps;
+
+impl TapFactory for MockTaps {
+    fn create(&self, name: &str, _bridge: &str) -> Result<Tap, nix::Error> {
+        Ok(Tap::from_name(name))
+    }
+
+    fn remove(&self, _tap: &Tap) -> Result<(), nix::Error> {
+        Ok(())
+    }
+}
+
+/// Machine of the mock environment
+#[derive(Debug)]
+pub struct MockVmm {
+    workspace: PathBuf,
+    /// Port the guest connects to, as the real agent reads it from its environment
+    vsock_port: u32,
+    guest: GuestBehavior,
+    boots: Arc<AtomicUsize>,
+    alive: bool,
+}
+
+impl MockVmm {
+    pub fn get_vsock_path(&self) -> String {
+        self.workspace
+            .join("vsock.sock")
+            .to_string_lossy()
+            .to_string()
+    }
+
+    pub fn get_metrics_path(&self) -> PathBuf {
+        self.workspace.join("metrics.json")
+    }
+}
+
+impl Vmm for MockVmm {
+    async fn start(&self) -> Result<(), FirepilotError> {
+        self.boots.fetch_add(1, Ordering::SeqCst);
+        if self.guest == GuestBehavior::Echo {
+            let path = listener_path(&self.get_vsock_path(), self.vsock_port);
+            actix_web::rt::spawn(async move {
+                if let Err(e) = echo(&path).await {
+                    warn!("The mock guest on {} failed: {}", path.display(), e);
+                }
+            });
+        }
+        Ok(())
+    }
+    async fn stop(&self) -> Result<(), FirepilotError> {
+        Ok(())
+    }
+    async fn pause(&self) -> Result<(), FirepilotError> {
+        Ok(())
+    }
+    async fn resume(&self) -> Result<(), FirepilotError> {
+        Ok(())
+    }
+    async fn kill(&mut self) -> Result<(), FirepilotError> {
+        self.alive = false;
+        match fs::remove_dir_all(&self.workspace) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(FirepilotError::Setup(format!(
+                "Cannot remove workspace: {e}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+    async fn is_running(&self) -> bool {
+        self.alive
+    }
+    async fn wait_exit(&mut self) -> Option<ExitStatus> {
+        // A mock machine never crashes
+        pending().await
+    }
+}
+
+/// Vsock port given to the guest on its command line, or the default one
+fn vsock_port(boot_args: &str) -> u32 {
+    let key = format!("env.{}=", VSOCK_PORT_ENV);
+    boot_args
+        .split_whitespace()
+        .find_map(|param| param.strip_prefix(&key)?.parse().ok())
+        .unwrap_or(DEFAULT_VSOCK_PORT)
+}
+
+/// Play the guest agent: announce it is ready, then answer with the payload
+async fn echo(path: &Path) -> io::Result<()> {
+    let mut stream = UnixStream::connect(path).await?;
+    let metadata = GuestMetadata {
+        agent: MOCK_AGENT.to_string(),
+        function: None,
+    };
+    let ready = Frame::new(FrameType::Ready, serde_json::to_vec(&metadata)?);
+    write_frame(&mut stream, &ready, 1000)
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let payload = read_frame(&mut stream, 1000)
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let result = Frame::new(FrameType::Result, payload.body);
+    write_frame(&mut stream, &result, 1000)
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))
+}
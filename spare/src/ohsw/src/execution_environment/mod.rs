@@ -1,3 +1,17 @@
 //! Execution environment module for SPARE project.
 //! This module contains the wrappers around supported execution environments for SPARE.
+pub mod boot_args;
+pub mod cgroup;
+pub mod environment;
 pub mod firecracker;
+pub mod guest;
+pub mod image_cache;
+pub mod inventory;
+pub mod lifecycle;
+pub mod metrics;
+pub mod mock;
+pub mod overlay;
+pub mod registry;
+pub mod simulate;
+pub mod vsock;
+pub mod workspaces;
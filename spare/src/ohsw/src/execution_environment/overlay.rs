@@ -0,0 +1,141 @@
+//! Per-instance copies of the function images.
+//! Each instance boots from its own copy-on-write overlay of the function image, so
+//! concurrent instances of the same function cannot corrupt each other's filesystem
+//! and the golden image is never modified.
+use std::{
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+};
+
+use log::{error, warn};
+
+/// How the function image is mounted in the instances
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageMode {
+    /// All the instances share the function image, mounted read-only
+    SharedRo,
+    /// Each instance boots from its own copy-on-write overlay of the image
+    Overlay,
+}
+
+impl FromStr for ImageMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "shared-ro" => Ok(ImageMode::SharedRo),
+            "overlay" => Ok(ImageMode::Overlay),
+            _ => Err(format!("Unknown image mode: {}", s)),
+        }
+    }
+}
+
+/// A per-instance copy of a function image, stored under the working directory.
+/// The copy is a reflink on filesystems that support it (btrfs, xfs) and a sparse
+/// copy otherwise.
+#[derive(Debug)]
+pub struct Overlay {
+    path: PathBuf,
+}
+
+impl Overlay {
+    /// Create a new overlay of `image` in `workdir`, named after `name`.
+    pub fn create(image: &Path, workdir: &Path, name: &str) -> Result<Self, io::Error> {
+        fs::create_dir_all(workdir)?;
+        let path = workdir.join(format!("{}.img", name));
+
+        // Let cp pick the cheapest strategy: reflink if supported, sparse copy otherwise
+        let status = Command::new("cp")
+            .arg("--reflink=auto")
+            .arg("--sparse=always")
+            .arg(image)
+            .arg(&path)
+            .status();
+        match status {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                warn!("cp exited with {}, falling back to a full copy", status);
+                fs::copy(image, &path)?;
+            }
+            Err(e) => {
+                warn!("Cannot run cp ({}), falling back to a full copy", e);
+                fs::copy(image, &path)?;
+            }
+        }
+
+        Ok(Self { path })
+    }
+
+    /// Get the path of the overlay
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the space actually allocated on disk by the overlay (in bytes)
+    pub fn disk_usage(&self) -> u64 {
+        fs::metadata(&self.path)
+            .map(|m| m.blocks() * 512)
+            .unwrap_or(0)
+    }
+
+    /// Delete the overlay
+    pub fn remove(&self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            error!("Failed to remove overlay {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Get the space allocated on disk by all the overlays in `workdir` (in bytes)
+pub fn disk_usage(workdir: &Path) -> u64 {
+    let entries = match fs::read_dir(workdir) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.blocks() * 512)
+        .sum()
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_mode() {
+        assert_eq!(ImageMode::from_str("shared-ro"), Ok(ImageMode::SharedRo));
+        assert_eq!(ImageMode::from_str("overlay"), Ok(ImageMode::Overlay));
+        assert!(ImageMode::from_str("rw").is_err());
+    }
+
+    #[test]
+    fn test_overlay() {
+        let workdir = std::env::temp_dir().join(format!("spare-overlay-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&workdir).unwrap();
+        let image = workdir.join("image");
+        fs::write(&image, vec![42; 64 * 1024]).unwrap();
+
+        let overlays = workdir.join("overlays");
+        let overlay = Overlay::create(&image, &overlays, "instance").unwrap();
+        assert!(overlay.path().exists());
+        assert_eq!(fs::read(overlay.path()).unwrap(), fs::read(&image).unwrap());
+        assert_eq!(disk_usage(&overlays), overlay.disk_usage());
+
+        // Writing in the overlay must not touch the golden image
+        fs::write(overlay.path(), b"corrupted").unwrap();
+        assert_eq!(fs::read(&image).unwrap(), vec![42; 64 * 1024]);
+
+        overlay.remove();
+        assert!(!overlay.path().exists());
+        assert_eq!(disk_usage(&overlays), 0);
+
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+}
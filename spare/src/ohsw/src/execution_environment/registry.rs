@@ -0,0 +1,342 @@
+//! Registry of the live instances.
+//! An instance is owned by the task serving its request, on its stack, so the other tasks
+//! cannot reach it. Each instance is registered when it is created, by the id of its
+//! workspace, and the other tasks find it by id or by image and send it lifecycle
+//! operations, which the owning task performs while it waits on the instance. The
+//! registration is dropped with the instance, on every teardown path including panics.
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+
+use super::lifecycle::LifecycleError;
+
+/// Lifecycle operation sent to an instance from another task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Control {
+    Pause,
+    Resume,
+    Stop,
+    Delete,
+}
+
+impl fmt::Display for Control {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Control::Pause => write!(f, "pause"),
+            Control::Resume => write!(f, "resume"),
+            Control::Stop => write!(f, "stop"),
+            Control::Delete => write!(f, "delete"),
+        }
+    }
+}
+
+/// Operation waiting to be performed by the owner of the instance, with where to answer
+pub type ControlRequest = (Control, oneshot::Sender<Result<(), LifecycleError>>);
+
+/// Error of an operation sent to an instance
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("No live instance {0}")]
+    Unknown(String),
+    /// The instance was torn down before performing the operation
+    #[error("Instance {0} was torn down")]
+    Gone(String),
+    #[error("Cannot {0} the instance: {1}")]
+    Lifecycle(Control, #[source] LifecycleError),
+}
+
+/// A live instance, as seen from the other tasks
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LiveInstance {
+    /// Id of the instance, the name of its workspace
+    pub id: String,
+    pub function: String,
+    pub image: String,
+    pub registered_at: DateTime<Utc>,
+}
+
+struct Entry {
+    instance: LiveInstance,
+    control: mpsc::UnboundedSender<ControlRequest>,
+}
+
+#[derive(Default)]
+struct Instances {
+    by_id: HashMap<String, Entry>,
+    /// Ids of the instances of each image
+    by_image: HashMap<String, BTreeSet<String>>,
+}
+
+/// Registry of the live instances, shared by the tasks of the node
+#[derive(Default)]
+pub struct InstanceRegistry {
+    instances: Mutex<Instances>,
+}
+
+impl InstanceRegistry {
+    /// Register a new instance, until the registration is dropped
+    pub fn register(self: &Arc<Self>, id: &str, function: &str, image: &str) -> Registration {
+        let (control, requests) = mpsc::unbounded_channel();
+        let instance = LiveInstance {
+            id: id.to_string(),
+            function: function.to_string(),
+            image: image.to_string(),
+            registered_at: Utc::now(),
+        };
+        let mut instances = self.instances.lock().unwrap();
+        instances
+            .by_image
+            .entry(image.to_string())
+            .or_default()
+            .insert(id.to_string());
+        instances
+            .by_id
+            .insert(id.to_string(), Entry { instance, control });
+        Registration {
+            registry: self.clone(),
+            id: id.to_string(),
+            requests,
+        }
+    }
+
+    fn deregister(&self, id: &str) {
+        let mut instances = self.instances.lock().unwrap();
+        if let Some(entry) = instances.by_id.remove(id) {
+            let image = entry.instance.image;
+            if let Some(ids) = instances.by_image.get_mut(&image) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    instances.by_image.remove(&image);
+                }
+            }
+        }
+    }
+
+    /// Get the live instance `id`
+    pub fn get(&self, id: &str) -> Option<LiveInstance> {
+        let instances = self.instances.lock().unwrap();
+        instances.by_id.get(id).map(|entry| entry.instance.clone())
+    }
+
+    /// Get the live instances of `image`, by id
+    pub fn by_image(&self, image: &str) -> Vec<LiveInstance> {
+        let instances = self.instances.lock().unwrap();
+        instances
+            .by_image
+            .get(image)
+            .into_iter()
+            .flatten()
+            .filter_map(|id| instances.by_id.get(id))
+            .map(|entry| entry.instance.clone())
+            .collect()
+    }
+
+    /// Get every live instance, by id
+    pub fn list(&self) -> Vec<LiveInstance> {
+        let instances = self.instances.lock().unwrap();
+        let mut list: Vec<_> = instances
+            .by_id
+            .values()
+            .map(|entry| entry.instance.clone())
+            .collect();
+        list.sort_by(|a, b| a.id.cmp(&b.id));
+        list
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.lock().unwrap().by_id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Have the instance `id` perform `control`, once its owner waits on it
+    pub async fn control(&self, id: &str, control: Control) -> Result<(), RegistryError> {
+        let (answer, answered) = oneshot::channel();
+        {
+            let instances = self.instances.lock().unwrap();
+            let entry = instances
+                .by_id
+                .get(id)
+                .ok_or_else(|| RegistryError::Unknown(id.to_string()))?;
+            entry
+                .control
+                .send((control, answer))
+                .map_err(|_| RegistryError::Gone(id.to_string()))?;
+        }
+        match answered.await {
+            Ok(result) => result.map_err(|e| RegistryError::Lifecycle(control, e)),
+            Err(_) => Err(RegistryError::Gone(id.to_string())),
+        }
+    }
+}
+
+/// Registration of a live instance, which deregisters it when dropped
+pub struct Registration {
+    registry: Arc<InstanceRegistry>,
+    id: String,
+    requests: mpsc::UnboundedReceiver<ControlRequest>,
+}
+
+impl Registration {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Wait for the next operation sent to the instance
+    pub async fn next_request(&mut self) -> Option<ControlRequest> {
+        self.requests.recv().await
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.deregister(&self.id);
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        execution_environment::{
+            firecracker::{FirecrackerBuilder, InstanceSpec},
+            lifecycle::InstanceState,
+            mock::{GuestBehavior, MockExecutionEnvironment, MockTaps},
+            overlay::ImageMode,
+        },
+        net::addresses::Addresses,
+    };
+    use std::{net::Ipv4Addr, str::FromStr};
+
+    #[test]
+    fn test_register() {
+        let registry = Arc::new(InstanceRegistry::default());
+        let a = registry.register("a", "resize", "resize.img");
+        let b = registry.register("b", "resize", "resize.img");
+        let c = registry.register("c", "render", "render.img");
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry.get("a").unwrap().function, "resize");
+        let ids = |instances: Vec<LiveInstance>| {
+            instances
+                .into_iter()
+                .map(|instance| instance.id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(registry.by_image("resize.img")), vec!["a", "b"]);
+        assert_eq!(ids(registry.list()), vec!["a", "b", "c"]);
+
+        drop(a);
+        assert!(registry.get("a").is_none());
+        assert_eq!(ids(registry.by_image("resize.img")), vec!["b"]);
+        drop((b, c));
+        assert!(registry.is_empty());
+        assert!(registry.by_image("resize.img").is_empty());
+    }
+
+    #[test]
+    fn test_panic_deregisters() {
+        let registry = Arc::new(InstanceRegistry::default());
+        let owner = registry.clone();
+        let panicked = std::thread::spawn(move || {
+            let _registration = owner.register("a", "resize", "resize.img");
+            panic!("The request failed");
+        })
+        .join();
+        assert!(panicked.is_err());
+        assert!(registry.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_concurrent_instances() {
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::from_str("192.168.30.1").unwrap(), 24).unwrap(),
+        )
+        .with_tap_factory(Arc::new(MockTaps))
+        .with_execution_environment(Arc::new(MockExecutionEnvironment::new(
+            GuestBehavior::Silent,
+        )))
+        .with_image_mode(ImageMode::SharedRo, std::env::temp_dir());
+        let builder = Arc::new(builder);
+
+        // Many requests create and tear down their instances at once
+        let created = futures::future::join_all((0..16).map(|i| {
+            let builder = builder.clone();
+            async move {
+                let image = format!("image-{}", i % 2);
+                let mut instance = builder
+                    .new_instance("test", InstanceSpec::new(image, 1, 128))
+                    .await
+                    .unwrap();
+                instance.start().await.unwrap();
+                instance
+            }
+        }))
+        .await;
+        assert_eq!(builder.instances.len(), 16);
+        assert_eq!(builder.instances.by_image("image-0").len(), 8);
+        for instance in &created {
+            let live = builder.instances.get(instance.id()).unwrap();
+            assert!(live.image.starts_with("image-"));
+        }
+
+        // Another task pauses an instance while its owner waits on it
+        let mut owned = created.into_iter();
+        let mut instance = owned.next().unwrap();
+        let id = instance.id().to_string();
+        let registry = builder.instances.clone();
+        let (_, waited) = futures::join!(
+            async {
+                registry.control(&id, Control::Pause).await.unwrap();
+                registry.control(&id, Control::Resume).await.unwrap();
+                registry.control(&id, Control::Pause).await.unwrap();
+            },
+            instance.watch(actix_web::rt::time::sleep(
+                std::time::Duration::from_millis(200)
+            ))
+        );
+        assert!(waited.is_ok());
+        assert_eq!(instance.get_status().await, InstanceState::Paused);
+        // A transition the instance cannot make is reported
+        let (refused, _) = futures::join!(
+            registry.control(&id, Control::Pause),
+            instance.watch(actix_web::rt::time::sleep(
+                std::time::Duration::from_millis(100)
+            ))
+        );
+        assert!(matches!(
+            refused,
+            Err(RegistryError::Lifecycle(Control::Pause, _))
+        ));
+
+        // Torn down before performing an operation, the instance leaves the registry
+        let (gone, _) = futures::join!(registry.control(&id, Control::Stop), async move {
+            let _ = instance.delete().await;
+            drop(instance);
+        });
+        assert!(matches!(gone, Err(RegistryError::Gone(_))));
+        assert!(registry.get(&id).is_none());
+        assert!(matches!(
+            registry.control(&id, Control::Stop).await,
+            Err(RegistryError::Unknown(_))
+        ));
+        let torn_down = futures::future::join_all(owned.map(|mut instance| async move {
+            let _ = instance.delete().await;
+        }))
+        .await;
+        assert_eq!(torn_down.len(), 15);
+        assert!(registry.is_empty());
+    }
+}
@@ -0,0 +1,473 @@
+//! Simulated execution environment, for the hosts without KVM (`--runtime simulate`).
+//! Its machines boot nothing: once started, a task of the runtime plays the guest agent on
+//! the vsock listener of the instance, as in the mock environment, and runs the function
+//! on the host. The image of the request says how:
+//! - a shared library (`.so`) is loaded in the node and its [`LIBRARY_HANDLER`] is called
+//!   on the payload;
+//! - an executable is spawned with the environment and the arguments of the guest, the
+//!   payload on its stdin: its stdout is the result, and a failure is the error of the
+//!   function, with its stderr;
+//! - any other file, e.g. a nanos image, answers with the payload.
+//!
+//! The rest of the node runs as with firecracker: resources, database, offloading and the
+//! HTTP API. What does not:
+//! - the function is not isolated, it runs as the user of the node with its files and
+//!   network, and the vcpus and memory of the instance are accounted but not enforced;
+//! - an instance boots in no time, so the cold start timings and the calibration say
+//!   nothing about firecracker;
+//! - the instances have no network: no tap is created, and guests serving HTTP cannot be
+//!   reached;
+//! - the guests speak the framed protocol (v2) only;
+//! - the images are used in place, never copied;
+//! - a payload sent through MMDS reaches the function as if it came on the vsock;
+//! - the machines write no metrics, and a crash of the function is an error of the
+//!   function, not of the machine. A crash of a shared library takes the node down.
+use std::{
+    ffi::{c_void, CStr, CString},
+    fs, io,
+    io::Write,
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    ptr, slice,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use actix_web::rt::{net::UnixStream, task::spawn_blocking};
+use firepilot::{builder::Configuration, machine::FirepilotError};
+use futures::future::{pending, LocalBoxFuture};
+use log::warn;
+
+use super::{
+    boot_args::GuestArgs,
+    environment::{AnyVmm, ExecutionEnvironment},
+    guest::{DEFAULT_VSOCK_PORT, VSOCK_PORT_ENV},
+    lifecycle::Vmm,
+    vsock::listener_path,
+};
+use crate::utils::protocol::{
+    read_frame, write_frame, Frame, FrameType, GuestMetadata, ProtocolError,
+};
+
+/// Name the simulated guest agent gives in its `Ready` frame
+pub const SIMULATED_AGENT: &str = "spare-simulate";
+
+/// Function of a shared library:
+/// `int spare_handler(const uint8_t *payload, size_t len, uint8_t **out, size_t *out_len)`.
+/// It returns 0 with the result in `out`, or the error message otherwise; `out` is then
+/// given back to [`LIBRARY_FREE`].
+pub const LIBRARY_HANDLER: &str = "spare_handler";
+
+/// Release of the output of a shared library: `void spare_free(uint8_t *out, size_t out_len)`
+pub const LIBRARY_FREE: &str = "spare_free";
+
+/// Time the node has to send the payload, once the guest is ready (in ms)
+const PAYLOAD_TIMEOUT: u64 = 1000;
+
+/// How the function of an image runs on the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Handler {
+    /// Shared library, loaded in the node
+    Library(PathBuf),
+    /// Executable, spawned for each request
+    Process(PathBuf),
+    /// Anything else, answering with the payload
+    Echo,
+}
+
+impl Handler {
+    /// Handler of the function in `image`
+    pub fn of(image: &Path) -> Self {
+        let metadata = fs::metadata(image);
+        if image.extension().is_some_and(|extension| extension == "so") {
+            Handler::Library(image.to_path_buf())
+        } else if metadata.is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0) {
+            Handler::Process(image.to_path_buf())
+        } else {
+            Handler::Echo
+        }
+    }
+
+    /// Run the function on `payload`, returning its result or its error.
+    /// The pid of a spawned executable is kept in `process` while it runs, to kill it.
+    pub fn run(
+        &self,
+        payload: Vec<u8>,
+        guest: &GuestArgs,
+        process: &Mutex<Option<u32>>,
+    ) -> Result<Vec<u8>, String> {
+        match self {
+            Handler::Library(path) => run_library(path, &payload),
+            Handler::Process(path) => run_process(path, payload, guest, process),
+            Handler::Echo => Ok(payload),
+        }
+    }
+}
+
+/// Environment of simulated machines
+#[derive(Debug, Clone, Default)]
+pub struct SimulatedExecutionEnvironment;
+
+impl ExecutionEnvironment for SimulatedExecutionEnvironment {
+    fn create(
+        &self,
+        configuration: Configuration,
+    ) -> LocalBoxFuture<'_, Result<AnyVmm, FirepilotError>> {
+        Box::pin(async move {
+            let executor = configuration.executor.ok_or_else(|| {
+                FirepilotError::Setup("No executor was provided in the configuration".to_string())
+            })?;
+            let image = configuration
+                .storage
+                .iter()
+                .find(|drive| drive.is_root_device)
+                .and_then(|drive| drive.path_on_host.clone())
+                .ok_or_else(|| FirepilotError::Setup("No root drive was provided".to_string()))?;
+            // The vsock of the instance is bound in the workspace, as with firecracker
+            let workspace = executor.chroot();
+            fs::create_dir_all(&workspace)
+                .map_err(|e| FirepilotError::Setup(format!("Cannot create workspace: {e}")))?;
+            let boot_args = configuration.kernel.and_then(|kernel| kernel.boot_args);
+            let guest = GuestArgs::parse(boot_args.as_deref().unwrap_or_default());
+            let vsock_port = guest
+                .env
+                .get(VSOCK_PORT_ENV)
+                .and_then(|port| port.parse().ok())
+                .unwrap_or(DEFAULT_VSOCK_PORT);
+            let mmds = configuration
+                .mmds_data
+                .as_ref()
+                .and_then(|data| data.pointer("/spare/payload")?.as_str())
+                .map(|payload| payload.as_bytes().to_vec());
+            Ok(AnyVmm::Simulated(SimulatedVmm {
+                workspace,
+                vsock_port,
+                handler: Handler::of(Path::new(&image)),
+                guest,
+                mmds,
+                process: Arc::new(Mutex::new(None)),
+                alive: true,
+            }))
+        })
+    }
+}
+
+/// Machine of the simulated environment
+#[derive(Debug)]
+pub struct SimulatedVmm {
+    workspace: PathBuf,
+    /// Port the guest connects to, as the real agent reads it from its environment
+    vsock_port: u32,
+    handler: Handler,
+    /// Environment and arguments of the guest, from the kernel command line
+    guest: GuestArgs,
+    /// Payload exposed through MMDS, if any
+    mmds: Option<Vec<u8>>,
+    /// Pid of the executable running the function
+    process: Arc<Mutex<Option<u32>>>,
+    alive: bool,
+}
+
+impl SimulatedVmm {
+    pub fn get_vsock_path(&self) -> String {
+        self.workspace
+            .join("vsock.sock")
+            .to_string_lossy()
+            .to_string()
+    }
+
+    pub fn get_metrics_path(&self) -> PathBuf {
+        self.workspace.join("metrics.json")
+    }
+}
+
+impl Vmm for SimulatedVmm {
+    async fn start(&self) -> Result<(), FirepilotError> {
+        let agent = Agent {
+            path: listener_path(&self.get_vsock_path(), self.vsock_port),
+            handler: self.handler.clone(),
+            guest: self.guest.clone(),
+            mmds: self.mmds.clone(),
+            process: self.process.clone(),
+        };
+        actix_web::rt::spawn(async move {
+            let path = agent.path.clone();
+            if let Err(e) = agent.serve().await {
+                warn!("The simulated guest on {} failed: {}", path.display(), e);
+            }
+        });
+        Ok(())
+    }
+    async fn stop(&self) -> Result<(), FirepilotError> {
+        Ok(())
+    }
+    async fn pause(&self) -> Result<(), FirepilotError> {
+        Ok(())
+    }
+    async fn resume(&self) -> Result<(), FirepilotError> {
+        Ok(())
+    }
+    async fn kill(&mut self) -> Result<(), FirepilotError> {
+        self.alive = false;
+        if let Some(pid) = *self.process.lock().unwrap() {
+            // SAFETY: the pid is the one of a child that was not waited for yet
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+        }
+        match fs::remove_dir_all(&self.workspace) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(FirepilotError::Setup(format!(
+                "Cannot remove workspace: {e}"
+            ))),
+            _ => Ok(()),
+        }
+    }
+    async fn is_running(&self) -> bool {
+        self.alive
+    }
+    async fn wait_exit(&mut self) -> Option<ExitStatus> {
+        // The failures of the function are reported through the protocol
+        pending().await
+    }
+}
+
+/// The guest agent of a simulated machine
+struct Agent {
+    path: PathBuf,
+    handler: Handler,
+    guest: GuestArgs,
+    mmds: Option<Vec<u8>>,
+    process: Arc<Mutex<Option<u32>>>,
+}
+
+impl Agent {
+    /// Announce the guest is ready, then answer with the result or the error of the function
+    async fn serve(self) -> io::Result<()> {
+        let mut stream = UnixStream::connect(&self.path).await?;
+        let metadata = GuestMetadata {
+            agent: SIMULATED_AGENT.to_string(),
+            function: None,
+        };
+        let ready = Frame::new(FrameType::Ready, serde_json::to_vec(&metadata)?);
+        write_frame(&mut stream, &ready, PAYLOAD_TIMEOUT)
+            .await
+            .map_err(protocol_error)?;
+        let payload = read_frame(&mut stream, PAYLOAD_TIMEOUT)
+            .await
+            .map_err(protocol_error)?;
+        // The guests read an empty payload from MMDS
+        let payload = match (payload.body.is_empty(), self.mmds) {
+            (true, Some(mmds)) => mmds,
+            (_, _) => payload.body,
+        };
+        let (handler, guest, process) = (self.handler, self.guest, self.process);
+        let result = spawn_blocking(move || handler.run(payload, &guest, &process))
+            .await
+            .map_err(io::Error::other)?;
+        let frame = match result {
+            Ok(result) => Frame::new(FrameType::Result, result),
+            Err(e) => Frame::new(FrameType::Error, e.into_bytes()),
+        };
+        write_frame(&mut stream, &frame, PAYLOAD_TIMEOUT)
+            .await
+            .map_err(protocol_error)
+    }
+}
+
+fn protocol_error(e: ProtocolError) -> io::Error {
+    io::Error::other(e.to_string())
+}
+
+/// Spawn `path` with the environment and the arguments of the guest only, the payload on
+/// its stdin
+fn run_process(
+    path: &Path,
+    payload: Vec<u8>,
+    guest: &GuestArgs,
+    process: &Mutex<Option<u32>>,
+) -> Result<Vec<u8>, String> {
+    let mut child = Command::new(path)
+        .args(&guest.args)
+        .env_clear()
+        .envs(&guest.env)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Cannot run {}: {}", path.display(), e))?;
+    *process.lock().unwrap() = Some(child.id());
+    // Written apart, so a large payload and a large result do not block each other
+    let mut stdin = child.stdin.take().unwrap();
+    let writer = thread::spawn(move || stdin.write_all(&payload));
+    let output = child.wait_with_output();
+    *process.lock().unwrap() = None;
+    let _ = writer.join();
+    let output = output.map_err(|e| format!("Cannot run {}: {}", path.display(), e))?;
+    if output.status.success() {
+        Ok(output.stdout)
+    } else {
+        Err(format!(
+            "{} exited with {}: {}",
+            path.display(),
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+    }
+}
+
+type HandlerFn = unsafe extern "C" fn(*const u8, usize, *mut *mut u8, *mut usize) -> i32;
+type FreeFn = unsafe extern "C" fn(*mut u8, usize);
+
+/// Load the library at `path` and call its handler on `payload`
+fn run_library(path: &Path, payload: &[u8]) -> Result<Vec<u8>, String> {
+    let library = Library::open(path)?;
+    // SAFETY: the library exports the functions with the documented signatures
+    let (handler, free) = unsafe {
+        (
+            std::mem::transmute::<*mut c_void, HandlerFn>(library.symbol(LIBRARY_HANDLER)?),
+            std::mem::transmute::<*mut c_void, FreeFn>(library.symbol(LIBRARY_FREE)?),
+        )
+    };
+    let (mut out, mut out_len) = (ptr::null_mut(), 0);
+    // SAFETY: the payload is valid for its length, the output is owned by the library
+    // until it is given back to it
+    let (code, output) = unsafe {
+        let code = handler(payload.as_ptr(), payload.len(), &mut out, &mut out_len);
+        let output = match out.is_null() {
+            true => vec![],
+            false => {
+                let output = slice::from_raw_parts(out, out_len).to_vec();
+                free(out, out_len);
+                output
+            }
+        };
+        (code, output)
+    };
+    match code {
+        0 => Ok(output),
+        code if output.is_empty() => Err(format!("{} returned {}", LIBRARY_HANDLER, code)),
+        _ => Err(String::from_utf8_lossy(&output).to_string()),
+    }
+}
+
+/// A shared library loaded with dlopen, closed when dropped
+struct Library(*mut c_void);
+
+impl Library {
+    fn open(path: &Path) -> Result<Self, String> {
+        let name = CString::new(path.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+        // SAFETY: the name is a valid C string
+        let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        match handle.is_null() {
+            true => Err(dlerror()),
+            false => Ok(Library(handle)),
+        }
+    }
+
+    fn symbol(&self, name: &str) -> Result<*mut c_void, String> {
+        let name = CString::new(name).map_err(|e| e.to_string())?;
+        // SAFETY: the handle is open and the name is a valid C string
+        let symbol = unsafe { libc::dlsym(self.0, name.as_ptr()) };
+        match symbol.is_null() {
+            true => Err(dlerror()),
+            false => Ok(symbol),
+        }
+    }
+}
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        // SAFETY: the handle is open, and nothing of the library outlives it
+        unsafe { libc::dlclose(self.0) };
+    }
+}
+
+/// Last error of dlopen or dlsym
+fn dlerror() -> String {
+    // SAFETY: dlerror returns either null or a valid C string
+    unsafe {
+        let error = libc::dlerror();
+        match error.is_null() {
+            true => "Unknown dlopen error".to_string(),
+            false => CStr::from_ptr(error).to_string_lossy().to_string(),
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write an executable script in a new directory
+    fn script(name: &str, body: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spare-simulate-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    fn run(handler: &Handler, payload: &str, guest: &GuestArgs) -> Result<Vec<u8>, String> {
+        handler.run(payload.as_bytes().to_vec(), guest, &Mutex::new(None))
+    }
+
+    #[test]
+    fn test_handler_of() {
+        let path = script("resize", "cat");
+        assert_eq!(Handler::of(&path), Handler::Process(path.clone()));
+        assert_eq!(
+            Handler::of(Path::new("/missing/libresize.so")),
+            Handler::Library(PathBuf::from("/missing/libresize.so"))
+        );
+        // A file that is not executable, as a nanos image
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(Handler::of(&path), Handler::Echo);
+        assert_eq!(Handler::of(Path::new("/missing/resize.img")), Handler::Echo);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_process() {
+        let path = script("greet", "printf '%s ' \"$GREETING\" \"$@\"\ncat");
+        let handler = Handler::of(&path);
+        let guest = GuestArgs {
+            env: [("GREETING".to_string(), "hello".to_string())].into(),
+            args: vec!["big".to_string()],
+        };
+        assert_eq!(run(&handler, "world", &guest).unwrap(), b"hello big world");
+        // Nothing of the environment of the node reaches the function
+        assert_eq!(
+            run(&handler, "world", &GuestArgs::default()).unwrap(),
+            b" world"
+        );
+        // A large payload is not stuck in the pipes
+        let large = "x".repeat(1 << 20);
+        assert_eq!(
+            run(&handler, &large, &GuestArgs::default()).unwrap().len(),
+            (1 << 20) + 1
+        );
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_process_failure() {
+        let path = script("fail", "echo 'no image' >&2\nexit 3");
+        let error = run(&Handler::of(&path), "", &GuestArgs::default()).unwrap_err();
+        assert!(error.contains("exit status: 3"), "{error}");
+        assert!(error.ends_with(": no image"), "{error}");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_echo_and_missing_library() {
+        assert_eq!(
+            run(&Handler::Echo, "hello", &GuestArgs::default()).unwrap(),
+            b"hello"
+        );
+        let missing = Handler::Library(PathBuf::from("/missing/libresize.so"));
+        assert!(run(&missing, "hello", &GuestArgs::default())
+            .unwrap_err()
+            .contains("libresize.so"));
+    }
+}
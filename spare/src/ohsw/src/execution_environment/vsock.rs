@@ -0,0 +1,231 @@
+//! Vsock ports of the instances.
+//! Firecracker forwards a connection of the guest to its vsock port `port` to the unix
+//! socket `<vsock path>_<port>` of the host, and the vsock path is in the workspace of the
+//! instance, named by its UUID. The host binds one socket per port the guest connects to,
+//! e.g. a control and a data channel, before the instance starts. A socket file left
+//! behind by a crash is removed before binding, a socket still answered by another
+//! listener is refused, and the socket files are removed with the instance.
+use std::{
+    fs, io,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+};
+
+use actix_web::rt::net::UnixListener;
+use log::{error, warn};
+
+/// A vsock port of an instance that cannot be bound
+#[derive(Debug, thiserror::Error)]
+pub enum VsockError {
+    /// Another listener answers on the socket, or a file that is not a socket is in the way
+    #[error("Vsock socket already in use: {0}")]
+    AddressInUse(PathBuf),
+    /// The node cannot create or remove the socket, retrying cannot help
+    #[error("Permission denied on the vsock socket {0}")]
+    PermissionDenied(PathBuf),
+    #[error("Cannot bind the vsock socket {path}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl VsockError {
+    /// Whether starting the instance again may succeed, with a new workspace
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, VsockError::PermissionDenied(_))
+    }
+
+    fn from_io(path: &Path, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::AddrInUse => VsockError::AddressInUse(path.to_path_buf()),
+            io::ErrorKind::PermissionDenied => VsockError::PermissionDenied(path.to_path_buf()),
+            _ => VsockError::Io {
+                path: path.to_path_buf(),
+                source: e,
+            },
+        }
+    }
+}
+
+/// Path of the socket the host listens on for the connections of the guest to its vsock
+/// port `port`, as firecracker names it
+pub fn listener_path(vsock_path: &str, port: u32) -> PathBuf {
+    PathBuf::from(format!("{vsock_path}_{port}"))
+}
+
+/// Remove the socket file at `path` if nothing listens on it anymore
+fn remove_stale(path: &Path) -> Result<(), VsockError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(VsockError::from_io(path, e)),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(VsockError::AddressInUse(path.to_path_buf()));
+    }
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(VsockError::AddressInUse(path.to_path_buf()));
+    }
+    warn!("Removing the stale vsock socket {}", path.display());
+    fs::remove_file(path).map_err(|e| VsockError::from_io(path, e))
+}
+
+/// A bound vsock port of an instance, its socket file is removed when it is dropped
+#[derive(Debug)]
+pub struct VsockPort {
+    port: u32,
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl VsockPort {
+    /// Bind the socket of the vsock port `port`, removing a stale one first.
+    /// Must be called from within an actix runtime.
+    pub fn bind(vsock_path: &str, port: u32) -> Result<Self, VsockError> {
+        let path = listener_path(vsock_path, port);
+        remove_stale(&path)?;
+        let listener = UnixListener::bind(&path).map_err(|e| VsockError::from_io(&path, e))?;
+        Ok(VsockPort {
+            port,
+            path,
+            listener,
+        })
+    }
+
+    pub fn port(&self) -> u32 {
+        self.port
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn listener(&self) -> &UnixListener {
+        &self.listener
+    }
+}
+
+impl Drop for VsockPort {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => error!(
+                "Cannot remove the vsock socket {}: {}",
+                self.path.display(),
+                e
+            ),
+            _ => {}
+        }
+    }
+}
+
+/// The vsock ports of an instance, bound together
+#[derive(Debug)]
+pub struct VsockPorts {
+    ports: Vec<VsockPort>,
+}
+
+impl VsockPorts {
+    /// Bind the sockets of the given vsock ports, the first one being the control channel.
+    /// If a port cannot be bound, the ones already bound are released.
+    pub fn reserve(vsock_path: &str, ports: &[u32]) -> Result<Self, VsockError> {
+        let mut bound: Vec<VsockPort> = Vec::with_capacity(ports.len());
+        for &port in ports {
+            if bound.iter().any(|bound| bound.port == port) {
+                continue;
+            }
+            bound.push(VsockPort::bind(vsock_path, port)?);
+        }
+        Ok(VsockPorts { ports: bound })
+    }
+
+    /// The port of the control channel, where the guest agent connects first
+    pub fn control(&self) -> &VsockPort {
+        &self.ports[0]
+    }
+
+    /// Get the bound port `port`, if it was reserved
+    pub fn get(&self, port: u32) -> Option<&VsockPort> {
+        self.ports.iter().find(|bound| bound.port == port)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use uuid::Uuid;
+
+    fn workspace() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spare-vsock-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[actix_web::test]
+    async fn test_stale_socket() {
+        let dir = workspace();
+        let vsock = dir.join("vsock.sock").display().to_string();
+        let path = listener_path(&vsock, 1234);
+        assert_eq!(path, dir.join("vsock.sock_1234"));
+
+        // Left behind by a crash, nobody listens on it anymore
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        let port = VsockPort::bind(&vsock, 1234).unwrap();
+        assert_eq!((port.port(), port.path()), (1234, path.as_path()));
+
+        // Still answered by a listener
+        assert!(matches!(
+            VsockPort::bind(&vsock, 1234),
+            Err(VsockError::AddressInUse(_))
+        ));
+        drop(port);
+
+        // A file that is not a socket is left alone
+        fs::write(&path, "data").unwrap();
+        let error = VsockPort::bind(&vsock, 1234).unwrap_err();
+        assert!(matches!(error, VsockError::AddressInUse(_)));
+        assert!(error.is_retryable());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "data");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_cleanup() {
+        let dir = workspace();
+        let vsock = dir.join("vsock.sock").display().to_string();
+        let ports = VsockPorts::reserve(&vsock, &[1234, 1235, 1234]).unwrap();
+        assert_eq!(ports.control().port(), 1234);
+        assert!(ports.get(1235).is_some());
+        assert!(ports.get(1236).is_none());
+        let paths = [listener_path(&vsock, 1234), listener_path(&vsock, 1235)];
+        assert!(paths.iter().all(|path| path.exists()));
+        // The guest reaches the data channel
+        std::os::unix::net::UnixStream::connect(&paths[1]).unwrap();
+        ports.get(1235).unwrap().listener().accept().await.unwrap();
+
+        // The socket files go with the instance
+        drop(ports);
+        assert!(paths.iter().all(|path| !path.exists()));
+
+        // A port in use releases the ones already bound
+        let control = VsockPort::bind(&vsock, 1235).unwrap();
+        assert!(VsockPorts::reserve(&vsock, &[1234, 1235]).is_err());
+        assert!(!paths[0].exists());
+        drop(control);
+
+        // The node cannot write in the workspace
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o500)).unwrap();
+        let denied = VsockPort::bind(&vsock, 1234);
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        // Root writes anywhere
+        if let Err(error) = denied {
+            assert!(matches!(error, VsockError::PermissionDenied(_)));
+            assert!(!error.is_retryable());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+//! Workspaces of the machines, and the sweeping of the ones left behind.
+//! Each machine gets a directory `firecracker-<uuid>` in the chroot of its executor (/tmp,
+//! or `<chroot_base>/<firecracker binary>` with the jailer) holding its API socket, vsock
+//! and metrics. The directory is removed with the machine, but a crash of the node or of
+//! firecracker leaves it behind, and the chroot slowly fills up.
+//!
+//! A workspace is stale when no instance of this node holds it, nothing answers on its
+//! API socket (another node on the same host may share the chroot) and it was not
+//! modified for a while. Only the directories directly in the chroot, named as this node
+//! names them, are ever removed: the symlinks and everything else are left alone.
+use std::{
+    collections::HashSet,
+    fs, io,
+    os::unix::{fs::MetadataExt, net::UnixStream},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::utils::blocking::BlockingPool;
+
+/// Prefix of the name of the workspaces, followed by a uuid
+pub const WORKSPACE_PREFIX: &str = "firecracker-";
+
+/// Name of the API socket of firecracker, in the root of the machine
+const API_SOCKET: &str = "firecracker.socket";
+
+/// How the stale workspaces are swept
+#[derive(Debug, Clone, Copy)]
+pub struct SweepConfig {
+    /// Workspaces modified more recently are kept, they may be in the making
+    pub min_age: Duration,
+    /// Time between two sweeps, after the one at startup. Zero sweeps only at startup
+    pub interval: Duration,
+    /// Only report the stale workspaces, without removing them
+    pub dry_run: bool,
+}
+
+/// Outcome of a sweep
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct SweepReport {
+    /// Stale workspaces, removed unless in dry run. The ones that could not be removed
+    /// are only counted in `failed`
+    pub stale: Vec<PathBuf>,
+    /// Workspaces kept because an instance holds them or their socket answers
+    pub live: usize,
+    /// Space allocated on disk by the stale workspaces (in bytes)
+    pub reclaimed_bytes: u64,
+    /// Stale workspaces that could not be removed
+    pub failed: usize,
+    pub dry_run: bool,
+}
+
+/// Totals of the sweeps since the node started, with the last one
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct SweepTotals {
+    pub sweeps: u64,
+    /// Workspaces removed, none in dry run
+    pub removed: u64,
+    /// Space freed by the removed workspaces (in bytes)
+    pub reclaimed_bytes: u64,
+    pub failed: u64,
+    pub last: Option<SweepReport>,
+}
+
+/// The workspaces of the machines of the node
+pub struct Workspaces {
+    dir: PathBuf,
+    live: Arc<Mutex<HashSet<String>>>,
+    totals: Mutex<SweepTotals>,
+}
+
+/// Name of the workspace of a machine, held while the machine may use it
+#[derive(Debug)]
+pub struct WorkspaceLease {
+    name: String,
+    live: Arc<Mutex<HashSet<String>>>,
+}
+
+impl WorkspaceLease {
+    /// Name of the workspace, also the id of the machine
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for WorkspaceLease {
+    fn drop(&mut self) {
+        self.live.lock().unwrap().remove(&self.name);
+    }
+}
+
+impl Workspaces {
+    /// Create the workspaces of the machines whose chroot is `dir`
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            live: Arc::new(Mutex::new(HashSet::new())),
+            totals: Mutex::new(SweepTotals::default()),
+        }
+    }
+
+    /// Directory holding the workspaces
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Name a new workspace, never swept while the lease is held
+    pub fn lease(&self) -> WorkspaceLease {
+        let name = format!("{}{}", WORKSPACE_PREFIX, uuid::Uuid::new_v4());
+        self.live.lock().unwrap().insert(name.clone());
+        WorkspaceLease {
+            name,
+            live: self.live.clone(),
+        }
+    }
+
+    /// Totals of the sweeps so far
+    pub fn totals(&self) -> SweepTotals {
+        self.totals.lock().unwrap().clone()
+    }
+
+    /// Find the stale workspaces and remove them, unless in dry run
+    pub fn sweep(&self, config: &SweepConfig) -> SweepReport {
+        let mut report = SweepReport {
+            dry_run: config.dry_run,
+            ..Default::default()
+        };
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    error!(
+                        "Cannot list the workspaces in {}: {}",
+                        self.dir.display(),
+                        e
+                    );
+                }
+                return self.record(report);
+            }
+        };
+        let now = SystemTime::now();
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !is_workspace_name(&name) {
+                continue;
+            }
+            // Never follow a symlink out of the chroot
+            let path = self.dir.join(&name);
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(metadata) if metadata.is_dir() => metadata,
+                _ => continue,
+            };
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .unwrap_or_default();
+            if age < config.min_age {
+                continue;
+            }
+            if self.live.lock().unwrap().contains(&name) || socket_answers(&path) {
+                report.live += 1;
+                continue;
+            }
+
+            let size = disk_usage(&path);
+            if config.dry_run {
+                info!("Would remove the stale workspace {}", path.display());
+            } else if let Err(e) = fs::remove_dir_all(&path) {
+                warn!(
+                    "Cannot remove the stale workspace {}: {}",
+                    path.display(),
+                    e
+                );
+                report.failed += 1;
+                continue;
+            }
+            report.reclaimed_bytes += size;
+            report.stale.push(path);
+        }
+        self.record(report)
+    }
+
+    /// Add a sweep to the totals
+    fn record(&self, report: SweepReport) -> SweepReport {
+        let mut totals = self.totals.lock().unwrap();
+        totals.sweeps += 1;
+        totals.failed += report.failed as u64;
+        if !report.dry_run {
+            totals.removed += report.stale.len() as u64;
+            totals.reclaimed_bytes += report.reclaimed_bytes;
+        }
+        totals.last = Some(report.clone());
+        report
+    }
+}
+
+/// Sweep the stale workspaces now, then every `interval` if set. The directories are
+/// walked on the blocking pool.
+pub async fn run(workspaces: Arc<Workspaces>, blocking: BlockingPool, config: SweepConfig) {
+    loop {
+        let sweeping = workspaces.clone();
+        let report = blocking.run(move || sweeping.sweep(&config)).await;
+        if !report.stale.is_empty() {
+            info!(
+                "{} {} stale workspaces in {} ({} bytes)",
+                if report.dry_run { "Found" } else { "Removed" },
+                report.stale.len(),
+                workspaces.dir().display(),
+                report.reclaimed_bytes
+            );
+        }
+        if config.interval.is_zero() {
+            break;
+        }
+        actix_web::rt::time::sleep(config.interval).await;
+    }
+}
+
+/// Whether `name` is the name of a workspace, as given by `Workspaces::lease`
+fn is_workspace_name(name: &str) -> bool {
+    name.strip_prefix(WORKSPACE_PREFIX)
+        .is_some_and(|id| uuid::Uuid::try_parse(id).is_ok())
+}
+
+/// Whether a firecracker process answers on the API socket of the workspace, directly
+/// in it or in the root of the jailer
+fn socket_answers(workspace: &Path) -> bool {
+    [
+        workspace.join(API_SOCKET),
+        workspace.join("root").join(API_SOCKET),
+    ]
+    .iter()
+    .any(|socket| UnixStream::connect(socket).is_ok())
+}
+
+/// Space allocated on disk by the files under `path` (in bytes), without following symlinks
+fn disk_usage(path: &Path) -> u64 {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return 0;
+    };
+    let mut size = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        if let Ok(entries) = fs::read_dir(path) {
+            size += entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| disk_usage(&entry.path()))
+                .sum::<u64>();
+        }
+    }
+    size
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixListener;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        // Short, the paths of the sockets are limited to 108 bytes
+        let id = &uuid::Uuid::new_v4().to_string()[..8];
+        let dir = std::env::temp_dir().join(format!("spare-{}-{}", name, id));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Create a workspace as a machine leaves it, with its metrics
+    fn workspace(dir: &Path) -> PathBuf {
+        let path = dir.join(format!("{}{}", WORKSPACE_PREFIX, uuid::Uuid::new_v4()));
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("metrics.fifo"), vec![1; 8192]).unwrap();
+        path
+    }
+
+    fn config(dry_run: bool) -> SweepConfig {
+        SweepConfig {
+            min_age: Duration::ZERO,
+            interval: Duration::ZERO,
+            dry_run,
+        }
+    }
+
+    #[test]
+    fn test_workspace_name() {
+        assert!(is_workspace_name(
+            "firecracker-4b8f5c2e-7d0a-4d1e-9f3b-2a6c8e1d0f57"
+        ));
+        for name in [
+            "firecracker-",
+            "firecracker-vm",
+            "firecracker-4b8f5c2e-7d0a-4d1e-9f3b-2a6c8e1d0f57.old",
+            "4b8f5c2e-7d0a-4d1e-9f3b-2a6c8e1d0f57",
+            "systemd-private-4b8f5c2e",
+        ] {
+            assert!(!is_workspace_name(name), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_sweep() {
+        let dir = temp_dir("ws");
+        let workspaces = Workspaces::new(dir.clone());
+        let stale = workspace(&dir);
+        // Left by a firecracker that crashed, nothing listens on its socket anymore
+        let crashed = workspace(&dir);
+        drop(UnixListener::bind(crashed.join(API_SOCKET)).unwrap());
+
+        // Held by an instance of the node, or used by another node
+        let lease = workspaces.lease();
+        let leased = dir.join(lease.name());
+        fs::create_dir_all(&leased).unwrap();
+        let running = workspace(&dir);
+        let _listener = UnixListener::bind(running.join(API_SOCKET)).unwrap();
+        let jailed = workspace(&dir);
+        fs::create_dir_all(jailed.join("root")).unwrap();
+        let _jailed_listener = UnixListener::bind(jailed.join("root").join(API_SOCKET)).unwrap();
+
+        // Not workspaces of the node
+        let other = dir.join("firecracker-vm");
+        fs::create_dir_all(&other).unwrap();
+        let file = dir.join(format!("{}{}", WORKSPACE_PREFIX, uuid::Uuid::new_v4()));
+        fs::write(&file, "").unwrap();
+        let outside = temp_dir("outside");
+        fs::write(outside.join("keep"), "").unwrap();
+        let link = dir.join(format!("{}{}", WORKSPACE_PREFIX, uuid::Uuid::new_v4()));
+        std::os::unix::fs::symlink(&outside, &link).unwrap();
+
+        // A dry run only reports them
+        let report = workspaces.sweep(&config(true));
+        let mut expected = vec![stale.clone(), crashed.clone()];
+        expected.sort();
+        let mut found = report.stale.clone();
+        found.sort();
+        assert_eq!(found, expected);
+        assert_eq!(report.live, 3);
+        assert!(report.reclaimed_bytes >= 2 * 8192);
+        assert!(stale.exists() && crashed.exists());
+        assert_eq!(workspaces.totals().removed, 0);
+
+        let swept = workspaces.sweep(&config(false));
+        assert_eq!(swept.stale.len(), 2);
+        assert_eq!(swept.reclaimed_bytes, report.reclaimed_bytes);
+        assert!(!stale.exists() && !crashed.exists());
+        for kept in [&leased, &running, &jailed, &other, &file, &link] {
+            assert!(fs::symlink_metadata(kept).is_ok(), "{}", kept.display());
+        }
+        assert!(outside.join("keep").exists());
+
+        // Once the instance is gone, its workspace is stale
+        drop(lease);
+        let report = workspaces.sweep(&config(false));
+        assert_eq!(report.stale, vec![leased.clone()]);
+        assert_eq!(report.live, 2);
+
+        let totals = workspaces.totals();
+        assert_eq!(totals.sweeps, 3);
+        assert_eq!(totals.removed, 3);
+        assert!(totals.reclaimed_bytes >= swept.reclaimed_bytes);
+        assert_eq!(totals.last, Some(report));
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn test_min_age() {
+        let dir = temp_dir("ws");
+        let workspaces = Workspaces::new(dir.clone());
+        let recent = workspace(&dir);
+        let config = SweepConfig {
+            min_age: Duration::from_secs(3600),
+            ..config(false)
+        };
+        assert_eq!(workspaces.sweep(&config), SweepReport::default());
+        assert!(recent.exists());
+
+        // The chroot may not exist yet
+        let missing = Workspaces::new(dir.join("missing"));
+        assert_eq!(missing.sweep(&config), SweepReport::default());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,15 @@
+//! SPARE node library.
+//! The node must build on stable Rust, as it is deployed on every edge device.
+#![deny(unstable_features)]
+// The std locks of the node must not be held across an await, it would stall the workers
+#![deny(clippy::await_holding_lock, clippy::await_holding_refcell_ref)]
 pub mod api;
+pub mod chaos;
 pub mod db;
 pub mod endpoints;
 pub mod execution_environment;
 pub mod net;
 pub mod orchestrator;
+pub mod preflight;
+pub mod schedules;
 pub mod utils;
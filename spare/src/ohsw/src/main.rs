@@ -1,38 +1,101 @@
 //! SPARE Serverless Platform
 //! SPARE is a serverless platform that aims to provide a scalable and efficient serverless platform for edge computing.
 //! The code provided here is a prototype of the SPARE platform.
-use actix_web::{
-    middleware,
-    web::{Data, JsonConfig},
-    App, HttpServer,
-};
+#![deny(unstable_features)]
+// The std locks of the node must not be held across an await, it would stall the workers
+#![deny(clippy::await_holding_lock, clippy::await_holding_refcell_ref)]
+use actix_web::{middleware, rt::time::sleep, web::Data, App, HttpServer};
 use clap::{arg, command, Parser};
+use futures::{
+    future::{select, Either},
+    FutureExt,
+};
 use local_ip_address::local_ip;
-use log::{error, info};
+use log::{error, info, warn};
 use ohsw::{
-    db::{self},
-    endpoints::{emergency, index, invoke, list, resources},
-    execution_environment::firecracker::FirecrackerBuilder,
+    api::{
+        calibrate::CalibrationSummary,
+        rate_limits::{Bucket, RateLimits},
+    },
+    db::{
+        self,
+        models::{Calibration, Instance, NodeRun, Quota},
+        request_log::RequestLog,
+        status_writer::StatusWriter,
+    },
+    endpoints::{configure_startup, invoke_unattended, InvokeContext, NodeState},
+    execution_environment::{
+        cgroup::Cgroups,
+        environment::Runtime,
+        firecracker::{FirecrackerBuilder, Jailer},
+        guest::{FunctionGuest, GuestMode},
+        image_cache::ImageCache,
+        inventory,
+        mock::MockTaps,
+        overlay::ImageMode,
+        simulate::SimulatedExecutionEnvironment,
+        workspaces::{self, SweepConfig},
+    },
     net::{
         addresses::Addresses,
-        iggy::{IggyConnector, Operation, Payload},
+        control_plane::{self, take_identity, Announcer, AnyControlPlane, Deferred},
+        controller::EmergencyController,
+        dead_letter::DeadLetterLog,
+        iggy::{
+            default_consumer_name, ConsumerConfig, Credentials, IggyConnector, Phase, PollingMode,
+            Topology, DEFAULT_ANNOUNCE_PARTITION_ID, DEFAULT_STREAM_ID, DEFAULT_TOPIC_ID,
+        },
+        networks::{FunctionNetwork, NetworkSpec, Networks},
+        registry::{self, HttpControlPlane, Registry},
+        tls::{self, Identity, NodeClient, Trust},
     },
     orchestrator::{
         self,
-        global::{emergency::Emergency, identity::Node},
-        Orchestrator,
+        capabilities::FunctionRequires,
+        global::{
+            identity::{self, Node},
+            node_cache::NodeCache,
+            position::{FilePosition, PositionTracker},
+            probed::{HttpProber, LatencyProbe, ProbeConfig},
+            snapshot::SortPolicy,
+            NeighborNodeStrategy, DEFAULT_INDEX_THRESHOLD,
+        },
+        pressure::{self, PressureThresholds, ProcPressure},
+        readiness::{Readiness, ReadinessPhase},
+        resource_spec::{FunctionResources, ResourceSpec},
+        scheduler::{CostModel, DEFAULT_LATENCY, DEFAULT_MAX_WAIT, DEFAULT_SERVICE_TIME},
+        ImageAffinity, Reserve,
+    },
+    preflight::{self, HostProbes, NetworkPlan, PositionlessPlan, PreflightConfig},
+    schedules::{CatchUp, Runner, SystemClock, DEFAULT_SCHEDULE_TICK},
+    utils::{
+        auth::{AdminToken, ClusterAuth},
+        blocking::{BlockingPolicy, BlockingPool, DEFAULT_BLOCKING_THREADS},
+        compression::Compression,
+        diagnostics::{self, Diagnostics, SECRETS},
+        idempotency::{IdempotencyCache, IdempotencyConfig},
+        log_ring,
+        notify::{self, LivenessFile, Notifiers, State, SystemdNotifier},
+        protocol::GuestProtocol,
+        quota::QuotaTracker,
+        spill::SpillConfig,
+        stats::{stats_path, StatsFormat, StatsWriter},
+        supervisor::{Backoff, Supervisor},
     },
 };
-use sqlx::{sqlite, Pool};
+use sha2::{Digest, Sha256};
 use std::{
-    env,
-    fs::File,
-    io::Write,
-    net::Ipv4Addr,
-    path::Path,
+    fs, io,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::pin,
+    rc::Rc,
     str::FromStr,
-    sync::{Arc, Mutex},
+    sync::Arc,
+    time::Duration,
 };
+use tokio::net::TcpSocket;
+use tokio_util::sync::CancellationToken;
 
 // Struct that represents the supported arguments for the executable
 #[derive(Parser, Debug)]
@@ -53,145 +116,650 @@ struct Args {
     // Bridge name for the virtual network
     #[arg(short, long, default_value = "br0")]
     bridge_name: String,
-}
-
-// Controller that handles the emergency mode
-#[actix_web::main]
-async fn emergency_controller(
-    pool: Pool<sqlite::Sqlite>,
-    orchestrator: Arc<Orchestrator>,
-    iggy_client: IggyConnector,
-    shutdown: Arc<Mutex<bool>>,
-) {
-    let orchestrator = orchestrator;
-    let (x, y) = orchestrator.get_identity().position;
-    let mut file = File::create(&format!("node_x{}_y{}.stats.data", x, y)).unwrap();
-    let _ = writeln!(
-        file,
-        "{:<15} {:<10} {:<10} {:<10} {:<10}",
-        "epoch", "hops_avg", "vcpus_sum", "memory_sum", "requests"
-    );
-    let mut eras = 0;
-    loop {
-        match iggy_client.receive_message().await {
-            Ok(Some(msg)) => match msg.op {
-                Operation::START_EMERGENCY => match msg.payload {
-                    Some(Payload::Emergency(em_pos)) => {
-                        info!(
-                            "Emergency mode activated at position: {:?} with radius: {}",
-                            em_pos.position, em_pos.radius
-                        );
-                        orchestrator.set_emergency(true, em_pos);
-                    }
-                    _ => continue,
-                },
-                Operation::STOP_EMERGENCY => {
-                    orchestrator.set_emergency(
-                        false,
-                        Emergency {
-                            position: (0.0, 0.0),
-                            radius: 0.0,
-                        },
-                    );
-                    info!("Emergency mode deactivated");
-                }
-                Operation::END => break,
-                Operation::WRITE_STATS => match msg.payload {
-                    Some(Payload::Period(period)) => {
-                        info!(
-                            "Writing stats for period: {} - {}",
-                            period.start, period.end
-                        );
-                        let start = period.start;
-                        let end = period.end;
-                        let mut stats = db::stats(&pool, &start, &end).await;
-                        loop {
-                            if stats.is_err() {
-                                stats = db::stats(&pool, &start, &end).await;
-                            } else {
-                                break;
-                            }
-                        }
-                        let stats = stats.unwrap();
-                        writeln!(
-                            file,
-                            "{:<15} {:<10} {:<10} {:<10} {:<10}",
-                            eras, stats.hops_avg, stats.vcpus, stats.memory, stats.requests
-                        )
-                        .unwrap();
-                        eras += 1;
-                    }
-                    _ => continue,
-                },
-                _ => (),
-            },
-            Ok(None) => {
-                if *shutdown.lock().unwrap() {
-                    break;
-                }
-            }
-            Err(e) => {
-                error!("Error receiving message: {e}");
-            }
-        }
-    }
+    // Speak the legacy (v1) vsock protocol with the guests, for images built before v2
+    #[arg(long, default_value_t = false)]
+    legacy_guest_protocol: bool,
+    // Default bandwidth limit (bytes/s) for the traffic received by each instance
+    #[arg(long)]
+    net_rx_bandwidth: Option<i64>,
+    // Default bandwidth limit (bytes/s) for the traffic sent by each instance
+    #[arg(long)]
+    net_tx_bandwidth: Option<i64>,
+    // Default bandwidth limit (bytes/s) for the root drive of each instance
+    #[arg(long)]
+    disk_bandwidth: Option<i64>,
+    // Default operations limit (ops/s) for the root drive of each instance
+    #[arg(long)]
+    disk_ops: Option<i64>,
+    // How function images are mounted in the instances: shared-ro or overlay
+    #[arg(long, default_value = "overlay")]
+    image_mode: ImageMode,
+    // Directory where the per-instance overlays are stored
+    #[arg(long, default_value = "/tmp/spare/overlays")]
+    overlay_dir: PathBuf,
+    // Directory where the images fetched from remote locations are cached
+    #[arg(long, default_value = "/tmp/spare/images")]
+    image_cache_dir: PathBuf,
+    // Maximum size of the image cache (in MiB)
+    #[arg(long, default_value = "10240")]
+    image_cache_size: u64,
+    // Directory of the images present on the node, listed on /images and checked by the
+    // offloads (repeatable)
+    #[arg(long = "image-dir")]
+    image_dirs: Vec<PathBuf>,
+    // Time between two scans of the image directories (in s)
+    #[arg(long, default_value = "60")]
+    image_scan_interval: u64,
+    // Offload to the neighbors without the image of the request after the others (prefer),
+    // or never (require)
+    #[arg(long, default_value = "prefer")]
+    offload_image: ImageAffinity,
+    // Run the instances through the jailer binary at the given path
+    #[arg(long)]
+    use_jailer: Option<PathBuf>,
+    // Base directory of the chroots created by the jailer
+    #[arg(long, default_value = "/srv/jailer")]
+    jailer_chroot_base: PathBuf,
+    // First uid/gid assigned to the jailed instances, each instance gets its own
+    #[arg(long, default_value = "10000")]
+    jailer_uid_base: u32,
+    // Enforce the vcpus and memory of each instance through a cgroup-v2
+    #[arg(long, default_value_t = false)]
+    enforce_cgroups: bool,
+    // Parent cgroup of the instances, relative to /sys/fs/cgroup
+    #[arg(long, default_value = "spare")]
+    cgroup_parent: PathBuf,
+    // Time to wait for the list of nodes (in s) before starting from the last known one,
+    // if the node has one. 0 waits for the control plane forever
+    #[arg(long, default_value = "30")]
+    registration_timeout: u64,
+    // Time between two resolutions of the hostnames of the neighbors (in s)
+    #[arg(long, default_value = "60")]
+    resolve_interval: u64,
+    // Time between two rounds of latency probes (in ms), used by the Probed strategy
+    #[arg(long, default_value = "5000")]
+    probe_interval: u64,
+    // Weight of a new latency sample in the moving average, in (0, 1]
+    #[arg(long, default_value = "0.2")]
+    probe_weight: f64,
+    // Time after which a latency probe is considered failed (in ms)
+    #[arg(long, default_value = "1000")]
+    probe_timeout: u64,
+    // When the neighbors are sorted again for a change of their latencies: on-change,
+    // interval:<ms> or on-expiry
+    #[arg(long, default_value = "on-change")]
+    sort_policy: SortPolicy,
+    // Number of neighbor nodes from which a spatial index is used instead of a linear scan
+    #[arg(long, default_value_t = DEFAULT_INDEX_THRESHOLD)]
+    spatial_index_threshold: usize,
+    // Path of the stats file, {address}, {x} and {y} are replaced with the ones of the node
+    #[arg(long, default_value = "node_x{x}_y{y}.stats.data")]
+    stats_output: String,
+    // Format of the stats file: csv, table or jsonl
+    #[arg(long, default_value = "table")]
+    stats_format: StatsFormat,
+    // Keep the default sqlite settings and write the instance status from the request handlers
+    #[arg(long, default_value_t = false)]
+    legacy_sqlite: bool,
+    // Time after which an idempotency key is forgotten (in s)
+    #[arg(long, default_value = "600")]
+    idempotency_ttl: u64,
+    // Maximum number of idempotency keys remembered
+    #[arg(long, default_value = "10000")]
+    idempotency_max_entries: usize,
+    // Maximum size of a result stored for an idempotency key (in bytes)
+    #[arg(long, default_value = "1048576")]
+    idempotency_max_body_size: usize,
+    // Name of the broker consumers of the node, defaults to address:port
+    #[arg(long)]
+    consumer_name: Option<String>,
+    // Partition the broadcast messages are polled from
+    #[arg(long, default_value = "2")]
+    consumer_partition: u32,
+    // Where the consumers start polling from: next, first or last
+    #[arg(long, default_value = "next")]
+    consumer_polling: PollingMode,
+    // Stream shared with the benchmark
+    #[arg(long, default_value_t = DEFAULT_STREAM_ID)]
+    stream_id: u32,
+    // Topic shared with the benchmark
+    #[arg(long, default_value_t = DEFAULT_TOPIC_ID)]
+    topic_id: u32,
+    // Partition the node announces itself on
+    #[arg(long, default_value_t = DEFAULT_ANNOUNCE_PARTITION_ID)]
+    announce_partition: u32,
+    // Username used to log in to the broker
+    #[arg(long, default_value = "iggy")]
+    broker_username: String,
+    // Password used to log in to the broker
+    #[arg(long, default_value = "iggy")]
+    broker_password: String,
+    // Personal access token used to log in to the broker, instead of username and password
+    #[arg(long)]
+    broker_token: Option<String>,
+    // File the broker messages that cannot be handled are logged to
+    #[arg(long, default_value = "dead_letters.jsonl")]
+    dead_letter_file: PathBuf,
+    // Size after which the dead letter file is rotated (in bytes)
+    #[arg(long, default_value = "1048576")]
+    dead_letter_max_size: u64,
+    // URL of the HTTP registry (e.g. http://10.0.0.1:9000), used instead of the Iggy broker
+    #[arg(long)]
+    registry: Option<String>,
+    // Host the HTTP registry on the given address (e.g. 0.0.0.0:9000)
+    #[arg(long)]
+    registry_server: Option<String>,
+    // Number of nodes the hosted registry waits for before sending the list of nodes
+    #[arg(long, default_value = "2")]
+    registry_nodes: usize,
+    // Token required by the administrative endpoints (e.g. /calibrate), disabled without it
+    #[arg(long)]
+    admin_token: Option<String>,
+    // Service time of a request until the node is calibrated (in ms)
+    #[arg(long, default_value_t = DEFAULT_SERVICE_TIME)]
+    default_service_time: f64,
+    // Share of a service time each queued request adds to the estimated local wait
+    #[arg(long, default_value = "1.0")]
+    wait_factor: f64,
+    // Latency of the neighbors that were never measured (in ms)
+    #[arg(long, default_value_t = DEFAULT_LATENCY)]
+    default_latency: f64,
+    // Times the latency of the neighbor is paid by an offloaded request
+    #[arg(long, default_value = "1.0")]
+    latency_factor: f64,
+    // Fixed cost of an offload (in ms)
+    #[arg(long, default_value = "0.0")]
+    offload_overhead: f64,
+    // Longest time a request waits for local resources instead of being offloaded (in ms)
+    #[arg(long, default_value_t = DEFAULT_MAX_WAIT)]
+    max_local_wait: f64,
+    // Rank the neighbors the same way for every function, instead of offloading each
+    // function to its own preferred neighbor first
+    #[arg(long, default_value_t = false)]
+    no_sticky_offload: bool,
+    // Forward the offloaded requests encoded again with the hops in the body, as the
+    // nodes that do not read the hops header expect, instead of as they were received
+    #[arg(long, default_value_t = false)]
+    legacy_offload: bool,
+    // Keep the traces of the decisions of the last offloads, served on /debug/offloads;
+    // 0 records none, recording allocates for every offload
+    #[arg(long, default_value_t = 0)]
+    trace_offloads: usize,
+    // Hold a request back for up to this long when it would take the cpus a larger waiting
+    // request needs, so that a node running requests of mixed sizes can fit the large ones
+    // (in ms); 0 admits every request as soon as it fits
+    #[arg(long, default_value_t = 0)]
+    packing_hold: u64,
+    // Take failure injections on /chaos and from the control plane, for chaos testing;
+    // never on a node serving real requests
+    #[arg(long, default_value_t = false)]
+    chaos: bool,
+    // During an emergency, rank the neighbors by their distance from this node minus their
+    // distance from the emergency, weighted by this value in [0, 1]; 0 ranks them as usual
+    #[arg(long, default_value = "0.0")]
+    emergency_weight: f64,
+    // Cpus kept free for the emergency requests, the other requests cannot use them
+    #[arg(long, default_value = "0")]
+    reserve_vcpus: usize,
+    // Admit instances for up to this many times the cpus of the node, at least 1;
+    // most functions are bursty and leave their cpus idle most of the time
+    #[arg(long, default_value = "1.0")]
+    cpu_overcommit: f64,
+    // Memory kept free for the emergency requests (in MiB)
+    #[arg(long, default_value = "0")]
+    reserve_memory_mb: usize,
+    // Time between two readings of the memory pressure of the host (in ms), 0 to admit
+    // the instances on MemAvailable only
+    #[arg(long, default_value = "1000")]
+    memory_pressure_interval: u64,
+    // Admit no local instance from this share of the time stalled on memory, over the last
+    // 10 seconds of /proc/pressure/memory (in %)
+    #[arg(long, default_value = "10.0")]
+    memory_pressure_high: f64,
+    // Admit local instances again once the share of the time stalled on memory falls to
+    // this (in %)
+    #[arg(long, default_value = "2.0")]
+    memory_pressure_low: f64,
+    // Admit no local instance below this available memory (in MiB), and again from twice
+    // as much; 0 to decide on the stalls only
+    #[arg(long, default_value = "256")]
+    memory_pressure_min_available_mb: usize,
+    // PEM file with the certificate chain of the node, serve HTTPS with it
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    // PEM file with the private key of the node
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    // PEM file with the CAs the certificates of the other nodes must be signed by
+    #[arg(long, requires = "tls_cert", conflicts_with = "tls_insecure")]
+    tls_ca: Option<PathBuf>,
+    // Do not check the certificates of the other nodes, only meant for the lab
+    #[arg(long, default_value_t = false, requires = "tls_cert")]
+    tls_insecure: bool,
+    // PEM file with the client certificate of the node, signed by the CA of --tls-ca.
+    // With it the node only accepts the forwarded requests of the nodes that have one.
+    #[arg(long, requires_all = ["tls_client_key", "tls_ca"])]
+    tls_client_cert: Option<PathBuf>,
+    // PEM file with the private key of the client certificate
+    #[arg(long, requires = "tls_client_cert")]
+    tls_client_key: Option<PathBuf>,
+    /// Number of HTTP workers, defaults to the number of CPUs
+    #[arg(long)]
+    http_workers: Option<usize>,
+    /// Where the image digests and the overlay copies run: offload, on the blocking pool,
+    /// or inline, on the HTTP worker serving the request
+    #[arg(long, default_value = "offload")]
+    blocking_policy: BlockingPolicy,
+    /// Number of image digests and overlay copies running at the same time on the node,
+    /// also the size of the blocking pool of each HTTP worker
+    #[arg(long, default_value_t = DEFAULT_BLOCKING_THREADS)]
+    blocking_threads: usize,
+    /// Report readiness and the watchdog to systemd through NOTIFY_SOCKET (Type=notify)
+    #[arg(long, default_value_t = false)]
+    systemd_notify: bool,
+    /// File refreshed with the time of the last successful health check
+    #[arg(long)]
+    liveness_file: Option<PathBuf>,
+    /// Time between two health checks (in ms), shortened to half of WatchdogSec under systemd
+    #[arg(long, default_value = "10000")]
+    health_interval: u64,
+    /// Run the preflight checks and exit, without starting the node
+    #[arg(long, default_value_t = false)]
+    check: bool,
+    /// Start even if the pool of the instances overlaps the node, the broker or the bridge
+    #[arg(long, default_value_t = false)]
+    allow_network_conflicts: bool,
+    /// Compression of the responses: off, auto (unless the function says its output does
+    /// not compress well) or forced
+    #[arg(long, default_value = "auto")]
+    compression: Compression,
+    /// What happens to the scheduled runs missed while the node was down: skip, once
+    /// (a single run for all of them) or all
+    #[arg(long, default_value = "skip")]
+    schedule_catch_up: CatchUp,
+    /// Time between two checks of the schedules (in ms)
+    #[arg(long, default_value_t = DEFAULT_SCHEDULE_TICK)]
+    schedule_tick: u64,
+    /// Another network of the instances, as name=bridge,address/prefix (repeatable)
+    #[arg(long = "network")]
+    networks: Vec<NetworkSpec>,
+    /// Attach the instances of a function to one of the networks, as function=network
+    /// (repeatable), the other functions use the bridge of --bridge-name
+    #[arg(long = "function-network")]
+    function_networks: Vec<FunctionNetwork>,
+    /// Time a request waits for an address once the network of its function has none left
+    /// (in ms), 0 to offload it right away
+    #[arg(long, default_value = "0")]
+    address_wait: u64,
+    /// Install the iptables rules dropping the traffic between the networks
+    #[arg(long, default_value_t = false)]
+    manage_firewall: bool,
+    /// Maximum size of the body of a request (in MiB)
+    #[arg(long, default_value = "50")]
+    max_body_size: usize,
+    /// Size from which the binary payloads of /invoke/{function} are written to disk while
+    /// they are received, instead of being kept in memory (in MiB)
+    #[arg(long, default_value = "8")]
+    spill_threshold: usize,
+    /// Directory where the binary payloads over the spill threshold are written
+    #[arg(long, default_value = "/tmp/spare/payloads")]
+    spill_dir: PathBuf,
+    /// Default resources of a function, as function=vcpus,memory (repeatable), for the
+    /// requests that leave them out; either may be left empty
+    #[arg(long = "function-resources")]
+    function_resources: Vec<FunctionResources>,
+    /// Capabilities a function requires from the neighbors it is offloaded to, as
+    /// function=capability,capability (repeatable), e.g. render=gpu,mem:8192
+    #[arg(long = "function-requires")]
+    function_requires: Vec<FunctionRequires>,
+    /// Capability of the node announced to its neighbors (repeatable), e.g. gpu or
+    /// mem:8192; its architecture, as arch:x86_64, is always announced
+    #[arg(long = "capability")]
+    capabilities: Vec<String>,
+    /// How the node talks to the guest of a function, as function=mode,guest_port,vsock_port
+    /// (repeatable); the mode is vsock (default) or http, for the images serving HTTP, and
+    /// each may be left empty for its default
+    #[arg(long = "function-guest")]
+    function_guests: Vec<FunctionGuest>,
+    /// Vcpus of the largest instance, the requests for more are clamped
+    #[arg(long)]
+    max_instance_vcpus: Option<i32>,
+    /// Memory of the largest instance (in MiB), the requests for more are clamped
+    #[arg(long)]
+    max_instance_memory: Option<i32>,
+    /// Directory of the state the node keeps across restarts, e.g. its id
+    #[arg(long, default_value = "/tmp/spare")]
+    data_dir: PathBuf,
+    /// File the position of the node is read from, as x,y, for the nodes that move
+    #[arg(long)]
+    position_file: Option<PathBuf>,
+    /// Time between two readings of the position file (in ms)
+    #[arg(long, default_value = "10000")]
+    position_interval: u64,
+    /// Run without a position, e.g. indoors where coordinates mean nothing: the neighbors
+    /// are ranked by the latencies measured by the Probed or SmartLatency strategy, and
+    /// the node is never inside an emergency zone
+    #[arg(long, default_value_t = false)]
+    positionless: bool,
+    /// Runtime of the instances: firecracker, or simulate to run the functions on the host
+    /// without KVM, e.g. on a laptop or in CI, with no isolation at all
+    #[arg(long, default_value = "firecracker")]
+    runtime: Runtime,
+    /// Time between two sweeps of the workspaces left behind by the instances (in s), 0
+    /// to sweep only at startup
+    #[arg(long, default_value = "600")]
+    workspace_sweep_interval: u64,
+    /// Keep the workspaces modified in the last seconds, they may be in the making
+    #[arg(long, default_value = "300")]
+    workspace_min_age: u64,
+    /// Only log the stale workspaces, without removing them
+    #[arg(long, default_value_t = false)]
+    workspace_sweep_dry_run: bool,
 }
 
 // Main function. It starts the server and the emergency controller
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
+    // Keep the last log lines in memory for the diagnostics bundle
+    let logs = log_ring::init(log_ring::DEFAULT_CAPACITY);
 
     // Parse arguments from command line
     let iggy_host = Args::parse().broker_address;
     let iggy_port = Args::parse().broker_port;
 
-    // Connect to the Iggy message broker
-    let iggy_client = IggyConnector::new(&format!("{iggy_host}:{iggy_port}")).await;
-
     // Registering Phase
     let worker_address = local_ip().unwrap();
     let worker_port = Args::parse().port;
 
+    // Connect to the Iggy message broker, with a consumer for each phase
+    let consumer_name = Args::parse()
+        .consumer_name
+        .unwrap_or_else(|| default_consumer_name(&worker_address.to_string(), worker_port));
+    let consumer = |phase| {
+        ConsumerConfig::new(&consumer_name, phase)
+            .with_partition(Args::parse().consumer_partition)
+            .with_polling(Args::parse().consumer_polling)
+    };
+    // Check the host before anything else, and report every problem at once
+    let readiness = Arc::new(Readiness::new());
+    let args = Args::parse();
+    let config = diagnostics::redact(&format!("{args:#?}"), &SECRETS);
+    let mut preflight = PreflightConfig::from_env(
+        &args.bridge_name,
+        args.legacy_sqlite,
+        args.registry
+            .is_none()
+            .then(|| format!("{iggy_host}:{iggy_port}")),
+    );
+    preflight.extra_bridges = args.networks.iter().map(|n| n.bridge.clone()).collect();
+    preflight.simulate = args.runtime == Runtime::Simulate;
+    preflight.network = Some(NetworkPlan {
+        cidr: args.cidr.clone(),
+        management: Some(worker_address),
+        broker: args
+            .registry
+            .is_none()
+            .then(|| iggy_host.parse().ok())
+            .flatten(),
+        bridge_address: None,
+    });
+    preflight.allow_network_conflicts = args.allow_network_conflicts;
+    preflight.positionless = args.positionless.then(|| PositionlessPlan {
+        strategy: NeighborNodeStrategy::from_env(),
+        position_file: args.position_file.is_some(),
+    });
+    if args.check {
+        let report = preflight::run(&preflight, &HostProbes::default()).await;
+        println!("{report}");
+        std::process::exit(if report.is_ok() { 0 } else { 1 });
+    }
+    // Serve HTTPS and call the other nodes over HTTPS, if the node has a certificate
+    let (server_tls, node_client) = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => {
+            let trust = match &args.tls_ca {
+                Some(ca) => Trust::Bundle(ca.clone()),
+                None if args.tls_insecure => Trust::Insecure,
+                None => {
+                    return Err(io::Error::other(
+                        "TLS needs --tls-ca, or --tls-insecure to skip the certificate checks",
+                    ))
+                }
+            };
+            // Prove the identity of the node to the others, and check theirs
+            let certificate = match (&args.tls_client_cert, &args.tls_client_key) {
+                (Some(cert), Some(key)) => Some(Identity {
+                    cert: cert.clone(),
+                    key: key.clone(),
+                }),
+                _ => None,
+            };
+            let clients = certificate.as_ref().and(args.tls_ca.as_deref());
+            let server = tls::server_config(cert, key, clients).map_err(io::Error::other)?;
+            let client =
+                tls::client_config(&trust, certificate.as_ref()).map_err(io::Error::other)?;
+            (Some(server), NodeClient::tls(client))
+        }
+        _ => (None, NodeClient::plain()),
+    };
+    // Bind the port at once: while the node starts and registers it tells its phase and
+    // refuses the requests, instead of refusing the connections
+    let listener = {
+        let socket = TcpSocket::new_v4()?;
+        socket.set_reuseaddr(true)?;
+        socket.bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, 8085)))?;
+        socket.listen(2048)?.into_std()?
+    };
+    let startup = {
+        let readiness = readiness.clone();
+        let server = HttpServer::new(move || {
+            App::new().configure(|cfg| configure_startup(cfg, readiness.clone()))
+        })
+        .workers(1);
+        match &server_tls {
+            Some(config) => server.listen_rustls_0_23(listener.try_clone()?, config.clone())?,
+            None => server.listen(listener.try_clone()?)?,
+        }
+        .disable_signals()
+        .run()
+    };
+    let startup_handle = startup.handle();
+    actix_web::rt::spawn(startup);
+
+    let report = preflight::run(&preflight, &HostProbes::default()).await;
+    if !report.is_ok() {
+        error!("The node cannot start:\n{report}");
+        std::process::exit(1);
+    }
+    info!("{report}");
+    readiness.advance(ReadinessPhase::Registering);
+    let diagnostics = Diagnostics {
+        logs,
+        config,
+        preflight: report.to_string(),
+    };
+
+    let topology = Topology {
+        stream_id: args.stream_id,
+        topic_id: args.topic_id,
+        announce_partition: args.announce_partition,
+        broadcast_partition: args.consumer_partition,
+    };
+    let credentials = match args.broker_token {
+        Some(token) => Credentials::Token(token),
+        None => Credentials::User {
+            username: args.broker_username,
+            password: args.broker_password,
+        },
+    };
+    let cluster_auth = ClusterAuth {
+        required: args.tls_client_cert.is_some(),
+    };
+    let dead_letters = Arc::new(DeadLetterLog::new(
+        args.dead_letter_file,
+        args.dead_letter_max_size,
+    ));
+    // Host the HTTP registry, if requested
+    if let Some(address) = &args.registry_server {
+        let address = registry::spawn_server(address, Registry::new(args.registry_nodes))?;
+        info!("Registry listening on {address}");
+    }
+
     // Register Node with (0, 0) position, we will update it later.
     // This is a temporary solution only used for the sake of the experiment.
-    let identity = Node {
-        address: format!("{worker_address}:{worker_port}"),
-        position: (0.0, 0.0),
+    let id = match identity::load_or_create_id(&Args::parse().data_dir) {
+        Ok(id) => id,
+        Err(e) => {
+            error!("Cannot load the id of the node: {e}");
+            return Err(e);
+        }
     };
-    info!("Registering node at {iggy_host}:{iggy_port}");
-    let _ = iggy_client.register_node(identity.clone()).await;
-
-    let mut nodes;
-
-    // Fetch remote nodes from the Iggy message broker
-    loop {
-        match iggy_client.receive_message().await {
-            Ok(Some(message)) => {
-                if message.op == Operation::ADD_NODES {
-                    match message.payload {
-                        Some(Payload::Nodes(n)) => {
-                            nodes = n;
-                            break;
-                        }
-                        _ => continue,
+    // Peers replace the announces of the previous starts of the node with this one
+    let incarnation = match identity::next_incarnation(&Args::parse().data_dir) {
+        Ok(incarnation) => incarnation,
+        Err(e) => {
+            error!("Cannot count the start of the node: {e}");
+            return Err(e);
+        }
+    };
+    let identity = Node::new(format!("{worker_address}:{worker_port}"), (0.0, 0.0))
+        .with_position((!args.positionless).then_some((0.0, 0.0)))
+        .with_id(id)
+        .with_tls(node_client.is_tls())
+        .with_incarnation(incarnation)
+        .with_capabilities(
+            std::iter::once(format!("arch:{}", std::env::consts::ARCH))
+                .chain(Args::parse().capabilities)
+                .collect(),
+        );
+    info!(
+        "Registering node {} ({}, start {})",
+        identity.address, identity.id, identity.incarnation
+    );
+
+    // Join the control plane. Connecting to the broker waits for it while it is down
+    let broker = format!("{iggy_host}:{iggy_port}");
+    let registry_url = args.registry.clone();
+    let consumers = (consumer(Phase::Registration), consumer(Phase::Broadcast));
+    let joining = {
+        let identity = identity.clone();
+        let registry_client = node_client.clone();
+        let dead_letters = dead_letters.clone();
+        async move {
+            let (registration_client, iggy_client) = match &registry_url {
+                Some(url) => {
+                    info!("Using the registry at {url}");
+                    (
+                        AnyControlPlane::Http(
+                            HttpControlPlane::new(url, Phase::Registration)
+                                .with_client(registry_client.clone()),
+                        ),
+                        AnyControlPlane::Http(
+                            HttpControlPlane::new(url, Phase::Broadcast)
+                                .with_client(registry_client),
+                        ),
+                    )
+                }
+                None => {
+                    let (registration, broadcast) = consumers;
+                    let connect =
+                        |consumer| IggyConnector::new(&broker, &credentials, topology, consumer);
+                    let registration_client = connect(registration)
+                        .await
+                        .map_err(|e| format!("Cannot log in to the broker at {broker}: {e}"))?;
+                    let iggy_client = connect(broadcast)
+                        .await
+                        .map_err(|e| format!("Cannot log in to the broker at {broker}: {e}"))?;
+                    if let Err(e) = registration_client.verify_topology().await {
+                        return Err(format!(
+                            "The broker at {broker} does not match {topology:?}: {e}"
+                        ));
                     }
+                    info!(
+                        "Consuming as {} and {}",
+                        registration_client.consumer().name,
+                        iggy_client.consumer().name
+                    );
+                    (
+                        AnyControlPlane::Iggy(registration_client),
+                        AnyControlPlane::Iggy(iggy_client),
+                    )
                 }
-            }
-            Ok(None) => continue,
-            Err(e) => {
-                error!("Error receiving message: {e}");
-            }
+            };
+            control_plane::join(
+                registration_client,
+                iggy_client,
+                identity,
+                topology,
+                &dead_letters,
+            )
+            .await
         }
     }
+    .boxed_local()
+    .shared();
+
+    // Wait for the list of nodes. A node that knew its neighbors starts from them if the
+    // control plane does not answer in time, and reconciles them once it does.
+    let cache = match NodeCache::load(&Args::parse().data_dir) {
+        Ok(cache) => cache,
+        Err(e) => {
+            error!("Cannot read the last known list of nodes: {e}");
+            None
+        }
+    };
+    let registration_timeout = Duration::from_secs(Args::parse().registration_timeout);
+    let (mut nodes, cache) = match cache {
+        Some(cache) if !registration_timeout.is_zero() => {
+            match select(joining.clone(), pin!(sleep(registration_timeout))).await {
+                Either::Left((joined, _)) => (joined.map_err(io::Error::other)?.nodes, None),
+                Either::Right(_) => {
+                    warn!(
+                        "No list of nodes from the control plane after {:?}, starting from the one saved at {}",
+                        registration_timeout, cache.saved_at
+                    );
+                    (cache.nodes.clone(), Some(cache))
+                }
+            }
+        }
+        _ => (joining.clone().await.map_err(io::Error::other)?.nodes, None),
+    };
+    let identity = match &cache {
+        // The node keeps the position it last had
+        Some(cache) => Node {
+            position: cache.identity.position,
+            ..identity
+        },
+        // A node whose announce was lost is left out of the list, e.g. when the benchmark
+        // proceeds with the nodes it has, it still serves its own requests
+        None => take_identity(&mut nodes, &identity).unwrap_or_else(|| {
+            warn!("The node is not in the list of nodes, its announce may have been lost");
+            identity
+        }),
+    };
+    // Whatever position the list of nodes gives it
+    let identity = match args.positionless {
+        true => identity.with_position(None),
+        false => identity,
+    };
+
+    // The registration is over, the announcements of the node go through the same client
+    let announcer = Announcer::spawn(Deferred::new(
+        joining
+            .clone()
+            .map(|joined| joined.ok().map(|joined| joined.registration)),
+    ));
+    let iggy_client = Deferred::new(
+        joining
+            .clone()
+            .map(|joined| joined.ok().map(|joined| joined.broadcast)),
+    );
 
-    // Extract identity (this node) from the list of nodes
-    let identity = nodes
-        .iter()
-        .position(|n| n.address == format!("{worker_address}:{worker_port}"))
-        .map(|i| nodes.remove(i))
-        .unwrap();
     info!("Found {} nodes", nodes.len());
+    // Never fall back to cleartext with the nodes that do not use TLS
+    if let Err(e) = tls::check_cluster(node_client.is_tls(), &nodes) {
+        error!("{e}");
+        return Err(io::Error::other(e));
+    }
     for node in &nodes {
         info!(
             "Added node: {}, position: {:?}",
@@ -200,44 +768,159 @@ async fn main() -> std::io::Result<()> {
     }
 
     // Create orchestrator
-    let orchestrator = Arc::new(orchestrator::Orchestrator::new(nodes, identity.clone()));
+    let args = Args::parse();
+    let cost_model = CostModel {
+        default_service_time: args.default_service_time,
+        wait_factor: args.wait_factor,
+        default_latency: args.default_latency,
+        latency_factor: args.latency_factor,
+        offload_overhead: args.offload_overhead,
+        max_wait: args.max_local_wait,
+    };
+    if let Err(e) = cost_model.validate() {
+        panic!("Invalid cost model: {e}");
+    }
+    if !(0.0..=1.0).contains(&args.emergency_weight) {
+        panic!("Invalid emergency weight: {}", args.emergency_weight);
+    }
+    if !(args.cpu_overcommit.is_finite() && args.cpu_overcommit >= 1.0) {
+        panic!("Invalid cpu overcommit: {}", args.cpu_overcommit);
+    }
+    let pressure_thresholds = PressureThresholds {
+        high: args.memory_pressure_high,
+        low: args.memory_pressure_low,
+        min_available: args.memory_pressure_min_available_mb * 1024,
+    };
+    if let Err(e) = pressure_thresholds.validate() {
+        panic!("Invalid memory pressure thresholds: {e}");
+    }
+    let orchestrator = Arc::new(
+        orchestrator::Orchestrator::new(nodes, identity.clone())
+            .with_index_threshold(args.spatial_index_threshold)
+            .with_sort_policy(args.sort_policy)
+            .with_cost_model(cost_model)
+            .with_sticky_offload(!args.no_sticky_offload)
+            .with_raw_offload(!args.legacy_offload)
+            .with_offload_traces(args.trace_offloads)
+            .with_packing_hold(Duration::from_millis(args.packing_hold))
+            .with_chaos(args.chaos)
+            .with_emergency_weight(args.emergency_weight)
+            .with_cpu_overcommit(args.cpu_overcommit)
+            .with_memory_pressure(pressure_thresholds)
+            .with_image_affinity(args.offload_image)
+            .with_reserve(Reserve {
+                cpus: args.reserve_vcpus,
+                memory: args.reserve_memory_mb * 1024,
+            })
+            .with_node_client(node_client.clone())
+            .with_readiness(readiness.clone())
+            .with_function_resources(args.function_resources)
+            .with_function_requires(args.function_requires)
+            .with_cached_topology(cache.as_ref().map(|cache| cache.saved_at))
+            .with_node_cache(args.data_dir.clone())
+            .with_instance_limits(ResourceSpec {
+                vcpus: args.max_instance_vcpus,
+                memory: args.max_instance_memory,
+            }),
+    );
     let orchestrator_clone = orchestrator.clone();
 
-    // Fetch the Firecracker executable and the Nanos kernel
-    // These must be set in the environment variables FIRECRACKER_EXECUTABLE and NANOS_KERNEL
-    let executable = match env::var("FIRECRACKER_EXECUTABLE") {
-        Ok(val) => {
-            // Check if file exists
-            if Path::new(&val).exists() {
-                val
-            } else {
-                panic!("Cannot find Firecracker executable in: {val}");
+    // Reconcile the last known list of nodes with the one of the control plane, once it
+    // answers again
+    if cache.is_some() {
+        let orchestrator = orchestrator.clone();
+        let identity = identity.clone();
+        let tls = node_client.is_tls();
+        actix_web::rt::spawn(async move {
+            let mut nodes = match joining.await {
+                Ok(joined) => joined.nodes,
+                Err(e) => {
+                    error!(
+                        "Cannot join the control plane, keeping the last known list of nodes: {e}"
+                    );
+                    return;
+                }
+            };
+            take_identity(&mut nodes, &identity);
+            // Never fall back to cleartext with the nodes that do not use TLS
+            if let Err(e) = tls::check_cluster(tls, &nodes) {
+                error!("{e}, keeping the last known list of nodes");
+                return;
             }
-        }
-        Err(_) => {
-            panic!("FIRECRACKER_EXECUTABLE environment variable not set");
-        }
-    };
+            let (added, removed) = orchestrator.reconcile(nodes);
+            info!(
+                "Joined the control plane: {} nodes added and {} removed from the last known list",
+                added, removed
+            );
+        });
+    }
 
-    let kernel = match env::var("NANOS_KERNEL") {
-        Ok(val) => {
-            // Check if file exists
-            if Path::new(&val).exists() {
-                val
-            } else {
-                panic!("Cannot find Nanos kernel in: {val}");
-            }
-        }
-        Err(_) => {
-            panic!("NANOS_KERNEL environment variable not set");
-        }
-    };
+    // The Firecracker executable and the Nanos kernel, checked by the preflight unless the
+    // instances are simulated
+    let executable = preflight.executable.unwrap_or_default();
+    let kernel = preflight.kernel.unwrap_or_default();
 
     // Fetch the bridge name from the arguments
     let bridge = Args::parse().bridge_name.to_owned();
 
     // Establish connection to the database
-    let pool = db::establish_connection().await.unwrap();
+    let legacy_sqlite = Args::parse().legacy_sqlite;
+    let pool = db::establish_connection_with(legacy_sqlite).await.unwrap();
+
+    // Record how the node was started, the instances and the requests are stamped with it
+    let kernel_sha256 = match args.runtime {
+        Runtime::Firecracker => match fs::read(&kernel) {
+            Ok(image) => Some(hex::encode(Sha256::digest(image))),
+            Err(e) => {
+                error!("Cannot read the kernel {kernel}: {e}");
+                None
+            }
+        },
+        Runtime::Simulate => None,
+    };
+    let mut run = NodeRun::new(
+        identity.address.clone(),
+        orchestrator.get_strategy().to_string(),
+        args.runtime.as_str().to_string(),
+        args.cidr.clone(),
+    )
+    .with_limits(serde_json::json!({
+        "max_instance_vcpus": args.max_instance_vcpus,
+        "max_instance_memory": args.max_instance_memory,
+        "reserve_vcpus": args.reserve_vcpus,
+        "reserve_memory_mb": args.reserve_memory_mb,
+        "cpu_overcommit": args.cpu_overcommit,
+    }))
+    .with_machines(
+        report.found("firecracker").map(str::to_string),
+        kernel_sha256,
+    )
+    .with_config(diagnostics.config.clone());
+    match run.insert(&pool).await {
+        Ok(()) => info!(
+            "Started run {} of the node ({})",
+            run.id,
+            run.git_hash.as_deref().unwrap_or("unknown commit")
+        ),
+        Err(e) => error!("Cannot record the run of the node: {e}"),
+    }
+
+    // Estimate the local service time from the last calibration, if any
+    match Calibration::last_run(&pool).await {
+        Ok(run) if !run.is_empty() => {
+            let summary =
+                CalibrationSummary::new(run[0].run_id.clone(), run[0].image.clone(), &run);
+            if let Some(service_time) = summary.service_time() {
+                info!(
+                    "Service time from calibration {}: {service_time:.3} ms",
+                    summary.run_id
+                );
+                orchestrator.scheduler().calibrate(service_time);
+            }
+        }
+        Ok(_) => info!("The node was never calibrated"),
+        Err(e) => error!("Cannot read the last calibration: {e}"),
+    }
 
     // Parse CIDR from arguments
     let cidr = Args::parse().cidr;
@@ -249,50 +932,367 @@ async fn main() -> std::io::Result<()> {
     )
     .unwrap();
 
+    // The other networks, and the functions pinned to them
+    let args = Args::parse();
+    let mut networks = Networks::new(bridge.clone(), addresses.clone());
+    for spec in args.networks {
+        match Addresses::new(spec.address, spec.prefix) {
+            Ok(addresses) => networks = networks.with_network(&spec.name, spec.bridge, addresses),
+            Err(e) => panic!("Invalid network {}: {e}", spec.name),
+        }
+    }
+    for pin in args.function_networks {
+        networks = match networks.with_pin(&pin.function, &pin.network) {
+            Ok(networks) => networks,
+            Err(e) => panic!("Invalid network of {}: {e}", pin.function),
+        };
+    }
+    if args.manage_firewall {
+        if let Err(e) = networks.install_isolation() {
+            panic!("Cannot isolate the networks: {e}");
+        }
+    }
+
+    // Select the protocol spoken with the guests
+    let guest_protocol = if Args::parse().legacy_guest_protocol {
+        GuestProtocol::V1
+    } else {
+        GuestProtocol::V2
+    };
+
+    // Node-level default rate limits
+    let args = Args::parse();
+    let rate_limits = RateLimits {
+        rx_bandwidth: args.net_rx_bandwidth.map(Bucket::per_second),
+        tx_bandwidth: args.net_tx_bandwidth.map(Bucket::per_second),
+        disk_bandwidth: args.disk_bandwidth.map(Bucket::per_second),
+        disk_ops: args.disk_ops.map(Bucket::per_second),
+    };
+    if let Err(e) = rate_limits.validate() {
+        panic!("Invalid default rate limits: {e}");
+    }
+
+    // Run the blocking steps of the creation of the instances off the HTTP workers
+    if args.blocking_threads == 0 {
+        panic!("The number of blocking threads must be positive");
+    }
+    let blocking = BlockingPool::new(args.blocking_policy, args.blocking_threads);
+    info!(
+        "Running the blocking steps {:?}, {} at a time",
+        blocking.policy(),
+        args.blocking_threads
+    );
+
+    // The simulated instances have no machine to jail or confine, and no network
+    let simulate = args.runtime == Runtime::Simulate;
+    if simulate {
+        let unsupported = [
+            (args.legacy_guest_protocol, "--legacy-guest-protocol"),
+            (args.use_jailer.is_some(), "--use-jailer"),
+            (args.enforce_cgroups, "--enforce-cgroups"),
+            (args.manage_firewall, "--manage-firewall"),
+            (
+                args.function_guests
+                    .iter()
+                    .any(|guest| guest.config.mode == GuestMode::Http),
+                "--function-guest in http mode",
+            ),
+        ];
+        if let Some((_, option)) = unsupported.iter().find(|(used, _)| *used) {
+            panic!("{option} cannot be used with --runtime simulate");
+        }
+        info!("Simulating the instances: the functions run on the host, without isolation");
+    }
+    let image_mode = match simulate {
+        true => ImageMode::SharedRo,
+        false => args.image_mode,
+    };
+
     // Create a new FirecrackerBuilder
-    let builder = Arc::new(FirecrackerBuilder::new(
-        executable,
-        kernel,
-        bridge,
-        addresses.clone(),
+    let mut builder = FirecrackerBuilder::new(executable, kernel, bridge, addresses.clone())
+        .with_networks(networks)
+        .with_guest_protocol(guest_protocol)
+        .with_function_guests(args.function_guests)
+        .with_rate_limits(rate_limits)
+        .with_image_mode(image_mode, args.overlay_dir)
+        .with_image_cache(
+            ImageCache::new(args.image_cache_dir, args.image_cache_size << 20)
+                .with_blocking_pool(blocking.clone())
+                .with_client(node_client.clone()),
+        )
+        .with_image_dirs(args.image_dirs)
+        .with_address_wait(Duration::from_millis(args.address_wait))
+        .with_blocking_pool(blocking);
+    if let Some(jailer) = args.use_jailer {
+        if !jailer.exists() {
+            panic!("Cannot find jailer in: {}", jailer.display());
+        }
+        builder = builder.with_jailer(Jailer {
+            binary: jailer,
+            chroot_base: args.jailer_chroot_base,
+            uid_base: args.jailer_uid_base,
+        });
+    }
+    if args.enforce_cgroups {
+        match Cgroups::new(PathBuf::from("/sys/fs/cgroup"), args.cgroup_parent) {
+            Ok(cgroups) => builder = builder.with_cgroups(cgroups),
+            Err(e) => panic!("Cannot set up the parent cgroup: {e}"),
+        }
+    }
+    if simulate {
+        builder = builder
+            .with_tap_factory(Arc::new(MockTaps))
+            .with_execution_environment(Arc::new(SimulatedExecutionEnvironment));
+    }
+    let builder = Arc::new(builder);
+
+    // Remove the workspaces left behind by the crashes, the simulated instances have none
+    if !simulate {
+        actix_web::rt::spawn(workspaces::run(
+            builder.workspaces.clone(),
+            builder.blocking.clone(),
+            SweepConfig {
+                min_age: Duration::from_secs(args.workspace_min_age),
+                interval: Duration::from_secs(args.workspace_sweep_interval),
+                dry_run: args.workspace_sweep_dry_run,
+            },
+        ));
+    }
+
+    // List the images of the node for the offloads of the other nodes
+    actix_web::rt::spawn(inventory::run(
+        builder.images.clone(),
+        builder.blocking.clone(),
+        Duration::from_secs(args.image_scan_interval),
     ));
 
-    let pool_clone = pool.clone();
+    // Resolve the hostnames of the neighbor nodes ahead of the offloads
+    actix_web::rt::spawn(node_client.hosts().clone().run(
+        orchestrator.clone(),
+        Duration::from_secs(args.resolve_interval),
+    ));
 
-    let shutdown = Arc::new(Mutex::new(false));
-    let shutdown_clone = shutdown.clone();
+    // Measure the latency of the neighbor nodes, if the strategy needs it
+    if orchestrator.get_strategy() == NeighborNodeStrategy::Probed {
+        if !(args.probe_weight > 0.0 && args.probe_weight <= 1.0) {
+            panic!("Invalid probe weight: {}", args.probe_weight);
+        }
+        let probe = LatencyProbe::new(
+            HttpProber::new(node_client),
+            ProbeConfig {
+                interval: Duration::from_millis(args.probe_interval),
+                weight: args.probe_weight,
+                timeout: Duration::from_millis(args.probe_timeout),
+            },
+        );
+        actix_web::rt::spawn(probe.run(orchestrator.clone()));
+    }
+
+    // Stop admitting local instances while the host is short of memory
+    if args.memory_pressure_interval > 0 {
+        actix_web::rt::spawn(pressure::run(
+            ProcPressure,
+            orchestrator.clone(),
+            Duration::from_millis(args.memory_pressure_interval),
+        ));
+    }
 
-    // Start emergency controller
-    let emergency_controller = std::thread::spawn(move || {
-        emergency_controller(
-            pool.clone(),
-            orchestrator_clone,
-            iggy_client,
-            shutdown_clone,
+    // Follow the position of the node, if it moves
+    if let Some(path) = args.position_file.clone() {
+        let tracker = PositionTracker::new(
+            FilePosition::new(path),
+            Duration::from_millis(args.position_interval),
         );
+        actix_web::rt::spawn(tracker.run(orchestrator.clone(), announcer.clone()));
+    }
+
+    let pool_clone = pool.clone();
+    let request_log = RequestLog::spawn(pool.clone());
+    let status_writer = if legacy_sqlite {
+        StatusWriter::Direct(pool.clone())
+    } else {
+        StatusWriter::spawn(pool.clone())
+    };
+
+    let idempotency = Arc::new(IdempotencyCache::new(IdempotencyConfig {
+        ttl: Duration::from_secs(args.idempotency_ttl),
+        max_entries: args.idempotency_max_entries,
+        max_body_size: args.idempotency_max_body_size,
+    }));
+
+    let admin_token = AdminToken(args.admin_token);
+
+    // Load the quotas, and the usage of the instances the database says are running. Those
+    // the previous runs left started died with them
+    let quotas = Arc::new(QuotaTracker::new());
+    match Quota::list(&pool).await {
+        Ok(stored) => {
+            for quota in stored {
+                quotas.set_limits(&quota.api_key, quota.limits());
+            }
+        }
+        Err(e) => error!("Cannot read the quotas: {e}"),
+    }
+    match Instance::fail_stale(&pool).await {
+        Ok(0) => {}
+        Ok(failed) => warn!("Failed {failed} instances left started by the previous runs"),
+        Err(e) => error!("Cannot fail the instances of the previous runs: {e}"),
+    }
+    match Quota::usage(&pool).await {
+        Ok(usage) => quotas.reconcile(usage),
+        Err(e) => error!("Cannot read the usage of the quotas: {e}"),
+    }
+
+    // Tell the service manager when the node is ready, and that it is still alive
+    let mut notifiers = Notifiers::new();
+    let mut health_interval = Duration::from_millis(args.health_interval);
+    if args.systemd_notify {
+        match SystemdNotifier::from_env() {
+            Some(systemd) => {
+                notifiers = notifiers.with(systemd);
+                if let Some(watchdog) = notify::systemd_watchdog() {
+                    health_interval = health_interval.min(watchdog / 2);
+                }
+            }
+            None => error!("NOTIFY_SOCKET is not set, the node does not run under systemd"),
+        }
+    }
+    if let Some(path) = args.liveness_file {
+        notifiers = notifiers.with(LivenessFile::new(path));
+    }
+    let notifiers = Arc::new(notifiers);
+    let health_pool = pool.clone();
+
+    let shutdown = CancellationToken::new();
+
+    let (stats_output, stats_format) = (args.stats_output, args.stats_format);
+    let (http_workers, blocking_threads) = (args.http_workers, args.blocking_threads);
+    let compression = args.compression;
+    let max_body_size = args.max_body_size << 20;
+    let spill = SpillConfig::new(args.spill_dir, args.spill_threshold << 20, max_body_size);
+    match spill.prepare() {
+        Ok(0) => {}
+        Ok(removed) => info!("Removed {} payloads left by the previous run", removed),
+        Err(e) => panic!(
+            "Cannot create the spill directory {}: {e}",
+            spill.dir.display()
+        ),
+    }
+
+    // Start the emergency controller, started again if it panics. The end of the
+    // experiment stops it, not the rest of the node.
+    let path = stats_path(&stats_output, &identity.address, identity.position);
+    let writer = StatsWriter::create(
+        path.clone(),
+        stats_format,
+        &identity.address,
+        &orchestrator.get_strategy().to_string(),
+    )
+    .unwrap_or_else(|e| panic!("Cannot create the stats file {}: {e}", path.display()));
+    // The controller runs on the task of the supervisor, it is never shared across threads
+    let controller = Rc::new(EmergencyController::new(
+        pool.clone(),
+        orchestrator_clone,
+        iggy_client,
+        dead_letters,
+        writer,
+    ));
+    let supervisor = Arc::new(Supervisor::new(Backoff::default()));
+    let emergency_controller = supervisor.supervise(
+        "emergency controller",
+        shutdown.child_token(),
+        move |cancel| {
+            let controller = controller.clone();
+            async move { controller.run(cancel).await }
+        },
+    );
+
+    // Fire the scheduled invocations through the same path as /invoke
+    if args.schedule_tick == 0 {
+        panic!("The schedule tick must be positive");
+    }
+    let runner = Runner::new(
+        pool_clone.clone(),
+        SystemClock,
+        args.schedule_catch_up,
+        Duration::from_millis(args.schedule_tick),
+    );
+    let context = InvokeContext {
+        db_pool: pool_clone.clone(),
+        firecracker_builder: Data::new(builder.clone()),
+        orchestrator: orchestrator.clone(),
+        request_log: request_log.clone(),
+        status_writer: status_writer.clone(),
+        quotas: quotas.clone(),
+    };
+    let schedule_context = context.clone();
+    actix_web::rt::spawn(async move {
+        runner
+            .run(|data| {
+                let context = schedule_context.clone();
+                async move { invoke_unattended(&context, data).await }
+            })
+            .await
     });
 
-    // Start the web server
+    // Start the web server on the port bound at startup, in place of the startup one. The
+    // connections arriving meanwhile wait in the backlog of the port
+    startup_handle.stop(true).await;
+    let state = NodeState {
+        context,
+        idempotency,
+        admin_token,
+        cluster_auth,
+        compression,
+        spill,
+        announcer: announcer.clone(),
+        diagnostics,
+        supervisor,
+    };
     let server = HttpServer::new(move || {
         App::new()
-            .wrap(middleware::Compress::default()) // Create option to enable or disable gzip compression
-            .app_data(JsonConfig::default().limit(1024 * 1024 * 50))
-            .app_data(Data::new(pool_clone.clone()))
-            .app_data(Data::new(builder.clone()))
-            .app_data(Data::new(orchestrator.clone()))
-            .service(index)
-            .service(list)
-            .service(invoke)
-            .service(resources)
-            .service(emergency)
+            .wrap(middleware::Condition::new(
+                compression.enabled(),
+                middleware::Compress::default(),
+            ))
+            .configure(|cfg| state.configure(cfg))
     })
-    .backlog(2048)
-    .bind(("0.0.0.0", 8085))?
+    .worker_max_blocking_threads(blocking_threads)
+    .on_connect(tls::on_connect);
+    let server = match http_workers {
+        Some(workers) => server.workers(workers),
+        None => server,
+    };
+    let server = match server_tls {
+        Some(config) => server.listen_rustls_0_23(listener, config)?,
+        None => server.listen(listener)?,
+    }
     .disable_signals()
     .run();
 
     let server_handle = server.handle();
 
+    // Registered and serving: the node is ready
+    readiness.advance(ReadinessPhase::Ready);
+    notifiers.notify(State::Ready);
+    if !notifiers.is_empty() {
+        let notifiers = notifiers.clone();
+        actix_web::rt::spawn(async move {
+            notifiers
+                .watchdog(health_interval, || {
+                    let pool = health_pool.clone();
+                    async move {
+                        sqlx::query("SELECT 1")
+                            .execute(&pool)
+                            .await
+                            .map(|_| ())
+                            .map_err(|e| format!("the database does not answer: {e}"))
+                    }
+                })
+                .await
+        });
+    }
+
     // Start the shutdown controller.
     //
     let shutdown = actix_web::rt::spawn(async move {
@@ -300,8 +1300,9 @@ async fn main() -> std::io::Result<()> {
         actix_web::rt::signal::ctrl_c().await.unwrap();
 
         // start shutdown of tasks
+        notifiers.notify(State::Stopping);
         let server_stop = server_handle.stop(true);
-        *shutdown.lock().unwrap() = true;
+        shutdown.cancel();
 
         // await shutdown of tasks
         server_stop.await;
@@ -311,7 +1312,7 @@ async fn main() -> std::io::Result<()> {
 
     shutdown.await?;
 
-    emergency_controller.join().unwrap();
+    emergency_controller.await?;
 
     Ok(())
 }
@@ -38,6 +38,11 @@ impl Addresses {
         }
     }
 
+    /// Get the number of addresses left.
+    pub fn available(&self) -> usize {
+        self.available.len()
+    }
+
     /// Get the network gateway (first usable IP).
     pub fn get_gateway(&self) -> Ipv4Addr {
         self.network.nth(1).unwrap_or(self.network.network())
@@ -0,0 +1,211 @@
+//! Control plane of the experiment.
+//! The nodes announce themselves, wait for the list of their neighbors and then
+//! follow the emergencies and the stats requests. These operations are carried either
+//! by the Iggy message broker or, for small deployments, by the HTTP registry.
+use std::{future::Future, rc::Rc};
+
+use futures::{
+    channel::mpsc,
+    future::{LocalBoxFuture, Shared},
+    FutureExt, StreamExt,
+};
+use log::{error, info};
+
+use super::{
+    dead_letter::DeadLetterLog,
+    iggy::{IggyConnector, Message, MessageError, Operation, Payload, Topology},
+    registry::HttpControlPlane,
+};
+use crate::orchestrator::global::identity::Node;
+
+/// Channel the node receives the operations of the experiment from
+pub trait ControlPlane {
+    /// Announce the node to the other participants
+    fn register_node(&self, node: Node) -> impl Future<Output = Result<(), MessageError>>;
+
+    /// Receive the next message for the phase of the client, if any.
+    /// It does not block waiting for a message.
+    fn receive_message(&self) -> impl Future<Output = Result<Option<Message>, MessageError>>;
+
+    /// Send a message of the node to the other nodes, e.g. that it is draining
+    fn announce(&self, message: Message) -> impl Future<Output = Result<(), MessageError>>;
+}
+
+/// Control plane selected on the command line
+pub enum AnyControlPlane {
+    Iggy(IggyConnector),
+    Http(HttpControlPlane),
+}
+
+impl ControlPlane for AnyControlPlane {
+    async fn register_node(&self, node: Node) -> Result<(), MessageError> {
+        match self {
+            AnyControlPlane::Iggy(client) => client.register_node(node).await,
+            AnyControlPlane::Http(client) => client.register_node(node).await,
+        }
+    }
+
+    async fn receive_message(&self) -> Result<Option<Message>, MessageError> {
+        match self {
+            AnyControlPlane::Iggy(client) => client.receive_message().await,
+            AnyControlPlane::Http(client) => client.receive_message().await,
+        }
+    }
+
+    async fn announce(&self, message: Message) -> Result<(), MessageError> {
+        match self {
+            AnyControlPlane::Iggy(client) => client.announce(message).await,
+            AnyControlPlane::Http(client) => client.announce(message).await,
+        }
+    }
+}
+
+/// Control plane still being joined, e.g. while the broker is down and the node started
+/// from its last known list of nodes. The calls wait until it is joined, forever if
+/// joining it failed.
+pub struct Deferred<C> {
+    control_plane: Shared<LocalBoxFuture<'static, Option<Rc<C>>>>,
+}
+
+impl<C: 'static> Deferred<C> {
+    /// Create a control plane joined once `join` is done, None if joining it failed
+    pub fn new(join: impl Future<Output = Option<Rc<C>>> + 'static) -> Self {
+        Self {
+            control_plane: join.boxed_local().shared(),
+        }
+    }
+
+    async fn joined(&self) -> Rc<C> {
+        match self.control_plane.clone().await {
+            Some(control_plane) => control_plane,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl<C: ControlPlane + 'static> ControlPlane for Deferred<C> {
+    async fn register_node(&self, node: Node) -> Result<(), MessageError> {
+        self.joined().await.register_node(node).await
+    }
+
+    async fn receive_message(&self) -> Result<Option<Message>, MessageError> {
+        self.joined().await.receive_message().await
+    }
+
+    async fn announce(&self, message: Message) -> Result<(), MessageError> {
+        self.joined().await.announce(message).await
+    }
+}
+
+/// Queue of the announcements of the node.
+/// The control plane is bound to the runtime it was connected from, so the workers
+/// only queue the announcements and a task of that runtime sends them.
+#[derive(Clone)]
+pub struct Announcer {
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+impl Announcer {
+    /// Create a new announcer and spawn the task that sends the announcements.
+    /// Must be called from within an actix runtime.
+    pub fn spawn(control_plane: impl ControlPlane + 'static) -> Self {
+        let (sender, receiver) = mpsc::unbounded();
+        actix_web::rt::spawn(send_announcements(control_plane, receiver));
+        Self { sender }
+    }
+
+    /// Queue an announcement, it is sent later
+    pub fn announce(&self, message: Message) {
+        if self.sender.unbounded_send(message).is_err() {
+            error!("The announcer is closed, announcement not sent");
+        }
+    }
+}
+
+/// Send the queued announcements until all the senders are dropped
+async fn send_announcements(
+    control_plane: impl ControlPlane,
+    mut receiver: mpsc::UnboundedReceiver<Message>,
+) {
+    while let Some(message) = receiver.next().await {
+        let op = format!("{:?}", message.op);
+        match control_plane.announce(message).await {
+            Ok(()) => info!("Announced {op}"),
+            Err(e) => error!("Cannot announce {op}: {e}"),
+        }
+    }
+}
+
+/// The node joined to the control plane
+#[derive(Clone)]
+pub struct Joined {
+    /// Client the node registered with, the announcements of the node go through it
+    pub registration: Rc<AnyControlPlane>,
+    /// Client the operations of the experiment are received from
+    pub broadcast: Rc<AnyControlPlane>,
+    /// Nodes listed by the control plane, the node itself included
+    pub nodes: Vec<Node>,
+}
+
+/// Take the node itself out of a list of nodes, in which it may be more than once, e.g.
+/// after a quick restart
+/// # Returns
+/// * The node as listed, with the id, the incarnation and the capabilities of `identity`,
+///   which the older control planes drop. None if it is not in the list.
+pub fn take_identity(nodes: &mut Vec<Node>, identity: &Node) -> Option<Node> {
+    let listed = nodes.iter().position(|n| n.is_same(identity)).map(|i| {
+        nodes
+            .remove(i)
+            .with_id(identity.id.clone())
+            .with_incarnation(identity.incarnation)
+            .with_capabilities(identity.capabilities.clone())
+    });
+    nodes.retain(|n| !n.is_same(identity));
+    listed
+}
+
+/// Register the node and wait for the list of nodes
+/// # Arguments
+/// * `registration` - Client to register with
+/// * `broadcast` - Client to receive the operations of the experiment from, once joined
+/// * `identity` - The node itself
+/// * `topology` - Topology of the broker, checked against the one of the benchmark
+/// * `dead_letters` - Log of the messages that cannot be handled
+pub async fn join(
+    registration: AnyControlPlane,
+    broadcast: AnyControlPlane,
+    identity: Node,
+    topology: Topology,
+    dead_letters: &DeadLetterLog,
+) -> Result<Joined, String> {
+    if let Err(e) = registration.register_node(identity).await {
+        error!("Cannot register the node: {e}");
+    }
+    loop {
+        match registration.receive_message().await {
+            Ok(Some(message)) => {
+                // The benchmark announces its topology, stop here if it differs from ours
+                if let Some(Payload::Topology(expected)) = &message.payload {
+                    topology.check(expected).map_err(|e| e.to_string())?;
+                    info!("Topology confirmed by the benchmark");
+                }
+                if message.op == Operation::ADD_NODES {
+                    if let Some(Payload::Nodes(nodes)) = message.payload {
+                        return Ok(Joined {
+                            registration: Rc::new(registration),
+                            broadcast: Rc::new(broadcast),
+                            nodes,
+                        });
+                    }
+                }
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                error!("Error receiving message: {e}");
+                if let Err(e) = dead_letters.record(&e) {
+                    error!("Cannot record the message in the dead letter log: {e}");
+                }
+            }
+        }
+    }
+}
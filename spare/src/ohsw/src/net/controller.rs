@@ -0,0 +1,464 @@
+//! Controller of the node on the control plane. It follows the emergencies, the other nodes
+//! draining or moving and the chaos commands, and writes and reports the stats of each
+//! epoch of the benchmark.
+//! The controller runs under the supervisor of the node, which starts it again if it
+//! panics, so what must outlive a run, the stats file and the count of the epochs, is
+//! kept in the controller rather than in the run.
+use std::{
+    io::Write,
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use futures::future::{select, Either};
+use log::{error, info, warn};
+use sqlx::{sqlite, Pool};
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    control_plane::ControlPlane,
+    dead_letter::DeadLetterLog,
+    iggy::{Message, Operation, Payload, Period, StatsReport},
+    node_address,
+};
+use crate::{
+    db,
+    orchestrator::{global::emergency::Emergency, Orchestrator},
+    utils::stats::StatsWriter,
+};
+
+/// Controller that handles the emergency mode and the other operations of the experiment
+pub struct EmergencyController<C, W: Write> {
+    pool: Pool<sqlite::Sqlite>,
+    orchestrator: Arc<Orchestrator>,
+    control_plane: C,
+    dead_letters: Arc<DeadLetterLog>,
+    writer: Mutex<StatsWriter<W>>,
+    /// Epochs are counted from 0, in the order they were asked
+    eras: AtomicU64,
+}
+
+impl<C: ControlPlane, W: Write> EmergencyController<C, W> {
+    /// Create a new controller, writing the stats of the epochs with `writer`
+    pub fn new(
+        pool: Pool<sqlite::Sqlite>,
+        orchestrator: Arc<Orchestrator>,
+        control_plane: C,
+        dead_letters: Arc<DeadLetterLog>,
+        writer: StatsWriter<W>,
+    ) -> Self {
+        EmergencyController {
+            pool,
+            orchestrator,
+            control_plane,
+            dead_letters,
+            writer: Mutex::new(writer),
+            eras: AtomicU64::new(0),
+        }
+    }
+
+    /// Handle the messages of the control plane until `cancel` is cancelled.
+    /// The end of the experiment cancels it too.
+    pub async fn run(&self, cancel: CancellationToken) {
+        // The messages wait in the control plane until the node serves requests, so an
+        // emergency is entered with the neighbors to offload to
+        let ready = self.orchestrator.readiness().ready_to_serve();
+        if let Either::Left(_) = select(pin!(cancel.cancelled()), pin!(ready)).await {
+            return;
+        }
+        loop {
+            let received = match select(
+                pin!(cancel.cancelled()),
+                pin!(self.control_plane.receive_message()),
+            )
+            .await
+            {
+                Either::Left(_) => return,
+                Either::Right((received, _)) => received,
+            };
+            match received {
+                Ok(Some(msg)) if msg.op == Operation::END => {
+                    info!("The experiment ended");
+                    cancel.cancel();
+                    return;
+                }
+                Ok(Some(msg)) => self.handle(msg).await,
+                Ok(None) => {}
+                Err(e) => {
+                    error!("Error receiving message: {e}");
+                    if let Err(e) = self.dead_letters.record(&e) {
+                        error!("Cannot record the message in the dead letter log: {e}");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a message of the control plane
+    async fn handle(&self, msg: Message) {
+        let orchestrator = &self.orchestrator;
+        let identity = orchestrator.get_identity();
+        match msg.op {
+            Operation::START_EMERGENCY => {
+                if let Some(Payload::Emergency(em_pos)) = msg.payload {
+                    match &em_pos.polygon {
+                        Some(vertices) => info!(
+                            "Emergency mode activated in a polygon of {} vertices around: {:?}",
+                            vertices.len(),
+                            em_pos.position
+                        ),
+                        None => info!(
+                            "Emergency mode activated at position: {:?} with radius: {}",
+                            em_pos.position, em_pos.radius
+                        ),
+                    }
+                    let mut event = orchestrator.set_emergency(true, em_pos);
+                    if let Err(e) = event.insert(&self.pool).await {
+                        error!("Cannot record the emergency: {e}");
+                    }
+                }
+            }
+            Operation::STOP_EMERGENCY => {
+                let mut event = orchestrator.set_emergency(
+                    false,
+                    Emergency {
+                        position: (0.0, 0.0),
+                        radius: 0.0,
+                        polygon: None,
+                    },
+                );
+                if let Err(e) = event.insert(&self.pool).await {
+                    error!("Cannot record the end of the emergency: {e}");
+                }
+                info!("Emergency mode deactivated");
+            }
+            Operation::DRAINING | Operation::RESUMED => {
+                if let Some(Payload::Nodes(nodes)) = msg.payload {
+                    let draining = msg.op == Operation::DRAINING;
+                    for node in nodes {
+                        // Our own announcement comes back too
+                        if node.is_same(&identity) {
+                            continue;
+                        }
+                        let address = match node_address::validate(&node.address) {
+                            Ok(address) => address,
+                            Err(e) => {
+                                warn!("Ignoring the announce of {}: {}", node.key(), e);
+                                continue;
+                            }
+                        };
+                        if let Some(previous) = orchestrator.observe_neighbor(&node) {
+                            info!("Node {} moved from {}", address, previous);
+                        }
+                        info!("Node {} draining: {}", address, draining);
+                        orchestrator.set_neighbor_draining(&address, draining);
+                    }
+                }
+            }
+            Operation::UPDATE_POSITION => match msg.payload {
+                // Our own position comes back too
+                Some(Payload::Position(update)) if update.node_id != identity.key() => {
+                    let known =
+                        orchestrator.update_neighbor_position(&update.node_id, update.position);
+                    if known {
+                        info!("Node {} moved to {:?}", update.node_id, update.position);
+                    }
+                }
+                _ => (),
+            },
+            Operation::CHAOS => match msg.payload {
+                Some(Payload::Chaos(command)) if command.applies_to(&identity) => {
+                    match orchestrator.chaos() {
+                        Some(chaos) => {
+                            if let Err(e) = chaos.apply(command) {
+                                error!("Cannot apply the chaos command: {e}");
+                            }
+                        }
+                        None => warn!("Chaos command ignored, the node runs without --chaos"),
+                    }
+                }
+                _ => (),
+            },
+            Operation::WRITE_STATS => {
+                if let Some(Payload::Period(period)) = msg.payload {
+                    self.write_stats(period).await
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Write the stats of an epoch to the stats file and report them to the benchmark
+    async fn write_stats(&self, period: Period) {
+        info!(
+            "Writing stats for period: {} - {}",
+            period.start, period.end
+        );
+        let eras = self.eras.fetch_add(1, Ordering::SeqCst);
+        // A window that cannot be read leaves the epoch without a report,
+        // the benchmark goes on without it
+        let window = match period.window() {
+            Ok(window) => window,
+            Err(e) => {
+                error!("Cannot write the stats of epoch {eras}: {e}");
+                return;
+            }
+        };
+        let mut stats = db::stats(&self.pool, &window).await;
+        loop {
+            if stats.is_err() {
+                stats = db::stats(&self.pool, &window).await;
+            } else {
+                break;
+            }
+        }
+        let stats = stats.unwrap();
+        // The file is kept, in case the report does not reach the benchmark. A run that
+        // panicked while writing leaves the file as it was, the next one goes on with it.
+        let written = self
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_epoch(eras, &stats);
+        if let Err(e) = written {
+            error!("Cannot write the stats of epoch {eras}: {e}");
+        }
+        let report = Message::new(
+            Operation::STATS_REPORT,
+            Some(Payload::StatsReport(StatsReport {
+                // The node may have moved since it started
+                node: self.orchestrator.get_identity(),
+                epoch: eras,
+                stats,
+            })),
+        );
+        if let Err(e) = self.control_plane.announce(report).await {
+            error!("Cannot report the stats of epoch {eras}: {e}");
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, time::Duration};
+
+    use super::*;
+    use crate::{
+        net::iggy::MessageError,
+        orchestrator::{
+            global::identity::Node,
+            readiness::{Readiness, ReadinessPhase},
+        },
+        utils::{
+            stats::StatsFormat,
+            supervisor::{Backoff, Supervisor, TaskState},
+        },
+    };
+
+    /// A control plane that trusts the messages it receives, so that a malformed one panics
+    /// in the controller as a bug in a handler would
+    struct TrustingControlPlane {
+        messages: std::sync::Mutex<VecDeque<&'static [u8]>>,
+        announced: std::sync::Mutex<Vec<Operation>>,
+    }
+
+    impl TrustingControlPlane {
+        fn new(messages: &[&'static [u8]]) -> Self {
+            TrustingControlPlane {
+                messages: std::sync::Mutex::new(messages.iter().copied().collect()),
+                announced: std::sync::Mutex::new(vec![]),
+            }
+        }
+    }
+
+    impl ControlPlane for TrustingControlPlane {
+        async fn register_node(&self, _node: Node) -> Result<(), MessageError> {
+            Ok(())
+        }
+
+        async fn receive_message(&self) -> Result<Option<Message>, MessageError> {
+            let raw = self.messages.lock().unwrap().pop_front();
+            match raw {
+                Some(raw) => Ok(Some(Message::decode(raw).unwrap())),
+                None => {
+                    actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+                    Ok(None)
+                }
+            }
+        }
+
+        async fn announce(&self, message: Message) -> Result<(), MessageError> {
+            self.announced.lock().unwrap().push(message.op);
+            Ok(())
+        }
+    }
+
+    async fn controller(
+        messages: &[&'static [u8]],
+    ) -> Arc<EmergencyController<TrustingControlPlane, Vec<u8>>> {
+        let orchestrator = Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        );
+        controller_of(orchestrator, messages).await
+    }
+
+    async fn controller_of(
+        orchestrator: Orchestrator,
+        messages: &[&'static [u8]],
+    ) -> Arc<EmergencyController<TrustingControlPlane, Vec<u8>>> {
+        let pool = db::establish_connection().await.unwrap();
+        let orchestrator = Arc::new(orchestrator);
+        let dead_letters =
+            std::env::temp_dir().join(format!("spare-dead-{}", uuid::Uuid::new_v4()));
+        let writer =
+            StatsWriter::new(Vec::new(), StatsFormat::Csv, "10.0.0.0:8085", "test").unwrap();
+        Arc::new(EmergencyController::new(
+            pool,
+            orchestrator,
+            TrustingControlPlane::new(messages),
+            Arc::new(DeadLetterLog::new(dead_letters, 1 << 20)),
+            writer,
+        ))
+    }
+
+    /// Wait until `done` holds, for up to a second
+    async fn until(done: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Timed out");
+    }
+
+    #[actix_web::test]
+    async fn test_recovery() {
+        let controller = controller(&[
+            br#"{"v":2,"op":"WRITE_STATS","payload":{"Period":{"start":"2026-01-01T00:00:00Z","end":"2026-01-01T00:01:00Z"}}}"#,
+            b"{not json",
+            br#"{"v":2,"op":"START_EMERGENCY","payload":{"Emergency":{"position":[45.4685,9.1824],"radius":1000.0}}}"#,
+            br#"{"v":2,"op":"WRITE_STATS","payload":{"Period":{"start":"2026-01-01T00:01:00Z","end":"2026-01-01T00:02:00Z"}}}"#,
+        ])
+        .await;
+        let supervisor = Arc::new(Supervisor::new(Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        }));
+        let cancel = CancellationToken::new();
+        let supervised = controller.clone();
+        let handle = supervisor.supervise("emergency controller", cancel.clone(), move |cancel| {
+            let controller = supervised.clone();
+            async move { controller.run(cancel).await }
+        });
+
+        // The malformed message panics the controller, the node is degraded until it runs again
+        until(|| supervisor.degraded()).await;
+        let health = supervisor.health();
+        assert_eq!(health[0].failures, 1);
+        assert!(health[0]
+            .last_failure
+            .as_ref()
+            .unwrap()
+            .starts_with("panicked"));
+        assert!(!controller.orchestrator.in_emergency_area());
+
+        // Started again, it handles the next messages
+        until(|| controller.orchestrator.in_emergency_area()).await;
+        assert!(!supervisor.degraded());
+        until(|| controller.control_plane.announced.lock().unwrap().len() == 2).await;
+
+        cancel.cancel();
+        handle.await.unwrap();
+        assert_eq!(supervisor.health()[0].state, TaskState::Stopped);
+
+        // The epochs are counted across the runs, in a single stats file
+        let controller = Arc::try_unwrap(controller).ok().unwrap();
+        let written = controller.writer.into_inner().unwrap().into_inner();
+        let epochs: Vec<_> = String::from_utf8(written)
+            .unwrap()
+            .lines()
+            .skip(2)
+            .map(|line| line.split(',').next().unwrap().to_string())
+            .collect();
+        assert_eq!(epochs, vec!["0", "1"]);
+    }
+
+    #[actix_web::test]
+    async fn test_end() {
+        let controller = controller(&[br#"{"v":2,"op":"END","payload":null}"#]).await;
+        let supervisor = Arc::new(Supervisor::new(Backoff::default()));
+        let shutdown = CancellationToken::new();
+        let supervised = controller.clone();
+        let handle = supervisor.supervise(
+            "emergency controller",
+            shutdown.child_token(),
+            move |cancel| {
+                let controller = supervised.clone();
+                async move { controller.run(cancel).await }
+            },
+        );
+
+        // The controller stops without a failure, the rest of the node goes on
+        handle.await.unwrap();
+        let health = supervisor.health();
+        assert_eq!(
+            (health[0].state, health[0].failures),
+            (TaskState::Stopped, 0)
+        );
+        assert!(!shutdown.is_cancelled());
+    }
+
+    #[actix_web::test]
+    async fn test_waits_for_readiness() {
+        let readiness = Readiness::new();
+        readiness.advance(ReadinessPhase::Registering);
+        let orchestrator = Orchestrator::new(
+            vec![],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        )
+        .with_readiness(Arc::new(readiness));
+        let controller = controller_of(orchestrator, &[
+            br#"{"v":2,"op":"START_EMERGENCY","payload":{"Emergency":{"position":[45.4685,9.1824],"radius":1000.0}}}"#,
+        ])
+        .await;
+        let cancel = CancellationToken::new();
+        let handle = actix_web::rt::spawn({
+            let controller = controller.clone();
+            let cancel = cancel.clone();
+            async move { controller.run(cancel).await }
+        });
+
+        // The emergency waits for the node to be ready
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+        assert!(!controller.orchestrator.in_emergency_area());
+        assert_eq!(controller.control_plane.messages.lock().unwrap().len(), 1);
+        controller
+            .orchestrator
+            .readiness()
+            .advance(ReadinessPhase::Ready);
+        until(|| controller.orchestrator.in_emergency_area()).await;
+
+        cancel.cancel();
+        handle.await.unwrap();
+
+        // A controller cancelled before the node is ready stops at once
+        let controller = controller_of(
+            Orchestrator::new(
+                vec![],
+                Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+            )
+            .with_readiness(Arc::new(Readiness::new())),
+            &[],
+        )
+        .await;
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        controller.run(cancel).await;
+    }
+}
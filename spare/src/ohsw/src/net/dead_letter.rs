@@ -0,0 +1,135 @@
+//! Log of the broker messages the node could not handle.
+//! Every entry is a JSON line with the time, the error and the offending payload.
+//! When the file grows beyond its size cap it is rotated to `<path>.1`, so at most
+//! two files are kept on disk.
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+use serde_json::json;
+
+use super::iggy::MessageError;
+
+/// Longest payload stored in an entry, longer ones are truncated
+pub const MAX_PAYLOAD_LEN: usize = 4096;
+
+/// Bounded log of the undeliverable messages
+pub struct DeadLetterLog {
+    path: PathBuf,
+    max_size: u64,
+    // Serializes the writers, so rotations and appends do not interleave
+    lock: Mutex<()>,
+}
+
+impl DeadLetterLog {
+    /// Create a log writing to `path`, rotated when it exceeds `max_size` bytes
+    pub fn new(path: PathBuf, max_size: u64) -> Self {
+        Self {
+            path,
+            max_size,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Get the path of the file the old entries are rotated to
+    pub fn rotated_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".1");
+        PathBuf::from(path)
+    }
+
+    /// Record a message that could not be handled. Transport errors carry no message
+    /// and are not recorded.
+    pub fn record(&self, error: &MessageError) -> Result<(), io::Error> {
+        let Some(raw) = error.raw() else {
+            return Ok(());
+        };
+        let truncated = raw.len() > MAX_PAYLOAD_LEN;
+        let payload = String::from_utf8_lossy(&raw[..raw.len().min(MAX_PAYLOAD_LEN)]);
+        let entry = json!({
+            "time": chrono::Utc::now().to_rfc3339(),
+            "error": error.to_string(),
+            "payload": payload,
+            "truncated": truncated,
+        });
+
+        let _guard = self.lock.lock().unwrap();
+        if fs::metadata(&self.path).is_ok_and(|m| m.len() >= self.max_size) {
+            fs::rename(&self.path, self.rotated_path())?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", entry)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::iggy::Message;
+
+    fn log(max_size: u64) -> DeadLetterLog {
+        let path = std::env::temp_dir().join(format!("spare-dead-{}", uuid::Uuid::new_v4()));
+        DeadLetterLog::new(path, max_size)
+    }
+
+    fn entries(path: &PathBuf) -> Vec<serde_json::Value> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_record() {
+        let log = log(1 << 20);
+        let error = Message::decode(b"not json").err().unwrap();
+        log.record(&error).unwrap();
+        let error = Message::decode(&vec![b'x'; MAX_PAYLOAD_LEN + 1])
+            .err()
+            .unwrap();
+        log.record(&error).unwrap();
+
+        let entries = entries(&log.path);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["payload"], "not json");
+        assert_eq!(entries[0]["truncated"], false);
+        assert!(entries[0]["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("Malformed message"));
+        assert_eq!(entries[1]["truncated"], true);
+        assert_eq!(
+            entries[1]["payload"].as_str().unwrap().len(),
+            MAX_PAYLOAD_LEN
+        );
+        fs::remove_file(&log.path).unwrap();
+    }
+
+    #[test]
+    fn test_rotation() {
+        let log = log(256);
+        let error = Message::decode(br#"{"v":1,"op":"REBALANCE"}"#)
+            .err()
+            .unwrap();
+        for _ in 0..10 {
+            log.record(&error).unwrap();
+        }
+        let current = fs::metadata(&log.path).unwrap().len();
+        let rotated = fs::metadata(log.rotated_path()).unwrap().len();
+        // Each file goes over the cap by at most one entry
+        assert!(current < 512 && rotated < 512);
+        // The oldest entries were dropped by the second rotation
+        let kept = entries(&log.path).len() + entries(&log.rotated_path()).len();
+        assert!(kept > 0 && kept < 10);
+        fs::remove_file(&log.path).unwrap();
+        fs::remove_file(log.rotated_path()).unwrap();
+    }
+}
@@ -1,7 +1,7 @@
 use std::str::FromStr;
 
 use iggy::{
-    client::{Client, MessageClient, UserClient},
+    client::{Client, MessageClient, PersonalAccessTokenClient, TopicClient, UserClient},
     clients::client::IggyClient,
     consumer::Consumer,
     error::IggyError,
@@ -11,14 +11,105 @@ use iggy::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::orchestrator::global::{emergency::Emergency, identity::Node};
+use super::control_plane::ControlPlane;
+use crate::{
+    chaos::ChaosCommand,
+    db::{
+        window::{Window, WindowError},
+        Stats,
+    },
+    orchestrator::global::{emergency::Emergency, identity::Node},
+};
+
+/// Default identifiers of the stream, topic and partitions shared with the benchmark
+pub const DEFAULT_STREAM_ID: u32 = 1;
+pub const DEFAULT_TOPIC_ID: u32 = 1;
+pub const DEFAULT_ANNOUNCE_PARTITION_ID: u32 = 1;
+pub const DEFAULT_BROADCAST_PARTITION_ID: u32 = 2;
+
+/// Stream, topic and partitions the nodes and the benchmark communicate through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Topology {
+    pub stream_id: u32,
+    pub topic_id: u32,
+    /// Partition the nodes announce themselves on
+    pub announce_partition: u32,
+    /// Partition the benchmark broadcasts its messages on
+    pub broadcast_partition: u32,
+}
 
-const STREAM_ID: u32 = 1;
-const TOPIC_ID: u32 = 1;
-const ANNOUNCE_PARTITION_ID: u32 = 1;
-const BROADCAST_PARTITION_ID: u32 = 2;
+impl Default for Topology {
+    fn default() -> Self {
+        Self {
+            stream_id: DEFAULT_STREAM_ID,
+            topic_id: DEFAULT_TOPIC_ID,
+            announce_partition: DEFAULT_ANNOUNCE_PARTITION_ID,
+            broadcast_partition: DEFAULT_BROADCAST_PARTITION_ID,
+        }
+    }
+}
+
+/// Error returned when the broker or the benchmark do not match the configuration of the node
+#[derive(Debug, thiserror::Error)]
+pub enum TopologyError {
+    #[error("The benchmark uses {expected:?}, but the node is configured with {configured:?}")]
+    Mismatch {
+        expected: Topology,
+        configured: Topology,
+    },
+    #[error("Topic {topic_id} of stream {stream_id} does not exist")]
+    MissingTopic { stream_id: u32, topic_id: u32 },
+    #[error("Partition {partition} does not exist, the topic has {count} partitions")]
+    MissingPartition { partition: u32, count: u32 },
+    #[error("Broker error: {0}")]
+    Broker(#[from] IggyError),
+}
+
+impl Topology {
+    /// Check the topology announced by the benchmark against the one of the node
+    pub fn check(&self, expected: &Topology) -> Result<(), TopologyError> {
+        if self != expected {
+            return Err(TopologyError::Mismatch {
+                expected: *expected,
+                configured: *self,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check that both partitions exist in a topic with `count` partitions.
+    /// Partitions are numbered from 1.
+    fn check_partitions(&self, count: u32) -> Result<(), TopologyError> {
+        for partition in [self.announce_partition, self.broadcast_partition] {
+            if partition == 0 || partition > count {
+                return Err(TopologyError::MissingPartition { partition, count });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Credentials used to log in to the broker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    User {
+        username: String,
+        password: String,
+    },
+    /// Personal access token
+    Token(String),
+}
 
-#[derive(PartialEq, Eq, Deserialize, Serialize)]
+impl Default for Credentials {
+    fn default() -> Self {
+        Credentials::User {
+            username: DEFAULT_ROOT_USERNAME.to_string(),
+            password: DEFAULT_ROOT_PASSWORD.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
 pub enum Operation {
     START_EMERGENCY = 0,
     STOP_EMERGENCY = 1,
@@ -26,92 +117,343 @@ pub enum Operation {
     ANNOUNCE = 3,
     END = 4,
     WRITE_STATS = 5,
+    HELLO = 6,
+    /// A node is draining before a maintenance, nothing must be offloaded to it
+    DRAINING = 7,
+    /// A node drained before takes requests again
+    RESUMED = 8,
+    /// The stats of an epoch of a node, for the benchmark
+    STATS_REPORT = 9,
+    /// A node moved, e.g. a vehicle
+    UPDATE_POSITION = 10,
+    /// Inject faults in some nodes, or clear them, for chaos testing
+    CHAOS = 11,
 }
 
+/// Window of an epoch of the benchmark, by its clock. The bounds are in RFC 3339, e.g.
+/// `2026-01-01T00:00:00.000Z`; the older benchmarks send them in UTC without an offset.
 #[derive(Deserialize, Serialize)]
 pub struct Period {
     pub start: String,
     pub end: String,
 }
 
+impl Period {
+    /// Parse the bounds of the epoch
+    pub fn window(&self) -> Result<Window, WindowError> {
+        Window::parse(&self.start, &self.end)
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub enum Payload {
     Nodes(Vec<Node>),
     Emergency(Emergency),
     Period(Period),
+    Topology(Topology),
+    StatsReport(StatsReport),
+    Position(PositionUpdate),
+    Chaos(ChaosCommand),
+}
+
+/// New position of a node that moved
+#[derive(Deserialize, Serialize)]
+pub struct PositionUpdate {
+    /// Id of the node, or its address if it has none
+    pub node_id: String,
+    pub position: (f64, f64),
 }
 
+/// Stats of an epoch, computed by a node when asked with WRITE_STATS
+#[derive(Deserialize, Serialize)]
+pub struct StatsReport {
+    pub node: Node,
+    /// Epochs are counted by each node from 0, in the order they were asked
+    pub epoch: u64,
+    pub stats: Stats,
+}
+
+/// Version of the message schema sent by this node.
+/// Messages without a version predate the versioning and share the schema of version 1.
+/// Version 2 adds the stable id of the nodes, the nodes of older messages have none and
+/// are known by their address.
+pub const MESSAGE_VERSION: u8 = 2;
+
 #[derive(Deserialize, Serialize)]
 pub struct Message {
+    #[serde(default)]
+    pub v: u8,
     pub op: Operation,
     pub payload: Option<Payload>,
 }
 
+impl Message {
+    /// Create a message with the current schema version
+    pub fn new(op: Operation, payload: Option<Payload>) -> Self {
+        Self {
+            v: MESSAGE_VERSION,
+            op,
+            payload,
+        }
+    }
+
+    /// Decode a message polled from the broker
+    pub fn decode(raw: &[u8]) -> Result<Self, MessageError> {
+        let malformed = |e: serde_json::Error| MessageError::Malformed {
+            reason: e.to_string(),
+            raw: raw.to_vec(),
+        };
+        // Look at the envelope first, so a newer schema or operation is reported as such
+        let envelope: Envelope = serde_json::from_slice(raw).map_err(malformed)?;
+        if envelope.v > MESSAGE_VERSION {
+            return Err(MessageError::Version {
+                version: envelope.v,
+                raw: raw.to_vec(),
+            });
+        }
+        if serde_json::from_value::<Operation>(serde_json::Value::String(envelope.op.clone()))
+            .is_err()
+        {
+            return Err(MessageError::UnknownOperation {
+                op: envelope.op,
+                raw: raw.to_vec(),
+            });
+        }
+        serde_json::from_slice(raw).map_err(malformed)
+    }
+}
+
+/// Fields of a message that are stable across schema versions
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    v: u8,
+    op: String,
+}
+
+/// Error types for the messages received from the broker
+#[derive(Debug, thiserror::Error)]
+pub enum MessageError {
+    #[error("Malformed message: {reason}")]
+    Malformed { reason: String, raw: Vec<u8> },
+    #[error("Unsupported message version {version}, expected at most {MESSAGE_VERSION}")]
+    Version { version: u8, raw: Vec<u8> },
+    #[error("Unknown operation {op}")]
+    UnknownOperation { op: String, raw: Vec<u8> },
+    #[error("Broker error: {0}")]
+    Broker(#[from] IggyError),
+    #[error("Registry error: {0}")]
+    Registry(String),
+}
+
+impl MessageError {
+    /// Get the payload that could not be handled, None for transport errors
+    pub fn raw(&self) -> Option<&[u8]> {
+        match self {
+            MessageError::Malformed { raw, .. }
+            | MessageError::Version { raw, .. }
+            | MessageError::UnknownOperation { raw, .. } => Some(raw),
+            MessageError::Broker(_) | MessageError::Registry(_) => None,
+        }
+    }
+}
+
+/// Phase of the life of the node a consumer is used in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for the list of nodes, after the node announced itself
+    Registration,
+    /// Handling the emergencies and the stats requests of the experiment
+    Broadcast,
+}
+
+impl Phase {
+    /// Whether a message is handled in this phase, the others are skipped
+    pub fn accepts(&self, op: &Operation) -> bool {
+        match self {
+            Phase::Registration => matches!(op, Operation::ADD_NODES | Operation::HELLO),
+            Phase::Broadcast => matches!(
+                op,
+                Operation::START_EMERGENCY
+                    | Operation::STOP_EMERGENCY
+                    | Operation::END
+                    | Operation::WRITE_STATS
+                    | Operation::DRAINING
+                    | Operation::RESUMED
+                    | Operation::UPDATE_POSITION
+                    | Operation::CHAOS
+            ),
+        }
+    }
+
+    fn suffix(&self) -> &'static str {
+        match self {
+            Phase::Registration => "registration",
+            Phase::Broadcast => "broadcast",
+        }
+    }
+}
+
+/// Where a consumer starts polling from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PollingMode {
+    /// After the last message polled by the consumer, the default
+    Next,
+    /// From the first message of the partition
+    First,
+    /// From the last message of the partition
+    Last,
+}
+
+impl FromStr for PollingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "next" => Ok(PollingMode::Next),
+            "first" => Ok(PollingMode::First),
+            "last" => Ok(PollingMode::Last),
+            _ => Err(format!("Unknown polling mode: {}", s)),
+        }
+    }
+}
+
+impl PollingMode {
+    fn strategy(&self) -> PollingStrategy {
+        match self {
+            PollingMode::Next => PollingStrategy::next(),
+            PollingMode::First => PollingStrategy::first(),
+            PollingMode::Last => PollingStrategy::last(),
+        }
+    }
+}
+
+/// Default name of the consumers of a node: the address it serves requests on.
+/// The bare IP is not enough, two nodes on the same host would share their consumer.
+pub fn default_consumer_name(address: &str, port: u16) -> String {
+    format!("{address}:{port}")
+}
+
+/// Identity and position of a consumer of the broadcast messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConsumerConfig {
+    /// Name of the consumer, the broker keeps an offset for each name
+    pub name: String,
+    /// Partition the messages are polled from
+    pub partition: u32,
+    /// Where the consumer starts polling from
+    pub polling: PollingMode,
+    /// Messages that are not for this phase are skipped
+    pub phase: Phase,
+}
+
+impl ConsumerConfig {
+    /// Create the consumer of a phase. Each phase has its own consumer, so they do
+    /// not steal each other's messages.
+    pub fn new(name: &str, phase: Phase) -> Self {
+        Self {
+            name: format!("{}/{}", name, phase.suffix()),
+            partition: DEFAULT_BROADCAST_PARTITION_ID,
+            polling: PollingMode::Next,
+            phase,
+        }
+    }
+
+    /// Poll the messages from another partition
+    pub fn with_partition(mut self, partition: u32) -> Self {
+        self.partition = partition;
+        self
+    }
+
+    /// Start polling from somewhere else than after the last polled message
+    pub fn with_polling(mut self, polling: PollingMode) -> Self {
+        self.polling = polling;
+        self
+    }
+
+    /// Decode a polled message, None if it is not for this consumer
+    pub fn route(&self, payload: &[u8]) -> Result<Option<Message>, MessageError> {
+        let message = Message::decode(payload)?;
+        Ok(self.phase.accepts(&message.op).then_some(message))
+    }
+}
+
 /// Receive message from topic
 /// Please note that is NOT BLOCKING
-async fn receive_message(client: &IggyClient) -> Result<Option<Message>, IggyError> {
+async fn receive_message(
+    client: &IggyClient,
+    topology: &Topology,
+    consumer: &ConsumerConfig,
+) -> Result<Option<Message>, MessageError> {
     let polled_messages = client
         .poll_messages(
-            &STREAM_ID.try_into()?,
-            &TOPIC_ID.try_into()?,
-            Some(BROADCAST_PARTITION_ID),
-            &Consumer::new(
-                Identifier::named(&local_ip_address::local_ip().unwrap().to_string()).unwrap(),
-            ),
-            &PollingStrategy::next(),
+            &topology.stream_id.try_into()?,
+            &topology.topic_id.try_into()?,
+            Some(consumer.partition),
+            &Consumer::new(Identifier::named(&consumer.name)?),
+            &consumer.polling.strategy(),
             1,
             true,
         )
         .await?;
 
-    if polled_messages.messages.is_empty() {
-        return Ok(None);
+    match polled_messages.messages.first() {
+        Some(message) => consumer.route(&message.payload),
+        None => Ok(None),
     }
-
-    let deserialized =
-        serde_json::from_slice::<Message>(&polled_messages.messages[0].payload).unwrap();
-    return Ok(Some(deserialized));
 }
 
-/// Send message to a topic
-async fn send_message(client: &IggyClient, message: Message) -> Result<(), IggyError> {
+/// Send message to a partition of the topic
+async fn send_message(
+    client: &IggyClient,
+    topology: &Topology,
+    partition: u32,
+    message: Message,
+) -> Result<(), IggyError> {
     let message =
         iggy::messages::send_messages::Message::from_str(&serde_json::to_string(&message).unwrap())
             .unwrap();
 
     client
         .send_messages(
-            &STREAM_ID.try_into().unwrap(),
-            &TOPIC_ID.try_into().unwrap(),
-            &Partitioning::partition_id(ANNOUNCE_PARTITION_ID),
+            &topology.stream_id.try_into()?,
+            &topology.topic_id.try_into()?,
+            &Partitioning::partition_id(partition),
             &mut [message],
         )
         .await
 }
 
 /// Connect to the Iggy message broker
-async fn connect(host: &str) -> Result<IggyClient, IggyError> {
+async fn connect(host: &str, credentials: &Credentials) -> Result<IggyClient, IggyError> {
     let client = IggyClient::builder()
         .with_tcp()
         .with_server_address(host.to_owned())
-        .build()
-        .unwrap();
+        .build()?;
 
-    client.connect().await.unwrap();
-    let _ = client
-        .login_user(DEFAULT_ROOT_USERNAME, DEFAULT_ROOT_PASSWORD)
-        .await;
+    client.connect().await?;
+    match credentials {
+        Credentials::User { username, password } => {
+            client.login_user(username, password).await?;
+        }
+        Credentials::Token(token) => {
+            client.login_with_personal_access_token(token).await?;
+        }
+    }
     Ok(client)
 }
 
 /// Register a node with the Iggy message broker
-async fn register_node(client: &IggyClient, node: Node) -> Result<(), IggyError> {
+async fn register_node(
+    client: &IggyClient,
+    topology: &Topology,
+    node: Node,
+) -> Result<(), IggyError> {
     send_message(
         client,
-        Message {
-            op: Operation::ANNOUNCE,
-            payload: Some(Payload::Nodes(vec![node])),
-        },
+        topology,
+        topology.announce_partition,
+        Message::new(Operation::ANNOUNCE, Some(Payload::Nodes(vec![node]))),
     )
     .await
 }
@@ -120,19 +462,337 @@ async fn register_node(client: &IggyClient, node: Node) -> Result<(), IggyError>
 /// for interacting with the Iggy message broker.
 pub struct IggyConnector {
     client: IggyClient,
+    topology: Topology,
+    consumer: ConsumerConfig,
 }
 
 impl IggyConnector {
-    pub async fn new(host: &str) -> Self {
-        let client = connect(host).await.unwrap();
-        Self { client }
+    /// Connect and log in to the broker at `host`
+    pub async fn new(
+        host: &str,
+        credentials: &Credentials,
+        topology: Topology,
+        consumer: ConsumerConfig,
+    ) -> Result<Self, IggyError> {
+        let client = connect(host, credentials).await?;
+        Ok(Self {
+            client,
+            topology,
+            consumer,
+        })
+    }
+
+    /// Check that the topic and the partitions of the topology exist on the broker
+    pub async fn verify_topology(&self) -> Result<(), TopologyError> {
+        let topic = self
+            .client
+            .get_topic(
+                &self.topology.stream_id.try_into()?,
+                &self.topology.topic_id.try_into()?,
+            )
+            .await?
+            .ok_or(TopologyError::MissingTopic {
+                stream_id: self.topology.stream_id,
+                topic_id: self.topology.topic_id,
+            })?;
+        self.topology.check_partitions(topic.partitions_count)
+    }
+
+    /// Get the consumer the messages are received with
+    pub fn consumer(&self) -> &ConsumerConfig {
+        &self.consumer
+    }
+}
+
+impl ControlPlane for IggyConnector {
+    async fn register_node(&self, node: Node) -> Result<(), MessageError> {
+        Ok(register_node(&self.client, &self.topology, node).await?)
+    }
+
+    /// Receive the next message for the consumer.
+    /// A message that cannot be decoded is consumed anyway and returned as an error,
+    /// so the next call moves on to the following one.
+    async fn receive_message(&self) -> Result<Option<Message>, MessageError> {
+        receive_message(&self.client, &self.topology, &self.consumer).await
+    }
+
+    /// Send the message on the broadcast partition, next to the ones of the benchmark.
+    /// The stats reports are for the benchmark only, they go on the announce partition.
+    async fn announce(&self, message: Message) -> Result<(), MessageError> {
+        let partition = match message.op {
+            Operation::STATS_REPORT => self.topology.announce_partition,
+            _ => self.topology.broadcast_partition,
+        };
+        Ok(send_message(&self.client, &self.topology, partition, message).await?)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::InstanceStats;
+
+    fn payload(message: &Message) -> Vec<u8> {
+        serde_json::to_vec(message).unwrap()
+    }
+
+    #[test]
+    fn test_consumer_identity() {
+        let name = default_consumer_name("10.0.0.1", 8085);
+        assert_eq!(name, "10.0.0.1:8085");
+        // Two nodes on the same host have different consumers
+        assert_ne!(name, default_consumer_name("10.0.0.1", 8086));
+
+        let registration = ConsumerConfig::new(&name, Phase::Registration);
+        let broadcast = ConsumerConfig::new(&name, Phase::Broadcast);
+        assert_eq!(registration.name, "10.0.0.1:8085/registration");
+        assert_eq!(broadcast.name, "10.0.0.1:8085/broadcast");
+        assert_eq!(broadcast.partition, DEFAULT_BROADCAST_PARTITION_ID);
+        assert_eq!(broadcast.polling, PollingMode::Next);
+        assert!(Identifier::named(&broadcast.name).is_ok());
+
+        let custom = ConsumerConfig::new("dev", Phase::Broadcast)
+            .with_partition(3)
+            .with_polling(PollingMode::First);
+        assert_eq!(custom.partition, 3);
+        assert_eq!(custom.polling.strategy(), PollingStrategy::first());
+    }
+
+    #[test]
+    fn test_topology() {
+        let topology = Topology::default();
+        assert_eq!(topology.stream_id, 1);
+        assert_eq!(topology.broadcast_partition, 2);
+        assert!(topology.check(&Topology::default()).is_ok());
+
+        let expected = Topology {
+            topic_id: 7,
+            ..Default::default()
+        };
+        let error = topology.check(&expected).unwrap_err();
+        assert!(matches!(error, TopologyError::Mismatch { .. }));
+        assert!(error.to_string().contains("topic_id: 7"));
+
+        assert!(topology.check_partitions(2).is_ok());
+        assert!(matches!(
+            topology.check_partitions(1),
+            Err(TopologyError::MissingPartition {
+                partition: 2,
+                count: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn test_polling_mode() {
+        assert_eq!(PollingMode::from_str("next"), Ok(PollingMode::Next));
+        assert_eq!(PollingMode::from_str("first"), Ok(PollingMode::First));
+        assert_eq!(PollingMode::from_str("last"), Ok(PollingMode::Last));
+        assert!(PollingMode::from_str("offset").is_err());
+    }
+
+    #[test]
+    fn test_routing() {
+        let registration = ConsumerConfig::new("node", Phase::Registration);
+        let broadcast = ConsumerConfig::new("node", Phase::Broadcast);
+
+        // Messages as sent by the benchmark
+        let nodes = payload(&Message::new(
+            Operation::ADD_NODES,
+            Some(Payload::Nodes(vec![Node::new(
+                "10.0.0.1:8085".to_string(),
+                (45.4642, 9.1900),
+            )])),
+        ));
+        let emergency = payload(&Message::new(
+            Operation::START_EMERGENCY,
+            Some(Payload::Emergency(Emergency {
+                position: (45.4642, 9.1900),
+                radius: 100.0,
+                polygon: None,
+            })),
+        ));
+        let stats = payload(&Message::new(
+            Operation::WRITE_STATS,
+            Some(Payload::Period(Period {
+                start: "2026-01-01T00:00:00.000Z".to_string(),
+                end: "2026-01-01T00:01:00.000Z".to_string(),
+            })),
+        ));
+        let end = payload(&Message::new(Operation::END, None));
+        // Sent by a node on the broadcast partition
+        let draining = payload(&Message::new(
+            Operation::DRAINING,
+            Some(Payload::Nodes(vec![Node::new(
+                "10.0.0.1:8085".to_string(),
+                (45.4642, 9.1900),
+            )])),
+        ));
+        let moved = payload(&Message::new(
+            Operation::UPDATE_POSITION,
+            Some(Payload::Position(PositionUpdate {
+                node_id: "a".to_string(),
+                position: (45.4642, 9.1900),
+            })),
+        ));
+        // The chaos commands of a script, as sent by the benchmark at the start of an epoch
+        let chaos = br#"{"v":2,"op":"CHAOS","payload":{"Chaos":{"nodes":["a"],"action":"clear"}}}"#
+            .to_vec();
+        let hello = payload(&Message::new(
+            Operation::HELLO,
+            Some(Payload::Topology(Topology::default())),
+        ));
+        // Announces and stats reports of the other nodes are not for anybody
+        let announce = payload(&Message::new(Operation::ANNOUNCE, None));
+        let report = payload(&Message::new(Operation::STATS_REPORT, None));
+
+        match registration.route(&nodes).unwrap() {
+            Some(Message {
+                payload: Some(Payload::Nodes(nodes)),
+                ..
+            }) => assert_eq!(nodes[0].address, "10.0.0.1:8085"),
+            _ => panic!("The list of nodes is for the registration"),
+        }
+        assert!(broadcast.route(&nodes).unwrap().is_none());
+
+        for message in [&emergency, &stats, &end, &draining, &moved, &chaos] {
+            assert!(registration.route(message).unwrap().is_none());
+            assert!(broadcast.route(message).unwrap().is_some());
+        }
+        match registration.route(&hello).unwrap() {
+            Some(Message {
+                payload: Some(Payload::Topology(topology)),
+                ..
+            }) => assert_eq!(topology, Topology::default()),
+            _ => panic!("The handshake is for the registration"),
+        }
+        assert!(broadcast.route(&hello).unwrap().is_none());
+        for message in [&announce, &report] {
+            assert!(registration.route(message).unwrap().is_none());
+            assert!(broadcast.route(message).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_stats_report() {
+        let report = Message::new(
+            Operation::STATS_REPORT,
+            Some(Payload::StatsReport(StatsReport {
+                node: Node::new("10.0.0.1:8085".to_string(), (45.4642, 9.1900)),
+                epoch: 3,
+                stats: Stats {
+                    hops_avg: 0.5,
+                    vcpus: 4,
+                    memory: 1024,
+                    requests: 2,
+                    received: 3,
+                    offloaded: 1,
+                    shed: 0,
+                    emergency: InstanceStats {
+                        hops_avg: 1.0,
+                        vcpus: 1,
+                        memory: 256,
+                        requests: 1,
+                    },
+                    normal: InstanceStats {
+                        hops_avg: 0.0,
+                        vcpus: 3,
+                        memory: 768,
+                        requests: 1,
+                    },
+                },
+            })),
+        );
+        let raw = payload(&report);
+        let json: serde_json::Value = serde_json::from_slice(&raw).unwrap();
+        assert_eq!(json["op"], "STATS_REPORT");
+        assert_eq!(
+            json["payload"]["StatsReport"]["node"]["address"],
+            "10.0.0.1:8085"
+        );
+        assert_eq!(json["payload"]["StatsReport"]["stats"]["offloaded"], 1);
+        assert_eq!(
+            json["payload"]["StatsReport"]["stats"]["emergency"]["vcpus"],
+            1
+        );
+
+        match Message::decode(&raw).unwrap() {
+            Message {
+                op: Operation::STATS_REPORT,
+                payload: Some(Payload::StatsReport(report)),
+                ..
+            } => {
+                assert_eq!(report.epoch, 3);
+                assert_eq!(report.stats.received, 3);
+            }
+            _ => panic!("A stats report is decoded as such"),
+        }
     }
 
-    pub async fn register_node(&self, node: Node) -> Result<(), IggyError> {
-        register_node(&self.client, node).await
+    #[test]
+    fn test_decode_malformed() {
+        let broadcast = ConsumerConfig::new("node", Phase::Broadcast);
+        for raw in [
+            &b"not json"[..],
+            br#"{"payload":null}"#,
+            br#"{"v":1,"op":"START_EMERGENCY","payload":{"Emergency":{"radius":"far"}}}"#,
+            // A bow tie, and a zone with neither a radius nor a polygon
+            br#"{"v":2,"op":"START_EMERGENCY","payload":{"Emergency":{"polygon":[[45.46,9.18],[45.47,9.19],[45.46,9.19],[45.47,9.18]]}}}"#,
+            br#"{"v":2,"op":"START_EMERGENCY","payload":{"Emergency":{"position":[45.46,9.18]}}}"#,
+        ] {
+            match broadcast.route(raw) {
+                Err(e @ MessageError::Malformed { .. }) => assert_eq!(e.raw(), Some(raw)),
+                _ => panic!("{} is malformed", String::from_utf8_lossy(raw)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_emergency_polygon() {
+        let raw = br#"{"v":2,"op":"START_EMERGENCY","payload":{"Emergency":{"polygon":[[45.46,9.18],[45.46,9.19],[45.47,9.19],[45.47,9.18],[45.46,9.18]]}}}"#;
+        match Message::decode(raw).unwrap().payload {
+            Some(Payload::Emergency(emergency)) => {
+                assert_eq!(emergency.polygon.unwrap().len(), 4);
+                assert!(emergency.radius > 0.0);
+            }
+            _ => panic!("An emergency carries its zone"),
+        }
+    }
+
+    #[test]
+    fn test_decode_versions() {
+        // Messages sent before the versioning are still understood
+        let message = Message::decode(br#"{"op":"END","payload":null}"#).unwrap();
+        assert_eq!(message.v, 0);
+        assert!(message.op == Operation::END);
+
+        let message = Message::decode(&payload(&Message::new(Operation::END, None))).unwrap();
+        assert_eq!(message.v, MESSAGE_VERSION);
+
+        // Nodes announced with version 1 have no id
+        let raw = br#"{"v":1,"op":"ANNOUNCE","payload":{"Nodes":[{"address":"10.0.0.1:8085","position":[0.0,0.0]}]}}"#;
+        match Message::decode(raw).unwrap().payload {
+            Some(Payload::Nodes(nodes)) => assert_eq!(nodes[0].key(), "10.0.0.1:8085"),
+            _ => panic!("An announce carries nodes"),
+        }
+
+        let raw = br#"{"v":3,"op":"END","payload":{"Something":"new"}}"#;
+        assert!(matches!(
+            Message::decode(raw),
+            Err(MessageError::Version { version: 3, .. })
+        ));
     }
 
-    pub async fn receive_message(&self) -> Result<Option<Message>, IggyError> {
-        receive_message(&self.client).await
+    #[test]
+    fn test_decode_unknown_operation() {
+        let raw = br#"{"v":1,"op":"REBALANCE","payload":null}"#;
+        match Message::decode(raw) {
+            Err(e @ MessageError::UnknownOperation { .. }) => {
+                assert_eq!(e.to_string(), "Unknown operation REBALANCE");
+                assert_eq!(e.raw(), Some(&raw[..]));
+            }
+            _ => panic!("REBALANCE is not an operation"),
+        }
     }
 }
@@ -1,4 +1,4 @@
-use super::sockaddr::SockaddrConvertible;
+use super::{bridge, sockaddr::SockaddrConvertible};
 use log::info;
 use nix::libc::{__c_anonymous_ifr_ifru, IFF_TAP};
 use nix::libc::{IFF_NO_PI, IFF_VNET_HDR};
@@ -272,6 +272,57 @@ impl Tap {
     pub fn name(&self) -> &str {
         &self.ifname
     }
+
+    /// Handle of an existing TAP interface.
+    pub fn from_name(name: &str) -> Self {
+        Tap {
+            ifname: name.to_owned(),
+        }
+    }
+}
+
+/// Creates the TAP interfaces of the instances and attaches them to their bridge.
+pub trait TapFactory: Send + Sync {
+    /// Create the TAP interface `name` attached to `bridge`.
+    /// Nothing is left behind on failure.
+    fn create(&self, name: &str, bridge: &str) -> Result<Tap, nix::Error>;
+    /// Remove a TAP interface created by the factory.
+    fn remove(&self, tap: &Tap) -> Result<(), nix::Error>;
+}
+
+/// TAP interfaces of the host.
+#[derive(Default)]
+pub struct LinuxTaps;
+
+impl TapFactory for LinuxTaps {
+    fn create(&self, name: &str, bridge: &str) -> Result<Tap, nix::Error> {
+        let tap = Tap::create(name)?;
+        let attached =
+            bridge::interface_id(name).and_then(|id| bridge::add_interface_to_bridge(id, bridge));
+        if let Err(e) = attached {
+            if let Err(e) = tap.remove() {
+                info!("Failed to remove tap {}: {}", name, e);
+            }
+            return Err(e);
+        }
+        info!("Added {} to {}", name, bridge);
+        Ok(tap)
+    }
+
+    fn remove(&self, tap: &Tap) -> Result<(), nix::Error> {
+        tap.remove()
+    }
+}
+
+/// Whether a failure to set up a TAP interface may not happen again with another one,
+/// e.g. the kernel is still tearing down the interface of a previous instance.
+/// A missing bridge or missing permissions fail every time.
+pub fn is_retryable(e: nix::Error) -> bool {
+    use nix::errno::Errno;
+    matches!(
+        e,
+        Errno::EBUSY | Errno::EAGAIN | Errno::EEXIST | Errno::ENOBUFS | Errno::EINTR
+    )
 }
 
 // Unit tests
@@ -279,6 +330,18 @@ impl Tap {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_retryable() {
+        use nix::errno::Errno;
+
+        assert!(is_retryable(Errno::EBUSY));
+        assert!(is_retryable(Errno::EEXIST));
+        // No bridge, no permissions: another name does not help
+        assert!(!is_retryable(Errno::ENODEV));
+        assert!(!is_retryable(Errno::EPERM));
+        assert!(!is_retryable(Errno::EINVAL));
+    }
+
     #[test]
     fn test_tap() {
         let tap = Tap::create("test_tap").expect("Failed to create tap");
@@ -1,4 +1,11 @@
 //! Module that contains network and communication related code.
 pub mod addresses;
+pub mod control_plane;
+pub mod controller;
+pub mod dead_letter;
 pub mod iggy;
 pub mod linux;
+pub mod networks;
+pub mod node_address;
+pub mod registry;
+pub mod tls;
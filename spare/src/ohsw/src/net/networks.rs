@@ -0,0 +1,410 @@
+//! Networks the instances are attached to.
+//! By default every instance shares the bridge of the node, so any instance can talk to
+//! any other. A node can define more networks, each a bridge with its own pool of
+//! addresses, and pin functions to them: the instances of a function pinned to a network
+//! only get addresses of that network, and the others go to the default one.
+//! Once every address of a network is taken, an instance may wait a bounded time for one
+//! to be given back, rather than failing right away.
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    net::Ipv4Addr,
+    pin::pin,
+    process::Command,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::info;
+use tokio::sync::Notify;
+
+use super::addresses::Addresses;
+
+/// Name of the network of the bridge given with `--bridge-name`
+pub const DEFAULT_NETWORK: &str = "default";
+
+/// Error of the networks
+#[derive(Debug, thiserror::Error)]
+pub enum NetworkError {
+    #[error("Unknown network: {0}")]
+    Unknown(String),
+    #[error("No more addresses available in network {0}")]
+    Exhausted(String),
+}
+
+/// A network given on the command line, as `name=bridge,address/prefix`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkSpec {
+    pub name: String,
+    pub bridge: String,
+    pub address: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl FromStr for NetworkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid network: {s}, expected name=bridge,address/prefix");
+        let (name, rest) = s.split_once('=').ok_or_else(invalid)?;
+        let (bridge, cidr) = rest.split_once(',').ok_or_else(invalid)?;
+        let (address, prefix) = cidr.split_once('/').ok_or_else(invalid)?;
+        if name.is_empty() || bridge.is_empty() {
+            return Err(invalid());
+        }
+        Ok(NetworkSpec {
+            name: name.to_string(),
+            bridge: bridge.to_string(),
+            address: address.parse().map_err(|_| invalid())?,
+            prefix: prefix.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// A function pinned to a network, given on the command line as `function=network`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionNetwork {
+    pub function: String,
+    pub network: String,
+}
+
+impl FromStr for FunctionNetwork {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((function, network)) if !function.is_empty() && !network.is_empty() => {
+                Ok(FunctionNetwork {
+                    function: function.to_string(),
+                    network: network.to_string(),
+                })
+            }
+            _ => Err(format!(
+                "Invalid function network: {s}, expected function=network"
+            )),
+        }
+    }
+}
+
+/// Addresses given to an instance
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    /// Name of the network
+    pub network: String,
+    /// Bridge the instance is attached to
+    pub bridge: String,
+    pub address: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+/// A bridge with its pool of addresses
+struct Network {
+    bridge: String,
+    addresses: Mutex<Addresses>,
+}
+
+/// The networks of the node, and the functions pinned to them
+pub struct Networks {
+    networks: BTreeMap<String, Network>,
+    pins: HashMap<String, String>,
+    /// Woken up when an address is given back
+    released: Notify,
+}
+
+impl Networks {
+    /// Create the networks of a node with only the default one
+    pub fn new(bridge: String, addresses: Addresses) -> Self {
+        Networks {
+            networks: BTreeMap::new(),
+            pins: HashMap::new(),
+            released: Notify::new(),
+        }
+        .with_network(DEFAULT_NETWORK, bridge, addresses)
+    }
+
+    /// Add a network, replacing any with the same name
+    pub fn with_network(mut self, name: &str, bridge: String, addresses: Addresses) -> Self {
+        self.networks.insert(
+            name.to_string(),
+            Network {
+                bridge,
+                addresses: Mutex::new(addresses),
+            },
+        );
+        self
+    }
+
+    /// Pin a function to a network, which must exist
+    pub fn with_pin(mut self, function: &str, network: &str) -> Result<Self, NetworkError> {
+        if !self.networks.contains_key(network) {
+            return Err(NetworkError::Unknown(network.to_string()));
+        }
+        self.pins.insert(function.to_string(), network.to_string());
+        Ok(self)
+    }
+
+    /// Name of the network of the instances of a function
+    pub fn network_of(&self, function: &str) -> &str {
+        self.pins
+            .get(function)
+            .map(String::as_str)
+            .unwrap_or(DEFAULT_NETWORK)
+    }
+
+    /// Take an address for an instance of a function, in the network it is pinned to
+    pub fn acquire(&self, function: &str) -> Result<Lease, NetworkError> {
+        let name = self.network_of(function);
+        let network = self
+            .networks
+            .get(name)
+            .ok_or_else(|| NetworkError::Unknown(name.to_string()))?;
+        let mut addresses = network.addresses.lock().unwrap();
+        let address = addresses
+            .get()
+            .ok_or_else(|| NetworkError::Exhausted(name.to_string()))?;
+        Ok(Lease {
+            network: name.to_string(),
+            bridge: network.bridge.clone(),
+            address,
+            gateway: addresses.get_gateway(),
+            netmask: addresses.get_netmask(),
+        })
+    }
+
+    /// Take an address for an instance of a function as `acquire`, waiting up to `wait`
+    /// for one to be given back if its network has none left
+    pub async fn acquire_within(
+        &self,
+        function: &str,
+        wait: Duration,
+    ) -> Result<Lease, NetworkError> {
+        let deadline = Instant::now() + wait;
+        loop {
+            // Listen before trying, so that an address given back in between is not missed
+            let mut released = pin!(self.released.notified());
+            released.as_mut().enable();
+            match self.acquire(function) {
+                Err(NetworkError::Exhausted(_)) if Instant::now() < deadline => {
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    let _ = actix_web::rt::time::timeout(left, released).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Give back the address of an instance to its network
+    pub fn release(&self, network: &str, address: Ipv4Addr) {
+        if let Some(network) = self.networks.get(network) {
+            network.addresses.lock().unwrap().release(address);
+            self.released.notify_waiters();
+        }
+    }
+
+    /// Number of addresses left in the network of the instances of a function
+    pub fn free(&self, function: &str) -> usize {
+        self.networks
+            .get(self.network_of(function))
+            .map_or(0, |network| network.addresses.lock().unwrap().available())
+    }
+
+    /// Names of the bridges of the networks
+    pub fn bridges(&self) -> Vec<&str> {
+        self.networks
+            .values()
+            .map(|network| network.bridge.as_str())
+            .collect()
+    }
+
+    /// Arguments of the iptables commands dropping the traffic routed between the bridges
+    pub fn isolation_rules(&self) -> Vec<Vec<String>> {
+        let bridges = self.bridges();
+        let mut rules = vec![];
+        for from in &bridges {
+            for to in bridges.iter().filter(|to| *to != from) {
+                rules.push(
+                    ["-I", "FORWARD", "-i", from, "-o", to, "-j", "DROP"]
+                        .iter()
+                        .map(|arg| arg.to_string())
+                        .collect(),
+                );
+            }
+        }
+        rules
+    }
+
+    /// Install the rules dropping the traffic between the networks, with iptables
+    pub fn install_isolation(&self) -> io::Result<()> {
+        for rule in self.isolation_rules() {
+            // Skip the rules already installed, -C fails when the rule is missing
+            let mut check = rule.clone();
+            check[0] = "-C".to_string();
+            let installed = Command::new("iptables")
+                .args(&check)
+                .output()
+                .is_ok_and(|output| output.status.success());
+            if installed {
+                continue;
+            }
+            let output = Command::new("iptables").args(&rule).output()?;
+            if !output.status.success() {
+                return Err(io::Error::other(format!(
+                    "iptables {} failed: {}",
+                    rule.join(" "),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+            info!("Installed firewall rule: iptables {}", rule.join(" "));
+        }
+        Ok(())
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn networks() -> Networks {
+        Networks::new(
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::new(192, 168, 30, 0), 24).unwrap(),
+        )
+        .with_network(
+            "tenant",
+            "br1".to_string(),
+            Addresses::new(Ipv4Addr::new(10, 1, 0, 0), 30).unwrap(),
+        )
+        .with_pin("isolated", "tenant")
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "tenant=br1,10.1.0.0/24".parse::<NetworkSpec>().unwrap(),
+            NetworkSpec {
+                name: "tenant".to_string(),
+                bridge: "br1".to_string(),
+                address: Ipv4Addr::new(10, 1, 0, 0),
+                prefix: 24,
+            }
+        );
+        assert!("tenant=br1".parse::<NetworkSpec>().is_err());
+        assert!("tenant=br1,10.1.0.0".parse::<NetworkSpec>().is_err());
+        assert!("=br1,10.1.0.0/24".parse::<NetworkSpec>().is_err());
+
+        assert_eq!(
+            "resize=tenant".parse::<FunctionNetwork>().unwrap(),
+            FunctionNetwork {
+                function: "resize".to_string(),
+                network: "tenant".to_string(),
+            }
+        );
+        assert!("resize".parse::<FunctionNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_selection() {
+        let networks = networks();
+        assert_eq!(networks.network_of("isolated"), "tenant");
+        assert_eq!(networks.network_of("other"), DEFAULT_NETWORK);
+
+        let lease = networks.acquire("isolated").unwrap();
+        assert_eq!(lease.network, "tenant");
+        assert_eq!(lease.bridge, "br1");
+        assert_eq!(lease.address, Ipv4Addr::new(10, 1, 0, 2));
+        assert_eq!(lease.gateway, Ipv4Addr::new(10, 1, 0, 1));
+        assert_eq!(lease.netmask, Ipv4Addr::new(255, 255, 255, 252));
+
+        let lease = networks.acquire("other").unwrap();
+        assert_eq!(lease.network, DEFAULT_NETWORK);
+        assert_eq!(lease.bridge, "br0");
+        assert_eq!(lease.address, Ipv4Addr::new(192, 168, 30, 254));
+
+        // A pin to a network that does not exist is refused
+        assert!(matches!(
+            networks.with_pin("lost", "nowhere"),
+            Err(NetworkError::Unknown(name)) if name == "nowhere"
+        ));
+    }
+
+    #[test]
+    fn test_exhaustion() {
+        let networks = networks();
+        // A /30 has two usable addresses
+        let first = networks.acquire("isolated").unwrap();
+        networks.acquire("isolated").unwrap();
+        assert!(matches!(
+            networks.acquire("isolated"),
+            Err(NetworkError::Exhausted(name)) if name == "tenant"
+        ));
+        // The other networks are not affected
+        assert!(networks.acquire("other").is_ok());
+
+        // An address given back to its network is taken again
+        networks.release(&first.network, first.address);
+        assert_eq!(networks.acquire("isolated").unwrap(), first);
+        // An address of another network is ignored
+        networks.release(DEFAULT_NETWORK, first.address);
+        assert!(networks.acquire("other").unwrap().address != first.address);
+    }
+
+    #[actix_web::test]
+    async fn test_acquire_within() {
+        let networks = std::sync::Arc::new(networks());
+        let first = networks.acquire("isolated").unwrap();
+        networks.acquire("isolated").unwrap();
+        assert_eq!(networks.free("isolated"), 0);
+        assert_eq!(networks.free("other"), 254);
+
+        // Without a wait, or when nothing is given back in time, the pool stays exhausted
+        let start = Instant::now();
+        assert!(matches!(
+            networks.acquire_within("isolated", Duration::ZERO).await,
+            Err(NetworkError::Exhausted(_))
+        ));
+        assert!(matches!(
+            networks
+                .acquire_within("isolated", Duration::from_millis(50))
+                .await,
+            Err(NetworkError::Exhausted(_))
+        ));
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // An address given back while waiting is taken right away
+        let releasing = networks.clone();
+        let (network, address) = (first.network.clone(), first.address);
+        actix_web::rt::spawn(async move {
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+            releasing.release(&network, address);
+        });
+        let start = Instant::now();
+        let lease = networks
+            .acquire_within("isolated", Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(lease, first);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_isolation_rules() {
+        let rules = networks().isolation_rules();
+        assert_eq!(
+            rules,
+            vec![
+                vec!["-I", "FORWARD", "-i", "br0", "-o", "br1", "-j", "DROP"],
+                vec!["-I", "FORWARD", "-i", "br1", "-o", "br0", "-j", "DROP"],
+            ]
+        );
+        // A single network has nothing to isolate
+        let single = Networks::new(
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::new(192, 168, 30, 0), 24).unwrap(),
+        );
+        assert!(single.isolation_rules().is_empty());
+    }
+}
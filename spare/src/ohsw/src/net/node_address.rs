@@ -0,0 +1,240 @@
+//! Addresses of the neighbor nodes.
+//! The addresses come from the control plane as free-form `host:port` strings, so they
+//! are validated where they enter the node, the list of nodes and the announces, and a
+//! malformed one is left out of the neighbors rather than failing deep in an offload.
+//! The hostnames are resolved ahead of the offloads and resolved again at an interval,
+//! so that a request does not wait for a lookup.
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::orchestrator::Orchestrator;
+
+/// Malformed address of a node
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum AddressError {
+    #[error("Empty address")]
+    Empty,
+    #[error("Address with whitespace: {0:?}")]
+    Whitespace(String),
+    #[error("Address without a port: {0:?}")]
+    MissingPort(String),
+    #[error("Invalid port in the address {0:?}")]
+    InvalidPort(String),
+    #[error("Invalid host in the address {0:?}")]
+    InvalidHost(String),
+}
+
+/// Validated address of a node: an ip and a port, or a hostname and a port
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NodeAddress {
+    Ip(SocketAddr),
+    Host { host: String, port: u16 },
+}
+
+/// Check if `host` is a valid hostname: dot separated labels of letters, digits and
+/// hyphens, neither starting nor ending with a hyphen
+fn valid_hostname(host: &str) -> bool {
+    host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+impl FromStr for NodeAddress {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(AddressError::Empty);
+        }
+        if s.chars().any(char::is_whitespace) {
+            return Err(AddressError::Whitespace(s.to_string()));
+        }
+        if let Ok(address) = s.parse::<SocketAddr>() {
+            return match address.port() {
+                0 => Err(AddressError::InvalidPort(s.to_string())),
+                _ => Ok(NodeAddress::Ip(address)),
+            };
+        }
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| AddressError::MissingPort(s.to_string()))?;
+        let port = match port.parse::<u16>() {
+            Ok(port) if port > 0 => port,
+            _ => return Err(AddressError::InvalidPort(s.to_string())),
+        };
+        // An ip that did not parse with its port, e.g. an IPv6 without brackets
+        if !valid_hostname(host) || host.parse::<std::net::IpAddr>().is_ok() {
+            return Err(AddressError::InvalidHost(s.to_string()));
+        }
+        Ok(NodeAddress::Host {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for NodeAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NodeAddress::Ip(address) => write!(f, "{address}"),
+            NodeAddress::Host { host, port } => write!(f, "{host}:{port}"),
+        }
+    }
+}
+
+/// Validate the address of a node
+/// # Returns
+/// * The address in its canonical form, e.g. with the hostname in lowercase
+pub fn validate(address: &str) -> Result<String, AddressError> {
+    address
+        .parse::<NodeAddress>()
+        .map(|address| address.to_string())
+}
+
+/// Hostnames of the neighbors resolved ahead of the offloads, by address
+#[derive(Debug, Default)]
+pub struct HostCache {
+    resolved: RwLock<HashMap<String, (SocketAddr, Instant)>>,
+}
+
+impl HostCache {
+    /// Get the address to connect to for a node: the ip its hostname was last resolved
+    /// to, or the address itself if it is an ip or was not resolved
+    pub fn resolve(&self, address: &str) -> String {
+        match self.resolved.read().unwrap().get(address) {
+            Some((resolved, _)) => resolved.to_string(),
+            None => address.to_string(),
+        }
+    }
+
+    /// Get the time since the hostname of a node was resolved, None if it was not
+    pub fn age(&self, address: &str) -> Option<Duration> {
+        let resolved = self.resolved.read().unwrap();
+        resolved.get(address).map(|(_, at)| at.elapsed())
+    }
+
+    /// Resolve again the hostnames of the given nodes. A hostname that cannot be resolved
+    /// keeps the ip it was last resolved to, if any.
+    pub async fn refresh(&self, addresses: impl IntoIterator<Item = String>) {
+        for address in addresses {
+            let Ok(NodeAddress::Host { .. }) = address.parse::<NodeAddress>() else {
+                continue;
+            };
+            match tokio::net::lookup_host(address.as_str()).await {
+                Ok(mut resolved) => match resolved.next() {
+                    Some(resolved) => {
+                        let previous = self
+                            .resolved
+                            .write()
+                            .unwrap()
+                            .insert(address.clone(), (resolved, Instant::now()));
+                        if previous.is_none_or(|(previous, _)| previous != resolved) {
+                            info!("Resolved {} to {}", address, resolved);
+                        }
+                    }
+                    None => warn!("Cannot resolve {}: no address", address),
+                },
+                Err(e) => warn!("Cannot resolve {}: {}", address, e),
+            }
+        }
+    }
+
+    /// Resolve the hostnames of the neighbors every `interval`, forever. The neighbors in
+    /// the emergency area are left out, no request is offloaded to them.
+    /// Must be called from within an actix runtime.
+    pub async fn run(self: Arc<Self>, orchestrator: Arc<Orchestrator>, interval: Duration) {
+        loop {
+            self.refresh(orchestrator.probe_targets()).await;
+            actix_web::rt::time::sleep(interval).await;
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate("10.0.0.1:8085").unwrap(), "10.0.0.1:8085");
+        assert_eq!(validate("[::1]:8085").unwrap(), "[::1]:8085");
+        assert_eq!(validate("Node-A.local:8085").unwrap(), "node-a.local:8085");
+        assert_eq!(
+            "node-a:8085".parse::<NodeAddress>().unwrap(),
+            NodeAddress::Host {
+                host: "node-a".to_string(),
+                port: 8085
+            }
+        );
+    }
+
+    #[test]
+    fn test_malformed() {
+        for (address, error) in [
+            ("", AddressError::Empty),
+            (
+                "node-a:8085 ",
+                AddressError::Whitespace("node-a:8085 ".into()),
+            ),
+            ("10.0.0.1", AddressError::MissingPort("10.0.0.1".into())),
+            ("node-a", AddressError::MissingPort("node-a".into())),
+            ("node-a:", AddressError::InvalidPort("node-a:".into())),
+            ("node-a:0", AddressError::InvalidPort("node-a:0".into())),
+            ("10.0.0.1:0", AddressError::InvalidPort("10.0.0.1:0".into())),
+            (
+                "node-a:99999",
+                AddressError::InvalidPort("node-a:99999".into()),
+            ),
+            (
+                "node_a:8085",
+                AddressError::InvalidHost("node_a:8085".into()),
+            ),
+            ("-node:8085", AddressError::InvalidHost("-node:8085".into())),
+            (
+                "node..a:8085",
+                AddressError::InvalidHost("node..a:8085".into()),
+            ),
+            ("::1:8085", AddressError::InvalidHost("::1:8085".into())),
+            (
+                "http://node-a:8085",
+                AddressError::InvalidHost("http://node-a:8085".into()),
+            ),
+        ] {
+            assert_eq!(validate(address), Err(error), "{:?}", address);
+        }
+        assert_eq!(
+            validate("node-a").unwrap_err().to_string(),
+            "Address without a port: \"node-a\""
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_host_cache() {
+        let cache = HostCache::default();
+        assert_eq!(cache.resolve("localhost:8085"), "localhost:8085");
+        cache
+            .refresh(["localhost:8085".to_string(), "10.0.0.1:8085".to_string()])
+            .await;
+        let resolved: SocketAddr = cache.resolve("localhost:8085").parse().unwrap();
+        assert!(resolved.ip().is_loopback());
+        assert_eq!(resolved.port(), 8085);
+        assert!(cache.age("localhost:8085").is_some());
+        // The ips are used as they are
+        assert_eq!(cache.resolve("10.0.0.1:8085"), "10.0.0.1:8085");
+        assert_eq!(cache.age("10.0.0.1:8085"), None);
+    }
+}
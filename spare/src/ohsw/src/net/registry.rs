@@ -0,0 +1,430 @@
+//! HTTP registry, a lightweight replacement of the Iggy broker.
+//! The registry collects the announces of the nodes and, once the expected number of
+//! nodes announced themselves, serves them the list of nodes. The operations of the
+//! experiment (emergencies, stats and end) are published to the registry and fetched
+//! by the nodes, which refresh periodically.
+use std::{
+    collections::VecDeque,
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Mutex,
+    time::Duration,
+};
+
+use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    control_plane::ControlPlane,
+    iggy::{Message, MessageError, Operation, Payload, Phase},
+    node_address,
+    tls::NodeClient,
+};
+use crate::orchestrator::global::identity::Node;
+
+/// State of the registry
+pub struct Registry {
+    /// Number of nodes the experiment is made of
+    expected: usize,
+    nodes: Mutex<Vec<Node>>,
+    /// Messages published so far, a node keeps the index of the next one to fetch
+    events: Mutex<Vec<serde_json::Value>>,
+}
+
+impl Registry {
+    /// Create a registry waiting for `expected` nodes
+    pub fn new(expected: usize) -> Self {
+        Self {
+            expected,
+            nodes: Mutex::new(Vec::new()),
+            events: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+/// Query of the events endpoint
+#[derive(Deserialize)]
+pub struct EventsQuery {
+    /// Index of the first event to return
+    #[serde(default)]
+    pub after: usize,
+}
+
+/// Events published after the requested index
+#[derive(Deserialize, Serialize)]
+pub struct EventsPage {
+    /// Index to ask for at the next refresh
+    pub next: usize,
+    pub events: Vec<serde_json::Value>,
+}
+
+/// Announce a node. A node announcing itself again replaces its previous announce, even
+/// from a new address, unless it was sent before the last start of the node. An announce
+/// with a malformed address is rejected with 400.
+#[post("/registry/nodes")]
+async fn announce(registry: web::Data<Registry>, node: web::Json<Node>) -> impl Responder {
+    let mut node = node.into_inner();
+    node.address = match node_address::validate(&node.address) {
+        Ok(address) => address,
+        Err(e) => {
+            warn!("Rejecting the announce of {}: {}", node.key(), e);
+            return HttpResponse::BadRequest().body(e.to_string());
+        }
+    };
+    let mut nodes = registry.nodes.lock().unwrap();
+    match nodes.iter_mut().find(|n| n.is_same(&node)) {
+        Some(previous) if previous.supersedes(&node) => (),
+        Some(previous) => *previous = node,
+        None => nodes.push(node),
+    }
+    HttpResponse::Ok().finish()
+}
+
+/// Get the list of nodes, 204 until all the expected nodes announced themselves
+#[get("/registry/nodes")]
+async fn list_nodes(registry: web::Data<Registry>) -> impl Responder {
+    let nodes = registry.nodes.lock().unwrap();
+    if nodes.len() < registry.expected {
+        return HttpResponse::NoContent().finish();
+    }
+    HttpResponse::Ok().json(Message::new(
+        Operation::ADD_NODES,
+        Some(Payload::Nodes(nodes.clone())),
+    ))
+}
+
+/// Publish an operation to all the nodes
+#[post("/registry/events")]
+async fn publish(registry: web::Data<Registry>, body: web::Bytes) -> impl Responder {
+    let message = match Message::decode(&body) {
+        Ok(message) => message,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    if !Phase::Broadcast.accepts(&message.op) {
+        return HttpResponse::BadRequest().body("Only broadcast operations can be published");
+    }
+    let mut events = registry.events.lock().unwrap();
+    events.push(serde_json::to_value(&message).unwrap());
+    HttpResponse::Ok().json(serde_json::json!({ "index": events.len() - 1 }))
+}
+
+/// Get the events published after the given index
+#[get("/registry/events")]
+async fn list_events(
+    registry: web::Data<Registry>,
+    query: web::Query<EventsQuery>,
+) -> impl Responder {
+    let events = registry.events.lock().unwrap();
+    let after = query.after.min(events.len());
+    HttpResponse::Ok().json(EventsPage {
+        next: events.len(),
+        events: events[after..].to_vec(),
+    })
+}
+
+/// Register the routes of the registry
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(announce)
+        .service(list_nodes)
+        .service(publish)
+        .service(list_events);
+}
+
+/// Start the registry on `address`, in the current runtime.
+/// Returns the address the registry listens on.
+pub fn spawn_server(
+    address: impl ToSocketAddrs,
+    registry: Registry,
+) -> Result<SocketAddr, io::Error> {
+    let registry = web::Data::new(registry);
+    let server =
+        HttpServer::new(move || App::new().app_data(registry.clone()).configure(configure))
+            .workers(1)
+            .bind(address)?;
+    let address = server.addrs()[0];
+    actix_web::rt::spawn(server.run());
+    Ok(address)
+}
+
+/// Client of the registry, for one phase of the life of the node
+pub struct HttpControlPlane {
+    url: String,
+    phase: Phase,
+    /// Time waited when there is nothing new
+    refresh: Duration,
+    /// Index of the next event to fetch
+    cursor: Mutex<usize>,
+    /// Events fetched but not received yet
+    pending: Mutex<VecDeque<Result<Message, MessageError>>>,
+    /// Factory of the clients calling the registry, over HTTPS if the nodes use TLS
+    client: NodeClient,
+}
+
+impl HttpControlPlane {
+    /// Create a client of the registry at `url` (e.g. `http://10.0.0.1:9000`)
+    pub fn new(url: &str, phase: Phase) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            phase,
+            refresh: Duration::from_millis(500),
+            cursor: Mutex::new(0),
+            pending: Mutex::new(VecDeque::new()),
+            client: NodeClient::plain(),
+        }
+    }
+
+    /// Set the time waited when there is nothing new
+    pub fn with_refresh(mut self, refresh: Duration) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    /// Set the factory of the clients, to check the certificate of the registry like
+    /// the ones of the nodes
+    pub fn with_client(mut self, client: NodeClient) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Publish an operation to all the nodes
+    pub async fn publish(&self, message: &Message) -> Result<(), MessageError> {
+        let response = self
+            .client
+            .client()
+            .post(format!("{}/registry/events", self.url))
+            .send_json(message)
+            .await
+            .map_err(|e| MessageError::Registry(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(MessageError::Registry(format!(
+                "Publish rejected with {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fetch the list of nodes, if complete
+    async fn fetch_nodes(&self) -> Result<Option<Message>, MessageError> {
+        let mut response = self
+            .client
+            .client()
+            .get(format!("{}/registry/nodes", self.url))
+            .send()
+            .await
+            .map_err(|e| MessageError::Registry(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(MessageError::Registry(format!(
+                "Node list request failed with {}",
+                response.status()
+            )));
+        }
+        let body = response
+            .body()
+            .await
+            .map_err(|e| MessageError::Registry(e.to_string()))?;
+        if body.is_empty() {
+            return Ok(None);
+        }
+        Message::decode(&body).map(Some)
+    }
+
+    /// Fetch the events published since the last refresh
+    async fn fetch_events(&self) -> Result<(), MessageError> {
+        let after = *self.cursor.lock().unwrap();
+        let page: EventsPage = self
+            .client
+            .client()
+            .get(format!("{}/registry/events?after={}", self.url, after))
+            .send()
+            .await
+            .map_err(|e| MessageError::Registry(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| MessageError::Registry(e.to_string()))?;
+
+        let mut pending = self.pending.lock().unwrap();
+        for event in page.events {
+            pending.push_back(Message::decode(&serde_json::to_vec(&event).unwrap()));
+        }
+        *self.cursor.lock().unwrap() = page.next;
+        Ok(())
+    }
+}
+
+impl ControlPlane for HttpControlPlane {
+    async fn register_node(&self, node: Node) -> Result<(), MessageError> {
+        let response = self
+            .client
+            .client()
+            .post(format!("{}/registry/nodes", self.url))
+            .send_json(&node)
+            .await
+            .map_err(|e| MessageError::Registry(e.to_string()))?;
+        if !response.status().is_success() {
+            return Err(MessageError::Registry(format!(
+                "Announce rejected with {}",
+                response.status()
+            )));
+        }
+        Ok(())
+    }
+
+    async fn receive_message(&self) -> Result<Option<Message>, MessageError> {
+        let message = match self.phase {
+            Phase::Registration => self.fetch_nodes().await?,
+            Phase::Broadcast => {
+                if self.pending.lock().unwrap().is_empty() {
+                    self.fetch_events().await?;
+                }
+                let next = self.pending.lock().unwrap().pop_front();
+                next.transpose()?
+            }
+        };
+        match message {
+            Some(message) if self.phase.accepts(&message.op) => Ok(Some(message)),
+            Some(_) => Ok(None),
+            None => {
+                actix_web::rt::time::sleep(self.refresh).await;
+                Ok(None)
+            }
+        }
+    }
+
+    async fn announce(&self, message: Message) -> Result<(), MessageError> {
+        self.publish(&message).await
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orchestrator::global::emergency::Emergency;
+
+    fn client(url: &str, phase: Phase) -> HttpControlPlane {
+        HttpControlPlane::new(url, phase).with_refresh(Duration::from_millis(10))
+    }
+
+    #[actix_web::test]
+    async fn test_registration() {
+        let address = spawn_server("127.0.0.1:0", Registry::new(2)).unwrap();
+        let url = format!("http://{}", address);
+        let a = client(&url, Phase::Registration);
+        let b = client(&url, Phase::Registration);
+
+        a.register_node(Node::new("10.0.0.1:8085".to_string(), (0.0, 0.0)))
+            .await
+            .unwrap();
+        // The second node is still missing
+        assert!(a.receive_message().await.unwrap().is_none());
+
+        b.register_node(Node::new("10.0.0.2:8085".to_string(), (0.0, 0.0)))
+            .await
+            .unwrap();
+        // Announcing again does not add the node twice
+        a.register_node(Node::new("10.0.0.1:8085".to_string(), (1.0, 1.0)))
+            .await
+            .unwrap();
+        for node in [&a, &b] {
+            match node.receive_message().await.unwrap() {
+                Some(Message {
+                    op: Operation::ADD_NODES,
+                    payload: Some(Payload::Nodes(nodes)),
+                    ..
+                }) => {
+                    assert_eq!(nodes.len(), 2);
+                    assert_eq!(nodes[0].position, Some((1.0, 1.0)));
+                }
+                _ => panic!("Both nodes receive the list of nodes"),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_address_change() {
+        let address = spawn_server("127.0.0.1:0", Registry::new(2)).unwrap();
+        let a = client(&format!("http://{}", address), Phase::Registration);
+        let node = |address: &str, id: &str| {
+            Node::new(address.to_string(), (0.0, 0.0)).with_id(id.to_string())
+        };
+
+        a.register_node(node("10.0.0.1:8085", "a").with_incarnation(1))
+            .await
+            .unwrap();
+        // The node restarted with another address, it is updated and not duplicated
+        a.register_node(node("10.0.0.9:8085", "a").with_incarnation(2))
+            .await
+            .unwrap();
+        // The announce from before the restart, delivered late, is ignored
+        a.register_node(node("10.0.0.1:8085", "a").with_incarnation(1))
+            .await
+            .unwrap();
+        // An announce with a malformed address is rejected
+        assert!(a.register_node(node("10.0.0.1", "c")).await.is_err());
+        assert!(a.receive_message().await.unwrap().is_none());
+        // Another node taking the old address is a new node
+        a.register_node(node("10.0.0.1:8085", "b")).await.unwrap();
+        match a.receive_message().await.unwrap() {
+            Some(Message {
+                payload: Some(Payload::Nodes(nodes)),
+                ..
+            }) => {
+                let nodes: Vec<_> = nodes
+                    .iter()
+                    .map(|n| (n.id.as_str(), n.address.as_str()))
+                    .collect();
+                assert_eq!(nodes, vec![("a", "10.0.0.9:8085"), ("b", "10.0.0.1:8085")]);
+            }
+            _ => panic!("The node receives the list of nodes"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_broadcast() {
+        let address = spawn_server("127.0.0.1:0", Registry::new(2)).unwrap();
+        let url = format!("http://{}", address);
+        let a = client(&url, Phase::Broadcast);
+        let b = client(&url, Phase::Broadcast);
+        assert!(a.receive_message().await.unwrap().is_none());
+
+        a.publish(&Message::new(
+            Operation::START_EMERGENCY,
+            Some(Payload::Emergency(Emergency {
+                position: (45.4642, 9.1900),
+                radius: 100.0,
+                polygon: None,
+            })),
+        ))
+        .await
+        .unwrap();
+        a.publish(&Message::new(Operation::END, None))
+            .await
+            .unwrap();
+        // The list of nodes is not an operation of the experiment
+        assert!(a
+            .publish(&Message::new(Operation::ADD_NODES, None))
+            .await
+            .is_err());
+
+        for node in [&a, &b] {
+            match node.receive_message().await.unwrap() {
+                Some(Message {
+                    op: Operation::START_EMERGENCY,
+                    payload: Some(Payload::Emergency(emergency)),
+                    ..
+                }) => assert_eq!(emergency.radius, 100.0),
+                _ => panic!("The emergency is received first"),
+            }
+            assert!(matches!(
+                node.receive_message().await.unwrap(),
+                Some(Message {
+                    op: Operation::END,
+                    ..
+                })
+            ));
+            assert!(node.receive_message().await.unwrap().is_none());
+        }
+    }
+}
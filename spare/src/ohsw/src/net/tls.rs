@@ -0,0 +1,549 @@
+//! TLS between the nodes.
+//! A node started with a certificate and a key serves HTTPS, and calls the other nodes
+//! over HTTPS checking their certificates against a CA bundle (or not at all, in the
+//! lab). TLS is a property of the whole cluster: every node announces whether it uses
+//! it, and a node refuses to join a cluster where some nodes do and others do not,
+//! instead of silently sending the payloads in cleartext.
+//! With client certificates, the nodes also prove their identity to each other: the
+//! server checks the certificate of the peer against the cluster CA and remembers
+//! its name for the whole connection.
+use std::{
+    any::Any,
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use awc::{Client, Connector};
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{self, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::{VerifierBuilderError, WebPkiClientVerifier},
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use x509_parser::extensions::GeneralName;
+
+use super::node_address::HostCache;
+use crate::orchestrator::global::identity::Node;
+
+/// Error returned when setting up TLS
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    /// A PEM file cannot be read
+    #[error("Cannot read {path}: {source}")]
+    Read { path: String, source: io::Error },
+    /// A PEM file has no certificate
+    #[error("No certificate found in {0}")]
+    NoCertificate(String),
+    /// A PEM file has no private key
+    #[error("No private key found in {0}")]
+    NoKey(String),
+    /// The certificates or the key are rejected
+    #[error("Invalid TLS configuration: {0}")]
+    Rustls(#[from] rustls::Error),
+    /// The CAs of the client certificates are rejected
+    #[error("Invalid client CA: {0}")]
+    Verifier(#[from] VerifierBuilderError),
+    /// Some nodes of the cluster do not agree on the use of TLS
+    #[error("TLS is {} on this node but not on {}", if *.tls { "enabled" } else { "disabled" }, .nodes.join(", "))]
+    Mismatch { tls: bool, nodes: Vec<String> },
+}
+
+/// How a node checks the certificates of the other nodes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trust {
+    /// Accept the certificates signed by the CAs in the given PEM file
+    Bundle(PathBuf),
+    /// Accept any certificate, only meant for the lab
+    Insecure,
+}
+
+/// Certificate and private key a node proves its identity with
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    /// PEM file with the certificate chain
+    pub cert: PathBuf,
+    /// PEM file with the private key
+    pub key: PathBuf,
+}
+
+/// Name of the node at the other end of a connection, from its verified certificate
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity(pub String);
+
+impl PeerIdentity {
+    /// Get the name of a certificate: its common name, or its first DNS name or
+    /// IP address. None if the certificate cannot be parsed or has no name.
+    pub fn from_certificate(cert: &CertificateDer<'_>) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+        if let Some(name) = cert
+            .subject()
+            .iter_common_name()
+            .find_map(|name| name.as_str().ok())
+        {
+            return Some(Self(name.to_string()));
+        }
+        let names = cert.subject_alternative_name().ok()??;
+        names
+            .value
+            .general_names
+            .iter()
+            .find_map(|name| match name {
+                GeneralName::DNSName(name) => Some(name.to_string()),
+                GeneralName::IPAddress(&[a, b, c, d]) => {
+                    Some(IpAddr::from([a, b, c, d]).to_string())
+                }
+                GeneralName::IPAddress(bytes) => <[u8; 16]>::try_from(*bytes)
+                    .ok()
+                    .map(|ip| IpAddr::from(ip).to_string()),
+                _ => None,
+            })
+            .map(Self)
+    }
+}
+
+/// Remember the identity of the peer of a TLS connection, if it sent a certificate.
+/// Meant for `HttpServer::on_connect`, the handlers read it with `HttpRequest::conn_data`.
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    let (_, session) = stream.get_ref();
+    // The certificates of the peer were already verified during the handshake
+    if let Some(peer) = session
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(PeerIdentity::from_certificate)
+    {
+        extensions.insert(peer);
+    }
+}
+
+/// Cryptographic provider used by the server and the clients
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+/// Read a PEM file
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    fs::read(path).map_err(|source| TlsError::Read {
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+/// Load the certificates of a PEM file
+pub fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs: Vec<_> = CertificateDer::pem_slice_iter(&read(path)?)
+        .filter_map(Result::ok)
+        .collect();
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.display().to_string()));
+    }
+    Ok(certs)
+}
+
+/// Load the first private key of a PEM file
+pub fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    PrivateKeyDer::from_pem_slice(&read(path)?)
+        .map_err(|_| TlsError::NoKey(path.display().to_string()))
+}
+
+/// Load the CAs of a PEM file
+fn load_roots(path: &Path) -> Result<RootCertStore, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+/// Build the configuration of the HTTPS server of the node
+/// # Arguments
+/// * `cert` - PEM file with the certificate chain of the node
+/// * `key` - PEM file with the private key of the node
+/// * `clients` - PEM file with the CAs of the client certificates of the nodes, if any.
+///   Clients without a certificate are still accepted, the routes decide whether
+///   they need one, but a certificate not signed by these CAs fails the handshake.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    clients: Option<&Path>,
+) -> Result<ServerConfig, TlsError> {
+    let builder =
+        ServerConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match clients {
+        Some(path) => builder.with_client_cert_verifier(
+            WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(path)?), provider())
+                .allow_unauthenticated()
+                .build()?,
+        ),
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_single_cert(load_certs(cert)?, load_key(key)?)?)
+}
+
+/// Build the configuration of the clients calling the other nodes
+/// # Arguments
+/// * `trust` - How the certificates of the other nodes are checked
+/// * `identity` - Client certificate of the node, if it must prove its identity
+pub fn client_config(trust: &Trust, identity: Option<&Identity>) -> Result<ClientConfig, TlsError> {
+    let builder =
+        ClientConfig::builder_with_provider(provider()).with_safe_default_protocol_versions()?;
+    let builder = match trust {
+        Trust::Bundle(path) => builder.with_root_certificates(load_roots(path)?),
+        Trust::Insecure => builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider()))),
+    };
+    let mut config = match identity {
+        Some(identity) => {
+            builder.with_client_auth_cert(load_certs(&identity.cert)?, load_key(&identity.key)?)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Verifier accepting any certificate, the handshake signatures are still checked
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Factory of the HTTP clients used to call the other nodes.
+/// awc clients cannot be shared between threads, so a new one is made for each call.
+#[derive(Clone, Default)]
+pub struct NodeClient {
+    /// Configuration of the TLS clients, None if the nodes speak plain HTTP
+    tls: Option<Arc<ClientConfig>>,
+    /// Hostnames of the nodes resolved ahead of the calls
+    hosts: Arc<HostCache>,
+}
+
+impl NodeClient {
+    /// Create a factory of clients speaking plain HTTP
+    pub fn plain() -> Self {
+        Self::default()
+    }
+
+    /// Create a factory of clients speaking HTTPS with the given configuration
+    pub fn tls(config: ClientConfig) -> Self {
+        Self {
+            tls: Some(Arc::new(config)),
+            hosts: Arc::default(),
+        }
+    }
+
+    /// Check whether the nodes are called over HTTPS
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    /// Get the cache of the hostnames of the nodes, shared by the clones of the factory
+    pub fn hosts(&self) -> &Arc<HostCache> {
+        &self.hosts
+    }
+
+    /// Get the URL of `path` on the node at `address` (ip:port or hostname:port).
+    /// Over plain HTTP a hostname is replaced by the ip it was resolved to, over HTTPS
+    /// it is kept, as the certificate of the node is checked against it.
+    pub fn url(&self, address: &str, path: &str) -> String {
+        match self.is_tls() {
+            true => format!("https://{}{}", address, path),
+            false => format!("http://{}{}", self.hosts.resolve(address), path),
+        }
+    }
+
+    /// Create a new client
+    pub fn client(&self) -> Client {
+        match &self.tls {
+            Some(config) => Client::builder()
+                .connector(Connector::new().rustls_0_23(config.clone()))
+                .finish(),
+            None => Client::default(),
+        }
+    }
+}
+
+/// Check that all the nodes of the cluster agree with this node on the use of TLS
+/// # Errors
+/// * Mismatch with the addresses of the nodes that do not agree
+pub fn check_cluster(tls: bool, nodes: &[Node]) -> Result<(), TlsError> {
+    let nodes: Vec<String> = nodes
+        .iter()
+        .filter(|node| node.tls != tls)
+        .map(|node| node.address.clone())
+        .collect();
+    if nodes.is_empty() {
+        Ok(())
+    } else {
+        Err(TlsError::Mismatch { tls, nodes })
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use actix_web::{get, App, HttpRequest, HttpResponse, HttpServer, Responder};
+    use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair};
+
+    use super::*;
+    use crate::utils::auth::ClusterAuth;
+
+    #[get("/")]
+    async fn hello() -> impl Responder {
+        HttpResponse::Ok().body("hello")
+    }
+
+    /// Answer with the name of the node forwarding the request
+    #[get("/forwarded")]
+    async fn forwarded(req: HttpRequest) -> HttpResponse {
+        let auth = ClusterAuth { required: true };
+        match auth.check(&req, 1) {
+            Ok(Some(peer)) => HttpResponse::Ok().body(peer.0),
+            Ok(None) => HttpResponse::Ok().finish(),
+            Err(refused) => refused,
+        }
+    }
+
+    /// Certificate authority writing the certificates it issues to a temporary directory
+    struct Pki {
+        dir: PathBuf,
+        /// PEM file with the certificate of the CA
+        ca: PathBuf,
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("spare-tls-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let mut params = CertificateParams::new(vec![]).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "spare-ca");
+            let key = KeyPair::generate().unwrap();
+            let cert = params.self_signed(&key).unwrap();
+            let ca = dir.join("ca.pem");
+            fs::write(&ca, cert.pem()).unwrap();
+            Self { dir, ca, cert, key }
+        }
+
+        /// Issue a certificate of 127.0.0.1 named `name`
+        fn issue(&self, name: &str) -> Identity {
+            let mut params = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            let identity = Identity {
+                cert: self.dir.join(format!("{name}.pem")),
+                key: self.dir.join(format!("{name}.key")),
+            };
+            fs::write(&identity.cert, cert.pem()).unwrap();
+            fs::write(&identity.key, key.serialize_pem()).unwrap();
+            identity
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Start an HTTPS server with the given certificate, returning its address
+    fn https_server(identity: &Identity, clients: Option<&Path>) -> String {
+        let config = server_config(&identity.cert, &identity.key, clients).unwrap();
+        let server = HttpServer::new(|| App::new().service(hello).service(forwarded))
+            .workers(1)
+            .on_connect(on_connect)
+            .bind_rustls_0_23(("127.0.0.1", 0), config)
+            .unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        address.to_string()
+    }
+
+    /// Get `path` from the node at `address`, as (status, body)
+    async fn get(client: &NodeClient, address: &str, path: &str) -> Result<(u16, String), String> {
+        let mut response = client
+            .client()
+            .get(client.url(address, path))
+            .send()
+            .await
+            .map_err(|e| e.to_string())?;
+        let body = response.body().await.map_err(|e| e.to_string())?;
+        Ok((
+            response.status().as_u16(),
+            String::from_utf8_lossy(&body).to_string(),
+        ))
+    }
+
+    #[actix_web::test]
+    async fn test_trusted_certificate() {
+        let pki = Pki::new();
+        let address = https_server(&pki.issue("node-a"), None);
+
+        let client = NodeClient::tls(client_config(&Trust::Bundle(pki.ca.clone()), None).unwrap());
+        assert_eq!(
+            client.url(&address, "/invoke"),
+            format!("https://{address}/invoke")
+        );
+        assert_eq!(
+            get(&client, &address, "/").await.unwrap(),
+            (200, "hello".to_string())
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_untrusted_certificate() {
+        let (pki, other) = (Pki::new(), Pki::new());
+        let address = https_server(&pki.issue("node-a"), None);
+
+        // A certificate not signed by the bundle is refused
+        let client =
+            NodeClient::tls(client_config(&Trust::Bundle(other.ca.clone()), None).unwrap());
+        assert!(get(&client, &address, "/").await.is_err());
+        // Unless the certificates are not checked at all
+        let client = NodeClient::tls(client_config(&Trust::Insecure, None).unwrap());
+        assert!(get(&client, &address, "/").await.is_ok());
+        // A plain HTTP client does not get an answer
+        assert!(get(&NodeClient::plain(), &address, "/").await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_client_certificates() {
+        let (pki, other) = (Pki::new(), Pki::new());
+        let address = https_server(&pki.issue("node-a"), Some(&pki.ca));
+        let trust = Trust::Bundle(pki.ca.clone());
+
+        // A node of the cluster is let in, and known by name
+        let member = pki.issue("node-b");
+        let client = NodeClient::tls(client_config(&trust, Some(&member)).unwrap());
+        assert_eq!(
+            get(&client, &address, "/forwarded").await.unwrap(),
+            (200, "node-b".to_string())
+        );
+
+        // A client without a certificate can connect, but cannot forward requests
+        let client = NodeClient::tls(client_config(&trust, None).unwrap());
+        assert_eq!(get(&client, &address, "/").await.unwrap().0, 200);
+        assert_eq!(get(&client, &address, "/forwarded").await.unwrap().0, 403);
+
+        // A certificate of another CA fails the handshake
+        let stranger = other.issue("node-b");
+        let client = NodeClient::tls(client_config(&trust, Some(&stranger)).unwrap());
+        assert!(get(&client, &address, "/forwarded").await.is_err());
+    }
+
+    #[test]
+    fn test_peer_identity() {
+        let pki = Pki::new();
+        let identity = pki.issue("node-a");
+        let cert = load_certs(&identity.cert).unwrap().remove(0);
+        assert_eq!(
+            PeerIdentity::from_certificate(&cert),
+            Some(PeerIdentity("node-a".to_string()))
+        );
+        // Without a common name, the first alternative name is used
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["10.0.0.1".to_string()]).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        let cert = params.self_signed(&key).unwrap();
+        let cert = CertificateDer::from(cert.der().to_vec());
+        assert_eq!(
+            PeerIdentity::from_certificate(&cert),
+            Some(PeerIdentity("10.0.0.1".to_string()))
+        );
+        assert_eq!(
+            PeerIdentity::from_certificate(&CertificateDer::from(vec![0])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        let pki = Pki::new();
+        let identity = pki.issue("node-a");
+        assert!(matches!(
+            server_config(&identity.key, &identity.key, None),
+            Err(TlsError::NoCertificate(_))
+        ));
+        assert!(matches!(
+            server_config(&identity.cert, &identity.cert, None),
+            Err(TlsError::NoKey(_))
+        ));
+        let missing = pki.dir.join("missing.pem");
+        assert!(matches!(
+            client_config(&Trust::Bundle(missing), None),
+            Err(TlsError::Read { .. })
+        ));
+    }
+
+    #[test]
+    fn test_check_cluster() {
+        let plain = Node::new("10.0.0.1:8085".to_string(), (0.0, 0.0));
+        let secure = Node::new("10.0.0.2:8085".to_string(), (0.0, 0.0)).with_tls(true);
+        let nodes = [plain, secure];
+        assert!(check_cluster(false, &nodes[..1]).is_ok());
+        assert!(check_cluster(true, &nodes[1..]).is_ok());
+
+        let error = check_cluster(true, &nodes).err().unwrap();
+        assert!(matches!(&error, TlsError::Mismatch { nodes, .. } if nodes == &["10.0.0.1:8085"]));
+        assert_eq!(
+            error.to_string(),
+            "TLS is enabled on this node but not on 10.0.0.1:8085"
+        );
+    }
+}
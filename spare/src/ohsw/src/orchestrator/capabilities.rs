@@ -0,0 +1,109 @@
+//! Capabilities of the nodes, e.g. `arch:x86_64`, `gpu` or `mem:8192`, announced with
+//! their identity, and the capabilities the functions require.
+//! Not every node can run every function, so a request is only offloaded to the
+//! neighbors with every capability its function requires; the others are not probed.
+use std::str::FromStr;
+
+/// Check if the capability `offered` by a node satisfies `required`: it is the same, or
+/// for a numeric value, e.g. `mem:8192`, the same name with at least that value
+pub fn satisfies(offered: &str, required: &str) -> bool {
+    if offered == required {
+        return true;
+    }
+    match (offered.split_once(':'), required.split_once(':')) {
+        (Some((name, offered)), Some((required_name, required))) if name == required_name => {
+            match (offered.parse::<u64>(), required.parse::<u64>()) {
+                (Ok(offered), Ok(required)) => offered >= required,
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Get the capabilities in `required` that none of `offered` satisfies
+pub fn missing(required: &[String], offered: &[String]) -> Vec<String> {
+    required
+        .iter()
+        .filter(|required| !offered.iter().any(|offered| satisfies(offered, required)))
+        .cloned()
+        .collect()
+}
+
+/// Capabilities required by a function, given on the command line as
+/// `function=capability,capability`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionRequires {
+    pub function: String,
+    pub requires: Vec<String>,
+}
+
+impl FromStr for FunctionRequires {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!("Invalid function requirements: {s}, expected function=capability,capability")
+        };
+        let (function, requires) = s.split_once('=').ok_or_else(invalid)?;
+        let requires: Vec<_> = requires.split(',').map(str::to_string).collect();
+        if function.is_empty() || requires.iter().any(String::is_empty) {
+            return Err(invalid());
+        }
+        Ok(FunctionRequires {
+            function: function.to_string(),
+            requires,
+        })
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(capabilities: &[&str]) -> Vec<String> {
+        capabilities.iter().map(|c| c.to_string()).collect()
+    }
+
+    #[test]
+    fn test_satisfies() {
+        assert!(satisfies("gpu", "gpu"));
+        assert!(satisfies("arch:x86_64", "arch:x86_64"));
+        assert!(!satisfies("arch:aarch64", "arch:x86_64"));
+        // Numeric values are lower bounds
+        assert!(satisfies("mem:16384", "mem:8192"));
+        assert!(!satisfies("mem:4096", "mem:8192"));
+        assert!(!satisfies("cpus:16384", "mem:8192"));
+        assert!(!satisfies("mem:lots", "mem:8192"));
+        assert!(!satisfies("gpu", "gpu:1"));
+    }
+
+    #[test]
+    fn test_missing() {
+        let offered = strings(&["arch:x86_64", "mem:8192"]);
+        assert!(missing(&[], &offered).is_empty());
+        assert!(missing(&strings(&["mem:4096"]), &offered).is_empty());
+        assert_eq!(
+            missing(&strings(&["gpu", "arch:x86_64", "mem:16384"]), &offered),
+            strings(&["gpu", "mem:16384"])
+        );
+        // A node that announced no capabilities has none
+        assert_eq!(missing(&strings(&["gpu"]), &[]), strings(&["gpu"]));
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "render=gpu,mem:8192".parse::<FunctionRequires>().unwrap(),
+            FunctionRequires {
+                function: "render".to_string(),
+                requires: strings(&["gpu", "mem:8192"]),
+            }
+        );
+        assert!("render".parse::<FunctionRequires>().is_err());
+        assert!("=gpu".parse::<FunctionRequires>().is_err());
+        assert!("render=".parse::<FunctionRequires>().is_err());
+        assert!("render=gpu,".parse::<FunctionRequires>().is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Drain of the node before a maintenance.
+//! A draining node advertises no resources and offloads the new requests, but for the
+//! emergency ones, while the instances already running complete. Once the last of them
+//! terminates the node is drained and can be stopped without dropping any request.
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// State of the node, as seen by the operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DrainState {
+    /// The node takes new requests
+    Serving,
+    /// The node waits for the instances still running
+    Draining,
+    /// No instance is running anymore, the node can be stopped
+    Drained,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Status {
+    draining: bool,
+    /// Local instances running
+    running: usize,
+}
+
+/// Drain of the node and the local instances running
+#[derive(Debug)]
+pub struct Drain {
+    status: watch::Sender<Status>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drain {
+    /// Create a new drain, with the node serving
+    pub fn new() -> Self {
+        Self {
+            status: watch::Sender::new(Status::default()),
+        }
+    }
+
+    /// Start draining the node
+    /// # Returns
+    /// * Whether the node was serving
+    pub fn start(&self) -> bool {
+        self.status.send_if_modified(|status| {
+            let started = !status.draining;
+            status.draining = true;
+            started
+        })
+    }
+
+    /// Serve the new requests again
+    /// # Returns
+    /// * Whether the node was draining
+    pub fn stop(&self) -> bool {
+        self.status.send_if_modified(|status| {
+            let stopped = status.draining;
+            status.draining = false;
+            stopped
+        })
+    }
+
+    /// Check whether the node is draining, or already drained
+    pub fn is_draining(&self) -> bool {
+        self.status.borrow().draining
+    }
+
+    /// Get the number of local instances running
+    pub fn running(&self) -> usize {
+        self.status.borrow().running
+    }
+
+    /// Get the state of the node
+    pub fn state(&self) -> DrainState {
+        let status = *self.status.borrow();
+        match status {
+            Status {
+                draining: false, ..
+            } => DrainState::Serving,
+            Status { running: 0, .. } => DrainState::Drained,
+            _ => DrainState::Draining,
+        }
+    }
+
+    /// Account a local instance until the returned guard is dropped
+    pub fn instance(&self) -> RunningInstance<'_> {
+        self.status.send_modify(|status| status.running += 1);
+        RunningInstance { drain: self }
+    }
+
+    /// Wait for the last local instance to terminate, or for the drain to be stopped
+    /// # Returns
+    /// * Whether the node is drained
+    pub async fn drained(&self) -> bool {
+        let mut status = self.status.subscribe();
+        // The sender lives as long as self, the wait cannot fail
+        let drained = match status
+            .wait_for(|status| !status.draining || status.running == 0)
+            .await
+        {
+            Ok(status) => status.draining,
+            Err(_) => false,
+        };
+        drained
+    }
+}
+
+/// A local instance running, accounted by the drain until it is dropped
+pub struct RunningInstance<'a> {
+    drain: &'a Drain,
+}
+
+impl Drop for RunningInstance<'_> {
+    fn drop(&mut self) {
+        self.drain.status.send_modify(|status| status.running -= 1);
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::rt::time::timeout;
+
+    use super::*;
+
+    #[test]
+    fn test_state() {
+        let drain = Drain::new();
+        assert_eq!(drain.state(), DrainState::Serving);
+        let instance = drain.instance();
+        assert_eq!(drain.running(), 1);
+
+        assert!(drain.start());
+        assert!(!drain.start());
+        assert!(drain.is_draining());
+        assert_eq!(drain.state(), DrainState::Draining);
+        drop(instance);
+        assert_eq!(drain.running(), 0);
+        assert_eq!(drain.state(), DrainState::Drained);
+
+        assert!(drain.stop());
+        assert!(!drain.stop());
+        assert_eq!(drain.state(), DrainState::Serving);
+    }
+
+    #[actix_web::test]
+    async fn test_drained() {
+        let drain = Drain::new();
+        let first = drain.instance();
+        let second = drain.instance();
+        drain.start();
+
+        // Resolves only once the last instance terminates
+        let drained = drain.drained();
+        futures::pin_mut!(drained);
+        assert!(timeout(Duration::from_millis(20), &mut drained)
+            .await
+            .is_err());
+        drop(first);
+        assert!(timeout(Duration::from_millis(20), &mut drained)
+            .await
+            .is_err());
+        drop(second);
+        assert!(timeout(Duration::from_millis(20), &mut drained)
+            .await
+            .unwrap());
+
+        // Already drained
+        assert!(drain.drained().await);
+    }
+
+    #[actix_web::test]
+    async fn test_undrained() {
+        let drain = Drain::new();
+        let _instance = drain.instance();
+        drain.start();
+        let (drained, _) = futures::join!(drain.drained(), async { drain.stop() });
+        assert!(!drained);
+    }
+}
@@ -2,15 +2,238 @@ use super::{Distance, NeighborNode};
 use longitude::Location;
 use serde::{Deserialize, Serialize};
 
+/// Smallest area of a polygon zone (in m²), a smaller one is most likely a mistake
+pub const MIN_ZONE_AREA: f64 = 100.0;
+
+/// Distance from an edge of a polygon zone within which a point is on the edge (in m)
+const EDGE_TOLERANCE: f64 = 0.01;
+
+/// Mean radius of the earth (in m)
+const EARTH_RADIUS: f64 = 6_371_000.0;
+
 /// Struct that implements the Distance trait
 /// and represents the emergency point.
-#[derive(Clone, Deserialize, Serialize, Copy)]
+/// The zone is a circle around the point, or a polygon when its vertices are given:
+/// the point is then the center of the vertices and the radius reaches the farthest one.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(try_from = "Zone")]
 pub struct Emergency {
     /// The position of the emergency point
     pub position: (f64, f64),
     /// The radius of the emergency point
     pub radius: f64,
+    /// The vertices of the zone, in the order of its boundary and in the order of the
+    /// coordinates of the positions; None for a circle
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub polygon: Option<Vec<(f64, f64)>>,
+}
+
+/// Zone of an emergency as received, checked before it becomes an Emergency
+#[derive(Deserialize)]
+struct Zone {
+    position: Option<(f64, f64)>,
+    radius: Option<f64>,
+    #[serde(default)]
+    polygon: Option<Vec<(f64, f64)>>,
+}
+
+impl TryFrom<Zone> for Emergency {
+    type Error = String;
+
+    fn try_from(zone: Zone) -> Result<Self, Self::Error> {
+        match (zone.polygon, zone.position, zone.radius) {
+            // The point and the radius of a polygon are computed from its vertices
+            (Some(vertices), _, _) => Emergency::polygon(vertices),
+            (None, Some(position), Some(radius)) => Ok(Emergency {
+                position,
+                radius,
+                polygon: None,
+            }),
+            _ => Err("An emergency needs a position and a radius, or a polygon".to_string()),
+        }
+    }
+}
+
+impl Emergency {
+    /// Create an emergency whose zone is a polygon
+    /// # Arguments
+    /// * `vertices` - Vertices of the zone in the order of its boundary, repeating the
+    ///   first one at the end is allowed
+    /// # Returns
+    /// * The emergency, or why the polygon is not a valid zone: fewer than 3 vertices,
+    ///   a vertex that is not finite or repeated, crossing edges or an area smaller
+    ///   than `MIN_ZONE_AREA`
+    pub fn polygon(mut vertices: Vec<(f64, f64)>) -> Result<Self, String> {
+        if vertices.len() > 1 && vertices.first() == vertices.last() {
+            vertices.pop();
+        }
+        if vertices.len() < 3 {
+            return Err(format!(
+                "A polygon needs at least 3 vertices, got {}",
+                vertices.len()
+            ));
+        }
+        if let Some(i) = vertices
+            .iter()
+            .position(|(a, b)| !a.is_finite() || !b.is_finite())
+        {
+            return Err(format!("Vertex {} of the polygon is not finite", i));
+        }
+        for i in 1..vertices.len() {
+            if let Some(j) = vertices[..i].iter().position(|v| *v == vertices[i]) {
+                return Err(format!("Vertex {} of the polygon repeats vertex {}", i, j));
+            }
+        }
+        let origin = vertices[0];
+        let plane: Vec<(f64, f64)> = vertices.iter().map(|v| project(origin, *v)).collect();
+        let n = plane.len();
+        for i in 0..n {
+            let (a, b) = (plane[i], plane[(i + 1) % n]);
+            // An edge going back along the previous one
+            let next = plane[(i + 2) % n];
+            if cross(a, b, next) == 0.0 && dot(sub(b, a), sub(next, b)) < 0.0 {
+                return Err(format!(
+                    "Edges {} and {} of the polygon overlap",
+                    i,
+                    (i + 1) % n
+                ));
+            }
+            // The edges next to each other share a vertex, the others must not touch
+            for j in i + 2..n {
+                if i == 0 && j == n - 1 {
+                    continue;
+                }
+                if segments_touch(a, b, plane[j], plane[(j + 1) % n]) {
+                    return Err(format!("Edges {} and {} of the polygon cross", i, j));
+                }
+            }
+        }
+        let area = (0..n)
+            .map(|i| {
+                let (a, b) = (plane[i], plane[(i + 1) % n]);
+                a.0 * b.1 - b.0 * a.1
+            })
+            .sum::<f64>()
+            .abs()
+            / 2.0;
+        if area < MIN_ZONE_AREA {
+            return Err(format!(
+                "The polygon covers {:.1} m², less than {} m²",
+                area, MIN_ZONE_AREA
+            ));
+        }
+        let position = (
+            vertices.iter().map(|v| v.0).sum::<f64>() / n as f64,
+            vertices.iter().map(|v| v.1).sum::<f64>() / n as f64,
+        );
+        let radius = vertices
+            .iter()
+            .map(|v| meters(position, *v))
+            .fold(0.0, f64::max);
+        Ok(Emergency {
+            position,
+            radius,
+            polygon: Some(vertices),
+        })
+    }
+
+    /// Check if a position is in the zone, the boundary included.
+    /// A position that is not finite is never in it.
+    pub fn contains(&self, position: (f64, f64)) -> bool {
+        if !position.0.is_finite() || !position.1.is_finite() {
+            return false;
+        }
+        let Some(vertices) = &self.polygon else {
+            return meters(self.position, position) <= self.radius;
+        };
+        let point = project(self.position, position);
+        let plane: Vec<(f64, f64)> = vertices
+            .iter()
+            .map(|v| project(self.position, *v))
+            .collect();
+        let n = plane.len();
+        let mut inside = false;
+        for i in 0..n {
+            let (a, b) = (plane[i], plane[(i + 1) % n]);
+            if segment_distance(point, a, b) <= EDGE_TOLERANCE {
+                return true;
+            }
+            // Count the edges crossed by a ray from the point towards +x
+            if (a.1 > point.1) != (b.1 > point.1)
+                && point.0 < a.0 + (point.1 - a.1) / (b.1 - a.1) * (b.0 - a.0)
+            {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    /// Radius around the emergency point holding the whole zone, to look the nodes up
+    /// with before checking them against a polygon
+    pub fn reach(&self) -> f64 {
+        match self.polygon {
+            // The circle through the vertices, with some room for the projection
+            Some(_) => self.radius * 1.01 + EDGE_TOLERANCE,
+            None => self.radius,
+        }
+    }
+}
+
+/// Great-circle distance between two positions (in m)
+fn meters(a: (f64, f64), b: (f64, f64)) -> f64 {
+    Location::from(a.0, a.1)
+        .distance(&Location::from(b.0, b.1))
+        .meters()
+}
+
+/// Project a position on the plane tangent to the earth at `origin`, in meters.
+/// Like the distances, the first coordinate is the latitude.
+fn project(origin: (f64, f64), position: (f64, f64)) -> (f64, f64) {
+    let x = (position.1 - origin.1).to_radians() * EARTH_RADIUS * origin.0.to_radians().cos();
+    let y = (position.0 - origin.0).to_radians() * EARTH_RADIUS;
+    (x, y)
+}
+
+fn sub(a: (f64, f64), b: (f64, f64)) -> (f64, f64) {
+    (a.0 - b.0, a.1 - b.1)
+}
+
+fn dot(a: (f64, f64), b: (f64, f64)) -> f64 {
+    a.0 * b.0 + a.1 * b.1
+}
+
+/// Cross product of `b - a` and `c - a`, 0 if the three points are aligned
+fn cross(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> f64 {
+    let (ab, ac) = (sub(b, a), sub(c, a));
+    ab.0 * ac.1 - ab.1 * ac.0
 }
+
+/// Distance of `p` from the segment from `a` to `b`
+fn segment_distance(p: (f64, f64), a: (f64, f64), b: (f64, f64)) -> f64 {
+    let ab = sub(b, a);
+    let length2 = dot(ab, ab);
+    let t = match length2 > 0.0 {
+        true => (dot(sub(p, a), ab) / length2).clamp(0.0, 1.0),
+        false => 0.0,
+    };
+    let closest = (a.0 + t * ab.0, a.1 + t * ab.1);
+    let d = sub(p, closest);
+    dot(d, d).sqrt()
+}
+
+/// Check if the segments `a`-`b` and `c`-`d` cross or touch
+fn segments_touch(a: (f64, f64), b: (f64, f64), c: (f64, f64), d: (f64, f64)) -> bool {
+    let (d1, d2) = (cross(c, d, a), cross(c, d, b));
+    let (d3, d4) = (cross(a, b, c), cross(a, b, d));
+    if d1 * d2 < 0.0 && d3 * d4 < 0.0 {
+        return true;
+    }
+    segment_distance(a, c, d) <= EDGE_TOLERANCE
+        || segment_distance(b, c, d) <= EDGE_TOLERANCE
+        || segment_distance(c, a, b) <= EDGE_TOLERANCE
+        || segment_distance(d, a, b) <= EDGE_TOLERANCE
+}
+
 impl Distance for Emergency {
     fn distance(&self, node: &mut dyn NeighborNode) -> f64 {
         let location_a = Location::from(self.position.0, self.position.1);
@@ -37,4 +260,194 @@ impl NeighborNode for Emergency {
         let _ = emergency;
         panic!("Emergency node cannot be set as emergency");
     }
+
+    fn set_position(&mut self, position: (f64, f64)) {
+        // The vertices of a polygon move with its point
+        if let Some(vertices) = &mut self.polygon {
+            let delta = sub(position, self.position);
+            for vertex in vertices.iter_mut() {
+                *vertex = (vertex.0 + delta.0, vertex.1 + delta.1);
+            }
+        }
+        self.position = position;
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A square of about 1.1 km per side around Milan
+    fn square() -> Vec<(f64, f64)> {
+        vec![(45.46, 9.18), (45.46, 9.19), (45.47, 9.19), (45.47, 9.18)]
+    }
+
+    #[test]
+    fn test_circle() {
+        let emergency = Emergency {
+            position: (45.4685, 9.1824),
+            radius: 1000.0,
+            polygon: None,
+        };
+        assert!(emergency.contains((45.4685, 9.1824)));
+        assert!(emergency.contains((45.4700, 9.1850)));
+        assert!(!emergency.contains((45.5, 9.3)));
+        assert!(!emergency.contains((f64::NAN, f64::NAN)));
+        assert_eq!(emergency.reach(), 1000.0);
+    }
+
+    #[test]
+    fn test_polygon_contains() {
+        let emergency = Emergency::polygon(square()).unwrap();
+        assert!(meters(emergency.position, (45.465, 9.185)) < 0.001);
+        // About half the diagonal of the square
+        assert!(
+            (emergency.radius - 680.0).abs() < 10.0,
+            "{}",
+            emergency.radius
+        );
+        assert!(emergency.contains((45.465, 9.185)));
+        assert!(emergency.contains((45.4601, 9.1899)));
+        assert!(!emergency.contains((45.455, 9.185)));
+        assert!(!emergency.contains((45.465, 9.195)));
+        assert!(!emergency.contains((f64::NAN, 9.185)));
+        // The whole zone is within its reach
+        for vertex in square() {
+            assert!(meters(emergency.position, vertex) <= emergency.reach());
+        }
+    }
+
+    #[test]
+    fn test_polygon_boundary() {
+        let emergency = Emergency::polygon(square()).unwrap();
+        // On the vertices
+        for vertex in square() {
+            assert!(emergency.contains(vertex), "{:?}", vertex);
+        }
+        // On the edges
+        assert!(emergency.contains((45.46, 9.185)));
+        assert!(emergency.contains((45.465, 9.19)));
+        assert!(emergency.contains((45.47, 9.181)));
+        assert!(emergency.contains((45.461, 9.18)));
+        // Just outside an edge and a vertex
+        assert!(!emergency.contains((45.4599, 9.185)));
+        assert!(!emergency.contains((45.4701, 9.1901)));
+        // On the line of an edge, past its end
+        assert!(!emergency.contains((45.46, 9.195)));
+
+        // A concave zone: the notch is outside, its edges are not
+        let notched = Emergency::polygon(vec![
+            (45.46, 9.18),
+            (45.46, 9.19),
+            (45.47, 9.19),
+            (45.465, 9.185),
+            (45.47, 9.18),
+        ])
+        .unwrap();
+        assert!(!notched.contains((45.469, 9.185)));
+        assert!(notched.contains((45.465, 9.185)));
+        assert!(notched.contains((45.4675, 9.1875)));
+        assert!(notched.contains((45.462, 9.185)));
+    }
+
+    #[test]
+    fn test_polygon_validation() {
+        // Closing the boundary with the first vertex is allowed
+        let mut closed = square();
+        closed.push(closed[0]);
+        assert_eq!(Emergency::polygon(closed).unwrap().polygon, Some(square()));
+
+        let err = |vertices: Vec<(f64, f64)>| Emergency::polygon(vertices).err().unwrap();
+        assert!(err(vec![(45.46, 9.18), (45.47, 9.19)]).contains("at least 3 vertices"));
+        assert!(
+            err(vec![(45.46, 9.18), (45.46, 9.18), (45.46, 9.18)]).contains("at least 3 vertices")
+        );
+        assert!(err(vec![(45.46, 9.18), (f64::NAN, 9.19), (45.47, 9.19)])
+            .contains("Vertex 1 of the polygon is not finite"));
+        // A bow tie
+        assert!(err(vec![
+            (45.46, 9.18),
+            (45.47, 9.19),
+            (45.46, 9.19),
+            (45.47, 9.18)
+        ])
+        .contains("Edges 0 and 2 of the polygon cross"));
+        // Two squares touching at a vertex
+        assert!(err(vec![
+            (45.46, 9.18),
+            (45.46, 9.19),
+            (45.47, 9.19),
+            (45.46, 9.19),
+            (45.45, 9.20),
+            (45.45, 9.19),
+        ])
+        .contains("repeats vertex 1"));
+        // A vertex on an edge that is not next to it
+        assert!(err(vec![
+            (45.46, 9.18),
+            (45.46, 9.19),
+            (45.47, 9.19),
+            (45.46, 9.185),
+            (45.45, 9.185),
+        ])
+        .contains("cross"));
+        // A spike going back along its edge
+        assert!(err(vec![
+            (45.46, 9.18),
+            (45.46, 9.19),
+            (45.46, 9.20),
+            (45.46, 9.195),
+            (45.47, 9.18),
+        ])
+        .contains("overlap"));
+        // Aligned vertices, the last edge goes back along the others
+        assert!(err(vec![(45.46, 9.18), (45.46, 9.19), (45.46, 9.20)]).contains("overlap"));
+        // A rectangle of about 5 m by 4 m
+        assert!(err(vec![
+            (45.46, 9.18),
+            (45.46, 9.18005),
+            (45.46005, 9.18005),
+            (45.46005, 9.18)
+        ])
+        .contains("less than 100 m²"));
+    }
+
+    #[test]
+    fn test_decode() {
+        let circle: Emergency =
+            serde_json::from_str(r#"{"position":[45.4685,9.1824],"radius":1000.0}"#).unwrap();
+        assert!(circle.polygon.is_none());
+        assert_eq!(circle.radius, 1000.0);
+        // The circle is sent as before
+        assert_eq!(
+            serde_json::to_string(&circle).unwrap(),
+            r#"{"position":[45.4685,9.1824],"radius":1000.0}"#
+        );
+
+        let polygon: Emergency = serde_json::from_str(
+            r#"{"polygon":[[45.46,9.18],[45.46,9.19],[45.47,9.19],[45.47,9.18]]}"#,
+        )
+        .unwrap();
+        assert_eq!(polygon.polygon, Some(square()));
+        assert!(meters(polygon.position, (45.465, 9.185)) < 0.001);
+        // The point and the radius sent with a polygon are computed again
+        let sent = serde_json::to_string(&polygon).unwrap();
+        let received: Emergency = serde_json::from_str(&sent).unwrap();
+        assert_eq!(received.position, polygon.position);
+        assert_eq!(received.radius, polygon.radius);
+
+        assert!(serde_json::from_str::<Emergency>(r#"{"radius":1000.0}"#)
+            .err()
+            .unwrap()
+            .to_string()
+            .contains("a position and a radius, or a polygon"));
+        assert!(serde_json::from_str::<Emergency>(
+            r#"{"polygon":[[45.46,9.18],[45.47,9.19],[45.46,9.19],[45.47,9.18]]}"#
+        )
+        .err()
+        .unwrap()
+        .to_string()
+        .contains("cross"));
+    }
 }
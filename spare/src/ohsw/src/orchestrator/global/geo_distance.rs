@@ -27,6 +27,10 @@ impl NeighborNode for GeoDistance {
     fn set_emergency(&mut self, emergency: bool) {
         self.emergency = emergency;
     }
+
+    fn set_position(&mut self, position: (f64, f64)) {
+        self.position = position;
+    }
 }
 impl Distance for GeoDistance {
     fn distance(&self, node: &mut dyn NeighborNode) -> f64 {
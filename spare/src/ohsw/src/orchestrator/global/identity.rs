@@ -1,25 +1,156 @@
+use std::{fs, io, path::Path};
+
 use longitude::Location;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{Distance, NeighborNode, NO_POSITION};
 
-use super::{Distance, NeighborNode};
+/// Name of the file, in the data directory, holding the id of the node
+pub const NODE_ID_FILE: &str = "node_id";
+/// Name of the file, in the data directory, holding the number of starts of the node
+pub const INCARNATION_FILE: &str = "incarnation";
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Node {
+    // Stable id of the node, kept across restarts and address changes.
+    // Nodes announced before the ids were introduced have none.
+    #[serde(default)]
+    pub id: String,
     pub address: String, // Ip:Port
-    pub position: (f64, f64),
+    // None for the nodes started with --positionless, ranked by their latencies only
+    #[serde(default)]
+    pub position: Option<(f64, f64)>,
+    // Whether the node serves HTTPS, nodes announced before TLS was supported do not
+    #[serde(default)]
+    pub tls: bool,
+    // Starts of the node, an announce with a lower one was sent before its last start.
+    // Nodes announced before the incarnations were introduced have 0
+    #[serde(default)]
+    pub incarnation: u64,
+    // What the node can run, e.g. arch:x86_64, gpu or mem:8192, see `capabilities`
+    #[serde(default)]
+    pub capabilities: Vec<String>,
 }
 impl Node {
     pub fn new(address: String, position: (f64, f64)) -> Self {
-        Self { address, position }
+        Self {
+            id: String::new(),
+            address,
+            position: Some(position),
+            tls: false,
+            incarnation: 0,
+            capabilities: Vec::new(),
+        }
+    }
+
+    /// Set the stable id of the node
+    pub fn with_id(self, id: String) -> Self {
+        Self { id, ..self }
+    }
+
+    /// Key the node is known by: its id, or its address when it has none
+    pub fn key(&self) -> &str {
+        if self.id.is_empty() {
+            &self.address
+        } else {
+            &self.id
+        }
+    }
+
+    /// Check if `other` is the same node, maybe announced from another address.
+    /// Nodes are compared by id, or by address when either has none.
+    pub fn is_same(&self, other: &Node) -> bool {
+        if self.id.is_empty() || other.id.is_empty() {
+            self.address == other.address
+        } else {
+            self.id == other.id
+        }
+    }
+
+    /// Set the position of the node, None if it has none
+    pub fn with_position(self, position: Option<(f64, f64)>) -> Self {
+        Self { position, ..self }
+    }
+
+    /// Set whether the node serves HTTPS
+    pub fn with_tls(self, tls: bool) -> Self {
+        Self { tls, ..self }
+    }
+
+    /// Set the number of starts of the node
+    pub fn with_incarnation(self, incarnation: u64) -> Self {
+        Self {
+            incarnation,
+            ..self
+        }
+    }
+
+    /// Set the capabilities the node announces
+    pub fn with_capabilities(self, capabilities: Vec<String>) -> Self {
+        Self {
+            capabilities,
+            ..self
+        }
+    }
+
+    /// Check if `other` is an older announce of the same node, sent before its last start
+    pub fn supersedes(&self, other: &Node) -> bool {
+        self.is_same(other) && self.incarnation > other.incarnation
+    }
+}
+/// Load the id of the node from `dir`, or generate one on the first start and store it
+pub fn load_or_create_id(dir: &Path) -> io::Result<String> {
+    let path = dir.join(NODE_ID_FILE);
+    match fs::read_to_string(&path) {
+        Ok(id) => Uuid::parse_str(id.trim())
+            .map(|id| id.to_string())
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid node id in {}: {e}", path.display()),
+                )
+            }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::create_dir_all(dir)?;
+            let id = Uuid::new_v4().to_string();
+            fs::write(&path, &id)?;
+            Ok(id)
+        }
+        Err(e) => Err(e),
     }
 }
+
+/// Count a start of the node in `dir`
+/// # Returns
+/// * The incarnation of the node, 1 on the first start
+pub fn next_incarnation(dir: &Path) -> io::Result<u64> {
+    let path = dir.join(INCARNATION_FILE);
+    let previous = match fs::read_to_string(&path) {
+        Ok(count) => count.trim().parse::<u64>().map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid incarnation in {}: {e}", path.display()),
+            )
+        })?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::create_dir_all(dir)?;
+            0
+        }
+        Err(e) => return Err(e),
+    };
+    let incarnation = previous + 1;
+    fs::write(&path, incarnation.to_string())?;
+    Ok(incarnation)
+}
+
 impl NeighborNode for Node {
     fn address(&self) -> String {
         self.address.clone()
     }
 
     fn position(&self) -> (f64, f64) {
-        self.position
+        self.position.unwrap_or(NO_POSITION)
     }
 
     fn emergency(&self) -> bool {
@@ -27,12 +158,109 @@ impl NeighborNode for Node {
     }
 
     fn set_emergency(&mut self, _emergency: bool) {}
+
+    fn set_position(&mut self, position: (f64, f64)) {
+        self.position = Some(position);
+    }
 }
 impl Distance for Node {
     fn distance(&self, node: &mut dyn NeighborNode) -> f64 {
-        let location_a = Location::from(self.position.0, self.position.1);
+        let position = self.position();
+        let location_a = Location::from(position.0, position.1);
         let location_b = Location::from(node.position().0, node.position().1);
 
         location_a.distance(&location_b).meters()
     }
 }
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_or_create_id() {
+        let dir = std::env::temp_dir().join(format!("spare-id-{}", Uuid::new_v4()));
+        let id = load_or_create_id(&dir).unwrap();
+        assert!(Uuid::parse_str(&id).is_ok());
+        // The id survives a restart
+        assert_eq!(load_or_create_id(&dir).unwrap(), id);
+        // A corrupted id is not silently replaced
+        fs::write(dir.join(NODE_ID_FILE), "not an id").unwrap();
+        assert_eq!(
+            load_or_create_id(&dir).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_position() {
+        // The nodes announced before --positionless always have one
+        let node: Node =
+            serde_json::from_str(r#"{"address":"10.0.0.1:8085","position":[45.0,9.0]}"#).unwrap();
+        assert_eq!(node.position, Some((45.0, 9.0)));
+        assert_eq!(NeighborNode::position(&node), (45.0, 9.0));
+        for raw in [
+            r#"{"address":"10.0.0.1:8085","position":null}"#,
+            r#"{"address":"10.0.0.1:8085"}"#,
+        ] {
+            let node: Node = serde_json::from_str(raw).unwrap();
+            assert_eq!(node.position, None);
+        }
+
+        let mut node = node.with_position(None);
+        let json = serde_json::to_value(&node).unwrap();
+        assert!(json["position"].is_null());
+        // Without a position, it is at no distance from anything
+        let mut other = Node::new("10.0.0.2:8085".to_string(), (45.0, 9.0));
+        assert!(node.distance(&mut other).is_nan());
+        node.set_position((45.0, 9.0));
+        assert_eq!(node.distance(&mut other), 0.0);
+    }
+
+    #[test]
+    fn test_next_incarnation() {
+        let dir = std::env::temp_dir().join(format!("spare-incarnation-{}", Uuid::new_v4()));
+        assert_eq!(next_incarnation(&dir).unwrap(), 1);
+        assert_eq!(next_incarnation(&dir).unwrap(), 2);
+        fs::write(dir.join(INCARNATION_FILE), "two").unwrap();
+        assert_eq!(
+            next_incarnation(&dir).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_same_node() {
+        let node = Node::new("10.0.0.1:8085".to_string(), (0.0, 0.0)).with_id("a".to_string());
+        // A node that moved is still the same node
+        let moved = Node::new("10.0.0.9:8085".to_string(), (0.0, 0.0)).with_id("a".to_string());
+        assert!(node.is_same(&moved));
+        // Another node that took its address is not
+        let other = Node::new("10.0.0.1:8085".to_string(), (0.0, 0.0)).with_id("b".to_string());
+        assert!(!node.is_same(&other));
+        // Without an id, the nodes are compared by address
+        let legacy = Node::new("10.0.0.1:8085".to_string(), (0.0, 0.0));
+        assert!(node.is_same(&legacy));
+        assert_eq!(legacy.key(), "10.0.0.1:8085");
+        assert_eq!(node.key(), "a");
+
+        // Only a later start of the same node supersedes it
+        let restarted = node.clone().with_incarnation(2);
+        assert!(restarted.supersedes(&node.clone().with_incarnation(1)));
+        assert!(!restarted.supersedes(&restarted));
+        assert!(!restarted.supersedes(&other));
+    }
+
+    #[test]
+    fn test_decode_without_id() {
+        let node: Node =
+            serde_json::from_str(r#"{"address":"10.0.0.1:8085","position":[0.0,0.0]}"#).unwrap();
+        assert!(node.id.is_empty());
+        assert_eq!(node.incarnation, 0);
+        assert!(node.capabilities.is_empty());
+        assert_eq!(node.key(), "10.0.0.1:8085");
+    }
+}
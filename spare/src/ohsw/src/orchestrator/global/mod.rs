@@ -1,33 +1,140 @@
-use actix_web::web;
-use awc::Client;
+use std::{cmp::Ordering, collections::HashMap, time::Duration};
+
+use actix_web::{
+    http::header::{self, HeaderMap},
+    web,
+};
 use dyn_clone::DynClone;
 use emergency::Emergency;
-use log::warn;
+use log::{error, warn};
+use longitude::Location;
+use ranking::RankMetric;
+use spatial_index::SpatialIndex;
 
-use crate::api::invoke::InvokeFunction;
+use crate::{
+    api::invoke::{BUDGET_HEADER, FORWARDED_BY_HEADER, HOPS_HEADER},
+    net::tls::NodeClient,
+};
 
 use super::InvokeError;
 pub mod emergency;
 pub mod geo_distance;
 pub mod identity;
+pub mod node_cache;
+pub mod position;
+pub mod probed;
+pub mod ranking;
 pub mod simple_cellular;
 pub mod smart_latency;
+pub mod snapshot;
+pub mod spatial_index;
 
 /// Enum that represents the different strategies
 /// available for the Neighbor Node Selection
 /// strategy.
-#[derive(Clone, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum NeighborNodeStrategy {
     /// Strategy that uses the Haversine formula to calculate
     /// the distance between two points.ß
     GeoDistance,
     /// Strategy that uses a simple model to calculate the
     /// latency between two nodes connected through the
-    /// same base station.
-    SimpleCellular,
+    /// same base station, with the queuing delays drawn as given.
+    SimpleCellular(simple_cellular::Queuing),
     /// Strategy that uses a smart model to consider both
-    /// distance and latency to select the best node.
-    SmartLatency,
+    /// distance and latency to select the best node, with the
+    /// latency estimated from the given number of last samples.
+    SmartLatency(usize),
+    /// Strategy that measures the latency by periodically
+    /// probing the nodes.
+    Probed,
+}
+
+impl std::fmt::Display for NeighborNodeStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NeighborNodeStrategy::GeoDistance => write!(f, "GeoDistance"),
+            NeighborNodeStrategy::SimpleCellular(queuing) => match queuing {
+                simple_cellular::Queuing::Sampled => write!(f, "SimpleCellular"),
+                simple_cellular::Queuing::Mean => write!(f, "SimpleCellular:deterministic"),
+                simple_cellular::Queuing::Seeded(seed) => write!(f, "SimpleCellular:seed={seed}"),
+            },
+            NeighborNodeStrategy::SmartLatency(smart_latency::DEFAULT_WINDOW) => {
+                write!(f, "SmartLatency")
+            }
+            NeighborNodeStrategy::SmartLatency(window) => write!(f, "SmartLatency:window={window}"),
+            NeighborNodeStrategy::Probed => write!(f, "Probed"),
+        }
+    }
+}
+
+impl NeighborNodeStrategy {
+    /// Get the strategy set in the STRATEGY environment variable, GeoDistance if it is not
+    /// set or not valid
+    pub fn from_env() -> Self {
+        match std::env::var("STRATEGY").map(|strategy| strategy.parse()) {
+            Ok(Ok(strategy)) => strategy,
+            Ok(Err(e)) => {
+                error!("{}.", e);
+                NeighborNodeStrategy::GeoDistance
+            }
+            Err(_) => NeighborNodeStrategy::GeoDistance,
+        }
+    }
+
+    /// Whether the strategy ranks the neighbors from their positions, so that a node
+    /// without one cannot use it. The others only use the latencies they measure.
+    pub fn needs_position(&self) -> bool {
+        matches!(
+            self,
+            NeighborNodeStrategy::GeoDistance | NeighborNodeStrategy::SimpleCellular(_)
+        )
+    }
+}
+
+impl std::str::FromStr for NeighborNodeStrategy {
+    type Err = String;
+
+    /// Parse a strategy as written by `Display`. The latencies of SimpleCellular are made
+    /// reproducible with `SimpleCellular:deterministic`, using the mean queuing delays,
+    /// or `SimpleCellular:seed=<N>`, sampling them from seeded generators. SmartLatency
+    /// estimates the latencies from the last `SmartLatency:window=<N>` samples.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None => match s {
+                "GeoDistance" => Ok(NeighborNodeStrategy::GeoDistance),
+                "SimpleCellular" => Ok(NeighborNodeStrategy::SimpleCellular(
+                    simple_cellular::Queuing::Sampled,
+                )),
+                "SmartLatency" => Ok(NeighborNodeStrategy::SmartLatency(
+                    smart_latency::DEFAULT_WINDOW,
+                )),
+                "Probed" => Ok(NeighborNodeStrategy::Probed),
+                _ => Err(format!("Unknown strategy: {s}")),
+            },
+            Some(("SimpleCellular", "deterministic")) => Ok(NeighborNodeStrategy::SimpleCellular(
+                simple_cellular::Queuing::Mean,
+            )),
+            Some(("SimpleCellular", option)) => match option.strip_prefix("seed=") {
+                Some(seed) => seed
+                    .parse()
+                    .map(|seed| {
+                        NeighborNodeStrategy::SimpleCellular(simple_cellular::Queuing::Seeded(seed))
+                    })
+                    .map_err(|e| format!("Invalid seed {seed}: {e}")),
+                None => Err(format!("Unknown option of SimpleCellular: {option}")),
+            },
+            Some(("SmartLatency", option)) => match option.strip_prefix("window=") {
+                Some(window) => match window.parse() {
+                    Ok(0) => Err("The window of SmartLatency must be positive".to_string()),
+                    Ok(window) => Ok(NeighborNodeStrategy::SmartLatency(window)),
+                    Err(e) => Err(format!("Invalid window {window}: {e}")),
+                },
+                None => Err(format!("Unknown option of SmartLatency: {option}")),
+            },
+            Some((strategy, _)) => Err(format!("The strategy {strategy} has no options")),
+        }
+    }
 }
 
 /// Trait that represents a Neighbor Node
@@ -36,17 +143,37 @@ pub trait NeighborNode {
     fn position(&self) -> (f64, f64);
     fn emergency(&self) -> bool;
     fn set_emergency(&mut self, emergency: bool);
+    /// Move the node, e.g. a vehicle
+    fn set_position(&mut self, position: (f64, f64));
 }
 
 pub trait Distance {
     /// Get the distance between two points + another metric (distance, latency)
     fn distance(&self, other: &mut dyn NeighborNode) -> f64;
 }
+/// Position of the nodes that have none, e.g. started with `--positionless`. It is not a
+/// valid position: such a node comes last by distance and is never inside an emergency.
+pub const NO_POSITION: (f64, f64) = (f64::NAN, f64::NAN);
+
+/// Time after which a cached latency estimate is estimated again, at the next sort
+pub const LATENCY_ESTIMATE_TTL: Duration = Duration::from_secs(60);
+
 pub trait Latency {
     /// Get the latency between two points
     fn latency(&mut self, other: &mut dyn NeighborNodeWithLatency) -> f64;
     /// Update the latency of the node
     fn update_latency(&mut self, new_latency: f64);
+    /// Get the latency last measured or estimated, without estimating it again
+    fn last_latency(&self) -> f64;
+    /// Get the time since the latency was estimated, None if it is not cached
+    fn latency_age(&self) -> Option<Duration> {
+        None
+    }
+    /// Get the number of samples the latency was estimated from, None if it is not
+    /// estimated from samples
+    fn sample_count(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// Trait that represents a Neighbor Node with distance
@@ -70,29 +197,92 @@ pub enum NeighborNodeType {
     Latency(Box<dyn NeighborNodeWithLatency>),
 }
 impl NeighborNodeType {
-    pub async fn invoke(&self, data: InvokeFunction) -> Result<web::Bytes, InvokeError> {
-        let client = Client::default();
-        let invoke = client
-            .post(format!("http://{}/invoke", self.address()))
+    /// Forward a request to the node
+    /// # Arguments
+    /// * `client` - Clients used to call the node
+    /// * `from` - Address of the node forwarding the request
+    /// * `hops` - Hops of the request once forwarded
+    /// * `budget` - Time left before the deadline of the request, if it has one
+    /// * `body` - The JSON body of the request, sent as it is
+    /// # Returns
+    /// * The output of the function and the headers of the answer, telling where it ran
+    pub async fn invoke(
+        &self,
+        client: &NodeClient,
+        from: &str,
+        hops: i32,
+        budget: Option<Duration>,
+        body: web::Bytes,
+    ) -> Result<(web::Bytes, HeaderMap), InvokeError> {
+        let mut request = client
+            .client()
+            .post(client.url(&self.address(), "/invoke"))
+            .insert_header((FORWARDED_BY_HEADER, from))
+            .insert_header((HOPS_HEADER, hops.to_string()));
+        if let Some(budget) = budget {
+            request = request.insert_header((BUDGET_HEADER, budget.as_millis().to_string()));
+        }
+        let mut invoke = request
+            .insert_header((header::CONTENT_TYPE, "application/json"))
+            // The output is compressed, if at all, by the node facing the client
+            .insert_header((header::ACCEPT_ENCODING, "identity"))
             .timeout(std::time::Duration::from_secs(60))
-            .send_json(&data)
-            .await;
+            .send_body(body)
+            .await?;
 
-        if invoke.is_err() {
-            return Err(InvokeError::Unknown(invoke.err().unwrap().to_string()));
+        if invoke.status().is_success() {
+            let headers = invoke.headers().clone();
+            Ok((invoke.body().await?, headers))
         } else {
-            let mut invoke = match invoke {
-                Ok(invoke) => invoke,
-                Err(e) => return Err(InvokeError::Unknown(e.to_string())),
-            };
-            if invoke.status().is_success() {
-                match invoke.body().await {
-                    Ok(body) => Ok(body),
-                    Err(e) => Err(InvokeError::Unknown(e.to_string())),
-                }
-            } else {
-                Err(InvokeError::Unknown(invoke.status().to_string()))
+            Err(InvokeError::Status(invoke.status()))
+        }
+    }
+
+    /// Get the latency last measured or estimated for the node (in ms),
+    /// None if it is unknown or the node is selected by distance
+    pub fn known_latency(&self) -> Option<f64> {
+        match self {
+            NeighborNodeType::Distance(_) => None,
+            NeighborNodeType::Latency(node) => {
+                let latency = node.last_latency();
+                (latency > 0.0 && latency < f64::MAX).then_some(latency)
+            }
+        }
+    }
+
+    /// Get the metric the node is ranked by from `position`, without estimating it
+    /// again: its distance for the nodes selected by distance, else its latency
+    pub fn rank_metric(&self, position: (f64, f64)) -> (RankMetric, Option<f64>) {
+        match self {
+            NeighborNodeType::Distance(node) => {
+                let from = Location::from(position.0, position.1);
+                let to = Location::from(node.position().0, node.position().1);
+                (RankMetric::Distance, Some(from.distance(&to).meters()))
             }
+            NeighborNodeType::Latency(_) => (RankMetric::Latency, self.known_latency()),
+        }
+    }
+
+    /// Get the time since the latency of the node was estimated, None if it is not cached
+    pub fn latency_age(&self) -> Option<Duration> {
+        match self {
+            NeighborNodeType::Distance(_) => None,
+            NeighborNodeType::Latency(node) => node.latency_age(),
+        }
+    }
+
+    /// Whether the cached latency estimate of the node is older than `LATENCY_ESTIMATE_TTL`
+    pub fn latency_expired(&self) -> bool {
+        self.latency_age()
+            .is_some_and(|age| age > LATENCY_ESTIMATE_TTL)
+    }
+
+    /// Get the number of samples the latency of the node was estimated from, None if it
+    /// is not estimated from samples
+    pub fn sample_count(&self) -> Option<usize> {
+        match self {
+            NeighborNodeType::Distance(_) => None,
+            NeighborNodeType::Latency(node) => node.sample_count(),
         }
     }
 }
@@ -124,8 +314,18 @@ impl NeighborNode for NeighborNodeType {
             NeighborNodeType::Latency(node) => node.set_emergency(emergency),
         }
     }
+
+    fn set_position(&mut self, position: (f64, f64)) {
+        match self {
+            NeighborNodeType::Distance(node) => node.set_position(position),
+            NeighborNodeType::Latency(node) => node.set_position(position),
+        }
+    }
 }
 
+/// Number of nodes from which the spatial index is used by default
+pub const DEFAULT_INDEX_THRESHOLD: usize = 256;
+
 /// Struct that represents the Neighbor Nodes
 /// available in the system.
 #[derive(Clone)]
@@ -136,6 +336,15 @@ pub struct NeighborNodeList {
     strategy: NeighborNodeStrategy,
     /// Emergency Position and Radius
     emergency: Option<Emergency>, // (Longitude, Latitude, Radius in meters)
+    /// Spatial index of the nodes, built once the list reaches `index_threshold` nodes
+    index: Option<SpatialIndex>,
+    /// Number of nodes from which the spatial index is used
+    index_threshold: usize,
+    /// Position of each node in `nodes`, by address
+    slots: HashMap<String, usize>,
+    /// Weight of the distance from the emergency when ranking the nodes during an
+    /// emergency, None to rank them as the strategy does
+    emergency_weight: Option<f64>,
 }
 impl NeighborNodeList {
     /// Create a new empty NeighborNodeList.
@@ -150,9 +359,63 @@ impl NeighborNodeList {
             nodes: Vec::new(),
             strategy,
             emergency: None,
+            index: None,
+            index_threshold: DEFAULT_INDEX_THRESHOLD,
+            slots: HashMap::new(),
+            emergency_weight: None,
+        }
+    }
+
+    /// Rank the nodes away from the emergency while there is one.
+    /// The nodes are then ranked by their distance from the current node, weighted by
+    /// `1 - weight`, minus their distance from the emergency, weighted by `weight`,
+    /// so the spillover is not all sent to the nodes just outside the zone.
+    /// A weight of 0 keeps the ranking of the strategy.
+    pub fn with_emergency_weight(mut self, weight: f64) -> Self {
+        self.emergency_weight = (weight > 0.0).then_some(weight);
+        self
+    }
+
+    /// Check if the nodes are currently ranked away from the emergency
+    pub fn is_emergency_aware(&self) -> bool {
+        self.emergency.is_some() && self.emergency_weight.is_some()
+    }
+
+    /// Set the number of nodes from which the spatial index is used.
+    /// Smaller lists are scanned linearly.
+    pub fn with_index_threshold(mut self, threshold: usize) -> Self {
+        self.index_threshold = threshold;
+        self.index = None;
+        self.update_index();
+        self
+    }
+
+    /// Check if the spatial index is in use
+    pub fn is_indexed(&self) -> bool {
+        self.index.is_some()
+    }
+
+    /// Build the spatial index if the list is large enough
+    fn update_index(&mut self) {
+        if self.index.is_none() && self.nodes.len() >= self.index_threshold {
+            self.index = Some(SpatialIndex::new(
+                self.nodes
+                    .iter()
+                    .map(|node| (node.address(), node.position())),
+            ));
         }
     }
 
+    /// Update the position of the nodes in `slots`, after they were reordered
+    fn update_slots(&mut self) {
+        self.slots = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.address(), i))
+            .collect();
+    }
+
     /// Get the strategy used to calculate the distance
     /// # Returns  
     /// * The strategy used to calculate the distance
@@ -161,43 +424,167 @@ impl NeighborNodeList {
         self.strategy.clone()
     }
 
-    /// Add a new node to the list
+    /// Add a new node to the list. A node already in the list, e.g. announced twice,
+    /// is moved to the position rather than added again.
     /// # Arguments
     /// * 'address' - Address of the node
     /// * 'position' - Position of the node as (Longitude, Latitude)
     pub fn add_node(&mut self, address: String, position: (f64, f64)) {
+        if self.update_position(&address, position) {
+            return;
+        }
+        self.slots.insert(address.clone(), self.nodes.len());
+        if let Some(index) = self.index.as_mut() {
+            index.insert(address.clone(), position);
+        }
+        let node = self.new_node(address, position);
+        self.nodes.push(node);
+        self.update_index();
+    }
+
+    /// Move a node to a new address, keeping its rank in the list.
+    /// What was learned about the node at its previous address, e.g. its latency, is
+    /// dropped. Returns false if no node has the previous address.
+    /// # Arguments
+    /// * 'previous' - Address the node had
+    /// * 'address' - Address of the node
+    /// * 'position' - Position of the node as (Longitude, Latitude)
+    pub fn update_node(&mut self, previous: &str, address: String, position: (f64, f64)) -> bool {
+        let Some(slot) = self.slots.remove(previous) else {
+            return false;
+        };
+        self.slots.insert(address.clone(), slot);
+        self.nodes[slot] = self.new_node(address, position);
+        // The index holds the addresses, build it again
+        self.index = None;
+        self.update_index();
+        true
+    }
+
+    /// Remove a node from the list, keeping the rank of the others.
+    /// Returns false if no node has the address.
+    /// # Arguments
+    /// * 'address' - Address of the node
+    pub fn remove_node(&mut self, address: &str) -> bool {
+        let Some(slot) = self.slots.remove(address) else {
+            return false;
+        };
+        self.nodes.remove(slot);
+        self.update_slots();
+        // The index holds the addresses, build it again
+        self.index = None;
+        self.update_index();
+        true
+    }
+
+    /// Move a node to a new position. While there is an emergency, whether the node is
+    /// in its zone is checked again. Returns false if no node has the address.
+    /// # Arguments
+    /// * 'address' - Address of the node
+    /// * 'position' - Position of the node as (Longitude, Latitude)
+    pub fn update_position(&mut self, address: &str, position: (f64, f64)) -> bool {
+        let Some(&slot) = self.slots.get(address) else {
+            return false;
+        };
+        let node = &mut self.nodes[slot];
+        node.set_position(position);
+        if let Some(em_pos) = &self.emergency {
+            node.set_emergency(em_pos.contains(position));
+        }
+        // The index holds the positions, build it again
+        self.index = None;
+        self.update_index();
+        true
+    }
+
+    /// Create a node of the strategy of the list
+    fn new_node(&self, address: String, position: (f64, f64)) -> NeighborNodeType {
         match self.strategy {
-            NeighborNodeStrategy::GeoDistance => {
-                self.nodes.push(NeighborNodeType::Distance(Box::new(
-                    geo_distance::GeoDistance::new(position, address),
-                )));
-            }
-            NeighborNodeStrategy::SimpleCellular => {
-                self.nodes.push(NeighborNodeType::Latency(Box::new(
-                    simple_cellular::SimpleCellular::new(position, address),
-                )));
-            }
-            NeighborNodeStrategy::SmartLatency => {
-                self.nodes.push(NeighborNodeType::Latency(Box::new(
-                    smart_latency::SmartLatency::new(position, address),
-                )));
+            NeighborNodeStrategy::GeoDistance => NeighborNodeType::Distance(Box::new(
+                geo_distance::GeoDistance::new(position, address),
+            )),
+            NeighborNodeStrategy::SimpleCellular(queuing) => NeighborNodeType::Latency(Box::new(
+                simple_cellular::SimpleCellular::new(position, address).with_queuing(queuing),
+            )),
+            NeighborNodeStrategy::SmartLatency(window) => NeighborNodeType::Latency(Box::new(
+                smart_latency::SmartLatency::new(position, address).with_window(window),
+            )),
+            NeighborNodeStrategy::Probed => {
+                NeighborNodeType::Latency(Box::new(probed::Probed::new(position, address)))
             }
         }
     }
 
     /// Set an emergency
     /// # Arguments
-    /// * 'position' - Position of the emergency as (Longitude, Latitude)
-    /// * 'radius' - Radius of the emergency in meters
+    /// * 'em_pos' - Zone of the emergency, a circle or a polygon
     pub fn set_emergency(&mut self, em_pos: Emergency) {
-        for node in self.nodes.iter_mut() {
-            if em_pos.distance(node) <= em_pos.radius {
-                node.set_emergency(true);
+        match &self.index {
+            Some(index) => {
+                // The index finds the nodes around the zone, a polygon may not hold them all
+                for address in index.within(em_pos.position, em_pos.reach()) {
+                    if let Some(slot) = self.slots.get(address) {
+                        let node = &mut self.nodes[*slot];
+                        if em_pos.polygon.is_none() || em_pos.contains(node.position()) {
+                            node.set_emergency(true);
+                        }
+                    }
+                }
+            }
+            None => {
+                for node in self.nodes.iter_mut() {
+                    if em_pos.contains(node.position()) {
+                        node.set_emergency(true);
+                    }
+                }
             }
         }
         self.emergency = Some(em_pos);
     }
 
+    /// Get the `k` closest nodes to `position` accepted by `filter`, closest first.
+    /// Distances are great-circle distances, nodes without a valid position come last.
+    /// # Arguments
+    /// * `position` - Position to search from
+    /// * `k` - Maximum number of nodes to return
+    /// * `filter` - Nodes for which it returns false are skipped
+    pub fn nearest_k(
+        &self,
+        position: (f64, f64),
+        k: usize,
+        filter: impl Fn(&NeighborNodeType) -> bool,
+    ) -> Vec<NeighborNodeType> {
+        match &self.index {
+            Some(index) => index
+                .nearest_k(position, k, |address| {
+                    self.slots
+                        .get(address)
+                        .is_some_and(|slot| filter(&self.nodes[*slot]))
+                })
+                .into_iter()
+                .map(|(address, _)| self.nodes[self.slots[address]].clone())
+                .collect(),
+            None => {
+                let target = spatial_index::Point::from_position(position);
+                let mut keyed: Vec<(f64, &NeighborNodeType)> = self
+                    .nodes
+                    .iter()
+                    .filter(|node| filter(node))
+                    .map(|node| {
+                        let point = spatial_index::Point::from_position(node.position());
+                        (point.chord2(&target), node)
+                    })
+                    .collect();
+                keyed.sort_by(|a, b| compare_metric(a.0, b.0));
+                keyed
+                    .into_iter()
+                    .take(k)
+                    .map(|(_, node)| node.clone())
+                    .collect()
+            }
+        }
+    }
+
     /// Clean the emergency
     pub fn clear_emergency(&mut self) {
         self.emergency = None;
@@ -230,6 +617,14 @@ impl NeighborNodeList {
     /// # Arguments
     /// * `current` - Current node)
     pub fn sort<T: NeighborNode>(&mut self, current: &mut T) {
+        // A node without a position cannot tell which neighbors are away from the emergency
+        let positioned = spatial_index::Point::from_position(current.position()).is_valid();
+        if let (Some(emergency), Some(weight), true) =
+            (self.emergency.clone(), self.emergency_weight, positioned)
+        {
+            self.sort_away_from_emergency(current, emergency, weight);
+            return;
+        }
         match self.strategy {
             NeighborNodeStrategy::GeoDistance => {
                 self.sort_by_distance(&mut geo_distance::GeoDistance {
@@ -238,25 +633,26 @@ impl NeighborNodeList {
                     emergency: current.emergency(),
                 });
             }
-            NeighborNodeStrategy::SimpleCellular => {
+            NeighborNodeStrategy::SimpleCellular(_) => {
                 self.sort_by_latency(&mut simple_cellular::SimpleCellular {
-                    position: current.position(),
-                    address: current.address(),
                     emergency: current.emergency(),
-                    latency: 0.0,
-                    last_update: std::time::Instant::now(),
+                    ..simple_cellular::SimpleCellular::new(current.position(), current.address())
                 });
             }
-            NeighborNodeStrategy::SmartLatency => {
+            NeighborNodeStrategy::Probed => {
+                self.sort_by_latency(&mut probed::Probed::new(
+                    current.position(),
+                    current.address(),
+                ));
+            }
+            NeighborNodeStrategy::SmartLatency(_) => {
+                let mut reference =
+                    smart_latency::SmartLatency::new(current.position(), current.address());
+                reference.emergency = current.emergency();
+                reference.latency = 0.0;
                 // If we have an emergency, we need to sort by latency
                 if self.emergency.is_some() {
-                    self.sort_by_latency(&mut smart_latency::SmartLatency {
-                        position: current.position(),
-                        address: current.address(),
-                        emergency: current.emergency(),
-                        latency: 0.0,
-                        sample_count: 0,
-                    });
+                    self.sort_by_latency(&mut reference);
                 } else {
                     // Put at the end the nodes that have latency != f64::MAX
                     // So we can offload to all the nodes and measure the latency
@@ -265,14 +661,7 @@ impl NeighborNodeList {
                     while i < self.nodes.len() {
                         match self.nodes[i] {
                             NeighborNodeType::Latency(ref mut node) => {
-                                if node.latency(&mut smart_latency::SmartLatency {
-                                    position: current.position(),
-                                    address: current.address(),
-                                    emergency: current.emergency(),
-                                    latency: 0.0,
-                                    sample_count: 0,
-                                }) != f64::MAX
-                                {
+                                if node.latency(&mut reference) != f64::MAX {
                                     let removed_node = self.nodes.remove(i);
                                     self.nodes.push(removed_node);
                                 } else {
@@ -284,66 +673,87 @@ impl NeighborNodeList {
                         }
                     }
                     // If all the nodes have latency != f64::MAX, we move the first one to the end
-                    // So we can do a round robin through the nodes and measure the latency
-                    if found {
+                    // So we can do a round robin through the nodes and measure the latency.
+                    // The last neighbor may have left the list
+                    if found && !self.nodes.is_empty() {
                         let node = self.nodes.remove(0);
                         self.nodes.push(node);
                     }
                 }
             }
         }
+        self.update_slots();
     }
 
-    /// Sort the nodes by latency from the current node
+    /// Sort the nodes by latency from the current node.
+    /// Nodes without a latency (or with a NaN one) are put at the end.
     /// # Arguments
     /// * `current` - Current node
     pub fn sort_by_latency(&mut self, current: &mut dyn NeighborNodeWithLatency) {
         warn!("Sorting by Latency");
-        let mut latencies: Vec<(f64, usize)> = self
-            .nodes
-            .iter_mut()
-            .enumerate()
-            .map(|(i, node)| match node {
-                NeighborNodeType::Latency(node) => (node.latency(current), i),
-                _ => panic!("Node is not a latency node"),
-            })
-            .collect();
-
-        latencies.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
-
-        self.nodes = latencies
-            .into_iter()
-            .map(|(_, i)| self.nodes[i].clone())
-            .collect();
+        self.sort_by_key(|node| match node {
+            NeighborNodeType::Latency(node) => node.latency(current),
+            NeighborNodeType::Distance(_) => f64::NAN,
+        });
     }
 
-    /// Sort the nodes by distance from the current node
+    /// Sort the nodes by distance from the current node.
+    /// Nodes with a NaN distance are put at the end.
     /// # Arguments
     /// * `current` - Current node
     pub fn sort_by_distance(&mut self, current: &mut dyn NeighborNode) {
-        self.nodes.sort_by(|a, b| {
-            let distance_a = match a {
-                NeighborNodeType::Distance(node) => node.distance(current),
-                NeighborNodeType::Latency(node) => {
-                    warn!("Sorting by distance, but node is a latency node");
-                    node.distance(current)
-                }
-            };
-            let distance_b = match b {
-                NeighborNodeType::Distance(node) => node.distance(current),
-                NeighborNodeType::Latency(node) => {
-                    warn!("Sorting by distance, but node is a latency node");
-                    node.distance(current)
-                }
-            };
-            distance_a.partial_cmp(&distance_b).unwrap()
+        self.sort_by_key(|node| match node {
+            NeighborNodeType::Distance(node) => node.distance(current),
+            NeighborNodeType::Latency(node) => {
+                warn!("Sorting by distance, but node is a latency node");
+                node.distance(current)
+            }
+        });
+    }
+
+    /// Sort the nodes by a blend of their distance from the current node, lower is
+    /// better, and of their distance from the emergency, higher is better.
+    /// # Arguments
+    /// * `current` - Current node
+    /// * `emergency` - The emergency to move away from
+    /// * `weight` - Weight of the distance from the emergency, in [0, 1]
+    pub fn sort_away_from_emergency(
+        &mut self,
+        current: &mut dyn NeighborNode,
+        emergency: Emergency,
+        weight: f64,
+    ) {
+        let current = geo_distance::GeoDistance::new(current.position(), current.address());
+        self.sort_by_key(|node| {
+            (1.0 - weight) * current.distance(node) - weight * emergency.distance(node)
         });
     }
+
+    /// Sort the nodes by a metric, computed once per node
+    fn sort_by_key(&mut self, mut metric: impl FnMut(&mut NeighborNodeType) -> f64) {
+        let mut keyed: Vec<(f64, NeighborNodeType)> = self
+            .nodes
+            .drain(..)
+            .map(|mut node| (metric(&mut node), node))
+            .collect();
+        keyed.sort_by(|a, b| compare_metric(a.0, b.0));
+        self.nodes = keyed.into_iter().map(|(_, node)| node).collect();
+        self.update_slots();
+    }
+}
+
+/// Compare two metrics, lower is better and NaN is the worst
+fn compare_metric(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (false, false) => a.partial_cmp(&b).unwrap(),
+        (a_nan, b_nan) => a_nan.cmp(&b_nan),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api::invoke::InvokeFunction;
 
     #[test]
     fn test_add_node() {
@@ -352,6 +762,19 @@ mod tests {
         list.add_node("node2".to_string(), (1.0, 1.0));
         list.add_node("node3".to_string(), (2.0, 2.0));
         assert_eq!(list.nodes.len(), 3);
+
+        // A node added again is moved, not duplicated
+        list.add_node("node2".to_string(), (3.0, 3.0));
+        assert_eq!(list.nodes.len(), 3);
+        assert_eq!(list.nodes[1].position(), (3.0, 3.0));
+
+        // The others keep their rank
+        assert!(list.remove_node("node1"));
+        assert!(!list.remove_node("node1"));
+        let addresses: Vec<_> = list.nodes.iter().map(|node| node.address()).collect();
+        assert_eq!(addresses, vec!["node2", "node3"]);
+        assert!(list.update_position("node3", (4.0, 4.0)));
+        assert_eq!(list.nodes[1].position(), (4.0, 4.0));
     }
 
     #[test]
@@ -363,6 +786,7 @@ mod tests {
         let emergency = Emergency {
             position: (0.0, 0.0),
             radius: 100.0,
+            polygon: None,
         };
         list.set_emergency(emergency);
         assert_eq!(
@@ -383,6 +807,7 @@ mod tests {
         let emergency = Emergency {
             position: (0.0, 0.0),
             radius: 100.0,
+            polygon: None,
         };
         list.set_emergency(emergency);
         list.clear_emergency();
@@ -404,36 +829,348 @@ mod tests {
         assert_eq!(list.nodes[0].address(), "node1");
     }
 
+    /// Nodes on a line through an emergency of 20 km centered on (9.0, 45.0)
+    const EMERGENCY_NODES: [(&str, (f64, f64)); 4] = [
+        ("far", (9.9, 45.0)),
+        ("west", (8.55, 45.0)),
+        ("inside", (9.05, 45.0)),
+        // Just outside the zone, and the closest to the current node
+        ("edge", (9.35, 45.0)),
+    ];
+
+    #[test]
+    fn test_sort_away_from_emergency() {
+        let emergency = Emergency {
+            position: (9.0, 45.0),
+            radius: 20_000.0,
+            polygon: None,
+        };
+        // Inside the zone
+        let current = (9.1, 45.0);
+        let order = |weight: f64, emergency: Option<Emergency>| {
+            let mut list = NeighborNodeList::new(NeighborNodeStrategy::GeoDistance)
+                .with_emergency_weight(weight);
+            for (address, position) in EMERGENCY_NODES {
+                list.add_node(address.to_string(), position);
+            }
+            if let Some(emergency) = emergency {
+                list.set_emergency(emergency);
+            }
+            list.sort(&mut geo_distance::GeoDistance::new(
+                current,
+                "current".to_string(),
+            ));
+            addresses(&list.nodes)
+        };
+
+        // Without an emergency, or without a weight, the closest nodes come first
+        let by_distance = vec!["inside", "edge", "west", "far"];
+        assert_eq!(order(0.9, None), by_distance);
+        assert_eq!(order(0.0, Some(emergency.clone())), by_distance);
+        // Only the distance from the current node
+        assert_eq!(
+            order(f64::MIN_POSITIVE, Some(emergency.clone())),
+            by_distance
+        );
+        // The node just outside the zone goes from first to last
+        assert_eq!(
+            order(0.9, Some(emergency.clone())),
+            vec!["far", "west", "edge", "inside"]
+        );
+        // Only the distance from the emergency
+        assert_eq!(
+            order(1.0, Some(emergency.clone())),
+            vec!["far", "west", "edge", "inside"]
+        );
+
+        let mut list =
+            NeighborNodeList::new(NeighborNodeStrategy::GeoDistance).with_emergency_weight(0.9);
+        list.set_emergency(emergency);
+        assert!(list.is_emergency_aware());
+        list.clear_emergency();
+        assert!(!list.is_emergency_aware());
+    }
+
     #[test]
     fn test_sort_by_latency() {
-        let mut list = NeighborNodeList::new(NeighborNodeStrategy::SimpleCellular);
+        // With the mean queuing delays the ranking only depends on the distances
+        let mut list = NeighborNodeList::new(NeighborNodeStrategy::SimpleCellular(
+            simple_cellular::Queuing::Mean,
+        ));
         list.add_node("node3".to_string(), (35.6764, 139.650));
         list.add_node("node2".to_string(), (40.7128, 74.0060));
         list.add_node("node1".to_string(), (48.8575, 2.3514));
 
-        list.sort_by_latency(&mut simple_cellular::SimpleCellular {
+        list.sort_by_latency(&mut simple_cellular::SimpleCellular::new(
+            (45.4685, 9.1824),
+            "current".to_string(),
+        ));
+        let latencies: Vec<_> = list.nodes.iter().map(|node| node.known_latency()).collect();
+        assert!(
+            latencies.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}",
+            latencies
+        );
+        let addresses: Vec<_> = list.nodes.iter().map(|node| node.address()).collect();
+        assert_eq!(addresses, vec!["node1", "node2", "node3"]);
+    }
+
+    #[test]
+    fn test_seeded_latency() {
+        // Neighbors under the base stations around the node, the queuing delays decide
+        let rank = |queuing| {
+            let mut list = NeighborNodeList::new(NeighborNodeStrategy::SimpleCellular(queuing));
+            for i in 0..20 {
+                let offset = 0.001 * (i % 5) as f64;
+                list.add_node(
+                    format!("10.0.0.{i}:8085"),
+                    (45.4685 + offset, 9.1824 - offset),
+                );
+            }
+            list.sort(&mut geo_distance::GeoDistance::new(
+                (45.4685, 9.1824),
+                "current".to_string(),
+            ));
+            list.nodes
+                .iter()
+                .map(|node| (node.address(), node.known_latency().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let seeded = rank(simple_cellular::Queuing::Seeded(1165));
+        assert_eq!(rank(simple_cellular::Queuing::Seeded(1165)), seeded);
+        assert_ne!(rank(simple_cellular::Queuing::Seeded(1166)), seeded);
+        assert_eq!(
+            rank(simple_cellular::Queuing::Mean),
+            rank(simple_cellular::Queuing::Mean)
+        );
+    }
+
+    #[test]
+    fn test_parse_strategy() {
+        for strategy in [
+            "GeoDistance",
+            "SimpleCellular",
+            "SimpleCellular:deterministic",
+            "SimpleCellular:seed=42",
+            "SmartLatency",
+            "SmartLatency:window=4",
+            "Probed",
+        ] {
+            let parsed: NeighborNodeStrategy = strategy.parse().unwrap();
+            assert_eq!(parsed.to_string(), strategy);
+        }
+        assert!(matches!(
+            "SimpleCellular:seed=42".parse(),
+            Ok(NeighborNodeStrategy::SimpleCellular(
+                simple_cellular::Queuing::Seeded(42)
+            ))
+        ));
+        for invalid in [
+            "Cellular",
+            "SimpleCellular:seed=x",
+            "SimpleCellular:fast",
+            "GeoDistance:deterministic",
+            "SmartLatency:window=0",
+            "SmartLatency:alpha=0.2",
+        ] {
+            assert!(
+                invalid.parse::<NeighborNodeStrategy>().is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_compare_metric() {
+        let mut metrics = [3.0, f64::NAN, 1.0, f64::INFINITY, 2.0];
+        metrics.sort_by(|a, b| compare_metric(*a, *b));
+        assert_eq!(metrics[..4], [1.0, 2.0, 3.0, f64::INFINITY]);
+        assert!(metrics[4].is_nan());
+    }
+
+    #[test]
+    fn test_sort_with_nan() {
+        // A node without a valid position has a NaN distance, it must not break the sort
+        let mut list = NeighborNodeList::new(NeighborNodeStrategy::GeoDistance);
+        list.add_node("node3".to_string(), (35.6764, 139.650));
+        list.add_node("broken".to_string(), (f64::NAN, f64::NAN));
+        list.add_node("node1".to_string(), (48.8575, 2.3514));
+
+        list.sort_by_distance(&mut geo_distance::GeoDistance {
             position: (45.4685, 9.1824),
             address: "current".to_string(),
             emergency: false,
-            latency: 0.0,
-            last_update: std::time::Instant::now(),
         });
-        for node in list.nodes.iter_mut() {
-            // print latency
-            match node {
-                NeighborNodeType::Latency(node) => println!(
-                    "Latency: {}",
-                    node.latency(&mut simple_cellular::SimpleCellular {
-                        position: (45.4685, 9.1824),
-                        address: "current".to_string(),
-                        emergency: false,
-                        latency: 0.0,
-                        last_update: std::time::Instant::now(),
-                    })
-                ),
-                _ => (),
+        let addresses: Vec<String> = list.nodes.iter().map(|node| node.address()).collect();
+        assert_eq!(addresses, vec!["node1", "node3", "broken"]);
+
+        // Distance nodes have no latency, they go last instead of panicking
+        list.sort_by_latency(&mut probed::Probed::new((0.0, 0.0), "current".to_string()));
+        assert_eq!(list.nodes.len(), 3);
+    }
+
+    fn grid(threshold: usize) -> NeighborNodeList {
+        let mut list = NeighborNodeList::new(NeighborNodeStrategy::GeoDistance)
+            .with_index_threshold(threshold);
+        for i in 0..400 {
+            let j = (i * 7919) % 400;
+            let position = (40.0 + (j % 20) as f64 * 0.5, 5.0 + (j / 20) as f64 * 0.5);
+            list.add_node(format!("node{}", j), position);
+        }
+        list
+    }
+
+    fn addresses(nodes: &[NeighborNodeType]) -> Vec<String> {
+        nodes.iter().map(|node| node.address()).collect()
+    }
+
+    #[test]
+    fn test_nearest_k_indexed_and_linear() {
+        let linear = grid(usize::MAX);
+        // The index is built halfway and then updated incrementally
+        let indexed = grid(200);
+        assert!(!linear.is_indexed());
+        assert!(indexed.is_indexed());
+
+        let even = |node: &NeighborNodeType| node.address().ends_with(['0', '2', '4', '6', '8']);
+        for position in [(45.4685, 9.1824), (40.0, 5.0), (52.0, 20.0)] {
+            for k in [1, 10, 400] {
+                assert_eq!(
+                    addresses(&indexed.nearest_k(position, k, |_| true)),
+                    addresses(&linear.nearest_k(position, k, |_| true))
+                );
+                assert_eq!(
+                    addresses(&indexed.nearest_k(position, k, even)),
+                    addresses(&linear.nearest_k(position, k, even))
+                );
             }
         }
-        assert_eq!(list.nodes[0].address(), "node1");
+        assert_eq!(
+            linear.nearest_k((40.0, 5.0), 1, |_| true)[0].address(),
+            "node0"
+        );
+    }
+
+    #[test]
+    fn test_emergency_indexed() {
+        let emergency = Emergency {
+            position: (45.0, 7.5),
+            radius: 60_000.0,
+            polygon: None,
+        };
+        let mut linear = grid(usize::MAX);
+        let mut indexed = grid(0);
+        // Reordering the nodes must not confuse the index
+        indexed.sort(&mut geo_distance::GeoDistance::new(
+            (45.0, 7.5),
+            "current".to_string(),
+        ));
+        linear.set_emergency(emergency.clone());
+        indexed.set_emergency(emergency);
+
+        let in_emergency = |list: &NeighborNodeList| {
+            let mut nodes: Vec<String> = list
+                .nodes
+                .iter()
+                .filter(|node| node.emergency())
+                .map(|node| node.address())
+                .collect();
+            nodes.sort();
+            nodes
+        };
+        assert!(!in_emergency(&linear).is_empty());
+        assert_eq!(in_emergency(&indexed), in_emergency(&linear));
+
+        // A triangle with nodes on its vertices and edges, the index only narrows it down
+        let triangle = Emergency::polygon(vec![(44.0, 6.0), (44.0, 9.0), (47.0, 6.0)]).unwrap();
+        let mut linear = grid(usize::MAX);
+        let mut indexed = grid(0);
+        linear.set_emergency(triangle.clone());
+        indexed.set_emergency(triangle);
+        let inside = in_emergency(&linear);
+        assert_eq!(inside.len(), 28);
+        // The vertices, then a node on each edge
+        for node in ["node48", "node168", "node54", "node88", "node130", "node51"] {
+            assert!(inside.contains(&node.to_string()), "{}", node);
+        }
+        // Next to the long edge, and past a vertex
+        for node in ["node131", "node188"] {
+            assert!(!inside.contains(&node.to_string()), "{}", node);
+        }
+        assert_eq!(in_emergency(&indexed), inside);
+    }
+
+    #[test]
+    fn test_update_node() {
+        let mut list = grid(0);
+        let count = list.nodes.len();
+        let rank = addresses(&list.nodes)
+            .iter()
+            .position(|address| address == "node0")
+            .unwrap();
+        assert!(list.update_node("node0", "moved".to_string(), (40.0, 5.0)));
+        // The node keeps its rank, and is found at its new address only
+        assert_eq!(list.nodes.len(), count);
+        assert_eq!(list.nodes[rank].address(), "moved");
+        assert_eq!(
+            list.nearest_k((40.0, 5.0), 1, |_| true)[0].address(),
+            "moved"
+        );
+        assert!(!list.update_node("node0", "again".to_string(), (40.0, 5.0)));
+    }
+
+    #[actix_web::post("/invoke")]
+    async fn echo_encoding(
+        req: actix_web::HttpRequest,
+        data: web::Json<InvokeFunction>,
+    ) -> actix_web::HttpResponse {
+        // The hops are in the header, and in the body for the older nodes
+        assert_eq!(req.headers().get(HOPS_HEADER).unwrap(), "2");
+        assert_eq!(data.hops, 2);
+        let accepted = req.headers().get(header::ACCEPT_ENCODING).unwrap();
+        actix_web::HttpResponse::Ok().body(accepted.to_str().unwrap().repeat(1000))
+    }
+
+    #[actix_web::test]
+    async fn test_invoke_uncompressed() {
+        use actix_web::{middleware, App, HttpServer};
+
+        // A node that compresses its responses whenever the client accepts it
+        let server = HttpServer::new(|| {
+            App::new()
+                .wrap(middleware::Compress::default())
+                .service(echo_encoding)
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0].to_string();
+        actix_web::rt::spawn(server.run());
+
+        let mut list = NeighborNodeList::new(NeighborNodeStrategy::GeoDistance);
+        list.add_node(address, (0.0, 0.0));
+        let data: InvokeFunction = serde_json::from_value(serde_json::json!({
+            "function": "test",
+            "image": "image",
+            "vcpus": 1,
+            "memory": 128,
+            "payload": null,
+            "emergency": false,
+            "hops": 1,
+        }))
+        .unwrap();
+        let (body, _) = list.nodes[0]
+            .invoke(
+                &NodeClient::plain(),
+                "10.0.0.1:8085",
+                2,
+                None,
+                data.forwarded_body(),
+            )
+            .await
+            .unwrap();
+        // The forwarded output comes back as the function wrote it
+        assert_eq!(body, "identity".repeat(1000));
     }
 }
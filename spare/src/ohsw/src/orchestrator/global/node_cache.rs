@@ -0,0 +1,145 @@
+//! Last known list of nodes, kept in the data directory.
+//! A node that restarts while the control plane is down would wait for the list of its
+//! neighbors forever, even though it knew them a minute ago. The list, with the identity
+//! of the node, is written every time it changes, so that the node can start from it
+//! and reconcile it with the list of the control plane once it answers again.
+use std::{fs, io, path::Path};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::identity::Node;
+
+/// Name of the file, in the data directory, holding the last known list of nodes
+pub const NODE_CACHE_FILE: &str = "nodes.json";
+
+/// Last known list of nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeCache {
+    /// The node itself, with its position
+    pub identity: Node,
+    /// Its neighbors
+    pub nodes: Vec<Node>,
+    pub saved_at: DateTime<Utc>,
+}
+
+/// Where the list of the neighbors comes from
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum TopologySource {
+    /// The control plane, and the announces since
+    ControlPlane,
+    /// The last known list, as saved at `saved_at`, while the control plane is down
+    Cache { saved_at: DateTime<Utc> },
+}
+
+impl TopologySource {
+    /// Whether the list may be out of date, as it was not confirmed by the control plane
+    pub fn is_stale(&self) -> bool {
+        matches!(self, TopologySource::Cache { .. })
+    }
+}
+
+impl NodeCache {
+    /// Create a cache of the given list, saved now
+    pub fn new(identity: Node, nodes: Vec<Node>) -> Self {
+        Self {
+            identity,
+            nodes,
+            saved_at: Utc::now(),
+        }
+    }
+
+    /// Write the list in `dir`. The file is replaced at once, a node stopped while
+    /// writing it keeps the previous list.
+    pub fn save(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(NODE_CACHE_FILE);
+        let partial = path.with_extension("json.tmp");
+        fs::write(&partial, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&partial, &path)
+    }
+
+    /// Read the list saved in `dir`
+    /// # Returns
+    /// * None if no list was saved
+    pub fn load(dir: &Path) -> io::Result<Option<Self>> {
+        let path = dir.join(NODE_CACHE_FILE);
+        match fs::read(&path) {
+            Ok(raw) => serde_json::from_slice(&raw).map(Some).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid list of nodes in {}: {e}", path.display()),
+                )
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_save_load() {
+        let dir = std::env::temp_dir().join(format!("spare-nodes-{}", Uuid::new_v4()));
+        assert!(NodeCache::load(&dir).unwrap().is_none());
+
+        let identity =
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)).with_id("self".to_string());
+        let cache = NodeCache::new(
+            identity,
+            vec![
+                Node::new("10.0.0.1:8085".to_string(), (45.4642, 9.1900)).with_id("a".to_string()),
+                Node::new("10.0.0.2:8085".to_string(), (48.8575, 2.3514))
+                    .with_capabilities(vec!["gpu".to_string()]),
+            ],
+        );
+        cache.save(&dir).unwrap();
+        let loaded = NodeCache::load(&dir).unwrap().unwrap();
+        assert_eq!(loaded.saved_at, cache.saved_at);
+        assert_eq!(loaded.identity.id, "self");
+        assert_eq!(loaded.identity.position, Some((45.4685, 9.1824)));
+        let nodes: Vec<_> = loaded
+            .nodes
+            .iter()
+            .map(|n| (n.key(), n.capabilities.clone()))
+            .collect();
+        assert_eq!(
+            nodes,
+            vec![("a", vec![]), ("10.0.0.2:8085", vec!["gpu".to_string()])]
+        );
+
+        // A newer list replaces it
+        NodeCache::new(loaded.identity, vec![]).save(&dir).unwrap();
+        assert!(NodeCache::load(&dir).unwrap().unwrap().nodes.is_empty());
+        assert!(!dir.join("nodes.json.tmp").exists());
+
+        fs::write(dir.join(NODE_CACHE_FILE), "{").unwrap();
+        assert_eq!(
+            NodeCache::load(&dir).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_topology_source() {
+        assert!(!TopologySource::ControlPlane.is_stale());
+        let saved_at = Utc::now();
+        let cached = TopologySource::Cache { saved_at };
+        assert!(cached.is_stale());
+        assert_eq!(
+            serde_json::to_value(&cached).unwrap(),
+            serde_json::json!({"source": "cache", "saved_at": saved_at})
+        );
+        assert_eq!(
+            serde_json::to_value(TopologySource::ControlPlane).unwrap(),
+            serde_json::json!({"source": "control_plane"})
+        );
+    }
+}
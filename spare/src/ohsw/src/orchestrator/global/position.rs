@@ -0,0 +1,223 @@
+//! Position of a mobile node, e.g. a vehicle.
+//! The position of the node is read periodically from a provider. When it changes the
+//! node ranks its neighbors again from there and broadcasts UPDATE_POSITION, so the other
+//! nodes rank it right and know whether it drove into an emergency zone.
+use std::{future::Future, io, path::PathBuf, sync::Arc, time::Duration};
+
+use log::{info, warn};
+
+use crate::{
+    net::{
+        control_plane::Announcer,
+        iggy::{Message, Operation, Payload, PositionUpdate},
+    },
+    orchestrator::Orchestrator,
+};
+
+/// Something able to tell where the node is
+pub trait PositionProvider {
+    /// Get the current position of the node
+    fn position(&self) -> impl Future<Output = io::Result<(f64, f64)>>;
+}
+
+/// Provider reading the position from a file, kept up to date by e.g. a GPS daemon.
+/// The file holds the two coordinates of the position, as `45.4685,9.1824`.
+pub struct FilePosition {
+    path: PathBuf,
+}
+
+impl FilePosition {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl PositionProvider for FilePosition {
+    async fn position(&self) -> io::Result<(f64, f64)> {
+        let content = std::fs::read_to_string(&self.path)?;
+        parse_position(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Parse a position given as `x,y`
+pub fn parse_position(s: &str) -> Result<(f64, f64), String> {
+    let invalid = || format!("Invalid position: {}, expected x,y", s.trim());
+    let (x, y) = s.trim().split_once(',').ok_or_else(invalid)?;
+    let coordinate = |value: &str| {
+        value
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|value| value.is_finite())
+            .ok_or_else(invalid)
+    };
+    Ok((coordinate(x)?, coordinate(y)?))
+}
+
+/// Periodically reads the position of the node and tells the orchestrator and the other
+/// nodes when it changes
+pub struct PositionTracker<P> {
+    provider: P,
+    /// Time between two readings of the position
+    interval: Duration,
+}
+
+impl<P: PositionProvider> PositionTracker<P> {
+    pub fn new(provider: P, interval: Duration) -> Self {
+        Self { provider, interval }
+    }
+
+    /// Read the position once and move the node if it changed.
+    /// Returns the announcement of the new position, if any.
+    pub async fn track_once(&self, orchestrator: &Orchestrator) -> Option<Message> {
+        let position = match self.provider.position().await {
+            Ok(position) => position,
+            Err(e) => {
+                warn!("Cannot read the position of the node: {}", e);
+                return None;
+            }
+        };
+        let identity = orchestrator.get_identity();
+        if identity.position == Some(position) {
+            return None;
+        }
+        info!("Node moved from {:?} to {:?}", identity.position, position);
+        orchestrator.update_own_position(position);
+        Some(Message::new(
+            Operation::UPDATE_POSITION,
+            Some(Payload::Position(PositionUpdate {
+                node_id: identity.key().to_string(),
+                position,
+            })),
+        ))
+    }
+
+    /// Track the position forever, every `interval`
+    pub async fn run(self, orchestrator: Arc<Orchestrator>, announcer: Announcer) {
+        info!(
+            "Reading the position of the node every {} ms",
+            self.interval.as_millis()
+        );
+        loop {
+            if let Some(message) = self.track_once(&orchestrator).await {
+                announcer.announce(message);
+            }
+            actix_web::rt::time::sleep(self.interval).await;
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orchestrator::global::{emergency::Emergency, identity::Node, NeighborNode};
+    use std::sync::Mutex;
+
+    /// Provider following a route, one position per reading
+    struct Route(Mutex<Vec<(f64, f64)>>);
+
+    impl PositionProvider for Route {
+        async fn position(&self) -> io::Result<(f64, f64)> {
+            let mut route = self.0.lock().unwrap();
+            match route.len() {
+                0 => Err(io::Error::other("No fix")),
+                1 => Ok(route[0]),
+                _ => Ok(route.remove(0)),
+            }
+        }
+    }
+
+    fn orchestrator() -> Orchestrator {
+        let nodes = vec![
+            Node::new("10.0.0.1:8085".to_string(), (45.4642, 9.1900)).with_id("a".to_string()),
+            Node::new("10.0.0.2:8085".to_string(), (48.8575, 2.3514)).with_id("b".to_string()),
+        ];
+        Orchestrator::new(
+            nodes,
+            Node::new("10.0.0.0:8085".to_string(), (48.8600, 2.3500)).with_id("self".to_string()),
+        )
+    }
+
+    fn candidates(orchestrator: &Orchestrator) -> Vec<String> {
+        orchestrator
+            .offload_candidates(None)
+            .iter()
+            .map(|node| node.address())
+            .collect()
+    }
+
+    // An emergency in Milan, the first neighbor is in its zone
+    const EMERGENCY: Emergency = Emergency {
+        position: (45.4642, 9.1900),
+        radius: 10_000.0,
+        polygon: None,
+    };
+
+    #[test]
+    fn test_parse_position() {
+        assert_eq!(parse_position("45.4685,9.1824\n"), Ok((45.4685, 9.1824)));
+        assert_eq!(parse_position(" -1.5 , 2 "), Ok((-1.5, 2.0)));
+        assert!(parse_position("45.4685").is_err());
+        assert!(parse_position("north,9.1824").is_err());
+        assert!(parse_position("NaN,9.1824").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_own_position() {
+        let orchestrator = orchestrator();
+        orchestrator.set_emergency(true, EMERGENCY);
+        assert!(!orchestrator.in_emergency_area());
+        // From Paris, the node drives into the emergency zone in Milan, then leaves it
+        let route = Route(Mutex::new(vec![
+            (45.4700, 9.1850),
+            (45.4700, 9.1850),
+            (46.0, 9.5),
+        ]));
+        let tracker = PositionTracker::new(route, Duration::from_millis(10));
+
+        let message = tracker.track_once(&orchestrator).await.unwrap();
+        assert!(orchestrator.in_emergency_area());
+        assert_eq!(
+            orchestrator.get_identity().position,
+            Some((45.4700, 9.1850))
+        );
+        match message.payload {
+            Some(Payload::Position(update)) => {
+                assert_eq!(update.node_id, "self");
+                assert_eq!(update.position, (45.4700, 9.1850));
+            }
+            _ => panic!("The new position is announced"),
+        }
+        // Standing still is not announced
+        assert!(tracker.track_once(&orchestrator).await.is_none());
+        assert!(tracker.track_once(&orchestrator).await.is_some());
+        assert!(!orchestrator.in_emergency_area());
+        // Without a fix the node stays where it is
+        tracker.provider.0.lock().unwrap().clear();
+        assert!(tracker.track_once(&orchestrator).await.is_none());
+        assert_eq!(orchestrator.get_identity().position, Some((46.0, 9.5)));
+    }
+
+    #[test]
+    fn test_neighbor_position() {
+        let orchestrator = orchestrator();
+        orchestrator.set_emergency(true, EMERGENCY);
+        assert_eq!(candidates(&orchestrator), vec!["10.0.0.2:8085"]);
+
+        // The second neighbor drives into the emergency zone
+        assert!(orchestrator.update_neighbor_position("b", (45.4650, 9.1890)));
+        assert!(candidates(&orchestrator).is_empty());
+        // The first one leaves it, and is ranked from its new position
+        assert!(orchestrator.update_neighbor_position("a", (48.8500, 2.3400)));
+        assert_eq!(candidates(&orchestrator), vec!["10.0.0.1:8085"]);
+
+        assert!(!orchestrator.update_neighbor_position("z", (0.0, 0.0)));
+        // Once the emergency is over, the nodes are ranked by distance only
+        orchestrator.set_emergency(false, EMERGENCY);
+        assert_eq!(
+            candidates(&orchestrator),
+            vec!["10.0.0.1:8085", "10.0.0.2:8085"]
+        );
+    }
+}
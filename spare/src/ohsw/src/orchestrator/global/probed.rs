@@ -0,0 +1,298 @@
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration, time::Instant};
+
+use log::{info, warn};
+use longitude::Location;
+
+use super::{NeighborNode, NeighborNodeWithLatency};
+use crate::{net::tls::NodeClient, orchestrator::Orchestrator};
+
+/// Neighbour Node Selection strategy in which the latency is measured
+/// by periodically probing the nodes, instead of being estimated.
+#[derive(Clone)]
+pub struct Probed {
+    pub position: (f64, f64), // Longitude and Latitude
+    pub address: String,
+    pub emergency: bool,
+    pub latency: f64, // Smoothed latency (ms), f64::MAX until the first probe
+}
+
+impl Probed {
+    pub fn new(position: (f64, f64), address: String) -> Self {
+        Self {
+            position,
+            address,
+            emergency: false,
+            latency: f64::MAX,
+        }
+    }
+}
+
+impl NeighborNode for Probed {
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    fn position(&self) -> (f64, f64) {
+        self.position
+    }
+
+    fn emergency(&self) -> bool {
+        self.emergency
+    }
+
+    fn set_emergency(&mut self, emergency: bool) {
+        self.emergency = emergency;
+    }
+
+    fn set_position(&mut self, position: (f64, f64)) {
+        self.position = position;
+    }
+}
+
+impl super::Distance for Probed {
+    fn distance(&self, node: &mut dyn NeighborNode) -> f64 {
+        let location_a = Location::from(self.position.0, self.position.1);
+        let location_b = Location::from(node.position().0, node.position().1);
+        location_a.distance(&location_b).meters()
+    }
+}
+
+impl super::Latency for Probed {
+    fn latency(&mut self, _node: &mut dyn NeighborNodeWithLatency) -> f64 {
+        self.latency
+    }
+
+    /// The latency is already smoothed by the `LatencyProbe`
+    fn update_latency(&mut self, new_latency: f64) {
+        self.latency = new_latency;
+    }
+
+    fn last_latency(&self) -> f64 {
+        self.latency
+    }
+}
+
+/// Configuration of the latency probes
+#[derive(Clone, Copy, Debug)]
+pub struct ProbeConfig {
+    /// Time between two rounds of probes
+    pub interval: Duration,
+    /// Weight of a new sample in the moving average, in (0, 1]
+    pub weight: f64,
+    /// Time after which a probe is considered failed
+    pub timeout: Duration,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            weight: 0.2,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Something able to measure the round trip time to a node
+pub trait Prober {
+    /// Measure the round trip time to `address`, None if the node did not answer in time
+    fn probe(&self, address: &str, timeout: Duration) -> impl Future<Output = Option<Duration>>;
+}
+
+/// Prober that times a GET request to the index endpoint of the node
+#[derive(Clone, Default)]
+pub struct HttpProber {
+    client: NodeClient,
+}
+
+impl HttpProber {
+    /// Create a prober calling the nodes with the given clients
+    pub fn new(client: NodeClient) -> Self {
+        Self { client }
+    }
+}
+
+impl Prober for HttpProber {
+    async fn probe(&self, address: &str, timeout: Duration) -> Option<Duration> {
+        let start = Instant::now();
+        self.client
+            .client()
+            .get(self.client.url(address, "/"))
+            .timeout(timeout)
+            .send()
+            .await
+            .ok()
+            .map(|_| start.elapsed())
+    }
+}
+
+/// Periodically probes the neighbor nodes and keeps an exponentially weighted
+/// moving average of their latency, which is pushed to the orchestrator.
+pub struct LatencyProbe<P> {
+    prober: P,
+    config: ProbeConfig,
+    /// Smoothed latency (ms) of each node
+    estimates: HashMap<String, f64>,
+}
+
+impl<P: Prober> LatencyProbe<P> {
+    pub fn new(prober: P, config: ProbeConfig) -> Self {
+        Self {
+            prober,
+            config,
+            estimates: HashMap::new(),
+        }
+    }
+
+    /// Get the smoothed latency of a node, if it was probed
+    pub fn estimate(&self, address: &str) -> Option<f64> {
+        self.estimates.get(address).copied()
+    }
+
+    /// Add a sample (ms) to the moving average of a node and return the new average
+    fn add_sample(&mut self, address: &str, sample: f64) -> f64 {
+        let weight = self.config.weight;
+        let estimate = self
+            .estimates
+            .entry(address.to_string())
+            .and_modify(|estimate| *estimate = weight * sample + (1.0 - weight) * *estimate)
+            .or_insert(sample);
+        *estimate
+    }
+
+    /// Probe once all the nodes that are not in the emergency area.
+    /// A node that does not answer is accounted with the timeout as its latency.
+    pub async fn probe_round(&mut self, orchestrator: &Orchestrator) {
+        let mut latencies = Vec::new();
+        for address in orchestrator.probe_targets() {
+            let sample = match self.prober.probe(&address, self.config.timeout).await {
+                Some(rtt) => rtt,
+                None => {
+                    warn!("Probe to {} failed", address);
+                    self.config.timeout
+                }
+            };
+            let latency = self.add_sample(&address, sample.as_secs_f64() * 1000.0);
+            latencies.push((address, latency));
+        }
+        orchestrator.update_latencies(&latencies);
+    }
+
+    /// Probe the nodes forever, every `interval`
+    pub async fn run(mut self, orchestrator: Arc<Orchestrator>) {
+        info!(
+            "Probing the neighbor nodes every {} ms",
+            self.config.interval.as_millis()
+        );
+        loop {
+            self.probe_round(&orchestrator).await;
+            actix_web::rt::time::sleep(self.config.interval).await;
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orchestrator::global::{emergency::Emergency, identity::Node};
+    use std::sync::Mutex;
+
+    /// Prober that answers with fixed round trip times and records the probed nodes
+    #[derive(Default)]
+    struct FakeProber {
+        rtts: HashMap<String, u64>,
+        probed: Mutex<Vec<String>>,
+    }
+
+    impl Prober for FakeProber {
+        async fn probe(&self, address: &str, _timeout: Duration) -> Option<Duration> {
+            self.probed.lock().unwrap().push(address.to_string());
+            self.rtts.get(address).copied().map(Duration::from_millis)
+        }
+    }
+
+    fn fake_prober(rtts: &[(&str, u64)]) -> FakeProber {
+        FakeProber {
+            rtts: rtts.iter().map(|(a, r)| (a.to_string(), *r)).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn config() -> ProbeConfig {
+        ProbeConfig {
+            interval: Duration::from_millis(10),
+            weight: 0.5,
+            timeout: Duration::from_millis(100),
+        }
+    }
+
+    fn orchestrator() -> Orchestrator {
+        let nodes = vec![
+            Node::new("10.0.0.1:8085".to_string(), (45.4642, 9.1900)),
+            Node::new("10.0.0.2:8085".to_string(), (48.8575, 2.3514)),
+            Node::new("10.0.0.3:8085".to_string(), (40.7128, 74.0060)),
+        ];
+        Orchestrator::with_strategy(
+            nodes,
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+            super::super::NeighborNodeStrategy::Probed,
+        )
+    }
+
+    fn order(orchestrator: &Orchestrator) -> Vec<String> {
+        orchestrator
+            .offload_candidates(None)
+            .iter()
+            .map(|node| node.address())
+            .collect()
+    }
+
+    #[test]
+    fn test_moving_average() {
+        let mut probe = LatencyProbe::new(fake_prober(&[]), config());
+        assert_eq!(probe.estimate("node"), None);
+        assert_eq!(probe.add_sample("node", 10.0), 10.0);
+        assert_eq!(probe.add_sample("node", 20.0), 15.0);
+        assert_eq!(probe.add_sample("node", 5.0), 10.0);
+        assert_eq!(probe.estimate("node"), Some(10.0));
+    }
+
+    #[actix_web::test]
+    async fn test_probe_round() {
+        let orchestrator = orchestrator();
+        // The third node never answers, so it gets the timeout as latency
+        let prober = fake_prober(&[("10.0.0.1:8085", 40), ("10.0.0.2:8085", 5)]);
+        let mut probe = LatencyProbe::new(prober, config());
+        probe.probe_round(&orchestrator).await;
+
+        assert_eq!(probe.estimate("10.0.0.3:8085"), Some(100.0));
+        assert_eq!(
+            order(&orchestrator),
+            vec!["10.0.0.2:8085", "10.0.0.1:8085", "10.0.0.3:8085"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_probes_skip_emergency() {
+        let orchestrator = orchestrator();
+        orchestrator.set_emergency(
+            true,
+            Emergency {
+                position: (45.4642, 9.1900),
+                radius: 100.0,
+                polygon: None,
+            },
+        );
+        let prober = fake_prober(&[("10.0.0.2:8085", 5), ("10.0.0.3:8085", 1)]);
+        let mut probe = LatencyProbe::new(prober, config());
+        probe.probe_round(&orchestrator).await;
+
+        assert_eq!(
+            *probe.prober.probed.lock().unwrap(),
+            vec!["10.0.0.2:8085", "10.0.0.3:8085"]
+        );
+        assert_eq!(probe.estimate("10.0.0.1:8085"), None);
+        assert_eq!(order(&orchestrator), vec!["10.0.0.3:8085", "10.0.0.2:8085"]);
+    }
+}
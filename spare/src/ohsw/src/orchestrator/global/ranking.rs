@@ -0,0 +1,186 @@
+//! Ranking of the neighbors, as the offloads try them.
+//! The latencies of some strategies are estimated again from time to time, e.g. with a
+//! random queuing delay for SimpleCellular, so the ranking can change between two
+//! requests with nothing moving. The ranking is served on /nodes/ranking and logged
+//! every time the neighbors are sorted again, with the metric each one was ranked by.
+use serde::{Deserialize, Serialize};
+
+use super::{NeighborNode, NeighborNodeType};
+
+/// Metric a neighbor is ranked by
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RankMetric {
+    /// Distance from the node (in meters)
+    Distance,
+    /// Latency last measured or estimated (in ms)
+    Latency,
+}
+
+/// Why no request is offloaded to a neighbor
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Exclusion {
+    /// The neighbor is in the emergency area
+    Emergency,
+    /// The neighbor announced it is draining
+    Draining,
+}
+
+/// A neighbor in the ranking
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RankedNode {
+    pub address: String,
+    pub metric: RankMetric,
+    /// Value of the metric, None while it is unknown, e.g. before the first probe
+    pub value: Option<f64>,
+    /// Time since the latency was estimated (in ms), None if it is not cached
+    pub age_ms: Option<u64>,
+    /// Number of samples the latency was estimated from, None if it is not estimated
+    /// from samples
+    #[serde(default)]
+    pub samples: Option<usize>,
+    /// Why the neighbor is skipped by the offloads, None if it is not
+    pub excluded: Option<Exclusion>,
+}
+
+impl RankedNode {
+    /// Rank a neighbor, reading the metric without estimating it again
+    /// # Arguments
+    /// * `node` - The neighbor
+    /// * `position` - Position of the node ranking it
+    /// * `excluded` - Why the neighbor is skipped, if it is
+    pub fn new(node: &NeighborNodeType, position: (f64, f64), excluded: Option<Exclusion>) -> Self {
+        let (metric, value) = node.rank_metric(position);
+        Self {
+            address: node.address(),
+            metric,
+            value,
+            age_ms: node.latency_age().map(|age| age.as_millis() as u64),
+            samples: node.sample_count(),
+            excluded,
+        }
+    }
+}
+
+/// The neighbors in the order the offloads try them, then the excluded ones
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ranking {
+    pub strategy: String,
+    /// Whether the neighbors are ranked away from the emergency, rather than by metric
+    pub emergency_aware: bool,
+    pub nodes: Vec<RankedNode>,
+}
+
+/// Compact form of a ranking, for the logs, e.g. `10.0.0.1:8085=0.52ms 10.0.0.2:8085=?`
+pub fn line(nodes: &[RankedNode]) -> String {
+    let nodes: Vec<_> = nodes
+        .iter()
+        .map(|node| {
+            let value = match (node.metric, node.value) {
+                (RankMetric::Distance, Some(value)) => format!("{value:.0}m"),
+                (RankMetric::Latency, Some(value)) => format!("{value:.2}ms"),
+                (_, None) => "?".to_string(),
+            };
+            match node.excluded {
+                Some(excluded) => format!("{}={value}({excluded:?})", node.address),
+                None => format!("{}={value}", node.address),
+            }
+        })
+        .collect();
+    nodes.join(" ")
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orchestrator::global::{
+        geo_distance::GeoDistance, simple_cellular::SimpleCellular, smart_latency::SmartLatency,
+        Latency,
+    };
+
+    #[test]
+    fn test_ranked_node() {
+        let near = NeighborNodeType::Distance(Box::new(GeoDistance::new(
+            (45.4642, 9.1900),
+            "10.0.0.1:8085".to_string(),
+        )));
+        let ranked = RankedNode::new(&near, (45.4685, 9.1824), None);
+        assert_eq!(ranked.metric, RankMetric::Distance);
+        assert!(ranked
+            .value
+            .is_some_and(|meters| meters > 500.0 && meters < 1500.0));
+        assert_eq!(ranked.age_ms, None);
+
+        // A latency never estimated is unknown, reading it does not estimate it
+        let cellular = NeighborNodeType::Latency(Box::new(SimpleCellular::new(
+            (45.4642, 9.1900),
+            "10.0.0.2:8085".to_string(),
+        )));
+        let ranked = RankedNode::new(&cellular, (45.4685, 9.1824), Some(Exclusion::Draining));
+        assert_eq!((ranked.metric, ranked.value), (RankMetric::Latency, None));
+        assert_eq!(ranked.age_ms, None);
+        assert_eq!(cellular.known_latency(), None);
+
+        // The latency of SmartLatency comes with the number of samples it is made of
+        let mut smart = SmartLatency::new((45.4642, 9.1900), "10.0.0.3:8085".to_string());
+        smart.update_latency(12.0);
+        smart.update_latency(14.0);
+        let ranked = RankedNode::new(
+            &NeighborNodeType::Latency(Box::new(smart)),
+            (45.4685, 9.1824),
+            None,
+        );
+        assert_eq!((ranked.value, ranked.samples), (Some(13.0), Some(2)));
+        assert_eq!(
+            RankedNode::new(&cellular, (45.4685, 9.1824), None).samples,
+            None
+        );
+    }
+
+    #[test]
+    fn test_serialize() {
+        let ranking = Ranking {
+            strategy: "SimpleCellular".to_string(),
+            emergency_aware: false,
+            nodes: vec![
+                RankedNode {
+                    address: "10.0.0.1:8085".to_string(),
+                    metric: RankMetric::Latency,
+                    value: Some(0.5213),
+                    age_ms: Some(1200),
+                    samples: None,
+                    excluded: None,
+                },
+                RankedNode {
+                    address: "10.0.0.2:8085".to_string(),
+                    metric: RankMetric::Latency,
+                    value: None,
+                    age_ms: None,
+                    samples: Some(0),
+                    excluded: Some(Exclusion::Emergency),
+                },
+            ],
+        };
+        let json = serde_json::to_value(&ranking).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "strategy": "SimpleCellular",
+                "emergency_aware": false,
+                "nodes": [
+                    {"address": "10.0.0.1:8085", "metric": "latency", "value": 0.5213,
+                        "age_ms": 1200, "samples": null, "excluded": null},
+                    {"address": "10.0.0.2:8085", "metric": "latency", "value": null,
+                        "age_ms": null, "samples": 0, "excluded": "emergency"},
+                ]
+            })
+        );
+        assert_eq!(serde_json::from_value::<Ranking>(json).unwrap(), ranking);
+        assert_eq!(
+            line(&ranking.nodes),
+            "10.0.0.1:8085=0.52ms 10.0.0.2:8085=?(Emergency)"
+        );
+    }
+}
@@ -1,11 +1,27 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use longitude::Location;
-use rand::thread_rng;
+use rand::{rngs::StdRng, SeedableRng};
 use rand_distr::{Distribution, Exp};
 
 use super::{NeighborNode, NeighborNodeWithLatency};
 
+/// Mean of the queuing delay of a hop (in s)
+const QUEUING_DELAY_MEAN: f64 = 0.0005;
+
+/// How the queuing delays of the latency model are drawn
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Queuing {
+    /// Sampled at every estimate, two runs rank the neighbors differently
+    #[default]
+    Sampled,
+    /// Replaced by their mean, the latencies only depend on the distances
+    Mean,
+    /// Sampled from a generator seeded with the seed and the address of each neighbor,
+    /// two runs with the same seed estimate the same latencies
+    Seeded(u64),
+}
+
 /// Neighbour Node Selection strategy in which the distance
 /// is calculated with a simple model that describes the
 /// latency between two nodes connected using a cellular network.
@@ -17,6 +33,9 @@ pub struct SimpleCellular {
     pub emergency: bool,
     pub latency: f64,
     pub last_update: Instant, // Last time the node was updated
+    pub queuing: Queuing,
+    /// Generator of the queuing delays, with `Queuing::Seeded`
+    pub rng: Option<StdRng>,
 }
 impl NeighborNode for SimpleCellular {
     fn address(&self) -> String {
@@ -34,6 +53,12 @@ impl NeighborNode for SimpleCellular {
     fn set_emergency(&mut self, emergency: bool) {
         self.emergency = emergency;
     }
+
+    fn set_position(&mut self, position: (f64, f64)) {
+        self.position = position;
+        // The latency is estimated from the distance, estimate it again
+        self.latency = 0.0;
+    }
 }
 impl super::Distance for SimpleCellular {
     fn distance(&self, node: &mut dyn NeighborNode) -> f64 {
@@ -45,7 +70,7 @@ impl super::Distance for SimpleCellular {
 }
 impl super::Latency for SimpleCellular {
     fn latency(&mut self, node: &mut dyn NeighborNodeWithLatency) -> f64 {
-        if self.latency == 0.0 || self.last_update.elapsed().as_secs() > 60 {
+        if self.latency == 0.0 || self.last_update.elapsed() > super::LATENCY_ESTIMATE_TTL {
             self.last_update = Instant::now();
             self.estimate_latency(node);
         }
@@ -57,6 +82,14 @@ impl super::Latency for SimpleCellular {
         // Here do nothing, the latency is estimated
         // using the estimate_latency function
     }
+
+    fn last_latency(&self) -> f64 {
+        self.latency
+    }
+
+    fn latency_age(&self) -> Option<Duration> {
+        (self.latency != 0.0).then(|| self.last_update.elapsed())
+    }
 }
 impl SimpleCellular {
     /// Create a new SimpleCellular
@@ -72,6 +105,36 @@ impl SimpleCellular {
             emergency: false,
             latency: 0.0,
             last_update: Instant::now(),
+            queuing: Queuing::Sampled,
+            rng: None,
+        }
+    }
+
+    /// Set how the queuing delays are drawn
+    pub fn with_queuing(self, queuing: Queuing) -> Self {
+        let rng = match queuing {
+            Queuing::Seeded(seed) => {
+                // Hashed by hand, the hashers of std may change between releases
+                let address = self.address.bytes().fold(0u64, |hash, byte| {
+                    hash.wrapping_mul(31).wrapping_add(byte as u64)
+                });
+                Some(StdRng::seed_from_u64(seed ^ address))
+            }
+            _ => None,
+        };
+        Self {
+            queuing,
+            rng,
+            ..self
+        }
+    }
+
+    /// Draw the queuing delay of a hop (in s)
+    fn queuing_delay(&mut self, distribution: &Exp<f64>) -> f64 {
+        match (self.queuing, self.rng.as_mut()) {
+            (Queuing::Mean, _) => QUEUING_DELAY_MEAN,
+            (Queuing::Seeded(_), Some(rng)) => distribution.sample(rng),
+            _ => distribution.sample(&mut rand::rng()),
         }
     }
 
@@ -129,20 +192,20 @@ impl SimpleCellular {
         let transmission_delay_backhaul = PACKET_SIZE / BACKHAUL_BANDWIDTH; // Transmission delay in backhaul network
 
         // **Queuing delay model**
-        let exp_distribution = Exp::new(1.0 / 0.0005).unwrap(); // Exponential distribution for queuing delay
+        let exp_distribution = Exp::new(1.0 / QUEUING_DELAY_MEAN).unwrap(); // Exponential distribution for queuing delay
 
         // Compute total latency
         self.latency = propagation_delay_access * access_hops as f64; // Start with access propagation delay
                                                                       // Add access network delays (queuing + transmission)
         for _ in 0..access_hops {
-            let queuing_delay = exp_distribution.sample(&mut thread_rng());
+            let queuing_delay = self.queuing_delay(&exp_distribution);
             self.latency += queuing_delay + transmission_delay_access;
         }
 
         // Add backhaul network delays (propagation + queuing + transmission)
         self.latency += propagation_delay_backhaul * backhaul_hops as f64;
         for _ in 0..backhaul_hops {
-            let queuing_delay = exp_distribution.sample(&mut thread_rng());
+            let queuing_delay = self.queuing_delay(&exp_distribution);
             self.latency += queuing_delay + transmission_delay_backhaul;
         }
     }
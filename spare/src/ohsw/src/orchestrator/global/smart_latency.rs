@@ -1,18 +1,30 @@
-use log::error;
+use std::collections::VecDeque;
+
+use log::debug;
 use longitude::Location;
-use rand::thread_rng;
 
 use super::{NeighborNode, NeighborNodeWithLatency};
 
+/// Number of samples the latency is estimated from, by default
+pub const DEFAULT_WINDOW: usize = 16;
+/// A sample more than this many times the median of the window is left out of the
+/// estimate, e.g. a request that timed out
+pub const OUTLIER_FACTOR: f64 = 3.0;
+
 /// Neighbour Node Selection strategy in which latency is estimated
-/// and updated over time using a running average.
+/// and updated over time from the last samples.
+/// The estimate is the mean of the last `window` samples, leaving out the outliers, so a
+/// node that was slow a while ago, e.g. during an emergency, recovers once it is fast
+/// again and a single timeout does not skew it.
 #[derive(Clone)]
 pub struct SmartLatency {
     pub position: (f64, f64), // Longitude and Latitude
     pub address: String,
     pub emergency: bool,
-    pub latency: f64,        // Average latency
-    pub sample_count: usize, // How many samples were considered
+    pub latency: f64, // Estimated latency (ms), f64::MAX until the first sample
+    pub sample_count: usize, // How many samples were taken since the node was added
+    samples: VecDeque<f64>, // The last samples, the oldest first
+    window: usize,
 }
 
 impl SmartLatency {
@@ -23,8 +35,36 @@ impl SmartLatency {
             emergency: false,
             latency: f64::MAX,
             sample_count: 0,
+            samples: VecDeque::with_capacity(DEFAULT_WINDOW),
+            window: DEFAULT_WINDOW,
         }
     }
+
+    /// Estimate the latency from the last `window` samples, at least one
+    pub fn with_window(self, window: usize) -> Self {
+        let window = window.max(1);
+        Self {
+            samples: VecDeque::with_capacity(window),
+            window,
+            ..self
+        }
+    }
+
+    /// Get the mean of the samples, leaving out the outliers
+    fn estimate(&self) -> f64 {
+        let mut sorted: Vec<f64> = self.samples.iter().copied().collect();
+        sorted.sort_by(f64::total_cmp);
+        let middle = sorted.len() / 2;
+        let median = match sorted.len() % 2 {
+            0 => (sorted[middle - 1] + sorted[middle]) / 2.0,
+            _ => sorted[middle],
+        };
+        let kept: Vec<f64> = sorted
+            .into_iter()
+            .filter(|sample| *sample <= median * OUTLIER_FACTOR)
+            .collect();
+        kept.iter().sum::<f64>() / kept.len() as f64
+    }
 }
 
 impl NeighborNode for SmartLatency {
@@ -43,6 +83,10 @@ impl NeighborNode for SmartLatency {
     fn set_emergency(&mut self, emergency: bool) {
         self.emergency = emergency;
     }
+
+    fn set_position(&mut self, position: (f64, f64)) {
+        self.position = position;
+    }
 }
 
 impl super::Distance for SmartLatency {
@@ -58,11 +102,67 @@ impl super::Latency for SmartLatency {
         self.latency
     }
     fn update_latency(&mut self, new_latency: f64) {
-        if self.sample_count == 0 {
-            self.latency = 0.0;
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
         }
+        self.samples.push_back(new_latency);
         self.sample_count += 1;
-        self.latency += (new_latency - self.latency) / self.sample_count as f64;
-        error!("Updated latency: {}", self.latency);
+        self.latency = self.estimate();
+        debug!("Updated latency of {}: {}", self.address, self.latency);
+    }
+    fn last_latency(&self) -> f64 {
+        self.latency
+    }
+    fn sample_count(&self) -> Option<usize> {
+        Some(self.sample_count)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orchestrator::global::Latency;
+
+    fn node(window: usize) -> SmartLatency {
+        SmartLatency::new((45.4642, 9.1900), "10.0.0.1:8085".to_string()).with_window(window)
+    }
+
+    #[test]
+    fn test_recovery() {
+        let mut node = node(8);
+        assert_eq!(node.last_latency(), f64::MAX);
+        // Slow during an emergency
+        for _ in 0..20 {
+            node.update_latency(500.0);
+        }
+        assert_eq!(node.last_latency(), 500.0);
+        // Fast again, the slow samples leave the window
+        for _ in 0..4 {
+            node.update_latency(10.0);
+        }
+        assert_eq!(node.last_latency(), 255.0);
+        for _ in 0..4 {
+            node.update_latency(10.0);
+        }
+        assert_eq!(node.last_latency(), 10.0);
+        assert_eq!(node.sample_count(), Some(28));
+    }
+
+    #[test]
+    fn test_outlier() {
+        let mut steady = node(DEFAULT_WINDOW);
+        for latency in [10.0, 12.0, 11.0] {
+            steady.update_latency(latency);
+        }
+        // A request that timed out
+        steady.update_latency(60_000.0);
+        assert_eq!(steady.last_latency(), 11.0);
+        steady.update_latency(14.0);
+        assert_eq!(steady.last_latency(), 11.75);
+        // The first sample is the estimate, whatever it is
+        let mut first = node(DEFAULT_WINDOW);
+        first.update_latency(60_000.0);
+        assert_eq!(first.last_latency(), 60_000.0);
     }
 }
@@ -0,0 +1,247 @@
+//! Immutable snapshots of the sorted neighbor nodes.
+//! Sorting the neighbors needs the write lock on the list, so doing it for every
+//! candidate of every offloaded request serializes the offloads and blocks the
+//! emergency controller. Instead, the sorted list is published as an immutable
+//! snapshot, regenerated only when the generation counter says something changed.
+//! A new latency is such a change too, unless the `SortPolicy` holds it back.
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// When the neighbors are sorted again for a change of their latencies. The other changes,
+/// e.g. a node joining or entering the emergency area, sort them at the next offload.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SortPolicy {
+    /// At the next offload, i.e. after every forward measuring a new latency
+    #[default]
+    OnChange,
+    /// At most once per interval, if a latency changed or an estimate expired since
+    Interval(Duration),
+    /// Only when an estimated latency expires, the measured ones wait for it or for
+    /// another change
+    OnExpiry,
+}
+
+impl FromStr for SortPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("interval", ms)) => ms
+                .parse()
+                .map(|ms| SortPolicy::Interval(Duration::from_millis(ms)))
+                .map_err(|_| format!("Invalid sort interval: {}", ms)),
+            None if s == "on-change" => Ok(SortPolicy::OnChange),
+            None if s == "on-expiry" => Ok(SortPolicy::OnExpiry),
+            _ => Err(format!("Unknown sort policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for SortPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SortPolicy::OnChange => write!(f, "on-change"),
+            SortPolicy::Interval(interval) => write!(f, "interval:{}", interval.as_millis()),
+            SortPolicy::OnExpiry => write!(f, "on-expiry"),
+        }
+    }
+}
+
+/// Sorted list of nodes, valid for a given generation
+#[derive(Debug)]
+pub struct Snapshot<T> {
+    /// Generation the snapshot was built for
+    pub generation: u64,
+    /// Nodes, in the order they should be tried
+    pub nodes: Vec<T>,
+    /// When the snapshot was built
+    pub built_at: Instant,
+}
+
+/// Holder of the current snapshot
+#[derive(Debug)]
+pub struct SnapshotCell<T> {
+    /// Bumped every time the nodes, their state or their latencies change
+    generation: AtomicU64,
+    /// Last published snapshot. The lock is only held to clone or swap the Arc.
+    current: RwLock<Arc<Snapshot<T>>>,
+    /// Number of snapshots built, i.e. of sorts of the nodes
+    refreshes: AtomicU64,
+}
+
+impl<T> Default for SnapshotCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> SnapshotCell<T> {
+    /// Create an empty cell, the first access builds the snapshot
+    pub fn new() -> Self {
+        Self {
+            generation: AtomicU64::new(1),
+            current: RwLock::new(Arc::new(Snapshot {
+                generation: 0,
+                nodes: Vec::new(),
+                built_at: Instant::now(),
+            })),
+            refreshes: AtomicU64::new(0),
+        }
+    }
+
+    /// Get the current generation
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Get the number of snapshots built so far
+    pub fn refreshes(&self) -> u64 {
+        self.refreshes.load(Ordering::Relaxed)
+    }
+
+    /// Get the last published snapshot, even if it is stale
+    pub fn current(&self) -> Arc<Snapshot<T>> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Mark the current snapshot as stale.
+    /// Must be called after the change is visible to `refresh`.
+    pub fn invalidate(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Get the current snapshot, rebuilding it with `refresh` if it is stale.
+    /// A change that happens while `refresh` runs leaves the new snapshot stale,
+    /// so it is rebuilt again at the next access.
+    pub fn get_or_refresh(&self, refresh: impl FnOnce() -> Vec<T>) -> Arc<Snapshot<T>> {
+        let generation = self.generation();
+        let current = self.current.read().unwrap().clone();
+        if current.generation == generation {
+            return current;
+        }
+
+        self.refreshes.fetch_add(1, Ordering::Relaxed);
+        let snapshot = Arc::new(Snapshot {
+            generation,
+            nodes: refresh(),
+            built_at: Instant::now(),
+        });
+        let mut current = self.current.write().unwrap();
+        // Another thread may have published a newer snapshot in the meantime
+        if current.generation < generation {
+            *current = snapshot.clone();
+            snapshot
+        } else {
+            current.clone()
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_refresh_only_when_stale() {
+        let cell = SnapshotCell::new();
+        let refreshes = AtomicU64::new(0);
+        let refresh = || {
+            refreshes.fetch_add(1, Ordering::SeqCst);
+            vec![1, 2, 3]
+        };
+
+        let first = cell.get_or_refresh(refresh);
+        let second = cell.get_or_refresh(refresh);
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(refreshes.load(Ordering::SeqCst), 1);
+
+        cell.invalidate();
+        let third = cell.get_or_refresh(refresh);
+        assert!(!Arc::ptr_eq(&second, &third));
+        assert_eq!(third.generation, cell.generation());
+        assert_eq!(refreshes.load(Ordering::SeqCst), 2);
+        assert_eq!(cell.refreshes(), 2);
+        assert!(Arc::ptr_eq(&third, &cell.current()));
+    }
+
+    #[test]
+    fn test_sort_policy() {
+        let cases = [
+            ("on-change", Some(SortPolicy::OnChange)),
+            ("on-expiry", Some(SortPolicy::OnExpiry)),
+            (
+                "interval:500",
+                Some(SortPolicy::Interval(Duration::from_millis(500))),
+            ),
+            ("interval:", None),
+            ("interval:soon", None),
+            ("always", None),
+        ];
+        for (policy, expected) in cases {
+            let parsed = policy.parse::<SortPolicy>().ok();
+            assert_eq!(parsed, expected, "{}", policy);
+            if let Some(parsed) = parsed {
+                assert_eq!(parsed.to_string(), policy);
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_swap() {
+        // Every version of the list is made of copies of the same value, so a snapshot
+        // mixing two versions is detected
+        let cell = Arc::new(SnapshotCell::new());
+        let list = Arc::new(Mutex::new(vec![0u64; 16]));
+
+        let writer = {
+            let cell = cell.clone();
+            let list = list.clone();
+            std::thread::spawn(move || {
+                for version in 1..=500u64 {
+                    list.lock().unwrap().iter_mut().for_each(|v| *v = version);
+                    cell.invalidate();
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let cell = cell.clone();
+                let list = list.clone();
+                std::thread::spawn(move || {
+                    let mut last = 0;
+                    for _ in 0..2000 {
+                        let snapshot = cell.get_or_refresh(|| list.lock().unwrap().clone());
+                        assert_eq!(snapshot.nodes.len(), 16);
+                        assert!(snapshot.nodes.iter().all(|v| *v == snapshot.nodes[0]));
+                        // Version n is invalidated into generation n + 1, so a snapshot
+                        // is never older than its generation
+                        assert!(snapshot.nodes[0] + 1 >= snapshot.generation);
+                        // The generation never goes backwards
+                        assert!(snapshot.generation >= last);
+                        last = snapshot.generation;
+                    }
+                })
+            })
+            .collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // Once the writer is done, the snapshot converges to the last version
+        let snapshot = cell.get_or_refresh(|| list.lock().unwrap().clone());
+        assert_eq!(snapshot.generation, cell.generation());
+        assert!(snapshot.nodes.iter().all(|v| *v == 500));
+    }
+}
@@ -0,0 +1,398 @@
+//! Spatial index of the neighbor nodes.
+//! Positions are mapped to points on the unit sphere, where the euclidean (chord)
+//! distance grows with the great-circle distance, so a 3-d tree answers nearest
+//! neighbor and radius queries exactly without evaluating the haversine formula
+//! for every node.
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+/// Radius of the Earth used for the conversions (in meters), the same used by `longitude`
+pub const EARTH_RADIUS: f64 = 6_378_137.0;
+
+/// A position on the unit sphere
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point([f64; 3]);
+
+impl Point {
+    /// Map a position, given as (Latitude, Longitude) in degrees like `longitude::Location`
+    pub fn from_position(position: (f64, f64)) -> Self {
+        let (lat, lng) = (position.0.to_radians(), position.1.to_radians());
+        Self([lat.cos() * lng.cos(), lat.cos() * lng.sin(), lat.sin()])
+    }
+
+    /// Check that the point comes from a valid position
+    pub fn is_valid(&self) -> bool {
+        self.0.iter().all(|c| c.is_finite())
+    }
+
+    /// Squared chord distance between two points
+    pub fn chord2(&self, other: &Point) -> f64 {
+        (0..3).map(|i| (self.0[i] - other.0[i]).powi(2)).sum()
+    }
+
+    /// Great-circle distance between two points (in meters)
+    pub fn distance(&self, other: &Point) -> f64 {
+        2.0 * EARTH_RADIUS * (self.chord2(other).sqrt() / 2.0).min(1.0).asin()
+    }
+}
+
+/// Squared chord distance corresponding to a great-circle distance (in meters)
+pub fn chord2_of(distance: f64) -> f64 {
+    let angle = (distance / EARTH_RADIUS).min(std::f64::consts::PI);
+    (2.0 * (angle / 2.0).sin()).powi(2)
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    point: Point,
+    address: String,
+    axis: usize,
+    left: Option<usize>,
+    right: Option<usize>,
+}
+
+/// Candidate of a nearest neighbor search, ordered by distance
+#[derive(PartialEq)]
+struct Candidate(f64, usize);
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+/// k-d tree of the positions of the nodes, keyed by address.
+/// Nodes are inserted incrementally, the tree is rebuilt balanced when it doubled
+/// in size since the last build.
+#[derive(Debug, Clone, Default)]
+pub struct SpatialIndex {
+    entries: Vec<Entry>,
+    root: Option<usize>,
+    /// Nodes without a valid position, they are never the closest
+    unplaced: Vec<String>,
+    /// Number of entries at the last balanced build
+    built: usize,
+}
+
+impl SpatialIndex {
+    /// Build a balanced index of the given nodes, as (address, position)
+    pub fn new(nodes: impl IntoIterator<Item = (String, (f64, f64))>) -> Self {
+        let mut index = Self::default();
+        for (address, position) in nodes {
+            let point = Point::from_position(position);
+            if point.is_valid() {
+                index.entries.push(Entry {
+                    point,
+                    address,
+                    axis: 0,
+                    left: None,
+                    right: None,
+                });
+            } else {
+                index.unplaced.push(address);
+            }
+        }
+        index.rebuild();
+        index
+    }
+
+    /// Get the number of nodes in the index
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.unplaced.len()
+    }
+
+    /// Check if the index is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Add a node to the index
+    pub fn insert(&mut self, address: String, position: (f64, f64)) {
+        let point = Point::from_position(position);
+        if !point.is_valid() {
+            self.unplaced.push(address);
+            return;
+        }
+        let new = self.entries.len();
+        let mut axis = 0;
+        let mut link = None;
+        let mut current = self.root;
+        while let Some(i) = current {
+            let entry = &self.entries[i];
+            axis = (entry.axis + 1) % 3;
+            let left = point.0[entry.axis] < entry.point.0[entry.axis];
+            link = Some((i, left));
+            current = if left { entry.left } else { entry.right };
+        }
+        self.entries.push(Entry {
+            point,
+            address,
+            axis,
+            left: None,
+            right: None,
+        });
+        match link {
+            Some((parent, true)) => self.entries[parent].left = Some(new),
+            Some((parent, false)) => self.entries[parent].right = Some(new),
+            None => self.root = Some(new),
+        }
+        if self.entries.len() > 2 * self.built.max(8) {
+            self.rebuild();
+        }
+    }
+
+    /// Rebuild the tree balanced
+    fn rebuild(&mut self) {
+        let mut order: Vec<usize> = (0..self.entries.len()).collect();
+        self.root = self.build(&mut order, 0);
+        self.built = self.entries.len();
+    }
+
+    fn build(&mut self, order: &mut [usize], axis: usize) -> Option<usize> {
+        if order.is_empty() {
+            return None;
+        }
+        let median = order.len() / 2;
+        let entries = &self.entries;
+        order.select_nth_unstable_by(median, |a, b| {
+            entries[*a].point.0[axis].total_cmp(&entries[*b].point.0[axis])
+        });
+        let i = order[median];
+        let (left, right) = order.split_at_mut(median);
+        let next = (axis + 1) % 3;
+        let left = self.build(left, next);
+        let right = self.build(&mut right[1..], next);
+        let entry = &mut self.entries[i];
+        entry.axis = axis;
+        entry.left = left;
+        entry.right = right;
+        Some(i)
+    }
+
+    /// Get the `k` closest nodes to `position` accepted by `filter`, closest first,
+    /// together with their great-circle distance (in meters).
+    /// Nodes without a valid position come last, with a NaN distance.
+    pub fn nearest_k(
+        &self,
+        position: (f64, f64),
+        k: usize,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<(&str, f64)> {
+        let target = Point::from_position(position);
+        let mut result = Vec::new();
+        if k == 0 {
+            return result;
+        }
+        if target.is_valid() {
+            let mut best = BinaryHeap::new();
+            self.search(self.root, &target, k, &filter, &mut best);
+            result = best
+                .into_sorted_vec()
+                .into_iter()
+                .map(|Candidate(_, i)| {
+                    let entry = &self.entries[i];
+                    (entry.address.as_str(), entry.point.distance(&target))
+                })
+                .collect();
+        }
+        // Without a valid target, every node is at an unknown distance
+        let unknown = match target.is_valid() {
+            true => &self.entries[..0],
+            false => &self.entries[..],
+        };
+        let unplaced = unknown
+            .iter()
+            .map(|entry| entry.address.as_str())
+            .chain(self.unplaced.iter().map(|address| address.as_str()))
+            .filter(|address| filter(address))
+            .map(|address| (address, f64::NAN));
+        let missing = k - result.len();
+        result.extend(unplaced.take(missing));
+        result
+    }
+
+    fn search(
+        &self,
+        node: Option<usize>,
+        target: &Point,
+        k: usize,
+        filter: &impl Fn(&str) -> bool,
+        best: &mut BinaryHeap<Candidate>,
+    ) {
+        let i = match node {
+            Some(i) => i,
+            None => return,
+        };
+        let entry = &self.entries[i];
+        if filter(&entry.address) {
+            let chord2 = entry.point.chord2(target);
+            if best.len() < k {
+                best.push(Candidate(chord2, i));
+            } else if best.peek().is_some_and(|worst| chord2 < worst.0) {
+                best.pop();
+                best.push(Candidate(chord2, i));
+            }
+        }
+        let delta = target.0[entry.axis] - entry.point.0[entry.axis];
+        let (near, far) = if delta < 0.0 {
+            (entry.left, entry.right)
+        } else {
+            (entry.right, entry.left)
+        };
+        self.search(near, target, k, filter, best);
+        // The other side can only contain closer nodes if the splitting plane is closer
+        if best.len() < k || best.peek().is_some_and(|worst| delta * delta < worst.0) {
+            self.search(far, target, k, filter, best);
+        }
+    }
+
+    /// Get the addresses of the nodes within `radius` meters from `position`
+    pub fn within(&self, position: (f64, f64), radius: f64) -> Vec<&str> {
+        let target = Point::from_position(position);
+        let mut result = Vec::new();
+        if target.is_valid() {
+            self.collect_within(self.root, &target, chord2_of(radius), &mut result);
+        }
+        result
+    }
+
+    fn collect_within<'a>(
+        &'a self,
+        node: Option<usize>,
+        target: &Point,
+        limit: f64,
+        result: &mut Vec<&'a str>,
+    ) {
+        let i = match node {
+            Some(i) => i,
+            None => return,
+        };
+        let entry = &self.entries[i];
+        if entry.point.chord2(target) <= limit {
+            result.push(&entry.address);
+        }
+        let delta = target.0[entry.axis] - entry.point.0[entry.axis];
+        if delta < 0.0 || delta * delta <= limit {
+            self.collect_within(entry.left, target, limit, result);
+        }
+        if delta >= 0.0 || delta * delta <= limit {
+            self.collect_within(entry.right, target, limit, result);
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    fn random_nodes(rng: &mut StdRng, n: usize) -> Vec<(String, (f64, f64))> {
+        (0..n)
+            .map(|i| {
+                let position = (
+                    rng.random_range(-80.0..80.0),
+                    rng.random_range(-180.0..180.0),
+                );
+                (format!("node{}", i), position)
+            })
+            .collect()
+    }
+
+    /// Reference implementation: sort all the accepted nodes by distance
+    fn brute_force(
+        nodes: &[(String, (f64, f64))],
+        position: (f64, f64),
+        k: usize,
+        filter: impl Fn(&str) -> bool,
+    ) -> Vec<&str> {
+        let target = Point::from_position(position);
+        let mut distances: Vec<(f64, &str)> = nodes
+            .iter()
+            .filter(|(address, _)| filter(address))
+            .map(|(address, p)| (Point::from_position(*p).chord2(&target), address.as_str()))
+            .collect();
+        distances.sort_by(|a, b| a.0.total_cmp(&b.0));
+        distances.into_iter().take(k).map(|(_, a)| a).collect()
+    }
+
+    fn addresses<'a>(result: &[(&'a str, f64)]) -> Vec<&'a str> {
+        result.iter().map(|(address, _)| *address).collect()
+    }
+
+    #[test]
+    fn test_distance() {
+        // Milan - Paris, about 640 km
+        let milan = Point::from_position((45.4685, 9.1824));
+        let paris = Point::from_position((48.8575, 2.3514));
+        assert!((milan.distance(&paris) - 640_000.0).abs() < 10_000.0);
+        assert_eq!(milan.distance(&milan), 0.0);
+        assert!((chord2_of(milan.distance(&paris)) - milan.chord2(&paris)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_nearest_k_against_brute_force() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let nodes = random_nodes(&mut rng, 500);
+        // Half the nodes are built balanced, the other half inserted incrementally
+        let mut index = SpatialIndex::new(nodes[..250].iter().cloned());
+        for (address, position) in nodes[250..].iter().cloned() {
+            index.insert(address, position);
+        }
+        assert_eq!(index.len(), 500);
+
+        let odd = |address: &str| address.ends_with(['1', '3', '5', '7', '9']);
+        for _ in 0..50 {
+            let (_, position) = random_nodes(&mut rng, 1).remove(0);
+            for k in [1, 5, 32, 500] {
+                assert_eq!(
+                    addresses(&index.nearest_k(position, k, |_| true)),
+                    brute_force(&nodes, position, k, |_| true)
+                );
+                assert_eq!(
+                    addresses(&index.nearest_k(position, k, odd)),
+                    brute_force(&nodes, position, k, odd)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_within_against_brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let nodes = random_nodes(&mut rng, 300);
+        let index = SpatialIndex::new(nodes.iter().cloned());
+        for radius in [1_000.0, 500_000.0, 3_000_000.0] {
+            let (_, position) = random_nodes(&mut rng, 1).remove(0);
+            let target = Point::from_position(position);
+            let mut expected: Vec<&str> = nodes
+                .iter()
+                .filter(|(_, p)| Point::from_position(*p).distance(&target) <= radius)
+                .map(|(address, _)| address.as_str())
+                .collect();
+            let mut found = index.within(position, radius);
+            expected.sort();
+            found.sort();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_invalid_positions() {
+        let mut index = SpatialIndex::new(vec![("a".to_string(), (45.0, 9.0))]);
+        index.insert("broken".to_string(), (f64::NAN, 0.0));
+        index.insert("b".to_string(), (46.0, 9.0));
+        let result = index.nearest_k((45.1, 9.0), 3, |_| true);
+        assert_eq!(addresses(&result), vec!["a", "b", "broken"]);
+        assert!(result[2].1.is_nan());
+        assert!(index.within((45.0, 9.0), 1_000_000.0).len() == 2);
+        assert!(index.nearest_k((45.0, 9.0), 0, |_| true).is_empty());
+    }
+}
@@ -1,3 +1,4 @@
+use log::warn;
 use serde::{Deserialize, Serialize};
 
 use super::OrchestratorError;
@@ -6,14 +7,76 @@ use super::OrchestratorError;
 #[derive(Deserialize, Serialize, Clone)]
 pub struct LocalResources {
     cpus_available: usize,
+    /// Cpus of the node, captured at construction
+    total_cpus: usize,
+    /// Virtual cpus the instances can take, the total with the overcommit
+    capacity: usize,
+    /// Memory of the node (in KiB), captured at construction
+    total_memory: usize,
+    /// Memory taken by the instances (in KiB)
+    memory_acquired: usize,
+    /// Releases of cpus or memory that were never acquired
+    drift: u64,
     // TODO: Put capabilities of the node
 }
 
+/// Totals and available resources of the node, with the accounting errors seen so far
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourcesSnapshot {
+    pub total_cpus: usize,
+    /// Virtual cpus the instances can take
+    pub capacity: usize,
+    pub available_cpus: usize,
+    /// In KiB
+    pub total_memory: usize,
+    /// In KiB
+    pub available_memory: usize,
+    /// Memory taken by the instances, in KiB
+    pub acquired_memory: usize,
+    /// Releases of cpus or memory that were never acquired, clamped to the totals
+    pub drift: u64,
+}
+
 impl LocalResources {
     /// Create a new LocalResources object
     pub fn new() -> Self {
+        Self::with_totals(num_cpus::get(), Self::get_total_memory())
+    }
+
+    /// Create a new LocalResources object for a node with the given cpus and memory (in KiB)
+    pub fn with_totals(total_cpus: usize, total_memory: usize) -> Self {
         Self {
-            cpus_available: num_cpus::get(),
+            cpus_available: total_cpus,
+            total_cpus,
+            capacity: total_cpus,
+            total_memory,
+            memory_acquired: 0,
+            drift: 0,
+        }
+    }
+
+    /// Admit instances for up to `overcommit` times the cpus of the node, most
+    /// functions are bursty and leave their cpus idle most of the time
+    pub fn with_overcommit(self, overcommit: f64) -> Self {
+        let capacity = (self.total_cpus as f64 * overcommit).floor() as usize;
+        let acquired = self.capacity - self.cpus_available;
+        Self {
+            cpus_available: capacity.saturating_sub(acquired),
+            capacity,
+            ..self
+        }
+    }
+
+    /// Get the totals and the available resources of the node
+    pub fn snapshot(&self) -> ResourcesSnapshot {
+        ResourcesSnapshot {
+            total_cpus: self.total_cpus,
+            capacity: self.capacity,
+            available_cpus: self.cpus_available,
+            total_memory: self.total_memory,
+            available_memory: Self::get_available_memory().min(self.total_memory),
+            acquired_memory: self.memory_acquired,
+            drift: self.drift,
         }
     }
 
@@ -33,9 +96,20 @@ impl LocalResources {
         }
     }
 
-    /// Release a number of CPUs
+    /// Release a number of CPUs.
+    /// Releasing cpus that were never acquired would make the node offload too late,
+    /// the available cpus are clamped to the capacity and the drift is counted.
     pub fn release_cpus(&mut self, cpus: usize) -> Result<(), OrchestratorError> {
         match self.cpus_available.checked_add(cpus) {
+            Some(x) if x > self.capacity => {
+                self.drift += 1;
+                warn!(
+                    "Released {} cpus with {} of {} available, accounting drift #{}",
+                    cpus, self.cpus_available, self.capacity, self.drift
+                );
+                self.cpus_available = self.capacity;
+                Ok(())
+            }
             Some(x) => {
                 self.cpus_available = x;
                 Ok(())
@@ -44,6 +118,47 @@ impl LocalResources {
         }
     }
 
+    /// Acquire the memory (in KiB) of an instance, up to the memory of the node
+    pub fn acquire_memory(&mut self, memory: usize) -> Result<(), OrchestratorError> {
+        match self.memory_acquired.checked_add(memory) {
+            Some(x) if x <= self.total_memory => {
+                self.memory_acquired = x;
+                Ok(())
+            }
+            _ => Err(OrchestratorError::InsufficientResources),
+        }
+    }
+
+    /// Release the memory (in KiB) of an instance.
+    /// As for the cpus, releasing more than was acquired is clamped and counted in the drift.
+    pub fn release_memory(&mut self, memory: usize) {
+        match self.memory_acquired.checked_sub(memory) {
+            Some(x) => self.memory_acquired = x,
+            None => {
+                self.drift += 1;
+                warn!(
+                    "Released {} KiB with {} KiB acquired, accounting drift #{}",
+                    memory, self.memory_acquired, self.drift
+                );
+                self.memory_acquired = 0;
+            }
+        }
+    }
+
+    /// Acquire the cpus and the memory (in KiB) of an instance, either both or none
+    pub fn acquire(&mut self, cpus: usize, memory: usize) -> Result<(), OrchestratorError> {
+        self.acquire_cpus(cpus)?;
+        self.acquire_memory(memory).inspect_err(|_| {
+            self.cpus_available += cpus;
+        })
+    }
+
+    /// Release the cpus and the memory (in KiB) of an instance
+    pub fn release(&mut self, cpus: usize, memory: usize) -> Result<(), OrchestratorError> {
+        self.release_memory(memory);
+        self.release_cpus(cpus)
+    }
+
     /// Get the total memory of the node
     pub fn get_total_memory() -> usize {
         let contents = std::fs::read_to_string("/proc/meminfo");
@@ -86,11 +201,123 @@ impl LocalResources {
     }
 }
 
+/// Resources kept free for the emergency requests, the other requests cannot use them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Reserve {
+    pub cpus: usize,
+    /// In KiB, as the memory of the requests
+    pub memory: usize,
+}
+
+impl Reserve {
+    /// Get what a request can use out of the available resources, as (cpus, memory)
+    /// # Arguments
+    /// * `cpus` - The available cpus
+    /// * `memory` - The available memory (in KiB)
+    /// * `emergency` - Whether the request is an emergency one, that can use the reserve
+    pub fn usable(&self, cpus: usize, memory: usize, emergency: bool) -> (usize, usize) {
+        if emergency {
+            (cpus, memory)
+        } else {
+            (
+                cpus.saturating_sub(self.cpus),
+                memory.saturating_sub(self.memory),
+            )
+        }
+    }
+}
+
 // Unit tests
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_reserve() {
+        let reserve = Reserve {
+            cpus: 2,
+            memory: 1024,
+        };
+        assert_eq!(reserve.usable(8, 4096, false), (6, 3072));
+        assert_eq!(reserve.usable(8, 4096, true), (8, 4096));
+        // Less than the reserve is left
+        assert_eq!(reserve.usable(1, 512, false), (0, 0));
+        assert_eq!(Reserve::default().usable(8, 4096, false), (8, 4096));
+    }
+
+    #[test]
+    fn test_release_clamped() {
+        let mut resources = LocalResources::with_totals(4, 1024);
+        resources.acquire_cpus(3).unwrap();
+        resources.release_cpus(2).unwrap();
+        assert_eq!(resources.snapshot().available_cpus, 3);
+        assert_eq!(resources.snapshot().drift, 0);
+
+        // One cpu more than acquired is released
+        resources.release_cpus(2).unwrap();
+        let snapshot = resources.snapshot();
+        assert_eq!((snapshot.available_cpus, snapshot.total_cpus), (4, 4));
+        assert_eq!(snapshot.drift, 1);
+        resources.release_cpus(1).unwrap();
+        assert_eq!(resources.snapshot().available_cpus, 4);
+        assert_eq!(resources.snapshot().drift, 2);
+
+        // The drift does not give back the cpus acquired afterwards
+        resources.acquire_cpus(4).unwrap();
+        assert!(resources.acquire_cpus(1).is_err());
+        assert!(resources.snapshot().available_memory <= 1024);
+    }
+
+    #[test]
+    fn test_release_memory_clamped() {
+        let mut resources = LocalResources::with_totals(4, 1024);
+        resources.acquire(1, 768).unwrap();
+        // Not enough memory left, the cpus are not taken either
+        assert!(resources.acquire(1, 512).is_err());
+        assert_eq!(resources.get_available_cpus(), 3);
+        resources.release(1, 256).unwrap();
+        let snapshot = resources.snapshot();
+        assert_eq!(
+            (snapshot.acquired_memory, snapshot.available_cpus),
+            (512, 4)
+        );
+        assert_eq!(snapshot.drift, 0);
+
+        // More memory than acquired is released
+        resources.release_memory(1024);
+        let snapshot = resources.snapshot();
+        assert_eq!((snapshot.acquired_memory, snapshot.total_memory), (0, 1024));
+        assert_eq!(snapshot.drift, 1);
+
+        // The drift does not give back the memory acquired afterwards
+        resources.acquire_memory(1024).unwrap();
+        assert!(resources.acquire_memory(1).is_err());
+    }
+
+    #[test]
+    fn test_overcommit() {
+        // 1.5 virtual cpus per cpu
+        let mut resources = LocalResources::with_totals(4, 1024).with_overcommit(1.5);
+        assert_eq!(resources.snapshot().capacity, 6);
+        assert_eq!(resources.get_available_cpus(), 6);
+        resources.acquire_cpus(5).unwrap();
+        resources.acquire_cpus(1).unwrap();
+        assert!(resources.acquire_cpus(1).is_err());
+        resources.release_cpus(6).unwrap();
+        // Releases are clamped to the capacity, not to the cpus of the node
+        resources.release_cpus(1).unwrap();
+        assert_eq!(resources.get_available_cpus(), 6);
+        assert_eq!(resources.snapshot().drift, 1);
+
+        // Partial virtual cpus are not admitted
+        let resources = LocalResources::with_totals(3, 1024).with_overcommit(1.5);
+        assert_eq!(resources.get_available_cpus(), 4);
+        // The cpus already acquired stay acquired
+        let mut resources = LocalResources::with_totals(4, 1024);
+        resources.acquire_cpus(3).unwrap();
+        assert_eq!(resources.with_overcommit(2.0).get_available_cpus(), 5);
+    }
+
     #[test]
     fn test_total_memory() {
         let total_mem = LocalResources::get_total_memory();
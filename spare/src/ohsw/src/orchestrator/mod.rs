@@ -1,47 +1,171 @@
 //! Orchestrator module. It is responsible for managing the local resources and monitoring the remote nodes
+pub mod capabilities;
+pub mod drain;
 pub mod global;
 mod local_resources;
+pub mod pressure;
+pub mod readiness;
+pub mod resource_spec;
+pub mod scheduler;
+pub mod sticky;
+pub mod trace;
 use std::{
-    sync::{Mutex, RwLock},
-    time::Instant,
+    collections::{HashMap, HashSet, VecDeque},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::api::{self, invoke::InvokeFunction, resources::Resources};
-use actix_web::{web, HttpRequest, HttpResponse};
-use awc::{body::BoxBody, Client};
+use crate::{
+    api::{
+        self,
+        invoke::{self, InvokeFunction},
+        resources::{Resources, ResourcesQuery},
+    },
+    chaos::{self, Chaos},
+    db::models::{EmergencyEvent, EmergencyEventType, RequestOutcome},
+    net::{node_address, tls::NodeClient},
+};
+use actix_web::{web, HttpResponse};
+use awc::{body::BoxBody, http::StatusCode};
+use capabilities::FunctionRequires;
+use chrono::{DateTime, Utc};
+use drain::Drain;
 use global::{
-    emergency::Emergency, geo_distance::GeoDistance, identity::Node, Distance, NeighborNode,
-    NeighborNodeList, NeighborNodeStrategy, NeighborNodeType,
+    emergency::Emergency,
+    geo_distance::GeoDistance,
+    identity::Node,
+    node_cache::{NodeCache, TopologySource},
+    ranking::{self, Exclusion, RankedNode, Ranking},
+    snapshot::{Snapshot, SnapshotCell, SortPolicy},
+    Distance, NeighborNode, NeighborNodeList, NeighborNodeStrategy, NeighborNodeType,
 };
 use local_resources::LocalResources;
+pub use local_resources::{Reserve, ResourcesSnapshot};
 use log::{error, info, warn};
+use pressure::{MemoryPressure, PressureThresholds};
+use readiness::Readiness;
+use resource_spec::{FunctionResources, NodeLimits, ResolveError, Resolved, ResourceSpec};
+use scheduler::{
+    packing::{Packing, PackingStats},
+    CostModel, Decision, Doomed, RemoteEstimate, Scheduler,
+};
+use trace::{Attempt, AttemptOutcome, Candidate, OffloadTrace, OffloadTraces, Probe, Recording};
+
+/// Time between two checks of the resources by a request waiting for them
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(2);
 
 // TODO: Move this inside the node module
 
+/// Error returned when a request is forwarded to another node
+#[derive(Debug, thiserror::Error)]
 pub enum InvokeError {
-    Unknown(String),
-}
-impl std::fmt::Display for InvokeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            InvokeError::Unknown(msg) => write!(f, "Unknown error: {}", msg),
-        }
-    }
+    /// The request cannot be sent to the node
+    #[error("Failed to send the request: {0}")]
+    Send(#[from] awc::error::SendRequestError),
+    /// The response of the node cannot be read
+    #[error("Failed to read the response: {0}")]
+    Payload(#[from] awc::error::PayloadError),
+    /// The node answered with an error
+    #[error("The node answered with status {0}")]
+    Status(awc::http::StatusCode),
 }
 
 /// Error returned by the orchestrator
+#[derive(Debug, thiserror::Error)]
 pub enum OrchestratorError {
+    #[error("Insufficient resources")]
     InsufficientResources,
+    #[error("Cannot acquire the lock on the resources")]
     CannotAcquireResources,
 }
 
+/// How the image of a request weighs on the neighbors it is offloaded to. The neighbors
+/// that do not tell whether they have it, e.g. older nodes, are tried as if they had it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageAffinity {
+    /// Try the neighbors without the image after the others
+    #[default]
+    Prefer,
+    /// Never offload to a neighbor without the image
+    Require,
+}
+
+impl FromStr for ImageAffinity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "prefer" => Ok(ImageAffinity::Prefer),
+            "require" => Ok(ImageAffinity::Require),
+            _ => Err(format!("Unknown image affinity: {}", s)),
+        }
+    }
+}
+
 /// Orchestrator. It is responsible for managing the local resources and monitoring the remote nodes
 /// available in the system.
+/// Its state is behind std locks, taken by the handlers of every request: they are only held
+/// for short synchronous sections, never across an `.await` (`clippy::await_holding_lock`
+/// is denied in the crate), so a worker never blocks on a lock held by a suspended task.
 pub struct Orchestrator {
     in_emergency_area: Mutex<bool>,
+    /// Identifier and zone of the current emergency
+    current_emergency: Mutex<Option<(String, Emergency)>>,
     resources: RwLock<LocalResources>,
-    identity: Node,
+    /// Identity of the node, its position changes when the node moves
+    identity: RwLock<Node>,
     global_resources: RwLock<NeighborNodeList>,
+    /// Sorted nodes available for offloading, rebuilt when `global_resources` changes
+    neighbors: SnapshotCell<NeighborNodeType>,
+    /// When the neighbors are sorted again for a change of their latencies
+    sort_policy: SortPolicy,
+    /// A latency changed since the last sort, and the sort policy held it back
+    latencies_changed: AtomicBool,
+    /// Decides whether the requests that cannot run right away wait or are offloaded
+    scheduler: Scheduler,
+    /// Holds the small requests back for the larger waiting ones, None if disabled
+    packing: Option<Packing>,
+    /// Offload the invocations of a function to the same neighbor first
+    sticky: bool,
+    /// Forward the body of a request as it was received, with the hops in a header
+    raw_offload: bool,
+    /// Clients used to call the neighbor nodes
+    client: NodeClient,
+    /// Resources only the emergency requests can use
+    reserve: Reserve,
+    /// Drain of the node before a maintenance
+    drain: Drain,
+    /// Phase of the node, no request is taken before it is ready
+    readiness: Arc<Readiness>,
+    /// Memory pressure of the host, no local instance is admitted while it is high
+    memory_pressure: MemoryPressure,
+    /// How the image of a request weighs on the neighbors it is offloaded to
+    image_affinity: ImageAffinity,
+    /// Neighbors that announced they are draining, nothing is offloaded to them
+    draining_neighbors: Mutex<HashSet<String>>,
+    /// Each neighbor as last announced, by key (its id, or its address for the nodes
+    /// without one)
+    members: Mutex<HashMap<String, Node>>,
+    /// Default resources of the functions, for the requests that leave them out
+    function_resources: HashMap<String, ResourceSpec>,
+    /// Capabilities the neighbors must have to be offloaded the requests of a function
+    function_requires: HashMap<String, Vec<String>>,
+    /// Largest instance the node runs, larger requests are clamped
+    instance_limits: ResourceSpec,
+    /// Traces of the last offloads, None if they are not recorded
+    traces: Option<OffloadTraces>,
+    /// Faults injected for chaos testing, None if the node takes no injection
+    chaos: Option<Chaos>,
+    /// Directory the list of nodes is saved in when it changes, None if it is not saved
+    node_cache: Option<PathBuf>,
+    /// Where the list of the neighbors comes from
+    topology: Mutex<TopologySource>,
 }
 
 impl Orchestrator {
@@ -52,30 +176,471 @@ impl Orchestrator {
     /// # Returns
     /// * A new orchestrator
     pub fn new(nodes: Vec<Node>, identity: Node) -> Self {
-        let mut strategy = NeighborNodeStrategy::GeoDistance;
-        // Read the strategy from the environment
-        if let Ok(strategy_str) = std::env::var("STRATEGY") {
-            match strategy_str.as_str() {
-                "SimpleCellular" => strategy = NeighborNodeStrategy::SimpleCellular,
-                "GeoDistance" => strategy = NeighborNodeStrategy::GeoDistance,
-                "SmartLatency" => strategy = NeighborNodeStrategy::SmartLatency,
-                _ => error!("Unknown strategy: {}.", strategy_str),
-            }
-        }
+        Self::with_strategy(nodes, identity, NeighborNodeStrategy::from_env())
+    }
 
-        let mut neighbor_nodes = NeighborNodeList::new(strategy);
+    /// Create a new orchestrator using the given strategy
+    /// # Arguments
+    /// * `nodes` - Vector of nodes in the system
+    /// * `identity` - Identity of the node itself
+    /// * `strategy` - Strategy used to select the neighbor nodes
+    pub fn with_strategy(nodes: Vec<Node>, identity: Node, strategy: NeighborNodeStrategy) -> Self {
+        let position = identity.position();
+        let mut orchestrator = Self {
+            in_emergency_area: Mutex::new(false),
+            current_emergency: Mutex::new(None),
+            resources: RwLock::new(LocalResources::new()),
+            identity: RwLock::new(identity),
+            global_resources: RwLock::new(NeighborNodeList::new(strategy)),
+            neighbors: SnapshotCell::new(),
+            sort_policy: SortPolicy::default(),
+            latencies_changed: AtomicBool::new(false),
+            scheduler: Scheduler::default(),
+            packing: None,
+            sticky: true,
+            raw_offload: true,
+            client: NodeClient::plain(),
+            reserve: Reserve::default(),
+            drain: Drain::new(),
+            readiness: Arc::default(),
+            memory_pressure: MemoryPressure::default(),
+            image_affinity: ImageAffinity::default(),
+            draining_neighbors: Mutex::new(HashSet::new()),
+            members: Mutex::new(HashMap::new()),
+            function_resources: HashMap::new(),
+            function_requires: HashMap::new(),
+            instance_limits: ResourceSpec::default(),
+            traces: None,
+            chaos: None,
+            node_cache: None,
+            topology: Mutex::new(TopologySource::ControlPlane),
+        };
+        // A node that announced itself more than once is registered once
         for node in nodes {
-            neighbor_nodes.add_node(node.address, node.position);
+            orchestrator.add_node(node);
         }
 
         // Sort the nodes based on the strategy
-        neighbor_nodes.sort(&mut GeoDistance::new(identity.position, "".to_string()));
+        orchestrator
+            .global_resources
+            .get_mut()
+            .unwrap()
+            .sort(&mut GeoDistance::new(position, "".to_string()));
+        orchestrator
+    }
 
+    /// Set when the neighbors are sorted again for a change of their latencies
+    pub fn with_sort_policy(self, sort_policy: SortPolicy) -> Self {
         Self {
-            in_emergency_area: Mutex::new(false),
-            resources: RwLock::new(LocalResources::new()),
-            identity: identity,
-            global_resources: RwLock::new(neighbor_nodes),
+            sort_policy,
+            ..self
+        }
+    }
+
+    /// Set the cost model used to decide whether a request waits or is offloaded
+    pub fn with_cost_model(self, model: CostModel) -> Self {
+        Self {
+            scheduler: Scheduler::new(model),
+            ..self
+        }
+    }
+
+    /// Hold a small request back for at most `hold` when it would take the cpus a larger
+    /// waiting request needs, never if it is zero
+    pub fn with_packing_hold(self, hold: Duration) -> Self {
+        Self {
+            packing: (!hold.is_zero()).then(|| Packing::new(hold)),
+            ..self
+        }
+    }
+
+    /// Set whether the invocations of a function are offloaded to the same neighbor first
+    pub fn with_sticky_offload(self, sticky: bool) -> Self {
+        Self { sticky, ..self }
+    }
+
+    /// Set whether the requests are forwarded as they were received, or re-encoded with
+    /// the hops in the body for the nodes that do not read the hops header
+    pub fn with_raw_offload(self, raw_offload: bool) -> Self {
+        Self {
+            raw_offload,
+            ..self
+        }
+    }
+
+    /// Keep the traces of the last `capacity` offloads, none if it is 0
+    pub fn with_offload_traces(self, capacity: usize) -> Self {
+        Self {
+            traces: (capacity > 0).then(|| OffloadTraces::new(capacity)),
+            ..self
+        }
+    }
+
+    /// Take the failure injections of chaos testing
+    pub fn with_chaos(self, enabled: bool) -> Self {
+        Self {
+            chaos: enabled.then(Chaos::new),
+            ..self
+        }
+    }
+
+    /// Admit instances for up to `overcommit` times the cpus of the node
+    pub fn with_cpu_overcommit(self, overcommit: f64) -> Self {
+        let resources = self.resources.into_inner().unwrap();
+        Self {
+            resources: RwLock::new(resources.with_overcommit(overcommit)),
+            ..self
+        }
+    }
+
+    /// Follow the startup of the node with `readiness`, instead of being ready at once.
+    /// The readiness is shared with the server answering while the node starts
+    pub fn with_readiness(self, readiness: Arc<Readiness>) -> Self {
+        Self { readiness, ..self }
+    }
+
+    /// Set the clients used to call the neighbor nodes, e.g. over HTTPS
+    pub fn with_node_client(self, client: NodeClient) -> Self {
+        Self { client, ..self }
+    }
+
+    /// Save the list of nodes in `dir` now and every time it changes, see `NodeCache`
+    pub fn with_node_cache(self, dir: PathBuf) -> Self {
+        let orchestrator = Self {
+            node_cache: Some(dir),
+            ..self
+        };
+        orchestrator.save_nodes();
+        orchestrator
+    }
+
+    /// Mark the list of nodes as the last known one, saved at `saved_at`, until the
+    /// control plane answers again, see `reconcile`. None if it comes from the control
+    /// plane.
+    pub fn with_cached_topology(self, saved_at: Option<DateTime<Utc>>) -> Self {
+        let topology = match saved_at {
+            Some(saved_at) => TopologySource::Cache { saved_at },
+            None => TopologySource::ControlPlane,
+        };
+        Self {
+            topology: Mutex::new(topology),
+            ..self
+        }
+    }
+
+    /// Get where the list of the neighbors comes from
+    pub fn topology_source(&self) -> TopologySource {
+        self.topology.lock().unwrap().clone()
+    }
+
+    /// Save the list of nodes, if it is saved
+    fn save_nodes(&self) {
+        let Some(dir) = &self.node_cache else {
+            return;
+        };
+        let mut nodes: Vec<_> = self.members.lock().unwrap().values().cloned().collect();
+        nodes.sort_by(|a, b| a.key().cmp(b.key()));
+        if let Err(e) = NodeCache::new(self.get_identity(), nodes).save(dir) {
+            error!("Cannot save the list of nodes in {}: {}", dir.display(), e);
+        }
+    }
+
+    /// Keep some resources free for the emergency requests
+    pub fn with_reserve(self, reserve: Reserve) -> Self {
+        Self { reserve, ..self }
+    }
+
+    /// Set the default resources of the functions
+    pub fn with_function_resources(self, defaults: Vec<FunctionResources>) -> Self {
+        let function_resources = defaults
+            .into_iter()
+            .map(|defaults| (defaults.function, defaults.resources))
+            .collect();
+        Self {
+            function_resources,
+            ..self
+        }
+    }
+
+    /// Set the capabilities the functions require from the neighbors they are offloaded to
+    pub fn with_function_requires(self, requires: Vec<FunctionRequires>) -> Self {
+        let function_requires = requires
+            .into_iter()
+            .map(|requires| (requires.function, requires.requires))
+            .collect();
+        Self {
+            function_requires,
+            ..self
+        }
+    }
+
+    /// Get the capabilities required by a function that a neighbor lacks. A neighbor that
+    /// announced none, e.g. an older node, has none.
+    /// # Arguments
+    /// * `function` - Name of the function
+    /// * `address` - Address of the neighbor
+    pub fn missing_capabilities(&self, function: &str, address: &str) -> Vec<String> {
+        let Some(required) = self.function_requires.get(function) else {
+            return Vec::new();
+        };
+        let members = self.members.lock().unwrap();
+        let offered = members
+            .values()
+            .find(|node| node.address == address)
+            .map(|node| node.capabilities.as_slice())
+            .unwrap_or_default();
+        capabilities::missing(required, offered)
+    }
+
+    /// Clamp the resources of the instances to these
+    pub fn with_instance_limits(self, instance_limits: ResourceSpec) -> Self {
+        Self {
+            instance_limits,
+            ..self
+        }
+    }
+
+    /// Resolve the resources of an instance of a function, see `ResourceSpec::resolve`.
+    /// The hard caps are the cpus the node can admit and its memory.
+    pub fn resolve_resources(
+        &self,
+        function: &str,
+        request: &ResourceSpec,
+    ) -> Result<Resolved, ResolveError> {
+        let snapshot = self.resources_snapshot();
+        let limits = NodeLimits {
+            max: self.instance_limits,
+            hard_vcpus: snapshot.capacity.try_into().unwrap_or(i32::MAX),
+            hard_memory: (snapshot.total_memory / 1024)
+                .try_into()
+                .unwrap_or(i32::MAX),
+        };
+        ResourceSpec::resolve(request, self.function_resources.get(function), &limits)
+    }
+
+    /// Get the clients used to call the neighbor nodes
+    pub fn node_client(&self) -> &NodeClient {
+        &self.client
+    }
+
+    /// Get the scheduler of the node
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    /// Get the faults injected in the node, None if it takes no injection
+    pub fn chaos(&self) -> Option<&Chaos> {
+        self.chaos.as_ref()
+    }
+
+    /// Set how the image of a request weighs on the neighbors it is offloaded to
+    pub fn with_image_affinity(self, image_affinity: ImageAffinity) -> Self {
+        Self {
+            image_affinity,
+            ..self
+        }
+    }
+
+    /// Stop admitting local instances when the memory pressure crosses `thresholds`
+    pub fn with_memory_pressure(self, thresholds: PressureThresholds) -> Self {
+        Self {
+            memory_pressure: MemoryPressure::new(thresholds),
+            ..self
+        }
+    }
+
+    /// Get the counters of the packing, None if it is disabled
+    pub fn packing_stats(&self) -> Option<PackingStats> {
+        let packing = self.packing.as_ref()?;
+        Some(packing.stats(self.usable_cpus(false)))
+    }
+
+    /// Get the drain of the node
+    pub fn drain(&self) -> &Drain {
+        &self.drain
+    }
+
+    /// Get the readiness of the node
+    pub fn readiness(&self) -> &Readiness {
+        &self.readiness
+    }
+
+    /// Get the memory pressure of the host
+    pub fn memory_pressure(&self) -> &MemoryPressure {
+        &self.memory_pressure
+    }
+
+    /// Get the traces of the last offloads, None if they are not recorded
+    pub fn offload_traces(&self) -> Option<&OffloadTraces> {
+        self.traces.as_ref()
+    }
+
+    /// Record that a neighbor started or stopped draining
+    /// # Arguments
+    /// * `address` - Address of the neighbor
+    /// * `draining` - Whether it is draining
+    pub fn set_neighbor_draining(&self, address: &str, draining: bool) {
+        let mut neighbors = self.draining_neighbors.lock().unwrap();
+        if draining {
+            neighbors.insert(address.to_string());
+        } else {
+            neighbors.remove(address);
+        }
+    }
+
+    /// Take note of an announcement of a neighbor. A known neighbor announcing itself from
+    /// a new address is moved there, rather than added again.
+    /// Returns the address the neighbor had, if it changed.
+    /// # Arguments
+    /// * `node` - Neighbor as announced
+    pub fn observe_neighbor(&self, node: &Node) -> Option<String> {
+        let mut members = self.members.lock().unwrap();
+        let previous = self.observe(&mut members, node);
+        drop(members);
+        self.save_nodes();
+        previous
+    }
+
+    /// Register a neighbor. Registering is idempotent: a known neighbor announced again is
+    /// taken note of as by `observe_neighbor`, rather than added twice. A neighbor with a
+    /// malformed address is left out.
+    /// Returns whether the neighbor was unknown.
+    /// # Arguments
+    /// * `node` - Neighbor as announced
+    pub fn add_node(&self, mut node: Node) -> bool {
+        node.address = match node_address::validate(&node.address) {
+            Ok(address) => address,
+            Err(e) => {
+                warn!("Ignoring node {}: {}", node.key(), e);
+                return false;
+            }
+        };
+        let mut members = self.members.lock().unwrap();
+        let added = match members.contains_key(node.key()) {
+            true => {
+                if let Some(previous) = self.observe(&mut members, &node) {
+                    info!("Node {} moved from {}", node.address, previous);
+                }
+                false
+            }
+            false => {
+                self.global_resources
+                    .write()
+                    .unwrap()
+                    .add_node(node.address.clone(), node.position());
+                members.insert(node.key().to_string(), node);
+                self.neighbors.invalidate();
+                true
+            }
+        };
+        drop(members);
+        self.save_nodes();
+        added
+    }
+
+    /// Forget a neighbor, e.g. one left out of the list of the control plane.
+    /// Returns false if the neighbor is unknown.
+    /// # Arguments
+    /// * `key` - Id of the neighbor, or its address if it has none
+    pub fn remove_node(&self, key: &str) -> bool {
+        let Some(node) = self.members.lock().unwrap().remove(key) else {
+            return false;
+        };
+        self.global_resources
+            .write()
+            .unwrap()
+            .remove_node(&node.address);
+        self.draining_neighbors
+            .lock()
+            .unwrap()
+            .remove(&node.address);
+        self.neighbors.invalidate();
+        self.save_nodes();
+        true
+    }
+
+    /// Replace the last known list of the neighbors with the list of the control plane,
+    /// once it answers again: the new neighbors are added, the known ones taken note of
+    /// and the ones left out removed. The list is no longer stale.
+    /// # Returns
+    /// * The number of neighbors added and removed
+    pub fn reconcile(&self, nodes: Vec<Node>) -> (usize, usize) {
+        let keys: HashSet<String> = nodes.iter().map(|node| node.key().to_string()).collect();
+        let added = nodes
+            .into_iter()
+            .map(|node| self.add_node(node))
+            .filter(|added| *added)
+            .count();
+        let left: Vec<String> = self
+            .members
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|key| !keys.contains(*key))
+            .cloned()
+            .collect();
+        for key in &left {
+            info!("Node {} is no longer in the list of nodes", key);
+            self.remove_node(key);
+        }
+        *self.topology.lock().unwrap() = TopologySource::ControlPlane;
+        self.save_nodes();
+        (added, left.len())
+    }
+
+    fn observe(&self, members: &mut HashMap<String, Node>, node: &Node) -> Option<String> {
+        let known = members.get_mut(node.key())?;
+        let address = match node_address::validate(&node.address) {
+            Ok(address) => address,
+            Err(e) => {
+                warn!("Ignoring an announce of {}: {}", node.key(), e);
+                return None;
+            }
+        };
+        // An announce sent before the last start of the neighbor, delivered late
+        if known.supersedes(node) {
+            warn!(
+                "Ignoring an announce of {} older than its last start",
+                node.key()
+            );
+            return None;
+        }
+        known.incarnation = node.incarnation;
+        known.capabilities.clone_from(&node.capabilities);
+        if known.address == address {
+            return None;
+        }
+        let previous = std::mem::replace(&mut known.address, address.clone());
+        self.global_resources.write().unwrap().update_node(
+            &previous,
+            address.clone(),
+            node.position(),
+        );
+        let mut draining = self.draining_neighbors.lock().unwrap();
+        if draining.remove(&previous) {
+            draining.insert(address);
+        }
+        drop(draining);
+        self.neighbors.invalidate();
+        Some(previous)
+    }
+
+    /// Set the number of neighbor nodes from which the spatial index is used
+    pub fn with_index_threshold(self, threshold: usize) -> Self {
+        let node_list = self.global_resources.into_inner().unwrap();
+        Self {
+            global_resources: RwLock::new(node_list.with_index_threshold(threshold)),
+            ..self
+        }
+    }
+
+    /// Rank the neighbor nodes away from the emergency while there is one,
+    /// see `NeighborNodeList::with_emergency_weight`
+    pub fn with_emergency_weight(self, weight: f64) -> Self {
+        let node_list = self.global_resources.into_inner().unwrap();
+        Self {
+            global_resources: RwLock::new(node_list.with_emergency_weight(weight)),
+            ..self
         }
     }
 
@@ -89,12 +654,63 @@ impl Orchestrator {
         self.global_resources
             .write()
             .unwrap()
-            .sort(&mut self.identity);
+            .sort(self.identity.get_mut().unwrap());
+        self.neighbors.invalidate();
     }
 
     /// Get the identity of the node itself
-    pub fn get_identity(&self) -> &Node {
-        &self.identity
+    pub fn get_identity(&self) -> Node {
+        self.identity.read().unwrap().clone()
+    }
+
+    /// Move the node, e.g. a vehicle. The neighbors are ranked again from the new
+    /// position and, during an emergency, whether the node is in its zone is checked again.
+    /// # Returns
+    /// * Whether the node is in the emergency zone
+    pub fn update_own_position(&self, position: (f64, f64)) -> bool {
+        self.identity.write().unwrap().position = Some(position);
+        self.save_nodes();
+        let zone = self
+            .current_emergency
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(_, zone)| zone.clone());
+        let mut in_emergency_area = self.in_emergency_area.lock().unwrap();
+        if let Some(zone) = zone {
+            let inside = zone.contains(position);
+            if inside != *in_emergency_area {
+                match inside {
+                    true => error!("Node moved into the emergency zone"),
+                    false => info!("Node moved out of the emergency zone"),
+                }
+            }
+            *in_emergency_area = inside;
+        }
+        self.neighbors.invalidate();
+        *in_emergency_area
+    }
+
+    /// Move a neighbor, e.g. a vehicle. During an emergency, whether the neighbor is in
+    /// its zone is checked again. Returns false if the neighbor is unknown.
+    /// # Arguments
+    /// * `key` - Id of the neighbor, or its address if it has none
+    /// * `position` - Position of the neighbor as (Longitude, Latitude)
+    pub fn update_neighbor_position(&self, key: &str, position: (f64, f64)) -> bool {
+        let Some(address) = self.members.lock().unwrap().get_mut(key).map(|node| {
+            node.position = Some(position);
+            node.address.clone()
+        }) else {
+            return false;
+        };
+        let updated = self
+            .global_resources
+            .write()
+            .unwrap()
+            .update_position(&address, position);
+        self.neighbors.invalidate();
+        self.save_nodes();
+        updated
     }
 
     /// Get if the node is in the emergency area
@@ -103,24 +719,44 @@ impl Orchestrator {
     }
 
     /// Set the emergency mode
-    pub fn set_emergency(&self, emergency: bool, mut em_pos: Emergency) {
+    /// # Returns
+    /// * The event to record, stopping an emergency reports the zone it was started with
+    pub fn set_emergency(&self, emergency: bool, em_pos: Emergency) -> EmergencyEvent {
         let mut lock = self.global_resources.write().unwrap();
-        if emergency {
+        let event = if emergency {
             info!(
                 "Entering emergency mode. Emergency point: {:?}",
                 em_pos.position
             );
-            lock.set_emergency(em_pos);
-            let radius = em_pos.radius;
-            if self.get_identity().distance(&mut em_pos) <= radius {
+            lock.set_emergency(em_pos.clone());
+            // A node without a position is never inside the zone
+            let inside = self
+                .get_identity()
+                .position
+                .is_some_and(|position| em_pos.contains(position));
+            if inside {
                 error!("Node is in the emergency zone");
                 *self.in_emergency_area.lock().unwrap() = true;
             }
+            let id = uuid::Uuid::new_v4().to_string();
+            let event =
+                EmergencyEvent::new(EmergencyEventType::Start, Some(id.clone()), &em_pos, inside);
+            *self.current_emergency.lock().unwrap() = Some((id, em_pos));
+            event
         } else {
             info!("Leaving emergency mode");
             lock.clear_emergency();
-            *self.in_emergency_area.lock().unwrap() = false;
-        }
+            let inside = std::mem::replace(&mut *self.in_emergency_area.lock().unwrap(), false);
+            match self.current_emergency.lock().unwrap().take() {
+                Some((id, zone)) => {
+                    EmergencyEvent::new(EmergencyEventType::Stop, Some(id), &zone, inside)
+                }
+                None => EmergencyEvent::new(EmergencyEventType::Stop, None, &em_pos, inside),
+            }
+        };
+        drop(lock);
+        self.neighbors.invalidate();
+        event
     }
 
     /// Get the number of available nodes
@@ -151,166 +787,747 @@ impl Orchestrator {
         None
     }
 
-    /// Get the nth node available in the system
-    pub fn get_remote_nth_node(
-        &self,
-        identity: &mut Node,
-        index: usize,
-    ) -> Option<NeighborNodeType> {
-        let mut node_list = self.global_resources.write().unwrap();
-        // Check the strategy
-        match node_list.strategy() {
-            NeighborNodeStrategy::SimpleCellular => {
-                node_list.sort(identity);
-            }
-            NeighborNodeStrategy::SmartLatency => {
-                node_list.sort(identity);
+    /// Get the sorted snapshot of the nodes available for offloading.
+    /// The nodes are sorted again only if they changed since the last snapshot,
+    /// so the write lock on the list is not taken on the offload path.
+    pub fn neighbor_snapshot(&self) -> Arc<Snapshot<NeighborNodeType>> {
+        if self.sort_due() {
+            self.neighbors.invalidate();
+        }
+        self.neighbors.get_or_refresh(|| {
+            let mut node_list = self.global_resources.write().unwrap();
+            // The latencies changed so far are in this sort
+            self.latencies_changed.store(false, Ordering::Release);
+            let identity = self.get_identity();
+            // Check the strategy
+            let nodes = match node_list.strategy() {
+                NeighborNodeStrategy::GeoDistance if !node_list.is_emergency_aware() => {
+                    node_list.nearest_k(identity.position(), usize::MAX, |node| !node.emergency())
+                }
+                _ => {
+                    node_list.sort(&mut identity.clone());
+                    node_list
+                        .nodes
+                        .iter()
+                        .filter(|node| !node.emergency())
+                        .cloned()
+                        .collect()
+                }
+            };
+            let ranking: Vec<_> = nodes
+                .iter()
+                .map(|node| RankedNode::new(node, identity.position(), None))
+                .collect();
+            info!(
+                "Neighbors ranked by {}: {}",
+                node_list.strategy(),
+                ranking::line(&ranking)
+            );
+            nodes
+        })
+    }
+
+    /// Whether the sort policy sorts the neighbors again, although nothing invalidated
+    /// their snapshot: their latencies changed or their estimates expired since
+    fn sort_due(&self) -> bool {
+        let current = self.neighbors.current();
+        let expired = || current.nodes.iter().any(NeighborNodeType::latency_expired);
+        match self.sort_policy {
+            SortPolicy::OnChange => false,
+            SortPolicy::Interval(interval) => {
+                current.built_at.elapsed() >= interval
+                    && (self.latencies_changed.load(Ordering::Acquire) || expired())
             }
-            _ => {} // Already sorted
+            SortPolicy::OnExpiry => expired(),
+        }
+    }
+
+    /// A latency of a neighbor changed: sort the neighbors again at the next offload, or
+    /// when the sort policy says so
+    fn latency_changed(&self) {
+        match self.sort_policy {
+            SortPolicy::OnChange => self.neighbors.invalidate(),
+            _ => self.latencies_changed.store(true, Ordering::Release),
         }
+    }
+
+    /// Get when the neighbors are sorted again for a change of their latencies
+    pub fn sort_policy(&self) -> SortPolicy {
+        self.sort_policy
+    }
 
-        let node = node_list.get_nth(index);
-        match node {
-            Some(node) => {
-                if node.emergency() {
-                    error!("Node is in emergency mode");
-                    return None;
+    /// Get the number of times the neighbors were sorted for the offloads
+    pub fn neighbor_sorts(&self) -> u64 {
+        self.neighbors.refreshes()
+    }
+
+    /// Get the ranking of the neighbors: the ones the offloads try, in order, then the
+    /// ones in the emergency area or draining, with the metric each one is ranked by
+    pub fn ranking(&self) -> Ranking {
+        let snapshot = self.neighbor_snapshot();
+        let position = self.get_identity().position();
+        let draining = self.draining_neighbors.lock().unwrap();
+        let node_list = self.global_resources.read().unwrap();
+        let (excluded, mut nodes): (Vec<_>, Vec<_>) = snapshot
+            .nodes
+            .iter()
+            .map(|node| {
+                let excluded = draining
+                    .contains(&node.address())
+                    .then_some(Exclusion::Draining);
+                RankedNode::new(node, position, excluded)
+            })
+            .partition(|node| node.excluded.is_some());
+        nodes.extend(excluded);
+        nodes.extend(
+            node_list
+                .nodes
+                .iter()
+                .filter(|node| node.emergency())
+                .map(|node| RankedNode::new(node, position, Some(Exclusion::Emergency))),
+        );
+        Ranking {
+            strategy: node_list.strategy().to_string(),
+            emergency_aware: node_list.is_emergency_aware(),
+            nodes,
+        }
+    }
+
+    /// Get the addresses of the nodes to probe, i.e. the ones not in the emergency area
+    pub fn probe_targets(&self) -> Vec<String> {
+        self.global_resources
+            .read()
+            .unwrap()
+            .nodes
+            .iter()
+            .filter(|node| !node.emergency())
+            .map(|node| node.address())
+            .collect()
+    }
+
+    /// Set the latency of the given nodes, as (address, latency)
+    pub fn update_latencies(&self, latencies: &[(String, f64)]) {
+        let mut node_list = self.global_resources.write().unwrap();
+        let mut changed = false;
+        for node in node_list.nodes.iter_mut() {
+            if let NeighborNodeType::Latency(node) = node {
+                if let Some((_, latency)) = latencies.iter().find(|(a, _)| *a == node.address()) {
+                    let last = node.last_latency();
+                    node.update_latency(*latency);
+                    changed |= node.last_latency() != last;
                 }
-                Some(node)
-            }
-            None => {
-                error!("Node not found");
-                None
             }
         }
+        drop(node_list);
+        // An estimated latency ignores the measured ones, the order is the same
+        if changed {
+            self.latency_changed();
+        }
+    }
+
+    /// Get the nth node available in the system
+    pub fn get_remote_nth_node(&self, index: usize) -> Option<NeighborNodeType> {
+        let node = self.neighbor_snapshot().nodes.get(index).cloned();
+        if node.is_none() {
+            error!("Node not found");
+        }
+        node
     }
 
     /// Get the resources available in the node
     pub fn get_resources(&self) -> Resources {
+        let snapshot = self.resources_snapshot();
         Resources {
-            cpus: self.resources.read().unwrap().get_available_cpus(),
+            cpus: snapshot.available_cpus,
             memory: LocalResources::get_available_memory(),
+            overlay_disk_usage: 0,
+            service_time: self.scheduler.calibrated_service_time(),
+            total_cpus: snapshot.total_cpus,
+            total_memory: snapshot.total_memory,
+            running_instances: self.drain.running(),
+            queued_requests: self.scheduler.local_estimate().queued,
+            has_image: None,
+            free_addresses: None,
+        }
+    }
+
+    /// Get the totals and the available resources of the node, with the accounting drift
+    pub fn resources_snapshot(&self) -> ResourcesSnapshot {
+        self.resources.read().unwrap().snapshot()
+    }
+
+    /// Get the resources the node can take for a request of the given class,
+    /// i.e. without the reserve unless it is an emergency one.
+    /// A draining node has nothing to offer to any request.
+    pub fn usable_resources(&self, emergency: bool) -> Resources {
+        let resources = self.get_resources();
+        let (cpus, memory) =
+            match self.drain.is_draining() || self.memory_pressure.is_under_pressure() {
+                true => (0, 0),
+                false => self
+                    .reserve
+                    .usable(resources.cpus, resources.memory, emergency),
+            };
+        Resources {
+            cpus,
+            memory,
+            ..resources
+        }
+    }
+
+    /// Check that a neighbor can answer a request before its deadline, if it has one
+    fn remote_deadline(
+        &self,
+        data: &InvokeFunction,
+        node: &NeighborNodeType,
+    ) -> Result<(), Doomed> {
+        let Some(deadline) = data.deadline else {
+            return Ok(());
+        };
+        let remote = RemoteEstimate {
+            latency: node.known_latency(),
+            service_time: self.scheduler.remote_service_time(&node.address()),
+        };
+        self.scheduler.model().remote_deadline(
+            deadline.saturating_duration_since(Instant::now()),
+            &remote,
+            &self.scheduler.local_estimate(),
+        )
+    }
+
+    /// Decide whether a request that cannot run right away waits for the local
+    /// resources or is offloaded, comparing its estimated completion time on this
+    /// node with the one on the first node it would be offloaded to.
+    /// # Arguments
+    /// * `data` - The request
+    /// * `origin` - Address of the node that sent the request, if known
+    pub fn schedule(&self, data: &InvokeFunction, origin: Option<IpAddr>) -> Decision {
+        let local = self.scheduler.local_estimate();
+        let remote = self
+            .offload_order(data, origin)
+            .first()
+            .map(|node| RemoteEstimate {
+                latency: node.known_latency(),
+                service_time: self.scheduler.remote_service_time(&node.address()),
+            });
+        let decision = self.scheduler.model().decide(&local, remote.as_ref());
+        info!(
+            "Scheduling with {} queued requests and remote estimate {:?}: {:?}",
+            local.queued, remote, decision
+        );
+        decision
+    }
+
+    /// Hold a request back while it would take the cpus a larger waiting request needs,
+    /// for at most the hold of the packing. The emergency requests are never held back.
+    /// # Arguments
+    /// * `cpus` - Number of cpus of the request
+    /// * `emergency` - Whether the request is an emergency one
+    pub async fn hold_for_larger(&self, cpus: usize, emergency: bool) {
+        let Some(packing) = self.packing.as_ref().filter(|_| !emergency) else {
+            return;
+        };
+        let start = Instant::now();
+        let mut held = false;
+        while start.elapsed() < packing.hold() && packing.defers(cpus, self.usable_cpus(false)) {
+            held = true;
+            actix_web::rt::time::sleep(WAIT_POLL_INTERVAL).await;
+        }
+        if held {
+            info!(
+                "Held {} cpus back for {} ms",
+                cpus,
+                start.elapsed().as_millis()
+            );
+            packing.record_deferral(start.elapsed());
+        }
+    }
+
+    /// Wait for the resources of a request to be available and acquire them
+    /// # Arguments
+    /// * `cpus` - Number of cpus to acquire
+    /// * `memory` - Amount of memory to acquire in KB
+    /// * `timeout` - Longest time to wait
+    /// # Errors
+    /// * InsufficientResources if the resources are still not available after `timeout`
+    pub async fn wait_for_resources(
+        &self,
+        cpus: usize,
+        memory: usize,
+        emergency: bool,
+        timeout: Duration,
+    ) -> Result<(), OrchestratorError> {
+        let _queued = self.scheduler.enqueue();
+        let _waiting = self.packing.as_ref().map(|packing| packing.wait(cpus));
+        let start = Instant::now();
+        loop {
+            if self.try_acquire_resources(cpus, memory, emergency) {
+                info!(
+                    "Acquired {} cpus after waiting {} ms",
+                    cpus,
+                    start.elapsed().as_millis()
+                );
+                return Ok(());
+            }
+            if start.elapsed() >= timeout {
+                warn!(
+                    "Resources still unavailable after {} ms",
+                    timeout.as_millis()
+                );
+                if let Some(packing) = &self.packing {
+                    packing.record_gave_up(cpus, self.usable_cpus(emergency));
+                }
+                return Err(OrchestratorError::InsufficientResources);
+            }
+            actix_web::rt::time::sleep(WAIT_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Get the cpus and the memory a request of the given class can take out of the
+    /// available ones. Only the emergency requests still run on a draining node.
+    fn usable(&self, cpus: usize, memory: usize, emergency: bool) -> (usize, usize) {
+        let admitting = !self.drain.is_draining() && !self.memory_pressure.is_under_pressure();
+        if !admitting && !emergency {
+            return (0, 0);
+        }
+        self.reserve.usable(cpus, memory, emergency)
+    }
+
+    /// Get the cpus a request of the given class can take now
+    fn usable_cpus(&self, emergency: bool) -> usize {
+        let available = self.resources.read().unwrap().get_available_cpus();
+        self.usable(available, 0, emergency).0
+    }
+
+    /// Acquire the resources if they are available, without logging
+    fn try_acquire_resources(&self, cpus: usize, memory: usize, emergency: bool) -> bool {
+        let available_memory = LocalResources::get_available_memory();
+        let mut resources = self.resources.write().unwrap();
+        let (usable_cpus, usable_memory) =
+            self.usable(resources.get_available_cpus(), available_memory, emergency);
+        let acquired = cpus <= usable_cpus
+            && memory <= usable_memory
+            && resources.acquire(cpus, memory).is_ok();
+        if acquired {
+            self.packing_acquired(cpus);
+        }
+        acquired
+    }
+
+    /// Record the cpus taken by an instance in the packing, if enabled
+    fn packing_acquired(&self, cpus: usize) {
+        if let Some(packing) = &self.packing {
+            packing.acquired(cpus);
         }
     }
 
-    /// Method to offload a function to a remote node
+    /// Get the nodes to try, in order, when offloading a request.
+    /// Nodes in the emergency area, draining nodes and the node the request comes
+    /// from are skipped.
+    /// # Arguments
+    /// * `origin` - Address of the node that sent the request, if known
+    pub fn offload_candidates(&self, origin: Option<IpAddr>) -> Vec<NeighborNodeType> {
+        let draining = self.draining_neighbors.lock().unwrap();
+        self.neighbor_snapshot()
+            .nodes
+            .iter()
+            // Do not forward request to origin, the node on the same address only
+            .filter(|node| match origin {
+                Some(origin) => !node
+                    .address()
+                    .parse::<SocketAddr>()
+                    .is_ok_and(|address| address.ip().to_canonical() == origin.to_canonical()),
+                None => true,
+            })
+            .filter(|node| !draining.contains(&node.address()))
+            .cloned()
+            .collect()
+    }
+
+    /// Get the nodes to try, in order, when offloading the given request.
+    /// With sticky offload the preferred neighbor of the function comes first,
+    /// the others follow in the order of `offload_candidates`.
+    pub fn offload_order(
+        &self,
+        data: &InvokeFunction,
+        origin: Option<IpAddr>,
+    ) -> Vec<NeighborNodeType> {
+        let mut candidates = self.offload_candidates(origin);
+        if self.sticky {
+            sticky::prefer(&data.function, &data.image, &mut candidates);
+        }
+        candidates
+    }
+
+    /// Method to offload a function to a remote node.
+    /// With the traces enabled, the decisions are recorded and the identifier of their
+    /// trace is attached to the response.
+    /// # Arguments
+    /// * `data` - The request
+    /// * `raw` - The body of the request as it was received, if it can be forwarded as it is
+    /// * `origin` - Address of the node that sent the request, if known
+    /// # Returns
+    /// * The response to send back and where the request went
     pub async fn offload(
         &self,
         data: web::Json<InvokeFunction>,
-        req: HttpRequest,
-    ) -> HttpResponse<BoxBody> {
+        raw: Option<web::Bytes>,
+        origin: Option<IpAddr>,
+    ) -> (HttpResponse<BoxBody>, RequestOutcome) {
         let cpus = data.vcpus;
         let memory = data.memory;
+        // Encoded once, every node tried shares the same buffer
+        let body = match raw {
+            Some(raw) if self.raw_offload => raw,
+            _ => data.forwarded_body(),
+        };
 
         // Iterate over the nodes
         warn!("Function must be offloaded");
-        for i in 0..self.number_of_nodes() {
-            warn!("Checking node: {}", i);
-            match self.get_remote_nth_node(&mut self.identity.clone(), i) {
-                Some(node) => {
-                    // Do not forward request to origin
-                    if node
-                        .address()
-                        .contains(req.peer_addr().unwrap().ip().to_string().as_str())
-                    {
+        // Only the emergency requests can take the reserve of the node, and the neighbors
+        // tell whether they have the image
+        let resources_query = ResourcesQuery {
+            emergency: data.emergency,
+            image: Some(data.image.clone()),
+            image_digest: data.image_digest.clone(),
+            function: Some(data.function.clone()),
+        };
+        let mut candidates = self.offload_order(&data, origin);
+        let mut recording = Recording::start(self.traces.as_ref(), || {
+            let identity = self.get_identity();
+            let candidates = candidates
+                .iter_mut()
+                .map(|node| Candidate {
+                    address: node.address(),
+                    distance: identity.distance(node),
+                    latency: node.known_latency(),
+                })
+                .collect();
+            OffloadTrace::new(
+                &data.function,
+                data.hops,
+                data.emergency,
+                origin.map(|ip| ip.to_string()),
+                self.get_strategy().to_string(),
+                self.sticky,
+                candidates,
+            )
+        });
+        // Each node comes with whether it was already put back for being busy
+        let mut nodes: VecDeque<(NeighborNodeType, bool)> =
+            candidates.into_iter().map(|node| (node, false)).collect();
+        // Whether a fault was injected in the offload
+        let mut injected = false;
+        // Neighbors skipped for lacking capabilities the function requires
+        let mut incapable = 0;
+        // Neighbors skipped for not having the image
+        let mut imageless = 0;
+        // Neighbors skipped for not answering before the deadline of the request
+        let mut doomed = 0;
+        while let Some((node, deferred)) = nodes.pop_front() {
+            let missing = self.missing_capabilities(&data.function, &node.address());
+            if !missing.is_empty() {
+                info!(
+                    "Not offloading {} to {}, it lacks {}",
+                    data.function,
+                    node.address(),
+                    missing.join(", ")
+                );
+                incapable += 1;
+                recording.attempt(|| Attempt {
+                    address: node.address(),
+                    probe: Probe::Skipped,
+                    outcome: AttemptOutcome::MissingCapabilities { missing },
+                });
+                continue;
+            }
+            if let Err(late) = self.remote_deadline(&data, &node) {
+                info!(
+                    "Not offloading {} to {}, it would answer too late: {}",
+                    data.function,
+                    node.address(),
+                    late
+                );
+                doomed += 1;
+                recording.attempt(|| Attempt {
+                    address: node.address(),
+                    probe: Probe::Skipped,
+                    outcome: AttemptOutcome::DeadlineExceeded {
+                        budget_ms: late.budget as u64,
+                        expected_ms: late.expected as u64,
+                    },
+                });
+                continue;
+            }
+            if self
+                .chaos()
+                .is_some_and(|chaos| chaos.blackholed(&node.address()))
+            {
+                injected = true;
+                recording.attempt(|| Attempt {
+                    address: node.address(),
+                    probe: Probe::Error("Blackholed by a chaos injection".to_string()),
+                    outcome: AttemptOutcome::Unreachable,
+                });
+                continue;
+            }
+            // Check if resource are available on the remote node
+            let response = match self
+                .client
+                .client()
+                .get(self.client.url(&node.address(), "/resources"))
+                .query(&resources_query)
+            {
+                Ok(request) => request.send().await.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            let remote_resources = match response {
+                Ok(mut response) => response
+                    .json::<api::resources::Resources>()
+                    .await
+                    .map_err(|e| e.to_string()),
+                Err(e) => Err(e),
+            };
+            let remote_resources = match remote_resources {
+                Ok(remote_resources) => remote_resources,
+                Err(e) => {
+                    // Cannot get resources from remote node, continue
+                    recording.attempt(|| Attempt {
+                        address: node.address(),
+                        probe: Probe::Error(e),
+                        outcome: AttemptOutcome::Unreachable,
+                    });
+                    continue;
+                }
+            };
+            if let Some(service_time) = remote_resources.service_time {
+                self.scheduler
+                    .set_remote_service_time(&node.address(), service_time);
+            }
+            let probe = || Probe::Resources {
+                cpus: remote_resources.cpus,
+                memory: remote_resources.memory,
+                queued_requests: remote_resources.queued_requests,
+            };
+            // Check if resources are available
+            let cpus = remote_resources.cpus.checked_sub(cpus as usize);
+            // Memory is in MB, so multiply by 1024
+            let memory = remote_resources
+                .memory
+                .checked_sub((memory * 1024) as usize);
+            // A node without addresses left cannot start the instance either
+            if cpus.is_none() || memory.is_none() || remote_resources.free_addresses == Some(0) {
+                recording.attempt(|| Attempt {
+                    address: node.address(),
+                    probe: probe(),
+                    outcome: AttemptOutcome::Insufficient,
+                });
+                continue;
+            }
+            // A node without the image would have to fetch it, or would fail the request
+            if remote_resources.has_image == Some(false) {
+                match self.image_affinity {
+                    ImageAffinity::Require => {
+                        info!(
+                            "Not offloading {} to {}, it does not have {}",
+                            data.function,
+                            node.address(),
+                            data.image
+                        );
+                        imageless += 1;
+                        recording.attempt(|| Attempt {
+                            address: node.address(),
+                            probe: probe(),
+                            outcome: AttemptOutcome::MissingImage,
+                        });
+                        continue;
+                    }
+                    // Deferred when some other node is left to try, the probe is not repeated
+                    ImageAffinity::Prefer if !deferred && !nodes.is_empty() => {
+                        recording.attempt(|| Attempt {
+                            address: node.address(),
+                            probe: probe(),
+                            outcome: AttemptOutcome::Deferred,
+                        });
+                        nodes.push_back((node, true));
                         continue;
                     }
+                    ImageAffinity::Prefer => {}
+                }
+            }
+            // A node with requests already waiting for its resources is
+            // tried again after the others, which may be idle
+            if remote_resources.queued_requests > 0 && !deferred {
+                recording.attempt(|| Attempt {
+                    address: node.address(),
+                    probe: probe(),
+                    outcome: AttemptOutcome::Deferred,
+                });
+                nodes.push_back((node, true));
+                continue;
+            }
+            // If resources are available, forward request
+            let address = node.address();
+            warn!("Forwarding request to {}", address);
 
-                    // Check if resource are available on the remote node
-                    let client = Client::default();
-                    let response = client
-                        .get(format!("http://{}/resources", node.address()))
-                        .send()
-                        .await;
-                    if response.is_ok() {
-                        let remote_resources =
-                            response.unwrap().json::<api::resources::Resources>().await;
-                        if remote_resources.is_err() {
-                            // Cannot get resources from remote node, continue
-                            continue;
-                        }
-                        match remote_resources {
-                            Ok(remote_resources) => {
-                                // Check if resources are available
-                                let cpus = remote_resources.cpus.checked_sub(cpus as usize);
-                                // Memory is in MB, so multiply by 1024
-                                let memory = remote_resources
-                                    .memory
-                                    .checked_sub((memory * 1024) as usize);
-                                // If resources are available, forward request
-                                if cpus.is_some() && memory.is_some() {
-                                    warn!("Forwarding request to {}", node.address());
-
-                                    let start = Instant::now();
-                                    let body = node.invoke(data.clone()).await;
-                                    let elapsed = start.elapsed();
-
-                                    info!(
-                                        "Request to {} took: {} ms",
-                                        node.address(),
-                                        elapsed.as_millis()
-                                    );
-
-                                    match body {
-                                        Ok(body) => {
-                                            error!(
-                                                "Successfully forwarded request to {}",
-                                                node.address()
-                                            );
-                                            // If the chosen sttrategy is latency-based, update the latency
-                                            // of the node
-                                            match node {
-                                                NeighborNodeType::Latency(node) => {
-                                                    let mut node_list =
-                                                        self.global_resources.write().unwrap();
-                                                    let n_ref = self
-                                                        .contains(
-                                                            &mut NeighborNodeType::Latency(node),
-                                                            &mut node_list,
-                                                        )
-                                                        .unwrap();
-                                                    match n_ref {
-                                                        NeighborNodeType::Latency(n_ref) => {
-                                                            n_ref.update_latency(
-                                                                elapsed.as_millis() as f64,
-                                                            );
-                                                        }
-                                                        _ => {}
-                                                    }
-                                                }
-
-                                                _ => {}
-                                            }
-                                            return HttpResponse::Ok().body(body);
-                                        }
-                                        Err(e) => {
-                                            error!(
-                                                "Failed to forward request to {}, error: {}!",
-                                                node.address(),
-                                                e
-                                            );
-                                            continue;
-                                        }
-                                    }
-                                }
+            let start = Instant::now();
+            if let Some(delay) = self
+                .chaos()
+                .map(|chaos| chaos.offload_latency(&address))
+                .filter(|delay| !delay.is_zero())
+            {
+                injected = true;
+                actix_web::rt::time::sleep(delay).await;
+            }
+            let answer = node
+                .invoke(
+                    &self.client,
+                    &self.get_identity().address,
+                    data.hops + 1,
+                    data.deadline
+                        .map(|deadline| deadline.saturating_duration_since(Instant::now())),
+                    body.clone(),
+                )
+                .await;
+            let elapsed = start.elapsed();
+
+            info!(
+                "Request to {} took: {} ms",
+                node.address(),
+                elapsed.as_millis()
+            );
+
+            match answer {
+                Ok((body, headers)) => {
+                    error!("Successfully forwarded request to {}", node.address());
+                    recording.attempt(|| Attempt {
+                        address: address.clone(),
+                        probe: probe(),
+                        outcome: AttemptOutcome::Forwarded {
+                            elapsed_ms: elapsed.as_millis() as u64,
+                        },
+                    });
+                    // If the chosen sttrategy is latency-based, update the latency
+                    // of the node. Probed latencies come from the probes only.
+                    match node {
+                        NeighborNodeType::Latency(node)
+                            if self.get_strategy() != NeighborNodeStrategy::Probed =>
+                        {
+                            let mut node_list = self.global_resources.write().unwrap();
+                            let n_ref =
+                                self.contains(&mut NeighborNodeType::Latency(node), &mut node_list);
+                            let mut changed = false;
+                            // The node may have left, or moved to another address, while
+                            // the request was forwarded: its latency is then dropped
+                            if let Some(NeighborNodeType::Latency(n_ref)) = n_ref {
+                                let last = n_ref.last_latency();
+                                n_ref.update_latency(elapsed.as_millis() as f64);
+                                changed = n_ref.last_latency() != last;
                             }
-                            Err(_) => {
-                                // Cannot get resources from remote node, continue
-                                continue;
+                            drop(node_list);
+                            // The new latency may change the order of the nodes
+                            if changed {
+                                self.latency_changed();
                             }
                         }
+
+                        _ => {}
                     }
+                    let mut response = HttpResponse::Ok().body(body);
+                    // The node that ran the function tells where it ran
+                    invoke::copy_served(&headers, &mut response);
+                    recording.finish(Some(&address), &mut response);
+                    if injected {
+                        chaos::mark(&mut response);
+                    }
+                    return (response, RequestOutcome::OffloadedTo(address));
+                }
+                // The node is already running a request with the same
+                // idempotency key, trying another node would run it twice
+                Err(InvokeError::Status(StatusCode::CONFLICT))
+                    if data.idempotency_key.is_some() =>
+                {
+                    recording.attempt(|| Attempt {
+                        address: address.clone(),
+                        probe: probe(),
+                        outcome: AttemptOutcome::Conflict,
+                    });
+                    let mut response = HttpResponse::Conflict().body(format!(
+                        "A request with the same idempotency key is still running on {}\n",
+                        address
+                    ));
+                    recording.finish(None, &mut response);
+                    if injected {
+                        chaos::mark(&mut response);
+                    }
+                    return (response, RequestOutcome::Rejected);
+                }
+                // The neighbor shed the request, it cannot answer in time either
+                Err(InvokeError::Status(StatusCode::GATEWAY_TIMEOUT))
+                    if data.deadline.is_some() =>
+                {
+                    doomed += 1;
+                    recording.attempt(|| Attempt {
+                        address,
+                        probe: probe(),
+                        outcome: AttemptOutcome::Failed {
+                            error: "Deadline exceeded".to_string(),
+                        },
+                    });
+                    continue;
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to forward request to {}, error: {}!",
+                        node.address(),
+                        e
+                    );
+                    recording.attempt(|| Attempt {
+                        address,
+                        probe: probe(),
+                        outcome: AttemptOutcome::Failed {
+                            error: e.to_string(),
+                        },
+                    });
+                    continue;
                 }
-                None => break,
             }
         }
-        return HttpResponse::InternalServerError().body("Insufficient resources\n");
+        // Nobody else could take the request, it is not that it came too late
+        if doomed > 0 {
+            let mut response = HttpResponse::GatewayTimeout().body(format!(
+                "Deadline exceeded, {} neighbors cannot answer {} in time\n",
+                doomed, data.function
+            ));
+            recording.finish(None, &mut response);
+            if injected {
+                chaos::mark(&mut response);
+            }
+            return (response, RequestOutcome::Shed);
+        }
+        let mut response = match (incapable, imageless) {
+            (0, 0) => HttpResponse::InternalServerError().body("Insufficient resources\n"),
+            (0, imageless) => HttpResponse::InternalServerError().body(format!(
+                "Insufficient resources, {} neighbors do not have the image of {}\n",
+                imageless, data.function
+            )),
+            (incapable, _) => HttpResponse::InternalServerError().body(format!(
+                "Insufficient resources, {} neighbors lack the capabilities required by {}\n",
+                incapable, data.function
+            )),
+        };
+        recording.finish(None, &mut response);
+        if injected {
+            chaos::mark(&mut response);
+        }
+        (response, RequestOutcome::Rejected)
     }
 
     /// Check if the resources are available and acquire them
     /// # Arguments
     /// * `cpus` - Number of cpus to acquire
     /// * `memory` - Amount of memory to acquire in KB
+    /// * `emergency` - Whether the request is an emergency one, that can use the reserve
     /// # Returns
     /// * Ok if the resources are available, Err otherwise
     /// # Errors
@@ -319,8 +1536,11 @@ impl Orchestrator {
         &self,
         cpus: usize,
         memory: usize,
+        emergency: bool,
     ) -> Result<(), OrchestratorError> {
         info!("Requested {} cpus and {} MB", cpus, memory / 1024);
+        // Read before taking the lock, which every request waits for
+        let available_memory = LocalResources::get_available_memory();
         let mut current_resources = match self.resources.write() {
             Ok(resources) => resources,
             Err(_) => {
@@ -328,23 +1548,23 @@ impl Orchestrator {
                 return Err(OrchestratorError::CannotAcquireResources);
             }
         };
+        let (usable_cpus, usable_memory) = self.usable(
+            current_resources.get_available_cpus(),
+            available_memory,
+            emergency,
+        );
 
-        if cpus > current_resources.get_available_cpus() {
-            warn!(
-                "Insufficient cpus: {}",
-                current_resources.get_available_cpus()
-            );
+        if cpus > usable_cpus {
+            warn!("Insufficient cpus: {}", usable_cpus);
             return Err(OrchestratorError::InsufficientResources);
         }
 
-        if memory > LocalResources::get_available_memory() {
-            warn!(
-                "Insufficient memory: {}",
-                LocalResources::get_available_memory()
-            );
+        if memory > usable_memory {
+            warn!("Insufficient memory: {}", usable_memory);
             return Err(OrchestratorError::InsufficientResources);
         }
-        current_resources.acquire_cpus(cpus)?;
+        current_resources.acquire(cpus, memory)?;
+        self.packing_acquired(cpus);
 
         info!("Acquired {} cpus and {} MB", cpus, memory / 1024);
 
@@ -352,8 +1572,819 @@ impl Orchestrator {
     }
 
     /// Release the resources
-    pub fn release_resources(&self, cpus: usize) -> Result<(), OrchestratorError> {
-        info!("Releasing {} cpus", cpus);
-        self.resources.write().unwrap().release_cpus(cpus)
+    /// # Arguments
+    /// * `cpus` - Number of cpus to release
+    /// * `memory` - Amount of memory to release in KB, as it was acquired
+    pub fn release_resources(&self, cpus: usize, memory: usize) -> Result<(), OrchestratorError> {
+        info!("Releasing {} cpus and {} MB", cpus, memory / 1024);
+        if let Some(packing) = &self.packing {
+            packing.released(cpus);
+        }
+        self.resources.write().unwrap().release(cpus, memory)
+    }
+
+    /// Acquire at once the resources of a batch of requests, given as
+    /// (cpus, memory, emergency), only the emergency ones can use the reserve.
+    /// Requests are admitted in order as long as what is left fits them, so a large
+    /// request does not keep the smaller ones after it from running locally, and
+    /// requests outside the batch cannot take the resources in the middle of it.
+    /// # Returns
+    /// * Whether each request was admitted, the resources of each admitted request
+    ///   must be released with `release_resources`
+    pub fn acquire_batch(&self, requests: &[(usize, usize, bool)]) -> Vec<bool> {
+        let mut memory = LocalResources::get_available_memory();
+        let mut resources = self.resources.write().unwrap();
+        let admitted: Vec<bool> = requests
+            .iter()
+            .map(|&(cpus, needed, emergency)| {
+                let (usable_cpus, usable_memory) =
+                    self.usable(resources.get_available_cpus(), memory, emergency);
+                if cpus > usable_cpus || needed > usable_memory {
+                    return false;
+                }
+                if resources.acquire(cpus, needed).is_err() {
+                    return false;
+                }
+                self.packing_acquired(cpus);
+                memory -= needed;
+                true
+            })
+            .collect();
+        info!(
+            "Admitted {} of {} requests of the batch",
+            admitted.iter().filter(|a| **a).count(),
+            requests.len()
+        );
+        admitted
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn orchestrator() -> Orchestrator {
+        // Milan, with neighbors at increasing distance
+        let nodes = vec![
+            Node::new("10.0.0.3:8085".to_string(), (40.7128, 74.0060)),
+            Node::new("10.0.0.1:8085".to_string(), (45.4642, 9.1900)),
+            Node::new("10.0.0.2:8085".to_string(), (48.8575, 2.3514)),
+        ];
+        Orchestrator::new(
+            nodes,
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        )
+    }
+
+    fn addresses(nodes: Vec<NeighborNodeType>) -> Vec<String> {
+        nodes.iter().map(|node| node.address()).collect()
+    }
+
+    fn invoke_function(function: &str) -> InvokeFunction {
+        serde_json::from_value(serde_json::json!({
+            "function": function,
+            "image": "image",
+            "vcpus": 1,
+            "memory": 128,
+            "payload": null,
+            "emergency": false,
+            "hops": 0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_offload_order() {
+        let orchestrator = orchestrator();
+        let ranking = addresses(orchestrator.offload_candidates(None));
+        let functions: Vec<InvokeFunction> =
+            (0..20).map(|f| invoke_function(&f.to_string())).collect();
+        for data in &functions {
+            // The preferred neighbor comes first, the others keep their ranking
+            let order = addresses(orchestrator.offload_order(data, None));
+            assert_eq!(order, addresses(orchestrator.offload_order(data, None)));
+            let rest: Vec<&String> = ranking.iter().filter(|a| **a != order[0]).collect();
+            assert_eq!(order[1..].iter().collect::<Vec<_>>(), rest);
+        }
+        // Different functions are spread over the neighbors
+        let first: std::collections::HashSet<String> = functions
+            .iter()
+            .map(|data| addresses(orchestrator.offload_order(data, None)).remove(0))
+            .collect();
+        assert!(first.len() > 1);
+
+        let orchestrator = orchestrator.with_sticky_offload(false);
+        for data in &functions {
+            assert_eq!(addresses(orchestrator.offload_order(data, None)), ranking);
+        }
+    }
+
+    #[test]
+    fn test_offload_candidates() {
+        let orchestrator = orchestrator();
+        assert_eq!(orchestrator.number_of_nodes(), 3);
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.1:8085", "10.0.0.2:8085", "10.0.0.3:8085"]
+        );
+        assert!(orchestrator.get_remote_nth_node(3).is_none());
+    }
+
+    #[test]
+    fn test_neighbor_snapshot() {
+        let orchestrator = orchestrator();
+        let first = orchestrator.neighbor_snapshot();
+        assert!(Arc::ptr_eq(&first, &orchestrator.neighbor_snapshot()));
+
+        // An emergency changes the available nodes, so the snapshot is rebuilt
+        orchestrator.set_emergency(
+            true,
+            Emergency {
+                position: (45.4642, 9.1900),
+                radius: 100.0,
+                polygon: None,
+            },
+        );
+        let second = orchestrator.neighbor_snapshot();
+        assert!(second.generation > first.generation);
+        assert_eq!(first.nodes.len(), 3);
+        assert_eq!(second.nodes.len(), 2);
+    }
+
+    #[test]
+    fn test_sort_policy() {
+        let probed = |policy: SortPolicy| {
+            let nodes = (1..=3)
+                .map(|i| Node::new(format!("10.0.0.{i}:8085"), (45.4642, 9.19)))
+                .collect();
+            let identity = Node::new("10.0.0.0:8085".to_string(), (45.4642, 9.19));
+            Orchestrator::with_strategy(nodes, identity, NeighborNodeStrategy::Probed)
+                .with_sort_policy(policy)
+        };
+        let faster = |orchestrator: &Orchestrator, address: &str, latency: f64| {
+            orchestrator.update_latencies(&[(address.to_string(), latency)]);
+            addresses(orchestrator.offload_candidates(None))
+        };
+
+        // Every new latency is sorted in at the next offload
+        let orchestrator = probed(SortPolicy::OnChange);
+        assert_eq!(
+            faster(&orchestrator, "10.0.0.3:8085", 5.0)[0],
+            "10.0.0.3:8085"
+        );
+        assert_eq!(
+            faster(&orchestrator, "10.0.0.2:8085", 1.0)[0],
+            "10.0.0.2:8085"
+        );
+        let sorts = orchestrator.neighbor_sorts();
+        // The same latency again leaves the order alone
+        faster(&orchestrator, "10.0.0.2:8085", 1.0);
+        assert_eq!(orchestrator.neighbor_sorts(), sorts);
+
+        // The order is kept until the interval elapses, whatever the latencies do
+        let orchestrator = probed(SortPolicy::Interval(Duration::from_secs(3600)));
+        let order = addresses(orchestrator.offload_candidates(None));
+        let sorts = orchestrator.neighbor_sorts();
+        for (i, address) in order.iter().rev().enumerate() {
+            assert_eq!(faster(&orchestrator, address, i as f64 + 1.0), order);
+        }
+        assert_eq!(orchestrator.neighbor_sorts(), sorts);
+        // A node leaving is not held back, and neither are the latencies then
+        assert!(orchestrator.remove_node(&order[0]));
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            [order[2].clone(), order[1].clone()]
+        );
+
+        // Once it elapsed, the latencies changed since are sorted in once
+        let orchestrator = probed(SortPolicy::Interval(Duration::ZERO));
+        assert_eq!(
+            faster(&orchestrator, "10.0.0.3:8085", 5.0)[0],
+            "10.0.0.3:8085"
+        );
+        let sorts = orchestrator.neighbor_sorts();
+        orchestrator.offload_candidates(None);
+        assert_eq!(orchestrator.neighbor_sorts(), sorts);
+
+        // No estimate expires for the measured latencies
+        let orchestrator = probed(SortPolicy::OnExpiry);
+        let order = addresses(orchestrator.offload_candidates(None));
+        assert_eq!(faster(&orchestrator, &order[2], 1.0), order);
+    }
+
+    #[test]
+    fn test_positionless() {
+        let nodes = vec![
+            Node::new("10.0.0.1:8085".to_string(), (45.4642, 9.19)),
+            Node::new("10.0.0.2:8085".to_string(), (0.0, 0.0)).with_position(None),
+            Node::new("10.0.0.3:8085".to_string(), (45.0703, 7.6869)),
+        ];
+        let identity = Node::new("10.0.0.0:8085".to_string(), (0.0, 0.0)).with_position(None);
+        let orchestrator =
+            Orchestrator::with_strategy(nodes, identity, NeighborNodeStrategy::Probed)
+                .with_emergency_weight(0.5);
+        orchestrator.update_latencies(&[
+            ("10.0.0.1:8085".to_string(), 30.0),
+            ("10.0.0.2:8085".to_string(), 10.0),
+            ("10.0.0.3:8085".to_string(), 20.0),
+        ]);
+        let by_latency = vec!["10.0.0.2:8085", "10.0.0.3:8085", "10.0.0.1:8085"];
+        assert_eq!(addresses(orchestrator.offload_candidates(None)), by_latency);
+
+        // Neither the node nor its neighbor without a position is inside the zone, and
+        // the others are still ranked by latency, not away from the emergency
+        let event = orchestrator.set_emergency(
+            true,
+            Emergency {
+                position: (45.4642, 9.19),
+                radius: 1000.0,
+                polygon: None,
+            },
+        );
+        assert!(!event.inside);
+        assert!(!orchestrator.in_emergency_area());
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.2:8085", "10.0.0.3:8085"]
+        );
+
+        // The ranking tells the distances it cannot compute apart
+        let ranking = serde_json::to_value(orchestrator.ranking()).unwrap();
+        assert_eq!(ranking["nodes"].as_array().unwrap().len(), 3);
+        let identity = serde_json::to_value(orchestrator.get_identity()).unwrap();
+        assert!(identity["position"].is_null());
+    }
+
+    #[test]
+    fn test_emergency_polygon() {
+        // A district along a river, the node is on its boundary
+        let zone = Emergency::polygon(vec![
+            (45.46, 9.18),
+            (45.46, 9.20),
+            (45.47, 9.20),
+            (45.47, 9.18),
+        ])
+        .unwrap();
+        let nodes = vec![
+            Node::new("10.0.0.1:8085".to_string(), (45.465, 9.19)),
+            Node::new("10.0.0.2:8085".to_string(), (45.47, 9.19)),
+            Node::new("10.0.0.3:8085".to_string(), (45.475, 9.19)),
+        ];
+        let identity = Node::new("10.0.0.0:8085".to_string(), (45.46, 9.18));
+        let orchestrator =
+            Orchestrator::with_strategy(nodes, identity, NeighborNodeStrategy::GeoDistance);
+        let event = orchestrator.set_emergency(true, zone);
+        assert!(event.inside);
+        assert!(orchestrator.in_emergency_area());
+        // The neighbors inside and on the edge are not offloaded to
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.3:8085"]
+        );
+
+        // Within the circle around the vertices, but out of the polygon
+        assert!(!orchestrator.update_own_position((45.4555, 9.19)));
+        assert!(orchestrator.update_own_position((45.4601, 9.19)));
+    }
+
+    #[test]
+    fn test_offload_candidates_indexed() {
+        let orchestrator = orchestrator().with_index_threshold(0);
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.1:8085", "10.0.0.2:8085", "10.0.0.3:8085"]
+        );
+    }
+
+    #[test]
+    fn test_offload_candidates_skip_origin() {
+        let orchestrator = orchestrator();
+        let origin = "10.0.0.2".parse().ok();
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(origin)),
+            vec!["10.0.0.1:8085", "10.0.0.3:8085"]
+        );
+
+        // Only the node on the address of the origin is skipped, not the ones whose
+        // address starts the same
+        let nodes = ["10.0.0.1:8085", "10.0.0.10:8085", "10.0.0.12:8086"]
+            .map(|address| Node::new(address.to_string(), (45.4642, 9.1900)));
+        let orchestrator = Orchestrator::new(
+            nodes.to_vec(),
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        );
+        let mut candidates = addresses(orchestrator.offload_candidates("10.0.0.1".parse().ok()));
+        candidates.sort();
+        assert_eq!(candidates, vec!["10.0.0.10:8085", "10.0.0.12:8086"]);
+        // The same, received on a dual-stack socket
+        let mut candidates =
+            addresses(orchestrator.offload_candidates("::ffff:10.0.0.1".parse().ok()));
+        candidates.sort();
+        assert_eq!(candidates, vec!["10.0.0.10:8085", "10.0.0.12:8086"]);
+    }
+
+    #[test]
+    fn test_offload_candidates_skip_emergency() {
+        let orchestrator = orchestrator();
+        // The emergency covers the closest neighbor, but not the node itself
+        orchestrator.set_emergency(
+            true,
+            Emergency {
+                position: (45.4642, 9.1900),
+                radius: 100.0,
+                polygon: None,
+            },
+        );
+        assert!(!orchestrator.in_emergency_area());
+        assert_eq!(orchestrator.number_of_nodes(), 2);
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.2:8085", "10.0.0.3:8085"]
+        );
+
+        orchestrator.set_emergency(
+            false,
+            Emergency {
+                position: (45.4642, 9.1900),
+                radius: 100.0,
+                polygon: None,
+            },
+        );
+        assert_eq!(orchestrator.offload_candidates(None).len(), 3);
+    }
+
+    #[test]
+    fn test_offload_candidates_away_from_emergency() {
+        let emergency = Emergency {
+            position: (9.0, 45.0),
+            radius: 20_000.0,
+            polygon: None,
+        };
+        let orchestrator = |weight| {
+            let nodes = vec![
+                Node::new("10.0.0.1:8085".to_string(), (9.35, 45.0)),
+                Node::new("10.0.0.2:8085".to_string(), (8.55, 45.0)),
+                Node::new("10.0.0.3:8085".to_string(), (9.9, 45.0)),
+            ];
+            let identity = Node::new("10.0.0.0:8085".to_string(), (9.1, 45.0));
+            Orchestrator::with_strategy(nodes, identity, NeighborNodeStrategy::GeoDistance)
+                .with_emergency_weight(weight)
+        };
+
+        // The node is inside the zone, 10.0.0.1 is just outside it
+        let orchestrator = orchestrator(0.9);
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.1:8085", "10.0.0.2:8085", "10.0.0.3:8085"]
+        );
+        orchestrator.set_emergency(true, emergency.clone());
+        assert!(orchestrator.in_emergency_area());
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.3:8085", "10.0.0.2:8085", "10.0.0.1:8085"]
+        );
+        orchestrator.set_emergency(false, emergency);
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None))[0],
+            "10.0.0.1:8085"
+        );
+    }
+
+    #[test]
+    fn test_acquire_batch() {
+        let orchestrator = orchestrator();
+        let cpus = orchestrator.get_resources().cpus;
+        // The second request does not fit in what the first one left, the third one does
+        let admitted =
+            orchestrator.acquire_batch(&[(cpus - 1, 1, false), (2, 1, false), (1, 1, false)]);
+        assert_eq!(admitted, vec![true, false, true]);
+        assert_eq!(orchestrator.get_resources().cpus, 0);
+
+        assert_eq!(orchestrator.resources_snapshot().acquired_memory, 2);
+
+        // Requests that need more memory than available are not admitted
+        orchestrator.release_resources(cpus, 2).unwrap();
+        assert_eq!(orchestrator.resources_snapshot().acquired_memory, 0);
+        assert_eq!(
+            orchestrator.acquire_batch(&[(1, usize::MAX, false)]),
+            vec![false]
+        );
+        assert_eq!(orchestrator.get_resources().cpus, cpus);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let orchestrator = orchestrator().with_reserve(Reserve { cpus: 1, memory: 0 });
+        let cpus = orchestrator.get_resources().cpus;
+        assert_eq!(orchestrator.usable_resources(false).cpus, cpus - 1);
+        assert_eq!(orchestrator.usable_resources(true).cpus, cpus);
+
+        // A normal request can take everything but the reserve
+        assert!(orchestrator
+            .check_and_acquire_resources(cpus, 0, false)
+            .is_err());
+        orchestrator
+            .check_and_acquire_resources(cpus - 1, 0, false)
+            .unwrap();
+        assert!(orchestrator
+            .check_and_acquire_resources(1, 0, false)
+            .is_err());
+        // Which is left to the emergency requests
+        orchestrator
+            .check_and_acquire_resources(1, 0, true)
+            .unwrap();
+        assert!(orchestrator
+            .check_and_acquire_resources(1, 0, true)
+            .is_err());
+        orchestrator.release_resources(cpus, 0).unwrap();
+
+        // The same goes for the batches
+        let admitted =
+            orchestrator.acquire_batch(&[(cpus - 1, 0, false), (1, 0, false), (1, 0, true)]);
+        assert_eq!(admitted, vec![true, false, true]);
+        orchestrator.release_resources(cpus, 0).unwrap();
+
+        // And for the memory, all of it is kept here
+        let orchestrator = orchestrator.with_reserve(Reserve {
+            cpus: 0,
+            memory: usize::MAX,
+        });
+        assert!(orchestrator
+            .check_and_acquire_resources(1, 1, false)
+            .is_err());
+        assert_eq!(
+            orchestrator.acquire_batch(&[(1, 1, false), (1, 1, true)]),
+            vec![false, true]
+        );
+        assert_eq!(orchestrator.usable_resources(false).memory, 0);
+    }
+
+    #[test]
+    fn test_drain() {
+        let orchestrator = orchestrator();
+        let cpus = orchestrator.get_resources().cpus;
+        orchestrator.drain().start();
+
+        // Nothing is advertised, only the emergency requests still run here
+        assert_eq!(orchestrator.usable_resources(false).cpus, 0);
+        assert_eq!(orchestrator.usable_resources(true).cpus, 0);
+        assert!(orchestrator
+            .check_and_acquire_resources(1, 0, false)
+            .is_err());
+        assert_eq!(
+            orchestrator.acquire_batch(&[(1, 0, false), (1, 0, true)]),
+            vec![false, true]
+        );
+        orchestrator.release_resources(1, 0).unwrap();
+
+        orchestrator.drain().stop();
+        assert_eq!(orchestrator.usable_resources(false).cpus, cpus);
+
+        // Nothing is offloaded to a draining neighbor
+        orchestrator.set_neighbor_draining("10.0.0.1:8085", true);
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.2:8085", "10.0.0.3:8085"]
+        );
+        orchestrator.set_neighbor_draining("10.0.0.1:8085", false);
+        assert_eq!(orchestrator.offload_candidates(None).len(), 3);
+    }
+
+    #[test]
+    fn test_memory_pressure() {
+        use pressure::PressureReading;
+
+        let orchestrator = orchestrator().with_memory_pressure(PressureThresholds::default());
+        let cpus = orchestrator.get_resources().cpus;
+        let stall = |stall| PressureReading {
+            stall: Some(stall),
+            available: None,
+        };
+        orchestrator.memory_pressure().observe(stall(40.0));
+
+        // As while draining, only the emergency requests still run here
+        assert_eq!(orchestrator.usable_resources(false).cpus, 0);
+        assert!(orchestrator
+            .check_and_acquire_resources(1, 0, false)
+            .is_err());
+        assert_eq!(
+            orchestrator.acquire_batch(&[(1, 0, false), (1, 0, true)]),
+            vec![false, true]
+        );
+        orchestrator.release_resources(1, 0).unwrap();
+
+        // Not yet recovered
+        orchestrator.memory_pressure().observe(stall(5.0));
+        assert_eq!(orchestrator.usable_resources(false).cpus, 0);
+        orchestrator.memory_pressure().observe(stall(0.5));
+        assert_eq!(orchestrator.usable_resources(false).cpus, cpus);
+        assert!(orchestrator
+            .check_and_acquire_resources(1, 0, false)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_observe_neighbor() {
+        let nodes = vec![
+            Node::new("10.0.0.1:8085".to_string(), (45.4642, 9.1900)).with_id("a".to_string()),
+            Node::new("10.0.0.2:8085".to_string(), (48.8575, 2.3514)),
+        ];
+        let orchestrator = Orchestrator::new(
+            nodes,
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        );
+        orchestrator.set_neighbor_draining("10.0.0.1:8085", true);
+
+        // The same announce changes nothing
+        let node =
+            Node::new("10.0.0.1:8085".to_string(), (45.4642, 9.1900)).with_id("a".to_string());
+        assert_eq!(orchestrator.observe_neighbor(&node), None);
+
+        // The neighbor moved: it is updated, not duplicated, and still draining
+        let moved =
+            Node::new("10.0.0.9:8085".to_string(), (45.4642, 9.1900)).with_id("a".to_string());
+        assert_eq!(
+            orchestrator.observe_neighbor(&moved),
+            Some("10.0.0.1:8085".to_string())
+        );
+        assert_eq!(orchestrator.number_of_nodes(), 2);
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.2:8085"]
+        );
+        orchestrator.set_neighbor_draining("10.0.0.9:8085", false);
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.9:8085", "10.0.0.2:8085"]
+        );
+
+        // The nodes without an id are known by address, and unknown nodes are ignored
+        let legacy = Node::new("10.0.0.2:8085".to_string(), (48.8575, 2.3514));
+        assert_eq!(orchestrator.observe_neighbor(&legacy), None);
+        let unknown = Node::new("10.0.0.7:8085".to_string(), (0.0, 0.0)).with_id("z".to_string());
+        assert_eq!(orchestrator.observe_neighbor(&unknown), None);
+        assert_eq!(orchestrator.number_of_nodes(), 2);
+    }
+
+    #[test]
+    fn test_add_node() {
+        let node = |id: &str, address: &str, incarnation| {
+            Node::new(address.to_string(), (45.4642, 9.1900))
+                .with_id(id.to_string())
+                .with_incarnation(incarnation)
+        };
+        // The node announced itself twice
+        let orchestrator = Orchestrator::new(
+            vec![node("a", "10.0.0.1:8085", 1), node("a", "10.0.0.1:8085", 1)],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        );
+        assert_eq!(orchestrator.number_of_nodes(), 1);
+        assert!(!orchestrator.add_node(node("a", "10.0.0.1:8085", 1)));
+        assert!(orchestrator.add_node(node("b", "10.0.0.2:8085", 1)));
+        assert_eq!(orchestrator.number_of_nodes(), 2);
+
+        // The node restarted at another address, then its announce from before the
+        // restart arrives late
+        assert!(!orchestrator.add_node(node("a", "10.0.0.9:8085", 2)));
+        assert!(!orchestrator.add_node(node("a", "10.0.0.1:8085", 1)));
+        assert_eq!(
+            orchestrator.observe_neighbor(&node("a", "10.0.0.1:8085", 1)),
+            None
+        );
+        assert_eq!(orchestrator.number_of_nodes(), 2);
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.9:8085", "10.0.0.2:8085"]
+        );
+    }
+
+    #[test]
+    fn test_node_cache() {
+        let dir = std::env::temp_dir().join(format!("spare-nodes-{}", uuid::Uuid::new_v4()));
+        let node = |id: &str, address: &str| {
+            Node::new(address.to_string(), (45.4642, 9.1900)).with_id(id.to_string())
+        };
+        let orchestrator = Orchestrator::new(
+            vec![node("a", "10.0.0.1:8085")],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        )
+        .with_node_cache(dir.clone());
+        let saved = || NodeCache::load(&dir).unwrap().unwrap();
+        assert_eq!(saved().nodes.len(), 1);
+
+        // Every change of the list is saved
+        orchestrator.add_node(node("b", "10.0.0.2:8085"));
+        orchestrator.observe_neighbor(&node("a", "10.0.0.9:8085"));
+        orchestrator.update_neighbor_position("b", (48.8575, 2.3514));
+        orchestrator.update_own_position((45.0703, 7.6869));
+        let cache = saved();
+        let nodes: Vec<_> = cache
+            .nodes
+            .iter()
+            .map(|n| (n.key(), n.address.as_str(), n.position.unwrap()))
+            .collect();
+        assert_eq!(
+            nodes,
+            vec![
+                ("a", "10.0.0.9:8085", (45.4642, 9.1900)),
+                ("b", "10.0.0.2:8085", (48.8575, 2.3514))
+            ]
+        );
+        assert_eq!(cache.identity.position, Some((45.0703, 7.6869)));
+
+        orchestrator.remove_node("a");
+        assert_eq!(saved().nodes.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let node = |id: &str, address: &str| {
+            Node::new(address.to_string(), (45.4642, 9.1900)).with_id(id.to_string())
+        };
+        let saved_at = chrono::Utc::now();
+        // Started from the last known list, while the control plane is down
+        let orchestrator = Orchestrator::new(
+            vec![
+                node("a", "10.0.0.1:8085"),
+                node("b", "10.0.0.2:8085"),
+                node("c", "10.0.0.3:8085"),
+            ],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        )
+        .with_cached_topology(Some(saved_at));
+        assert_eq!(
+            orchestrator.topology_source(),
+            TopologySource::Cache { saved_at }
+        );
+        orchestrator.set_neighbor_draining("10.0.0.2:8085", true);
+
+        // The list of the control plane differs: b left, a moved and d joined
+        let (added, removed) = orchestrator.reconcile(vec![
+            node("a", "10.0.0.9:8085"),
+            node("c", "10.0.0.3:8085"),
+            node("d", "10.0.0.4:8085"),
+        ]);
+        assert_eq!((added, removed), (1, 1));
+        assert_eq!(orchestrator.topology_source(), TopologySource::ControlPlane);
+        assert_eq!(orchestrator.number_of_nodes(), 3);
+        let mut candidates = addresses(orchestrator.offload_candidates(None));
+        candidates.sort();
+        assert_eq!(
+            candidates,
+            vec!["10.0.0.3:8085", "10.0.0.4:8085", "10.0.0.9:8085"]
+        );
+        assert!(!orchestrator.remove_node("b"));
+    }
+
+    #[test]
+    fn test_malformed_address() {
+        let node = |id: &str, address: &str| {
+            Node::new(address.to_string(), (45.4642, 9.1900)).with_id(id.to_string())
+        };
+        // The malformed entries of the list of nodes are left out
+        let orchestrator = Orchestrator::new(
+            vec![
+                node("a", "10.0.0.1:8085"),
+                node("b", "10.0.0.2"),
+                node("c", "node c:8085"),
+                node("d", "Node-D:8085"),
+            ],
+            Node::new("10.0.0.0:8085".to_string(), (45.4685, 9.1824)),
+        );
+        assert_eq!(orchestrator.number_of_nodes(), 2);
+        assert!(!orchestrator.add_node(node("e", "10.0.0.5:0")));
+        // A known node announcing a malformed address keeps the one it had
+        assert_eq!(orchestrator.observe_neighbor(&node("a", "10.0.0.1")), None);
+        assert_eq!(
+            addresses(orchestrator.offload_candidates(None)),
+            vec!["10.0.0.1:8085", "node-d:8085"]
+        );
+    }
+
+    #[test]
+    fn test_cpu_overcommit() {
+        let cpus = num_cpus::get();
+        let orchestrator = orchestrator().with_cpu_overcommit(2.0);
+        let resources = orchestrator.get_resources();
+        assert_eq!((resources.cpus, resources.total_cpus), (2 * cpus, cpus));
+
+        // Admitted up to twice the cpus of the node, not one more
+        orchestrator
+            .check_and_acquire_resources(2 * cpus - 1, 0, false)
+            .unwrap();
+        orchestrator
+            .check_and_acquire_resources(1, 0, false)
+            .unwrap();
+        assert!(orchestrator
+            .check_and_acquire_resources(1, 0, false)
+            .is_err());
+        orchestrator.release_resources(2 * cpus, 0).unwrap();
+        assert_eq!(orchestrator.resources_snapshot().drift, 0);
+
+        // The instances running and the requests waiting are advertised
+        let _running = orchestrator.drain().instance();
+        let _queued = orchestrator.scheduler().enqueue();
+        let resources = orchestrator.get_resources();
+        assert_eq!(
+            (resources.running_instances, resources.queued_requests),
+            (1, 1)
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_wait_for_resources() {
+        let orchestrator = Arc::new(orchestrator());
+        let cpus = orchestrator.get_resources().cpus;
+        orchestrator
+            .check_and_acquire_resources(cpus, 0, false)
+            .unwrap();
+
+        // Nothing is released, the request gives up
+        let timeout = Duration::from_millis(10);
+        assert!(orchestrator
+            .wait_for_resources(1, 0, false, timeout)
+            .await
+            .is_err());
+
+        // A slot frees while the request waits for it
+        let releaser = orchestrator.clone();
+        actix_web::rt::spawn(async move {
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+            releaser.release_resources(1, 0).unwrap();
+        });
+        let timeout = Duration::from_secs(5);
+        assert!(orchestrator
+            .wait_for_resources(1, 0, false, timeout)
+            .await
+            .is_ok());
+        assert_eq!(orchestrator.get_resources().cpus, 0);
+        assert_eq!(orchestrator.scheduler().local_estimate().queued, 0);
+    }
+
+    #[actix_web::test]
+    async fn test_packing() {
+        assert!(orchestrator().packing_stats().is_none());
+        let orchestrator = Arc::new(
+            orchestrator()
+                .with_cpu_overcommit(4.0)
+                .with_packing_hold(Duration::from_millis(50)),
+        );
+        let cpus = orchestrator.get_resources().cpus;
+        for _ in 0..cpus {
+            orchestrator
+                .check_and_acquire_resources(1, 0, false)
+                .unwrap();
+        }
+
+        // A request of two cpus waits, then a single cpu frees
+        let waiter = orchestrator.clone();
+        let large = actix_web::rt::spawn(async move {
+            waiter
+                .wait_for_resources(2, 0, false, Duration::from_secs(5))
+                .await
+        });
+        while orchestrator.scheduler().local_estimate().queued == 0 {
+            actix_web::rt::time::sleep(Duration::from_millis(1)).await;
+        }
+        orchestrator.release_resources(1, 0).unwrap();
+
+        // A request of one cpu is held back for it, up to the hold, unless an emergency one
+        let start = Instant::now();
+        orchestrator.hold_for_larger(1, true).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        orchestrator.hold_for_larger(1, false).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        let stats = orchestrator.packing_stats().unwrap();
+        assert_eq!(stats.waiting, BTreeMap::from([(2, 1)]));
+        assert_eq!((stats.free_cpus, stats.fragmented_cpus), (1, 1));
+        assert_eq!(stats.deferred, 1);
+
+        // Once another cpu frees, the large request takes both
+        orchestrator.release_resources(1, 0).unwrap();
+        large.await.unwrap().unwrap();
+        let stats = orchestrator.packing_stats().unwrap();
+        assert!(stats.waiting.is_empty());
+        assert_eq!(stats.held, BTreeMap::from([(1, cpus - 2), (2, 2)]));
+        assert_eq!(stats.fragmented_cpus, 0);
+    }
+
+    #[test]
+    fn test_schedule() {
+        // The neighbors are selected by distance, so their latency is the default one
+        let orchestrator = orchestrator().with_cost_model(CostModel {
+            default_latency: 1000.0,
+            ..Default::default()
+        });
+        let data = invoke_function("f");
+        assert!(matches!(
+            orchestrator.schedule(&data, None),
+            Decision::Wait(_)
+        ));
+
+        orchestrator.scheduler().calibrate(2000.0);
+        assert_eq!(orchestrator.schedule(&data, None), Decision::Offload);
     }
 }
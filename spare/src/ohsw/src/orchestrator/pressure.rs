@@ -0,0 +1,324 @@
+//! Memory pressure of the host.
+//! The memory a request needs is checked against MemAvailable when it is admitted, which
+//! misses the page cache churned by the image reads: the kernel may then OOM-kill a
+//! firecracker process in the middle of a request. A monitor reads the pressure stall
+//! information of the memory (`/proc/pressure/memory`) and MemAvailable, and once the
+//! pressure crosses a threshold the node admits no new local instance, offloading the new
+//! requests but for the emergency ones, and advertises no resources. The instances already
+//! running complete. The node admits again only once the pressure falls below a lower
+//! threshold and enough memory is available, so that it does not flap around a threshold.
+use std::{
+    fs, io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::Serialize;
+
+use super::{local_resources::LocalResources, Orchestrator};
+
+/// Path of the pressure stall information of the memory
+pub const PSI_MEMORY: &str = "/proc/pressure/memory";
+
+/// A reading of the memory pressure of the host, what could not be read is None
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct PressureReading {
+    /// Share of the time some task stalled on memory in the last 10 seconds (in %)
+    pub stall: Option<f64>,
+    /// Memory available to new processes (in KiB)
+    pub available: Option<usize>,
+}
+
+impl PressureReading {
+    /// Read the `some avg10` share of the pressure stall information
+    pub fn parse_psi(contents: &str) -> Option<f64> {
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix("some "))?
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("avg10="))?
+            .parse()
+            .ok()
+    }
+}
+
+/// Thresholds of the memory pressure
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PressureThresholds {
+    /// The node is under pressure from this share of stalled time (in %)
+    pub high: f64,
+    /// The node is under pressure until the share of stalled time falls to this (in %)
+    pub low: f64,
+    /// The node is under pressure below this available memory (in KiB), 0 to ignore it;
+    /// it recovers from twice as much
+    pub min_available: usize,
+}
+
+impl Default for PressureThresholds {
+    fn default() -> Self {
+        Self {
+            high: 10.0,
+            low: 2.0,
+            min_available: 256 * 1024,
+        }
+    }
+}
+
+impl PressureThresholds {
+    /// Check that the node can recover from the pressure
+    pub fn validate(&self) -> Result<(), String> {
+        if !(0.0..=100.0).contains(&self.high) || !(0.0..=100.0).contains(&self.low) {
+            return Err(format!(
+                "The pressure thresholds must be between 0 and 100: {} and {}",
+                self.high, self.low
+            ));
+        }
+        if self.low >= self.high {
+            return Err(format!(
+                "The low pressure threshold {} must be lower than the high one {}",
+                self.low, self.high
+            ));
+        }
+        Ok(())
+    }
+
+    fn crossed(&self, reading: &PressureReading) -> bool {
+        reading.stall.is_some_and(|stall| stall >= self.high)
+            || reading
+                .available
+                .is_some_and(|available| available < self.min_available)
+    }
+
+    fn recovered(&self, reading: &PressureReading) -> bool {
+        (reading.stall.is_some() || reading.available.is_some())
+            && reading.stall.is_none_or(|stall| stall <= self.low)
+            && reading
+                .available
+                .is_none_or(|available| available >= 2 * self.min_available)
+    }
+}
+
+/// A change of the memory pressure of the node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressureEvent {
+    /// The node stops admitting local instances
+    Entered,
+    /// The node admits local instances again
+    Left,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    since: Option<DateTime<Utc>>,
+    /// Times the node went under pressure since it started
+    episodes: u64,
+    last: Option<PressureReading>,
+}
+
+/// Memory pressure of the node, as last read by the monitor
+#[derive(Debug, Default)]
+pub struct MemoryPressure {
+    thresholds: PressureThresholds,
+    state: Mutex<State>,
+}
+
+/// Memory pressure of the node, as seen by the operator
+#[derive(Debug, Clone, Serialize)]
+pub struct PressureStatus {
+    pub under_pressure: bool,
+    /// When the node went under pressure
+    pub since: Option<DateTime<Utc>>,
+    pub episodes: u64,
+    pub last: Option<PressureReading>,
+    pub thresholds: PressureThresholds,
+}
+
+impl MemoryPressure {
+    pub fn new(thresholds: PressureThresholds) -> Self {
+        Self {
+            thresholds,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Take a new reading into account
+    /// # Returns
+    /// * The change of the pressure, if any
+    pub fn observe(&self, reading: PressureReading) -> Option<PressureEvent> {
+        let mut state = self.state.lock().unwrap();
+        state.last = Some(reading);
+        match state.since {
+            None if self.thresholds.crossed(&reading) => {
+                state.since = Some(Utc::now());
+                state.episodes += 1;
+                Some(PressureEvent::Entered)
+            }
+            Some(_) if self.thresholds.recovered(&reading) => {
+                state.since = None;
+                Some(PressureEvent::Left)
+            }
+            _ => None,
+        }
+    }
+
+    /// Check whether the node admits no new local instance
+    pub fn is_under_pressure(&self) -> bool {
+        self.state.lock().unwrap().since.is_some()
+    }
+
+    pub fn status(&self) -> PressureStatus {
+        let state = self.state.lock().unwrap();
+        PressureStatus {
+            under_pressure: state.since.is_some(),
+            since: state.since,
+            episodes: state.episodes,
+            last: state.last,
+            thresholds: self.thresholds,
+        }
+    }
+}
+
+/// Something able to read the memory pressure of the host
+pub trait PressureSource {
+    fn read(&self) -> io::Result<PressureReading>;
+}
+
+/// Pressure of the host, from procfs. The kernels without PSI give MemAvailable only.
+pub struct ProcPressure;
+
+impl PressureSource for ProcPressure {
+    fn read(&self) -> io::Result<PressureReading> {
+        let stall = match fs::read_to_string(PSI_MEMORY) {
+            Ok(contents) => PressureReading::parse_psi(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let available = Some(LocalResources::get_available_memory()).filter(|&kb| kb > 0);
+        Ok(PressureReading { stall, available })
+    }
+}
+
+/// Read the memory pressure every `interval`, and have the node stop admitting local
+/// instances while it is high
+pub async fn run(source: impl PressureSource, orchestrator: Arc<Orchestrator>, interval: Duration) {
+    info!(
+        "Reading the memory pressure every {} ms",
+        interval.as_millis()
+    );
+    loop {
+        match source.read() {
+            Ok(reading) => match orchestrator.memory_pressure().observe(reading) {
+                Some(PressureEvent::Entered) => warn!(
+                    "Memory pressure: {:?}, admitting no local instance until it recovers",
+                    reading
+                ),
+                Some(PressureEvent::Left) => {
+                    info!("Memory pressure recovered: {:?}", reading)
+                }
+                None => {}
+            },
+            Err(e) => warn!("Cannot read the memory pressure: {}", e),
+        }
+        actix_web::rt::time::sleep(interval).await;
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(stall: f64, available_mb: usize) -> PressureReading {
+        PressureReading {
+            stall: Some(stall),
+            available: Some(available_mb * 1024),
+        }
+    }
+
+    #[test]
+    fn test_parse_psi() {
+        let contents = "some avg10=12.50 avg60=3.21 avg300=0.80 total=123456\n\
+                        full avg10=4.00 avg60=1.00 avg300=0.20 total=65432\n";
+        assert_eq!(PressureReading::parse_psi(contents), Some(12.5));
+        assert_eq!(PressureReading::parse_psi("full avg10=4.00\n"), None);
+        assert_eq!(PressureReading::parse_psi("some avg10=high\n"), None);
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let pressure = MemoryPressure::new(PressureThresholds::default());
+        assert_eq!(pressure.observe(reading(5.0, 4096)), None);
+        assert!(!pressure.is_under_pressure());
+
+        // Crossing the high threshold stops the admissions
+        assert_eq!(
+            pressure.observe(reading(15.0, 4096)),
+            Some(PressureEvent::Entered)
+        );
+        assert!(pressure.is_under_pressure());
+        // Between the thresholds, the node stays under pressure
+        for stall in [9.0, 11.0, 5.0, 2.5] {
+            assert_eq!(pressure.observe(reading(stall, 4096)), None);
+            assert!(pressure.is_under_pressure());
+        }
+        assert_eq!(
+            pressure.observe(reading(1.0, 4096)),
+            Some(PressureEvent::Left)
+        );
+        assert!(!pressure.is_under_pressure());
+        // Below the high threshold again, the node keeps admitting
+        assert_eq!(pressure.observe(reading(9.0, 4096)), None);
+        assert_eq!(pressure.status().episodes, 1);
+    }
+
+    #[test]
+    fn test_available_memory() {
+        let pressure = MemoryPressure::new(PressureThresholds::default());
+        // Little memory left enters the pressure without any stall
+        assert_eq!(
+            pressure.observe(reading(0.0, 200)),
+            Some(PressureEvent::Entered)
+        );
+        // It recovers from twice the threshold only
+        assert_eq!(pressure.observe(reading(0.0, 400)), None);
+        assert_eq!(
+            pressure.observe(reading(0.0, 600)),
+            Some(PressureEvent::Left)
+        );
+
+        // Without PSI, MemAvailable alone decides
+        let available = |mb: usize| PressureReading {
+            stall: None,
+            available: Some(mb * 1024),
+        };
+        assert_eq!(
+            pressure.observe(available(100)),
+            Some(PressureEvent::Entered)
+        );
+        // A reading that failed changes nothing
+        assert_eq!(pressure.observe(PressureReading::default()), None);
+        assert!(pressure.is_under_pressure());
+        assert_eq!(pressure.observe(available(1024)), Some(PressureEvent::Left));
+        let status = pressure.status();
+        assert_eq!(status.episodes, 2);
+        assert_eq!(status.last, Some(available(1024)));
+    }
+
+    #[test]
+    fn test_thresholds() {
+        assert!(PressureThresholds::default().validate().is_ok());
+        let flapping = PressureThresholds {
+            high: 5.0,
+            low: 5.0,
+            ..Default::default()
+        };
+        assert!(flapping.validate().is_err());
+        let percent = PressureThresholds {
+            high: 150.0,
+            ..Default::default()
+        };
+        assert!(percent.validate().is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Readiness of the node to serve requests.
+//! A node starts by checking its runtime, then registers to learn its neighbors. Until it
+//! knows them and its runtime is validated, the requests it took would fail to be offloaded
+//! or to start an instance, so they are refused and retried later. Once ready, the node
+//! serves until it is drained before a maintenance.
+use std::{fmt, sync::Mutex, time::Duration};
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Time a client waits before sending again a request refused while the node starts
+pub const RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Phase of the life of the node
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadinessPhase {
+    /// The node checks its runtime
+    Starting,
+    /// The node waits for the list of its neighbors
+    Registering,
+    /// The node serves requests
+    #[default]
+    Ready,
+    /// The node serves the emergency requests only, before a maintenance
+    Draining,
+}
+
+impl ReadinessPhase {
+    /// Check whether the node takes requests in this phase. A draining node still takes
+    /// them, to serve the emergency ones and offload the others
+    pub fn accepts_requests(&self) -> bool {
+        matches!(self, ReadinessPhase::Ready | ReadinessPhase::Draining)
+    }
+
+    /// Check whether the node can move from this phase to `next`
+    fn leads_to(&self, next: ReadinessPhase) -> bool {
+        use ReadinessPhase::*;
+        matches!(
+            (self, next),
+            (Starting, Registering) | (Registering, Ready) | (Ready, Draining) | (Draining, Ready)
+        )
+    }
+}
+
+impl fmt::Display for ReadinessPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phase = match self {
+            ReadinessPhase::Starting => "starting",
+            ReadinessPhase::Registering => "registering",
+            ReadinessPhase::Ready => "ready",
+            ReadinessPhase::Draining => "draining",
+        };
+        f.write_str(phase)
+    }
+}
+
+/// A phase the node entered, and when
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PhaseChange {
+    pub phase: ReadinessPhase,
+    pub at: DateTime<Utc>,
+}
+
+/// Phase of the node, and the phases it went through
+#[derive(Debug)]
+pub struct Readiness {
+    phase: watch::Sender<ReadinessPhase>,
+    changes: Mutex<Vec<PhaseChange>>,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Self::ready()
+    }
+}
+
+impl Readiness {
+    /// Create the readiness of a node starting
+    pub fn new() -> Self {
+        Self::from(ReadinessPhase::Starting)
+    }
+
+    /// Create the readiness of a node that is ready at once, e.g. one built in process
+    pub fn ready() -> Self {
+        Self::from(ReadinessPhase::Ready)
+    }
+
+    fn from(phase: ReadinessPhase) -> Self {
+        Self {
+            phase: watch::Sender::new(phase),
+            changes: Mutex::new(vec![PhaseChange {
+                phase,
+                at: Utc::now(),
+            }]),
+        }
+    }
+
+    /// Get the current phase
+    pub fn phase(&self) -> ReadinessPhase {
+        *self.phase.borrow()
+    }
+
+    /// Move to the next phase, if the current one leads to it
+    /// # Returns
+    /// * Whether the phase changed
+    pub fn advance(&self, next: ReadinessPhase) -> bool {
+        let changed = self.phase.send_if_modified(|phase| {
+            if !phase.leads_to(next) {
+                // Draining twice, or undraining a serving node, is expected
+                if !phase.accepts_requests() {
+                    warn!("The node cannot be {next} while it is {phase}");
+                }
+                return false;
+            }
+            info!("The node is {next}, it was {phase}");
+            *phase = next;
+            true
+        });
+        if changed {
+            self.changes.lock().unwrap().push(PhaseChange {
+                phase: next,
+                at: Utc::now(),
+            });
+        }
+        changed
+    }
+
+    /// Get the phases the node entered, the first one when it was created
+    pub fn changes(&self) -> Vec<PhaseChange> {
+        self.changes.lock().unwrap().clone()
+    }
+
+    /// Wait for the node to take requests
+    pub async fn ready_to_serve(&self) {
+        let mut phase = self.phase.subscribe();
+        // The sender lives as long as self, the wait cannot fail
+        let _ = phase.wait_for(ReadinessPhase::accepts_requests).await;
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_transitions() {
+        let readiness = Readiness::new();
+        assert_eq!(readiness.phase(), ReadinessPhase::Starting);
+        assert!(!readiness.phase().accepts_requests());
+        // No phase is skipped
+        assert!(!readiness.advance(ReadinessPhase::Ready));
+        assert!(!readiness.advance(ReadinessPhase::Draining));
+        assert!(readiness.advance(ReadinessPhase::Registering));
+        assert!(!readiness.phase().accepts_requests());
+        assert!(readiness.advance(ReadinessPhase::Ready));
+        assert!(readiness.phase().accepts_requests());
+
+        assert!(readiness.advance(ReadinessPhase::Draining));
+        assert!(!readiness.advance(ReadinessPhase::Draining));
+        assert!(readiness.phase().accepts_requests());
+        assert!(readiness.advance(ReadinessPhase::Ready));
+        // A ready node never registers again
+        assert!(!readiness.advance(ReadinessPhase::Registering));
+
+        let phases: Vec<ReadinessPhase> = readiness
+            .changes()
+            .iter()
+            .map(|change| change.phase)
+            .collect();
+        assert_eq!(
+            phases,
+            [
+                ReadinessPhase::Starting,
+                ReadinessPhase::Registering,
+                ReadinessPhase::Ready,
+                ReadinessPhase::Draining,
+                ReadinessPhase::Ready,
+            ]
+        );
+        assert!(readiness
+            .changes()
+            .windows(2)
+            .all(|changes| changes[0].at <= changes[1].at));
+
+        assert_eq!(Readiness::default().phase(), ReadinessPhase::Ready);
+        assert_eq!(Readiness::default().changes().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_ready_to_serve() {
+        let readiness = Arc::new(Readiness::new());
+        let waiting = actix_web::rt::spawn({
+            let readiness = readiness.clone();
+            async move { readiness.ready_to_serve().await }
+        });
+        readiness.advance(ReadinessPhase::Registering);
+        actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+        readiness.advance(ReadinessPhase::Ready);
+        actix_web::rt::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // A ready node does not wait
+        Readiness::ready().ready_to_serve().await;
+    }
+
+    #[test]
+    fn test_serialize() {
+        assert_eq!(
+            serde_json::to_string(&ReadinessPhase::Registering).unwrap(),
+            r#""registering""#
+        );
+        assert_eq!(ReadinessPhase::Draining.to_string(), "draining");
+    }
+}
@@ -0,0 +1,333 @@
+//! Resolution of the resources of an instance.
+//! A request may leave its vcpus or its memory out, they are then taken from the defaults
+//! of the function. The values are clamped to the largest instance the node is configured
+//! to run, and a request over the hard caps, the capacity of the node, is refused.
+use std::{fmt, str::FromStr};
+
+use log::warn;
+
+/// Vcpus and memory (in MiB) of an instance, either may be left out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceSpec {
+    pub vcpus: Option<i32>,
+    pub memory: Option<i32>,
+}
+
+/// Vcpus and memory (in MiB) an instance runs with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolved {
+    pub vcpus: i32,
+    pub memory: i32,
+}
+
+/// Limits of the node on the resources of an instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NodeLimits {
+    /// Larger values are clamped to these, when set
+    pub max: ResourceSpec,
+    /// Larger values are refused
+    pub hard_vcpus: i32,
+    pub hard_memory: i32,
+}
+
+/// A resource over a hard cap of the node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverLimit {
+    pub field: &'static str,
+    pub requested: i32,
+    pub cap: i32,
+}
+
+impl fmt::Display for OverLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} > {})", self.field, self.requested, self.cap)
+    }
+}
+
+/// Error of the resolution, listing every field at fault
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ResolveError {
+    #[error("Missing resources, given neither by the request nor by the function: {}", .0.join(", "))]
+    Missing(Vec<&'static str>),
+    #[error("Invalid resources, they must be positive: {}", .0.join(", "))]
+    Invalid(Vec<&'static str>),
+    #[error("Resources over the limits of the node: {}", join(.0))]
+    OverLimit(Vec<OverLimit>),
+}
+
+fn join(over: &[OverLimit]) -> String {
+    over.iter()
+        .map(OverLimit::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+impl ResourceSpec {
+    /// Resources of a request, a value of 0 is left to the defaults of the function
+    pub fn requested(vcpus: i32, memory: i32) -> Self {
+        ResourceSpec {
+            vcpus: (vcpus != 0).then_some(vcpus),
+            memory: (memory != 0).then_some(memory),
+        }
+    }
+
+    /// Resolve the resources of an instance: the values missing from the request are
+    /// taken from the defaults of the function, then clamped to the limits of the node.
+    /// A value over the hard caps of the node is refused.
+    pub fn resolve(
+        request: &ResourceSpec,
+        function_defaults: Option<&ResourceSpec>,
+        node_limits: &NodeLimits,
+    ) -> Result<Resolved, ResolveError> {
+        let defaults = function_defaults.copied().unwrap_or_default();
+        let fields = [
+            (
+                "vcpus",
+                request.vcpus.or(defaults.vcpus),
+                node_limits.max.vcpus,
+                node_limits.hard_vcpus,
+            ),
+            (
+                "memory",
+                request.memory.or(defaults.memory),
+                node_limits.max.memory,
+                node_limits.hard_memory,
+            ),
+        ];
+
+        let missing: Vec<_> = fields
+            .iter()
+            .filter(|(_, value, ..)| value.is_none())
+            .map(|(field, ..)| *field)
+            .collect();
+        if !missing.is_empty() {
+            return Err(ResolveError::Missing(missing));
+        }
+        let invalid: Vec<_> = fields
+            .iter()
+            .filter(|(_, value, ..)| value.is_some_and(|value| value <= 0))
+            .map(|(field, ..)| *field)
+            .collect();
+        if !invalid.is_empty() {
+            return Err(ResolveError::Invalid(invalid));
+        }
+        let over: Vec<_> = fields
+            .iter()
+            .filter_map(|&(field, value, _, cap)| {
+                let requested = value.unwrap();
+                (requested > cap).then_some(OverLimit {
+                    field,
+                    requested,
+                    cap,
+                })
+            })
+            .collect();
+        if !over.is_empty() {
+            return Err(ResolveError::OverLimit(over));
+        }
+
+        let [vcpus, memory] = fields.map(|(field, value, max, _)| {
+            let value = value.unwrap();
+            match max {
+                Some(max) if value > max => {
+                    warn!(
+                        "Clamped the {} of an instance from {} to {}",
+                        field, value, max
+                    );
+                    max
+                }
+                _ => value,
+            }
+        });
+        Ok(Resolved { vcpus, memory })
+    }
+}
+
+/// Default resources of a function, given on the command line as
+/// `function=vcpus,memory`, either may be left empty
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionResources {
+    pub function: String,
+    pub resources: ResourceSpec,
+}
+
+impl FromStr for FunctionResources {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid function resources: {s}, expected function=vcpus,memory");
+        let (function, rest) = s.split_once('=').ok_or_else(invalid)?;
+        let (vcpus, memory) = rest.split_once(',').ok_or_else(invalid)?;
+        if function.is_empty() {
+            return Err(invalid());
+        }
+        let value = |value: &str| match value {
+            "" => Ok(None),
+            value => value.parse().map(Some).map_err(|_| invalid()),
+        };
+        Ok(FunctionResources {
+            function: function.to_string(),
+            resources: ResourceSpec {
+                vcpus: value(vcpus)?,
+                memory: value(memory)?,
+            },
+        })
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: NodeLimits = NodeLimits {
+        max: ResourceSpec {
+            vcpus: Some(4),
+            memory: Some(2048),
+        },
+        hard_vcpus: 8,
+        hard_memory: 4096,
+    };
+
+    const DEFAULTS: ResourceSpec = ResourceSpec {
+        vcpus: Some(2),
+        memory: Some(512),
+    };
+
+    fn spec(vcpus: Option<i32>, memory: Option<i32>) -> ResourceSpec {
+        ResourceSpec { vcpus, memory }
+    }
+
+    fn resolved(vcpus: i32, memory: i32) -> Result<Resolved, ResolveError> {
+        Ok(Resolved { vcpus, memory })
+    }
+
+    #[test]
+    fn test_requested() {
+        assert_eq!(ResourceSpec::requested(2, 256), spec(Some(2), Some(256)));
+        assert_eq!(ResourceSpec::requested(0, 256), spec(None, Some(256)));
+        assert_eq!(ResourceSpec::requested(0, 0), spec(None, None));
+        // Negative values are kept, to be refused
+        assert_eq!(ResourceSpec::requested(-1, 0), spec(Some(-1), None));
+    }
+
+    #[test]
+    fn test_present_or_absent() {
+        let resolve = |request, defaults| ResourceSpec::resolve(&request, defaults, &LIMITS);
+        // The request wins over the defaults
+        assert_eq!(
+            resolve(spec(Some(1), Some(128)), Some(&DEFAULTS)),
+            resolved(1, 128)
+        );
+        assert_eq!(resolve(spec(Some(1), Some(128)), None), resolved(1, 128));
+        // The missing values come from the defaults
+        assert_eq!(
+            resolve(spec(None, Some(128)), Some(&DEFAULTS)),
+            resolved(2, 128)
+        );
+        assert_eq!(
+            resolve(spec(Some(1), None), Some(&DEFAULTS)),
+            resolved(1, 512)
+        );
+        assert_eq!(resolve(spec(None, None), Some(&DEFAULTS)), resolved(2, 512));
+        // Partial defaults fill in what they have
+        let partial = spec(None, Some(256));
+        assert_eq!(
+            resolve(spec(Some(3), None), Some(&partial)),
+            resolved(3, 256)
+        );
+        assert_eq!(
+            resolve(spec(None, None), Some(&partial)),
+            Err(ResolveError::Missing(vec!["vcpus"]))
+        );
+        // Nothing to fill them in with
+        assert_eq!(
+            resolve(spec(None, Some(128)), None),
+            Err(ResolveError::Missing(vec!["vcpus"]))
+        );
+        assert_eq!(
+            resolve(spec(Some(1), None), None),
+            Err(ResolveError::Missing(vec!["memory"]))
+        );
+        assert_eq!(
+            resolve(spec(None, None), None),
+            Err(ResolveError::Missing(vec!["vcpus", "memory"]))
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let resolve = |request, defaults| ResourceSpec::resolve(&request, defaults, &LIMITS);
+        assert_eq!(
+            resolve(spec(Some(-1), Some(128)), None),
+            Err(ResolveError::Invalid(vec!["vcpus"]))
+        );
+        assert_eq!(
+            resolve(spec(Some(-1), Some(-128)), None),
+            Err(ResolveError::Invalid(vec!["vcpus", "memory"]))
+        );
+        // Bad defaults are refused as well
+        assert_eq!(
+            resolve(spec(None, Some(128)), Some(&spec(Some(0), None))),
+            Err(ResolveError::Invalid(vec!["vcpus"]))
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let resolve = |request, defaults| ResourceSpec::resolve(&request, defaults, &LIMITS);
+        // At the limits, nothing changes
+        assert_eq!(resolve(spec(Some(4), Some(2048)), None), resolved(4, 2048));
+        // Between the limits and the hard caps, the values are clamped
+        assert_eq!(resolve(spec(Some(6), Some(128)), None), resolved(4, 128));
+        assert_eq!(resolve(spec(Some(1), Some(4096)), None), resolved(1, 2048));
+        assert_eq!(resolve(spec(Some(8), Some(3000)), None), resolved(4, 2048));
+        // So are the defaults
+        let large = spec(Some(6), Some(3000));
+        assert_eq!(resolve(spec(None, None), Some(&large)), resolved(4, 2048));
+        // Over the hard caps, the request is refused with every field at fault
+        assert_eq!(
+            resolve(spec(Some(9), Some(128)), None),
+            Err(ResolveError::OverLimit(vec![OverLimit {
+                field: "vcpus",
+                requested: 9,
+                cap: 8
+            }]))
+        );
+        let error = resolve(spec(Some(16), Some(8192)), None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Resources over the limits of the node: vcpus (16 > 8), memory (8192 > 4096)"
+        );
+        // Without limits, only the hard caps apply
+        let limits = NodeLimits {
+            max: ResourceSpec::default(),
+            ..LIMITS
+        };
+        assert_eq!(
+            ResourceSpec::resolve(&spec(Some(8), Some(4096)), None, &limits),
+            resolved(8, 4096)
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "resize=2,512".parse::<FunctionResources>().unwrap(),
+            FunctionResources {
+                function: "resize".to_string(),
+                resources: spec(Some(2), Some(512)),
+            }
+        );
+        assert_eq!(
+            "resize=,512"
+                .parse::<FunctionResources>()
+                .unwrap()
+                .resources,
+            spec(None, Some(512))
+        );
+        assert!("resize=2".parse::<FunctionResources>().is_err());
+        assert!("resize=two,512".parse::<FunctionResources>().is_err());
+        assert!("=2,512".parse::<FunctionResources>().is_err());
+    }
+}
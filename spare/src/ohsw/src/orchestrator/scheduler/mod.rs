@@ -0,0 +1,457 @@
+//! Scheduler deciding where a request runs when the node cannot run it right away.
+//! Offloading as soon as the local resources are exhausted is not always the fastest
+//! option: when the closest neighbor is far, waiting for a local slot to free may take
+//! less than the round trip. The cost model compares the estimated completion time of
+//! the two options, the decision itself is a pure function of the estimates.
+//! The same estimates tell whether a request can still be answered before its deadline:
+//! a request that cannot is shed rather than served by nobody in time.
+pub mod packing;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// Service time assumed until the node is calibrated (in ms)
+pub const DEFAULT_SERVICE_TIME: f64 = 100.0;
+/// Latency assumed for the neighbors that were never measured (in ms)
+pub const DEFAULT_LATENCY: f64 = 50.0;
+/// Longest time a request waits for local resources by default (in ms)
+pub const DEFAULT_MAX_WAIT: f64 = 1000.0;
+
+/// Coefficients of the cost model, times are in milliseconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CostModel {
+    /// Service time of a request until the node is calibrated
+    pub default_service_time: f64,
+    /// Share of a service time that each queued request, this one included, adds to the wait
+    pub wait_factor: f64,
+    /// Latency of a neighbor that was never measured
+    pub default_latency: f64,
+    /// Times the latency of the neighbor is paid by an offloaded request
+    pub latency_factor: f64,
+    /// Fixed cost of an offload, e.g. asking the neighbor for its resources
+    pub offload_overhead: f64,
+    /// Longest time a request waits for local resources
+    pub max_wait: f64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel {
+            default_service_time: DEFAULT_SERVICE_TIME,
+            wait_factor: 1.0,
+            default_latency: DEFAULT_LATENCY,
+            latency_factor: 1.0,
+            offload_overhead: 0.0,
+            max_wait: DEFAULT_MAX_WAIT,
+        }
+    }
+}
+
+/// Estimate of the cost of running a request locally
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LocalEstimate {
+    /// Requests already waiting for local resources
+    pub queued: usize,
+    /// Service time of a request on this node (in ms)
+    pub service_time: f64,
+}
+
+/// Estimate of the cost of offloading a request to a neighbor, None if unknown
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RemoteEstimate {
+    /// Latency of the neighbor (in ms)
+    pub latency: Option<f64>,
+    /// Service time of a request on the neighbor (in ms)
+    pub service_time: Option<f64>,
+}
+
+/// Where a request that cannot run right away goes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decision {
+    /// Wait at most the given time for the local resources, then offload
+    Wait(Duration),
+    /// Offload the request now
+    Offload,
+}
+
+/// A request that would be answered after its deadline
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Doomed {
+    /// Time left before the deadline of the request (in ms)
+    pub budget: f64,
+    /// Expected time to answer the request (in ms)
+    pub expected: f64,
+}
+
+impl fmt::Display for Doomed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.0} ms left, {:.0} ms expected",
+            self.budget, self.expected
+        )
+    }
+}
+
+/// Check that a request with `budget` left can be answered in the `expected` time (in ms)
+fn within(budget: Duration, expected: f64) -> Result<(), Doomed> {
+    let budget = budget.as_secs_f64() * 1000.0;
+    match budget < expected {
+        true => Err(Doomed { budget, expected }),
+        false => Ok(()),
+    }
+}
+
+impl CostModel {
+    /// Check that the coefficients make sense
+    pub fn validate(&self) -> Result<(), String> {
+        let coefficients = [
+            ("default service time", self.default_service_time),
+            ("wait factor", self.wait_factor),
+            ("default latency", self.default_latency),
+            ("latency factor", self.latency_factor),
+            ("offload overhead", self.offload_overhead),
+            ("max wait", self.max_wait),
+        ];
+        for (name, value) in coefficients {
+            if !value.is_finite() || value < 0.0 {
+                return Err(format!("The {} must be a non-negative number", name));
+            }
+        }
+        Ok(())
+    }
+
+    /// Estimated time before a local slot is available (in ms)
+    pub fn local_wait(&self, local: &LocalEstimate) -> f64 {
+        (local.queued + 1) as f64 * self.wait_factor * local.service_time
+    }
+
+    /// Estimated completion time of a request run locally (in ms)
+    pub fn local_time(&self, local: &LocalEstimate) -> f64 {
+        self.local_wait(local) + local.service_time
+    }
+
+    /// Estimated completion time of a request offloaded to a neighbor (in ms).
+    /// A neighbor whose service time is unknown is assumed as fast as this node.
+    pub fn remote_time(&self, remote: &RemoteEstimate, local: &LocalEstimate) -> f64 {
+        remote.latency.unwrap_or(self.default_latency) * self.latency_factor
+            + remote.service_time.unwrap_or(local.service_time)
+            + self.offload_overhead
+    }
+
+    /// Check that a request with `budget` left can be served by a new local instance in
+    /// time, the cold start and the execution of the function
+    pub fn local_deadline(&self, budget: Duration, local: &LocalEstimate) -> Result<(), Doomed> {
+        within(budget, local.service_time)
+    }
+
+    /// Check that a request with `budget` left can be offloaded to a neighbor in time
+    pub fn remote_deadline(
+        &self,
+        budget: Duration,
+        remote: &RemoteEstimate,
+        local: &LocalEstimate,
+    ) -> Result<(), Doomed> {
+        within(budget, self.remote_time(remote, local))
+    }
+
+    /// Decide whether a request waits for the local resources or is offloaded
+    /// # Arguments
+    /// * `local` - Estimate of running the request locally
+    /// * `remote` - Estimate of offloading it to the first candidate, None if there is none
+    pub fn decide(&self, local: &LocalEstimate, remote: Option<&RemoteEstimate>) -> Decision {
+        let wait = self.local_wait(local);
+        if wait > self.max_wait {
+            return Decision::Offload;
+        }
+        match remote {
+            Some(remote) if self.remote_time(remote, local) < self.local_time(local) => {
+                Decision::Offload
+            }
+            _ => Decision::Wait(Duration::from_secs_f64(wait / 1000.0)),
+        }
+    }
+}
+
+/// State of the scheduler, shared by the requests served by the node
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    model: CostModel,
+    /// Requests waiting for local resources
+    queued: AtomicUsize,
+    /// Service time measured by the last calibration (in ms)
+    service_time: RwLock<Option<f64>>,
+    /// Service time advertised by each neighbor (in ms), by address
+    remote_service_times: RwLock<HashMap<String, f64>>,
+}
+
+/// A request waiting for local resources, it leaves the queue when dropped
+pub struct Queued<'a>(&'a AtomicUsize);
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Scheduler {
+    /// Create a new scheduler using the given cost model
+    pub fn new(model: CostModel) -> Self {
+        Scheduler {
+            model,
+            ..Default::default()
+        }
+    }
+
+    /// Get the cost model
+    pub fn model(&self) -> &CostModel {
+        &self.model
+    }
+
+    /// Get the calibrated service time, None if the node was never calibrated
+    pub fn calibrated_service_time(&self) -> Option<f64> {
+        *self.service_time.read().unwrap()
+    }
+
+    /// Set the service time measured by a calibration (in ms)
+    pub fn calibrate(&self, service_time: f64) {
+        *self.service_time.write().unwrap() = Some(service_time);
+    }
+
+    /// Record the service time advertised by a neighbor (in ms)
+    pub fn set_remote_service_time(&self, address: &str, service_time: f64) {
+        self.remote_service_times
+            .write()
+            .unwrap()
+            .insert(address.to_string(), service_time);
+    }
+
+    /// Get the service time last advertised by a neighbor
+    pub fn remote_service_time(&self, address: &str) -> Option<f64> {
+        self.remote_service_times
+            .read()
+            .unwrap()
+            .get(address)
+            .copied()
+    }
+
+    /// Estimate the cost of running a request locally now
+    pub fn local_estimate(&self) -> LocalEstimate {
+        LocalEstimate {
+            queued: self.queued.load(Ordering::SeqCst),
+            service_time: self
+                .calibrated_service_time()
+                .unwrap_or(self.model.default_service_time),
+        }
+    }
+
+    /// Check that a request due at `deadline` can be served by a new local instance in time
+    pub fn local_deadline(&self, deadline: Instant) -> Result<(), Doomed> {
+        let budget = deadline.saturating_duration_since(Instant::now());
+        self.model.local_deadline(budget, &self.local_estimate())
+    }
+
+    /// Put a request in the queue of the ones waiting for local resources
+    pub fn enqueue(&self) -> Queued<'_> {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        Queued(&self.queued)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(queued: usize, service_time: f64) -> LocalEstimate {
+        LocalEstimate {
+            queued,
+            service_time,
+        }
+    }
+
+    fn remote(latency: f64, service_time: f64) -> RemoteEstimate {
+        RemoteEstimate {
+            latency: Some(latency),
+            service_time: Some(service_time),
+        }
+    }
+
+    #[test]
+    fn test_deadline() {
+        let model = CostModel {
+            offload_overhead: 5.0,
+            ..Default::default()
+        };
+        // Calibrated at 2 s, the node cannot boot for a request with 1.5 s left
+        let local = local(0, 2000.0);
+        let ms = Duration::from_millis;
+        // (budget, neighbor, local outcome, remote outcome)
+        let table = [
+            (ms(10_000), remote(40.0, 2000.0), true, true),
+            (ms(2000), remote(40.0, 1000.0), true, true),
+            (ms(1500), remote(40.0, 1000.0), false, true),
+            (ms(1500), remote(40.0, 1500.0), false, false),
+            (ms(50), remote(40.0, 1.0), false, true),
+            (ms(0), remote(0.0, 0.0), false, false),
+        ];
+        for (budget, remote, local_ok, remote_ok) in table {
+            assert_eq!(
+                model.local_deadline(budget, &local).is_ok(),
+                local_ok,
+                "{:?}",
+                budget
+            );
+            assert_eq!(
+                model.remote_deadline(budget, &remote, &local).is_ok(),
+                remote_ok,
+                "{:?} {:?}",
+                budget,
+                remote
+            );
+        }
+
+        // A neighbor never measured is assumed as fast as this node, 50 ms away
+        let unknown = RemoteEstimate::default();
+        let doomed = model
+            .remote_deadline(ms(2000), &unknown, &local)
+            .unwrap_err();
+        assert_eq!(
+            doomed,
+            Doomed {
+                budget: 2000.0,
+                expected: 2055.0
+            }
+        );
+        assert_eq!(doomed.to_string(), "2000 ms left, 2055 ms expected");
+    }
+
+    #[test]
+    fn test_local_deadline() {
+        let scheduler = Scheduler::default();
+        let deadline = Instant::now() + Duration::from_secs(1);
+        // Until calibrated, the default service time is assumed
+        assert!(scheduler.local_deadline(deadline).is_ok());
+        scheduler.calibrate(2000.0);
+        let doomed = scheduler.local_deadline(deadline).unwrap_err();
+        assert!(doomed.budget <= 1000.0);
+        assert_eq!(doomed.expected, 2000.0);
+        scheduler.calibrate(10.0);
+        assert!(scheduler.local_deadline(deadline).is_ok());
+        // Past its deadline, a request cannot be served
+        assert!(scheduler.local_deadline(Instant::now()).is_err());
+    }
+
+    #[test]
+    fn test_far_neighbor() {
+        let model = CostModel {
+            wait_factor: 0.5,
+            ..Default::default()
+        };
+        // A slot frees in 5 ms, the neighbor is 40 ms away
+        let decision = model.decide(&local(0, 10.0), Some(&remote(40.0, 10.0)));
+        assert_eq!(decision, Decision::Wait(Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_near_neighbor() {
+        let model = CostModel::default();
+        assert_eq!(
+            model.decide(&local(0, 100.0), Some(&remote(5.0, 100.0))),
+            Decision::Offload
+        );
+        // Unless it is much slower than this node
+        assert!(matches!(
+            model.decide(&local(0, 100.0), Some(&remote(5.0, 500.0))),
+            Decision::Wait(_)
+        ));
+    }
+
+    #[test]
+    fn test_long_queue() {
+        let model = CostModel::default();
+        let neighbor = remote(80.0, 10.0);
+        assert!(matches!(
+            model.decide(&local(0, 10.0), Some(&neighbor)),
+            Decision::Wait(_)
+        ));
+        // Every queued request makes the wait longer
+        assert_eq!(
+            model.decide(&local(10, 10.0), Some(&neighbor)),
+            Decision::Offload
+        );
+    }
+
+    #[test]
+    fn test_max_wait() {
+        let model = CostModel {
+            max_wait: 50.0,
+            ..Default::default()
+        };
+        // Even with nowhere to offload, requests do not wait forever
+        assert!(matches!(
+            model.decide(&local(3, 10.0), None),
+            Decision::Wait(_)
+        ));
+        assert_eq!(model.decide(&local(5, 10.0), None), Decision::Offload);
+    }
+
+    #[test]
+    fn test_unknown_neighbor() {
+        let model = CostModel {
+            default_latency: 30.0,
+            offload_overhead: 2.0,
+            ..Default::default()
+        };
+        let estimate = local(0, 20.0);
+        assert_eq!(
+            model.remote_time(&RemoteEstimate::default(), &estimate),
+            52.0
+        );
+        assert_eq!(model.local_time(&estimate), 40.0);
+        assert!(matches!(
+            model.decide(&estimate, Some(&RemoteEstimate::default())),
+            Decision::Wait(_)
+        ));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(CostModel::default().validate().is_ok());
+        let model = CostModel {
+            latency_factor: -1.0,
+            ..Default::default()
+        };
+        assert!(model.validate().is_err());
+        let model = CostModel {
+            max_wait: f64::NAN,
+            ..Default::default()
+        };
+        assert!(model.validate().is_err());
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let scheduler = Scheduler::new(CostModel::default());
+        assert_eq!(scheduler.local_estimate(), local(0, DEFAULT_SERVICE_TIME));
+
+        scheduler.calibrate(12.5);
+        let queued = scheduler.enqueue();
+        let other = scheduler.enqueue();
+        assert_eq!(scheduler.local_estimate(), local(2, 12.5));
+        drop(queued);
+        drop(other);
+        assert_eq!(scheduler.local_estimate().queued, 0);
+
+        assert_eq!(scheduler.remote_service_time("10.0.0.1:8085"), None);
+        scheduler.set_remote_service_time("10.0.0.1:8085", 30.0);
+        assert_eq!(scheduler.remote_service_time("10.0.0.1:8085"), Some(30.0));
+    }
+}
@@ -0,0 +1,345 @@
+//! Packing of the requests of mixed sizes on the cpus of the node.
+//! Admitting every request as soon as it fits keeps the small requests flowing, but a
+//! large one then only finds enough free cpus by chance: the cpus freed by an instance
+//! are taken by the next small request, and the large one waits until it gives up and
+//! is offloaded while the node runs well below its capacity.
+//! With packing, a small request that would take the cpus a larger waiting request needs
+//! is held back for a while, so that the cpus freed meanwhile add up. The hold is bounded:
+//! the small requests are delayed, never refused because of it.
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// Size class of a request, its vcpus rounded up to a power of two
+pub fn size_class(cpus: usize) -> usize {
+    cpus.max(1).next_power_of_two()
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Requests waiting for local resources, by number of cpus
+    waiting: BTreeMap<usize, usize>,
+    /// Cpus held by the running instances, by size class
+    held: BTreeMap<usize, usize>,
+    deferred: u64,
+    deferred_time: Duration,
+    stranded: u64,
+}
+
+/// Counters of the packing, as served on /debug/packing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PackingStats {
+    /// Longest time a request is held back for a larger one (in ms)
+    pub hold_ms: u64,
+    /// Cpus held by the running instances, by size class
+    pub held: BTreeMap<usize, usize>,
+    /// Requests waiting for local resources, by size class
+    pub waiting: BTreeMap<usize, usize>,
+    /// Cpus the requests without emergency can take now
+    pub free_cpus: usize,
+    /// Free cpus that are too few for the largest waiting request
+    pub fragmented_cpus: usize,
+    /// Requests held back for a larger one
+    pub deferred: u64,
+    /// Time the requests were held back, in total (in ms)
+    pub deferred_ms: u64,
+    /// Requests that gave up waiting while some cpus were free, but fewer than they needed
+    pub stranded: u64,
+}
+
+/// Packing policy, shared by the requests served by the node
+#[derive(Debug)]
+pub struct Packing {
+    /// Longest time a request is held back for a larger one
+    hold: Duration,
+    state: Mutex<State>,
+}
+
+/// A request waiting for local resources, seen by the smaller ones until dropped
+pub struct Waiting<'a> {
+    packing: &'a Packing,
+    cpus: usize,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut state = self.packing.state.lock().unwrap();
+        remove(&mut state.waiting, self.cpus, 1);
+    }
+}
+
+/// Take `count` from the entry of `key`, removing it once empty
+fn remove(map: &mut BTreeMap<usize, usize>, key: usize, count: usize) {
+    if let Some(value) = map.get_mut(&key) {
+        *value = value.saturating_sub(count);
+        if *value == 0 {
+            map.remove(&key);
+        }
+    }
+}
+
+impl Packing {
+    /// Create a new policy holding the small requests back for at most `hold`
+    pub fn new(hold: Duration) -> Self {
+        Packing {
+            hold,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Longest time a request is held back for a larger one
+    pub fn hold(&self) -> Duration {
+        self.hold
+    }
+
+    /// Put a request in the ones waiting for local resources
+    pub fn wait(&self, cpus: usize) -> Waiting<'_> {
+        *self.state.lock().unwrap().waiting.entry(cpus).or_default() += 1;
+        Waiting {
+            packing: self,
+            cpus,
+        }
+    }
+
+    /// Cpus of the largest request waiting for local resources, if any
+    pub fn largest_waiting(&self) -> Option<usize> {
+        let state = self.state.lock().unwrap();
+        state.waiting.keys().next_back().copied()
+    }
+
+    /// Whether a request should be held back: a larger request waits and taking the cpus
+    /// now would leave it fewer than it needs
+    /// # Arguments
+    /// * `cpus` - Cpus of the request
+    /// * `available` - Cpus the request could take now
+    pub fn defers(&self, cpus: usize, available: usize) -> bool {
+        match self.largest_waiting() {
+            Some(largest) if largest > cpus => available.saturating_sub(cpus) < largest,
+            _ => false,
+        }
+    }
+
+    /// Record the cpus taken by an instance
+    pub fn acquired(&self, cpus: usize) {
+        let mut state = self.state.lock().unwrap();
+        *state.held.entry(size_class(cpus)).or_default() += cpus;
+    }
+
+    /// Record the cpus released by an instance
+    pub fn released(&self, cpus: usize) {
+        let mut state = self.state.lock().unwrap();
+        remove(&mut state.held, size_class(cpus), cpus);
+    }
+
+    /// Record a request held back for a larger one
+    pub fn record_deferral(&self, held: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.deferred += 1;
+        state.deferred_time += held;
+    }
+
+    /// Record a request that gave up waiting for local resources
+    /// # Arguments
+    /// * `cpus` - Cpus of the request
+    /// * `available` - Cpus it could take when it gave up
+    pub fn record_gave_up(&self, cpus: usize, available: usize) {
+        if available > 0 && available < cpus {
+            self.state.lock().unwrap().stranded += 1;
+        }
+    }
+
+    /// Get the counters of the packing
+    /// # Arguments
+    /// * `free_cpus` - Cpus the requests without emergency can take now
+    pub fn stats(&self, free_cpus: usize) -> PackingStats {
+        let state = self.state.lock().unwrap();
+        let mut waiting = BTreeMap::new();
+        for (cpus, count) in &state.waiting {
+            *waiting.entry(size_class(*cpus)).or_default() += count;
+        }
+        let fragmented_cpus = match state.waiting.keys().next_back() {
+            Some(largest) if *largest > free_cpus => free_cpus,
+            _ => 0,
+        };
+        PackingStats {
+            hold_ms: self.hold.as_millis() as u64,
+            held: state.held.clone(),
+            waiting,
+            free_cpus,
+            fragmented_cpus,
+            deferred: state.deferred,
+            deferred_ms: state.deferred_time.as_millis() as u64,
+            stranded: state.stranded,
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::{prelude::*, test_runner::RngSeed};
+
+    /// A request of a simulated workload, times are in ms
+    #[derive(Clone, Debug)]
+    struct Job {
+        arrival: u64,
+        cpus: usize,
+        service: u64,
+    }
+
+    /// Longest time a simulated request waits for local resources (in ms), about what the
+    /// cost model gives to a request queued behind a few others
+    const PATIENCE: u64 = 300;
+
+    /// Simulate a node with `capacity` cpus serving `jobs`, sorted by arrival, one ms at a
+    /// time. A request that cannot run within `patience` ms of its arrival is offloaded,
+    /// the time it is held back included. Without packing every request is admitted as
+    /// soon as it fits, with packing it can be held back when it arrives, then it waits
+    /// for the resources like the others.
+    /// # Returns
+    /// * The work served locally, the cpus times the service time of each request
+    fn simulate(jobs: &[Job], capacity: usize, patience: u64, packing: Option<&Packing>) -> u64 {
+        let mut work = 0;
+        let mut free = capacity;
+        let mut running: Vec<(u64, usize)> = vec![];
+        let mut pending: Vec<(&Job, Option<Waiting>)> = vec![];
+        let mut arrivals = jobs.iter().peekable();
+        let mut now = 0;
+        while arrivals.peek().is_some() || !pending.is_empty() {
+            running.retain(|&(end, cpus)| {
+                if end <= now {
+                    free += cpus;
+                }
+                end > now
+            });
+            while let Some(job) = arrivals.next_if(|job| job.arrival <= now) {
+                pending.push((job, None));
+            }
+            pending.retain_mut(|(job, waiting)| {
+                let waited = now - job.arrival;
+                // Held back before it first tries, as the node does
+                let held = waiting.is_none()
+                    && packing.is_some_and(|packing| {
+                        waited < packing.hold().as_millis() as u64 && packing.defers(job.cpus, free)
+                    });
+                if !held && job.cpus <= free {
+                    free -= job.cpus;
+                    running.push((now + job.service, job.cpus));
+                    work += job.cpus as u64 * job.service;
+                    return false;
+                }
+                if !held && waiting.is_none() {
+                    *waiting = packing.map(|packing| packing.wait(job.cpus));
+                }
+                waited < patience
+            });
+            now += 1;
+        }
+        work
+    }
+
+    /// A node kept busy by short requests of one cpu, with a few longer ones taking from
+    /// half to all of its cpus
+    fn mixed_workload() -> impl Strategy<Value = (usize, Vec<Job>)> {
+        (8usize..=16).prop_flat_map(|capacity| {
+            let job = prop_oneof![
+                39 => (1u64..=12, Just(1), 10u64..=50),
+                1 => (1u64..=12, capacity / 2..=capacity, 100u64..=300),
+            ];
+            (
+                Just(capacity),
+                prop::collection::vec(job, 200..1000).prop_map(|jobs| {
+                    let mut arrival = 0;
+                    jobs.into_iter()
+                        .map(|(gap, cpus, service)| {
+                            arrival += gap;
+                            Job {
+                                arrival,
+                                cpus,
+                                service,
+                            }
+                        })
+                        .collect()
+                }),
+            )
+        })
+    }
+
+    proptest! {
+        // Seeded, the simulations are the same on every run
+        #![proptest_config(ProptestConfig {
+            cases: 32,
+            rng_seed: RngSeed::Fixed(1160),
+            ..ProptestConfig::default()
+        })]
+
+        // A single workload can be served better without packing, e.g. when a large
+        // request admitted earlier blocks a later one, so they are compared in batches
+        #[test]
+        fn test_packing_throughput(workloads in prop::collection::vec(mixed_workload(), 8)) {
+            let (mut naive, mut packed) = (0, 0);
+            for (capacity, jobs) in &workloads {
+                naive += simulate(jobs, *capacity, PATIENCE, None);
+                let packing = Packing::new(Duration::from_millis(100));
+                packed += simulate(jobs, *capacity, PATIENCE, Some(&packing));
+            }
+            prop_assert!(packed > naive, "{} <= {}", packed, naive);
+        }
+    }
+
+    #[test]
+    fn test_defers() {
+        let packing = Packing::new(Duration::from_millis(50));
+        assert_eq!(packing.largest_waiting(), None);
+        assert!(!packing.defers(1, 0));
+
+        let large = packing.wait(4);
+        let small = packing.wait(1);
+        assert_eq!(packing.largest_waiting(), Some(4));
+        // A small request is held back unless the large one still fits after it
+        assert!(packing.defers(1, 3));
+        assert!(packing.defers(2, 5));
+        assert!(!packing.defers(1, 5));
+        // Requests as large are never held back
+        assert!(!packing.defers(4, 4));
+        assert!(!packing.defers(8, 1));
+
+        drop(large);
+        assert_eq!(packing.largest_waiting(), Some(1));
+        assert!(!packing.defers(1, 0));
+        drop(small);
+        assert_eq!(packing.largest_waiting(), None);
+    }
+
+    #[test]
+    fn test_stats() {
+        let packing = Packing::new(Duration::from_millis(50));
+        packing.acquired(1);
+        packing.acquired(3);
+        packing.acquired(4);
+        packing.acquired(1);
+        packing.released(1);
+        let _waiting = [packing.wait(8), packing.wait(5), packing.wait(1)];
+        packing.record_deferral(Duration::from_millis(20));
+        packing.record_deferral(Duration::from_millis(30));
+        packing.record_gave_up(8, 3);
+        // Nothing was free, or enough was
+        packing.record_gave_up(8, 0);
+        packing.record_gave_up(1, 3);
+
+        let stats = packing.stats(3);
+        assert_eq!(stats.held, BTreeMap::from([(1, 1), (4, 7)]));
+        assert_eq!(stats.waiting, BTreeMap::from([(1, 1), (8, 2)]));
+        assert_eq!((stats.free_cpus, stats.fragmented_cpus), (3, 3));
+        assert_eq!((stats.deferred, stats.deferred_ms), (2, 50));
+        assert_eq!(stats.stranded, 1);
+        assert_eq!(packing.stats(8).fragmented_cpus, 0);
+    }
+
+    #[test]
+    fn test_size_class() {
+        let classes: Vec<_> = (0..=9).map(size_class).collect();
+        assert_eq!(classes, vec![1, 1, 2, 4, 4, 8, 8, 8, 8, 16]);
+    }
+}
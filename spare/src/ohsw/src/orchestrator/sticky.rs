@@ -0,0 +1,107 @@
+//! Sticky routing of the offloaded requests.
+//! Sending every offloaded invocation of a function to the same neighbor lets it reuse
+//! the image it already fetched. The neighbor is chosen by rendezvous hashing: every
+//! candidate gets a score from the hash of the function and of its address, and the
+//! highest score wins, so a node joining or leaving only moves the functions it wins.
+use sha2::{Digest, Sha256};
+
+use super::global::{NeighborNode, NeighborNodeType};
+
+/// Score of a node for a function, the same on every node and at every run
+fn score(function: &str, image: &str, address: &str) -> u64 {
+    let mut hasher = Sha256::new();
+    for part in [function, image, address] {
+        hasher.update(part.as_bytes());
+        // Keep ("ab", "c") and ("a", "bc") apart
+        hasher.update([0]);
+    }
+    let digest = hasher.finalize();
+    u64::from_be_bytes(digest[..8].try_into().unwrap())
+}
+
+/// Get the position of the preferred node of a function among the given addresses
+pub fn preferred<'a>(
+    function: &str,
+    image: &str,
+    addresses: impl IntoIterator<Item = &'a str>,
+) -> Option<usize> {
+    addresses
+        .into_iter()
+        .enumerate()
+        .max_by_key(|(_, address)| score(function, image, address))
+        .map(|(position, _)| position)
+}
+
+/// Move the preferred node of a function first, the others keep their ranking
+pub fn prefer(function: &str, image: &str, nodes: &mut Vec<NeighborNodeType>) {
+    let addresses: Vec<String> = nodes.iter().map(|node| node.address()).collect();
+    if let Some(position) = preferred(function, image, addresses.iter().map(String::as_str)) {
+        let node = nodes.remove(position);
+        nodes.insert(0, node);
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("10.0.0.{}:8085", i)).collect()
+    }
+
+    /// Address of the preferred node of each function
+    fn mapping(addresses: &[String]) -> Vec<String> {
+        (0..1000)
+            .map(|f| {
+                let function = format!("function-{}", f);
+                let position =
+                    preferred(&function, "image", addresses.iter().map(String::as_str)).unwrap();
+                addresses[position].clone()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_preferred() {
+        let addresses = nodes(5);
+        let first = preferred("f", "image", addresses.iter().map(String::as_str));
+        // The order of the candidates does not matter
+        let reversed: Vec<&str> = addresses.iter().rev().map(String::as_str).collect();
+        let second = preferred("f", "image", reversed.iter().copied()).unwrap();
+        assert_eq!(reversed[second], addresses[first.unwrap()]);
+
+        assert_eq!(preferred("f", "image", []), None);
+    }
+
+    #[test]
+    fn test_node_leaves() {
+        let mut addresses = nodes(20);
+        let before = mapping(&addresses);
+        let gone = addresses.remove(7);
+        let after = mapping(&addresses);
+        // Only the functions of the node that left move
+        for (before, after) in before.iter().zip(&after) {
+            if *before != gone {
+                assert_eq!(before, after);
+            }
+        }
+    }
+
+    #[test]
+    fn test_node_joins() {
+        let mut addresses = nodes(20);
+        let before = mapping(&addresses);
+        addresses.push("10.0.1.0:8085".to_string());
+        let after = mapping(&addresses);
+        // The functions that move all go to the new node, about 1 in 21 of them
+        let moved: Vec<&String> = before
+            .iter()
+            .zip(&after)
+            .filter(|(before, after)| before != after)
+            .map(|(_, after)| after)
+            .collect();
+        assert!(moved.iter().all(|after| *after == "10.0.1.0:8085"));
+        assert!(!moved.is_empty() && moved.len() < 2 * 1000 / 21);
+    }
+}
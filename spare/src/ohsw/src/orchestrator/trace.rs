@@ -0,0 +1,257 @@
+//! Traces of the offload decisions, to explain after the fact why a request went to a
+//! neighbor rather than another. Each trace keeps the candidates in the order they were
+//! ranked, what the probe of each one answered and where the request went.
+//! Recording allocates for every offload, so the traces are only kept when enabled, in a
+//! bounded ring where the oldest are dropped first.
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use actix_web::HttpResponse;
+use serde::{Deserialize, Serialize};
+
+/// Number of traces returned by default
+pub const DEFAULT_LIMIT: usize = 100;
+
+/// A neighbor the request could be offloaded to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candidate {
+    pub address: String,
+    /// Distance from this node (in meters)
+    pub distance: f64,
+    /// Latency last measured or estimated (in ms), if known
+    pub latency: Option<f64>,
+}
+
+/// What the probe of the resources of a neighbor answered
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Probe {
+    /// The resources the neighbor offers to the request
+    Resources {
+        cpus: usize,
+        memory: usize,
+        queued_requests: usize,
+    },
+    /// The neighbor did not answer, or not with its resources
+    Error(String),
+    /// The neighbor was not probed
+    Skipped,
+}
+
+/// What was made of a neighbor once probed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AttemptOutcome {
+    /// The resources of the neighbor are unknown
+    Unreachable,
+    /// The neighbor cannot take the request
+    Insufficient,
+    /// The neighbor has requests waiting, it is tried again after the others
+    Deferred,
+    /// The request was forwarded and the neighbor served it
+    Forwarded { elapsed_ms: u64 },
+    /// The request was forwarded but the neighbor failed it
+    Failed { error: String },
+    /// The neighbor is already running a request with the same idempotency key
+    Conflict,
+    /// The neighbor lacks capabilities the function requires, it was not probed
+    MissingCapabilities { missing: Vec<String> },
+    /// The neighbor does not have the image of the request
+    MissingImage,
+    /// The neighbor cannot answer before the deadline of the request, it was not probed
+    DeadlineExceeded { budget_ms: u64, expected_ms: u64 },
+}
+
+/// A neighbor tried for the request, in the order they were tried
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attempt {
+    pub address: String,
+    pub probe: Probe,
+    pub outcome: AttemptOutcome,
+}
+
+/// The decisions taken to offload a request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OffloadTrace {
+    /// Identifier of the trace, also recorded with the request
+    pub id: u64,
+    pub at: chrono::NaiveDateTime,
+    pub function: String,
+    pub hops: i32,
+    pub emergency: bool,
+    /// Address of the node that sent the request, if known
+    pub origin: Option<String>,
+    /// Strategy that ranked the candidates
+    pub strategy: String,
+    /// Whether the preferred neighbor of the function was moved first
+    pub sticky: bool,
+    /// Neighbors the request could go to, best ranked first
+    pub candidates: Vec<Candidate>,
+    pub attempts: Vec<Attempt>,
+    /// Neighbor that served the request, None if it was rejected
+    pub chosen: Option<String>,
+}
+
+/// Identifier of the trace of an offloaded request, attached to its response so the
+/// request can be recorded with it
+#[derive(Debug, Clone, Copy)]
+pub struct OffloadTraceId(pub u64);
+
+/// Get the identifier of the trace attached to a response, if any
+pub fn trace_id(response: &HttpResponse) -> Option<u64> {
+    response.extensions().get::<OffloadTraceId>().map(|id| id.0)
+}
+
+/// The last offload traces of the node
+pub struct OffloadTraces {
+    traces: Mutex<VecDeque<OffloadTrace>>,
+    capacity: usize,
+    next_id: AtomicU64,
+}
+
+impl OffloadTraces {
+    /// Create a new ring keeping at most `capacity` traces
+    pub fn new(capacity: usize) -> Self {
+        OffloadTraces {
+            traces: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Add a trace, dropping the oldest one if the ring is full
+    /// # Returns
+    /// * The identifier given to the trace
+    fn push(&self, mut trace: OffloadTrace) -> u64 {
+        trace.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let id = trace.id;
+        let mut traces = self.traces.lock().unwrap();
+        if traces.len() == self.capacity {
+            traces.pop_front();
+        }
+        traces.push_back(trace);
+        id
+    }
+
+    /// The newest `limit` traces, newest first
+    pub fn recent(&self, limit: usize) -> Vec<OffloadTrace> {
+        let traces = self.traces.lock().unwrap();
+        traces.iter().rev().take(limit).cloned().collect()
+    }
+}
+
+/// Trace of an offload in progress, that does nothing when the traces are disabled
+pub struct Recording<'a> {
+    traces: Option<&'a OffloadTraces>,
+    trace: Option<OffloadTrace>,
+}
+
+impl<'a> Recording<'a> {
+    /// Start recording, building the trace only if the traces are enabled
+    pub fn start(traces: Option<&'a OffloadTraces>, trace: impl FnOnce() -> OffloadTrace) -> Self {
+        Recording {
+            traces,
+            trace: traces.map(|_| trace()),
+        }
+    }
+
+    /// Add a neighbor that was tried
+    pub fn attempt(&mut self, attempt: impl FnOnce() -> Attempt) {
+        if let Some(trace) = self.trace.as_mut() {
+            trace.attempts.push(attempt());
+        }
+    }
+
+    /// Keep the trace, with the neighbor that served the request if any, and attach its
+    /// identifier to the response
+    pub fn finish(self, chosen: Option<&str>, response: &mut HttpResponse) {
+        if let (Some(traces), Some(mut trace)) = (self.traces, self.trace) {
+            trace.chosen = chosen.map(str::to_string);
+            let id = traces.push(trace);
+            response.extensions_mut().insert(OffloadTraceId(id));
+        }
+    }
+}
+
+impl OffloadTrace {
+    /// Create the trace of a request, before any neighbor is tried
+    pub fn new(
+        function: &str,
+        hops: i32,
+        emergency: bool,
+        origin: Option<String>,
+        strategy: String,
+        sticky: bool,
+        candidates: Vec<Candidate>,
+    ) -> Self {
+        OffloadTrace {
+            id: 0,
+            at: chrono::Utc::now().naive_utc(),
+            function: function.to_string(),
+            hops,
+            emergency,
+            origin,
+            strategy,
+            sticky,
+            candidates,
+            attempts: vec![],
+            chosen: None,
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trace(function: &str) -> OffloadTrace {
+        OffloadTrace::new(
+            function,
+            0,
+            false,
+            None,
+            "GeoDistance".to_string(),
+            true,
+            vec![],
+        )
+    }
+
+    #[test]
+    fn test_ring() {
+        let traces = OffloadTraces::new(2);
+        for function in ["a", "b", "c"] {
+            let mut response = HttpResponse::Ok().finish();
+            let mut recording = Recording::start(Some(&traces), || trace(function));
+            recording.attempt(|| Attempt {
+                address: "10.0.0.1:8085".to_string(),
+                probe: Probe::Error("refused".to_string()),
+                outcome: AttemptOutcome::Unreachable,
+            });
+            recording.finish(Some("10.0.0.2:8085"), &mut response);
+            assert!(trace_id(&response).is_some());
+        }
+
+        // Only the last traces are kept, newest first
+        let recent = traces.recent(usize::MAX);
+        let kept: Vec<_> = recent.iter().map(|t| (t.id, t.function.as_str())).collect();
+        assert_eq!(kept, vec![(3, "c"), (2, "b")]);
+        assert_eq!(recent[0].attempts.len(), 1);
+        assert_eq!(recent[0].chosen.as_deref(), Some("10.0.0.2:8085"));
+        assert_eq!(traces.recent(1).len(), 1);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut response = HttpResponse::Ok().finish();
+        let mut recording = Recording::start(None, || unreachable!());
+        recording.attempt(|| unreachable!());
+        recording.finish(None, &mut response);
+        assert_eq!(trace_id(&response), None);
+    }
+}
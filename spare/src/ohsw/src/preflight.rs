@@ -0,0 +1,860 @@
+//! Checks run before the node starts.
+//! A node missing KVM, its bridge or its database used to start anyway, and the first
+//! invoke failed with an error far from the cause. The preflight checks every
+//! precondition up front and reports all the failures at once, each with a hint on how
+//! to fix it.
+//! The pool of the instances is checked against the addresses of the host as well: a pool
+//! overlapping the management network hands out to the instances the addresses of real
+//! hosts, which fails far from the cause.
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    future::Future,
+    io::Read,
+    net::{IpAddr, Ipv4Addr},
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use ipnetwork::Ipv4Network;
+
+use crate::{db, orchestrator::global::NeighborNodeStrategy};
+
+/// Time after which the broker is considered unreachable
+pub const BROKER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Fewest usable addresses of the pool of the instances
+pub const MIN_POOL_ADDRESSES: u32 = 4;
+
+/// What the node needs, as configured
+#[derive(Debug, Clone, Default)]
+pub struct PreflightConfig {
+    /// From FIRECRACKER_EXECUTABLE
+    pub executable: Option<String>,
+    /// From NANOS_KERNEL
+    pub kernel: Option<String>,
+    /// Bridge the instances are attached to
+    pub bridge: String,
+    /// Bridges of the other networks of the instances
+    pub extra_bridges: Vec<String>,
+    /// From DATABASE_URL
+    pub database_url: Option<String>,
+    /// Keep the default sqlite settings
+    pub legacy_sqlite: bool,
+    /// Address of the broker, None if the node does not use one
+    pub broker: Option<String>,
+    /// The functions run on the host (`--runtime simulate`): no KVM, firecracker, kernel,
+    /// bridge or tap is needed
+    pub simulate: bool,
+    /// Pool of the instances and the addresses it must keep clear of, None to skip the check
+    pub network: Option<NetworkPlan>,
+    /// Report the problems of the network without failing, for the setups that mean them
+    pub allow_network_conflicts: bool,
+    /// How a node without a position ranks its neighbors, None if it has a position
+    pub positionless: Option<PositionlessPlan>,
+}
+
+/// How a node started with --positionless ranks its neighbors
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PositionlessPlan {
+    /// Strategy the neighbors are ranked by
+    pub strategy: NeighborNodeStrategy,
+    /// Whether the position of the node is followed from a file (--position-file)
+    pub position_file: bool,
+}
+
+impl PositionlessPlan {
+    /// Check that nothing needs the position of the node, reporting every problem at once
+    pub fn check(&self) -> Result<String, String> {
+        let mut problems = Vec::new();
+        if self.strategy.needs_position() {
+            problems.push(format!(
+                "the {} strategy ranks the neighbors by their positions, use Probed or \
+                 SmartLatency without one",
+                self.strategy
+            ));
+        }
+        if self.position_file {
+            problems.push("--position-file gives the node a position".to_string());
+        }
+        match problems.is_empty() {
+            true => Ok(format!(
+                "no position, neighbors ranked by {} only",
+                self.strategy
+            )),
+            false => Err(problems.join("; ")),
+        }
+    }
+}
+
+/// Pool of the instances, with the addresses of the host it must not contain
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NetworkPlan {
+    /// Pool of the instances, as given with --cidr
+    pub cidr: String,
+    /// Address of the node on its management network
+    pub management: Option<IpAddr>,
+    /// Address of the broker, None if the node uses none or knows it by name
+    pub broker: Option<IpAddr>,
+    /// IPv4 address of the bridge, None if it has none or it is not known
+    pub bridge_address: Option<Ipv4Addr>,
+}
+
+impl NetworkPlan {
+    /// Check the plan, reporting every problem at once
+    pub fn check(&self) -> Result<String, String> {
+        let pool = parse_pool(&self.cidr)?;
+        let mut problems = vec![];
+        let overlaps = |address: &Option<IpAddr>| match address {
+            Some(IpAddr::V4(address)) => pool.contains(*address).then_some(*address),
+            // The pool is IPv4 only
+            _ => None,
+        };
+        if let Some(address) = overlaps(&self.management) {
+            problems.push(format!(
+                "{pool} contains the address of the node {address}, so the instances would                  take the addresses of the hosts of its network. Pass a network of its own                  to the instances with --cidr"
+            ));
+        }
+        if let Some(address) = overlaps(&self.broker) {
+            problems.push(format!(
+                "{pool} contains the address of the broker {address}. Pass a network of                  its own to the instances with --cidr"
+            ));
+        }
+        // The instances use the first address as their gateway
+        let gateway = pool.nth(1).unwrap_or(pool.network());
+        match self.bridge_address {
+            Some(address) if address != gateway => problems.push(format!(
+                "the bridge has the address {address}, not the gateway of {pool}, {gateway}.                  Assign it with `ip addr add {gateway}/{} dev <bridge>`",
+                pool.prefix()
+            )),
+            _ => {}
+        }
+        let usable = pool.size().saturating_sub(2);
+        if usable < MIN_POOL_ADDRESSES {
+            problems.push(format!(
+                "{pool} has {usable} usable addresses, at least {MIN_POOL_ADDRESSES} are                  needed. Pass a larger network with --cidr"
+            ));
+        }
+        match problems.is_empty() {
+            true => Ok(format!("{pool} has {usable} addresses for the instances")),
+            false => Err(problems.join("; ")),
+        }
+    }
+}
+
+/// Parse the pool of the instances, an IPv4 network as address/prefix
+fn parse_pool(cidr: &str) -> Result<Ipv4Network, String> {
+    let invalid = |reason: String| format!("--cidr {cidr} is not valid: {reason}");
+    let (address, prefix) = cidr
+        .split_once('/')
+        .ok_or_else(|| invalid("expected address/prefix".to_string()))?;
+    let address: IpAddr = address.parse().map_err(|e| invalid(format!("{e}")))?;
+    let IpAddr::V4(address) = address else {
+        return Err(invalid("the instances get IPv4 addresses only".to_string()));
+    };
+    let prefix = prefix.parse().map_err(|e| invalid(format!("{e}")))?;
+    // The pool starts at the network address, whatever address it is given with
+    let network = Ipv4Network::new(address, prefix).map_err(|e| invalid(format!("{e}")))?;
+    Ipv4Network::new(network.network(), prefix).map_err(|e| invalid(format!("{e}")))
+}
+
+/// Read the IPv4 address of an interface from the output of `ip -4 -o addr show dev <name>`
+pub fn parse_interface_address(output: &str) -> Option<Ipv4Addr> {
+    output
+        .split_whitespace()
+        .skip_while(|field| *field != "inet")
+        .nth(1)?
+        .split('/')
+        .next()?
+        .parse()
+        .ok()
+}
+
+impl PreflightConfig {
+    /// Create a new configuration, reading the paths from the environment variables
+    pub fn from_env(bridge: &str, legacy_sqlite: bool, broker: Option<String>) -> Self {
+        Self {
+            executable: std::env::var("FIRECRACKER_EXECUTABLE").ok(),
+            kernel: std::env::var("NANOS_KERNEL").ok(),
+            bridge: bridge.to_string(),
+            extra_bridges: vec![],
+            database_url: std::env::var("DATABASE_URL").ok(),
+            legacy_sqlite,
+            broker,
+            simulate: false,
+            network: None,
+            allow_network_conflicts: false,
+            positionless: None,
+        }
+    }
+}
+
+/// The probes of the host, each returns what it found or why it failed
+pub trait Probes {
+    /// Check that /dev/kvm can be opened
+    fn kvm(&self) -> Result<String, String>;
+    /// Check that the firecracker executable runs, returning its version
+    fn firecracker(&self, executable: &Path) -> Result<String, String>;
+    /// Check that the kernel can be read
+    fn kernel(&self, kernel: &Path) -> Result<String, String>;
+    /// Check that the bridge exists
+    fn bridge(&self, name: &str) -> Result<String, String>;
+    /// Get the IPv4 address of the bridge, None if it has none or it cannot be read
+    fn bridge_address(&self, name: &str) -> Option<Ipv4Addr>;
+    /// Check that the tun device can be opened, to create the taps
+    fn tun(&self) -> Result<String, String>;
+    /// Check that the database can be opened and migrated
+    fn database(&self, url: &str, legacy: bool) -> impl Future<Output = Result<String, String>>;
+    /// Check that the broker accepts connections
+    fn broker(&self, address: &str) -> impl Future<Output = Result<String, String>>;
+}
+
+/// Probes of the actual host, the paths are relative to `root`
+pub struct HostProbes {
+    root: PathBuf,
+}
+
+impl Default for HostProbes {
+    fn default() -> Self {
+        Self::new(PathBuf::from("/"))
+    }
+}
+
+impl HostProbes {
+    /// Create new probes of the host whose /dev and /sys are under `root`
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// Open a device for reading and writing
+    fn open_device(&self, device: &str) -> std::io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(self.root.join(device))
+    }
+}
+
+impl Probes for HostProbes {
+    fn kvm(&self) -> Result<String, String> {
+        self.open_device("dev/kvm")
+            .map(|_| "/dev/kvm is available".to_string())
+            .map_err(|e| {
+                format!(
+                    "cannot open /dev/kvm: {e}. Enable the virtualization extensions and \
+                     load the kvm module, then add the user to the kvm group"
+                )
+            })
+    }
+
+    fn firecracker(&self, executable: &Path) -> Result<String, String> {
+        let output = Command::new(executable)
+            .arg("--version")
+            .output()
+            .map_err(|e| {
+                format!(
+                    "cannot run {}: {e}. Point FIRECRACKER_EXECUTABLE to an executable \
+                     firecracker binary",
+                    executable.display()
+                )
+            })?;
+        if !output.status.success() {
+            return Err(format!(
+                "{} --version exited with {}: {}",
+                executable.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        Ok(stdout.lines().next().unwrap_or_default().trim().to_string())
+    }
+
+    fn kernel(&self, kernel: &Path) -> Result<String, String> {
+        let mut first = [0; 1];
+        File::open(kernel)
+            .and_then(|mut file| file.read_exact(&mut first))
+            .map(|_| format!("{} is readable", kernel.display()))
+            .map_err(|e| {
+                format!(
+                    "cannot read {}: {e}. Point NANOS_KERNEL to the Nanos kernel",
+                    kernel.display()
+                )
+            })
+    }
+
+    fn bridge(&self, name: &str) -> Result<String, String> {
+        let interface = self.root.join("sys/class/net").join(name);
+        if interface.join("bridge").is_dir() {
+            Ok(format!("{name} exists"))
+        } else if interface.exists() {
+            Err(format!(
+                "{name} is not a bridge. Pass the name of the bridge with --bridge-name"
+            ))
+        } else {
+            Err(format!(
+                "{name} does not exist. Create it with `ip link add {name} type bridge` \
+                 and `ip link set {name} up`"
+            ))
+        }
+    }
+
+    fn bridge_address(&self, name: &str) -> Option<Ipv4Addr> {
+        let output = Command::new("ip")
+            .args(["-4", "-o", "addr", "show", "dev", name])
+            .output()
+            .ok()?;
+        parse_interface_address(&String::from_utf8_lossy(&output.stdout))
+    }
+
+    fn tun(&self) -> Result<String, String> {
+        self.open_device("dev/net/tun")
+            .map(|_| "/dev/net/tun is available".to_string())
+            .map_err(|e| {
+                format!(
+                    "cannot open /dev/net/tun: {e}. Load the tun module and run the node \
+                     with CAP_NET_ADMIN"
+                )
+            })
+    }
+
+    async fn database(&self, url: &str, legacy: bool) -> Result<String, String> {
+        let pool = db::connect(url, legacy).await.map_err(|e| {
+            format!("cannot open and migrate {url}: {e}. Check DATABASE_URL and its directory")
+        })?;
+        pool.close().await;
+        Ok(format!("{url} is up to date"))
+    }
+
+    async fn broker(&self, address: &str) -> Result<String, String> {
+        match actix_web::rt::time::timeout(
+            BROKER_TIMEOUT,
+            actix_web::rt::net::TcpStream::connect(address),
+        )
+        .await
+        {
+            Ok(Ok(_)) => Ok(format!("{address} accepts connections")),
+            Ok(Err(e)) => Err(format!(
+                "cannot connect to {address}: {e}. Start the broker or fix --broker-address \
+                 and --broker-port"
+            )),
+            Err(_) => Err(format!(
+                "{address} did not answer within {BROKER_TIMEOUT:?}. Check the firewall"
+            )),
+        }
+    }
+}
+
+/// Outcome of a check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Result<String, String>,
+}
+
+/// Outcome of all the checks
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    /// Check whether every check passed
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|c| c.outcome.is_ok())
+    }
+
+    /// The checks that failed
+    pub fn failures(&self) -> impl Iterator<Item = &Check> {
+        self.checks.iter().filter(|c| c.outcome.is_err())
+    }
+
+    /// What the check named `name` found, if it ran and passed
+    pub fn found(&self, name: &str) -> Option<&str> {
+        self.checks
+            .iter()
+            .find(|c| c.name == name)
+            .and_then(|c| c.outcome.as_deref().ok())
+    }
+
+    fn push(&mut self, name: &'static str, outcome: Result<String, String>) {
+        self.checks.push(Check { name, outcome });
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.outcome {
+                Ok(found) => writeln!(f, "[ ok ] {}: {}", check.name, found)?,
+                Err(e) => writeln!(f, "[FAIL] {}: {}", check.name, e)?,
+            }
+        }
+        match self.failures().count() {
+            0 => write!(f, "All {} checks passed", self.checks.len()),
+            failed => write!(f, "{} of {} checks failed", failed, self.checks.len()),
+        }
+    }
+}
+
+/// Run every check, even after a failure, so all the problems are reported at once
+pub async fn run(config: &PreflightConfig, probes: &impl Probes) -> Report {
+    let mut report = Report::default();
+    if !config.simulate {
+        check_machines(config, probes, &mut report);
+    }
+    if let Some(plan) = &config.network {
+        // The functions run on the host do not use the bridge
+        let plan = NetworkPlan {
+            bridge_address: match config.simulate {
+                true => None,
+                false => probes.bridge_address(&config.bridge),
+            },
+            ..plan.clone()
+        };
+        let outcome = match plan.check() {
+            Err(e) if config.allow_network_conflicts => Ok(format!("ignored: {e}")),
+            outcome => outcome,
+        };
+        report.push("network", outcome);
+    }
+    if let Some(plan) = &config.positionless {
+        report.push("position", plan.check());
+    }
+    report.push(
+        "database",
+        match &config.database_url {
+            Some(url) => probes.database(url, config.legacy_sqlite).await,
+            None => Err(
+                "DATABASE_URL is not set. Export the URL of the sqlite database \
+                         (e.g. sqlite://spare.db)"
+                    .to_string(),
+            ),
+        },
+    );
+    if let Some(broker) = &config.broker {
+        report.push("broker", probes.broker(broker).await);
+    }
+    report
+}
+
+/// Check what the machines of the instances need
+fn check_machines(config: &PreflightConfig, probes: &impl Probes, report: &mut Report) {
+    report.push("kvm", probes.kvm());
+    report.push(
+        "firecracker",
+        match &config.executable {
+            Some(executable) => probes.firecracker(Path::new(executable)),
+            None => Err("FIRECRACKER_EXECUTABLE is not set. Export the path of the \
+                         firecracker binary"
+                .to_string()),
+        },
+    );
+    report.push(
+        "kernel",
+        match &config.kernel {
+            Some(kernel) => probes.kernel(Path::new(kernel)),
+            None => Err("NANOS_KERNEL is not set. Export the path of the Nanos kernel".to_string()),
+        },
+    );
+    report.push("bridge", probes.bridge(&config.bridge));
+    for bridge in &config.extra_bridges {
+        report.push("bridge", probes.bridge(bridge));
+    }
+    report.push("tun", probes.tun());
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        os::unix::fs::PermissionsExt,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    /// Probes answering from a list of the broken ones, recording the probed names
+    #[derive(Default)]
+    struct FakeProbes {
+        broken: Vec<&'static str>,
+        probed: Arc<Mutex<Vec<&'static str>>>,
+        bridge_address: Option<Ipv4Addr>,
+    }
+
+    impl FakeProbes {
+        fn probe(&self, name: &'static str) -> Result<String, String> {
+            self.probed.lock().unwrap().push(name);
+            match self.broken.contains(&name) {
+                true => Err(format!("{name} is broken")),
+                false => Ok(format!("{name} works")),
+            }
+        }
+    }
+
+    impl Probes for FakeProbes {
+        fn kvm(&self) -> Result<String, String> {
+            self.probe("kvm")
+        }
+
+        fn firecracker(&self, _executable: &Path) -> Result<String, String> {
+            self.probe("firecracker")
+        }
+
+        fn kernel(&self, _kernel: &Path) -> Result<String, String> {
+            self.probe("kernel")
+        }
+
+        fn bridge(&self, _name: &str) -> Result<String, String> {
+            self.probe("bridge")
+        }
+
+        fn bridge_address(&self, _name: &str) -> Option<Ipv4Addr> {
+            self.bridge_address
+        }
+
+        fn tun(&self) -> Result<String, String> {
+            self.probe("tun")
+        }
+
+        async fn database(&self, _url: &str, _legacy: bool) -> Result<String, String> {
+            self.probe("database")
+        }
+
+        async fn broker(&self, _address: &str) -> Result<String, String> {
+            self.probe("broker")
+        }
+    }
+
+    fn config() -> PreflightConfig {
+        PreflightConfig {
+            executable: Some("/usr/bin/firecracker".to_string()),
+            kernel: Some("/srv/kernel.img".to_string()),
+            bridge: "br0".to_string(),
+            extra_bridges: vec![],
+            database_url: Some("sqlite::memory:".to_string()),
+            legacy_sqlite: false,
+            broker: Some("127.0.0.1:8090".to_string()),
+            simulate: false,
+            network: None,
+            allow_network_conflicts: false,
+            positionless: None,
+        }
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("spare-preflight-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[actix_web::test]
+    async fn test_all_failures_reported() {
+        let probes = FakeProbes {
+            broken: vec!["kvm", "tun", "broker"],
+            ..Default::default()
+        };
+        let report = run(&config(), &probes).await;
+        assert!(!report.is_ok());
+        let failed: Vec<_> = report.failures().map(|c| c.name).collect();
+        assert_eq!(failed, vec!["kvm", "tun", "broker"]);
+        // Every probe ran despite the first failure
+        assert_eq!(probes.probed.lock().unwrap().len(), 7);
+        let text = report.to_string();
+        assert!(text.contains("[FAIL] kvm: kvm is broken"));
+        assert!(text.contains("[ ok ] bridge: bridge works"));
+        assert!(text.ends_with("3 of 7 checks failed"));
+        assert_eq!(report.found("firecracker"), Some("firecracker works"));
+        assert_eq!(report.found("kvm"), None);
+    }
+
+    #[actix_web::test]
+    async fn test_passing_and_optional() {
+        let probes = FakeProbes::default();
+        let report = run(
+            &PreflightConfig {
+                broker: None,
+                ..config()
+            },
+            &probes,
+        )
+        .await;
+        assert!(report.is_ok());
+        assert_eq!(report.checks.len(), 6);
+        assert!(report.to_string().ends_with("All 6 checks passed"));
+    }
+
+    #[actix_web::test]
+    async fn test_missing_environment() {
+        let probes = FakeProbes::default();
+        let config = PreflightConfig {
+            executable: None,
+            kernel: None,
+            database_url: None,
+            ..config()
+        };
+        let report = run(&config, &probes).await;
+        let failed: Vec<_> = report.failures().map(|c| c.name).collect();
+        assert_eq!(failed, vec!["firecracker", "kernel", "database"]);
+        assert!(report
+            .to_string()
+            .contains("FIRECRACKER_EXECUTABLE is not set"));
+        // Nothing to probe without a path
+        assert_eq!(
+            *probes.probed.lock().unwrap(),
+            vec!["kvm", "bridge", "tun", "broker"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_simulate() {
+        let probes = FakeProbes {
+            broken: vec!["kvm", "firecracker", "kernel", "bridge", "tun"],
+            ..Default::default()
+        };
+        let config = PreflightConfig {
+            executable: None,
+            kernel: None,
+            simulate: true,
+            ..config()
+        };
+        let report = run(&config, &probes).await;
+        // Nothing of the machines is needed to run the functions on the host
+        assert!(report.is_ok());
+        assert_eq!(*probes.probed.lock().unwrap(), vec!["database", "broker"]);
+        assert_eq!(report.found("firecracker"), None);
+    }
+
+    #[test]
+    fn test_network_plan() {
+        let ip = |address: &str| Some(address.parse::<IpAddr>().unwrap());
+        let plan = |cidr: &str| NetworkPlan {
+            cidr: cidr.to_string(),
+            management: ip("10.0.0.5"),
+            broker: ip("10.0.0.2"),
+            bridge_address: Some(Ipv4Addr::new(192, 168, 30, 1)),
+        };
+        // (plan, the problem it has, if any)
+        let table = [
+            (plan("192.168.30.0/24"), None),
+            // Given with another address of the network
+            (plan("192.168.30.1/24"), None),
+            (
+                NetworkPlan {
+                    bridge_address: None,
+                    management: ip("fd00::5"),
+                    ..plan("192.168.30.0/29")
+                },
+                None,
+            ),
+            (plan("10.0.0.0/16"), Some("address of the node 10.0.0.5")),
+            (
+                NetworkPlan {
+                    management: ip("172.16.0.5"),
+                    ..plan("10.0.0.0/24")
+                },
+                Some("address of the broker 10.0.0.2"),
+            ),
+            (
+                NetworkPlan {
+                    bridge_address: Some(Ipv4Addr::new(192, 168, 30, 254)),
+                    ..plan("192.168.30.0/24")
+                },
+                Some("ip addr add 192.168.30.1/24"),
+            ),
+            (plan("192.168.30.0/30"), Some("2 usable addresses")),
+            (plan("192.168.30.0/31"), Some("0 usable addresses")),
+            (plan("fd00::/64"), Some("IPv4 addresses only")),
+            (plan("192.168.30.0"), Some("expected address/prefix")),
+            (plan("192.168.30.0/33"), Some("is not valid")),
+        ];
+        for (plan, problem) in table {
+            match (plan.check(), problem) {
+                (Ok(_), None) => {}
+                (Err(e), Some(problem)) => assert!(e.contains(problem), "{}: {}", plan.cidr, e),
+                (outcome, _) => panic!("{}: {:?}", plan.cidr, outcome),
+            }
+        }
+
+        // Every problem is reported at once
+        let e = NetworkPlan {
+            cidr: "10.0.0.0/30".to_string(),
+            management: ip("10.0.0.1"),
+            broker: ip("10.0.0.2"),
+            bridge_address: None,
+        }
+        .check()
+        .unwrap_err();
+        assert_eq!(e.split("; ").count(), 3, "{}", e);
+    }
+
+    #[actix_web::test]
+    async fn test_network_check() {
+        let probes = FakeProbes {
+            bridge_address: Some(Ipv4Addr::new(10, 0, 0, 1)),
+            ..Default::default()
+        };
+        let config = PreflightConfig {
+            network: Some(NetworkPlan {
+                cidr: "192.168.30.0/24".to_string(),
+                ..Default::default()
+            }),
+            ..config()
+        };
+        let report = run(&config, &probes).await;
+        assert_eq!(
+            report.failures().map(|c| c.name).collect::<Vec<_>>(),
+            vec!["network"]
+        );
+        // The setups meaning it are let through, with the problem still reported
+        let report = run(
+            &PreflightConfig {
+                allow_network_conflicts: true,
+                ..config.clone()
+            },
+            &probes,
+        )
+        .await;
+        assert!(report.is_ok());
+        assert!(report
+            .found("network")
+            .unwrap()
+            .starts_with("ignored: the bridge has the address 10.0.0.1"));
+        // Without the bridge, its address is not checked
+        let report = run(
+            &PreflightConfig {
+                simulate: true,
+                ..config
+            },
+            &probes,
+        )
+        .await;
+        assert!(report.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_positionless() {
+        let plan = |strategy: &str, position_file: bool| PositionlessPlan {
+            strategy: strategy.parse().unwrap(),
+            position_file,
+        };
+        assert_eq!(
+            plan("Probed", false).check(),
+            Ok("no position, neighbors ranked by Probed only".to_string())
+        );
+        assert!(plan("SmartLatency:window=8", false).check().is_ok());
+        assert!(plan("GeoDistance", false)
+            .check()
+            .unwrap_err()
+            .starts_with("the GeoDistance strategy ranks the neighbors by their positions"));
+        assert!(plan("SimpleCellular", false).check().is_err());
+        assert_eq!(
+            plan("Probed", true).check(),
+            Err("--position-file gives the node a position".to_string())
+        );
+        assert_eq!(
+            plan("GeoDistance", true)
+                .check()
+                .unwrap_err()
+                .split("; ")
+                .count(),
+            2
+        );
+
+        let config = PreflightConfig {
+            positionless: Some(plan("GeoDistance", false)),
+            ..config()
+        };
+        let report = run(&config, &FakeProbes::default()).await;
+        assert_eq!(
+            report.failures().map(|c| c.name).collect::<Vec<_>>(),
+            vec!["position"]
+        );
+    }
+
+    #[test]
+    fn test_parse_interface_address() {
+        let output = "5: br0    inet 192.168.30.1/24 brd 192.168.30.255 scope global br0\\       \
+                      valid_lft forever preferred_lft forever\n";
+        assert_eq!(
+            parse_interface_address(output),
+            Some(Ipv4Addr::new(192, 168, 30, 1))
+        );
+        assert_eq!(parse_interface_address(""), None);
+    }
+
+    #[test]
+    fn test_host_devices() {
+        let root = temp_dir();
+        let probes = HostProbes::new(root.clone());
+        assert!(probes.kvm().unwrap_err().contains("kvm group"));
+        assert!(probes.tun().unwrap_err().contains("tun module"));
+        assert!(probes
+            .bridge("br0")
+            .unwrap_err()
+            .contains("ip link add br0"));
+
+        fs::create_dir_all(root.join("dev/net")).unwrap();
+        fs::write(root.join("dev/kvm"), "").unwrap();
+        fs::write(root.join("dev/net/tun"), "").unwrap();
+        fs::create_dir_all(root.join("sys/class/net/eth0")).unwrap();
+        fs::create_dir_all(root.join("sys/class/net/br0/bridge")).unwrap();
+        assert!(probes.kvm().is_ok());
+        assert!(probes.tun().is_ok());
+        assert!(probes.bridge("br0").is_ok());
+        assert!(probes.bridge("eth0").unwrap_err().contains("not a bridge"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_host_binaries() {
+        let dir = temp_dir();
+        let probes = HostProbes::default();
+        let executable = dir.join("firecracker");
+        assert!(probes
+            .firecracker(&executable)
+            .unwrap_err()
+            .contains("FIRECRACKER_EXECUTABLE"));
+        fs::write(&executable, "#!/bin/sh\necho 'Firecracker v1.10.1'\n").unwrap();
+        fs::set_permissions(&executable, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(
+            probes.firecracker(&executable).unwrap(),
+            "Firecracker v1.10.1"
+        );
+        fs::write(&executable, "#!/bin/sh\necho 'bad flag' >&2\nexit 1\n").unwrap();
+        assert!(probes
+            .firecracker(&executable)
+            .unwrap_err()
+            .contains("bad flag"));
+
+        let kernel = dir.join("kernel.img");
+        assert!(probes.kernel(&kernel).unwrap_err().contains("NANOS_KERNEL"));
+        fs::write(&kernel, "").unwrap();
+        assert!(probes.kernel(&kernel).is_err());
+        fs::write(&kernel, [0x7f, b'E', b'L', b'F']).unwrap();
+        assert!(probes.kernel(&kernel).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_host_services() {
+        let probes = HostProbes::default();
+        assert!(probes.database("sqlite::memory:", false).await.is_ok());
+        assert!(probes
+            .database("sqlite:///nonexistent/spare/spare.db", false)
+            .await
+            .is_err());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        assert!(probes.broker(&address).await.is_ok());
+        drop(listener);
+        assert!(probes
+            .broker(&address)
+            .await
+            .unwrap_err()
+            .contains("Start the broker"));
+    }
+}
@@ -0,0 +1,476 @@
+//! Invocations run by the node on a schedule, e.g. to aggregate the readings of the
+//! sensors every few minutes without an external cron calling /invoke.
+//! A runner checks the schedules every tick and fires the runs that are due through the
+//! same path as /invoke, so they wait for the resources or are offloaded like any request.
+//! The runs missed while the node was down are dropped, merged or replayed depending on
+//! the catch-up policy.
+use std::{collections::VecDeque, str::FromStr, time::Duration};
+
+use chrono::{DateTime, Utc};
+use futures::{future::join_all, Future};
+use log::{error, info, warn};
+use sqlx::{Pool, Sqlite};
+
+use crate::{
+    api::invoke::InvokeFunction,
+    db::models::{RequestOutcome, Schedule},
+};
+
+/// Time between two checks of the schedules by default, in ms
+pub const DEFAULT_SCHEDULE_TICK: u64 = 1000;
+
+/// Most runs of a schedule fired at once when catching up
+pub const MAX_CATCH_UP: usize = 100;
+
+/// When the runs of a schedule are due
+#[derive(Debug, Clone)]
+pub enum Timing {
+    /// At the times of a cron expression
+    Cron(Box<cron::Schedule>),
+    /// Every given number of seconds
+    Interval(chrono::Duration),
+}
+
+impl Timing {
+    /// Create the timing of a schedule, from either a cron expression or an interval
+    /// # Arguments
+    /// * `cron_expr` - Cron expression, with five fields or a leading seconds field
+    /// * `interval` - Seconds between two runs
+    pub fn new(cron_expr: Option<&str>, interval: Option<i64>) -> Result<Self, String> {
+        match (cron_expr, interval) {
+            (Some(expr), None) => {
+                // The cron crate wants the seconds, the usual five fields start on the minute
+                let expr = match expr.split_whitespace().count() {
+                    5 => format!("0 {}", expr),
+                    _ => expr.to_string(),
+                };
+                cron::Schedule::from_str(&expr)
+                    .map(|schedule| Timing::Cron(Box::new(schedule)))
+                    .map_err(|e| format!("Invalid cron expression {}: {}", expr, e))
+            }
+            (None, Some(interval)) if interval > 0 => {
+                Ok(Timing::Interval(chrono::Duration::seconds(interval)))
+            }
+            (None, Some(interval)) => Err(format!("Invalid interval: {}", interval)),
+            _ => Err("A schedule needs either a cron expression or an interval".to_string()),
+        }
+    }
+
+    /// Get the first run after the given time, None if there is no other run
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            Timing::Cron(schedule) => schedule.after(&after).next(),
+            Timing::Interval(interval) => Some(after + *interval),
+        }
+    }
+
+    /// Get the runs due at `now`, starting from `next_run`, and the run after them.
+    /// At most `MAX_CATCH_UP` runs are returned, the most recent ones.
+    pub fn due(
+        &self,
+        next_run: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> (Vec<DateTime<Utc>>, Option<DateTime<Utc>>) {
+        let mut due = VecDeque::new();
+        let mut run = Some(next_run);
+        while let Some(time) = run.filter(|time| *time <= now) {
+            if due.len() == MAX_CATCH_UP {
+                due.pop_front();
+            }
+            due.push_back(time);
+            run = self.next_after(time);
+        }
+        (due.into(), run)
+    }
+}
+
+/// What happens to the runs missed while the node was down or busy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CatchUp {
+    /// The missed runs are dropped, only a run due on time fires
+    #[default]
+    Skip,
+    /// The missed runs are merged into a single one
+    Once,
+    /// Every missed run fires, up to `MAX_CATCH_UP`
+    All,
+}
+
+impl FromStr for CatchUp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(CatchUp::Skip),
+            "once" => Ok(CatchUp::Once),
+            "all" => Ok(CatchUp::All),
+            _ => Err(format!("Unknown catch-up policy: {}", s)),
+        }
+    }
+}
+
+impl CatchUp {
+    /// Get the number of runs to fire out of the due ones
+    /// # Arguments
+    /// * `due` - Times of the runs due
+    /// * `now` - Current time
+    /// * `grace` - How late a run can fire and still be on time
+    pub fn runs(&self, due: &[DateTime<Utc>], now: DateTime<Utc>, grace: Duration) -> usize {
+        match self {
+            CatchUp::Skip => {
+                let grace = chrono::Duration::from_std(grace).unwrap_or(chrono::Duration::MAX);
+                due.iter().any(|time| now - *time <= grace) as usize
+            }
+            CatchUp::Once => due.len().min(1),
+            CatchUp::All => due.len(),
+        }
+    }
+}
+
+/// Source of the current time, mocked in the tests
+pub trait Clock {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The time of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Fires the runs of the schedules when they are due
+pub struct Runner<C> {
+    pool: Pool<Sqlite>,
+    clock: C,
+    catch_up: CatchUp,
+    /// Time between two checks of the schedules
+    tick: Duration,
+}
+
+impl<C: Clock> Runner<C> {
+    /// Create a new runner of the schedules stored in the database
+    pub fn new(pool: Pool<Sqlite>, clock: C, catch_up: CatchUp, tick: Duration) -> Self {
+        Self {
+            pool,
+            clock,
+            catch_up,
+            tick,
+        }
+    }
+
+    /// Check the schedules every tick, forever
+    /// # Arguments
+    /// * `fire` - Runs an invocation as /invoke would
+    pub async fn run<F, Fut>(&self, fire: F)
+    where
+        F: Fn(InvokeFunction) -> Fut,
+        Fut: Future<Output = RequestOutcome>,
+    {
+        let mut ticks = actix_web::rt::time::interval(self.tick);
+        loop {
+            ticks.tick().await;
+            self.check(&fire).await;
+        }
+    }
+
+    /// Fire the runs due now, the schedules run concurrently
+    /// # Returns
+    /// * The number of runs fired
+    pub async fn check<F, Fut>(&self, fire: &F) -> usize
+    where
+        F: Fn(InvokeFunction) -> Fut,
+        Fut: Future<Output = RequestOutcome>,
+    {
+        let now = self.clock.now();
+        let schedules = match Schedule::due(&self.pool, now.naive_utc()).await {
+            Ok(schedules) => schedules,
+            Err(e) => {
+                error!("Cannot read the schedules: {}", e);
+                return 0;
+            }
+        };
+        join_all(
+            schedules
+                .iter()
+                .map(|schedule| self.fire_due(schedule, now, fire)),
+        )
+        .await
+        .into_iter()
+        .sum()
+    }
+
+    /// Fire the runs of a schedule due at `now`, according to the catch-up policy
+    async fn fire_due<F, Fut>(&self, schedule: &Schedule, now: DateTime<Utc>, fire: &F) -> usize
+    where
+        F: Fn(InvokeFunction) -> Fut,
+        Fut: Future<Output = RequestOutcome>,
+    {
+        let timing = Timing::new(schedule.cron_expr.as_deref(), schedule.interval);
+        let (timing, invoke) = match (timing, schedule.invoke()) {
+            (Ok(timing), Ok(invoke)) => (timing, invoke),
+            (Err(e), _) => return self.disable(schedule, &e).await,
+            (_, Err(e)) => return self.disable(schedule, &e.to_string()).await,
+        };
+        let (due, next_run) = timing.due(schedule.next_run.and_utc(), now);
+        let runs = self.catch_up.runs(&due, now, self.tick * 2);
+        if runs < due.len() {
+            warn!(
+                "Skipping {} missed runs of schedule {} ({})",
+                due.len() - runs,
+                schedule.id,
+                schedule.function
+            );
+        }
+
+        let mut status = format!("skipped {} missed runs", due.len());
+        for _ in 0..runs {
+            info!("Running schedule {} ({})", schedule.id, schedule.function);
+            status = fire(invoke.clone()).await.as_str().to_string();
+        }
+        let last_run = (runs > 0).then_some(now.naive_utc());
+        let result = match next_run {
+            Some(next_run) => {
+                Schedule::record_run(
+                    &self.pool,
+                    schedule.id,
+                    last_run,
+                    &status,
+                    next_run.naive_utc(),
+                )
+                .await
+            }
+            // Nothing left to run
+            None => Schedule::record_run(
+                &self.pool,
+                schedule.id,
+                last_run,
+                &status,
+                schedule.next_run,
+            )
+            .await
+            .and(Schedule::set_paused(&self.pool, schedule.id, true, None).await)
+            .map(|_| ()),
+        };
+        if let Err(e) = result {
+            error!("Cannot record the run of schedule {}: {}", schedule.id, e);
+        }
+        runs
+    }
+
+    /// Pause a schedule that cannot run, recording why
+    async fn disable(&self, schedule: &Schedule, reason: &str) -> usize {
+        error!("Cannot run schedule {}: {}", schedule.id, reason);
+        let status = format!("invalid: {}", reason);
+        let result =
+            Schedule::record_run(&self.pool, schedule.id, None, &status, schedule.next_run)
+                .await
+                .and(Schedule::set_paused(&self.pool, schedule.id, true, None).await);
+        if let Err(e) = result {
+            error!("Cannot pause schedule {}: {}", schedule.id, e);
+        }
+        0
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use super::*;
+    use crate::db;
+
+    /// A clock moved by hand
+    struct FakeClock(Mutex<DateTime<Utc>>);
+
+    impl FakeClock {
+        fn advance(&self, seconds: i64) {
+            *self.0.lock().unwrap() += chrono::Duration::seconds(seconds);
+        }
+    }
+
+    impl Clock for &FakeClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    fn start() -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339("2026-01-01T00:00:00Z")
+            .unwrap()
+            .to_utc()
+    }
+
+    fn invoke_function() -> InvokeFunction {
+        serde_json::from_value(serde_json::json!({
+            "function": "aggregate",
+            "image": "image",
+            "vcpus": 1,
+            "memory": 128,
+            "payload": null,
+            "emergency": false,
+            "hops": 0,
+        }))
+        .unwrap()
+    }
+
+    /// Store a schedule running every minute from `start`
+    async fn every_minute(pool: &Pool<Sqlite>) -> i64 {
+        let next_run = start() + chrono::Duration::seconds(60);
+        let mut schedule = Schedule::new(&invoke_function(), None, Some(60), next_run.naive_utc());
+        schedule.insert(pool).await.unwrap();
+        schedule.id
+    }
+
+    #[test]
+    fn test_timing() {
+        let timing = Timing::new(Some("*/5 * * * *"), None).unwrap();
+        let next = timing.next_after(start()).unwrap();
+        assert_eq!(next, start() + chrono::Duration::minutes(5));
+        // With the seconds
+        let timing = Timing::new(Some("30 * * * * *"), None).unwrap();
+        assert_eq!(
+            timing.next_after(start()).unwrap(),
+            start() + chrono::Duration::seconds(30)
+        );
+        let timing = Timing::new(None, Some(90)).unwrap();
+        assert_eq!(
+            timing.next_after(start()).unwrap(),
+            start() + chrono::Duration::seconds(90)
+        );
+
+        assert!(Timing::new(Some("every minute"), None).is_err());
+        assert!(Timing::new(None, Some(0)).is_err());
+        assert!(Timing::new(None, None).is_err());
+        assert!(Timing::new(Some("* * * * *"), Some(60)).is_err());
+    }
+
+    #[test]
+    fn test_due() {
+        let timing = Timing::new(None, Some(60)).unwrap();
+        let minutes = |m| start() + chrono::Duration::minutes(m);
+
+        let (due, next) = timing.due(minutes(1), minutes(0));
+        assert!(due.is_empty());
+        assert_eq!(next, Some(minutes(1)));
+        let (due, next) = timing.due(minutes(1), minutes(3));
+        assert_eq!(due, vec![minutes(1), minutes(2), minutes(3)]);
+        assert_eq!(next, Some(minutes(4)));
+
+        // Only the most recent runs are kept when far behind
+        let (due, next) = timing.due(minutes(1), minutes(1000));
+        assert!(due.len() <= MAX_CATCH_UP);
+        assert_eq!(due.last(), Some(&minutes(1000)));
+        assert_eq!(next, Some(minutes(1001)));
+    }
+
+    #[test]
+    fn test_catch_up() {
+        assert_eq!("skip".parse(), Ok(CatchUp::Skip));
+        assert_eq!("once".parse(), Ok(CatchUp::Once));
+        assert_eq!("all".parse(), Ok(CatchUp::All));
+        assert!("never".parse::<CatchUp>().is_err());
+
+        let grace = Duration::from_secs(2);
+        let now = start();
+        let late = |s| now - chrono::Duration::seconds(s);
+        let missed = [late(180), late(120), late(60)];
+        assert_eq!(CatchUp::Skip.runs(&missed, now, grace), 0);
+        assert_eq!(CatchUp::Once.runs(&missed, now, grace), 1);
+        assert_eq!(CatchUp::All.runs(&missed, now, grace), 3);
+        // A run on time fires anyway
+        let on_time = [late(120), late(60), late(1)];
+        assert_eq!(CatchUp::Skip.runs(&on_time, now, grace), 1);
+        assert_eq!(CatchUp::Skip.runs(&[], now, grace), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_fire() {
+        let pool = db::establish_connection().await.unwrap();
+        let id = every_minute(&pool).await;
+        let clock = FakeClock(Mutex::new(start()));
+        let runner = Runner::new(pool.clone(), &clock, CatchUp::Skip, Duration::from_secs(1));
+        let fired = AtomicUsize::new(0);
+        let fire = |invoke: InvokeFunction| {
+            assert_eq!(invoke.function, "aggregate");
+            fired.fetch_add(1, Ordering::SeqCst);
+            async { RequestOutcome::ServedLocally }
+        };
+
+        // Not due yet
+        clock.advance(30);
+        assert_eq!(runner.check(&fire).await, 0);
+        clock.advance(30);
+        assert_eq!(runner.check(&fire).await, 1);
+        // Already run
+        assert_eq!(runner.check(&fire).await, 0);
+
+        let schedule = Schedule::get(&pool, id).await.unwrap().unwrap();
+        assert_eq!(
+            schedule.last_run,
+            Some((start() + chrono::Duration::seconds(60)).naive_utc())
+        );
+        assert_eq!(schedule.last_status.as_deref(), Some("served_locally"));
+        assert_eq!(
+            schedule.next_run,
+            (start() + chrono::Duration::seconds(120)).naive_utc()
+        );
+
+        // A paused schedule does not run
+        Schedule::set_paused(&pool, id, true, None).await.unwrap();
+        clock.advance(60);
+        assert_eq!(runner.check(&fire).await, 0);
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_missed_runs() {
+        for (catch_up, runs) in [(CatchUp::Skip, 0), (CatchUp::Once, 1), (CatchUp::All, 10)] {
+            let pool = db::establish_connection().await.unwrap();
+            let id = every_minute(&pool).await;
+            let clock = FakeClock(Mutex::new(start()));
+            let runner = Runner::new(pool.clone(), &clock, catch_up, Duration::from_secs(1));
+            let fire = |_| async { RequestOutcome::Failed };
+
+            // The node was down for ten minutes and a half
+            clock.advance(630);
+            assert_eq!(runner.check(&fire).await, runs, "{:?}", catch_up);
+            let schedule = Schedule::get(&pool, id).await.unwrap().unwrap();
+            assert_eq!(
+                schedule.next_run,
+                (start() + chrono::Duration::seconds(660)).naive_utc()
+            );
+            match runs {
+                0 => {
+                    assert_eq!(schedule.last_run, None);
+                    assert_eq!(
+                        schedule.last_status.as_deref(),
+                        Some("skipped 10 missed runs")
+                    );
+                }
+                _ => assert_eq!(schedule.last_status.as_deref(), Some("failed")),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_schedule() {
+        let pool = db::establish_connection().await.unwrap();
+        let mut schedule = Schedule::new(&invoke_function(), None, Some(60), start().naive_utc());
+        schedule.request = "{}".to_string();
+        schedule.insert(&pool).await.unwrap();
+        let clock = FakeClock(Mutex::new(start()));
+        let runner = Runner::new(pool.clone(), &clock, CatchUp::All, Duration::from_secs(1));
+        assert_eq!(runner.check(&|_| async { RequestOutcome::Failed }).await, 0);
+
+        let schedule = Schedule::get(&pool, schedule.id).await.unwrap().unwrap();
+        assert!(schedule.paused);
+        assert!(schedule.last_status.unwrap().starts_with("invalid"));
+    }
+}
@@ -0,0 +1,115 @@
+//! Token protecting the administrative endpoints of the node.
+//! These endpoints boot instances or change the state of the node, so they are only
+//! served when the node was started with a token, and only to the clients sending it
+//! as `Authorization: Bearer <token>`.
+//! The requests forwarded by other nodes are instead authenticated by the client
+//! certificate of the node, when the cluster uses them.
+use actix_web::{http::header, HttpRequest, HttpResponse};
+
+use crate::{api::invoke::FORWARDED_BY_HEADER, net::tls::PeerIdentity};
+
+/// Token of the administrative endpoints, None if they are disabled
+#[derive(Clone, Debug, Default)]
+pub struct AdminToken(pub Option<String>);
+
+impl AdminToken {
+    /// Check the token of a request, returning the answer if it must be refused
+    pub fn check(&self, req: &HttpRequest) -> Option<HttpResponse> {
+        let Some(expected) = &self.0 else {
+            return Some(HttpResponse::Forbidden().body("Administrative endpoints are disabled\n"));
+        };
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        match token {
+            Some(token) if token == expected => None,
+            _ => Some(
+                HttpResponse::Unauthorized()
+                    .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+                    .body("Invalid or missing token\n"),
+            ),
+        }
+    }
+}
+
+/// Whether the requests forwarded by other nodes must come with a client certificate
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClusterAuth {
+    pub required: bool,
+}
+
+impl ClusterAuth {
+    /// Check that a request forwarded by another node comes from a member of the cluster.
+    /// A request is forwarded if it took some hops or carries the forwarding header.
+    /// # Returns
+    /// * The verified identity of the peer, if it sent a certificate
+    /// # Errors
+    /// * The answer to send back if the request must be refused
+    pub fn check(
+        &self,
+        req: &HttpRequest,
+        hops: i32,
+    ) -> Result<Option<PeerIdentity>, HttpResponse> {
+        let peer = req.conn_data::<PeerIdentity>().cloned();
+        let forwarded = hops > 0 || req.headers().contains_key(FORWARDED_BY_HEADER);
+        if self.required && forwarded && peer.is_none() {
+            return Err(HttpResponse::Forbidden()
+                .body("Forwarded requests must come with the certificate of a node\n"));
+        }
+        Ok(peer)
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn status(token: &AdminToken, header: Option<&str>) -> Option<u16> {
+        let mut req = TestRequest::default();
+        if let Some(value) = header {
+            req = req.insert_header((header::AUTHORIZATION, value));
+        }
+        token
+            .check(&req.to_http_request())
+            .map(|response| response.status().as_u16())
+    }
+
+    #[test]
+    fn test_check() {
+        let token = AdminToken(Some("secret".to_string()));
+        assert_eq!(status(&token, Some("Bearer secret")), None);
+        assert_eq!(status(&token, Some("Bearer other")), Some(401));
+        assert_eq!(status(&token, Some("secret")), Some(401));
+        assert_eq!(status(&token, None), Some(401));
+
+        // Without a token nobody is let in
+        assert_eq!(status(&AdminToken::default(), Some("Bearer ")), Some(403));
+    }
+
+    #[test]
+    fn test_cluster_auth() {
+        let status = |auth: ClusterAuth, req: TestRequest, hops| {
+            auth.check(&req.to_http_request(), hops)
+                .err()
+                .map(|response| response.status().as_u16())
+        };
+        let required = ClusterAuth { required: true };
+        // Requests from the clients need no certificate
+        assert_eq!(status(required, TestRequest::default(), 0), None);
+        // Forwarded ones do
+        assert_eq!(status(required, TestRequest::default(), 1), Some(403));
+        let forwarded =
+            TestRequest::default().insert_header((FORWARDED_BY_HEADER, "10.0.0.1:8085"));
+        assert_eq!(status(required, forwarded, 0), Some(403));
+        // Unless the cluster does not use client certificates
+        assert_eq!(
+            status(ClusterAuth::default(), TestRequest::default(), 1),
+            None
+        );
+    }
+}
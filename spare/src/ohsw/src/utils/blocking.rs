@@ -0,0 +1,224 @@
+//! Where the blocking steps of the creation of an instance run.
+//! Hashing an image or copying it into an overlay can take seconds: run on the async
+//! runtime, they stall every request served by the same worker. By default these steps
+//! run on the blocking pool instead, at most a given number at a time on the whole node,
+//! so a burst of cold starts cannot take all the threads either.
+use std::{str::FromStr, sync::Arc};
+
+use tokio::sync::Semaphore;
+
+/// Blocking steps running at the same time by default
+pub const DEFAULT_BLOCKING_THREADS: usize = 4;
+
+/// Where the blocking steps run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BlockingPolicy {
+    /// On the async runtime, blocking the worker serving the request
+    Inline,
+    /// On the blocking pool of the runtime
+    #[default]
+    Offload,
+}
+
+impl FromStr for BlockingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inline" => Ok(BlockingPolicy::Inline),
+            "offload" => Ok(BlockingPolicy::Offload),
+            _ => Err(format!("Unknown blocking policy: {}", s)),
+        }
+    }
+}
+
+/// Runs the blocking steps according to the policy, shared by the workers of the node
+#[derive(Debug, Clone)]
+pub struct BlockingPool {
+    policy: BlockingPolicy,
+    /// One permit for each step that can run at the same time
+    permits: Arc<Semaphore>,
+}
+
+impl Default for BlockingPool {
+    fn default() -> Self {
+        Self::new(BlockingPolicy::default(), DEFAULT_BLOCKING_THREADS)
+    }
+}
+
+impl BlockingPool {
+    /// Create a new pool running at most `threads` steps at a time (at least one)
+    pub fn new(policy: BlockingPolicy, threads: usize) -> Self {
+        Self {
+            policy,
+            permits: Arc::new(Semaphore::new(threads.max(1))),
+        }
+    }
+
+    /// Get the policy of the pool
+    pub fn policy(&self) -> BlockingPolicy {
+        self.policy
+    }
+
+    /// Run a blocking step, waiting for a free thread if needed
+    pub async fn run<F, T>(&self, step: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.policy {
+            BlockingPolicy::Inline => step(),
+            BlockingPolicy::Offload => {
+                // The semaphore is never closed
+                let _permit = self.permits.acquire().await.unwrap();
+                match actix_web::rt::task::spawn_blocking(step).await {
+                    Ok(result) => result,
+                    Err(e) => std::panic::resume_unwind(e.into_panic()),
+                }
+            }
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+    use futures::future::join_all;
+
+    use super::*;
+    use crate::execution_environment::overlay::Overlay;
+
+    #[test]
+    fn test_policy() {
+        assert_eq!("inline".parse(), Ok(BlockingPolicy::Inline));
+        assert_eq!("offload".parse(), Ok(BlockingPolicy::Offload));
+        assert!("async".parse::<BlockingPolicy>().is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_inline() {
+        let pool = BlockingPool::new(BlockingPolicy::Inline, 1);
+        let caller = thread::current().id();
+        assert_eq!(pool.run(move || thread::current().id()).await, caller);
+
+        let pool = BlockingPool::default();
+        assert_ne!(pool.run(move || thread::current().id()).await, caller);
+    }
+
+    #[actix_web::test]
+    async fn test_bounded() {
+        let pool = BlockingPool::new(BlockingPolicy::Offload, 3);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let steps = (0..12).map(|i| {
+            let (running, peak) = (running.clone(), peak.clone());
+            pool.run(move || {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                peak.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                i
+            })
+        });
+        let results = join_all(steps).await;
+        assert_eq!(results, (0..12).collect::<Vec<_>>());
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[get("/")]
+    async fn index() -> impl Responder {
+        HttpResponse::Ok().finish()
+    }
+
+    /// Copy the image into a new overlay, like the creation of an instance
+    #[post("/create")]
+    async fn create(pool: web::Data<BlockingPool>, dir: web::Data<PathBuf>) -> impl Responder {
+        let dir = dir.get_ref().clone();
+        pool.run(move || {
+            let name = uuid::Uuid::new_v4().to_string();
+            let overlay = Overlay::create(&dir.join("image"), &dir.join("overlays"), &name)?;
+            // Read it back, as the digest of the image would
+            let size = fs::read(overlay.path())?.len();
+            overlay.remove();
+            Ok::<_, std::io::Error>(size)
+        })
+        .await
+        .unwrap();
+        HttpResponse::Ok().finish()
+    }
+
+    /// Slowest answer of the index endpoint while instances are being created
+    async fn slowest_answer(policy: BlockingPolicy) -> Duration {
+        let dir = std::env::temp_dir().join(format!("spare-blocking-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        // Random content, so the copy cannot be sparse
+        let image: Vec<u8> = (0..256u32 << 20)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect();
+        fs::write(dir.join("image"), image).unwrap();
+
+        let pool = BlockingPool::new(policy, DEFAULT_BLOCKING_THREADS);
+        let data = (web::Data::new(pool), web::Data::new(dir.clone()));
+        // A single worker, as on a loaded node all the workers are busy
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(data.0.clone())
+                .app_data(data.1.clone())
+                .service(index)
+                .service(create)
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let address = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+
+        let client = awc::Client::builder()
+            .timeout(Duration::from_secs(120))
+            .finish();
+        let creations =
+            join_all((0..8).map(|_| client.post(format!("http://{}/create", address)).send()));
+        let probes = async {
+            let mut slowest = Duration::ZERO;
+            for _ in 0..50 {
+                let start = Instant::now();
+                client
+                    .get(format!("http://{}/", address))
+                    .send()
+                    .await
+                    .unwrap();
+                slowest = slowest.max(start.elapsed());
+                actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+            }
+            slowest
+        };
+        let (creations, slowest) = futures::join!(creations, probes);
+        assert!(creations
+            .iter()
+            .all(|r| r.as_ref().is_ok_and(|r| r.status().is_success())));
+        fs::remove_dir_all(&dir).unwrap();
+        slowest
+    }
+
+    #[actix_web::test]
+    #[ignore = "load test, copies 2 GiB of images"]
+    async fn test_responsive_under_load() {
+        let offload = slowest_answer(BlockingPolicy::Offload).await;
+        let inline = slowest_answer(BlockingPolicy::Inline).await;
+        println!(
+            "Slowest answer: {:?} offloading, {:?} inline",
+            offload, inline
+        );
+        assert!(offload < Duration::from_millis(100));
+        assert!(offload < inline);
+    }
+}
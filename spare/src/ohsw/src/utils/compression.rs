@@ -0,0 +1,105 @@
+//! Compression of the responses of the node.
+//! Compressing the output of a function that is already compressed (an image, a video)
+//! only costs CPU time and skews the latency measurements, so a function can say its
+//! output does not compress well. Between nodes nothing is compressed: the node facing
+//! the client is the only one deciding, so an offloaded output is never compressed twice.
+use std::str::FromStr;
+
+use actix_web::{
+    http::header::{HeaderValue, CONTENT_ENCODING},
+    HttpResponse,
+};
+
+/// When the responses are compressed, for the clients accepting it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Never
+    Off,
+    /// Unless the function says its output does not compress well
+    #[default]
+    Auto,
+    /// Always, ignoring the hints of the functions
+    Forced,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Compression::Off),
+            "auto" => Ok(Compression::Auto),
+            "forced" => Ok(Compression::Forced),
+            _ => Err(format!("Unknown compression mode: {}", s)),
+        }
+    }
+}
+
+impl Compression {
+    /// Check whether the compression middleware is needed
+    pub fn enabled(&self) -> bool {
+        *self != Compression::Off
+    }
+
+    /// Check whether the output of a function may be compressed
+    /// # Arguments
+    /// * `compressible` - The hint of the function, None if it gave none
+    pub fn allows(&self, compressible: Option<bool>) -> bool {
+        match self {
+            Compression::Off => false,
+            Compression::Auto => compressible.unwrap_or(true),
+            Compression::Forced => true,
+        }
+    }
+
+    /// Mark the response of a function with `Content-Encoding: identity` if it must not
+    /// be compressed, the compression middleware leaves such responses as they are
+    pub fn apply(&self, compressible: Option<bool>, response: &mut HttpResponse) {
+        if self.enabled()
+            && !self.allows(compressible)
+            && !response.headers().contains_key(CONTENT_ENCODING)
+        {
+            response
+                .headers_mut()
+                .insert(CONTENT_ENCODING, HeaderValue::from_static("identity"));
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode() {
+        assert_eq!("off".parse(), Ok(Compression::Off));
+        assert_eq!("auto".parse(), Ok(Compression::Auto));
+        assert_eq!("forced".parse(), Ok(Compression::Forced));
+        assert!("gzip".parse::<Compression>().is_err());
+        assert!(!Compression::Off.enabled());
+    }
+
+    #[test]
+    fn test_apply() {
+        let encoding = |mode: Compression, compressible| {
+            let mut response = HttpResponse::Ok().body("output");
+            mode.apply(compressible, &mut response);
+            response.headers().get(CONTENT_ENCODING).cloned()
+        };
+        let identity = Some(HeaderValue::from_static("identity"));
+        assert_eq!(encoding(Compression::Auto, None), None);
+        assert_eq!(encoding(Compression::Auto, Some(true)), None);
+        assert_eq!(encoding(Compression::Auto, Some(false)), identity);
+        assert_eq!(encoding(Compression::Forced, Some(false)), None);
+        // Nothing to mark without the middleware
+        assert_eq!(encoding(Compression::Off, Some(false)), None);
+
+        // An encoding already chosen is kept
+        let mut response = HttpResponse::Ok()
+            .insert_header((CONTENT_ENCODING, "br"))
+            .body("output");
+        Compression::Auto.apply(Some(false), &mut response);
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "br");
+    }
+}
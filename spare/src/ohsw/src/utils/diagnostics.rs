@@ -0,0 +1,248 @@
+//! Bundle of diagnostics of the node, attached to bug reports.
+//! The bundle is a tar.gz archive with one file per section (recent logs, state of the
+//! orchestrator, last failed instances, configuration, preflight checks). Each section is
+//! capped, so the bundle stays small on a node that has been failing for days, and the
+//! secrets of the configuration are redacted before it leaves the node.
+use std::io::{self, Write};
+
+use actix_web::web::Bytes;
+use flate2::{write::GzEncoder, Compression};
+use futures::{channel::mpsc, SinkExt};
+
+use super::log_ring::LogRing;
+
+/// Maximum size of a section of the bundle
+pub const MAX_SECTION_SIZE: usize = 1024 * 1024;
+
+/// Fields of the configuration whose values never leave the node
+pub const SECRETS: [&str; 3] = ["admin_token", "broker_password", "broker_token"];
+
+/// Replacement of the redacted values
+pub const REDACTED: &str = "\"[redacted]\"";
+
+/// Size of a block of a tar archive
+const BLOCK_SIZE: usize = 512;
+
+/// Number of sections compressed before the bundle waits for the client
+const SECTIONS_IN_FLIGHT: usize = 2;
+
+/// What the node knows about itself from its startup, for the bundle
+#[derive(Clone)]
+pub struct Diagnostics {
+    /// Recent log lines
+    pub logs: LogRing,
+    /// Configuration of the node, with the secrets redacted
+    pub config: String,
+    /// Results of the preflight checks
+    pub preflight: String,
+}
+
+/// A file of the bundle
+#[derive(Debug, Clone)]
+pub struct Section {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl Section {
+    /// Create a new section, truncated to `MAX_SECTION_SIZE`
+    pub fn new(name: &str, data: impl Into<Vec<u8>>) -> Self {
+        let mut data = data.into();
+        if data.len() > MAX_SECTION_SIZE {
+            let dropped = data.len() - MAX_SECTION_SIZE;
+            data.truncate(MAX_SECTION_SIZE);
+            data.extend_from_slice(format!("\n[truncated {dropped} bytes]\n").as_bytes());
+        }
+        Section {
+            name: name.to_string(),
+            data,
+        }
+    }
+}
+
+/// Redact the values of the `secrets` fields in the pretty `Debug` output of a struct.
+/// A value spanning several lines (`Some(` ... `),`) is redacted as a whole.
+pub fn redact(debug: &str, secrets: &[&str]) -> String {
+    let mut redacted = String::with_capacity(debug.len());
+    // Indentation of the field whose value is being skipped
+    let mut skipping: Option<usize> = None;
+    for line in debug.lines() {
+        let indent = line.len() - line.trim_start().len();
+        if let Some(field_indent) = skipping {
+            // The value ends on the line closing it, at the indentation of the field
+            if indent <= field_indent {
+                skipping = None;
+            }
+            continue;
+        }
+        match line.trim_start().split_once(": ") {
+            Some((field, value)) if secrets.contains(&field) && value != "None," => {
+                redacted.push_str(&line[..indent]);
+                redacted.push_str(field);
+                redacted.push_str(": ");
+                redacted.push_str(REDACTED);
+                redacted.push(',');
+                if value.ends_with(['(', '{', '[']) {
+                    skipping = Some(indent);
+                }
+            }
+            _ => redacted.push_str(line),
+        }
+        redacted.push('\n');
+    }
+    redacted
+}
+
+/// Write a field of a tar header as a zero-terminated octal number
+fn octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let value = format!("{value:0digits$o}");
+    field[..digits].copy_from_slice(&value.as_bytes()[value.len() - digits..]);
+    field[digits] = 0;
+}
+
+/// Header of a regular file of a ustar archive
+fn tar_header(name: &str, size: usize, mtime: u64) -> [u8; BLOCK_SIZE] {
+    let mut header = [0; BLOCK_SIZE];
+    let name = name.as_bytes();
+    let len = name.len().min(100);
+    header[..len].copy_from_slice(&name[..len]);
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size as u64);
+    octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+    octal(&mut header[148..155], checksum);
+    header
+}
+
+/// Append a section to a tar archive
+fn append(out: &mut impl Write, section: &Section, mtime: u64) -> io::Result<()> {
+    out.write_all(&tar_header(&section.name, section.data.len(), mtime))?;
+    out.write_all(&section.data)?;
+    let padding = (BLOCK_SIZE - section.data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+    out.write_all(&[0; BLOCK_SIZE][..padding])
+}
+
+/// Stream the sections as a tar.gz archive, compressed one section at a time
+pub fn bundle(sections: Vec<Section>) -> mpsc::Receiver<Result<Bytes, io::Error>> {
+    let (mut sender, receiver) = mpsc::channel(SECTIONS_IN_FLIGHT);
+    actix_web::rt::spawn(async move {
+        let mtime = chrono::Utc::now().timestamp().max(0) as u64;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        for section in &sections {
+            if let Err(e) = append(&mut encoder, section, mtime) {
+                // The client sees a truncated archive
+                let _ = sender.send(Err(e)).await;
+                return;
+            }
+            let chunk = std::mem::take(encoder.get_mut());
+            if !chunk.is_empty() && sender.send(Ok(Bytes::from(chunk))).await.is_err() {
+                // The client went away
+                return;
+            }
+        }
+        // Two empty blocks end the archive
+        let end = encoder
+            .write_all(&[0; 2 * BLOCK_SIZE])
+            .and_then(|_| encoder.finish());
+        let _ = sender.send(end.map(Bytes::from)).await;
+    });
+    receiver
+}
+
+// Unit tests
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use futures::TryStreamExt;
+    use std::io::Read;
+
+    /// Unpack a tar.gz archive into its (name, data) entries
+    pub(crate) fn unpack(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut tar = Vec::new();
+        GzDecoder::new(archive).read_to_end(&mut tar).unwrap();
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + BLOCK_SIZE <= tar.len() && tar[offset] != 0 {
+            let header = &tar[offset..offset + BLOCK_SIZE];
+            let stored: u32 = header
+                .iter()
+                .enumerate()
+                .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u32)
+                .sum();
+            let checksum = std::str::from_utf8(&header[148..154]).unwrap();
+            assert_eq!(u32::from_str_radix(checksum, 8).unwrap(), stored);
+            let name = std::str::from_utf8(&header[..100])
+                .unwrap()
+                .trim_end_matches('\0')
+                .to_string();
+            let size = std::str::from_utf8(&header[124..135]).unwrap();
+            let size = usize::from_str_radix(size, 8).unwrap();
+            offset += BLOCK_SIZE;
+            entries.push((name, tar[offset..offset + size].to_vec()));
+            offset += size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        }
+        entries
+    }
+
+    #[test]
+    fn test_section_capped() {
+        let section = Section::new("logs.txt", vec![b'a'; MAX_SECTION_SIZE + 10]);
+        assert!(section.data[..MAX_SECTION_SIZE].iter().all(|&b| b == b'a'));
+        assert!(section.data.ends_with(b"\n[truncated 10 bytes]\n"));
+        assert_eq!(Section::new("small", "abc").data, b"abc");
+    }
+
+    #[test]
+    fn test_redact() {
+        #[allow(dead_code)]
+        #[derive(Debug)]
+        struct Config {
+            port: u16,
+            admin_token: Option<String>,
+            broker_password: String,
+            broker_token: Option<String>,
+        }
+        let config = Config {
+            port: 8085,
+            admin_token: Some("s3cret".to_string()),
+            broker_password: "hunter2".to_string(),
+            broker_token: None,
+        };
+
+        let redacted = redact(&format!("{config:#?}"), &SECRETS);
+        assert!(!redacted.contains("s3cret"));
+        assert!(!redacted.contains("hunter2"));
+        assert!(redacted.contains("    port: 8085,\n"));
+        assert!(redacted.contains("    admin_token: \"[redacted]\",\n"));
+        assert!(redacted.contains("    broker_password: \"[redacted]\",\n"));
+        // Not set, nothing to hide
+        assert!(redacted.contains("    broker_token: None,\n"));
+    }
+
+    #[actix_web::test]
+    async fn test_bundle() {
+        let sections = vec![
+            Section::new("empty.txt", ""),
+            Section::new("block.txt", vec![b'x'; BLOCK_SIZE]),
+            Section::new("config.txt", "port: 8085\n"),
+        ];
+        let chunks: Vec<Bytes> = bundle(sections).try_collect().await.unwrap();
+        let archive = chunks.concat();
+
+        let entries = unpack(&archive);
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["empty.txt", "block.txt", "config.txt"]);
+        assert!(entries[0].1.is_empty());
+        assert_eq!(entries[1].1, vec![b'x'; BLOCK_SIZE]);
+        assert_eq!(entries[2].1, b"port: 8085\n");
+    }
+}
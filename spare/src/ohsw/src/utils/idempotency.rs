@@ -0,0 +1,317 @@
+//! Idempotency keys of the invocations.
+//! A client that retries a request it did not get an answer for sends the same key
+//! again: while the first attempt is running the retry is refused, and once it is
+//! done the stored result is returned instead of starting a second instance.
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{http::StatusCode, web::Bytes, HttpResponse};
+
+/// Header carrying the idempotency key of a request
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+/// Header set on the responses replayed from the cache
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+/// Configuration of the idempotency cache
+#[derive(Clone, Copy, Debug)]
+pub struct IdempotencyConfig {
+    /// Time after which a key is forgotten
+    pub ttl: Duration,
+    /// Maximum number of keys, the oldest ones are forgotten first
+    pub max_entries: usize,
+    /// Maximum size of a stored body, larger results are not replayed
+    pub max_body_size: usize,
+}
+
+impl Default for IdempotencyConfig {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(600),
+            max_entries: 10_000,
+            max_body_size: 1024 * 1024,
+        }
+    }
+}
+
+/// Result of a request, as sent to the client
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredResponse {
+    pub status: StatusCode,
+    /// None if the body was larger than the maximum size
+    pub body: Option<Bytes>,
+}
+
+impl StoredResponse {
+    /// Build the response of a replayed request.
+    /// A result too large to be stored cannot be replayed, but the request must
+    /// not run again either, so the client gets 410 Gone.
+    pub fn to_response(&self) -> HttpResponse {
+        match &self.body {
+            Some(body) => HttpResponse::build(self.status)
+                .insert_header((IDEMPOTENT_REPLAYED_HEADER, "true"))
+                .body(body.clone()),
+            None => HttpResponse::Gone()
+                .insert_header((IDEMPOTENT_REPLAYED_HEADER, "true"))
+                .body("The request already ran, but its result was too large to be stored\n"),
+        }
+    }
+}
+
+/// State of a request
+enum State {
+    InFlight,
+    Done(StoredResponse),
+}
+
+struct Entry {
+    /// Distinguishes the successive uses of the same key
+    token: u64,
+    created: Instant,
+    state: State,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<String, Entry>,
+    /// Keys in creation order, with the token of their entry.
+    /// A key whose entry was replaced or removed has a stale token and is skipped.
+    order: VecDeque<(String, u64)>,
+    next_token: u64,
+}
+
+impl Entries {
+    /// Remove the entry of `key` if it still has the given token
+    fn remove(&mut self, key: &str, token: u64) {
+        if self.map.get(key).is_some_and(|entry| entry.token == token) {
+            self.map.remove(key);
+        }
+    }
+
+    /// Forget the expired entries and the oldest ones beyond `max_entries`
+    fn purge(&mut self, now: Instant, config: &IdempotencyConfig) {
+        while let Some((key, token)) = self.order.front() {
+            let live = match self.map.get(key) {
+                Some(entry) if entry.token == *token => Some(entry),
+                _ => None,
+            };
+            match live {
+                Some(entry)
+                    if now.duration_since(entry.created) < config.ttl
+                        && self.map.len() < config.max_entries =>
+                {
+                    break
+                }
+                Some(_) => {
+                    let (key, _) = self.order.pop_front().unwrap();
+                    self.map.remove(&key);
+                }
+                None => {
+                    self.order.pop_front();
+                }
+            }
+        }
+    }
+}
+
+/// Outcome of the lookup of a key
+pub enum Begin<'a> {
+    /// First time the key is seen, the request must run
+    New(InFlight<'a>),
+    /// A request with the same key is still running
+    Running,
+    /// A request with the same key already ran
+    Done(StoredResponse),
+}
+
+/// Bounded cache of the idempotency keys and of the results of their requests
+pub struct IdempotencyCache {
+    config: IdempotencyConfig,
+    entries: Mutex<Entries>,
+}
+
+impl IdempotencyCache {
+    pub fn new(config: IdempotencyConfig) -> Self {
+        Self {
+            config,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Look up a key, marking it as running if it is new
+    pub fn begin(&self, key: &str) -> Begin<'_> {
+        self.begin_at(key, Instant::now())
+    }
+
+    fn begin_at(&self, key: &str, now: Instant) -> Begin<'_> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.purge(now, &self.config);
+        if let Some(entry) = entries.map.get(key) {
+            return match &entry.state {
+                State::InFlight => Begin::Running,
+                State::Done(response) => Begin::Done(response.clone()),
+            };
+        }
+
+        let token = entries.next_token;
+        entries.next_token += 1;
+        entries.map.insert(
+            key.to_string(),
+            Entry {
+                token,
+                created: now,
+                state: State::InFlight,
+            },
+        );
+        entries.order.push_back((key.to_string(), token));
+        Begin::New(InFlight {
+            cache: self,
+            key: key.to_string(),
+            token,
+            finished: false,
+        })
+    }
+
+    /// Number of keys in the cache
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A running request with an idempotency key.
+/// Dropping it without calling `finish` forgets the key, so the request can be retried:
+/// this happens when the request failed or when the client went away.
+pub struct InFlight<'a> {
+    cache: &'a IdempotencyCache,
+    key: String,
+    token: u64,
+    finished: bool,
+}
+
+impl InFlight<'_> {
+    /// Store the result of the request
+    pub fn finish(mut self, status: StatusCode, body: &Bytes) {
+        self.finished = true;
+        let body = (body.len() <= self.cache.config.max_body_size).then(|| body.clone());
+        let mut entries = self.cache.entries.lock().unwrap();
+        if let Some(entry) = entries.map.get_mut(&self.key) {
+            if entry.token == self.token {
+                entry.state = State::Done(StoredResponse { status, body });
+            }
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.cache
+                .entries
+                .lock()
+                .unwrap()
+                .remove(&self.key, self.token);
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cache(max_entries: usize, max_body_size: usize) -> IdempotencyCache {
+        IdempotencyCache::new(IdempotencyConfig {
+            ttl: Duration::from_secs(60),
+            max_entries,
+            max_body_size,
+        })
+    }
+
+    fn is_new(begin: Begin) -> bool {
+        matches!(begin, Begin::New(_))
+    }
+
+    #[test]
+    fn test_replay() {
+        let cache = cache(10, 1024);
+        let Begin::New(in_flight) = cache.begin("key") else {
+            panic!("The key is new");
+        };
+        in_flight.finish(StatusCode::OK, &Bytes::from("result"));
+
+        match cache.begin("key") {
+            Begin::Done(response) => {
+                assert_eq!(response.status, StatusCode::OK);
+                assert_eq!(response.body, Some(Bytes::from("result")));
+                let response = response.to_response();
+                assert_eq!(response.status(), StatusCode::OK);
+                assert!(response.headers().contains_key(IDEMPOTENT_REPLAYED_HEADER));
+            }
+            _ => panic!("The result must be replayed"),
+        }
+        assert!(is_new(cache.begin("other")));
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let cache = cache(10, 1024);
+        let first = cache.begin("key");
+        assert!(is_new(first));
+        // The first attempt was dropped without a result, so the key is free again
+        let Begin::New(second) = cache.begin("key") else {
+            panic!("The key was released");
+        };
+        assert!(matches!(cache.begin("key"), Begin::Running));
+        second.finish(StatusCode::OK, &Bytes::new());
+        assert!(matches!(cache.begin("key"), Begin::Done(_)));
+    }
+
+    #[test]
+    fn test_body_too_large() {
+        let cache = cache(10, 4);
+        if let Begin::New(in_flight) = cache.begin("key") {
+            in_flight.finish(StatusCode::OK, &Bytes::from("too large"));
+        }
+        match cache.begin("key") {
+            Begin::Done(response) => {
+                assert_eq!(response.body, None);
+                assert_eq!(response.to_response().status(), StatusCode::GONE);
+            }
+            _ => panic!("The request must not run again"),
+        };
+    }
+
+    #[test]
+    fn test_expiry() {
+        let cache = cache(10, 1024);
+        let start = Instant::now();
+        if let Begin::New(in_flight) = cache.begin_at("key", start) {
+            in_flight.finish(StatusCode::OK, &Bytes::new());
+        }
+        let later = start + Duration::from_secs(30);
+        assert!(matches!(cache.begin_at("key", later), Begin::Done(_)));
+        let expired = start + Duration::from_secs(61);
+        assert!(is_new(cache.begin_at("key", expired)));
+    }
+
+    #[test]
+    fn test_bounded() {
+        let cache = cache(3, 1024);
+        for i in 0..10 {
+            if let Begin::New(in_flight) = cache.begin(&format!("key-{}", i)) {
+                in_flight.finish(StatusCode::OK, &Bytes::new());
+            }
+        }
+        assert_eq!(cache.len(), 3);
+        assert!(matches!(cache.begin("key-9"), Begin::Done(_)));
+        // The oldest keys were forgotten
+        assert!(is_new(cache.begin("key-0")));
+    }
+}
@@ -0,0 +1,158 @@
+//! Recent log lines of the node, kept in memory for the diagnostics bundle.
+//! Every record is still written by env_logger as before, the ring only keeps a copy of
+//! the last lines, so a bug report can carry them without access to the host.
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use log::{Log, Metadata, Record};
+
+/// Number of log lines kept by default
+pub const DEFAULT_CAPACITY: usize = 2000;
+
+/// The last log lines of the node, the oldest are dropped first
+#[derive(Clone)]
+pub struct LogRing {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    capacity: usize,
+}
+
+impl LogRing {
+    /// Create a new ring keeping at most `capacity` lines
+    pub fn new(capacity: usize) -> Self {
+        LogRing {
+            lines: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// Add a line, dropping the oldest one if the ring is full
+    pub fn push(&self, line: String) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    /// The newest lines fitting in `max_bytes`, oldest first, one per line
+    pub fn tail(&self, max_bytes: usize) -> String {
+        let lines = self.lines.lock().unwrap();
+        let mut size = 0;
+        let newest = lines
+            .iter()
+            .rev()
+            .take_while(|line| {
+                size += line.len() + 1;
+                size <= max_bytes
+            })
+            .count();
+        let mut tail = String::with_capacity(size.min(max_bytes));
+        for line in lines.iter().skip(lines.len() - newest) {
+            tail.push_str(line);
+            tail.push('\n');
+        }
+        tail
+    }
+}
+
+/// Logger writing through env_logger and keeping the records it writes in a ring
+pub struct RingLogger {
+    inner: env_logger::Logger,
+    ring: LogRing,
+}
+
+impl RingLogger {
+    pub fn new(inner: env_logger::Logger, ring: LogRing) -> Self {
+        RingLogger { inner, ring }
+    }
+}
+
+impl Log for RingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+        self.ring.push(format!(
+            "[{} {:<5} {}] {}",
+            chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            record.level(),
+            record.target(),
+            record.args()
+        ));
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install the logger of the node, configured by `RUST_LOG` as `env_logger::init` does,
+/// and return the ring of its last `capacity` lines
+pub fn init(capacity: usize) -> LogRing {
+    let inner = env_logger::Builder::from_default_env().build();
+    let max_level = inner.filter();
+    let ring = LogRing::new(capacity);
+    log::set_boxed_logger(Box::new(RingLogger::new(inner, ring.clone())))
+        .expect("The logger is already set");
+    log::set_max_level(max_level);
+    ring
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{Level, LevelFilter};
+
+    #[test]
+    fn test_ring() {
+        let ring = LogRing::new(3);
+        for i in 0..5 {
+            ring.push(format!("line {i}"));
+        }
+        // Only the last lines are kept
+        assert_eq!(ring.tail(usize::MAX), "line 2\nline 3\nline 4\n");
+        // The newest lines are kept when the size is capped
+        assert_eq!(ring.tail(14), "line 3\nline 4\n");
+        assert_eq!(ring.tail(3), "");
+    }
+
+    #[test]
+    fn test_logger() {
+        let ring = LogRing::new(10);
+        let inner = env_logger::Builder::new()
+            .filter_level(LevelFilter::Warn)
+            .build();
+        let logger = RingLogger::new(inner, ring.clone());
+
+        logger.log(
+            &Record::builder()
+                .args(format_args!("kept"))
+                .level(Level::Error)
+                .target("ohsw::test")
+                .build(),
+        );
+        // Filtered out as env_logger would
+        logger.log(
+            &Record::builder()
+                .args(format_args!("dropped"))
+                .level(Level::Info)
+                .target("ohsw::test")
+                .build(),
+        );
+
+        let tail = ring.tail(usize::MAX);
+        assert_eq!(tail.lines().count(), 1);
+        assert!(tail.ends_with("ERROR ohsw::test] kept\n"));
+    }
+}
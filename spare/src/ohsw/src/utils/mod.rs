@@ -1 +1,13 @@
+pub mod auth;
+pub mod blocking;
+pub mod compression;
+pub mod diagnostics;
+pub mod idempotency;
+pub mod log_ring;
+pub mod notify;
+pub mod protocol;
+pub mod quota;
 pub mod socket;
+pub mod spill;
+pub mod stats;
+pub mod supervisor;
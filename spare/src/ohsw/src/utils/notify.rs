@@ -0,0 +1,310 @@
+//! Readiness and liveness of the node, reported to the service manager.
+//! Under systemd (`Type=notify`) the node sends `READY=1` once it is registered and its
+//! server is bound, then `WATCHDOG=1` after every successful health check, so a stuck node
+//! is restarted when `WatchdogSec=` is set. Without systemd, a liveness file holding the
+//! time of the last successful health check can be watched instead.
+use std::{
+    env,
+    ffi::OsString,
+    fs, io,
+    os::unix::net::{SocketAddr, UnixDatagram},
+    path::PathBuf,
+    time::Duration,
+};
+
+use futures::Future;
+use log::{error, warn};
+
+/// State of the node reported to the notifiers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// The node is registered and serves requests
+    Ready,
+    /// The last health check succeeded
+    Alive,
+    /// The node is shutting down
+    Stopping,
+}
+
+/// Something the state of the node is reported to
+pub trait Notifier: Send + Sync {
+    fn notify(&self, state: State) -> io::Result<()>;
+}
+
+/// Notifies systemd through the datagram socket in `NOTIFY_SOCKET`
+pub struct SystemdNotifier {
+    socket: OsString,
+}
+
+impl SystemdNotifier {
+    /// Create a new notifier writing to the given socket, `@` starts an abstract one
+    pub fn new(socket: impl Into<OsString>) -> Self {
+        Self {
+            socket: socket.into(),
+        }
+    }
+
+    /// Create a new notifier from `NOTIFY_SOCKET`, if the node runs under systemd
+    pub fn from_env() -> Option<Self> {
+        env::var_os("NOTIFY_SOCKET")
+            .filter(|socket| !socket.is_empty())
+            .map(Self::new)
+    }
+
+    fn address(&self) -> io::Result<SocketAddr> {
+        let socket = self.socket.as_encoded_bytes();
+        match socket.strip_prefix(b"@") {
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)
+            }
+            None => SocketAddr::from_pathname(PathBuf::from(&self.socket)),
+        }
+    }
+}
+
+impl Notifier for SystemdNotifier {
+    fn notify(&self, state: State) -> io::Result<()> {
+        let message = match state {
+            State::Ready => "READY=1",
+            State::Alive => "WATCHDOG=1",
+            State::Stopping => "STOPPING=1",
+        };
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to_addr(message.as_bytes(), &self.address()?)?;
+        Ok(())
+    }
+}
+
+/// Interval of the watchdog asked by systemd (`WATCHDOG_USEC`), if it is meant for this process
+pub fn systemd_watchdog() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// File holding the time (in s since the epoch) of the last successful health check
+pub struct LivenessFile {
+    path: PathBuf,
+}
+
+impl LivenessFile {
+    /// Create a new liveness file at the given path, written on the first notification
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Notifier for LivenessFile {
+    fn notify(&self, state: State) -> io::Result<()> {
+        match state {
+            State::Ready | State::Alive => {
+                // Write aside and rename, so a reader never sees a partial timestamp
+                let tmp = self.path.with_extension("tmp");
+                fs::write(&tmp, format!("{}\n", chrono::Utc::now().timestamp()))?;
+                fs::rename(&tmp, &self.path)
+            }
+            State::Stopping => match fs::remove_file(&self.path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
+    }
+}
+
+/// The notifiers of the node, none if neither systemd nor a liveness file is configured
+#[derive(Default)]
+pub struct Notifiers {
+    notifiers: Vec<Box<dyn Notifier>>,
+}
+
+impl Notifiers {
+    /// Create a new set of notifiers, with no notifier
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Also report the state to the given notifier
+    pub fn with(mut self, notifier: impl Notifier + 'static) -> Self {
+        self.notifiers.push(Box::new(notifier));
+        self
+    }
+
+    /// Check whether there is any notifier
+    pub fn is_empty(&self) -> bool {
+        self.notifiers.is_empty()
+    }
+
+    /// Report the state to every notifier, a failing notifier is logged and skipped
+    pub fn notify(&self, state: State) {
+        for notifier in &self.notifiers {
+            if let Err(e) = notifier.notify(state) {
+                error!("Cannot report {state:?}: {e}");
+            }
+        }
+    }
+
+    /// Run the health check every `interval`, reporting the node alive when it succeeds.
+    /// A failed check is only logged: systemd restarts the node once it misses the
+    /// watchdog, and the liveness file grows stale.
+    pub async fn watchdog<F, Fut>(&self, interval: Duration, mut check: F)
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<(), String>>,
+    {
+        let mut ticks = actix_web::rt::time::interval(interval);
+        loop {
+            ticks.tick().await;
+            match check().await {
+                Ok(()) => self.notify(State::Alive),
+                Err(e) => warn!("Health check failed: {e}"),
+            }
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// A fake NOTIFY_SOCKET, bound in a temporary directory
+    struct FakeSystemd {
+        dir: PathBuf,
+        socket: UnixDatagram,
+    }
+
+    impl FakeSystemd {
+        fn new() -> Self {
+            let dir = env::temp_dir().join(format!("spare-notify-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let socket = UnixDatagram::bind(dir.join("notify")).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            Self { dir, socket }
+        }
+
+        fn path(&self) -> PathBuf {
+            self.dir.join("notify")
+        }
+
+        fn receive(&self) -> String {
+            let mut buf = [0; 256];
+            let n = self.socket.recv(&mut buf).unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        }
+    }
+
+    impl Drop for FakeSystemd {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.dir).unwrap();
+        }
+    }
+
+    /// Records the reported states
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<State>>>);
+
+    impl Notifier for Recorder {
+        fn notify(&self, state: State) -> io::Result<()> {
+            self.0.lock().unwrap().push(state);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_systemd_notifier() {
+        let systemd = FakeSystemd::new();
+        let notifier = SystemdNotifier::new(systemd.path());
+        notifier.notify(State::Ready).unwrap();
+        assert_eq!(systemd.receive(), "READY=1");
+        notifier.notify(State::Alive).unwrap();
+        assert_eq!(systemd.receive(), "WATCHDOG=1");
+        notifier.notify(State::Stopping).unwrap();
+        assert_eq!(systemd.receive(), "STOPPING=1");
+
+        // Nobody listening
+        let gone = SystemdNotifier::new(systemd.dir.join("gone"));
+        assert!(gone.notify(State::Ready).is_err());
+    }
+
+    #[test]
+    fn test_from_env() {
+        let systemd = FakeSystemd::new();
+        env::set_var("NOTIFY_SOCKET", systemd.path());
+        let notifier = SystemdNotifier::from_env().unwrap();
+        env::remove_var("NOTIFY_SOCKET");
+        notifier.notify(State::Ready).unwrap();
+        assert_eq!(systemd.receive(), "READY=1");
+        assert!(SystemdNotifier::from_env().is_none());
+    }
+
+    #[test]
+    fn test_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("spare-notify-{}", uuid::Uuid::new_v4());
+        let address = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&address).unwrap();
+        SystemdNotifier::new(format!("@{name}"))
+            .notify(State::Ready)
+            .unwrap();
+        let mut buf = [0; 16];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+    }
+
+    #[test]
+    fn test_liveness_file() {
+        let path = env::temp_dir().join(format!("spare-alive-{}", uuid::Uuid::new_v4()));
+        let file = LivenessFile::new(path.clone());
+        let before = chrono::Utc::now().timestamp();
+        file.notify(State::Ready).unwrap();
+        let written: i64 = fs::read_to_string(&path).unwrap().trim().parse().unwrap();
+        assert!(written >= before);
+        file.notify(State::Alive).unwrap();
+        file.notify(State::Stopping).unwrap();
+        assert!(!path.exists());
+        // Already removed
+        file.notify(State::Stopping).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_watchdog() {
+        let systemd = FakeSystemd::new();
+        let recorder = Recorder::default();
+        let notifiers = Notifiers::new()
+            .with(SystemdNotifier::new(systemd.path()))
+            .with(recorder.clone());
+        notifiers.notify(State::Ready);
+        assert_eq!(systemd.receive(), "READY=1");
+
+        // Only the successful health checks ping the watchdog
+        let mut checks = 0;
+        let watchdog = notifiers.watchdog(Duration::from_millis(10), || {
+            checks += 1;
+            let healthy = checks % 2 == 1;
+            async move {
+                match healthy {
+                    true => Ok(()),
+                    false => Err("unhealthy".to_string()),
+                }
+            }
+        });
+        let _ = actix_web::rt::time::timeout(Duration::from_millis(95), watchdog).await;
+        let states = recorder.0.lock().unwrap().clone();
+        assert_eq!(states[0], State::Ready);
+        let alive = states[1..].len();
+        assert!(alive >= 2 && states[1..].iter().all(|s| *s == State::Alive));
+        for _ in 0..alive {
+            assert_eq!(systemd.receive(), "WATCHDOG=1");
+        }
+    }
+}
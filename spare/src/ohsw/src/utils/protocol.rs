@@ -0,0 +1,307 @@
+//! Framed protocol spoken over the vsock between the host and the guest agent.
+//!
+//! Every frame starts with a fixed size header followed by `length` bytes of body:
+//! ```text
+//! | magic (4 bytes) | version (1 byte) | frame type (1 byte) | length (8 bytes, big endian) |
+//! ```
+//! The guest opens the conversation with a `Ready` frame carrying its handshake metadata,
+//! the host answers with a `Payload` frame and then waits for either a `Result` or an
+//! `Error` frame. `Log` frames can be interleaved by the guest at any time after `Ready`.
+//!
+//! The legacy (v1) protocol, i.e. a bare `ready` string followed by raw length-prefixed
+//! payloads, is still supported for images built before the introduction of frames.
+use actix_web::rt::net::UnixStream;
+use serde::{Deserialize, Serialize};
+
+use super::socket::{read_exact, write_all};
+
+/// Magic bytes that open every frame
+pub const MAGIC: [u8; 4] = *b"SPRE";
+/// Version of the framed protocol
+pub const VERSION: u8 = 2;
+/// Size of the frame header in bytes
+pub const HEADER_LEN: usize = 14;
+/// Largest body of a frame, the length comes from the guest and the body is read in memory
+pub const MAX_FRAME_LEN: usize = 256 << 20;
+/// Message sent by legacy (v1) guests when they are ready
+pub const LEGACY_READY: &[u8; 5] = b"ready";
+
+/// Version of the protocol spoken with the guest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestProtocol {
+    /// Bare `ready` string followed by raw length-prefixed payloads
+    V1,
+    /// Framed protocol with handshake metadata and error frames
+    V2,
+}
+
+/// Type of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    /// Sent by the guest once it is ready to receive the payload
+    Ready = 0,
+    /// Sent by the host, contains the input of the function
+    Payload = 1,
+    /// Sent by the guest, contains the output of the function
+    Result = 2,
+    /// Sent by the guest when the function fails, contains the error message
+    Error = 3,
+    /// Sent by the guest to forward a log line to the host
+    Log = 4,
+}
+
+impl TryFrom<u8> for FrameType {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, ProtocolError> {
+        match value {
+            0 => Ok(FrameType::Ready),
+            1 => Ok(FrameType::Payload),
+            2 => Ok(FrameType::Result),
+            3 => Ok(FrameType::Error),
+            4 => Ok(FrameType::Log),
+            _ => Err(ProtocolError::UnknownFrameType(value)),
+        }
+    }
+}
+
+/// Error types for the guest protocol
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The frame does not start with the expected magic bytes
+    BadMagic,
+    /// The guest speaks a version of the protocol we do not understand
+    UnsupportedVersion(u8),
+    /// The frame type is not known
+    UnknownFrameType(u8),
+    /// The frame is valid, but it was not expected at this point of the conversation
+    UnexpectedFrame(FrameType),
+    /// The body of the frame is longer than `MAX_FRAME_LEN`
+    FrameTooLarge(u64),
+    /// Error reading from or writing to the stream
+    Io(std::io::Error),
+}
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::BadMagic => write!(f, "Bad magic bytes"),
+            ProtocolError::UnsupportedVersion(v) => write!(f, "Unsupported version: {}", v),
+            ProtocolError::UnknownFrameType(t) => write!(f, "Unknown frame type: {}", t),
+            ProtocolError::UnexpectedFrame(t) => write!(f, "Unexpected frame: {:?}", t),
+            ProtocolError::FrameTooLarge(len) => {
+                write!(
+                    f,
+                    "Frame too large: {} bytes, at most {}",
+                    len, MAX_FRAME_LEN
+                )
+            }
+            ProtocolError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+impl From<std::io::Error> for ProtocolError {
+    fn from(e: std::io::Error) -> Self {
+        ProtocolError::Io(e)
+    }
+}
+
+/// Metadata sent by the guest in the `Ready` frame
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct GuestMetadata {
+    /// Name and version of the guest agent
+    #[serde(default)]
+    pub agent: String,
+    /// Name of the function served by the guest, if known
+    #[serde(default)]
+    pub function: Option<String>,
+}
+
+/// A single frame of the protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub frame_type: FrameType,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    /// Create a new frame
+    pub fn new(frame_type: FrameType, body: Vec<u8>) -> Self {
+        Self { frame_type, body }
+    }
+
+    /// Encode the header of a frame with a body of `len` bytes, for a body written apart
+    pub fn header(frame_type: FrameType, len: usize) -> [u8; HEADER_LEN] {
+        let mut header = [0; HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC);
+        header[4] = VERSION;
+        header[5] = frame_type as u8;
+        header[6..].copy_from_slice(&(len as u64).to_be_bytes());
+        header
+    }
+
+    /// Encode the frame, header included
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.body.len());
+        buf.extend_from_slice(&Self::header(self.frame_type, self.body.len()));
+        buf.extend_from_slice(&self.body);
+        buf
+    }
+
+    /// Decode a frame header, returning the type and the length of the body
+    pub fn decode_header(header: &[u8; HEADER_LEN]) -> Result<(FrameType, usize), ProtocolError> {
+        if header[0..4] != MAGIC {
+            return Err(ProtocolError::BadMagic);
+        }
+        if header[4] != VERSION {
+            return Err(ProtocolError::UnsupportedVersion(header[4]));
+        }
+        let frame_type = FrameType::try_from(header[5])?;
+        let len = u64::from_be_bytes(header[6..].try_into().unwrap());
+        if len > MAX_FRAME_LEN as u64 {
+            return Err(ProtocolError::FrameTooLarge(len));
+        }
+        Ok((frame_type, len as usize))
+    }
+
+    /// Parse the handshake metadata of a `Ready` frame.
+    /// An empty or malformed body yields the default metadata.
+    pub fn metadata(&self) -> GuestMetadata {
+        serde_json::from_slice(&self.body).unwrap_or_default()
+    }
+
+    /// Get the body as a (lossy) UTF-8 string
+    pub fn body_str(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+}
+
+/// Read a single frame from the stream.
+/// The `max_timeout` parameter is applied to the header and the body separately (in milliseconds).
+pub async fn read_frame(stream: &mut UnixStream, max_timeout: u64) -> Result<Frame, ProtocolError> {
+    let mut header = [0; HEADER_LEN];
+    read_exact(stream, &mut header, max_timeout).await?;
+    let (frame_type, len) = Frame::decode_header(&header)?;
+    let mut body = vec![0; len];
+    read_exact(stream, &mut body, max_timeout).await?;
+    Ok(Frame::new(frame_type, body))
+}
+
+/// Write a single frame to the stream.
+pub async fn write_frame(
+    stream: &mut UnixStream,
+    frame: &Frame,
+    max_timeout: u64,
+) -> Result<(), ProtocolError> {
+    write_all(stream, &frame.encode(), max_timeout).await?;
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode_header() {
+        let frame = Frame::new(FrameType::Result, b"hello".to_vec());
+        let buf = frame.encode();
+        assert_eq!(buf.len(), HEADER_LEN + 5);
+        let header: [u8; HEADER_LEN] = buf[..HEADER_LEN].try_into().unwrap();
+        let (frame_type, len) = Frame::decode_header(&header).unwrap();
+        assert_eq!(frame_type, FrameType::Result);
+        assert_eq!(len, 5);
+        assert_eq!(&buf[HEADER_LEN..], b"hello");
+        // The same header as a body written apart
+        assert_eq!(Frame::header(FrameType::Result, 5), header);
+    }
+
+    #[test]
+    fn test_decode_invalid_header() {
+        let mut header: [u8; HEADER_LEN] = Frame::new(FrameType::Log, vec![]).encode()
+            [..HEADER_LEN]
+            .try_into()
+            .unwrap();
+        // A length the node would not allocate
+        header[6..].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            Frame::decode_header(&header),
+            Err(ProtocolError::FrameTooLarge(u64::MAX))
+        ));
+        header[6..].copy_from_slice(&(MAX_FRAME_LEN as u64 + 1).to_be_bytes());
+        assert!(matches!(
+            Frame::decode_header(&header),
+            Err(ProtocolError::FrameTooLarge(_))
+        ));
+        header[6..].copy_from_slice(&(MAX_FRAME_LEN as u64).to_be_bytes());
+        assert_eq!(
+            Frame::decode_header(&header).unwrap(),
+            (FrameType::Log, MAX_FRAME_LEN)
+        );
+        header[5] = 42;
+        assert!(matches!(
+            Frame::decode_header(&header),
+            Err(ProtocolError::UnknownFrameType(42))
+        ));
+        header[4] = 1;
+        assert!(matches!(
+            Frame::decode_header(&header),
+            Err(ProtocolError::UnsupportedVersion(1))
+        ));
+        header[0] = b'r';
+        assert!(matches!(
+            Frame::decode_header(&header),
+            Err(ProtocolError::BadMagic)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_round_trip() {
+        let (mut host, mut guest) = UnixStream::pair().unwrap();
+        let metadata = GuestMetadata {
+            agent: "spare-agent/2".to_string(),
+            function: Some("mandelbrot".to_string()),
+        };
+        let frames = vec![
+            Frame::new(FrameType::Ready, serde_json::to_vec(&metadata).unwrap()),
+            Frame::new(FrameType::Log, b"computing".to_vec()),
+            Frame::new(FrameType::Result, vec![0; 64 * 1024]),
+            Frame::new(FrameType::Error, b"division by zero".to_vec()),
+            // An empty payload or result, with nothing after its header
+            Frame::new(FrameType::Payload, vec![]),
+        ];
+        for frame in &frames {
+            write_frame(&mut guest, frame, 1000).await.unwrap();
+            let received = read_frame(&mut host, 1000).await.unwrap();
+            assert_eq!(&received, frame);
+        }
+        let ready = Frame::new(FrameType::Ready, serde_json::to_vec(&metadata).unwrap());
+        assert_eq!(ready.metadata(), metadata);
+        assert_eq!(
+            Frame::new(FrameType::Ready, vec![]).metadata(),
+            GuestMetadata::default()
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_frame_too_large() {
+        let (mut host, mut guest) = UnixStream::pair().unwrap();
+        let mut header = Frame::header(FrameType::Result, 0);
+        header[6..].copy_from_slice(&(1u64 << 62).to_be_bytes());
+        write_all(&mut guest, &header, 1000).await.unwrap();
+        // Refused from its header, before anything is allocated
+        assert!(matches!(
+            read_frame(&mut host, 1000).await,
+            Err(ProtocolError::FrameTooLarge(len)) if len == 1 << 62
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_guest_crash() {
+        let (mut host, guest) = UnixStream::pair().unwrap();
+        drop(guest);
+        assert!(matches!(
+            read_frame(&mut host, 100).await,
+            Err(ProtocolError::Io(_))
+        ));
+    }
+}
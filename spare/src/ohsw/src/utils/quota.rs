@@ -0,0 +1,218 @@
+//! Accounting of the resources used by each API key.
+//! The resources of a request are reserved against the quota of its key before the
+//! local resources are acquired, and given back when the instance is torn down or
+//! the request leaves the node. Keys without a quota are accounted but not limited.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::api::quota::{QuotaLimits, QuotaStatus, QuotaUsage};
+
+#[derive(Default)]
+struct Entry {
+    limits: Option<QuotaLimits>,
+    usage: QuotaUsage,
+}
+
+/// Usage and limits of the API keys
+#[derive(Default)]
+pub struct QuotaTracker {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// Resources reserved for a request, given back when dropped.
+/// It keeps the tracker, to outlive the request, e.g. in a streamed batch.
+pub struct QuotaReservation {
+    tracker: Arc<QuotaTracker>,
+    api_key: String,
+    usage: QuotaUsage,
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        let mut entries = self.tracker.entries.lock().unwrap();
+        if let Some(entry) = entries.get_mut(&self.api_key) {
+            entry.usage.vcpus -= self.usage.vcpus;
+            entry.usage.memory -= self.usage.memory;
+            entry.usage.instances -= self.usage.instances;
+        }
+    }
+}
+
+impl QuotaTracker {
+    /// Create a new tracker, with no quota and no usage
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the limits of an API key
+    pub fn set_limits(&self, api_key: &str, limits: QuotaLimits) {
+        self.entries
+            .lock()
+            .unwrap()
+            .entry(api_key.to_string())
+            .or_default()
+            .limits = Some(limits);
+    }
+
+    /// Replace the usage of the API keys with the given one, e.g. read from the database
+    pub fn reconcile(&self, usage: Vec<(String, QuotaUsage)>) {
+        let mut entries = self.entries.lock().unwrap();
+        for entry in entries.values_mut() {
+            entry.usage = QuotaUsage::default();
+        }
+        for (api_key, usage) in usage {
+            entries.entry(api_key).or_default().usage = usage;
+        }
+    }
+
+    /// Get the usage and the limits of an API key
+    pub fn status(&self, api_key: &str) -> QuotaStatus {
+        match self.entries.lock().unwrap().get(api_key) {
+            Some(entry) => QuotaStatus {
+                usage: entry.usage,
+                limits: entry.limits,
+            },
+            None => QuotaStatus {
+                usage: QuotaUsage::default(),
+                limits: None,
+            },
+        }
+    }
+
+    /// Reserve the resources of an instance for an API key
+    /// # Arguments
+    /// * `api_key` - The key of the request
+    /// * `vcpus` - The vcpus of the instance
+    /// * `memory` - The memory of the instance (in MiB)
+    /// # Errors
+    /// * The current usage and limits of the key, if the instance would exceed its quota
+    pub fn reserve(
+        self: &Arc<Self>,
+        api_key: &str,
+        vcpus: i64,
+        memory: i64,
+    ) -> Result<QuotaReservation, QuotaStatus> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entry(api_key.to_string()).or_default();
+        let usage = QuotaUsage {
+            vcpus: entry.usage.vcpus + vcpus,
+            memory: entry.usage.memory + memory,
+            instances: entry.usage.instances + 1,
+        };
+        if let Some(limits) = &entry.limits {
+            if !usage.within(limits) {
+                return Err(QuotaStatus {
+                    usage: entry.usage,
+                    limits: entry.limits,
+                });
+            }
+        }
+        entry.usage = usage;
+        Ok(QuotaReservation {
+            tracker: self.clone(),
+            api_key: api_key.to_string(),
+            usage: QuotaUsage {
+                vcpus,
+                memory,
+                instances: 1,
+            },
+        })
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    fn limits(max_vcpus: i64, max_memory: i64, max_instances: i64) -> QuotaLimits {
+        QuotaLimits {
+            max_vcpus,
+            max_memory,
+            max_instances,
+        }
+    }
+
+    #[test]
+    fn test_reserve() {
+        let tracker = Arc::new(QuotaTracker::new());
+        tracker.set_limits("tenant", limits(4, 512, 2));
+
+        let first = tracker.reserve("tenant", 2, 256).unwrap();
+        let second = tracker.reserve("tenant", 2, 128).unwrap();
+        // Each limit is enforced on its own
+        let refused = tracker.reserve("tenant", 0, 0).err().unwrap();
+        assert_eq!(
+            refused.usage,
+            QuotaUsage {
+                vcpus: 4,
+                memory: 384,
+                instances: 2
+            }
+        );
+        drop(second);
+        assert!(tracker.reserve("tenant", 1, 512).is_err());
+        assert!(tracker.reserve("tenant", 3, 128).is_err());
+        drop(tracker.reserve("tenant", 2, 256).unwrap());
+        drop(first);
+        assert_eq!(tracker.status("tenant").usage, QuotaUsage::default());
+
+        // Keys without a quota are only accounted
+        let _other = tracker.reserve("other", 64, 65536).unwrap();
+        assert_eq!(tracker.status("other").usage.vcpus, 64);
+        assert_eq!(tracker.status("other").limits, None);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let tracker = Arc::new(QuotaTracker::new());
+        tracker.set_limits("tenant", limits(4, 512, 2));
+        let _stale = tracker.reserve("gone", 1, 128).unwrap();
+        let usage = QuotaUsage {
+            vcpus: 4,
+            memory: 256,
+            instances: 2,
+        };
+        tracker.reconcile(vec![("tenant".to_string(), usage)]);
+        assert_eq!(tracker.status("tenant").usage, usage);
+        assert!(tracker.reserve("tenant", 1, 128).is_err());
+    }
+
+    #[test]
+    fn test_concurrent_reservations() {
+        let tracker = Arc::new(QuotaTracker::new());
+        tracker.set_limits("tenant", limits(8, 8 * 128, 8));
+        let running = Arc::new(AtomicI64::new(0));
+        let peak = Arc::new(AtomicI64::new(0));
+        let threads: Vec<_> = (0..16)
+            .map(|_| {
+                let (tracker, running, peak) = (tracker.clone(), running.clone(), peak.clone());
+                std::thread::spawn(move || {
+                    let mut admitted = 0;
+                    for _ in 0..1000 {
+                        if let Ok(reservation) = tracker.reserve("tenant", 2, 128) {
+                            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                            peak.fetch_max(now, Ordering::SeqCst);
+                            running.fetch_sub(1, Ordering::SeqCst);
+                            drop(reservation);
+                            admitted += 1;
+                        }
+                    }
+                    admitted
+                })
+            })
+            .collect();
+        let admitted: i64 = threads.into_iter().map(|t| t.join().unwrap()).sum();
+        assert!(admitted > 0);
+        // At most 4 instances of 2 vcpus fit in the quota at any time
+        assert!(peak.load(Ordering::SeqCst) <= 4);
+        assert_eq!(tracker.status("tenant").usage, QuotaUsage::default());
+    }
+}
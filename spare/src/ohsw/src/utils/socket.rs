@@ -1,3 +1,8 @@
+use std::{
+    io::IoSlice,
+    time::{Duration, Instant},
+};
+
 use actix_web::rt::{
     net::UnixStream,
     time::{sleep, timeout},
@@ -22,6 +27,10 @@ pub async fn read_exact(
     buf: &mut [u8],
     max_timeout: u64,
 ) -> Result<(), std::io::Error> {
+    // Nothing to wait for, e.g. the body of an empty frame: no more data may ever come
+    if buf.is_empty() {
+        return Ok(());
+    }
     let mut total_read = 0;
     let mut delay = 2;
     let mut total_timeout = 0;
@@ -153,3 +162,108 @@ pub async fn write_all(
     }
     Ok(())
 }
+
+/// Writes all bytes of `bufs` to the stream, in order, as `write_all` does for a single buffer.
+/// The buffers are handed to the kernel together, e.g. a length prefix and the payload it
+/// announces, so they are neither copied into a single buffer nor sent in separate writes.
+/// Rather than backing off when the socket is full, it waits for the other end to read.
+/// # Arguments
+/// * `stream` - The UnixStream to write to.
+/// * `bufs` - The buffers to write the data from, one after the other.
+/// * `max_timeout` - The maximum time for the whole write operation (in milliseconds).
+/// # Returns
+/// A Result indicating success or failure.
+/// # Errors
+/// If the stream is closed before writing every buffer, or if a timeout occurs.
+pub async fn write_all_vectored(
+    stream: &mut UnixStream,
+    bufs: &[IoSlice<'_>],
+    max_timeout: u64,
+) -> Result<(), std::io::Error> {
+    // The slices are advanced as they are written, the caller's are left untouched
+    let mut slices: Vec<IoSlice<'_>> = bufs
+        .iter()
+        .filter(|buf| !buf.is_empty())
+        .map(|buf| IoSlice::new(buf))
+        .collect();
+    let mut remaining = &mut slices[..];
+    let deadline = Instant::now() + Duration::from_millis(max_timeout);
+
+    while !remaining.is_empty() {
+        // A write that would block clears the readiness, this waits for the reader
+        let left = deadline.saturating_duration_since(Instant::now());
+        match timeout(left, stream.writable()).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "Timeout Writing!",
+                ))
+            }
+        }
+
+        match stream.try_write_vectored(remaining) {
+            Ok(0) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Stream closed before writing the expected amount of data",
+                ));
+            }
+            Ok(n) => IoSlice::advance_slices(&mut remaining, n),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stream of the node, with the other end read to the end by a thread as the guest
+    fn guest() -> (UnixStream, std::thread::JoinHandle<Vec<u8>>) {
+        use std::io::Read;
+
+        let (node, mut guest) = std::os::unix::net::UnixStream::pair().unwrap();
+        node.set_nonblocking(true).unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut received = vec![];
+            guest.read_to_end(&mut received).unwrap();
+            received
+        });
+        (UnixStream::from_std(node).unwrap(), reader)
+    }
+
+    #[actix_web::test]
+    async fn test_write_all_vectored() {
+        // Larger than the buffer of the socket, the writes are partial
+        let payload: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let len = (payload.len() as u64).to_be_bytes();
+        let (mut node, reader) = guest();
+        let bufs = [
+            IoSlice::new(&len),
+            IoSlice::new(&[]),
+            IoSlice::new(&payload),
+        ];
+        write_all_vectored(&mut node, &bufs, 1000).await.unwrap();
+        // Nothing to write
+        write_all_vectored(&mut node, &[IoSlice::new(&[])], 1000)
+            .await
+            .unwrap();
+        drop(node);
+        let received = reader.join().unwrap();
+        assert_eq!(received.len(), 8 + payload.len());
+        assert_eq!(&received[..8], &len);
+        assert!(received[8..] == payload[..]);
+
+        // The guest is gone
+        let (mut node, guest) = UnixStream::pair().unwrap();
+        drop(guest);
+        assert!(write_all_vectored(&mut node, &[IoSlice::new(b"x")], 1000)
+            .await
+            .is_err());
+    }
+}
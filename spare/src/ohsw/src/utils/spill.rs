@@ -0,0 +1,315 @@
+//! Binary payloads of the requests, kept in memory or spilled to disk.
+//! A payload larger than a threshold is written to a file while it is received, and read
+//! back a chunk at a time when it is sent to the instance, so the memory taken by a
+//! request does not grow with its payload. The file is removed when the payload is
+//! dropped: once the request is served, when it fails, or when the client goes away.
+use std::{
+    fs::{self, File},
+    io::{self, IoSlice, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use actix_web::{
+    error::PayloadError,
+    rt::net::UnixStream,
+    web::{Bytes, BytesMut},
+};
+use futures::{Stream, StreamExt};
+use log::warn;
+
+use super::socket::write_all_vectored;
+
+/// Extension of the files of the spilled payloads
+const EXTENSION: &str = "payload";
+
+/// Size of the chunks a spilled payload is read back with
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Error while receiving a payload
+#[derive(Debug, thiserror::Error)]
+pub enum SpillError {
+    #[error("The payload is larger than {0} bytes")]
+    TooLarge(usize),
+    #[error("Cannot read the payload: {0}")]
+    Payload(#[from] PayloadError),
+    #[error("Cannot spill the payload to disk: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Where and from which size the payloads are spilled
+#[derive(Debug, Clone)]
+pub struct SpillConfig {
+    /// Directory of the spilled payloads
+    pub dir: PathBuf,
+    /// Size from which a payload is spilled (in bytes)
+    pub threshold: usize,
+    /// Maximum size of a payload (in bytes)
+    pub max_size: usize,
+}
+
+impl SpillConfig {
+    pub fn new(dir: PathBuf, threshold: usize, max_size: usize) -> Self {
+        SpillConfig {
+            dir,
+            threshold,
+            max_size,
+        }
+    }
+
+    /// Create the directory of the spilled payloads, removing the ones left by a
+    /// previous run of the node. Returns the number of payloads removed.
+    pub fn prepare(&self) -> io::Result<usize> {
+        fs::create_dir_all(&self.dir)?;
+        let mut removed = 0;
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == EXTENSION)
+            {
+                fs::remove_file(&path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Receive a payload, spilling it to disk once it grows over the threshold
+    pub async fn receive<S>(&self, mut stream: S) -> Result<Body, SpillError>
+    where
+        S: Stream<Item = Result<Bytes, PayloadError>> + Unpin,
+    {
+        let mut buffer = BytesMut::new();
+        let mut spilled: Option<(SpillFile, File)> = None;
+        let mut size = 0;
+        while let Some(chunk) = stream.next().await {
+            // On any error the file, if any, is dropped and so removed
+            let chunk = chunk?;
+            size += chunk.len();
+            if size > self.max_size {
+                return Err(SpillError::TooLarge(self.max_size));
+            }
+            match &mut spilled {
+                Some((_, file)) => file.write_all(&chunk)?,
+                None if size > self.threshold => {
+                    let (spill_file, mut file) = SpillFile::create(&self.dir)?;
+                    file.write_all(&buffer)?;
+                    file.write_all(&chunk)?;
+                    buffer = BytesMut::new();
+                    spilled = Some((spill_file, file));
+                }
+                None => buffer.extend_from_slice(&chunk),
+            }
+        }
+        match spilled {
+            Some((mut spill_file, mut file)) => {
+                file.flush()?;
+                spill_file.len = size;
+                Ok(Body::File(spill_file))
+            }
+            None => Ok(Body::Memory(buffer.freeze())),
+        }
+    }
+}
+
+/// A payload spilled to disk, the file is removed when it is dropped
+#[derive(Debug)]
+pub struct SpillFile {
+    path: PathBuf,
+    len: usize,
+}
+
+impl SpillFile {
+    /// Create a new empty file in `dir`
+    fn create(dir: &Path) -> io::Result<(Self, File)> {
+        let path = dir.join(format!("{}.{EXTENSION}", uuid::Uuid::new_v4()));
+        let file = File::options().write(true).create_new(true).open(&path)?;
+        Ok((SpillFile { path, len: 0 }, file))
+    }
+
+    /// Path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!(
+                "Cannot remove the spilled payload {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// A payload to be sent to an instance
+#[derive(Debug)]
+pub enum Body {
+    Memory(Bytes),
+    File(SpillFile),
+}
+
+impl Body {
+    /// Size of the payload (in bytes)
+    pub fn len(&self) -> usize {
+        match self {
+            Body::Memory(bytes) => bytes.len(),
+            Body::File(spill_file) => spill_file.len,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The whole payload in memory, a spilled one is read back from its file
+    pub fn to_bytes(&self) -> io::Result<Bytes> {
+        match self {
+            Body::Memory(bytes) => Ok(bytes.clone()),
+            Body::File(spill_file) => fs::read(&spill_file.path).map(Bytes::from),
+        }
+    }
+
+    /// Write `header`, e.g. the length of the payload, then the payload on the stream.
+    /// A payload in memory goes with its header in vectored writes, without copying them
+    /// together; a spilled one is read back a chunk at a time. The file is opened again at
+    /// every call, so the payload can be sent more than once.
+    pub async fn write_to(
+        &self,
+        stream: &mut UnixStream,
+        header: &[u8],
+        max_timeout: u64,
+    ) -> io::Result<()> {
+        match self {
+            Body::Memory(bytes) => {
+                let bufs = [IoSlice::new(header), IoSlice::new(bytes)];
+                write_all_vectored(stream, &bufs, max_timeout).await
+            }
+            Body::File(spill_file) => {
+                let mut file = File::open(&spill_file.path)?;
+                write_all_vectored(stream, &[IoSlice::new(header)], max_timeout).await?;
+                let mut chunk = vec![0; CHUNK_SIZE];
+                loop {
+                    let read = file.read(&mut chunk)?;
+                    if read == 0 {
+                        return Ok(());
+                    }
+                    write_all_vectored(stream, &[IoSlice::new(&chunk[..read])], max_timeout)
+                        .await?;
+                }
+            }
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::socket::read_exact;
+    use futures::stream;
+
+    fn spill_config(threshold: usize, max_size: usize) -> SpillConfig {
+        let dir = std::env::temp_dir().join(format!("spare-spill-{}", uuid::Uuid::new_v4()));
+        let config = SpillConfig::new(dir, threshold, max_size);
+        config.prepare().unwrap();
+        config
+    }
+
+    fn files(config: &SpillConfig) -> usize {
+        fs::read_dir(&config.dir).unwrap().count()
+    }
+
+    fn chunks(chunks: &[&'static [u8]]) -> impl Stream<Item = Result<Bytes, PayloadError>> + Unpin {
+        stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok(Bytes::from_static(chunk)))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[actix_web::test]
+    async fn test_in_memory() {
+        let config = spill_config(10, 100);
+        let body = config.receive(chunks(&[b"hello", b" you"])).await.unwrap();
+        assert!(matches!(&body, Body::Memory(bytes) if bytes == "hello you"));
+        assert_eq!(files(&config), 0);
+
+        // Sent after its header, as one stream of bytes
+        let (mut node, mut guest) = UnixStream::pair().unwrap();
+        body.write_to(&mut node, b"9:", 1000).await.unwrap();
+        let mut received = [0; 11];
+        read_exact(&mut guest, &mut received, 1000).await.unwrap();
+        assert_eq!(&received, b"9:hello you");
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_spilled() {
+        let config = spill_config(4, 100);
+        let body = config
+            .receive(chunks(&[b"abc", b"defg", b"hij"]))
+            .await
+            .unwrap();
+        let path = match &body {
+            Body::File(spill_file) => spill_file.path().to_path_buf(),
+            Body::Memory(_) => panic!("The payload was not spilled"),
+        };
+        assert_eq!(body.len(), 10);
+        assert_eq!(fs::read(&path).unwrap(), b"abcdefghij");
+
+        // The payload is read back from the file, as many times as needed
+        let (mut node, mut guest) = UnixStream::pair().unwrap();
+        for _ in 0..2 {
+            body.write_to(&mut node, b"10:", 1000).await.unwrap();
+            let mut received = [0; 13];
+            read_exact(&mut guest, &mut received, 1000).await.unwrap();
+            assert_eq!(&received, b"10:abcdefghij");
+        }
+
+        assert_eq!(body.to_bytes().unwrap(), "abcdefghij");
+
+        drop(body);
+        assert!(!path.exists());
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_too_large() {
+        let config = spill_config(4, 8);
+        let result = config.receive(chunks(&[b"abcdef", b"ghi"])).await;
+        assert!(matches!(result, Err(SpillError::TooLarge(8))));
+        // What was spilled before the limit was reached is gone
+        assert_eq!(files(&config), 0);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_disconnect() {
+        let config = spill_config(4, 100);
+        // The client goes away in the middle of the payload
+        let stream = stream::iter(vec![
+            Ok(Bytes::from_static(b"abcdef")),
+            Err(PayloadError::Incomplete(None)),
+        ]);
+        let result = config.receive(stream).await;
+        assert!(matches!(result, Err(SpillError::Payload(_))));
+        assert_eq!(files(&config), 0);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn test_prepare() {
+        let config = spill_config(4, 100);
+        fs::write(config.dir.join("left.payload"), b"x").unwrap();
+        fs::write(config.dir.join("other.txt"), b"x").unwrap();
+        // Only the payloads left by a previous run are removed
+        assert_eq!(config.prepare().unwrap(), 1);
+        assert_eq!(files(&config), 1);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+//! Output of the per-epoch statistics of the node.
+//! The file is named after a template, so nodes sharing a filesystem can write to
+//! different files, and flushed after every epoch, so a crash does not lose the run.
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use serde_json::json;
+
+use crate::db::{InstanceStats, Stats};
+
+/// Format of the statistics file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsFormat {
+    /// Comma-separated values
+    Csv,
+    /// Fixed-width columns
+    Table,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl FromStr for StatsFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(StatsFormat::Csv),
+            "table" => Ok(StatsFormat::Table),
+            "jsonl" => Ok(StatsFormat::Jsonl),
+            _ => Err(format!("Unknown stats format: {}", s)),
+        }
+    }
+}
+
+/// Fill a path template, replacing `{address}`, `{x}` and `{y}`.
+/// The coordinates of a node without a position are `none`.
+pub fn stats_path(template: &str, address: &str, position: Option<(f64, f64)>) -> PathBuf {
+    let (x, y) = match position {
+        Some((x, y)) => (x.to_string(), y.to_string()),
+        None => ("none".to_string(), "none".to_string()),
+    };
+    PathBuf::from(
+        template
+            .replace("{address}", address)
+            .replace("{x}", &x)
+            .replace("{y}", &y),
+    )
+}
+
+/// Record of the instances started for one kind of request, in the JSON lines format
+fn breakdown(stats: &InstanceStats) -> serde_json::Value {
+    json!({
+        "hops_avg": stats.hops_avg,
+        "vcpus_sum": stats.vcpus,
+        "memory_sum": stats.memory,
+        "requests": stats.requests,
+    })
+}
+
+/// Writer of the statistics of the node, one record per epoch
+pub struct StatsWriter<W: Write> {
+    out: W,
+    format: StatsFormat,
+}
+
+impl StatsWriter<BufWriter<File>> {
+    /// Create the statistics file at `path`
+    pub fn create(
+        path: PathBuf,
+        format: StatsFormat,
+        address: &str,
+        strategy: &str,
+    ) -> Result<Self, io::Error> {
+        Self::new(
+            BufWriter::new(File::create(path)?),
+            format,
+            address,
+            strategy,
+        )
+    }
+}
+
+impl<W: Write> StatsWriter<W> {
+    /// Create a new writer and write the header record, with the node address and strategy
+    pub fn new(
+        out: W,
+        format: StatsFormat,
+        address: &str,
+        strategy: &str,
+    ) -> Result<Self, io::Error> {
+        let mut writer = Self { out, format };
+        match format {
+            StatsFormat::Csv => {
+                writeln!(writer.out, "# address={},strategy={}", address, strategy)?;
+                writeln!(
+                    writer.out,
+                    "epoch,hops_avg,vcpus_sum,memory_sum,requests,received,offloaded,\
+                     emergency_hops_avg,emergency_vcpus_sum,emergency_memory_sum,emergency_requests,\
+                     normal_hops_avg,normal_vcpus_sum,normal_memory_sum,normal_requests"
+                )?;
+            }
+            StatsFormat::Table => {
+                writeln!(writer.out, "# address: {} strategy: {}", address, strategy)?;
+                writeln!(
+                    writer.out,
+                    "{:<15} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10}",
+                    "epoch",
+                    "hops_avg",
+                    "vcpus_sum",
+                    "memory_sum",
+                    "requests",
+                    "received",
+                    "offloaded",
+                    "em_hops",
+                    "em_vcpus",
+                    "em_memory",
+                    "em_reqs",
+                    "nm_hops",
+                    "nm_vcpus",
+                    "nm_memory",
+                    "nm_reqs"
+                )?;
+            }
+            StatsFormat::Jsonl => {
+                let header = json!({ "address": address, "strategy": strategy });
+                writeln!(writer.out, "{}", header)?;
+            }
+        }
+        writer.out.flush()?;
+        Ok(writer)
+    }
+
+    /// Write the statistics of an epoch and flush them
+    pub fn write_epoch(&mut self, epoch: u64, stats: &Stats) -> Result<(), io::Error> {
+        match self.format {
+            StatsFormat::Csv => writeln!(
+                self.out,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                epoch,
+                stats.hops_avg,
+                stats.vcpus,
+                stats.memory,
+                stats.requests,
+                stats.received,
+                stats.offloaded,
+                stats.emergency.hops_avg,
+                stats.emergency.vcpus,
+                stats.emergency.memory,
+                stats.emergency.requests,
+                stats.normal.hops_avg,
+                stats.normal.vcpus,
+                stats.normal.memory,
+                stats.normal.requests
+            )?,
+            StatsFormat::Table => writeln!(
+                self.out,
+                "{:<15} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10} {:<10}",
+                epoch,
+                stats.hops_avg,
+                stats.vcpus,
+                stats.memory,
+                stats.requests,
+                stats.received,
+                stats.offloaded,
+                stats.emergency.hops_avg,
+                stats.emergency.vcpus,
+                stats.emergency.memory,
+                stats.emergency.requests,
+                stats.normal.hops_avg,
+                stats.normal.vcpus,
+                stats.normal.memory,
+                stats.normal.requests
+            )?,
+            StatsFormat::Jsonl => {
+                let record = json!({
+                    "epoch": epoch,
+                    "hops_avg": stats.hops_avg,
+                    "vcpus_sum": stats.vcpus,
+                    "memory_sum": stats.memory,
+                    "requests": stats.requests,
+                    "received": stats.received,
+                    "offloaded": stats.offloaded,
+                    "emergency": breakdown(&stats.emergency),
+                    "normal": breakdown(&stats.normal),
+                });
+                writeln!(self.out, "{}", record)?
+            }
+        }
+        self.out.flush()
+    }
+
+    /// Get the underlying writer
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(requests: i64) -> Stats {
+        Stats {
+            hops_avg: 1.5,
+            vcpus: 4,
+            memory: 512,
+            requests,
+            received: requests + 5,
+            offloaded: 5,
+            shed: 0,
+            emergency: InstanceStats {
+                hops_avg: 0.0,
+                vcpus: 1,
+                memory: 128,
+                requests: 1,
+            },
+            normal: InstanceStats {
+                hops_avg: 2.0,
+                vcpus: 3,
+                memory: 384,
+                requests: requests - 1,
+            },
+        }
+    }
+
+    fn write(format: StatsFormat) -> String {
+        let mut writer =
+            StatsWriter::new(Vec::new(), format, "10.0.0.1:8085", "GeoDistance").unwrap();
+        writer.write_epoch(0, &stats(10)).unwrap();
+        writer.write_epoch(1, &stats(20)).unwrap();
+        String::from_utf8(writer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn test_stats_format() {
+        assert_eq!(StatsFormat::from_str("csv"), Ok(StatsFormat::Csv));
+        assert_eq!(StatsFormat::from_str("table"), Ok(StatsFormat::Table));
+        assert_eq!(StatsFormat::from_str("jsonl"), Ok(StatsFormat::Jsonl));
+        assert!(StatsFormat::from_str("xml").is_err());
+    }
+
+    #[test]
+    fn test_stats_path() {
+        assert_eq!(
+            stats_path(
+                "node_x{x}_y{y}.stats.data",
+                "10.0.0.1:8085",
+                Some((1.5, -2.0))
+            ),
+            PathBuf::from("node_x1.5_y-2.stats.data")
+        );
+        assert_eq!(
+            stats_path("/data/{address}.jsonl", "10.0.0.1:8085", Some((0.0, 0.0))),
+            PathBuf::from("/data/10.0.0.1:8085.jsonl")
+        );
+        assert_eq!(
+            stats_path("node_x{x}_y{y}.stats.data", "10.0.0.1:8085", None),
+            PathBuf::from("node_xnone_ynone.stats.data")
+        );
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(
+            write(StatsFormat::Csv),
+            "# address=10.0.0.1:8085,strategy=GeoDistance\n\
+             epoch,hops_avg,vcpus_sum,memory_sum,requests,received,offloaded,\
+             emergency_hops_avg,emergency_vcpus_sum,emergency_memory_sum,emergency_requests,\
+             normal_hops_avg,normal_vcpus_sum,normal_memory_sum,normal_requests\n\
+             0,1.5,4,512,10,15,5,0,1,128,1,2,3,384,9\n\
+             1,1.5,4,512,20,25,5,0,1,128,1,2,3,384,19\n"
+        );
+    }
+
+    #[test]
+    fn test_table() {
+        let output = write(StatsFormat::Table);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "# address: 10.0.0.1:8085 strategy: GeoDistance");
+        assert!(lines[1].starts_with("epoch           hops_avg"));
+        assert!(lines[3].starts_with("1               1.5        4"));
+    }
+
+    #[test]
+    fn test_jsonl() {
+        let output = write(StatsFormat::Jsonl);
+        let records: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records[0]["address"], "10.0.0.1:8085");
+        assert_eq!(records[0]["strategy"], "GeoDistance");
+        assert_eq!(records[2]["epoch"], 1);
+        assert_eq!(records[2]["requests"], 20);
+        assert_eq!(records[2]["offloaded"], 5);
+        assert_eq!(records[2]["emergency"]["requests"], 1);
+        assert_eq!(records[2]["normal"]["memory_sum"], 384);
+    }
+
+    #[test]
+    fn test_flush_every_epoch() {
+        let path = std::env::temp_dir().join(format!("spare-stats-{}", uuid::Uuid::new_v4()));
+        let mut writer =
+            StatsWriter::create(path.clone(), StatsFormat::Csv, "node", "GeoDistance").unwrap();
+        writer.write_epoch(0, &stats(1)).unwrap();
+        // The writer is still open, the epoch must already be on disk
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+        drop(writer);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
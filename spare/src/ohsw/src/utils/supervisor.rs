@@ -0,0 +1,281 @@
+//! Supervision of the long-running tasks of the node, e.g. the emergency controller.
+//! A task that panics, or returns before it was cancelled, is logged and started again
+//! after a backoff, doubled at every failure in a row, so that a bug hit by one message
+//! does not leave the node deaf to the control plane until it is restarted. While a task
+//! waits to be started again the node reports itself degraded on /healthz.
+use std::{
+    any::Any,
+    collections::BTreeMap,
+    future::Future,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::rt::{task::JoinHandle, time::sleep};
+use futures::future::{select, Either};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio_util::sync::CancellationToken;
+
+/// Backoff before starting a failed task again
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// Wait after the first failure, doubled at every failure in a row
+    pub initial: Duration,
+    /// Longest wait. A task that ran for longer before failing starts over from `initial`
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Backoff {
+    /// Wait after the given number of failures in a row, at least one
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// State of a supervised task
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Running,
+    /// The task failed, it is started again after the backoff
+    Restarting,
+    /// The task was cancelled, it is not started again
+    Stopped,
+}
+
+/// Health of a supervised task, as served on /healthz
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskHealth {
+    pub name: String,
+    pub state: TaskState,
+    /// Times the task failed since the node started
+    pub failures: u64,
+    /// Why the task failed last, if it ever did
+    pub last_failure: Option<String>,
+}
+
+/// The supervised tasks of the node
+#[derive(Debug, Default)]
+pub struct Supervisor {
+    backoff: Backoff,
+    tasks: Mutex<BTreeMap<String, TaskHealth>>,
+}
+
+/// Get the message a task panicked with
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => "unknown panic".to_string(),
+        },
+    }
+}
+
+impl Supervisor {
+    /// Create a new supervisor, starting the failed tasks again after `backoff`
+    pub fn new(backoff: Backoff) -> Self {
+        Supervisor {
+            backoff,
+            tasks: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Health of the supervised tasks, by name
+    pub fn health(&self) -> Vec<TaskHealth> {
+        self.tasks.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Whether a task failed and waits to be started again
+    pub fn degraded(&self) -> bool {
+        self.tasks
+            .lock()
+            .unwrap()
+            .values()
+            .any(|task| task.state == TaskState::Restarting)
+    }
+
+    fn set_state(&self, name: &str, state: TaskState) {
+        if let Some(task) = self.tasks.lock().unwrap().get_mut(name) {
+            task.state = state;
+        }
+    }
+
+    fn record_failure(&self, name: &str, failure: String) {
+        if let Some(task) = self.tasks.lock().unwrap().get_mut(name) {
+            task.state = TaskState::Restarting;
+            task.failures += 1;
+            task.last_failure = Some(failure);
+        }
+    }
+
+    /// Run a task under supervision, until `cancel` is cancelled.
+    /// `task` starts a run of the task, which must return once the token it is given is
+    /// cancelled. A run that returns before is a failure, as one that panics.
+    /// Must be called from within an actix runtime.
+    /// # Returns
+    /// * The handle of the supervision, done once the task stopped
+    pub fn supervise<F, Fut>(
+        self: &Arc<Self>,
+        name: &str,
+        cancel: CancellationToken,
+        task: F,
+    ) -> JoinHandle<()>
+    where
+        F: Fn(CancellationToken) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        let supervisor = self.clone();
+        let name = name.to_string();
+        self.tasks.lock().unwrap().insert(
+            name.clone(),
+            TaskHealth {
+                name: name.clone(),
+                state: TaskState::Running,
+                failures: 0,
+                last_failure: None,
+            },
+        );
+        actix_web::rt::spawn(async move {
+            let mut failures = 0;
+            loop {
+                let started = Instant::now();
+                let failure = match actix_web::rt::spawn(task(cancel.clone())).await {
+                    Ok(()) if cancel.is_cancelled() => break,
+                    Ok(()) => "returned before it was cancelled".to_string(),
+                    Err(e) if e.is_panic() => {
+                        format!("panicked: {}", panic_message(e.into_panic()))
+                    }
+                    Err(e) => e.to_string(),
+                };
+                // A task that ran for long enough failed for another reason
+                if started.elapsed() >= supervisor.backoff.max {
+                    failures = 0;
+                }
+                failures += 1;
+                let delay = supervisor.backoff.delay(failures);
+                error!("Task {} {}, restarting it in {:?}", name, failure, delay);
+                supervisor.record_failure(&name, failure);
+                if let Either::Left(_) = select(pin!(cancel.cancelled()), pin!(sleep(delay))).await
+                {
+                    break;
+                }
+                info!("Restarting task {}", name);
+                supervisor.set_state(&name, TaskState::Running);
+            }
+            info!("Task {} stopped", name);
+            supervisor.set_state(&name, TaskState::Stopped);
+        })
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const BACKOFF: Backoff = Backoff {
+        initial: Duration::from_millis(20),
+        max: Duration::from_millis(500),
+    };
+
+    /// Wait until `done` holds, for up to a second
+    async fn until(done: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        panic!("Timed out");
+    }
+
+    #[test]
+    fn test_backoff() {
+        let delays: Vec<_> = (0..=7).map(|failures| BACKOFF.delay(failures)).collect();
+        let millis: Vec<_> = delays.iter().map(Duration::as_millis).collect();
+        assert_eq!(millis, vec![20, 20, 40, 80, 160, 320, 500, 500]);
+        assert_eq!(BACKOFF.delay(u32::MAX), BACKOFF.max);
+    }
+
+    #[actix_web::test]
+    async fn test_restart() {
+        let supervisor = Arc::new(Supervisor::new(BACKOFF));
+        let cancel = CancellationToken::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let counted = runs.clone();
+        // The first two runs fail, one panicking and one returning early
+        let handle = supervisor.supervise("flaky", cancel.clone(), move |cancel| {
+            let run = counted.fetch_add(1, Ordering::SeqCst);
+            async move {
+                match run {
+                    0 => panic!("first run"),
+                    1 => {}
+                    _ => cancel.cancelled().await,
+                }
+            }
+        });
+
+        until(|| supervisor.degraded()).await;
+        let health = supervisor.health();
+        assert_eq!(health[0].name, "flaky");
+        assert_eq!(
+            health[0].last_failure.as_deref(),
+            Some("panicked: first run")
+        );
+
+        until(|| runs.load(Ordering::SeqCst) == 3).await;
+        until(|| !supervisor.degraded()).await;
+        let health = supervisor.health();
+        assert_eq!(
+            (health[0].state, health[0].failures),
+            (TaskState::Running, 2)
+        );
+        assert_eq!(
+            health[0].last_failure.as_deref(),
+            Some("returned before it was cancelled")
+        );
+
+        // A cancelled task is not started again
+        cancel.cancel();
+        handle.await.unwrap();
+        assert_eq!(supervisor.health()[0].state, TaskState::Stopped);
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_cancel_backoff() {
+        let supervisor = Arc::new(Supervisor::new(Backoff {
+            initial: Duration::from_secs(60),
+            max: Duration::from_secs(60),
+        }));
+        let cancel = CancellationToken::new();
+        let handle = supervisor.supervise("broken", cancel.clone(), |_| async {
+            panic!("always");
+        });
+        until(|| supervisor.degraded()).await;
+
+        // The supervision stops without waiting for the backoff
+        cancel.cancel();
+        handle.await.unwrap();
+        let health = supervisor.health();
+        assert_eq!(
+            (health[0].state, health[0].failures),
+            (TaskState::Stopped, 1)
+        );
+        assert!(!supervisor.degraded());
+    }
+}
@@ -0,0 +1,284 @@
+//! Nodes of the integration tests, run in process.
+//! Every node has its own in-memory database and runs its instances in the mock execution
+//! environment, so the whole path of a request is exercised without firecracker or root.
+#![allow(dead_code)]
+use std::{
+    fs,
+    net::{Ipv4Addr, TcpListener},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use actix_web::{
+    http::{header, StatusCode},
+    rt::time::sleep,
+    test,
+    web::{self, Bytes},
+    App, HttpServer,
+};
+use ohsw::{
+    api::invoke::{InvokeFunction, PayloadVia},
+    db::{
+        self,
+        models::{Instance, Request},
+        request_log::RequestLog,
+        status_writer::StatusWriter,
+    },
+    endpoints::{InvokeContext, NodeState},
+    execution_environment::{
+        environment::ExecutionEnvironment,
+        firecracker::FirecrackerBuilder,
+        guest::FunctionGuest,
+        mock::{GuestBehavior, MockExecutionEnvironment, MockTaps},
+        overlay::ImageMode,
+        simulate::SimulatedExecutionEnvironment,
+    },
+    net::{
+        addresses::Addresses,
+        control_plane::Announcer,
+        iggy::Phase,
+        registry::{self, HttpControlPlane, Registry},
+    },
+    orchestrator::{
+        global::{identity::Node, NeighborNodeStrategy},
+        Orchestrator,
+    },
+    utils::{
+        auth::{AdminToken, ClusterAuth},
+        compression::Compression,
+        diagnostics::Diagnostics,
+        idempotency::IdempotencyCache,
+        log_ring::LogRing,
+        quota::QuotaTracker,
+        spill::SpillConfig,
+        supervisor::Supervisor,
+    },
+};
+use sqlx::{Pool, Sqlite};
+
+/// Position of every node of the tests
+pub const POSITION: (f64, f64) = (45.4685, 9.1824);
+
+/// A node of the tests, with the mock environment its instances run in
+pub struct TestNode {
+    pub state: NodeState,
+    pub environment: MockExecutionEnvironment,
+    /// Directory of the files of the node, removed with it
+    workdir: PathBuf,
+}
+
+impl TestNode {
+    /// Create a node whose guests behave as `guest`, offloading to `neighbors`
+    pub async fn new(guest: GuestBehavior, neighbors: Vec<Node>) -> Self {
+        Self::with_identity(
+            guest,
+            Node::new("10.0.0.0:8085".to_string(), POSITION),
+            neighbors,
+        )
+        .await
+    }
+
+    /// Create a node as `new` does, known to the others as `identity`
+    pub async fn with_identity(guest: GuestBehavior, identity: Node, neighbors: Vec<Node>) -> Self {
+        Self::build(guest, identity, neighbors, vec![], None).await
+    }
+
+    /// Create a node as `new` does, talking to the guests of some functions as `guests`
+    pub async fn with_guests(guest: GuestBehavior, guests: Vec<FunctionGuest>) -> Self {
+        Self::build(
+            guest,
+            Node::new("10.0.0.0:8085".to_string(), POSITION),
+            vec![],
+            guests,
+            None,
+        )
+        .await
+    }
+
+    /// Create a node running the functions of the images on the host, as with
+    /// `--runtime simulate`: its mock environment is left unused
+    pub async fn simulated() -> Self {
+        Self::build(
+            GuestBehavior::Silent,
+            Node::new("10.0.0.0:8085".to_string(), POSITION),
+            vec![],
+            vec![],
+            Some(Arc::new(SimulatedExecutionEnvironment)),
+        )
+        .await
+    }
+
+    async fn build(
+        guest: GuestBehavior,
+        identity: Node,
+        neighbors: Vec<Node>,
+        guests: Vec<FunctionGuest>,
+        runtime: Option<Arc<dyn ExecutionEnvironment>>,
+    ) -> Self {
+        let environment = MockExecutionEnvironment::new(guest);
+        let runtime = runtime.unwrap_or_else(|| Arc::new(environment.clone()));
+        let workdir = std::env::temp_dir().join(format!("spare-node-{}", uuid::Uuid::new_v4()));
+        let builder = FirecrackerBuilder::new(
+            "firecracker".to_string(),
+            "kernel".to_string(),
+            "br0".to_string(),
+            Addresses::new(Ipv4Addr::new(10, 0, 0, 0), 24).unwrap(),
+        )
+        .with_image_mode(ImageMode::SharedRo, workdir.join("overlays"))
+        .with_tap_factory(Arc::new(MockTaps))
+        .with_execution_environment(runtime)
+        .with_function_guests(guests);
+        let orchestrator =
+            Orchestrator::with_strategy(neighbors, identity, NeighborNodeStrategy::GeoDistance)
+                .with_offload_traces(16);
+
+        let pool = db::connect("sqlite::memory:", false).await.unwrap();
+        let spill = SpillConfig::new(workdir.join("payloads"), 8 << 20, 50 << 20);
+        spill.prepare().unwrap();
+        // The announcements go to a registry of the node alone
+        let registry = registry::spawn_server(("127.0.0.1", 0), Registry::new(1)).unwrap();
+        let control_plane = HttpControlPlane::new(&format!("http://{registry}"), Phase::Broadcast);
+        let state = NodeState {
+            context: InvokeContext {
+                db_pool: pool.clone(),
+                firecracker_builder: web::Data::new(Arc::new(builder)),
+                orchestrator: Arc::new(orchestrator),
+                request_log: RequestLog::spawn(pool.clone()),
+                status_writer: StatusWriter::Direct(pool),
+                quotas: Arc::new(QuotaTracker::new()),
+            },
+            idempotency: Arc::new(IdempotencyCache::new(Default::default())),
+            admin_token: AdminToken(None),
+            cluster_auth: ClusterAuth::default(),
+            compression: Compression::default(),
+            spill,
+            announcer: Announcer::spawn(control_plane),
+            diagnostics: Diagnostics {
+                logs: LogRing::new(0),
+                config: String::new(),
+                preflight: String::new(),
+            },
+            supervisor: Arc::new(Supervisor::default()),
+        };
+        TestNode {
+            state,
+            environment,
+            workdir,
+        }
+    }
+
+    pub fn orchestrator(&self) -> &Orchestrator {
+        &self.state.context.orchestrator
+    }
+
+    pub fn pool(&self) -> &Pool<Sqlite> {
+        &self.state.context.db_pool
+    }
+
+    /// Send a request to /invoke of the node, in process
+    pub async fn invoke(&self, request: &InvokeFunction) -> (StatusCode, Bytes) {
+        let state = self.state.clone();
+        let app = test::init_service(App::new().configure(|cfg| state.configure(cfg))).await;
+        let request = test::TestRequest::post()
+            .uri("/invoke")
+            .set_json(request)
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        let status = response.status();
+        (status, test::read_body(response).await)
+    }
+
+    /// Send a GET request to the node, in process, with its admin token if it has one
+    pub async fn get(&self, uri: &str) -> (StatusCode, Bytes) {
+        let state = self.state.clone();
+        let app = test::init_service(App::new().configure(|cfg| state.configure(cfg))).await;
+        let mut request = test::TestRequest::get().uri(uri);
+        if let Some(token) = &self.state.admin_token.0 {
+            request = request.insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+        }
+        let response = test::call_service(&app, request.to_request()).await;
+        let status = response.status();
+        (status, test::read_body(response).await)
+    }
+
+    /// Serve the node on a free port of the loopback, returning its address
+    pub fn serve(&self) -> String {
+        let (listener, address) = bind();
+        self.serve_on(listener);
+        address
+    }
+
+    /// Serve the node on a listener bound beforehand, e.g. to give its address to the
+    /// nodes it is a neighbor of
+    pub fn serve_on(&self, listener: TcpListener) {
+        let state = self.state.clone();
+        let server = HttpServer::new(move || App::new().configure(|cfg| state.configure(cfg)))
+            .workers(1)
+            .listen(listener)
+            .unwrap();
+        actix_web::rt::spawn(server.run());
+    }
+
+    /// Instances started by the node
+    pub async fn instances(&self) -> Vec<Instance> {
+        Instance::list(self.pool()).await.unwrap()
+    }
+
+    /// Requests received by the node, waiting for the `expected` ones to be written
+    pub async fn requests(&self, expected: usize) -> Vec<Request> {
+        for _ in 0..100 {
+            let requests = Request::list(self.pool()).await.unwrap();
+            if requests.len() >= expected {
+                return requests;
+            }
+            sleep(Duration::from_millis(20)).await;
+        }
+        panic!("The requests were not written");
+    }
+
+    /// Take every cpu of the node, as if it were running other instances
+    pub fn exhaust(&self) -> usize {
+        let cpus = self.orchestrator().get_resources().cpus;
+        self.orchestrator()
+            .check_and_acquire_resources(cpus, 0, false)
+            .unwrap();
+        cpus
+    }
+}
+
+impl Drop for TestNode {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.workdir);
+    }
+}
+
+/// Bind a free port of the loopback, returning the listener and its address
+pub fn bind() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    (listener, address)
+}
+
+/// A request of one vcpu running on the mock guests
+pub fn request(payload: &str, emergency: bool) -> InvokeFunction {
+    InvokeFunction {
+        function: "echo".to_string(),
+        image: "echo.img".to_string(),
+        image_digest: None,
+        vcpus: 1,
+        memory: 128,
+        payload: Some(payload.to_string()),
+        emergency,
+        hops: 0,
+        payload_via: PayloadVia::Vsock,
+        rate_limits: None,
+        idempotency_key: None,
+        env: None,
+        args: None,
+        api_key: None,
+        compressible: None,
+        timeout_ms: None,
+        deadline: None,
+    }
+}
@@ -0,0 +1,156 @@
+//! Requests to /invoke through the whole node, with the instances run by the mock
+//! execution environment: admission, instance start, exchange with the guest, cleanup.
+mod common;
+
+use actix_web::http::StatusCode;
+use common::{request, TestNode, POSITION};
+use ohsw::{
+    execution_environment::{
+        guest::{FunctionGuest, GuestConfig, GuestMode},
+        mock::GuestBehavior,
+    },
+    orchestrator::global::{emergency::Emergency, identity::Node},
+};
+
+/// Status of the instances started by the node
+async fn statuses(node: &TestNode) -> Vec<String> {
+    node.instances()
+        .await
+        .into_iter()
+        .map(|instance| instance.status)
+        .collect()
+}
+
+#[actix_web::test]
+async fn test_served_locally() {
+    let node = TestNode::new(GuestBehavior::Echo, vec![]).await;
+    let cpus = node.orchestrator().get_resources().cpus;
+
+    let (status, body) = node.invoke(&request("hello", false)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "hello");
+    assert_eq!(node.environment.boots(), 1);
+    assert_eq!(statuses(&node).await, ["terminated"]);
+    // Everything was given back
+    assert_eq!(node.orchestrator().get_resources().cpus, cpus);
+}
+
+#[actix_web::test]
+async fn test_guest_ports() {
+    let guest = FunctionGuest {
+        function: "echo".to_string(),
+        config: GuestConfig {
+            mode: GuestMode::Vsock,
+            guest_port: 8080,
+            vsock_port: 4321,
+        },
+    };
+    let node = TestNode::with_guests(GuestBehavior::Echo, vec![guest]).await;
+
+    // The guest connects to the port given in its environment
+    let (status, body) = node.invoke(&request("hello", false)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "hello");
+    let instances = node.instances().await;
+    assert_eq!(instances.len(), 1);
+    assert_eq!(instances[0].port, 8080);
+}
+
+#[actix_web::test]
+async fn test_guest_timeout() {
+    let node = TestNode::new(GuestBehavior::Silent, vec![]).await;
+    let cpus = node.orchestrator().get_resources().cpus;
+
+    let (status, body) = node.invoke(&request("hello", false)).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body, "Failed to start instance, attempt 4 of 4\n");
+    // The first attempt and the three retries, each on a new instance
+    assert_eq!(node.environment.boots(), 4);
+    assert_eq!(statuses(&node).await, ["failed"; 4]);
+    let attempts: Vec<i64> = node
+        .instances()
+        .await
+        .iter()
+        .map(|instance| instance.attempt)
+        .collect();
+    assert_eq!(attempts, [1, 2, 3, 4]);
+    assert_eq!(node.orchestrator().get_resources().cpus, cpus);
+}
+
+#[actix_web::test]
+async fn test_image_digest_mismatch() {
+    let node = TestNode::new(GuestBehavior::Echo, vec![]).await;
+    let cpus = node.orchestrator().get_resources().cpus;
+    let image = std::env::temp_dir().join(format!("spare-digest-{}.img", std::process::id()));
+    std::fs::write(&image, "not the expected image").unwrap();
+    let mut request = request("hello", false);
+    request.image = image.display().to_string();
+    request.image_digest = Some(format!("sha256:{}", "0".repeat(64)));
+
+    // The image would not match on another attempt either
+    let (status, body) = node.invoke(&request).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body, "Failed to start instance, attempt 1 of 4\n");
+    assert_eq!(node.environment.boots(), 0);
+    assert!(node.instances().await.is_empty());
+    assert_eq!(node.orchestrator().get_resources().cpus, cpus);
+    std::fs::remove_file(&image).unwrap();
+}
+
+#[actix_web::test]
+async fn test_offload() {
+    let neighbor = TestNode::new(GuestBehavior::Echo, vec![]).await;
+    let address = neighbor.serve();
+    let node = TestNode::new(GuestBehavior::Echo, vec![Node::new(address, POSITION)]).await;
+    node.exhaust();
+
+    let (status, body) = node.invoke(&request("hello", false)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "hello");
+    // Run by the neighbor only
+    assert_eq!(node.environment.boots(), 0);
+    assert!(statuses(&node).await.is_empty());
+    assert_eq!(neighbor.environment.boots(), 1);
+    assert_eq!(statuses(&neighbor).await, ["terminated"]);
+}
+
+#[actix_web::test]
+async fn test_emergency_rejection() {
+    let node = TestNode::new(GuestBehavior::Echo, vec![]).await;
+    node.orchestrator().set_emergency(
+        true,
+        Emergency {
+            position: POSITION,
+            radius: 1000.0,
+            polygon: None,
+        },
+    );
+    assert!(node.orchestrator().in_emergency_area());
+
+    // Inside the zone, the other requests are sent away, and there is nowhere to send them
+    let (status, body) = node.invoke(&request("hello", false)).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body, "Insufficient resources\n");
+    assert_eq!(node.environment.boots(), 0);
+
+    // The emergency requests are still served
+    let (status, body) = node.invoke(&request("hello", true)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "hello");
+    assert_eq!(node.environment.boots(), 1);
+}
+
+#[actix_web::test]
+async fn test_resource_exhaustion() {
+    let node = TestNode::new(GuestBehavior::Echo, vec![]).await;
+    let cpus = node.exhaust();
+
+    let (status, body) = node.invoke(&request("hello", false)).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body, "Insufficient resources\n");
+    assert_eq!(node.environment.boots(), 0);
+    assert!(statuses(&node).await.is_empty());
+    // Nothing was acquired for the request
+    assert_eq!(node.orchestrator().get_resources().cpus, 0);
+    node.orchestrator().release_resources(cpus, 0).unwrap();
+}
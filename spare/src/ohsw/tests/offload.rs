@@ -0,0 +1,152 @@
+//! Offloading between two full nodes served on the loopback, each listed as the neighbor
+//! of the other: a request node A cannot take must be served by node B, one hop away.
+mod common;
+
+use actix_web::http::StatusCode;
+use common::{bind, request, TestNode};
+use ohsw::{
+    execution_environment::mock::GuestBehavior,
+    orchestrator::{
+        global::{emergency::Emergency, identity::Node},
+        trace::{AttemptOutcome, OffloadTrace, Probe},
+    },
+    utils::auth::AdminToken,
+};
+
+/// Position of node A, the center of the emergency zone
+const POSITION_A: (f64, f64) = (45.4685, 9.1824);
+
+/// Position of node B, far from the zone
+const POSITION_B: (f64, f64) = (48.8575, 2.3514);
+
+/// Why node A does not run the request itself
+#[derive(Debug, Clone, Copy)]
+enum Scenario {
+    /// Every cpu of node A is taken
+    Exhausted,
+    /// Node A is inside an emergency zone, and the request is not an emergency
+    EmergencyZone,
+}
+
+/// Start nodes A and B, each the only neighbor of the other
+async fn cluster() -> (TestNode, TestNode, String) {
+    let (listener_a, address_a) = bind();
+    let (listener_b, address_b) = bind();
+    let node_a = TestNode::with_identity(
+        GuestBehavior::Echo,
+        Node::new(address_a.clone(), POSITION_A),
+        vec![Node::new(address_b.clone(), POSITION_B)],
+    )
+    .await;
+    let node_b = TestNode::with_identity(
+        GuestBehavior::Echo,
+        Node::new(address_b.clone(), POSITION_B),
+        vec![Node::new(address_a, POSITION_A)],
+    )
+    .await;
+    node_a.serve_on(listener_a);
+    node_b.serve_on(listener_b);
+    (node_a, node_b, address_b)
+}
+
+async fn offloaded_to_b(scenario: Scenario) {
+    let (node_a, node_b, address_b) = cluster().await;
+    match scenario {
+        Scenario::Exhausted => {
+            node_a.exhaust();
+        }
+        Scenario::EmergencyZone => {
+            let zone = Emergency {
+                position: POSITION_A,
+                radius: 1000.0,
+                polygon: None,
+            };
+            node_a.orchestrator().set_emergency(true, zone.clone());
+            node_b.orchestrator().set_emergency(true, zone);
+            assert!(node_a.orchestrator().in_emergency_area());
+            assert!(!node_b.orchestrator().in_emergency_area());
+        }
+    }
+
+    let (status, body) = node_a.invoke(&request("hello", false)).await;
+    assert_eq!(status, StatusCode::OK, "{scenario:?}");
+    assert_eq!(body, "hello");
+
+    // Node A only forwarded the request
+    assert_eq!(node_a.environment.boots(), 0);
+    assert!(node_a.instances().await.is_empty());
+    let requests = node_a.requests(1).await;
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].outcome, "offloaded");
+    assert_eq!(
+        requests[0].offloaded_to.as_deref(),
+        Some(address_b.as_str())
+    );
+    assert_eq!(requests[0].hops, 0);
+
+    // Node B ran it, one hop away from the client
+    assert_eq!(node_b.environment.boots(), 1);
+    let instances = node_b.instances().await;
+    assert_eq!(instances.len(), 1);
+    assert_eq!(instances[0].status, "terminated");
+    assert_eq!(instances[0].hops, 1);
+    let requests = node_b.requests(1).await;
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].outcome, "served_locally");
+    assert_eq!(requests[0].hops, 1);
+}
+
+#[actix_web::test]
+async fn test_offload_exhausted() {
+    offloaded_to_b(Scenario::Exhausted).await;
+}
+
+#[actix_web::test]
+async fn test_offload_emergency_zone() {
+    offloaded_to_b(Scenario::EmergencyZone).await;
+}
+
+#[actix_web::test]
+async fn test_offload_trace() {
+    let (mut node_a, node_b, address_b) = cluster().await;
+    node_a.state.admin_token = AdminToken(Some("secret".to_string()));
+    node_a.exhaust();
+
+    let (status, _) = node_a.invoke(&request("hello", false)).await;
+    assert_eq!(status, StatusCode::OK);
+    // Node B cannot take the next one either
+    node_b.exhaust();
+    let (status, _) = node_a.invoke(&request("hello", false)).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+    let (status, body) = node_a.get("/debug/offloads").await;
+    assert_eq!(status, StatusCode::OK);
+    let traces: Vec<OffloadTrace> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(traces.len(), 2);
+    // Newest first
+    let (rejected, offloaded) = (&traces[0], &traces[1]);
+    assert_eq!(offloaded.chosen.as_deref(), Some(address_b.as_str()));
+    assert_eq!(offloaded.candidates.len(), 1);
+    assert_eq!(offloaded.candidates[0].address, address_b);
+    assert!(matches!(
+        offloaded.attempts[0].outcome,
+        AttemptOutcome::Forwarded { .. }
+    ));
+    assert_eq!(rejected.chosen, None);
+    assert_eq!(rejected.attempts[0].outcome, AttemptOutcome::Insufficient);
+    assert!(matches!(
+        rejected.attempts[0].probe,
+        Probe::Resources { cpus: 0, .. }
+    ));
+
+    // Each request is recorded with its trace
+    let requests = node_a.requests(2).await;
+    assert_eq!(requests[0].offload_trace_id, Some(offloaded.id as i64));
+    assert_eq!(requests[1].offload_trace_id, Some(rejected.id as i64));
+    // Node B served its request without offloading it
+    assert_eq!(node_b.requests(1).await[0].offload_trace_id, None);
+
+    let (_, body) = node_a.get("/debug/offloads?limit=1").await;
+    let traces: Vec<OffloadTrace> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(traces, vec![rejected.clone()]);
+}
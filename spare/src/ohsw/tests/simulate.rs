@@ -0,0 +1,163 @@
+//! Requests to /invoke through a node simulating its instances (`--runtime simulate`):
+//! the functions of the images run on the host, as an executable or a shared library.
+mod common;
+
+use std::{
+    collections::HashMap,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use actix_web::http::StatusCode;
+use common::{request, TestNode};
+use ohsw::api::invoke::{InvokeFunction, PayloadVia};
+
+/// Shared library upper-casing the payload, refusing the payloads that are not UTF-8
+const LIBRARY: &str = r#"
+#[no_mangle]
+pub unsafe extern "C" fn spare_handler(
+    payload: *const u8,
+    len: usize,
+    out: *mut *mut u8,
+    out_len: *mut usize,
+) -> i32 {
+    let payload = std::slice::from_raw_parts(payload, len);
+    let (code, output) = match std::str::from_utf8(payload) {
+        Ok(payload) => (0, payload.to_uppercase().into_bytes()),
+        Err(_) => (1, b"not utf-8".to_vec()),
+    };
+    let output = output.into_boxed_slice();
+    *out_len = output.len();
+    *out = Box::into_raw(output) as *mut u8;
+    code
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn spare_free(out: *mut u8, len: usize) {
+    drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(out, len)));
+}
+"#;
+
+/// Directory of the images of a test
+fn images() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("spare-images-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Write an executable image running `body` with sh
+fn script(dir: &Path, name: &str, body: &str) -> String {
+    let path = dir.join(name);
+    fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path.to_string_lossy().to_string()
+}
+
+/// A request of `function` to the image at `image`
+fn invoke(image: &str, payload: &str) -> InvokeFunction {
+    InvokeFunction {
+        function: "resize".to_string(),
+        image: image.to_string(),
+        ..request(payload, false)
+    }
+}
+
+#[actix_web::test]
+async fn test_executable() {
+    let node = TestNode::simulated().await;
+    let cpus = node.orchestrator().get_resources().cpus;
+    let dir = images();
+    let image = script(&dir, "greet", "printf '%s %s ' \"$GREETING\" \"$1\"\ncat");
+
+    let mut request = invoke(&image, "world");
+    request.env = Some(HashMap::from([(
+        "GREETING".to_string(),
+        "hello there".to_string(),
+    )]));
+    request.args = Some(vec!["big".to_string()]);
+    let (status, body) = node.invoke(&request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "hello there big world");
+    // The instance was accounted as any other
+    assert_eq!(node.instances().await[0].status, "terminated");
+    assert_eq!(node.orchestrator().get_resources().cpus, cpus);
+    // Nothing was booted
+    assert_eq!(node.environment.boots(), 0);
+
+    // The payload delivered through MMDS reaches the function too
+    let mut request = invoke(&image, "world");
+    request.payload_via = PayloadVia::Mmds;
+    let (status, body) = node.invoke(&request).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "  world");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[actix_web::test]
+async fn test_executable_failure() {
+    let node = TestNode::simulated().await;
+    let cpus = node.orchestrator().get_resources().cpus;
+    let dir = images();
+    let image = script(&dir, "fail", "echo 'no image' >&2\nexit 3");
+
+    // The failure is the error of the function, without retries
+    let (status, body) = node.invoke(&invoke(&image, "hello")).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    let body = String::from_utf8(body.to_vec()).unwrap();
+    assert!(
+        body.ends_with("exited with exit status: 3: no image"),
+        "{body}"
+    );
+    assert_eq!(node.instances().await.len(), 1);
+    assert_eq!(node.orchestrator().get_resources().cpus, cpus);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[actix_web::test]
+async fn test_echo() {
+    // A nanos image has nothing to run on the host: it answers with the payload
+    let node = TestNode::simulated().await;
+    let dir = images();
+    let image = dir.join("resize.img");
+    fs::write(&image, b"not an executable").unwrap();
+
+    let (status, body) = node
+        .invoke(&invoke(&image.to_string_lossy(), "hello"))
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "hello");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[actix_web::test]
+async fn test_library() {
+    let node = TestNode::simulated().await;
+    let dir = images();
+    let source = dir.join("upper.rs");
+    fs::write(&source, LIBRARY).unwrap();
+    let library = dir.join("libupper.so");
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let output = Command::new(rustc)
+        .args(["--edition", "2021", "--crate-type", "cdylib", "-o"])
+        .arg(&library)
+        .arg(&source)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let image = library.to_string_lossy();
+
+    let (status, body) = node.invoke(&invoke(&image, "hello")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "HELLO");
+    // The library is loaded again by the next request
+    let (status, body) = node.invoke(&invoke(&image, "world")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, "WORLD");
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,162 @@
+//! Client of the HTTP APIs of the nodes and of the registry.
+use std::{fs::File, io::Write, path::Path, time::Duration};
+
+use actix_web::{
+    http::{header, StatusCode},
+    web::Bytes,
+};
+use awc::{Client, ClientRequest};
+use futures::StreamExt;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::AdminError;
+
+/// Largest body of an answer read in memory, the exports are written as they come
+const BODY_LIMIT: usize = 64 << 20;
+
+/// Client of the nodes of a cluster, sending the admin token with every request
+#[derive(Clone)]
+pub struct NodeClient {
+    client: Client,
+    token: Option<String>,
+}
+
+impl NodeClient {
+    /// Create a client waiting at most `timeout` for each answer
+    pub fn new(token: Option<String>, timeout: Duration) -> Self {
+        Self {
+            client: Client::builder().timeout(timeout).finish(),
+            token,
+        }
+    }
+
+    /// URL of a path of a node, or of the registry
+    fn url(target: &str, path: &str) -> String {
+        match target.contains("://") {
+            true => format!("{}{}", target.trim_end_matches('/'), path),
+            false => format!("http://{}{}", target, path),
+        }
+    }
+
+    fn request(&self, request: ClientRequest) -> ClientRequest {
+        match &self.token {
+            Some(token) => {
+                request.insert_header((header::AUTHORIZATION, format!("Bearer {token}")))
+            }
+            None => request,
+        }
+    }
+
+    /// Send a GET request, returning the status and the body of the answer
+    pub async fn get(&self, target: &str, path: &str) -> Result<(StatusCode, Bytes), AdminError> {
+        let request = self.request(self.client.get(Self::url(target, path)));
+        let response = request.send().await;
+        read(target, response).await
+    }
+
+    /// Send a POST request with a JSON body, None for an empty one
+    pub async fn post(
+        &self,
+        target: &str,
+        path: &str,
+        body: Option<&impl Serialize>,
+    ) -> Result<(StatusCode, Bytes), AdminError> {
+        let request = self.request(self.client.post(Self::url(target, path)));
+        let response = match body {
+            Some(body) => request.send_json(body).await,
+            None => request.send().await,
+        };
+        read(target, response).await
+    }
+
+    /// Send a GET request and decode its answer, refusing the errors of the node
+    pub async fn get_json<T: DeserializeOwned>(
+        &self,
+        target: &str,
+        path: &str,
+    ) -> Result<T, AdminError> {
+        let (status, body) = self.get(target, path).await?;
+        decode(target, status, &body)
+    }
+
+    /// Send a POST request and decode its answer, refusing the errors of the node
+    pub async fn post_json<T: DeserializeOwned>(
+        &self,
+        target: &str,
+        path: &str,
+        body: Option<&impl Serialize>,
+    ) -> Result<T, AdminError> {
+        let (status, answer) = self.post(target, path, body).await?;
+        decode(target, status, &answer)
+    }
+
+    /// Write the answer of a GET request to a file as it comes, returning its size
+    pub async fn download(&self, target: &str, path: &str, file: &Path) -> Result<u64, AdminError> {
+        let request = self.request(self.client.get(Self::url(target, path)));
+        let mut response = request.send().await.map_err(|e| unreachable(target, e))?;
+        if !response.status().is_success() {
+            let body = response.body().limit(BODY_LIMIT).await.unwrap_or_default();
+            return Err(status_error(target, response.status(), &body));
+        }
+        let write = |source| AdminError::Write {
+            path: file.to_path_buf(),
+            source,
+        };
+        let mut out = File::create(file).map_err(write)?;
+        let mut size = 0;
+        while let Some(chunk) = response.next().await {
+            let chunk = chunk.map_err(|e| unreachable(target, e))?;
+            out.write_all(&chunk).map_err(write)?;
+            size += chunk.len() as u64;
+        }
+        Ok(size)
+    }
+}
+
+/// Read the whole answer of a node
+async fn read<S>(
+    target: &str,
+    response: Result<awc::ClientResponse<S>, awc::error::SendRequestError>,
+) -> Result<(StatusCode, Bytes), AdminError>
+where
+    S: futures::Stream<Item = Result<Bytes, awc::error::PayloadError>> + Unpin,
+{
+    let mut response = response.map_err(|e| unreachable(target, e))?;
+    let body = response
+        .body()
+        .limit(BODY_LIMIT)
+        .await
+        .map_err(|e| unreachable(target, e))?;
+    Ok((response.status(), body))
+}
+
+/// Decode the answer of a node, an error if it is not a success
+fn decode<T: DeserializeOwned>(
+    target: &str,
+    status: StatusCode,
+    body: &[u8],
+) -> Result<T, AdminError> {
+    if !status.is_success() {
+        return Err(status_error(target, status, body));
+    }
+    serde_json::from_slice(body).map_err(|e| AdminError::Body {
+        node: target.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+fn unreachable(target: &str, e: impl std::fmt::Display) -> AdminError {
+    AdminError::Unreachable {
+        node: target.to_string(),
+        reason: e.to_string(),
+    }
+}
+
+/// Error of an answer that is not a success
+pub fn status_error(target: &str, status: StatusCode, body: &[u8]) -> AdminError {
+    AdminError::Status {
+        node: target.to_string(),
+        status: status.as_u16(),
+        body: String::from_utf8_lossy(body).trim().to_string(),
+    }
+}
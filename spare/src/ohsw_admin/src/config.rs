@@ -0,0 +1,163 @@
+//! Configuration of the cluster administered: the addresses of its nodes, the token of
+//! their administrative endpoints, the registry the emergencies are published to and the
+//! functions registered with `functions register`.
+use std::{collections::BTreeMap, fs, path::Path};
+
+use ohsw::net::node_address;
+use serde::{Deserialize, Serialize};
+
+use crate::AdminError;
+
+/// Configuration of a cluster, read from a JSON file, e.g.
+/// `{"nodes": ["10.0.0.1:8085", "10.0.0.2:8085"], "admin_token": "secret",
+/// "registry": "http://10.0.0.10:8090"}`
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ClusterConfig {
+    /// Addresses of the nodes, as they announce themselves
+    pub nodes: Vec<String>,
+    /// Token of the administrative endpoints of the nodes, sent to every node
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_token: Option<String>,
+    /// URL of the HTTP registry of the cluster, None if it uses the Iggy broker
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    /// Functions invoked by their name, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub functions: BTreeMap<String, Function>,
+}
+
+/// A function registered in the configuration
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Function {
+    /// Image of the function: a path on the nodes, a file:// or an http(s):// URL
+    pub image: String,
+    /// Expected SHA-256 digest of the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_digest: Option<String>,
+    /// Virtual CPUs of the instances, 0 for the default of the node
+    #[serde(default)]
+    pub vcpus: i32,
+    /// Memory of the instances (in MiB), 0 for the default of the node
+    #[serde(default)]
+    pub memory: i32,
+}
+
+impl ClusterConfig {
+    /// Read the configuration, the addresses of the nodes in their canonical form
+    pub fn load(path: &Path) -> Result<Self, AdminError> {
+        let raw = fs::read_to_string(path).map_err(|source| AdminError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let invalid = |reason: String| AdminError::Config {
+            path: path.to_path_buf(),
+            reason,
+        };
+        let mut config: ClusterConfig =
+            serde_json::from_str(&raw).map_err(|e| invalid(e.to_string()))?;
+        if config.nodes.is_empty() {
+            return Err(invalid("No nodes".to_string()));
+        }
+        for node in config.nodes.iter_mut() {
+            *node = node_address::validate(node)
+                .map_err(|e| invalid(format!("Invalid node {}: {}", node, e)))?;
+        }
+        for (i, node) in config.nodes.iter().enumerate() {
+            if config.nodes[..i].contains(node) {
+                return Err(invalid(format!("Node {} is listed twice", node)));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Write the configuration back, replacing the file at once
+    pub fn save(&self, path: &Path) -> Result<(), AdminError> {
+        let write = |source| AdminError::Write {
+            path: path.to_path_buf(),
+            source,
+        };
+        let tmp = path.with_extension("json.tmp");
+        let mut raw = serde_json::to_string_pretty(self).unwrap();
+        raw.push('\n');
+        fs::write(&tmp, raw).map_err(write)?;
+        fs::rename(&tmp, path).map_err(write)
+    }
+
+    /// Get the nodes a command is sent to: every node, the one asked for or the first one
+    pub fn targets(&self, node: Option<&str>, all: bool) -> Result<Vec<String>, AdminError> {
+        match (node, all) {
+            (_, true) => Ok(self.nodes.clone()),
+            (Some(node), false) => node_address::validate(node)
+                .map(|node| vec![node])
+                .map_err(|e| AdminError::Invalid(format!("Invalid node {}: {}", node, e))),
+            (None, false) => Ok(vec![self.nodes[0].clone()]),
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(raw: &str) -> Result<ClusterConfig, AdminError> {
+        let path = std::env::temp_dir().join(format!("cluster-{}.json", uuid::Uuid::new_v4()));
+        fs::write(&path, raw).unwrap();
+        let config = ClusterConfig::load(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn test_load() {
+        let config =
+            load(r#"{"nodes": ["Edge-1:8085", "10.0.0.2:8085"], "admin_token": "secret"}"#)
+                .unwrap();
+        assert_eq!(config.nodes, vec!["edge-1:8085", "10.0.0.2:8085"]);
+        assert_eq!(config.admin_token.as_deref(), Some("secret"));
+        assert!(config.registry.is_none() && config.functions.is_empty());
+        assert_eq!(
+            config.targets(None, false).unwrap(),
+            vec!["edge-1:8085".to_string()]
+        );
+        assert_eq!(config.targets(Some("x:1"), true).unwrap().len(), 2);
+        assert_eq!(
+            config.targets(Some("10.0.0.9:8085"), false).unwrap(),
+            vec!["10.0.0.9:8085".to_string()]
+        );
+
+        for (raw, reason) in [
+            (r#"{"nodes": []}"#, "No nodes"),
+            (r#"{"nodes": ["10.0.0.1"]}"#, "Invalid node 10.0.0.1"),
+            (
+                r#"{"nodes": ["10.0.0.1:8085", "10.0.0.1:8085"]}"#,
+                "listed twice",
+            ),
+            (r#"{"node": ["10.0.0.1:8085"]}"#, "missing field `nodes`"),
+        ] {
+            let e = load(raw).err().unwrap().to_string();
+            assert!(e.contains(reason), "{}: {}", raw, e);
+        }
+    }
+
+    #[test]
+    fn test_save() {
+        let path = std::env::temp_dir().join(format!("cluster-{}.json", uuid::Uuid::new_v4()));
+        let mut config = ClusterConfig {
+            nodes: vec!["10.0.0.1:8085".to_string()],
+            ..Default::default()
+        };
+        config.functions.insert(
+            "resize".to_string(),
+            Function {
+                image: "resize.img".to_string(),
+                image_digest: None,
+                vcpus: 2,
+                memory: 256,
+            },
+        );
+        config.save(&path).unwrap();
+        assert_eq!(ClusterConfig::load(&path).unwrap(), config);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,766 @@
+//! Administration of a cluster of SPARE nodes through their HTTP APIs.
+//! The nodes are listed in a cluster configuration, with the token of their administrative
+//! endpoints. The commands sent to several nodes are sent to all of them at once, and
+//! their answers aggregated; the emergencies are published to the registry of the cluster.
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
+use futures::future::join_all;
+use ohsw::{
+    api::{
+        health::Health,
+        invoke::{InvokeFunction, PayloadVia},
+        resources::Resources,
+    },
+    db::{export::ExportFormat, window::parse_utc, BucketStats},
+    net::iggy::{Message, Operation, Payload},
+    orchestrator::global::emergency::Emergency,
+};
+use serde_json::json;
+
+pub mod client;
+pub mod config;
+pub mod output;
+pub mod stats;
+
+use client::{status_error, NodeClient};
+use config::{ClusterConfig, Function};
+use output::{Failures, Format, Report, Table};
+
+/// Error of a command
+#[derive(Debug, thiserror::Error)]
+pub enum AdminError {
+    #[error("Cannot read {path}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Cannot write {path}: {source}")]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid cluster configuration {path}: {reason}")]
+    Config { path: PathBuf, reason: String },
+    #[error("{node} is unreachable: {reason}")]
+    Unreachable { node: String, reason: String },
+    #[error("{node} answered {status}: {body}")]
+    Status {
+        node: String,
+        status: u16,
+        body: String,
+    },
+    #[error("{node} answered with an unexpected body: {reason}")]
+    Body { node: String, reason: String },
+    #[error("Unknown function {0}, give its --image or register it")]
+    UnknownFunction(String),
+    #[error("The cluster configuration has no registry, the emergencies are published to it")]
+    NoRegistry,
+    #[error("{0}")]
+    Invalid(String),
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "ohsw-admin",
+    version,
+    about = "Administer a cluster of SPARE nodes"
+)]
+pub struct Cli {
+    /// JSON file listing the nodes of the cluster, the token of their administrative
+    /// endpoints, the registry and the registered functions
+    #[arg(long, default_value = "cluster.json")]
+    pub config: PathBuf,
+    /// Format of the output
+    #[arg(long, value_enum, default_value_t = Format::Table)]
+    pub output: Format,
+    /// Longest wait for the answer of a node (in ms)
+    #[arg(long, default_value = "30000")]
+    pub timeout: u64,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// The nodes of the cluster
+    #[command(subcommand)]
+    Nodes(NodesCommand),
+    /// Invoke a function on a node and print its output
+    Invoke(InvokeArgs),
+    /// The functions invoked by their name
+    #[command(subcommand)]
+    Functions(FunctionsCommand),
+    /// Start or stop an emergency on every node, through the registry
+    #[command(subcommand)]
+    Emergency(EmergencyCommand),
+    /// Drain nodes before a maintenance: they offload the new requests but for the
+    /// emergency ones
+    Drain {
+        #[command(flatten)]
+        target: Target,
+        /// Answer once the last local instance terminated
+        #[arg(long)]
+        wait: bool,
+    },
+    /// Take new requests again after a drain
+    Undrain {
+        #[command(flatten)]
+        target: Target,
+    },
+    /// Stats of the requests and instances of nodes over a window, in buckets; summed
+    /// over the nodes with --all
+    Stats {
+        #[command(flatten)]
+        target: Target,
+        #[command(flatten)]
+        window: StatsWindow,
+        /// Length of a bucket, e.g. 60, 5m or 1h; the whole window by default
+        #[arg(long)]
+        bucket: Option<String>,
+    },
+    /// Export the instances of nodes, one file per node named after its address
+    Export {
+        #[command(flatten)]
+        target: Target,
+        /// Directory of the files
+        #[arg(long, default_value = ".")]
+        dir: PathBuf,
+        /// Format of the files: csv or ndjson
+        #[arg(long, default_value = "ndjson")]
+        format: String,
+        /// Only the instances created from this time, e.g. 2026-01-01T00:00:00
+        #[arg(long)]
+        since: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum NodesCommand {
+    /// List the nodes with their state, whether they are in the emergency area and the
+    /// resources they advertise
+    List,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum FunctionsCommand {
+    /// Register a function in the cluster configuration, to invoke it by its name
+    Register {
+        name: String,
+        /// Image of the function: a path on the nodes, a file:// or an http(s):// URL
+        #[arg(long)]
+        image: String,
+        /// Expected SHA-256 digest of the image
+        #[arg(long)]
+        image_digest: Option<String>,
+        /// Virtual CPUs of the instances, 0 for the default of the node
+        #[arg(long, default_value = "0")]
+        vcpus: i32,
+        /// Memory of the instances (in MiB), 0 for the default of the node
+        #[arg(long, default_value = "0")]
+        memory: i32,
+    },
+    /// List the registered functions
+    List {
+        /// Also ask every node whether it has the image of each function
+        #[arg(long)]
+        check: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum EmergencyCommand {
+    /// Start an emergency in a circle or a polygon
+    Start {
+        /// Center of the circle, e.g. 45.4685,9.1824
+        #[arg(long, requires = "radius", conflicts_with = "polygon")]
+        position: Option<String>,
+        /// Radius of the circle (in meters)
+        #[arg(long, requires = "position")]
+        radius: Option<f64>,
+        /// JSON file with the vertices of the zone, e.g. [[45.46, 9.18], [45.46, 9.19],
+        /// [45.47, 9.19]]
+        #[arg(long)]
+        polygon: Option<PathBuf>,
+    },
+    /// Stop the emergency
+    Stop,
+}
+
+/// Arguments of the invoke command
+#[derive(Args, Debug)]
+pub struct InvokeArgs {
+    /// Name of the function, registered or not
+    pub function: String,
+    /// Node the request is sent to, the first one of the configuration by default
+    #[arg(long)]
+    pub node: Option<String>,
+    /// Image of the function, instead of the registered one
+    #[arg(long)]
+    pub image: Option<String>,
+    /// Virtual CPUs of the instance, instead of the registered ones
+    #[arg(long)]
+    pub vcpus: Option<i32>,
+    /// Memory of the instance (in MiB), instead of the registered one
+    #[arg(long)]
+    pub memory: Option<i32>,
+    /// Payload of the request
+    #[arg(long)]
+    pub payload: Option<String>,
+    /// Send the request as an emergency one
+    #[arg(long)]
+    pub emergency: bool,
+}
+
+/// Nodes a command is sent to
+#[derive(Args, Debug)]
+pub struct Target {
+    /// Node the command is sent to, the first one of the configuration by default
+    #[arg(long, conflicts_with = "all")]
+    pub node: Option<String>,
+    /// Send the command to every node of the configuration, at once
+    #[arg(long)]
+    pub all: bool,
+}
+
+/// Window of the stats
+#[derive(Args, Debug)]
+pub struct StatsWindow {
+    /// Length of the window ending now (in seconds)
+    #[arg(long, default_value = "600", conflicts_with_all = ["start", "end"])]
+    pub last: i64,
+    /// Start of the window, in RFC 3339
+    #[arg(long, requires = "end")]
+    pub start: Option<String>,
+    /// End of the window, in RFC 3339
+    #[arg(long, requires = "start")]
+    pub end: Option<String>,
+}
+
+/// Run a command against the cluster of the configuration
+pub async fn run(cli: &Cli) -> Result<Report, AdminError> {
+    let mut config = ClusterConfig::load(&cli.config)?;
+    let client = NodeClient::new(
+        config.admin_token.clone(),
+        Duration::from_millis(cli.timeout),
+    );
+    match &cli.command {
+        Command::Nodes(NodesCommand::List) => list_nodes(&client, &config).await,
+        Command::Invoke(args) => invoke(&client, &config, args).await,
+        Command::Functions(FunctionsCommand::Register {
+            name,
+            image,
+            image_digest,
+            vcpus,
+            memory,
+        }) => {
+            let function = Function {
+                image: image.clone(),
+                image_digest: image_digest.clone(),
+                vcpus: *vcpus,
+                memory: *memory,
+            };
+            let replaced = config.functions.insert(name.clone(), function.clone());
+            config.save(&cli.config)?;
+            let action = match replaced {
+                Some(_) => "Updated",
+                None => "Registered",
+            };
+            Ok(Report::new(
+                format!("{} {}\n", action, name),
+                json!({ "name": name, "function": function }),
+            ))
+        }
+        Command::Functions(FunctionsCommand::List { check }) => {
+            list_functions(&client, &config, *check).await
+        }
+        Command::Emergency(command) => emergency(&client, &config, command).await,
+        Command::Drain { target, wait } => {
+            let path = match wait {
+                true => "/drain?wait=true",
+                false => "/drain",
+            };
+            drain(&client, &config, target, path).await
+        }
+        Command::Undrain { target } => drain(&client, &config, target, "/undrain").await,
+        Command::Stats {
+            target,
+            window,
+            bucket,
+        } => stats(&client, &config, target, window, bucket.as_deref()).await,
+        Command::Export {
+            target,
+            dir,
+            format,
+            since,
+        } => export(&client, &config, target, dir, format, since.as_deref()).await,
+    }
+}
+
+/// Run `task` on every node at once, keeping the order of the nodes
+async fn fan_out<T, F, Fut>(nodes: &[String], task: F) -> Vec<(String, Result<T, AdminError>)>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<T, AdminError>>,
+{
+    join_all(nodes.iter().map(|node| {
+        let answer = task(node.clone());
+        async move { (node.clone(), answer.await) }
+    }))
+    .await
+}
+
+/// Encode the value of a parameter of a query
+fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Check the answers of the nodes, returning the ones that succeeded and the failures
+fn split<T>(answers: Vec<(String, Result<T, AdminError>)>) -> (Vec<(String, T)>, Failures) {
+    let mut succeeded = Vec::new();
+    let mut failures = Vec::new();
+    for (node, answer) in answers {
+        match answer {
+            Ok(answer) => succeeded.push((node, answer)),
+            Err(e) => failures.push((node, e.to_string())),
+        }
+    }
+    (succeeded, failures)
+}
+
+async fn list_nodes(client: &NodeClient, config: &ClusterConfig) -> Result<Report, AdminError> {
+    let answers = fan_out(&config.nodes, |node| async move {
+        let health = client.get_json::<Health>(&node, "/healthz").await?;
+        let resources = client.get_json::<Resources>(&node, "/resources").await?;
+        Ok((health, resources))
+    })
+    .await;
+    let mut table = Table::new(vec![
+        "NODE",
+        "PHASE",
+        "STATE",
+        "EMERGENCY",
+        "INSTANCES",
+        "CPUS",
+        "MEMORY",
+        "DEGRADED",
+    ]);
+    let mut nodes = Vec::new();
+    let mut failures = Vec::new();
+    for (node, answer) in answers {
+        match answer {
+            Ok((health, resources)) => {
+                table.row(vec![
+                    node.clone(),
+                    health.phase.to_string(),
+                    json!(health.state).as_str().unwrap_or_default().to_string(),
+                    health.emergency.to_string(),
+                    health.running_instances.to_string(),
+                    format!("{}/{}", resources.cpus, resources.total_cpus),
+                    format!("{}/{}", resources.memory, resources.total_memory),
+                    health.degraded.to_string(),
+                ]);
+                nodes.push(json!({ "node": node, "health": health, "resources": resources }));
+            }
+            Err(e) => {
+                let mut row = vec![node.clone(), "unreachable".to_string()];
+                row.resize(8, String::new());
+                table.row(row);
+                nodes.push(json!({ "node": node, "error": e.to_string() }));
+                failures.push((node, e.to_string()));
+            }
+        }
+    }
+    let mut report = Report::new(table.render(), json!(nodes));
+    report.failures = failures;
+    Ok(report)
+}
+
+async fn invoke(
+    client: &NodeClient,
+    config: &ClusterConfig,
+    args: &InvokeArgs,
+) -> Result<Report, AdminError> {
+    let registered = config.functions.get(&args.function);
+    let image = match (&args.image, registered) {
+        (Some(image), _) => image.clone(),
+        (None, Some(function)) => function.image.clone(),
+        (None, None) => return Err(AdminError::UnknownFunction(args.function.clone())),
+    };
+    let request = InvokeFunction {
+        function: args.function.clone(),
+        image,
+        image_digest: registered.and_then(|function| function.image_digest.clone()),
+        vcpus: args
+            .vcpus
+            .or(registered.map(|function| function.vcpus))
+            .unwrap_or_default(),
+        memory: args
+            .memory
+            .or(registered.map(|function| function.memory))
+            .unwrap_or_default(),
+        payload: args.payload.clone(),
+        emergency: args.emergency,
+        hops: 0,
+        payload_via: PayloadVia::default(),
+        rate_limits: None,
+        idempotency_key: None,
+        env: None,
+        args: None,
+        api_key: None,
+        compressible: None,
+        timeout_ms: None,
+        deadline: None,
+    };
+    let node = config.targets(args.node.as_deref(), false)?.remove(0);
+    let (status, body) = client.post(&node, "/invoke", Some(&request)).await?;
+    if !status.is_success() {
+        return Err(status_error(&node, status, &body));
+    }
+    let body = String::from_utf8_lossy(&body).to_string();
+    let mut text = body.clone();
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+    Ok(Report::new(
+        text,
+        json!({ "node": node, "status": status.as_u16(), "body": body }),
+    ))
+}
+
+async fn list_functions(
+    client: &NodeClient,
+    config: &ClusterConfig,
+    check: bool,
+) -> Result<Report, AdminError> {
+    let mut headers = vec!["NAME", "IMAGE", "VCPUS", "MEMORY"];
+    if check {
+        headers.push("NODES");
+    }
+    let mut table = Table::new(headers);
+    let mut functions = Vec::new();
+    let mut failures = Vec::new();
+    for (name, function) in &config.functions {
+        let mut row = vec![
+            name.clone(),
+            function.image.clone(),
+            function.vcpus.to_string(),
+            function.memory.to_string(),
+        ];
+        let mut entry = json!({ "name": name, "function": function });
+        if check {
+            let mut path = format!("/resources?image={}", encode(&function.image));
+            if let Some(digest) = &function.image_digest {
+                path += &format!("&image_digest={}", encode(digest));
+            }
+            let answers = fan_out(&config.nodes, |node| {
+                let path = path.clone();
+                async move { client.get_json::<Resources>(&node, &path).await }
+            })
+            .await;
+            let (answered, failed) = split(answers);
+            let with_image: Vec<&String> = answered
+                .iter()
+                .filter(|(_, resources)| resources.has_image == Some(true))
+                .map(|(node, _)| node)
+                .collect();
+            row.push(format!("{}/{}", with_image.len(), config.nodes.len()));
+            entry["nodes"] = json!(with_image);
+            failures.extend(failed);
+        }
+        table.row(row);
+        functions.push(entry);
+    }
+    // A node failing for every function is reported once
+    failures.sort();
+    failures.dedup_by(|a, b| a.0 == b.0);
+    let mut report = Report::new(table.render(), json!(functions));
+    report.failures = failures;
+    Ok(report)
+}
+
+/// Parse a position given as two coordinates, e.g. 45.4685,9.1824
+fn parse_position(position: &str) -> Result<(f64, f64), AdminError> {
+    let invalid = || AdminError::Invalid(format!("Invalid position: {}", position));
+    let (a, b) = position.split_once(',').ok_or_else(invalid)?;
+    let a: f64 = a.trim().parse().map_err(|_| invalid())?;
+    let b: f64 = b.trim().parse().map_err(|_| invalid())?;
+    match a.is_finite() && b.is_finite() {
+        true => Ok((a, b)),
+        false => Err(invalid()),
+    }
+}
+
+async fn emergency(
+    client: &NodeClient,
+    config: &ClusterConfig,
+    command: &EmergencyCommand,
+) -> Result<Report, AdminError> {
+    let registry = config.registry.as_deref().ok_or(AdminError::NoRegistry)?;
+    let message = match command {
+        EmergencyCommand::Start {
+            position,
+            radius,
+            polygon,
+        } => {
+            let zone = match (position, radius, polygon) {
+                (_, _, Some(path)) => {
+                    let raw = std::fs::read_to_string(path).map_err(|source| AdminError::Read {
+                        path: path.clone(),
+                        source,
+                    })?;
+                    let vertices = serde_json::from_str(&raw).map_err(|e| {
+                        AdminError::Invalid(format!("Invalid polygon {}: {}", path.display(), e))
+                    })?;
+                    // Refused here rather than by every node
+                    Emergency::polygon(vertices).map_err(AdminError::Invalid)?
+                }
+                (Some(position), Some(radius), None) if *radius > 0.0 => Emergency {
+                    position: parse_position(position)?,
+                    radius: *radius,
+                    polygon: None,
+                },
+                _ => {
+                    return Err(AdminError::Invalid(
+                        "Give the --position and a positive --radius, or a --polygon".to_string(),
+                    ))
+                }
+            };
+            Message::new(Operation::START_EMERGENCY, Some(Payload::Emergency(zone)))
+        }
+        EmergencyCommand::Stop => Message::new(Operation::STOP_EMERGENCY, None),
+    };
+    let published: serde_json::Value = client
+        .post_json(registry, "/registry/events", Some(&message))
+        .await?;
+    let text = match command {
+        EmergencyCommand::Start { .. } => "Emergency started\n",
+        EmergencyCommand::Stop => "Emergency stopped\n",
+    };
+    Ok(Report::new(
+        text.to_string(),
+        json!({ "message": message, "index": published["index"] }),
+    ))
+}
+
+async fn drain(
+    client: &NodeClient,
+    config: &ClusterConfig,
+    target: &Target,
+    path: &str,
+) -> Result<Report, AdminError> {
+    let nodes = config.targets(target.node.as_deref(), target.all)?;
+    let answers = fan_out(&nodes, |node| async move {
+        client.post_json::<Health>(&node, path, None::<&()>).await
+    })
+    .await;
+    let (answered, failures) = split(answers);
+    let mut table = Table::new(vec!["NODE", "STATE", "INSTANCES"]);
+    for (node, health) in &answered {
+        table.row(vec![
+            node.clone(),
+            json!(health.state).as_str().unwrap_or_default().to_string(),
+            health.running_instances.to_string(),
+        ]);
+    }
+    let json = answered
+        .iter()
+        .map(|(node, health)| json!({ "node": node, "health": health }))
+        .collect::<Vec<_>>();
+    let mut report = Report::new(table.render(), json!(json));
+    report.failures = failures;
+    Ok(report)
+}
+
+async fn stats(
+    client: &NodeClient,
+    config: &ClusterConfig,
+    target: &Target,
+    window: &StatsWindow,
+    bucket: Option<&str>,
+) -> Result<Report, AdminError> {
+    // Every node is asked for the same window, so their buckets line up
+    let (start, end) = match (&window.start, &window.end) {
+        (Some(start), Some(end)) => (
+            parse_utc(start).map_err(|e| AdminError::Invalid(e.to_string()))?,
+            parse_utc(end).map_err(|e| AdminError::Invalid(e.to_string()))?,
+        ),
+        _ if window.last > 0 => {
+            let end = chrono::Utc::now();
+            (end - chrono::Duration::seconds(window.last), end)
+        }
+        _ => {
+            return Err(AdminError::Invalid(format!(
+                "Invalid last: {}",
+                window.last
+            )))
+        }
+    };
+    let mut path = format!(
+        "/stats?start={}&end={}",
+        encode(&start.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)),
+        encode(&end.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+    );
+    if let Some(bucket) = bucket {
+        path += &format!("&bucket={}", encode(bucket));
+    }
+    let nodes = config.targets(target.node.as_deref(), target.all)?;
+    let answers = fan_out(&nodes, |node| {
+        let path = path.clone();
+        async move { client.get_json::<Vec<BucketStats>>(&node, &path).await }
+    })
+    .await;
+    let (answered, failures) = split(answers);
+    let answered_nodes: Vec<&String> = answered.iter().map(|(node, _)| node).collect();
+    let answered_nodes = json!(answered_nodes);
+    let buckets = stats::aggregate(answered.into_iter().map(|(_, buckets)| buckets).collect());
+
+    let mut table = Table::new(vec![
+        "START",
+        "RECEIVED",
+        "OFFLOADED",
+        "SHED",
+        "INSTANCES",
+        "EMERGENCY",
+        "VCPUS",
+        "MEMORY",
+        "HOPS",
+        "DURATION_MS",
+    ]);
+    for bucket in &buckets {
+        let stats = &bucket.stats;
+        table.row(vec![
+            bucket.start.format("%Y-%m-%d %H:%M:%S").to_string(),
+            stats.received.to_string(),
+            stats.offloaded.to_string(),
+            stats.shed.to_string(),
+            stats.requests.to_string(),
+            stats.emergency.requests.to_string(),
+            stats.vcpus.to_string(),
+            stats.memory.to_string(),
+            format!("{:.2}", stats.hops_avg),
+            format!("{:.1}", bucket.duration_avg),
+        ]);
+    }
+    let mut report = Report::new(
+        table.render(),
+        json!({ "nodes": answered_nodes, "buckets": buckets }),
+    );
+    report.failures = failures;
+    Ok(report)
+}
+
+async fn export(
+    client: &NodeClient,
+    config: &ClusterConfig,
+    target: &Target,
+    dir: &Path,
+    format: &str,
+    since: Option<&str>,
+) -> Result<Report, AdminError> {
+    let format: ExportFormat = serde_json::from_value(json!(format))
+        .map_err(|_| AdminError::Invalid(format!("Unknown format: {}", format)))?;
+    let mut path = format!("/export/instances?format={}", format.extension());
+    if let Some(since) = since {
+        path += &format!("&since={}", encode(since));
+    }
+    std::fs::create_dir_all(dir).map_err(|source| AdminError::Write {
+        path: dir.to_path_buf(),
+        source,
+    })?;
+    let nodes = config.targets(target.node.as_deref(), target.all)?;
+    let answers = fan_out(&nodes, |node| {
+        let path = path.clone();
+        let file = dir.join(format!("{}.{}", node, format.extension()));
+        async move {
+            let size = client.download(&node, &path, &file).await?;
+            Ok((file, size))
+        }
+    })
+    .await;
+    let (answered, failures) = split(answers);
+    let mut table = Table::new(vec!["NODE", "FILE", "BYTES"]);
+    for (node, (file, size)) in &answered {
+        table.row(vec![
+            node.clone(),
+            file.display().to_string(),
+            size.to_string(),
+        ]);
+    }
+    let json = answered
+        .iter()
+        .map(|(node, (file, size))| json!({ "node": node, "file": file, "bytes": size }))
+        .collect::<Vec<_>>();
+    let mut report = Report::new(table.render(), json!(json));
+    report.failures = failures;
+    Ok(report)
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let cli = Cli::try_parse_from(["ohsw-admin", "stats", "--all", "--last", "60"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Stats {
+                target: Target { all: true, .. },
+                window: StatsWindow { last: 60, .. },
+                ..
+            }
+        ));
+        // A node or all of them
+        assert!(Cli::try_parse_from(["ohsw-admin", "drain", "--all", "--node", "a:1"]).is_err());
+        // A circle or a polygon
+        assert!(
+            Cli::try_parse_from(["ohsw-admin", "emergency", "start", "--radius", "100"]).is_err()
+        );
+        assert!(Cli::try_parse_from([
+            "ohsw-admin",
+            "emergency",
+            "start",
+            "--position",
+            "45.0,9.0",
+            "--radius",
+            "100",
+            "--polygon",
+            "zone.json"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_position() {
+        assert_eq!(
+            parse_position("45.4685, 9.1824").unwrap(),
+            (45.4685, 9.1824)
+        );
+        for invalid in ["45.4685", "a,b", "NaN,9.0", ""] {
+            assert!(parse_position(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            encode("2026-01-01T00:00:00.000+01:00"),
+            "2026-01-01T00%3A00%3A00.000%2B01%3A00"
+        );
+        assert_eq!(encode("/images/a b.img"), "%2Fimages%2Fa%20b.img");
+    }
+}
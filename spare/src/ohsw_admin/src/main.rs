@@ -0,0 +1,23 @@
+//! ohsw-admin: administer a cluster of SPARE nodes through their HTTP APIs.
+use clap::Parser;
+use ohsw_admin::{run, Cli};
+
+#[actix_web::main]
+async fn main() {
+    let cli = Cli::parse();
+    match run(&cli).await {
+        Ok(report) => {
+            print!("{}", report.render(cli.output));
+            for (node, error) in &report.failures {
+                eprintln!("{}: {}", node, error);
+            }
+            if !report.failures.is_empty() {
+                std::process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Output of the commands: a table for the operator, or JSON for the scripts.
+
+/// Format of the output of the commands
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Aligned columns, the answers of the nodes summarized
+    #[default]
+    Table,
+    /// The answers of the nodes as JSON
+    Json,
+}
+
+/// Table of aligned columns
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table {
+    headers: Vec<&'static str>,
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(headers: Vec<&'static str>) -> Self {
+        Self {
+            headers,
+            rows: Vec::new(),
+        }
+    }
+
+    /// Add a row, with a cell per column
+    pub fn row(&mut self, cells: Vec<String>) {
+        debug_assert_eq!(cells.len(), self.headers.len());
+        self.rows.push(cells);
+    }
+
+    /// Render the table, each column as wide as its widest cell
+    pub fn render(&self) -> String {
+        let mut widths: Vec<usize> = self.headers.iter().map(|h| h.len()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let line = |cells: Vec<&str>| {
+            let padded: Vec<String> = cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect();
+            padded.join("  ").trim_end().to_string() + "\n"
+        };
+        let mut out = line(self.headers.clone());
+        for row in &self.rows {
+            out += &line(row.iter().map(String::as_str).collect());
+        }
+        out
+    }
+}
+
+/// Nodes that did not answer as expected, with the error
+pub type Failures = Vec<(String, String)>;
+
+/// Output of a command, and the nodes it failed on
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// Output in the table format
+    pub text: String,
+    /// Output in the JSON format
+    pub json: serde_json::Value,
+    pub failures: Failures,
+}
+
+impl Report {
+    pub fn new(text: String, json: serde_json::Value) -> Self {
+        Self {
+            text,
+            json,
+            failures: Vec::new(),
+        }
+    }
+
+    /// Render the output in the format
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Table => self.text.clone(),
+            Format::Json => serde_json::to_string_pretty(&self.json).unwrap() + "\n",
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let mut table = Table::new(vec!["NODE", "STATE", "CPUS"]);
+        table.row(vec![
+            "10.0.0.1:8085".to_string(),
+            "serving".to_string(),
+            "8".to_string(),
+        ]);
+        table.row(vec![
+            "edge:8085".to_string(),
+            "unreachable".to_string(),
+            "".to_string(),
+        ]);
+        assert_eq!(
+            table.render(),
+            "NODE           STATE        CPUS\n\
+             10.0.0.1:8085  serving      8\n\
+             edge:8085      unreachable\n"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! Stats of the cluster, summed over the buckets of its nodes.
+use ohsw::db::{BucketStats, Stats};
+
+/// Average of two averages, weighted by the number of samples behind each
+fn weighted(a: f64, a_count: i64, b: f64, b_count: i64) -> f64 {
+    match a_count + b_count {
+        0 => 0.0,
+        count => (a * a_count as f64 + b * b_count as f64) / count as f64,
+    }
+}
+
+/// Add the stats of a node to the ones of the others, for the same bucket
+fn add(total: &mut Stats, other: &Stats) {
+    total.hops_avg = weighted(
+        total.hops_avg,
+        total.requests,
+        other.hops_avg,
+        other.requests,
+    );
+    total.vcpus += other.vcpus;
+    total.memory += other.memory;
+    total.requests += other.requests;
+    total.received += other.received;
+    total.offloaded += other.offloaded;
+    total.shed += other.shed;
+    for (total, other) in [
+        (&mut total.emergency, &other.emergency),
+        (&mut total.normal, &other.normal),
+    ] {
+        total.hops_avg = weighted(
+            total.hops_avg,
+            total.requests,
+            other.hops_avg,
+            other.requests,
+        );
+        total.vcpus += other.vcpus;
+        total.memory += other.memory;
+        total.requests += other.requests;
+    }
+}
+
+/// Sum the buckets of the nodes, asked for the same window and length of the buckets.
+/// The averages are weighted: the hops by the instances, the durations by the requests.
+pub fn aggregate(nodes: Vec<Vec<BucketStats>>) -> Vec<BucketStats> {
+    let mut nodes = nodes.into_iter();
+    let Some(mut total) = nodes.next() else {
+        return Vec::new();
+    };
+    for buckets in nodes {
+        for (i, bucket) in buckets.into_iter().enumerate() {
+            let Some(sum) = total.get_mut(i) else {
+                total.push(bucket);
+                continue;
+            };
+            sum.duration_avg = weighted(
+                sum.duration_avg,
+                sum.stats.received,
+                bucket.duration_avg,
+                bucket.stats.received,
+            );
+            add(&mut sum.stats, &bucket.stats);
+        }
+    }
+    total
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ohsw::db::InstanceStats;
+
+    fn bucket(requests: i64, hops_avg: f64, received: i64, duration_avg: f64) -> BucketStats {
+        BucketStats {
+            stats: Stats {
+                hops_avg,
+                vcpus: requests,
+                memory: requests * 128,
+                requests,
+                received,
+                offloaded: 1,
+                emergency: InstanceStats {
+                    hops_avg,
+                    vcpus: requests,
+                    memory: requests * 128,
+                    requests,
+                },
+                ..Default::default()
+            },
+            duration_avg,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let total = aggregate(vec![
+            vec![bucket(3, 1.0, 4, 10.0), bucket(0, 0.0, 0, 0.0)],
+            vec![bucket(1, 3.0, 1, 60.0), bucket(2, 2.0, 2, 5.0)],
+        ]);
+        assert_eq!(total.len(), 2);
+        let first = &total[0].stats;
+        assert_eq!(
+            (first.requests, first.vcpus, first.memory, first.received),
+            (4, 4, 512, 5)
+        );
+        assert_eq!(first.offloaded, 2);
+        assert_eq!(first.hops_avg, 1.5);
+        assert_eq!(first.emergency.hops_avg, 1.5);
+        assert_eq!(total[0].duration_avg, 20.0);
+        // A bucket empty on a node takes the averages of the other
+        assert_eq!(total[1].stats.hops_avg, 2.0);
+        assert_eq!(total[1].duration_avg, 5.0);
+
+        assert!(aggregate(vec![]).is_empty());
+    }
+}
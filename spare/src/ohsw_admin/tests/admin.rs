@@ -0,0 +1,263 @@
+//! Commands of ohsw-admin against nodes served in process, with the mock execution
+//! environment, and a registry of their own.
+#[path = "../../ohsw/tests/common/mod.rs"]
+mod common;
+
+use std::{fs, path::PathBuf};
+
+use clap::Parser;
+use common::TestNode;
+use ohsw::{
+    execution_environment::mock::GuestBehavior,
+    net::registry::{self, Registry},
+    utils::auth::AdminToken,
+};
+use ohsw_admin::{run, AdminError, Cli};
+use serde_json::json;
+
+/// Directory of the files of a test, removed with it
+struct Workdir(PathBuf);
+
+impl Workdir {
+    fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("ohsw-admin-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    /// Write the configuration of the cluster, returning its path
+    fn config(&self, config: serde_json::Value) -> String {
+        let path = self.0.join("cluster.json");
+        fs::write(&path, config.to_string()).unwrap();
+        path.display().to_string()
+    }
+}
+
+impl Drop for Workdir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Run a command with the configuration at `config`
+async fn admin(config: &str, args: &[&str]) -> Result<ohsw_admin::output::Report, AdminError> {
+    let cli = Cli::try_parse_from(
+        [
+            "ohsw-admin",
+            "--config",
+            config,
+            "--output",
+            "json",
+            "--timeout",
+            "5000",
+        ]
+        .iter()
+        .chain(args),
+    )
+    .unwrap();
+    run(&cli).await
+}
+
+/// A node serving its API on the loopback, its administrative endpoints behind `secret`
+async fn node() -> (TestNode, String) {
+    let mut node = TestNode::new(GuestBehavior::Echo, vec![]).await;
+    node.state.admin_token = AdminToken(Some("secret".to_string()));
+    let address = node.serve();
+    (node, address)
+}
+
+#[actix_web::test]
+async fn test_nodes_and_drain() {
+    let dir = Workdir::new();
+    let (_node, address) = node().await;
+    // Nothing listens on the discard port of the loopback
+    let config = dir.config(json!({
+        "nodes": [address, "127.0.0.1:9"],
+        "admin_token": "secret",
+    }));
+
+    let report = admin(&config, &["nodes", "list"]).await.unwrap();
+    assert_eq!(report.json[0]["node"], address);
+    assert_eq!(report.json[0]["health"]["phase"], "ready");
+    assert_eq!(report.json[0]["health"]["state"], "serving");
+    assert_eq!(report.json[0]["health"]["emergency"], false);
+    assert!(report.json[0]["resources"]["cpus"].as_u64().unwrap() > 0);
+    // The unreachable node is reported, not the whole command
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].0, "127.0.0.1:9");
+
+    let report = admin(&config, &["drain", "--node", &address, "--wait"])
+        .await
+        .unwrap();
+    assert_eq!(report.json[0]["health"]["state"], "drained");
+    assert!(report.failures.is_empty());
+    let report = admin(&config, &["undrain"]).await.unwrap();
+    assert_eq!(report.json[0]["health"]["state"], "serving");
+
+    // The administrative endpoints refuse a wrong token
+    let config = dir.config(json!({ "nodes": [address], "admin_token": "wrong" }));
+    let report = admin(&config, &["drain"]).await.unwrap();
+    assert!(report.json.as_array().unwrap().is_empty());
+    assert!(
+        report.failures[0].1.contains("401"),
+        "{:?}",
+        report.failures
+    );
+}
+
+#[actix_web::test]
+async fn test_functions_and_invoke() {
+    let dir = Workdir::new();
+    let (_node, address) = node().await;
+    let config = dir.config(json!({ "nodes": [address] }));
+
+    let e = admin(&config, &["invoke", "echo"]).await.err().unwrap();
+    assert!(matches!(e, AdminError::UnknownFunction(_)), "{}", e);
+
+    admin(
+        &config,
+        &[
+            "functions",
+            "register",
+            "echo",
+            "--image",
+            "echo.img",
+            "--vcpus",
+            "1",
+            "--memory",
+            "128",
+        ],
+    )
+    .await
+    .unwrap();
+    let report = admin(&config, &["functions", "list", "--check"])
+        .await
+        .unwrap();
+    assert_eq!(report.json[0]["name"], "echo");
+    assert_eq!(report.json[0]["function"]["image"], "echo.img");
+    assert!(report.json[0]["nodes"].is_array());
+    assert!(report.failures.is_empty());
+
+    let report = admin(&config, &["invoke", "echo", "--payload", "hello"])
+        .await
+        .unwrap();
+    assert_eq!(report.json["body"], "hello");
+    assert_eq!(report.text, "hello\n");
+}
+
+#[actix_web::test]
+async fn test_stats_and_export() {
+    let dir = Workdir::new();
+    let (first, first_address) = node().await;
+    let (second, second_address) = node().await;
+    let config = dir.config(json!({
+        "nodes": [first_address, second_address],
+        "admin_token": "secret",
+    }));
+    for node in [&first, &second] {
+        let (status, _) = node.invoke(&common::request("hello", false)).await;
+        assert!(status.is_success());
+    }
+
+    let report = admin(&config, &["stats", "--all", "--last", "600"])
+        .await
+        .unwrap();
+    assert_eq!(report.json["nodes"], json!([first_address, second_address]));
+    let buckets = report.json["buckets"].as_array().unwrap();
+    assert_eq!(buckets.len(), 1);
+    assert_eq!(buckets[0]["requests"], 2);
+    assert_eq!(buckets[0]["vcpus"], 2);
+
+    let exports = dir.0.join("exports");
+    let report = admin(
+        &config,
+        &[
+            "export",
+            "--all",
+            "--format",
+            "csv",
+            "--dir",
+            exports.to_str().unwrap(),
+        ],
+    )
+    .await
+    .unwrap();
+    assert!(report.failures.is_empty());
+    for address in [&first_address, &second_address] {
+        let raw = fs::read_to_string(exports.join(format!("{}.csv", address))).unwrap();
+        // The header and the instance of the node
+        assert_eq!(raw.lines().count(), 2, "{}", raw);
+    }
+
+    let e = admin(&config, &["export", "--format", "xml"])
+        .await
+        .err()
+        .unwrap();
+    assert!(e.to_string().contains("Unknown format"), "{}", e);
+}
+
+#[actix_web::test]
+async fn test_emergency() {
+    let dir = Workdir::new();
+    let config = dir.config(json!({ "nodes": ["127.0.0.1:9"] }));
+    let e = admin(&config, &["emergency", "stop"]).await.err().unwrap();
+    assert!(matches!(e, AdminError::NoRegistry), "{}", e);
+
+    let registry = registry::spawn_server(("127.0.0.1", 0), Registry::new(1)).unwrap();
+    let config = dir.config(json!({
+        "nodes": ["127.0.0.1:9"],
+        "registry": format!("http://{registry}"),
+    }));
+    admin(
+        &config,
+        &[
+            "emergency",
+            "start",
+            "--position",
+            "45.4685,9.1824",
+            "--radius",
+            "1000",
+        ],
+    )
+    .await
+    .unwrap();
+    let polygon = dir.0.join("zone.json");
+    fs::write(&polygon, "[[45.46, 9.18], [45.46, 9.19], [45.47, 9.19]]").unwrap();
+    admin(
+        &config,
+        &["emergency", "start", "--polygon", polygon.to_str().unwrap()],
+    )
+    .await
+    .unwrap();
+    let report = admin(&config, &["emergency", "stop"]).await.unwrap();
+    assert_eq!(report.json["index"], 2);
+
+    // A zone the nodes would refuse is not published
+    fs::write(&polygon, "[[45.46, 9.18], [45.46, 9.19]]").unwrap();
+    let e = admin(
+        &config,
+        &["emergency", "start", "--polygon", polygon.to_str().unwrap()],
+    )
+    .await
+    .err()
+    .unwrap();
+    assert!(matches!(e, AdminError::Invalid(_)), "{}", e);
+
+    let events: serde_json::Value = awc::Client::default()
+        .get(format!("http://{registry}/registry/events"))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let events = events["events"].as_array().unwrap();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0]["op"], "START_EMERGENCY");
+    assert_eq!(events[0]["payload"]["Emergency"]["radius"], 1000.0);
+    assert_eq!(
+        events[1]["payload"]["Emergency"]["polygon"][2],
+        json!([45.47, 9.19])
+    );
+    assert_eq!(events[2]["op"], "STOP_EMERGENCY");
+}
@@ -0,0 +1,150 @@
+//! Generator of the guest function crates of SPARE.
+//! A function runs in a nanos image as a guest agent: it connects to the node through the
+//! vsock, speaks the protocol of the node and calls the handler of the function on the
+//! payload of the request. The generated project holds the whole agent, only the handler
+//! is left to write, and a script building the binary and its image with ops.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+const CARGO_TOML: &str = include_str!("../templates/Cargo.toml.in");
+const MAIN_V1: &str = include_str!("../templates/main_v1.rs");
+const MAIN_V2: &str = include_str!("../templates/main_v2.rs");
+const HANDLER: &str = include_str!("../templates/handler.rs");
+const BUILD_SH: &str = include_str!("../templates/build.sh.in");
+
+/// Protocol spoken with the node, it must match the one of the node
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Protocol {
+    /// Bare `ready` string and length-prefixed payloads, for the nodes started with
+    /// `--legacy-guest-protocol`
+    V1,
+    /// Framed protocol, with handshake metadata, logs and errors of the function
+    #[default]
+    V2,
+}
+
+/// Error of the generation of a project
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+    /// Names are made of letters, digits, `-` and `_`, and start with a letter
+    #[error("Invalid function name: {0:?}")]
+    InvalidName(String),
+    #[error("{0} already exists")]
+    Exists(PathBuf),
+    #[error("Cannot write the project: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// A file of the generated project
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct File {
+    /// Path, relative to the root of the project
+    pub path: PathBuf,
+    pub content: String,
+    pub executable: bool,
+}
+
+/// A guest function crate, ready to be built
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Project {
+    pub name: String,
+    pub files: Vec<File>,
+}
+
+impl Project {
+    /// Generate the project of the function `name`, speaking `protocol`
+    pub fn new(name: &str, protocol: Protocol) -> Result<Self, TemplateError> {
+        if !is_valid_name(name) {
+            return Err(TemplateError::InvalidName(name.to_string()));
+        }
+        let main = match protocol {
+            Protocol::V1 => MAIN_V1,
+            Protocol::V2 => MAIN_V2,
+        };
+        let file = |path: &str, template: &str, executable| File {
+            path: PathBuf::from(path),
+            content: template.replace("{{name}}", name),
+            executable,
+        };
+        Ok(Project {
+            name: name.to_string(),
+            files: vec![
+                file("Cargo.toml", CARGO_TOML, false),
+                file("src/main.rs", main, false),
+                file("src/handler.rs", HANDLER, false),
+                file("build.sh", BUILD_SH, true),
+            ],
+        })
+    }
+
+    /// Write the project in `dir`, which must not exist yet
+    pub fn write(&self, dir: &Path) -> Result<(), TemplateError> {
+        if dir.exists() {
+            return Err(TemplateError::Exists(dir.to_path_buf()));
+        }
+        for file in &self.files {
+            let path = dir.join(&file.path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, &file.content)?;
+            if file.executable {
+                use std::os::unix::fs::PermissionsExt;
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Commands building the binary of the function and its nanos image, from the
+    /// directory of the project
+    pub fn build_commands(&self) -> Vec<String> {
+        vec![
+            "cargo build --release".to_string(),
+            format!("ops build target/release/{0} -i {0}", self.name),
+        ]
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic())
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names() {
+        for name in ["resize", "image-resize", "r2_d2"] {
+            assert!(Project::new(name, Protocol::V2).is_ok());
+        }
+        for name in ["", "2resize", "-resize", "re size", "../resize", "resize/x"] {
+            assert!(matches!(
+                Project::new(name, Protocol::V2),
+                Err(TemplateError::InvalidName(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = std::env::temp_dir().join(format!("spare-template-{}", std::process::id()));
+        let project = Project::new("resize", Protocol::V2).unwrap();
+        project.write(&dir).unwrap();
+        assert!(dir.join("src/handler.rs").exists());
+        let mode = fs::metadata(dir.join("build.sh")).unwrap().permissions();
+        assert_eq!(
+            std::os::unix::fs::PermissionsExt::mode(&mode) & 0o111,
+            0o111
+        );
+        // An existing project is never overwritten
+        assert!(matches!(project.write(&dir), Err(TemplateError::Exists(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
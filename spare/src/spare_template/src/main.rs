@@ -0,0 +1,42 @@
+//! spare-template: generate the crate of a guest function, ready to be built.
+use std::path::PathBuf;
+
+use clap::Parser;
+use spare_template::{Project, Protocol};
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Generate the crate of a SPARE guest function")]
+struct Args {
+    /// Name of the function, and of its crate and image
+    name: String,
+    /// Protocol spoken with the node, it must match the one of the node
+    #[arg(long, value_enum, default_value_t = Protocol::V2)]
+    protocol: Protocol,
+    /// Directory of the project (default: ./<name>)
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+fn main() {
+    let args = Args::parse();
+    let project = match Project::new(&args.name, args.protocol) {
+        Ok(project) => project,
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    };
+    let dir = args.output.unwrap_or_else(|| PathBuf::from(&args.name));
+    if let Err(e) = project.write(&dir) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+
+    println!("Created {}", dir.display());
+    println!("Write the function in src/handler.rs, then build its image with:");
+    println!("    cd {}", dir.display());
+    for command in project.build_commands() {
+        println!("    {command}");
+    }
+    println!("or run ./build.sh");
+}
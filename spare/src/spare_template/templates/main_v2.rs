@@ -0,0 +1,170 @@
+//! Guest agent of the {{name}} function, generated by spare-template.
+//! It connects to the node through the vsock and speaks the framed protocol (v2) of the
+//! node: write the function in `handler.rs`, this file does not need to be changed.
+use std::io::{Read, Write};
+
+use vsock::{VsockAddr, VsockStream};
+
+mod handler;
+
+// Framed protocol (v2) spoken with the host, see `ohsw::utils::protocol`.
+// Header: | magic (4 bytes) | version (1 byte) | frame type (1 byte) | length (8 bytes, BE) |
+const MAGIC: [u8; 4] = *b"SPRE";
+const VERSION: u8 = 2;
+const HEADER_LEN: usize = 14;
+
+const FRAME_READY: u8 = 0;
+const FRAME_PAYLOAD: u8 = 1;
+const FRAME_RESULT: u8 = 2;
+const FRAME_ERROR: u8 = 3;
+const FRAME_LOG: u8 = 4;
+
+// Vsock port of the host, given by the node in the environment of the guest
+const DEFAULT_VSOCK_PORT: u32 = 1234;
+
+fn vsock_port() -> u32 {
+    std::env::var("SPARE_VSOCK_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(DEFAULT_VSOCK_PORT)
+}
+
+fn write_all(vsock: &mut VsockStream, buf: &[u8]) -> Result<(), std::io::Error> {
+    let mut bytes_written = 0;
+    while bytes_written < buf.len() {
+        match vsock.write(&buf[bytes_written..]) {
+            Ok(n) => bytes_written += n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    vsock.flush()
+}
+
+fn read_exact(vsock: &mut VsockStream, buf: &mut [u8]) -> Result<(), std::io::Error> {
+    let mut bytes_read = 0;
+    while bytes_read < buf.len() {
+        match vsock.read(&mut buf[bytes_read..]) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => bytes_read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn write_frame(vsock: &mut VsockStream, frame_type: u8, body: &[u8]) -> Result<(), std::io::Error> {
+    let mut buf = Vec::with_capacity(HEADER_LEN + body.len());
+    buf.extend_from_slice(&MAGIC);
+    buf.push(VERSION);
+    buf.push(frame_type);
+    buf.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    buf.extend_from_slice(body);
+    write_all(vsock, &buf)
+}
+
+fn read_frame(vsock: &mut VsockStream) -> Result<(u8, Vec<u8>), std::io::Error> {
+    let mut header = [0u8; HEADER_LEN];
+    read_exact(vsock, &mut header)?;
+    if header[0..4] != MAGIC || header[4] != VERSION {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Invalid frame header",
+        ));
+    }
+    let len = u64::from_be_bytes(header[6..].try_into().unwrap()) as usize;
+    let mut body = vec![0u8; len];
+    read_exact(vsock, &mut body)?;
+    Ok((header[5], body))
+}
+
+// Address of the Firecracker metadata service (MMDS), used by the host to
+// deliver small payloads when the invocation sets `"payload_via": "mmds"`.
+const MMDS_ADDRESS: &str = "169.254.169.254:80";
+
+fn mmds_request(request: &str) -> Option<Vec<u8>> {
+    let address = MMDS_ADDRESS.parse().ok()?;
+    let mut stream =
+        std::net::TcpStream::connect_timeout(&address, std::time::Duration::from_millis(100))
+            .ok()?;
+    stream.write_all(request.as_bytes()).ok()?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).ok()?;
+    // Split headers and body, only 200 OK responses are accepted
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n")?;
+    if !response.starts_with(b"HTTP/1.1 200") {
+        return None;
+    }
+    Some(response[split + 4..].to_vec())
+}
+
+fn fetch_mmds_payload() -> Option<Vec<u8>> {
+    // MMDS V2 requires a session token
+    let token = mmds_request(
+        "PUT /latest/api/token HTTP/1.1\r\nHost: 169.254.169.254\r\n\
+         X-metadata-token-ttl-seconds: 60\r\nConnection: close\r\n\r\n",
+    )?;
+    let token = String::from_utf8(token).ok()?;
+    mmds_request(&format!(
+        "GET /spare/payload HTTP/1.1\r\nHost: 169.254.169.254\r\n\
+         X-metadata-token: {}\r\nConnection: close\r\n\r\n",
+        token.trim()
+    ))
+}
+
+fn main() {
+    // Let the orchestrator know we're ready
+    let mut vsock = VsockStream::connect(&VsockAddr::new(2, vsock_port())).expect("Failed to connect");
+    vsock.set_nonblocking(true).expect("Failed to set non-blocking");
+
+    // Handshake metadata, read by the host from the Ready frame
+    let metadata = br#"{"agent":"spare-template/2","function":"{{name}}"}"#;
+    if let Err(e) = write_frame(&mut vsock, FRAME_READY, metadata) {
+        println!("Failed to write to VsockStream: {}", e);
+        return;
+    }
+
+    let buf = match read_frame(&mut vsock) {
+        Ok((FRAME_PAYLOAD, body)) => body,
+        Ok((frame_type, _)) => {
+            println!("Unexpected frame: {}", frame_type);
+            return;
+        }
+        Err(e) => {
+            println!("Failed to read from VsockStream: {}", e);
+            return;
+        }
+    };
+    // An empty payload frame means the payload, if any, was delivered through MMDS
+    let buf = if buf.is_empty() {
+        fetch_mmds_payload().unwrap_or_default()
+    } else {
+        buf
+    };
+    println!("Received {} bytes", buf.len());
+    let _ = write_frame(&mut vsock, FRAME_LOG, b"Payload received");
+
+    // Call to the function. A panic of the handler is reported as an error of the
+    // function, which is surfaced to the caller as a 500.
+    let result = match std::panic::catch_unwind(|| handler::handler(buf)) {
+        Ok(output) => {
+            println!("Output generated!");
+            write_frame(&mut vsock, FRAME_RESULT, &output)
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "The function panicked".to_string());
+            println!("Function failed: {}", message);
+            write_frame(&mut vsock, FRAME_ERROR, message.as_bytes())
+        }
+    };
+    if let Err(e) = result {
+        println!("Failed to write to VsockStream: {}", e);
+        return;
+    }
+    let _ = vsock.shutdown(std::net::Shutdown::Both);
+}
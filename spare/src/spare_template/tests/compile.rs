@@ -0,0 +1,174 @@
+//! The generated guests build, and speak the protocol of the node.
+//! They are built with rustc against a stand-in for the vsock crate (`tests/stub`), which
+//! goes over a Unix socket, so they run on the host without network access or a VM.
+use std::{
+    fs,
+    io::{Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    process::{Child, Command},
+};
+
+use spare_template::{Project, Protocol};
+
+/// Vsock port the guests are told to connect to
+const PORT: &str = "4321";
+
+fn rustc() -> String {
+    std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string())
+}
+
+fn run(command: &mut Command) {
+    let output = command.output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+/// Generate the project of `protocol` in a new directory, with `handler` if given, and
+/// build it. Returns the directory and the path of the binary.
+fn build(protocol: Protocol, handler: Option<&str>) -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!(
+        "spare-template-{:?}-{}-{}",
+        protocol,
+        handler.is_some(),
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    Project::new("resize", protocol)
+        .unwrap()
+        .write(&dir.join("resize"))
+        .unwrap();
+    if let Some(handler) = handler {
+        fs::write(dir.join("resize/src/handler.rs"), handler).unwrap();
+    }
+    let stub = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/stub/vsock.rs");
+    run(Command::new(rustc())
+        .args([
+            "--edition",
+            "2021",
+            "--crate-type",
+            "lib",
+            "--crate-name",
+            "vsock",
+        ])
+        .arg(stub)
+        .arg("--out-dir")
+        .arg(&dir));
+    let binary = dir.join("guest");
+    run(Command::new(rustc())
+        .args(["--edition", "2021", "-D", "warnings", "--extern"])
+        .arg(format!("vsock={}", dir.join("libvsock.rlib").display()))
+        .arg(dir.join("resize/src/main.rs"))
+        .arg("-o")
+        .arg(&binary));
+    (dir, binary)
+}
+
+/// Start the guest and accept its connection, as the node does
+fn start(dir: &Path, binary: &Path) -> (Child, UnixStream) {
+    let vsock = dir.join("vsock");
+    let listener = UnixListener::bind(format!("{}_{}", vsock.display(), PORT)).unwrap();
+    let guest = Command::new(binary)
+        .env("SPARE_TEST_VSOCK", &vsock)
+        .env("SPARE_VSOCK_PORT", PORT)
+        .spawn()
+        .unwrap();
+    let (stream, _) = listener.accept().unwrap();
+    (guest, stream)
+}
+
+fn write_frame(stream: &mut UnixStream, frame_type: u8, body: &[u8]) {
+    let mut frame = b"SPRE".to_vec();
+    frame.extend_from_slice(&[2, frame_type]);
+    frame.extend_from_slice(&(body.len() as u64).to_be_bytes());
+    frame.extend_from_slice(body);
+    stream.write_all(&frame).unwrap();
+}
+
+fn read_frame(stream: &mut UnixStream) -> (u8, Vec<u8>) {
+    let mut header = [0; 14];
+    stream.read_exact(&mut header).unwrap();
+    assert_eq!(&header[..5], b"SPRE\x02");
+    let mut body = vec![0; u64::from_be_bytes(header[6..].try_into().unwrap()) as usize];
+    stream.read_exact(&mut body).unwrap();
+    (header[5], body)
+}
+
+/// Frames of the guest up to the result or the error, without the logs
+fn exchange_v2(dir: &Path, binary: &Path, payload: &[u8]) -> (u8, Vec<u8>) {
+    let (mut guest, mut stream) = start(dir, binary);
+    let (frame_type, metadata) = read_frame(&mut stream);
+    assert_eq!(frame_type, 0);
+    assert_eq!(
+        metadata,
+        br#"{"agent":"spare-template/2","function":"resize"}"#
+    );
+    write_frame(&mut stream, 1, payload);
+    let last = loop {
+        match read_frame(&mut stream) {
+            (4, _) => continue,
+            frame => break frame,
+        }
+    };
+    guest.wait().unwrap();
+    last
+}
+
+#[test]
+fn test_v2() {
+    let (dir, binary) = build(Protocol::V2, None);
+    // The handler of the template answers with the payload
+    assert_eq!(exchange_v2(&dir, &binary, b"hello"), (2, b"hello".to_vec()));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_v2_panic() {
+    let handler = "pub fn handler(_payload: Vec<u8>) -> Vec<u8> {\n    panic!(\"no image\")\n}\n";
+    let (dir, binary) = build(Protocol::V2, Some(handler));
+    // A panic of the function is reported as its error
+    assert_eq!(
+        exchange_v2(&dir, &binary, b"hello"),
+        (3, b"no image".to_vec())
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_v1() {
+    let (dir, binary) = build(Protocol::V1, None);
+    let (mut guest, mut stream) = start(&dir, &binary);
+    let mut ready = [0; 5];
+    stream.read_exact(&mut ready).unwrap();
+    assert_eq!(&ready, b"ready");
+    stream.write_all(&5u64.to_be_bytes()).unwrap();
+    stream.write_all(b"hello").unwrap();
+    let mut len = [0; 8];
+    stream.read_exact(&mut len).unwrap();
+    let mut result = vec![0; u64::from_be_bytes(len) as usize];
+    stream.read_exact(&mut result).unwrap();
+    assert_eq!(result, b"hello");
+    guest.wait().unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// The generated project builds with cargo and the real vsock crate. It needs to fetch
+/// the dependencies: run it with `cargo test -p spare_template -- --ignored`.
+#[test]
+#[ignore]
+fn test_cargo_build() {
+    let dir = std::env::temp_dir().join(format!("spare-template-cargo-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    Project::new("resize", Protocol::V2)
+        .unwrap()
+        .write(&dir)
+        .unwrap();
+    run(Command::new(env!("CARGO"))
+        .arg("build")
+        .current_dir(&dir)
+        .env("CARGO_TARGET_DIR", dir.join("target")));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,49 @@
+//! The generated projects match the golden files of `tests/golden`.
+//! After a change of the templates, write them again with
+//! `SPARE_BLESS=1 cargo test -p spare_template --test golden` and review the diff.
+use std::{fs, path::Path};
+
+use spare_template::{Project, Protocol};
+
+fn check(protocol: Protocol, golden: &str) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(golden);
+    let project = Project::new("resize", protocol).unwrap();
+    for file in &project.files {
+        let path = dir.join(&file.path);
+        if std::env::var("SPARE_BLESS").is_ok() {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, &file.content).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e));
+        assert_eq!(
+            file.content,
+            expected,
+            "{} differs from its golden file",
+            file.path.display()
+        );
+    }
+}
+
+#[test]
+fn test_v1() {
+    check(Protocol::V1, "v1");
+}
+
+#[test]
+fn test_v2() {
+    check(Protocol::V2, "v2");
+}
+
+#[test]
+fn test_build_commands() {
+    // The script runs the commands printed by the generator
+    let project = Project::new("resize", Protocol::V2).unwrap();
+    let script = &project.files.iter().find(|file| file.executable).unwrap();
+    for command in project.build_commands() {
+        assert!(script.content.contains(&command), "{command}");
+    }
+}
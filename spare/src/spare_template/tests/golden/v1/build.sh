@@ -0,0 +1,7 @@
+#!/bin/sh
+# Build the resize function and its nanos image, booted by the nodes with the kernel
+# of nanos (NANOS_KERNEL). Needs ops: https://ops.city
+set -e
+cargo build --release
+ops build target/release/resize -i resize
+echo "Image: $HOME/.ops/images/resize, invoke it as {\"function\": \"resize\", \"image\": ...}"
@@ -0,0 +1,84 @@
+//! Guest agent of the resize function, generated by spare-template.
+//! It connects to the node through the vsock and speaks the legacy protocol (v1), for the
+//! nodes started with `--legacy-guest-protocol`: write the function in `handler.rs`, this
+//! file does not need to be changed.
+use std::io::{Read, Write};
+
+use vsock::{VsockAddr, VsockStream};
+
+mod handler;
+
+// Legacy protocol (v1) spoken with the host, see `ohsw::utils::protocol`: the guest
+// writes `ready`, then reads the length of the payload (8 bytes, BE) and the payload,
+// and writes back the length of the result and the result.
+const READY: &[u8; 5] = b"ready";
+
+// Vsock port of the host, given by the node in the environment of the guest
+const DEFAULT_VSOCK_PORT: u32 = 1234;
+
+fn vsock_port() -> u32 {
+    std::env::var("SPARE_VSOCK_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(DEFAULT_VSOCK_PORT)
+}
+
+fn write_all(vsock: &mut VsockStream, buf: &[u8]) -> Result<(), std::io::Error> {
+    let mut bytes_written = 0;
+    while bytes_written < buf.len() {
+        match vsock.write(&buf[bytes_written..]) {
+            Ok(n) => bytes_written += n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    vsock.flush()
+}
+
+fn read_exact(vsock: &mut VsockStream, buf: &mut [u8]) -> Result<(), std::io::Error> {
+    let mut bytes_read = 0;
+    while bytes_read < buf.len() {
+        match vsock.read(&mut buf[bytes_read..]) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => bytes_read += n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    // Let the orchestrator know we're ready
+    let mut vsock = VsockStream::connect(&VsockAddr::new(2, vsock_port())).expect("Failed to connect");
+    vsock.set_nonblocking(true).expect("Failed to set non-blocking");
+    if let Err(e) = write_all(&mut vsock, READY) {
+        println!("Failed to write to VsockStream: {}", e);
+        return;
+    }
+
+    let mut len = [0u8; 8];
+    if let Err(e) = read_exact(&mut vsock, &mut len) {
+        println!("Failed to read from VsockStream: {}", e);
+        return;
+    }
+    let mut buf = vec![0u8; u64::from_be_bytes(len) as usize];
+    if let Err(e) = read_exact(&mut vsock, &mut buf) {
+        println!("Failed to read from VsockStream: {}", e);
+        return;
+    }
+    println!("Received {} bytes", buf.len());
+
+    // Call to the function
+    let output = handler::handler(buf);
+    println!("Output generated!");
+
+    // Write back the result
+    let mut result = (output.len() as u64).to_be_bytes().to_vec();
+    result.extend_from_slice(&output);
+    if let Err(e) = write_all(&mut vsock, &result) {
+        println!("Failed to write to VsockStream: {}", e);
+        return;
+    }
+    let _ = vsock.shutdown(std::net::Shutdown::Both);
+}
@@ -0,0 +1,9 @@
+//! The resize function.
+
+/// Run the function on the payload of a request, returning the answer of the request.
+/// The payload is empty when the request has none. A panic is reported to the caller
+/// as an error of the function.
+pub fn handler(payload: Vec<u8>) -> Vec<u8> {
+    // Replace with the function, it answers with the payload for now
+    payload
+}
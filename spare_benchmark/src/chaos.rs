@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+
+// Chaos command sent to the nodes on the control plane. The action is passed through as
+// written in the script, the nodes check it: e.g. `{"inject": {"kind": "drop_invokes",
+// "percent": 20.0}}` or `"clear"`
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ChaosCommand {
+    // Ids or addresses of the nodes it applies to, every node if empty
+    #[serde(default)]
+    pub nodes: Vec<String>,
+    pub action: serde_json::Value,
+}
+
+// A command of a chaos script, sent at the start of an epoch
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct ScriptedChaos {
+    // Epochs are counted across the scenarios, from 0
+    pub epoch: u64,
+    #[serde(flatten)]
+    pub command: ChaosCommand,
+}
+
+// Parse a chaos script, a JSON array of commands, sorted by epoch
+pub fn parse_script(raw: &str) -> Result<Vec<ScriptedChaos>, serde_json::Error> {
+    let mut script: Vec<ScriptedChaos> = serde_json::from_str(raw)?;
+    // Commands of the same epoch are sent in the order they were written
+    script.sort_by_key(|scripted| scripted.epoch);
+    Ok(script)
+}
+
+// Commands of the script to send at the start of `epoch`
+pub fn due(script: &[ScriptedChaos], epoch: u64) -> impl Iterator<Item = &ChaosCommand> {
+    script
+        .iter()
+        .filter(move |scripted| scripted.epoch == epoch)
+        .map(|scripted| &scripted.command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_script() {
+        let script = parse_script(
+            r#"[
+                {"epoch": 3, "action": "clear"},
+                {"epoch": 1, "nodes": ["node-a"], "action": {"inject": {"kind": "drop_invokes", "percent": 20.0}}},
+                {"epoch": 1, "action": {"inject": {"kind": "blackhole", "peer": "10.0.0.2:8085"}}}
+            ]"#,
+        )
+        .unwrap();
+        let epochs: Vec<_> = script.iter().map(|scripted| scripted.epoch).collect();
+        assert_eq!(epochs, vec![1, 1, 3]);
+        assert_eq!(script[0].command.nodes, vec!["node-a".to_string()]);
+        assert!(script[1].command.nodes.is_empty());
+
+        let due: Vec<_> = due(&script, 1).collect();
+        assert_eq!(due.len(), 2);
+        assert_eq!(due[1].action["inject"]["kind"], "blackhole");
+        assert_eq!(super::due(&script, 2).count(), 0);
+        // The command is sent without its epoch
+        assert_eq!(
+            serde_json::to_value(&script[2].command).unwrap(),
+            serde_json::json!({"nodes": [], "action": "clear"})
+        );
+
+        assert!(parse_script(r#"[{"action": "clear"}]"#).is_err());
+        assert!(parse_script(r#"{"epoch": 1, "action": "clear"}"#).is_err());
+    }
+}
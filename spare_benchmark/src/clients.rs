@@ -0,0 +1,256 @@
+use std::str::FromStr;
+
+use rand::{
+    distr::{Distribution, Uniform},
+    seq::SliceRandom,
+    Rng,
+};
+use rand_distr::Zipf;
+
+use crate::Node;
+
+// Header of the targets CSV
+pub const TARGETS_CSV_HEADER: &str = "Scenario,Epoch,Model,Node,Id,Requests";
+
+// How the clients choose the node they send a request to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientModel {
+    // The node closest to the client, the client being at a vehicle of the dataset
+    Nearest,
+    // Any node, with the same probability
+    Uniform,
+    // A few nodes get most of the requests
+    Zipf,
+}
+
+impl ClientModel {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ClientModel::Nearest => "nearest",
+            ClientModel::Uniform => "uniform",
+            ClientModel::Zipf => "zipf",
+        }
+    }
+}
+
+impl FromStr for ClientModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(ClientModel::Nearest),
+            "uniform" => Ok(ClientModel::Uniform),
+            "zipf" => Ok(ClientModel::Zipf),
+            _ => Err(format!(
+                "Unknown client model {}, expected nearest, uniform or zipf",
+                s
+            )),
+        }
+    }
+}
+
+// Chooses the target of each request among the nodes of the test, by their index
+pub enum TargetPicker {
+    Nearest {
+        // Nodes of the test, for their positions
+        nodes: Vec<Node>,
+        clients: Vec<(f64, f64)>,
+    },
+    Uniform(Uniform<usize>),
+    Zipf {
+        distribution: Zipf<f64>,
+        // Node of each rank, the most popular first
+        ranks: Vec<usize>,
+    },
+}
+
+impl TargetPicker {
+    // Picker of `model` for `nodes`. The positions of the clients are used by nearest only,
+    // the ranks of zipf are drawn once, so that the same nodes are popular in every epoch.
+    pub fn new(
+        model: ClientModel,
+        nodes: &[Node],
+        clients: Vec<(f64, f64)>,
+        zipf_exponent: f64,
+        rng: &mut impl Rng,
+    ) -> Result<Self, String> {
+        if nodes.is_empty() {
+            return Err("No node to send the requests to".to_string());
+        }
+        match model {
+            ClientModel::Nearest if clients.is_empty() => {
+                Err("No client position in the dataset".to_string())
+            }
+            ClientModel::Nearest => Ok(TargetPicker::Nearest {
+                nodes: nodes.to_vec(),
+                clients,
+            }),
+            ClientModel::Uniform => Ok(TargetPicker::Uniform(
+                Uniform::new(0, nodes.len()).map_err(|e| e.to_string())?,
+            )),
+            ClientModel::Zipf => {
+                let mut ranks: Vec<usize> = (0..nodes.len()).collect();
+                ranks.shuffle(rng);
+                Ok(TargetPicker::Zipf {
+                    distribution: Zipf::new(nodes.len() as f64, zipf_exponent)
+                        .map_err(|e| e.to_string())?,
+                    ranks,
+                })
+            }
+        }
+    }
+
+    // Index of the node the next request is sent to
+    pub fn pick(&self, rng: &mut impl Rng) -> usize {
+        match self {
+            TargetPicker::Nearest { nodes, clients } => {
+                let mut client = Node::point("client");
+                client.position = Some(clients[rng.random_range(0..clients.len())]);
+                nearest(nodes, &client)
+            }
+            TargetPicker::Uniform(distribution) => distribution.sample(rng),
+            // The ranks are from 1
+            TargetPicker::Zipf {
+                distribution,
+                ranks,
+            } => ranks[distribution.sample(rng) as usize - 1],
+        }
+    }
+}
+
+// Index of the node closest to `client`, the first one on a tie
+pub fn nearest(nodes: &[Node], client: &Node) -> usize {
+    nodes
+        .iter()
+        .map(|node| node.distance(client))
+        .enumerate()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(index, _)| index)
+        .unwrap()
+}
+
+// Records of the requests sent to each node in an epoch in the targets CSV, in the order of
+// the nodes
+pub fn target_records(
+    scenario: &str,
+    epoch: usize,
+    model: ClientModel,
+    nodes: &[Node],
+    targets: &[usize],
+) -> Vec<String> {
+    nodes
+        .iter()
+        .zip(targets)
+        .map(|(node, requests)| {
+            format!(
+                "{},{},{},{},{},{}",
+                scenario,
+                epoch,
+                model.name(),
+                node.address,
+                node.id,
+                requests
+            )
+        })
+        .collect()
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn nodes() -> Vec<Node> {
+        [(43.72, 10.42), (43.70, 10.40), (43.60, 10.30)]
+            .iter()
+            .enumerate()
+            .map(|(i, position)| {
+                let mut node = Node::point(&format!("10.0.0.{}:8085", i + 1));
+                node.position = Some(*position);
+                node
+            })
+            .collect()
+    }
+
+    // Requests sent to each node by `picker`
+    fn targets(picker: &TargetPicker, requests: usize) -> Vec<usize> {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut targets = vec![0; nodes().len()];
+        for _ in 0..requests {
+            targets[picker.pick(&mut rng)] += 1;
+        }
+        targets
+    }
+
+    #[test]
+    fn test_parse_client_model() {
+        for model in [
+            ClientModel::Nearest,
+            ClientModel::Uniform,
+            ClientModel::Zipf,
+        ] {
+            assert_eq!(model.name().parse::<ClientModel>(), Ok(model));
+        }
+        assert!("random".parse::<ClientModel>().is_err());
+    }
+
+    #[test]
+    fn test_nearest() {
+        let nodes = nodes();
+        let mut client = Node::point("client");
+        // Between the first two nodes, slightly closer to the first one
+        client.position = Some((43.7101, 10.4101));
+        assert_eq!(nearest(&nodes, &client), 0);
+        client.position = Some((43.65, 10.31));
+        assert_eq!(nearest(&nodes, &client), 2);
+
+        // Every client goes to its closest node
+        let mut rng = StdRng::seed_from_u64(7);
+        let clients = vec![(43.721, 10.421), (43.601, 10.301), (43.599, 10.299)];
+        let picker =
+            TargetPicker::new(ClientModel::Nearest, &nodes, clients, 1.0, &mut rng).unwrap();
+        let targets = targets(&picker, 300);
+        assert_eq!(targets[1], 0);
+        assert!(targets[2] > targets[0], "{:?}", targets);
+        assert_eq!(targets.iter().sum::<usize>(), 300);
+
+        assert!(TargetPicker::new(ClientModel::Nearest, &nodes, vec![], 1.0, &mut rng).is_err());
+        assert!(TargetPicker::new(ClientModel::Uniform, &[], vec![], 1.0, &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_uniform_and_zipf() {
+        let nodes = nodes();
+        let mut rng = StdRng::seed_from_u64(7);
+        let picker =
+            TargetPicker::new(ClientModel::Uniform, &nodes, vec![], 1.0, &mut rng).unwrap();
+        assert!(targets(&picker, 3000)
+            .iter()
+            .all(|t| (800..1200).contains(t)));
+
+        let picker = TargetPicker::new(ClientModel::Zipf, &nodes, vec![], 1.5, &mut rng).unwrap();
+        let TargetPicker::Zipf { ranks, .. } = &picker else {
+            panic!("Not a zipf picker");
+        };
+        let targets = targets(&picker, 3000);
+        // The more popular the rank, the more requests its node gets
+        assert!(targets[ranks[0]] > targets[ranks[1]], "{:?}", targets);
+        assert!(targets[ranks[1]] > targets[ranks[2]], "{:?}", targets);
+        assert!(TargetPicker::new(ClientModel::Zipf, &nodes, vec![], -1.0, &mut rng).is_err());
+    }
+
+    #[test]
+    fn test_target_records() {
+        let mut nodes = nodes();
+        nodes[0].id = "node-a".to_string();
+        assert_eq!(
+            target_records("Normal", 1, ClientModel::Zipf, &nodes[..2], &[5, 0]),
+            vec![
+                "Normal,1,zipf,10.0.0.1:8085,node-a,5",
+                "Normal,1,zipf,10.0.0.2:8085,,0"
+            ]
+        );
+    }
+}
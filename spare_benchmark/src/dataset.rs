@@ -8,6 +8,8 @@ struct EdgeNode {
     cell_id: u32,
     cell_lat: f64,
     cell_lon: f64,
+    vehicle_lat: f64,
+    vehicle_lon: f64,
 }
 
 pub fn generate_points_from_csv(nodes: &mut [Node], file_path: &str) {
@@ -15,10 +17,20 @@ pub fn generate_points_from_csv(nodes: &mut [Node], file_path: &str) {
     let mut edge_nodes: Vec<EdgeNode> = rdr.deserialize().map(|result| result.unwrap()).collect();
 
     let mut rng = rng();
-    (0..nodes.len()).for_each(|i| {
+    // The nodes without a position keep none
+    for node in nodes.iter_mut().filter(|node| node.position.is_some()) {
         let edge_node = edge_nodes.remove(rng.random_range(0..edge_nodes.len()));
-        nodes[i].position = (edge_node.cell_lat, edge_node.cell_lon);
-    });
+        node.position = Some((edge_node.cell_lat, edge_node.cell_lon));
+    }
+}
+
+// Positions of the vehicles of the dataset, the clients of the nodes
+pub fn load_client_positions(file_path: &str) -> Vec<(f64, f64)> {
+    let mut rdr = csv::Reader::from_path(file_path).unwrap();
+    rdr.deserialize()
+        .map(|result: Result<EdgeNode, _>| result.unwrap())
+        .map(|edge_node| (edge_node.vehicle_lat, edge_node.vehicle_lon))
+        .collect()
 }
 
 // Test the generation of points from a CSV file
@@ -29,18 +41,9 @@ mod tests {
     #[test]
     fn test_generate_points_from_csv() {
         let mut nodes = vec![
-            Node {
-                address: "node_1".to_string(),
-                position: (0.0, 0.0),
-            },
-            Node {
-                address: "node_2".to_string(),
-                position: (0.0, 0.0),
-            },
-            Node {
-                address: "node_3".to_string(),
-                position: (0.0, 0.0),
-            },
+            Node::point("node_1"),
+            Node::point("node_2"),
+            Node::point("node_3"),
         ];
         generate_points_from_csv(&mut nodes, "../data/edge_nodes.csv");
         println!("{:?}", nodes);
@@ -1,4 +1,11 @@
-use std::str::FromStr;
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 use iggy::{
     client::{MessageClient, StreamClient, TopicClient},
@@ -12,14 +19,46 @@ use iggy::{
 };
 use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
 
-use crate::{Emergency, Message, Node, Payload, Period};
+use crate::{Emergency, Message, Node, Payload, StatsReport};
 
 pub const STREAM_ID: u32 = 1;
 pub const TOPIC_ID: u32 = 1;
 pub const ANNOUNCE_PARTITION_ID: u32 = 1;
 pub const BROADCAST_PARTITION_ID: u32 = 2;
 
+// Time between two polls of the stats reports
+const STATS_POLL_INTERVAL: Duration = Duration::from_millis(50);
+// Time between two polls of the announces
+const ANNOUNCE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Version of the message schema, messages without a version share the schema of version 1.
+// Version 2 adds the stable id of the nodes.
+pub const MESSAGE_VERSION: u8 = 2;
+
+// Stream, topic and partitions shared with the nodes, must match their configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Topology {
+    pub stream_id: u32,
+    pub topic_id: u32,
+    pub announce_partition: u32,
+    pub broadcast_partition: u32,
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Self {
+            stream_id: STREAM_ID,
+            topic_id: TOPIC_ID,
+            announce_partition: ANNOUNCE_PARTITION_ID,
+            broadcast_partition: BROADCAST_PARTITION_ID,
+        }
+    }
+}
+
+// The names of the operations are sent on the wire, they must match the ones of the nodes
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Eq, Deserialize, Serialize)]
 pub enum Operation {
     START_EMERGENCY = 0,
@@ -28,6 +67,12 @@ pub enum Operation {
     ANNOUNCE = 3,
     END = 4,
     WRITE_STATS = 5,
+    HELLO = 6,
+    DRAINING = 7,
+    RESUMED = 8,
+    STATS_REPORT = 9,
+    UPDATE_POSITION = 10,
+    CHAOS = 11,
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
@@ -39,12 +84,136 @@ pub struct InvokeFunction {
     pub payload: Option<String>,
     pub emergency: bool,
     pub hops: i32,
+    // Id of the request, a retry with the same id is not run twice by the node
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub idempotency_key: Option<String>,
+    // Time the benchmark waits for the answer (in ms), the nodes shed the request rather
+    // than answer it later
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub timeout_ms: Option<u64>,
+}
+
+// Error types for the messages polled from the broker
+#[derive(Debug)]
+pub enum MessageError {
+    // The payload is not a message
+    Malformed { reason: String, raw: Vec<u8> },
+    // The message was sent with a newer schema
+    Version { version: u8, raw: Vec<u8> },
+    // The operation is not known
+    UnknownOperation { op: String, raw: Vec<u8> },
+    Broker(IggyError),
+}
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Malformed { reason, .. } => write!(f, "Malformed message: {}", reason),
+            MessageError::Version { version, .. } => write!(
+                f,
+                "Unsupported message version {}, expected at most {}",
+                version, MESSAGE_VERSION
+            ),
+            MessageError::UnknownOperation { op, .. } => write!(f, "Unknown operation {}", op),
+            MessageError::Broker(e) => write!(f, "Broker error: {}", e),
+        }
+    }
+}
+impl std::error::Error for MessageError {}
+impl From<IggyError> for MessageError {
+    fn from(e: IggyError) -> Self {
+        MessageError::Broker(e)
+    }
+}
+impl MessageError {
+    // Get the payload that could not be handled, None for broker errors
+    pub fn raw(&self) -> Option<&[u8]> {
+        match self {
+            MessageError::Malformed { raw, .. }
+            | MessageError::Version { raw, .. }
+            | MessageError::UnknownOperation { raw, .. } => Some(raw),
+            MessageError::Broker(_) => None,
+        }
+    }
+}
+
+// Fields of a message that are stable across schema versions
+#[derive(Deserialize)]
+struct Envelope {
+    #[serde(default)]
+    v: u8,
+    op: String,
+}
+
+// Decode a message polled from the broker
+pub fn decode(raw: &[u8]) -> Result<Message, MessageError> {
+    let malformed = |e: serde_json::Error| MessageError::Malformed {
+        reason: e.to_string(),
+        raw: raw.to_vec(),
+    };
+    let envelope: Envelope = serde_json::from_slice(raw).map_err(malformed)?;
+    if envelope.v > MESSAGE_VERSION {
+        return Err(MessageError::Version {
+            version: envelope.v,
+            raw: raw.to_vec(),
+        });
+    }
+    if serde_json::from_value::<Operation>(serde_json::Value::String(envelope.op.clone())).is_err()
+    {
+        return Err(MessageError::UnknownOperation {
+            op: envelope.op,
+            raw: raw.to_vec(),
+        });
+    }
+    serde_json::from_slice(raw).map_err(malformed)
+}
+
+// Longest payload stored in a dead letter, longer ones are truncated
+pub const MAX_DEAD_LETTER_LEN: usize = 4096;
+
+// Log of the messages that could not be decoded, one JSON line each.
+// The file is rotated to <path>.1 when it grows beyond max_size.
+pub struct DeadLetters {
+    path: PathBuf,
+    max_size: u64,
+}
+
+impl DeadLetters {
+    pub fn new(path: PathBuf, max_size: u64) -> Self {
+        Self { path, max_size }
+    }
+
+    pub fn rotated_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".1");
+        PathBuf::from(path)
+    }
+
+    // Record a message that could not be decoded, broker errors are not recorded
+    pub fn record(&self, error: &MessageError) -> Result<(), io::Error> {
+        let Some(raw) = error.raw() else {
+            return Ok(());
+        };
+        let entry = serde_json::json!({
+            "time": chrono::Utc::now().to_rfc3339(),
+            "error": error.to_string(),
+            "payload": String::from_utf8_lossy(&raw[..raw.len().min(MAX_DEAD_LETTER_LEN)]),
+            "truncated": raw.len() > MAX_DEAD_LETTER_LEN,
+        });
+        if fs::metadata(&self.path).is_ok_and(|m| m.len() >= self.max_size) {
+            fs::rename(&self.path, self.rotated_path())?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", entry)
+    }
 }
 
 // Initializes stream and topic
-pub async fn init_system(client: &IggyClient) {
+pub async fn init_system(client: &IggyClient, topology: &Topology) {
     match client
-        .create_stream("default-stream", Some(STREAM_ID))
+        .create_stream("default-stream", Some(topology.stream_id))
         .await
     {
         Ok(_) => info!("Stream was created."),
@@ -53,12 +222,14 @@ pub async fn init_system(client: &IggyClient) {
 
     match client
         .create_topic(
-            &STREAM_ID.try_into().unwrap(),
+            &topology.stream_id.try_into().unwrap(),
             "cluster",
-            2,
+            topology
+                .announce_partition
+                .max(topology.broadcast_partition),
             CompressionAlgorithm::default(),
             None,
-            Some(TOPIC_ID),
+            Some(topology.topic_id),
             IggyExpiry::NeverExpire,
             None.into(),
         )
@@ -69,35 +240,89 @@ pub async fn init_system(client: &IggyClient) {
     }
 }
 
+// Partition and payload of a message sent to the nodes
+fn outgoing(topology: &Topology, message: &Message) -> (u32, String) {
+    (
+        topology.broadcast_partition,
+        serde_json::to_string(message).unwrap(),
+    )
+}
+
 // Send message to topic
 pub async fn send_message(
     client: &IggyClient,
-    partition_id: u32,
+    topology: &Topology,
     message: Message,
 ) -> Result<(), IggyError> {
-    let message = iggy::messages::send_messages::Message::from_str(
-        serde_json::to_string(&message).unwrap().as_str(),
-    )
-    .unwrap();
+    let (partition, payload) = outgoing(topology, &message);
+    let message = iggy::messages::send_messages::Message::from_str(&payload).unwrap();
     client
         .send_messages(
-            &STREAM_ID.try_into().unwrap(),
-            &TOPIC_ID.try_into().unwrap(),
-            &Partitioning::partition_id(partition_id),
+            &topology.stream_id.try_into().unwrap(),
+            &topology.topic_id.try_into().unwrap(),
+            &Partitioning::partition_id(partition),
             &mut [message],
         )
         .await
 }
 
-// Receive message from topic
-pub async fn receive_message(client: &IggyClient) -> Result<Message, IggyError> {
-    loop {
+// Nodes that announced themselves before the registration timeout
+#[derive(Debug, Serialize)]
+pub struct Membership {
+    pub expected: usize,
+    pub nodes: Vec<Node>,
+}
+
+impl Membership {
+    // Number of nodes that did not announce themselves
+    pub fn missing(&self) -> usize {
+        self.expected.saturating_sub(self.nodes.len())
+    }
+
+    // Check that every node announced itself, or that a partial membership is allowed
+    pub fn check(self, allow_partial: bool) -> Result<Self, String> {
+        if self.missing() == 0 || allow_partial {
+            return Ok(self);
+        }
+        let present = self
+            .nodes
+            .iter()
+            .map(|node| match node.id.as_str() {
+                "" => node.address.clone(),
+                id => format!("{} ({})", id, node.address),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        Err(format!(
+            "Only {} of {} nodes announced themselves, {} missing; present: [{}]",
+            self.nodes.len(),
+            self.expected,
+            self.missing(),
+            present
+        ))
+    }
+}
+
+// Wait for nodes to be ready, until `number_of_nodes` nodes announced themselves or
+// `timeout` expires, if any
+pub async fn wait_for_nodes(
+    client: &IggyClient,
+    topology: &Topology,
+    number_of_nodes: usize,
+    timeout: Option<Duration>,
+    dead_letters: &DeadLetters,
+) -> Result<Membership, IggyError> {
+    let mut nodes: Vec<Node> = Vec::new();
+    let consumer = Consumer::new(Identifier::named("master").unwrap());
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    while nodes.len() < number_of_nodes && deadline.is_none_or(|d| Instant::now() < d) {
         let polled_messages = client
             .poll_messages(
-                &STREAM_ID.try_into()?,
-                &TOPIC_ID.try_into()?,
-                Some(BROADCAST_PARTITION_ID),
-                &Consumer::new(Identifier::named("master").unwrap()),
+                &topology.stream_id.try_into()?,
+                &topology.topic_id.try_into()?,
+                Some(topology.announce_partition),
+                &consumer,
                 &PollingStrategy::next(),
                 1,
                 true,
@@ -105,88 +330,502 @@ pub async fn receive_message(client: &IggyClient) -> Result<Message, IggyError>
             .await?;
 
         if polled_messages.messages.is_empty() {
+            sleep(ANNOUNCE_POLL_INTERVAL).await;
             continue;
         }
 
-        let deserialized =
-            serde_json::from_slice::<Message>(&polled_messages.messages[0].payload).unwrap();
-        return Ok(deserialized);
+        info!("Polled {} messages", polled_messages.messages.len());
+
+        for message in polled_messages.messages {
+            add_announce(&mut nodes, &message.payload, dead_letters);
+        }
     }
+
+    if nodes.len() < number_of_nodes {
+        warn!(
+            "Only {} of {} nodes announced themselves",
+            nodes.len(),
+            number_of_nodes
+        );
+    }
+    Ok(Membership {
+        expected: number_of_nodes,
+        nodes,
+    })
 }
 
-// Wait for nodes to be ready
-pub async fn wait_for_nodes(
+// Add the nodes of an announce. An announce that cannot be decoded is recorded in the
+// dead letter log.
+fn add_announce(nodes: &mut Vec<Node>, raw: &[u8], dead_letters: &DeadLetters) {
+    let msg = match decode(raw) {
+        Ok(msg) => msg,
+        Err(e) => {
+            error!("Cannot decode an announce: {}", e);
+            if let Err(e) = dead_letters.record(&e) {
+                error!("Cannot record the announce in the dead letter log: {}", e);
+            }
+            return;
+        }
+    };
+
+    match msg.payload {
+        Some(Payload::Nodes(tmp)) => {
+            for node in tmp {
+                add_announced(nodes, node);
+            }
+        }
+        _ => {
+            error!("Unexpected payload type");
+        }
+    }
+}
+
+// Add an announced node. A node announcing itself again, even from a new address,
+// replaces its previous announce, unless it was sent before the last start of the node.
+fn add_announced(nodes: &mut Vec<Node>, node: Node) {
+    match nodes.iter_mut().find(|n| n.is_same(&node)) {
+        Some(previous) if previous.incarnation > node.incarnation => {
+            info!(
+                "Ignoring an announce of {} older than its last start",
+                node.address
+            );
+        }
+        Some(previous) => *previous = node,
+        None => nodes.push(node),
+    }
+}
+
+// Gather the stats reports of an epoch, sent by the nodes on the announce partition,
+// until `number_of_nodes` nodes reported or `timeout` expires.
+// Reports of the other epochs, e.g. of a straggler, are dropped.
+pub async fn gather_stats(
     client: &IggyClient,
-    number_of_nodes: i32,
-) -> Result<Vec<Node>, IggyError> {
-    let mut nodes: Vec<Node> = Vec::new();
+    topology: &Topology,
+    epoch: u64,
+    number_of_nodes: usize,
+    timeout: Duration,
+    dead_letters: &DeadLetters,
+) -> Result<Vec<StatsReport>, IggyError> {
+    let mut reports: Vec<StatsReport> = Vec::new();
     let consumer = Consumer::new(Identifier::named("master").unwrap());
+    let deadline = Instant::now() + timeout;
 
-    loop {
+    while reports.len() < number_of_nodes && Instant::now() < deadline {
         let polled_messages = client
             .poll_messages(
-                &STREAM_ID.try_into()?,
-                &TOPIC_ID.try_into()?,
-                Some(ANNOUNCE_PARTITION_ID),
+                &topology.stream_id.try_into()?,
+                &topology.topic_id.try_into()?,
+                Some(topology.announce_partition),
                 &consumer,
                 &PollingStrategy::next(),
-                1,
+                10,
                 true,
             )
             .await?;
 
         if polled_messages.messages.is_empty() {
+            sleep(STATS_POLL_INTERVAL).await;
             continue;
         }
 
-        info!("Polled {} messages", polled_messages.messages.len());
-
         for message in polled_messages.messages {
-            let msg =
-                serde_json::from_str::<Message>(std::str::from_utf8(&message.payload).unwrap());
-
-            if msg.is_err() {
-                continue;
-            }
-
-            let msg = msg.unwrap();
+            let msg = match decode(&message.payload) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("Cannot decode a stats report: {}", e);
+                    if let Err(e) = dead_letters.record(&e) {
+                        error!("Cannot record the report in the dead letter log: {}", e);
+                    }
+                    continue;
+                }
+            };
 
             match msg.payload {
-                Some(Payload::Nodes(tmp)) => {
-                    nodes.extend(tmp);
-                }
-                _ => {
-                    error!("Unexpected payload type");
+                Some(Payload::StatsReport(report)) if report.epoch == epoch => {
+                    // A node reports once per epoch, a duplicate replaces the first one
+                    reports.retain(|other| !other.node.is_same(&report.node));
+                    reports.push(report);
                 }
+                Some(Payload::StatsReport(report)) => warn!(
+                    "Dropped the stats of epoch {} of {}, gathering epoch {}",
+                    report.epoch, report.node.address, epoch
+                ),
+                _ => error!("Unexpected payload type"),
             }
         }
+    }
 
-        if nodes.len() == number_of_nodes as usize {
-            return Ok(nodes);
-        }
+    if reports.len() < number_of_nodes {
+        warn!(
+            "Only {} of {} nodes reported the stats of epoch {}",
+            reports.len(),
+            number_of_nodes,
+            epoch
+        );
     }
+    Ok(reports)
 }
 
-pub async fn start_emergency(client: &IggyClient, emergency: Emergency) -> Result<(), IggyError> {
+// Tell the nodes which topology is used, so a misconfigured node stops instead of hanging
+pub async fn send_hello(client: &IggyClient, topology: &Topology) -> Result<(), IggyError> {
+    send_message(
+        client,
+        topology,
+        Message::new(Operation::HELLO, Some(Payload::Topology(*topology))),
+    )
+    .await
+}
+
+pub async fn start_emergency(
+    client: &IggyClient,
+    topology: &Topology,
+    emergency: Emergency,
+) -> Result<(), IggyError> {
     send_message(
         &client,
-        BROADCAST_PARTITION_ID,
-        Message {
-            op: Operation::START_EMERGENCY,
-            payload: Some(Payload::Emergency(emergency)),
-        },
+        topology,
+        Message::new(
+            Operation::START_EMERGENCY,
+            Some(Payload::Emergency(emergency)),
+        ),
     )
     .await
 }
 
-pub async fn stop_emergency(client: &IggyClient) -> Result<(), IggyError> {
+pub async fn stop_emergency(client: &IggyClient, topology: &Topology) -> Result<(), IggyError> {
     send_message(
         &client,
-        BROADCAST_PARTITION_ID,
-        Message {
-            op: Operation::STOP_EMERGENCY,
-            payload: None,
-        },
+        topology,
+        Message::new(Operation::STOP_EMERGENCY, None),
     )
     .await
 }
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_malformed() {
+        for raw in [
+            &b"not json"[..],
+            br#"{"payload":null}"#,
+            br#"{"v":1,"op":"ANNOUNCE","payload":{"Nodes":"node"}}"#,
+        ] {
+            match decode(raw) {
+                Err(e @ MessageError::Malformed { .. }) => assert_eq!(e.raw(), Some(raw)),
+                _ => panic!("{} is malformed", String::from_utf8_lossy(raw)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_versions() {
+        // Announces of the nodes built before the versioning
+        let message =
+            decode(br#"{"op":"ANNOUNCE","payload":{"Nodes":[{"address":"10.0.0.1:8085","position":[0.0,0.0]}]}}"#)
+                .unwrap();
+        assert_eq!(message.v, 0);
+        assert!(matches!(message.payload, Some(Payload::Nodes(nodes)) if nodes.len() == 1));
+
+        let raw = serde_json::to_vec(&Message::new(Operation::END, None)).unwrap();
+        assert_eq!(decode(&raw).unwrap().v, MESSAGE_VERSION);
+
+        assert!(matches!(
+            decode(br#"{"v":3,"op":"ANNOUNCE","payload":null}"#),
+            Err(MessageError::Version { version: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_decode_capabilities() {
+        // The capabilities announced by a node are forwarded to the others as they are
+        let raw = br#"{"v":2,"op":"ANNOUNCE","payload":{"Nodes":[{"id":"a","address":"10.0.0.1:8085","position":[0.0,0.0],"incarnation":1,"capabilities":["arch:x86_64","gpu"]}]}}"#;
+        let nodes = match decode(raw).unwrap().payload {
+            Some(Payload::Nodes(nodes)) => nodes,
+            _ => panic!("An announce carries nodes"),
+        };
+        assert_eq!(nodes[0].capabilities, vec!["arch:x86_64", "gpu"]);
+        let forwarded = Message::new(Operation::ADD_NODES, Some(Payload::Nodes(nodes)));
+        let json = serde_json::to_value(&forwarded).unwrap();
+        assert_eq!(
+            json["payload"]["Nodes"][0]["capabilities"],
+            serde_json::json!(["arch:x86_64", "gpu"])
+        );
+
+        // The older nodes announce none
+        let raw = br#"{"v":1,"op":"ANNOUNCE","payload":{"Nodes":[{"address":"10.0.0.1:8085","position":[0.0,0.0]}]}}"#;
+        match decode(raw).unwrap().payload {
+            Some(Payload::Nodes(nodes)) => assert!(nodes[0].capabilities.is_empty()),
+            _ => panic!("An announce carries nodes"),
+        }
+    }
+
+    #[test]
+    fn test_decode_positionless() {
+        // A node started with --positionless announces none, and is forwarded with none
+        let raw = br#"{"v":2,"op":"ANNOUNCE","payload":{"Nodes":[{"id":"a","address":"10.0.0.1:8085","position":null,"incarnation":1}]}}"#;
+        let nodes = match decode(raw).unwrap().payload {
+            Some(Payload::Nodes(nodes)) => nodes,
+            _ => panic!("An announce carries nodes"),
+        };
+        assert_eq!(nodes[0].position, None);
+        let forwarded = Message::new(Operation::ADD_NODES, Some(Payload::Nodes(nodes)));
+        let json = serde_json::to_value(&forwarded).unwrap();
+        assert!(json["payload"]["Nodes"][0]["position"].is_null());
+    }
+
+    #[test]
+    fn test_encode_emergency() {
+        let json = |emergency| {
+            serde_json::to_string(&Message::new(
+                Operation::START_EMERGENCY,
+                Some(Payload::Emergency(emergency)),
+            ))
+            .unwrap()
+        };
+        // A circle is sent as before the polygons
+        assert!(json(Emergency {
+            position: Some((45.4685, 9.1824)),
+            radius: Some(1000.0),
+            polygon: None,
+        })
+        .contains(r#"{"Emergency":{"position":[45.4685,9.1824],"radius":1000.0}}"#));
+        // A polygon only with its vertices, the nodes compute the rest
+        assert!(json(Emergency {
+            position: None,
+            radius: None,
+            polygon: Some(vec![(45.46, 9.18), (45.46, 9.19), (45.47, 9.19)]),
+        })
+        .contains(r#"{"Emergency":{"polygon":[[45.46,9.18],[45.46,9.19],[45.47,9.19]]}}"#));
+    }
+
+    #[test]
+    fn test_decode_unknown_operation() {
+        let raw = br#"{"v":1,"op":"REBALANCE","payload":null}"#;
+        match decode(raw) {
+            Err(e @ MessageError::UnknownOperation { .. }) => {
+                assert_eq!(e.to_string(), "Unknown operation REBALANCE")
+            }
+            _ => panic!("REBALANCE is not an operation"),
+        }
+    }
+
+    #[test]
+    fn test_add_announced() {
+        let node = |id: &str, address: &str, incarnation| Node {
+            id: id.to_string(),
+            incarnation,
+            ..Node::point(address)
+        };
+        let mut nodes = vec![];
+        add_announced(&mut nodes, node("a", "10.0.0.1:8085", 1));
+        // The node announced itself twice
+        add_announced(&mut nodes, node("a", "10.0.0.1:8085", 1));
+        // The node restarted with another address, then the announce from before the
+        // restart arrives late
+        add_announced(&mut nodes, node("a", "10.0.0.9:8085", 2));
+        add_announced(&mut nodes, node("a", "10.0.0.1:8085", 1));
+        // Another node took its old address
+        add_announced(&mut nodes, node("b", "10.0.0.1:8085", 1));
+        // An older node is known by its address
+        add_announced(&mut nodes, node("", "10.0.0.2:8085", 0));
+        add_announced(&mut nodes, node("", "10.0.0.2:8085", 0));
+        let nodes: Vec<_> = nodes
+            .iter()
+            .map(|n| (n.id.as_str(), n.address.as_str()))
+            .collect();
+        assert_eq!(
+            nodes,
+            vec![
+                ("a", "10.0.0.9:8085"),
+                ("b", "10.0.0.1:8085"),
+                ("", "10.0.0.2:8085")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_stats_report() {
+        // As sent by a node
+        let raw = br#"{"v":1,"op":"STATS_REPORT","payload":{"StatsReport":{"node":{"address":"10.0.0.1:8085","position":[45.4642,9.19],"tls":false},"epoch":3,"stats":{"hops_avg":0.5,"vcpus":4,"memory":1024,"requests":2,"received":3,"offloaded":1}}}}"#;
+        let message = decode(raw).unwrap();
+        assert!(message.op == Operation::STATS_REPORT);
+        match message.payload {
+            Some(Payload::StatsReport(report)) => {
+                assert_eq!(report.node.address, "10.0.0.1:8085");
+                assert_eq!(report.epoch, 3);
+                assert_eq!(report.stats.offloaded, 1);
+                assert_eq!(report.record(), "3,10.0.0.1:8085,,0.5,4,1024,2,3,1");
+            }
+            _ => panic!("A stats report is decoded as such"),
+        }
+
+        // The reports of version 2 carry the id of the node
+        let raw = br#"{"v":2,"op":"STATS_REPORT","payload":{"StatsReport":{"node":{"id":"7f1c","address":"10.0.0.1:8085","position":[45.4642,9.19],"tls":false},"epoch":3,"stats":{"hops_avg":0.5,"vcpus":4,"memory":1024,"requests":2,"received":3,"offloaded":1}}}}"#;
+        match decode(raw).unwrap().payload {
+            Some(Payload::StatsReport(report)) => {
+                assert_eq!(report.record(), "3,10.0.0.1:8085,7f1c,0.5,4,1024,2,3,1")
+            }
+            _ => panic!("A stats report is decoded as such"),
+        }
+
+        let report = Message::new(Operation::STATS_REPORT, None);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["op"], "STATS_REPORT");
+    }
+
+    #[test]
+    fn test_dead_letters() {
+        let path = std::env::temp_dir().join(format!("benchmark-dead-{}", std::process::id()));
+        let dead_letters = DeadLetters::new(path.clone(), 256);
+        let error = decode(b"not json").err().unwrap();
+        for _ in 0..10 {
+            dead_letters.record(&error).unwrap();
+        }
+        let entry: serde_json::Value =
+            serde_json::from_str(fs::read_to_string(&path).unwrap().lines().next().unwrap())
+                .unwrap();
+        assert_eq!(entry["payload"], "not json");
+        // The file was rotated, so neither file grows past the cap by more than one entry
+        assert!(fs::metadata(&path).unwrap().len() < 512);
+        assert!(fs::metadata(dead_letters.rotated_path()).unwrap().len() < 512);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(dead_letters.rotated_path()).unwrap();
+    }
+
+    #[test]
+    fn test_routing_to_the_nodes() {
+        use crate::{epoch_bound, ChaosCommand, Period};
+        use ohsw::net::iggy::{self as node, ConsumerConfig, Phase};
+
+        // Both sides share the same defaults
+        assert_eq!(
+            serde_json::to_value(Topology::default()).unwrap(),
+            serde_json::to_value(node::Topology::default()).unwrap()
+        );
+        let topology = Topology {
+            broadcast_partition: 3,
+            ..Default::default()
+        };
+        // The consumers of a node configured like the benchmark
+        let consumers = [Phase::Registration, Phase::Broadcast].map(|phase| {
+            ConsumerConfig::new("10.0.0.1:8085", phase).with_partition(topology.broadcast_partition)
+        });
+
+        // Every message sent by the benchmark, with the phase of the node handling it
+        let messages = [
+            (
+                Message::new(Operation::HELLO, Some(Payload::Topology(topology))),
+                Phase::Registration,
+            ),
+            (
+                Message::new(
+                    Operation::ADD_NODES,
+                    Some(Payload::Nodes(vec![Node::point("10.0.0.1:8085")])),
+                ),
+                Phase::Registration,
+            ),
+            (
+                Message::new(
+                    Operation::START_EMERGENCY,
+                    Some(Payload::Emergency(Emergency {
+                        position: Some((45.4685, 9.1824)),
+                        radius: Some(1000.0),
+                        polygon: None,
+                    })),
+                ),
+                Phase::Broadcast,
+            ),
+            (
+                Message::new(Operation::STOP_EMERGENCY, None),
+                Phase::Broadcast,
+            ),
+            (
+                Message::new(
+                    Operation::WRITE_STATS,
+                    Some(Payload::Period(Period {
+                        start: epoch_bound(),
+                        end: epoch_bound(),
+                    })),
+                ),
+                Phase::Broadcast,
+            ),
+            (
+                Message::new(
+                    Operation::CHAOS,
+                    Some(Payload::Chaos(ChaosCommand {
+                        nodes: vec!["a".to_string()],
+                        action: serde_json::json!("clear"),
+                    })),
+                ),
+                Phase::Broadcast,
+            ),
+            (Message::new(Operation::END, None), Phase::Broadcast),
+        ];
+        for (message, phase) in messages {
+            let (partition, raw) = outgoing(&topology, &message);
+            for consumer in &consumers {
+                // The node decodes every message, even those it skips
+                let routed = consumer.route(raw.as_bytes()).unwrap().is_some();
+                assert_eq!(
+                    partition == consumer.partition && routed,
+                    consumer.phase == phase,
+                    "{} polled by {}",
+                    raw,
+                    consumer.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_missing_announces() {
+        let path = std::env::temp_dir().join(format!("benchmark-announce-{}", std::process::id()));
+        let dead_letters = DeadLetters::new(path.clone(), 1024);
+        let mut nodes = vec![];
+        // Three nodes are expected, one announce is lost and another cannot be decoded
+        add_announce(
+            &mut nodes,
+            br#"{"v":2,"op":"ADD_NODES","payload":{"Nodes":[{"id":"a","address":"10.0.0.1:8085","position":[0.0,0.0],"incarnation":1}]}}"#,
+            &dead_letters,
+        );
+        add_announce(&mut nodes, b"not json", &dead_letters);
+        add_announce(
+            &mut nodes,
+            br#"{"op":"ADD_NODES","payload":{"Nodes":[{"address":"10.0.0.2:8085","position":[0.0,0.0]}]}}"#,
+            &dead_letters,
+        );
+        // A node announced twice is counted once
+        add_announce(
+            &mut nodes,
+            br#"{"v":2,"op":"ADD_NODES","payload":{"Nodes":[{"id":"a","address":"10.0.0.1:8085","position":[0.0,0.0],"incarnation":1}]}}"#,
+            &dead_letters,
+        );
+        let membership = Membership { expected: 3, nodes };
+        assert_eq!(membership.missing(), 1);
+        let partial = Membership {
+            expected: 3,
+            nodes: membership.nodes.clone(),
+        };
+        assert_eq!(
+            membership.check(false).unwrap_err(),
+            "Only 2 of 3 nodes announced themselves, 1 missing; present: [a (10.0.0.1:8085), 10.0.0.2:8085]"
+        );
+        let partial = partial.check(true).unwrap();
+        assert_eq!(partial.nodes.len(), 2);
+        assert_eq!(
+            serde_json::to_value(&partial).unwrap()["expected"],
+            serde_json::json!(3)
+        );
+        // Every node announced itself
+        let complete = Membership {
+            expected: 2,
+            nodes: partial.nodes,
+        };
+        assert_eq!(complete.missing(), 0);
+        assert!(complete.check(false).is_ok());
+        fs::remove_file(&path).unwrap();
+    }
+}
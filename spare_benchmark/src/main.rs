@@ -15,24 +15,36 @@ use base64::{engine::general_purpose, Engine};
 use clap::Parser;
 
 use iggy::{
-    client::{Client, UserClient},
+    client::{Client, PersonalAccessTokenClient, UserClient},
     clients::client::IggyClient,
-    users::defaults::{DEFAULT_ROOT_PASSWORD, DEFAULT_ROOT_USERNAME},
 };
 use log::{error, info};
 use longitude::Location;
-use rand::distr::Distribution;
-use rand::distr::Uniform;
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
 use tokio::{fs, sync::Mutex, time::sleep};
 
+mod chaos;
+use chaos::*;
+
+mod clients;
+use clients::*;
+
 mod dataset;
 use dataset::*;
 
 mod iggy_client;
 use iggy_client::*;
 
+mod requests;
+use requests::*;
+
+mod retry;
+use retry::*;
+
+mod served;
+use served::*;
+
 // Args for the CLI
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -48,25 +60,165 @@ struct Args {
     emergency_radius: f64, // Radius in meters
 
     // Path for the dataset
-    #[arg(short, long)]
-    dataset: String,
+    #[arg(short, long, required_unless_present = "merge")]
+    dataset: Option<String>,
 
     #[arg(short, long, default_value = "10")]
     iterations: i32,
 
     #[arg(short, long, default_value = "")]
     payload: String,
+
+    /// Stream shared with the nodes
+    #[arg(long, default_value_t = STREAM_ID)]
+    stream_id: u32,
+
+    /// Topic shared with the nodes
+    #[arg(long, default_value_t = TOPIC_ID)]
+    topic_id: u32,
+
+    /// Partition the nodes announce themselves on
+    #[arg(long, default_value_t = ANNOUNCE_PARTITION_ID)]
+    announce_partition: u32,
+
+    /// Partition the operations are broadcast on
+    #[arg(long, default_value_t = BROADCAST_PARTITION_ID)]
+    broadcast_partition: u32,
+
+    /// Username used to log in to the broker
+    #[arg(long, default_value = "iggy")]
+    broker_username: String,
+
+    /// Password used to log in to the broker
+    #[arg(long, default_value = "iggy")]
+    broker_password: String,
+
+    /// Personal access token used to log in to the broker, instead of username and password
+    #[arg(long)]
+    broker_token: Option<String>,
+
+    /// File the messages that cannot be decoded are logged to
+    #[arg(long, default_value = "benchmark_dead_letters.jsonl")]
+    dead_letter_file: String,
+
+    /// Size after which the dead letter file is rotated (in bytes)
+    #[arg(long, default_value = "1048576")]
+    dead_letter_max_size: u64,
+
+    /// First backoff after an overloaded response, doubled at every retry (in ms)
+    #[arg(long, default_value = "100")]
+    backoff_base: u64,
+
+    /// Longest backoff after an overloaded response (in ms)
+    #[arg(long, default_value = "5000")]
+    backoff_max: u64,
+
+    /// Retries of an overloaded request before giving up
+    #[arg(long, default_value = "10")]
+    max_overload_retries: u32,
+
+    /// Retries after a server or connection error before giving up
+    #[arg(long, default_value = "3")]
+    max_retries: u32,
+
+    /// Longest wait for the stats reports of the nodes after each epoch (in ms)
+    #[arg(long, default_value = "5000")]
+    stats_timeout: u64,
+
+    /// JSON file with the chaos commands to send at the start of some epochs, e.g.
+    /// `[{"epoch": 2, "nodes": ["node-a"], "action": {"inject": {"kind": "drop_invokes",
+    /// "percent": 20.0}}}, {"epoch": 4, "action": "clear"}]`; the nodes must run with --chaos
+    #[arg(long)]
+    chaos_script: Option<String>,
+
+    /// JSON file with the vertices of the emergency zone in the order of its boundary and
+    /// of the coordinates of the dataset, e.g. `[[45.46, 9.18], [45.46, 9.19], [45.47, 9.19]]`;
+    /// the zone is then this polygon instead of a circle of --emergency-radius around a
+    /// random point. The nodes refuse a polygon crossing itself or smaller than 100 m²
+    #[arg(long)]
+    emergency_polygon: Option<String>,
+
+    /// Longest wait for the nodes to announce themselves (in s), 0 to wait forever
+    #[arg(long, default_value = "0")]
+    registration_timeout: u64,
+
+    /// Proceed with the nodes that announced themselves before --registration-timeout,
+    /// instead of aborting
+    #[arg(long)]
+    allow_partial: bool,
+
+    /// Instead of running a test, join the requests of the last one (requests.ndjson) with
+    /// the exports of /export/instances of the nodes in this directory, one file per node
+    /// named after its id or address, into requests_merged.ndjson
+    #[arg(long)]
+    merge: Option<String>,
+
+    /// How the clients choose the node of each request: nearest (the node closest to a
+    /// vehicle of the dataset), uniform or zipf
+    #[arg(long, default_value = "uniform")]
+    client_model: ClientModel,
+
+    /// Exponent of the popularity of the nodes with --client-model zipf, the larger the
+    /// more skewed
+    #[arg(long, default_value = "1.0")]
+    zipf_exponent: f64,
+}
+
+// Stats reports of the nodes, gathered after each epoch
+struct NodeStats {
+    dead_letters: DeadLetters,
+    timeout: Duration,
+    // Epochs are counted across the scenarios, as the nodes do
+    epoch: u64,
+    reports: Vec<StatsReport>,
+    // Chaos commands to send, by epoch
+    chaos_script: Vec<ScriptedChaos>,
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 struct Node {
+    // Stable id of the node, missing in the announces of the older nodes
+    #[serde(default)]
+    id: String,
     address: String, // Ip:Port
-    position: (f64, f64),
+    // None for the nodes started with --positionless, they keep none
+    #[serde(default)]
+    position: Option<(f64, f64)>,
+    // Starts of the node, missing in the announces of the older nodes
+    #[serde(default)]
+    incarnation: u64,
+    // What the node can run, e.g. arch:x86_64, gpu or mem:8192, forwarded to the others
+    #[serde(default)]
+    capabilities: Vec<String>,
 }
 impl Node {
+    // Node named `address`, its position is generated afterwards
+    fn point(address: &str) -> Self {
+        Self {
+            id: String::new(),
+            address: address.to_string(),
+            position: Some((0.0, 0.0)),
+            incarnation: 0,
+            capabilities: Vec::new(),
+        }
+    }
+
+    // Check if `other` is the same node, maybe announced from another address
+    fn is_same(&self, other: &Node) -> bool {
+        if self.id.is_empty() || other.id.is_empty() {
+            self.address == other.address
+        } else {
+            self.id == other.id
+        }
+    }
+
+    // A node without a position is infinitely far from everything
     fn distance(&self, other: &Self) -> f64 {
-        let location_a = Location::from(self.position.0, self.position.1);
-        let location_b = Location::from(other.position.0, other.position.1);
+        let (Some(a), Some(b)) = (self.position, other.position) else {
+            return f64::INFINITY;
+        };
+        let location_a = Location::from(a.0, a.1);
+        let location_b = Location::from(b.0, b.1);
 
         location_a.distance(&location_b).meters()
     }
@@ -78,36 +230,123 @@ enum Payload {
     Nodes(Vec<Node>),
     Emergency(Emergency),
     Period(Period),
+    Topology(Topology),
+    StatsReport(StatsReport),
+    Chaos(ChaosCommand),
+}
+
+// Stats of an epoch of a node, as computed by the node
+#[derive(Deserialize, Serialize)]
+struct Stats {
+    hops_avg: f64,
+    vcpus: i64,
+    memory: i64,
+    requests: i64,
+    received: i64,
+    offloaded: i64,
+}
+
+// Stats of an epoch, sent by each node after WRITE_STATS
+#[derive(Deserialize, Serialize)]
+struct StatsReport {
+    node: Node,
+    epoch: u64,
+    stats: Stats,
+}
+
+// Header of the per-node stats CSV
+const STATS_HEADER: &str = "Epoch,Node,Id,Hops Avg,Vcpus,Memory,Requests,Received,Offloaded";
+
+impl StatsReport {
+    // Record of the report in the per-node stats CSV
+    fn record(&self) -> String {
+        format!(
+            "{},{},{},{},{},{},{},{},{}",
+            self.epoch,
+            self.node.address,
+            self.node.id,
+            self.stats.hops_avg,
+            self.stats.vcpus,
+            self.stats.memory,
+            self.stats.requests,
+            self.stats.received,
+            self.stats.offloaded
+        )
+    }
 }
 
 #[derive(Deserialize, Serialize)]
 struct Message {
+    // Version of the schema, missing in the messages sent before the versioning
+    #[serde(default)]
+    v: u8,
     op: Operation,
     payload: Option<Payload>,
 }
 
+impl Message {
+    fn new(op: Operation, payload: Option<Payload>) -> Self {
+        Self {
+            v: MESSAGE_VERSION,
+            op,
+            payload,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 struct Emergency {
-    /// The position of the emergency point
-    position: (f64, f64),
-    /// The radius of the emergency point
-    radius: f64,
+    /// The position of the emergency point, None for a polygon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    position: Option<(f64, f64)>,
+    /// The radius of the emergency point, None for a polygon
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    radius: Option<f64>,
+    /// The vertices of the zone, the nodes compute its point and radius from them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    polygon: Option<Vec<(f64, f64)>>,
 }
 
+/// Window of an epoch, in RFC 3339 UTC
 #[derive(Deserialize, Serialize)]
 struct Period {
     start: String,
     end: String,
 }
 
+/// The current time as a bound of an epoch. The nodes count the requests of the epoch by
+/// their own clock, which must be kept in sync with this one by NTP.
+fn epoch_bound() -> String {
+    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+/// Time the answer of a request is waited for
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Requests sent in an epoch to `nodes` nodes, for a 100% load
+fn requests_per_epoch(nodes: usize) -> usize {
+    ((8 * nodes) as f32 * 0.8).floor() as usize
+}
+
+// Average latency, outcomes and hops of the completed requests of an epoch, with the
+// requests sent to each node
+type EpochResult = (u128, Outcomes, HopHistogram, Vec<usize>);
+
+#[allow(clippy::too_many_arguments)]
 async fn test(
     client: &IggyClient,
+    topology: &Topology,
+    scenario: &str,
     iterations: i32,
     nodes: Vec<Node>,
+    picker: &TargetPicker,
     function_path: &String,
     payload: &Option<String>,
-) -> (u128, usize, usize, Vec<u128>) {
-    let request_per_epoch = ((8 * nodes.len()) as f32 * 0.8).floor() as usize; // 100% Load
+    policy: RetryPolicy,
+    node_stats: &mut NodeStats,
+    requests: &Arc<RequestWriter>,
+) -> (u128, usize, usize, Vec<EpochResult>) {
+    let request_per_epoch = requests_per_epoch(nodes.len());
 
     let inter_arrival = 11; // ms
     let mut latency_per_epoch = Vec::new();
@@ -119,20 +358,42 @@ async fn test(
     for i in 0..iterations {
         println!("Iteration: {}", i);
         let latency_per_epoch_tmp = Arc::new(Mutex::new(Vec::new()));
+        let outcomes = Arc::new(Mutex::new(Outcomes::default()));
+        let hops = Arc::new(Mutex::new(HopHistogram::default()));
         let mut handles = Vec::new();
+        let mut targets = vec![0; nodes.len()];
+
+        for command in due(&node_stats.chaos_script, node_stats.epoch) {
+            info!(
+                "Sending chaos command of epoch {}: {:?}",
+                node_stats.epoch, command
+            );
+            send_message(
+                client,
+                topology,
+                Message::new(Operation::CHAOS, Some(Payload::Chaos(command.clone()))),
+            )
+            .await
+            .unwrap();
+        }
 
-        let uniform_distribution = Uniform::new(0, nodes.len()).unwrap();
-
-        let start_time = chrono::Utc::now().naive_utc().to_string();
-        for _j in 0..(request_per_epoch) {
+        let start_time = epoch_bound();
+        for j in 0..(request_per_epoch) {
             let latency_per_epoch_tmp_copy = Arc::clone(&latency_per_epoch_tmp);
             let latency_tmp = Arc::clone(&latency);
-            let node = nodes.get(uniform_distribution.sample(&mut rng)).unwrap();
+            let target = picker.pick(&mut rng);
+            targets[target] += 1;
+            let node = nodes.get(target).unwrap();
             let address = node.address.clone();
 
             let completed_tmp = Arc::clone(&completed);
             let failed_tmp = Arc::clone(&failed);
+            let outcomes_tmp = Arc::clone(&outcomes);
+            let hops_tmp = Arc::clone(&hops);
             let function_path_tmp = function_path.clone();
+            let requests_tmp = Arc::clone(requests);
+            let request_id = requests.request_id(scenario, i as usize, j);
+            let scenario = scenario.to_string();
 
             let payload_clone = payload.clone();
             sleep(Duration::from_millis(inter_arrival)).await; // Inter-arrival time
@@ -151,54 +412,98 @@ async fn test(
                     payload: payload_clone,
                     emergency: false,
                     hops: 0,
+                    // The retries of a request share its id
+                    idempotency_key: Some(request_id.clone()),
+                    timeout_ms: Some(REQUEST_TIMEOUT.as_millis() as u64),
                 };
 
+                let timestamp = epoch_bound();
                 let mut total_time = 0;
-                loop {
+                let mut retries = 0;
+                // Where the request ran, as told on the last answer
+                let mut served;
+                let outcome = loop {
                     let start = Instant::now();
                     let req: Result<reqwest::Response, reqwest::Error> = web_client
                         .post(
                             Url::from_str(&format!("http://{}/invoke", address).as_str()).unwrap(),
                         )
                         .json(&invoke_function)
-                        .timeout(Duration::from_secs(60))
+                        .timeout(REQUEST_TIMEOUT)
                         .send()
                         .await;
 
                     let end = Instant::now();
 
-                    match req {
+                    total_time += end.duration_since(start).as_millis();
+
+                    let class = match req {
                         Ok(res) => {
+                            served = Served::from_headers(res.headers());
                             if res.status().is_success() {
-                                info!("Success");
-                                total_time += end.duration_since(start).as_millis();
+                                info!(
+                                    "Success, served by {} after {} hops",
+                                    served.by.as_deref().unwrap_or("unknown"),
+                                    served.hops.map_or("unknown".to_string(), |h| h.to_string())
+                                );
+                                hops_tmp.lock().await.record(served.hops);
                                 let mut latency_tmp = latency_tmp.lock().await;
                                 latency_tmp.push(total_time);
                                 latency_per_epoch_tmp_copy.lock().await.push(total_time);
 
                                 completed_tmp.fetch_add(1, Ordering::SeqCst);
-                                break;
-                            } else {
-                                error!("Error: {}", res.text().await.unwrap());
-                                sleep(Duration::from_millis(10)).await; // Retry after 10ms TODO: Revise this
-                                total_time += end.duration_since(start).as_millis();
-                                failed_tmp.fetch_add(1, Ordering::SeqCst);
-                                continue;
+                                break Outcome::Completed;
                             }
+                            let status = res.status().as_u16();
+                            let body = res.text().await.unwrap_or_default();
+                            error!("Error: {} {}", status, body);
+                            classify(status, &body)
                         }
                         Err(e) => {
+                            served = Served::default();
                             error!("Error: {}!", e);
                             if e.is_timeout() {
-                                error!("Timeout! Now trying again...");
+                                error!("Timeout!");
                             } else if e.is_connect() {
-                                error!("Connection error! Now trying again...");
+                                error!("Connection error!");
                             } else if e.is_redirect() {
-                                error!("Redirect error! Now trying again...");
+                                error!("Redirect error!");
                             }
-                            continue;
+                            ErrorClass::Transport
+                        }
+                    };
+
+                    // Overloaded nodes are given time to recover, invalid requests are not retried
+                    match policy.decide(class, retries) {
+                        Decision::Retry(delay) => {
+                            retries += 1;
+                            sleep(delay).await;
+                        }
+                        Decision::Fail => {
+                            error!("Giving up after {} retries: {}", retries, class.name());
+                            failed_tmp.fetch_add(1, Ordering::SeqCst);
+                            break Outcome::Failed(class);
                         }
                     }
-                }
+                };
+                outcomes_tmp.lock().await.record(outcome);
+                let record = RequestRecord {
+                    request_id,
+                    scenario,
+                    epoch: i as usize,
+                    timestamp,
+                    target: address,
+                    latency_ms: total_time,
+                    attempts: retries + 1,
+                    outcome: match outcome {
+                        Outcome::Completed => "Completed".to_string(),
+                        Outcome::Failed(class) => class.name().to_string(),
+                    },
+                    served_by: None,
+                    hops: None,
+                    instance_id: None,
+                };
+                requests_tmp.write(&record.with_served(served));
             });
 
             handles.push(handle);
@@ -209,26 +514,47 @@ async fn test(
             handle.await.unwrap();
         }
 
-        let end_time = chrono::Utc::now().naive_utc().to_string();
+        let end_time = epoch_bound();
 
         sleep(Duration::from_millis(2500)).await;
 
         // Announce the end of an epoch
         send_message(
             &client,
-            BROADCAST_PARTITION_ID,
-            Message {
-                op: Operation::WRITE_STATS,
-                payload: Some(Payload::Period(Period {
+            topology,
+            Message::new(
+                Operation::WRITE_STATS,
+                Some(Payload::Period(Period {
                     start: start_time,
                     end: end_time,
                 })),
-            },
+            ),
         )
         .await
         .unwrap();
 
-        latency_per_epoch.push(
+        // The nodes keep the stats in a local file too, a missing report is not fatal
+        match gather_stats(
+            client,
+            topology,
+            node_stats.epoch,
+            nodes.len(),
+            node_stats.timeout,
+            &node_stats.dead_letters,
+        )
+        .await
+        {
+            Ok(reports) => node_stats.reports.extend(reports),
+            Err(e) => error!(
+                "Cannot gather the stats of epoch {}: {}",
+                node_stats.epoch, e
+            ),
+        }
+        node_stats.epoch += 1;
+
+        let outcomes = *outcomes.lock().await;
+        let hops = hops.lock().await.clone();
+        latency_per_epoch.push((
             latency_per_epoch_tmp
                 .clone()
                 .lock()
@@ -236,17 +562,23 @@ async fn test(
                 .iter()
                 .sum::<u128>()
                 / (request_per_epoch as u128),
-        );
+            outcomes,
+            hops,
+            targets,
+        ));
         println!(
-            "Epoch {} - Latency: {} ms",
+            "Epoch {} - Latency: {} ms, Completed: {}, Failed: {}",
             i,
-            latency_per_epoch.last().unwrap_or(&0).to_string()
+            latency_per_epoch.last().map(|(l, _, _, _)| *l).unwrap_or(0),
+            outcomes.completed,
+            request_per_epoch - outcomes.completed
         );
         sleep(Duration::from_millis(2500)).await;
     }
     let latency_tmp = latency.lock().await;
     let sum = latency_tmp.iter().sum::<u128>();
-    let avg = sum / latency_tmp.len() as u128;
+    // Every request may have failed
+    let avg = sum.checked_div(latency_tmp.len() as u128).unwrap_or(0);
 
     return (
         avg,
@@ -256,9 +588,33 @@ async fn test(
     );
 }
 
+// Record of an epoch in the per-epoch CSV files
+fn epoch_record(epoch: usize, latency: u128, outcomes: &Outcomes) -> String {
+    let failed = ErrorClass::ALL
+        .iter()
+        .map(|class| format!(",{}", outcomes.failed(*class)))
+        .collect::<String>();
+    format!("{},{},{}{}", epoch, latency, outcomes.completed, failed)
+}
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
+    // Parse arguments from CLI
+    let args = Args::parse();
+
+    // Post-processing of the last test, the nodes are not involved
+    if let Some(exports) = &args.merge {
+        match merge_files(REQUESTS_FILE, Path::new(exports), MERGED_FILE) {
+            Ok((total, joined)) => println!(
+                "{} of {} requests joined with their instance, written to {}",
+                joined, total, MERGED_FILE
+            ),
+            Err(e) => panic!("Cannot merge the requests: {}", e),
+        }
+        return;
+    }
+
     // Check environment variables
     // Fetch the function to execute from environment
     let function = env::var("SPARE_FUNCTION");
@@ -276,8 +632,6 @@ async fn main() {
         }
     };
 
-    // Parse arguments from CLI
-    let args = Args::parse();
     let client = IggyClient::builder()
         .with_tcp()
         .with_server_address(args.broker_address)
@@ -285,39 +639,98 @@ async fn main() {
         .unwrap();
 
     client.connect().await.unwrap();
-    client
-        .login_user(DEFAULT_ROOT_USERNAME, DEFAULT_ROOT_PASSWORD)
-        .await
-        .unwrap();
-
-    init_system(&client).await;
+    match &args.broker_token {
+        Some(token) => client
+            .login_with_personal_access_token(token)
+            .await
+            .map(|_| ()),
+        None => client
+            .login_user(&args.broker_username, &args.broker_password)
+            .await
+            .map(|_| ()),
+    }
+    .unwrap_or_else(|e| panic!("Cannot log in to the broker: {}", e));
 
-    let mut nodes = wait_for_nodes(&client, args.number_of_nodes).await.unwrap();
+    let topology = Topology {
+        stream_id: args.stream_id,
+        topic_id: args.topic_id,
+        announce_partition: args.announce_partition,
+        broadcast_partition: args.broadcast_partition,
+    };
+    init_system(&client, &topology).await;
+
+    let chaos_script = match &args.chaos_script {
+        Some(path) => std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|raw| parse_script(&raw).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| panic!("Cannot read the chaos script {}: {}", path, e)),
+        None => Vec::new(),
+    };
+    let emergency_polygon: Option<Vec<(f64, f64)>> = args.emergency_polygon.as_ref().map(|path| {
+        std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|raw| serde_json::from_str(&raw).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| panic!("Cannot read the emergency polygon {}: {}", path, e))
+    });
+
+    let dead_letters = DeadLetters::new(args.dead_letter_file.into(), args.dead_letter_max_size);
+    let registration_timeout =
+        Some(Duration::from_secs(args.registration_timeout)).filter(|t| !t.is_zero());
+    let membership = wait_for_nodes(
+        &client,
+        &topology,
+        args.number_of_nodes as usize,
+        registration_timeout,
+        &dead_letters,
+    )
+    .await
+    .unwrap()
+    .check(args.allow_partial)
+    .unwrap_or_else(|e| panic!("Cannot start the test: {}", e));
+
+    // Record the actual membership, which may be partial, before the test starts
+    let file_path_membership = "membership.json";
+    let manifest = serde_json::json!({
+        "expected": membership.expected,
+        "missing": membership.missing(),
+        "nodes": membership.nodes,
+        "request_per_epoch": requests_per_epoch(membership.nodes.len()),
+    });
+    std::fs::write(
+        file_path_membership,
+        serde_json::to_string_pretty(&manifest).unwrap(),
+    )
+    .unwrap();
+    let mut nodes = membership.nodes;
+    let mut node_stats = NodeStats {
+        dead_letters,
+        timeout: Duration::from_millis(args.stats_timeout),
+        epoch: 0,
+        reports: Vec::new(),
+        chaos_script,
+    };
 
     generate_points_from_csv(&mut nodes, "../data/edge_nodes.csv");
 
     // Generate random point for the emergency
-    let mut emergency = vec![Node {
-        address: "emergency".to_string(),
-        position: (0.0, 0.0),
-    }];
+    let mut emergency = vec![Node::point("emergency")];
     generate_points_from_csv(&mut emergency, "../data/edge_nodes.csv");
 
     let mut emergency = emergency.remove(0);
 
-    // if more than 1/3 of nodes are within the emergency area, recompute emergency point
-    while nodes
-        .iter()
-        .filter(|node| node.distance(&emergency) <= args.emergency_radius)
-        .count()
-        != (nodes.len() / 3)
+    // if more than 1/3 of nodes are within the emergency area, recompute emergency point.
+    // The nodes without a position are never within it. A polygon is used as it is
+    let positioned = nodes.iter().filter(|node| node.position.is_some()).count();
+    while emergency_polygon.is_none()
+        && nodes
+            .iter()
+            .filter(|node| node.distance(&emergency) <= args.emergency_radius)
+            .count()
+            != (positioned / 3)
     {
         println!("Recomputing emergency node");
         generate_points_from_csv(&mut nodes, "../data/edge_nodes.csv");
-        let mut tmp = vec![Node {
-            address: "emergency".to_string(),
-            position: (0.0, 0.0),
-        }];
+        let mut tmp = vec![Node::point("emergency")];
         generate_points_from_csv(&mut tmp, "../data/edge_nodes.csv");
         emergency = tmp.remove(0);
     }
@@ -332,13 +745,11 @@ async fn main() {
         );
     }
 
+    send_hello(&client, &topology).await.unwrap();
     send_message(
         &client,
-        BROADCAST_PARTITION_ID,
-        Message {
-            op: Operation::ADD_NODES,
-            payload: Some(Payload::Nodes(nodes.clone())),
-        },
+        &topology,
+        Message::new(Operation::ADD_NODES, Some(Payload::Nodes(nodes.clone()))),
     )
     .await
     .unwrap();
@@ -360,46 +771,117 @@ async fn main() {
         None
     };
 
+    // The clients are the vehicles of the dataset
+    let clients = match args.client_model {
+        ClientModel::Nearest => load_client_positions("../data/edge_nodes.csv"),
+        _ => Vec::new(),
+    };
+    let picker = TargetPicker::new(
+        args.client_model,
+        &nodes,
+        clients,
+        args.zipf_exponent,
+        &mut rand::rng(),
+    )
+    .unwrap_or_else(|e| {
+        panic!(
+            "Cannot use the {} client model: {}",
+            args.client_model.name(),
+            e
+        )
+    });
+
+    let policy = RetryPolicy {
+        backoff_base: Duration::from_millis(args.backoff_base),
+        backoff_max: Duration::from_millis(args.backoff_max),
+        max_overload_retries: args.max_overload_retries,
+        max_retries: args.max_retries,
+    };
+
     // EXPERIMENT
-    println!("Starting test with {} nodes", args.number_of_nodes);
+    println!(
+        "Starting test with {} of {} nodes",
+        nodes.len(),
+        args.number_of_nodes
+    );
     println!("NORMAL SCENARIO");
     let iterations = args.iterations;
 
-    let (avg_normal_latency, completed_normal, failed_normal, latency_per_epoch_normal) =
-        test(&client, iterations, nodes.clone(), &function_path, &payload).await;
+    let requests = Arc::new(
+        RequestWriter::create(REQUESTS_FILE)
+            .unwrap_or_else(|e| panic!("Cannot create {}: {}", REQUESTS_FILE, e)),
+    );
+    let (avg_normal_latency, completed_normal, failed_normal, latency_per_epoch_normal) = test(
+        &client,
+        &topology,
+        "Normal",
+        iterations,
+        nodes.clone(),
+        &picker,
+        &function_path,
+        &payload,
+        policy,
+        &mut node_stats,
+        &requests,
+    )
+    .await;
 
     println!("EMERGENCY SCENARIO");
-    let emergency = Emergency {
-        position: emergency.position,
-        radius: args.emergency_radius,
+    let emergency = match emergency_polygon {
+        Some(vertices) => {
+            println!("Emergency Polygon {:?}", vertices);
+            Emergency {
+                position: None,
+                radius: None,
+                polygon: Some(vertices),
+            }
+        }
+        None => {
+            let position = emergency
+                .position
+                .expect("The emergency point has a position");
+            println!(
+                "Emergency Position {:?} and Radius {}",
+                position, args.emergency_radius
+            );
+            Emergency {
+                position: Some(position),
+                radius: Some(args.emergency_radius),
+                polygon: None,
+            }
+        }
     };
-    println!(
-        "Emergency Position {:?} and Radius {}",
-        emergency.position, emergency.radius
-    );
 
-    start_emergency(&client, emergency).await.unwrap();
+    start_emergency(&client, &topology, emergency)
+        .await
+        .unwrap();
 
     // Wait for nodes to be ready
     sleep(Duration::from_secs(10)).await;
 
     let (avg_emergency_latency, completed_emergency, failed_emergency, latency_per_epoch_emergency) =
-        test(&client, iterations, nodes.clone(), &function_path, &payload).await;
+        test(
+            &client,
+            &topology,
+            "Emergency",
+            iterations,
+            nodes.clone(),
+            &picker,
+            &function_path,
+            &payload,
+            policy,
+            &mut node_stats,
+            &requests,
+        )
+        .await;
 
-    stop_emergency(&client).await.unwrap();
+    stop_emergency(&client, &topology).await.unwrap();
 
     // Compute average latency
 
-    send_message(
-        &client,
-        BROADCAST_PARTITION_ID,
-        Message {
-            op: Operation::END,
-            payload: None,
-        },
-    )
-    .await
-    .unwrap();
+    send_message(&client, &topology, Message::new(Operation::END, None))
+        .await
+        .unwrap();
 
     println!(
         "Normal Scenario - Average Latency: {} ms, Completed: {}, Failed: {}",
@@ -448,11 +930,21 @@ async fn main() {
         .unwrap();
 
     // Write headers if files are new
+    // Failed requests are counted by the class of their last error
+    let classes = ErrorClass::ALL
+        .iter()
+        .map(|class| format!(",{} Failed", class.name()))
+        .collect::<String>();
     if file_normal.metadata().unwrap().len() == 0 {
-        writeln!(file_normal, "Epoch,Normal Latency").unwrap();
+        writeln!(file_normal, "Epoch,Normal Latency,Completed{}", classes).unwrap();
     }
     if file_emergency.metadata().unwrap().len() == 0 {
-        writeln!(file_emergency, "Epoch,Emergency Latency").unwrap();
+        writeln!(
+            file_emergency,
+            "Epoch,Emergency Latency,Completed{}",
+            classes
+        )
+        .unwrap();
     }
     if file_summary.metadata().unwrap().len() == 0 {
         writeln!(
@@ -463,11 +955,11 @@ async fn main() {
     }
 
     // Write latencies per epoch for normal and emergency scenarios
-    for (epoch, lat) in latency_per_epoch_normal.iter().enumerate() {
-        writeln!(file_normal, "{},{}", epoch, lat).unwrap();
+    for (epoch, (lat, outcomes, _, _)) in latency_per_epoch_normal.iter().enumerate() {
+        writeln!(file_normal, "{}", epoch_record(epoch, *lat, outcomes)).unwrap();
     }
-    for (epoch, lat) in latency_per_epoch_emergency.iter().enumerate() {
-        writeln!(file_emergency, "{},{}", epoch, lat).unwrap();
+    for (epoch, (lat, outcomes, _, _)) in latency_per_epoch_emergency.iter().enumerate() {
+        writeln!(file_emergency, "{}", epoch_record(epoch, *lat, outcomes)).unwrap();
     }
 
     // Write summary data for each scenario
@@ -484,8 +976,53 @@ async fn main() {
     )
     .unwrap();
 
+    // Stats of every node, epoch by epoch
+    let file_path_nodes = "node_stats.csv";
+    let mut file_nodes = File::create(file_path_nodes).unwrap();
+    writeln!(file_nodes, "{}", STATS_HEADER).unwrap();
+    for report in &node_stats.reports {
+        writeln!(file_nodes, "{}", report.record()).unwrap();
+    }
+
+    // Completed requests of every epoch by the hops they took, as told by the nodes
+    let file_path_hops = "hops_per_epoch.csv";
+    let mut file_hops = File::create(file_path_hops).unwrap();
+    writeln!(file_hops, "{}", HOPS_CSV_HEADER).unwrap();
+    for (scenario, latency_per_epoch) in [
+        ("Normal", &latency_per_epoch_normal),
+        ("Emergency", &latency_per_epoch_emergency),
+    ] {
+        for (epoch, (_, _, hops, _)) in latency_per_epoch.iter().enumerate() {
+            for record in hops.records(scenario, epoch) {
+                writeln!(file_hops, "{}", record).unwrap();
+            }
+        }
+    }
+
+    // Requests sent to every node in every epoch, the distribution of the client model
+    let file_path_targets = "targets_per_epoch.csv";
+    let mut file_targets = File::create(file_path_targets).unwrap();
+    writeln!(file_targets, "{}", TARGETS_CSV_HEADER).unwrap();
+    for (scenario, latency_per_epoch) in [
+        ("Normal", &latency_per_epoch_normal),
+        ("Emergency", &latency_per_epoch_emergency),
+    ] {
+        for (epoch, (_, _, _, targets)) in latency_per_epoch.iter().enumerate() {
+            for record in target_records(scenario, epoch, args.client_model, &nodes, targets) {
+                writeln!(file_targets, "{}", record).unwrap();
+            }
+        }
+    }
+
     println!(
-        "Results written to {}, {}, and {}",
-        file_path_normal, file_path_emergency, file_path_summary
+        "Results written to {}, {}, {}, {}, {}, {}, {}, and {}",
+        file_path_normal,
+        file_path_emergency,
+        file_path_summary,
+        file_path_nodes,
+        file_path_hops,
+        file_path_targets,
+        file_path_membership,
+        REQUESTS_FILE
     );
 }
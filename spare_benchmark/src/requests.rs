@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::Path,
+    sync::Mutex,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::served::Served;
+
+// File the requests of the test are written to, one JSON object per line
+pub const REQUESTS_FILE: &str = "requests.ndjson";
+
+// File the requests joined with the instances of the nodes are written to
+pub const MERGED_FILE: &str = "requests_merged.ndjson";
+
+// A request of the test, as seen by the benchmark
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RequestRecord {
+    // Id of the request, sent to the node as its idempotency key
+    pub request_id: String,
+    pub scenario: String,
+    pub epoch: usize,
+    // When the request was first sent, in RFC 3339 UTC
+    pub timestamp: String,
+    // Node the request was sent to
+    pub target: String,
+    // Time spent in the attempts, without the backoffs (in ms)
+    pub latency_ms: u128,
+    pub attempts: u32,
+    // Completed, or the class of the error of the last attempt
+    pub outcome: String,
+    // Where the request ran, as told by the node that ran it
+    pub served_by: Option<String>,
+    pub hops: Option<u32>,
+    pub instance_id: Option<i64>,
+}
+
+impl RequestRecord {
+    pub fn with_served(mut self, served: Served) -> Self {
+        self.served_by = served.by;
+        self.hops = served.hops;
+        self.instance_id = served.instance_id;
+        self
+    }
+}
+
+// Writer of the requests file, shared by the tasks sending the requests
+pub struct RequestWriter {
+    // Random id of the run, so that the ids of the requests are not reused across runs:
+    // the nodes would answer a reused id from their idempotency cache
+    run: String,
+    file: Mutex<File>,
+}
+
+impl RequestWriter {
+    pub fn create(path: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            run: format!("{:016x}", rand::random::<u64>()),
+            file: Mutex::new(File::create(path)?),
+        })
+    }
+
+    // Id of the `index`-th request of an epoch
+    pub fn request_id(&self, scenario: &str, epoch: usize, index: usize) -> String {
+        format!(
+            "{}-{}-{}-{}",
+            self.run,
+            scenario.to_lowercase(),
+            epoch,
+            index
+        )
+    }
+
+    // A request that cannot be written is logged, the test goes on
+    pub fn write(&self, record: &RequestRecord) {
+        let line = serde_json::to_string(record).unwrap();
+        if let Err(e) = writeln!(self.file.lock().unwrap(), "{}", line) {
+            log::error!("Cannot write request {}: {}", record.request_id, e);
+        }
+    }
+}
+
+// An instance of the export of a node (`/export/instances`), the fields the join keeps.
+// The csv export has no error nor emergency columns.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExportedInstance {
+    pub id: i64,
+    pub image: String,
+    pub vcpus: i32,
+    pub memory: i32,
+    pub hops: i32,
+    pub status: String,
+    // In UTC, by the clock of the node
+    pub created_at: String,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub emergency: bool,
+}
+
+// A request, with the instance that ran it if it was found in the exports
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MergedRecord {
+    #[serde(flatten)]
+    pub request: RequestRecord,
+    pub instance: Option<ExportedInstance>,
+}
+
+// Instances of the exports by node and id, the ids being given by each node
+pub type Exports = HashMap<(String, i64), ExportedInstance>;
+
+// Parse one JSON object per line, skipping the empty lines
+fn parse_ndjson<T: DeserializeOwned>(raw: &str) -> Result<Vec<T>, String> {
+    raw.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            serde_json::from_str(line).map_err(|e| format!("Line {}: {}", number + 1, e))
+        })
+        .collect()
+}
+
+// Parse the requests file
+pub fn parse_requests(raw: &str) -> Result<Vec<RequestRecord>, String> {
+    parse_ndjson(raw)
+}
+
+// Parse an export of the instances of a node, in csv (with its header) or ndjson
+pub fn parse_instances(raw: &str, csv: bool) -> Result<Vec<ExportedInstance>, String> {
+    if csv {
+        return csv::Reader::from_reader(raw.as_bytes())
+            .deserialize()
+            .enumerate()
+            .map(|(number, row)| row.map_err(|e| format!("Row {}: {}", number + 1, e)))
+            .collect();
+    }
+    parse_ndjson(raw)
+}
+
+// Load the exports of a directory, one file per node named after what the node sets in
+// X-Spare-Served-By (its id, or its address), e.g. `node-a.ndjson` or `10.0.0.1:8085.csv`
+pub fn load_exports(dir: &Path) -> Result<Exports, String> {
+    let mut exports = Exports::new();
+    let entries = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    for entry in entries {
+        let path = entry.map_err(|e| e.to_string())?.path();
+        let csv = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => true,
+            Some("ndjson") | Some("jsonl") => false,
+            _ => continue,
+        };
+        let Some(node) = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+        else {
+            continue;
+        };
+        let raw = fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        for instance in
+            parse_instances(&raw, csv).map_err(|e| format!("{}: {}", path.display(), e))?
+        {
+            exports.insert((node.clone(), instance.id), instance);
+        }
+    }
+    Ok(exports)
+}
+
+// Join the requests with the instances that ran them, in the order of the requests
+pub fn merge(requests: Vec<RequestRecord>, exports: &Exports) -> Vec<MergedRecord> {
+    requests
+        .into_iter()
+        .map(|request| {
+            let instance = match (&request.served_by, request.instance_id) {
+                (Some(node), Some(id)) => exports.get(&(node.clone(), id)).cloned(),
+                _ => None,
+            };
+            MergedRecord { request, instance }
+        })
+        .collect()
+}
+
+// Join the requests file with the exports of `dir` into `output`
+// # Returns
+// * The number of requests, and of those joined with their instance
+pub fn merge_files(requests: &str, dir: &Path, output: &str) -> Result<(usize, usize), String> {
+    let raw = fs::read_to_string(requests).map_err(|e| format!("{}: {}", requests, e))?;
+    let requests = parse_requests(&raw).map_err(|e| format!("{}: {}", requests, e))?;
+    let merged = merge(requests, &load_exports(dir)?);
+    let mut file = File::create(output).map_err(|e| format!("{}: {}", output, e))?;
+    for record in &merged {
+        writeln!(file, "{}", serde_json::to_string(record).unwrap())
+            .map_err(|e| format!("{}: {}", output, e))?;
+    }
+    let joined = merged
+        .iter()
+        .filter(|record| record.instance.is_some())
+        .count();
+    Ok((merged.len(), joined))
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REQUESTS: &str = r#"{"request_id":"run-normal-0-0","scenario":"Normal","epoch":0,"timestamp":"2025-03-01T10:00:00.000Z","target":"10.0.0.1:8085","latency_ms":812,"attempts":1,"outcome":"Completed","served_by":"node-a","hops":0,"instance_id":1}
+{"request_id":"run-normal-0-1","scenario":"Normal","epoch":0,"timestamp":"2025-03-01T10:00:00.011Z","target":"10.0.0.1:8085","latency_ms":1530,"attempts":2,"outcome":"Completed","served_by":"10.0.0.2:8085","hops":1,"instance_id":1}
+
+{"request_id":"run-normal-0-2","scenario":"Normal","epoch":0,"timestamp":"2025-03-01T10:00:00.022Z","target":"10.0.0.2:8085","latency_ms":60000,"attempts":4,"outcome":"Overloaded","served_by":null,"hops":null,"instance_id":null}
+"#;
+
+    const EXPORT_NDJSON: &str = r#"{"id":1,"functions":"test","kernel":"vmlinux","image":"/data/nanosvm","vcpus":2,"memory":512,"ip":"192.168.30.2","port":1234,"hops":0,"status":"terminated","created_at":"2025-03-01T10:00:00.120","env":null,"args":null,"error":null,"emergency":false,"run_id":3}
+{"id":2,"functions":"test","kernel":"vmlinux","image":"/data/nanosvm","vcpus":2,"memory":512,"ip":"192.168.30.3","port":1234,"hops":0,"status":"failed","created_at":"2025-03-01T10:00:01.500","env":null,"args":null,"error":"boom","emergency":true,"run_id":3}
+"#;
+
+    const EXPORT_CSV: &str = "id,functions,kernel,image,vcpus,memory,ip,port,hops,status,created_at,env,args\n\
+        1,test,vmlinux,/data/nanosvm,2,512,192.168.31.2,1234,1,terminated,2025-03-01 10:00:00.900,,\n";
+
+    fn instance(id: i64, hops: i32, status: &str, created_at: &str) -> ExportedInstance {
+        ExportedInstance {
+            id,
+            image: "/data/nanosvm".to_string(),
+            vcpus: 2,
+            memory: 512,
+            hops,
+            status: status.to_string(),
+            created_at: created_at.to_string(),
+            error: None,
+            emergency: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        let requests = parse_requests(REQUESTS).unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1].attempts, 2);
+        assert_eq!(requests[1].served_by.as_deref(), Some("10.0.0.2:8085"));
+        assert_eq!(requests[2].instance_id, None);
+        let e = parse_requests("{\"request_id\":\"x\"}\nnot json").unwrap_err();
+        assert!(e.starts_with("Line 1:"), "{}", e);
+    }
+
+    #[test]
+    fn test_parse_instances() {
+        let instances = parse_instances(EXPORT_NDJSON, false).unwrap();
+        assert_eq!(
+            instances[0],
+            instance(1, 0, "terminated", "2025-03-01T10:00:00.120")
+        );
+        assert_eq!(instances[1].error.as_deref(), Some("boom"));
+        assert!(instances[1].emergency);
+
+        // The csv export has no error nor emergency columns
+        let instances = parse_instances(EXPORT_CSV, true).unwrap();
+        assert_eq!(
+            instances,
+            vec![instance(1, 1, "terminated", "2025-03-01 10:00:00.900")]
+        );
+        assert!(parse_instances("id,image\nx,y\n", true)
+            .unwrap_err()
+            .starts_with("Row 1:"));
+    }
+
+    #[test]
+    fn test_merge() {
+        let dir = std::env::temp_dir().join(format!("spare-merge-{}", rand::random::<u64>()));
+        let exports_dir = dir.join("exports");
+        fs::create_dir_all(&exports_dir).unwrap();
+        fs::write(exports_dir.join("node-a.ndjson"), EXPORT_NDJSON).unwrap();
+        fs::write(exports_dir.join("10.0.0.2:8085.csv"), EXPORT_CSV).unwrap();
+        fs::write(exports_dir.join("README.txt"), "not an export").unwrap();
+        let exports = load_exports(&exports_dir).unwrap();
+        assert_eq!(exports.len(), 3);
+
+        // The ids are joined within the node that ran the request only
+        let merged = merge(parse_requests(REQUESTS).unwrap(), &exports);
+        assert_eq!(merged.len(), 3);
+        assert_eq!(
+            merged[0].instance,
+            Some(instance(1, 0, "terminated", "2025-03-01T10:00:00.120"))
+        );
+        assert_eq!(merged[1].instance.as_ref().unwrap().hops, 1);
+        assert_eq!(merged[2].instance, None);
+
+        // The request fields stay at the top level of the combined records
+        let requests = dir.join(REQUESTS_FILE);
+        let output = dir.join(MERGED_FILE);
+        fs::write(&requests, REQUESTS).unwrap();
+        let (total, joined) = merge_files(
+            requests.to_str().unwrap(),
+            &exports_dir,
+            output.to_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!((total, joined), (3, 2));
+        let line = fs::read_to_string(&output).unwrap();
+        let first: serde_json::Value = serde_json::from_str(line.lines().next().unwrap()).unwrap();
+        assert_eq!(first["request_id"], "run-normal-0-0");
+        assert_eq!(first["instance"]["status"], "terminated");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+// Class of a failed attempt, deciding whether and when the request is retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    // The cluster has no resources left (429, 503 or insufficient resources)
+    Overloaded,
+    // The request itself is invalid, retrying it cannot help
+    Validation,
+    // Any other server error
+    Server,
+    // The node could not be reached or did not answer in time
+    Transport,
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 4] = [
+        ErrorClass::Overloaded,
+        ErrorClass::Validation,
+        ErrorClass::Server,
+        ErrorClass::Transport,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ErrorClass::Overloaded => "Overloaded",
+            ErrorClass::Validation => "Validation",
+            ErrorClass::Server => "Server",
+            ErrorClass::Transport => "Transport",
+        }
+    }
+}
+
+// Classify a non-success response of a node
+pub fn classify(status: u16, body: &str) -> ErrorClass {
+    match status {
+        429 | 503 => ErrorClass::Overloaded,
+        // The nodes report that no node has room for the request as an internal error
+        500 if body.starts_with("Insufficient resources") => ErrorClass::Overloaded,
+        400..=499 => ErrorClass::Validation,
+        _ => ErrorClass::Server,
+    }
+}
+
+// What to do after a failed attempt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Retry(Duration),
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    // First backoff of an overloaded request, doubled at every retry
+    pub backoff_base: Duration,
+    // Longest backoff of an overloaded request
+    pub backoff_max: Duration,
+    // Retries of an overloaded request before giving up
+    pub max_overload_retries: u32,
+    // Retries after a server or transport error before giving up, each after `backoff_base`
+    pub max_retries: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            backoff_base: Duration::from_millis(100),
+            backoff_max: Duration::from_secs(5),
+            max_overload_retries: 10,
+            max_retries: 3,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Decide what to do after `retries` retries of a request failed, the last one with `class`
+    pub fn decide(&self, class: ErrorClass, retries: u32) -> Decision {
+        match class {
+            ErrorClass::Validation => Decision::Fail,
+            ErrorClass::Overloaded if retries < self.max_overload_retries => {
+                let backoff = self
+                    .backoff_base
+                    .saturating_mul(2u32.saturating_pow(retries));
+                Decision::Retry(backoff.min(self.backoff_max))
+            }
+            ErrorClass::Server | ErrorClass::Transport if retries < self.max_retries => {
+                Decision::Retry(self.backoff_base)
+            }
+            _ => Decision::Fail,
+        }
+    }
+}
+
+// Terminal outcome of a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Completed,
+    Failed(ErrorClass),
+}
+
+// Number of requests per terminal outcome
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Outcomes {
+    pub completed: usize,
+    pub overloaded: usize,
+    pub validation: usize,
+    pub server: usize,
+    pub transport: usize,
+}
+
+impl Outcomes {
+    pub fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Completed => self.completed += 1,
+            Outcome::Failed(ErrorClass::Overloaded) => self.overloaded += 1,
+            Outcome::Failed(ErrorClass::Validation) => self.validation += 1,
+            Outcome::Failed(ErrorClass::Server) => self.server += 1,
+            Outcome::Failed(ErrorClass::Transport) => self.transport += 1,
+        }
+    }
+
+    pub fn failed(&self, class: ErrorClass) -> usize {
+        match class {
+            ErrorClass::Overloaded => self.overloaded,
+            ErrorClass::Validation => self.validation,
+            ErrorClass::Server => self.server,
+            ErrorClass::Transport => self.transport,
+        }
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(429, ""), ErrorClass::Overloaded);
+        assert_eq!(classify(503, ""), ErrorClass::Overloaded);
+        assert_eq!(
+            classify(500, "Insufficient resources\n"),
+            ErrorClass::Overloaded
+        );
+        assert_eq!(
+            classify(400, "Invalid environment variable name: 1A\n"),
+            ErrorClass::Validation
+        );
+        assert_eq!(classify(404, ""), ErrorClass::Validation);
+        assert_eq!(classify(500, "Too many hops\n"), ErrorClass::Server);
+        assert_eq!(classify(502, ""), ErrorClass::Server);
+    }
+
+    #[test]
+    fn test_decision_table() {
+        let policy = RetryPolicy::default();
+        // (class, retries so far, decision)
+        let table = [
+            (ErrorClass::Overloaded, 0, Decision::Retry(ms(100))),
+            (ErrorClass::Overloaded, 1, Decision::Retry(ms(200))),
+            (ErrorClass::Overloaded, 3, Decision::Retry(ms(800))),
+            (ErrorClass::Overloaded, 6, Decision::Retry(ms(5000))),
+            (ErrorClass::Overloaded, 9, Decision::Retry(ms(5000))),
+            (ErrorClass::Overloaded, 10, Decision::Fail),
+            (ErrorClass::Validation, 0, Decision::Fail),
+            (ErrorClass::Server, 0, Decision::Retry(ms(100))),
+            (ErrorClass::Server, 2, Decision::Retry(ms(100))),
+            (ErrorClass::Server, 3, Decision::Fail),
+            (ErrorClass::Transport, 0, Decision::Retry(ms(100))),
+            (ErrorClass::Transport, 3, Decision::Fail),
+        ];
+        for (class, retries, decision) in table {
+            assert_eq!(
+                policy.decide(class, retries),
+                decision,
+                "{:?} after {} retries",
+                class,
+                retries
+            );
+        }
+    }
+
+    #[test]
+    fn test_backoff_overflow() {
+        let policy = RetryPolicy {
+            max_overload_retries: u32::MAX,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.decide(ErrorClass::Overloaded, 64),
+            Decision::Retry(policy.backoff_max)
+        );
+    }
+
+    #[test]
+    fn test_outcomes() {
+        let mut outcomes = Outcomes::default();
+        outcomes.record(Outcome::Completed);
+        outcomes.record(Outcome::Failed(ErrorClass::Overloaded));
+        outcomes.record(Outcome::Failed(ErrorClass::Overloaded));
+        outcomes.record(Outcome::Failed(ErrorClass::Validation));
+        assert_eq!(outcomes.completed, 1);
+        assert_eq!(outcomes.failed(ErrorClass::Overloaded), 2);
+        assert_eq!(outcomes.failed(ErrorClass::Validation), 1);
+        assert_eq!(outcomes.failed(ErrorClass::Server), 0);
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+
+use reqwest::header::HeaderMap;
+
+// Headers set on the answer by the node that ran the function
+pub const SERVED_BY_HEADER: &str = "X-Spare-Served-By";
+pub const HOPS_HEADER: &str = "X-Spare-Hops";
+pub const INSTANCE_ID_HEADER: &str = "X-Spare-Instance-Id";
+
+// Header of the hops CSV
+pub const HOPS_CSV_HEADER: &str = "Scenario,Epoch,Hops,Requests";
+
+// Where a request ran, as told by the node that ran it. The older nodes tell nothing.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Served {
+    pub by: Option<String>,
+    pub hops: Option<u32>,
+    pub instance_id: Option<i64>,
+}
+
+impl Served {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        Self {
+            by: header(SERVED_BY_HEADER).map(str::to_string),
+            hops: header(HOPS_HEADER).and_then(|hops| hops.parse().ok()),
+            instance_id: header(INSTANCE_ID_HEADER).and_then(|id| id.parse().ok()),
+        }
+    }
+}
+
+// Number of completed requests of an epoch by the hops they took
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HopHistogram {
+    counts: BTreeMap<u32, usize>,
+    // Requests answered by the older nodes, without the hops
+    unknown: usize,
+}
+
+impl HopHistogram {
+    pub fn record(&mut self, hops: Option<u32>) {
+        match hops {
+            Some(hops) => *self.counts.entry(hops).or_default() += 1,
+            None => self.unknown += 1,
+        }
+    }
+
+    // Records of the histogram in the hops CSV, fewest hops first
+    pub fn records(&self, scenario: &str, epoch: usize) -> Vec<String> {
+        let mut records: Vec<String> = self
+            .counts
+            .iter()
+            .map(|(hops, requests)| format!("{},{},{},{}", scenario, epoch, hops, requests))
+            .collect();
+        if self.unknown > 0 {
+            records.push(format!("{},{},unknown,{}", scenario, epoch, self.unknown));
+        }
+        records
+    }
+}
+
+// Unit tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_served_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Served::from_headers(&headers), Served::default());
+        headers.insert(SERVED_BY_HEADER, "node-a".parse().unwrap());
+        headers.insert(HOPS_HEADER, "2".parse().unwrap());
+        headers.insert(INSTANCE_ID_HEADER, "42".parse().unwrap());
+        assert_eq!(
+            Served::from_headers(&headers),
+            Served {
+                by: Some("node-a".to_string()),
+                hops: Some(2),
+                instance_id: Some(42),
+            }
+        );
+        headers.insert(HOPS_HEADER, "many".parse().unwrap());
+        assert_eq!(Served::from_headers(&headers).hops, None);
+    }
+
+    #[test]
+    fn test_hop_histogram() {
+        let mut histogram = HopHistogram::default();
+        assert!(histogram.records("Normal", 0).is_empty());
+        for hops in [Some(1), Some(0), None, Some(1), Some(3)] {
+            histogram.record(hops);
+        }
+        assert_eq!(
+            histogram.records("Normal", 2),
+            vec![
+                "Normal,2,0,1",
+                "Normal,2,1,2",
+                "Normal,2,3,1",
+                "Normal,2,unknown,1"
+            ]
+        );
+    }
+}